@@ -0,0 +1,263 @@
+//! Abuse log and IP reputation list
+//!
+//! The abuse log records the clients that misbehave: requests above the rate limits, and
+//! publishes with an invalid signature or a malformed packet. With an [`AbuseLogConfig`], each
+//! event is logged on the `warn` level with the target `iroh_dns_server::abuse`, and, if `path`
+//! is set, appended to a dedicated file with one line per event, which tools like fail2ban can
+//! match without parsing the rest of the logs:
+//!
+//! ```text
+//! 2024-05-01T12:00:00Z abuse kind=rate_limited ip=192.0.2.1 detail="publish"
+//! ```
+//!
+//! The events are counted in the `abuse_events` metric, also without an [`AbuseLogConfig`].
+//!
+//! The IP reputation list of an [`IpReputationConfig`] is a file with an IP address or a network
+//! in CIDR notation per line, e.g. exported from an external reputation feed. Connections to the
+//! HTTP and HTTPS listeners and DNS requests from the listed addresses are dropped before they
+//! are handled. The file is read again periodically, so the feed can replace it at any time.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{debug, info, warn};
+
+use crate::metrics::AbuseMetrics;
+
+/// Default interval in seconds in which the IP reputation list is read again.
+const DEFAULT_RELOAD_INTERVAL_SECS: u64 = 300;
+
+/// Config for the abuse log
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AbuseLogConfig {
+    /// The file to append the events to, one line per event (only logged if unset).
+    pub path: Option<PathBuf>,
+}
+
+/// Config for dropping the traffic of the addresses in an IP reputation list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IpReputationConfig {
+    /// The file with an IP address or a network in CIDR notation per line.
+    ///
+    /// Empty lines and comments starting with `#` are ignored.
+    pub path: PathBuf,
+    /// Interval in seconds in which the file is read again (defaults to 300).
+    pub reload_interval_secs: Option<u64>,
+}
+
+/// The kind of an event of the abuse log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum AbuseKind {
+    /// A request above the rate limit
+    RateLimited,
+    /// A publish with a signature that doesn't match the key
+    InvalidSignature,
+    /// A publish with an invalid key or a packet that can't be decoded
+    MalformedPacket,
+}
+
+/// The abuse log, which only counts the events unless it is enabled with a config.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AbuseLog(Option<Arc<AbuseLogInner>>);
+
+#[derive(Debug)]
+struct AbuseLogInner {
+    file: Option<Mutex<File>>,
+}
+
+impl AbuseLog {
+    /// Create the abuse log of `config`, and open its file.
+    pub(crate) fn open(config: &AbuseLogConfig) -> Result<Self> {
+        let file = match &config.path {
+            None => None,
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open the abuse log {}", path.display()))?;
+                Some(Mutex::new(file))
+            }
+        };
+        Ok(Self(Some(Arc::new(AbuseLogInner { file }))))
+    }
+
+    /// Record an event of `kind` by the client at `ip`.
+    pub(crate) fn record(&self, kind: AbuseKind, ip: IpAddr, detail: &str) {
+        AbuseMetrics::count(kind);
+        let Some(inner) = &self.0 else {
+            return;
+        };
+        let kind: &'static str = kind.into();
+        let ip = ip.to_canonical();
+        warn!(kind, %ip, detail, "abuse");
+        if let Some(file) = &inner.file {
+            let now = OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default();
+            let line = format!("{now} abuse kind={kind} ip={ip} detail={detail:?}\n");
+            if let Err(err) = file.lock().write_all(line.as_bytes()) {
+                debug!("failed to write to the abuse log: {err}");
+            }
+        }
+    }
+}
+
+/// The networks of an IP reputation list, empty unless a list is loaded.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IpReputation(Arc<RwLock<Networks>>);
+
+/// Non-overlapping networks, sorted by their address
+#[derive(Debug, Default)]
+struct Networks {
+    v4: Vec<Ipv4Net>,
+    v6: Vec<Ipv6Net>,
+}
+
+impl IpReputation {
+    /// Load the list of `config`.
+    pub(crate) fn load(config: &IpReputationConfig) -> Result<Self> {
+        let networks = read_list(&config.path)?;
+        info!(
+            networks = networks.len(),
+            "loaded the IP reputation list {}",
+            config.path.display()
+        );
+        Ok(Self(Arc::new(RwLock::new(networks))))
+    }
+
+    /// Whether `ip` is in one of the listed networks.
+    pub(crate) fn is_listed(&self, ip: IpAddr) -> bool {
+        let networks = self.0.read();
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&networks.v4, ip, Ipv4Net::network, |net, ip| {
+                net.contains(&ip)
+            }),
+            IpAddr::V6(ip) => contains(&networks.v6, ip, Ipv6Net::network, |net, ip| {
+                net.contains(&ip)
+            }),
+        }
+    }
+
+    /// Read the list of `config` again in its interval, until the task is aborted.
+    ///
+    /// If the file can't be read, the networks that were loaded before stay in effect.
+    pub(crate) async fn run(self, config: IpReputationConfig) {
+        let interval = Duration::from_secs(
+            config
+                .reload_interval_secs
+                .unwrap_or(DEFAULT_RELOAD_INTERVAL_SECS),
+        );
+        loop {
+            tokio::time::sleep(interval).await;
+            let path = config.path.clone();
+            match tokio::task::spawn_blocking(move || read_list(&path)).await {
+                Ok(Ok(networks)) => {
+                    debug!(networks = networks.len(), "reloaded the IP reputation list");
+                    *self.0.write() = networks;
+                }
+                Ok(Err(err)) => warn!("failed to reload the IP reputation list: {err:#}"),
+                Err(err) => warn!("failed to reload the IP reputation list: {err}"),
+            }
+        }
+    }
+}
+
+impl Networks {
+    fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+/// Whether `ip` is in one of the sorted, non-overlapping `networks`.
+fn contains<N, A: Ord + Copy>(
+    networks: &[N],
+    ip: A,
+    network: impl Fn(&N) -> A,
+    contains: impl Fn(&N, A) -> bool,
+) -> bool {
+    // the only network that can contain the address is the last one that starts before it
+    let index = networks.partition_point(|net| network(net) <= ip);
+    index > 0 && contains(&networks[index - 1], ip)
+}
+
+/// Read the networks of the list at `path`.
+fn read_list(path: &Path) -> Result<Networks> {
+    let list = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read the IP reputation list {}", path.display()))?;
+    let networks = parse_list(&list).with_context(|| format!("in {}", path.display()))?;
+    AbuseMetrics::get()
+        .ip_reputation_entries
+        .set(networks.len() as i64);
+    Ok(networks)
+}
+
+/// Parse a list with an address or network per line, and merge the overlapping networks.
+fn parse_list(list: &str) -> Result<Networks> {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for (index, line) in list.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let net = match line.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(_) => line
+                .parse::<IpAddr>()
+                .map(IpNet::from)
+                .with_context(|| format!("invalid address on line {}: {line}", index + 1))?,
+        };
+        match net {
+            IpNet::V4(net) => v4.push(net),
+            IpNet::V6(net) => v6.push(net),
+        }
+    }
+    let mut v4 = Ipv4Net::aggregate(&v4);
+    v4.sort_by_key(|net| net.network());
+    let mut v6 = Ipv6Net::aggregate(&v6);
+    v6.sort_by_key(|net| net.network());
+    Ok(Networks { v4, v6 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_addresses() -> Result<()> {
+        let networks = parse_list(
+            "# from the feed\n\
+             192.0.2.0/24\n\
+             192.0.2.7 # inside the network above\n\
+             \n\
+             203.0.113.9\n\
+             2001:db8::/48\n",
+        )?;
+        assert_eq!(networks.len(), 3);
+        let reputation = IpReputation(Arc::new(RwLock::new(networks)));
+        assert!(reputation.is_listed("192.0.2.200".parse()?));
+        assert!(reputation.is_listed("203.0.113.9".parse()?));
+        assert!(!reputation.is_listed("203.0.113.10".parse()?));
+        assert!(reputation.is_listed("2001:db8::53".parse()?));
+        // IPv4 clients of dual-stack sockets have mapped addresses
+        assert!(reputation.is_listed("::ffff:192.0.2.1".parse()?));
+        assert!(!reputation.is_listed("198.51.100.1".parse()?));
+        assert!(!reputation.is_listed("2001:db8:1::1".parse()?));
+
+        assert!(parse_list("192.0.2.0/33").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+//! Access control of the operations by client network
+//!
+//! An [`AclConfig`] lists the networks that may perform each [`Operation`]. The lists are
+//! evaluated against the client IP address of a request the same way on all listeners: the
+//! source address of DNS requests over UDP and TCP, or the address from the PROXY protocol
+//! header, and the client address of HTTP requests, which is only read from the headers of
+//! trusted proxies. Denied DNS requests, including DNS-over-HTTPS, are answered with `REFUSED`,
+//! denied HTTP requests with `403 Forbidden`.
+
+use std::{net::IpAddr, sync::Arc};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::metrics::AbuseMetrics;
+
+/// Config of the networks that may perform the operations
+///
+/// An operation without a list is allowed from anywhere, an empty list denies it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AclConfig {
+    /// Networks that may query the DNS server, over UDP, TCP and DoH, and look up packets with
+    /// `GET /pkarr/{key}`, in CIDR notation (e.g. `10.0.0.0/8`)
+    pub query: Option<Vec<IpNet>>,
+    /// Networks that may transfer the zones, in addition to the `allow` list of
+    /// [`crate::dns::TransferConfig`]
+    pub transfer: Option<Vec<IpNet>>,
+    /// Networks that may send dynamic updates
+    ///
+    /// The zones of the server are not updated with DNS UPDATE, so the updates of the allowed
+    /// networks are refused by the zones instead.
+    pub update: Option<Vec<IpNet>>,
+    /// Networks that may publish packets with `PUT /pkarr/{key}` and its dry run
+    pub publish: Option<Vec<IpNet>>,
+    /// Networks that may call the admin endpoints
+    pub admin: Option<Vec<IpNet>>,
+}
+
+/// An operation that is controlled by the ACL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Operation {
+    /// DNS queries and pkarr lookups
+    Query,
+    /// Zone transfers
+    Transfer,
+    /// Dynamic updates
+    Update,
+    /// Pkarr publishes
+    Publish,
+    /// Admin calls
+    Admin,
+}
+
+/// The ACL of the server, which allows everything unless it is configured
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Acl(Option<Arc<AclConfig>>);
+
+impl Acl {
+    /// Create the ACL of `config`.
+    pub(crate) fn new(config: AclConfig) -> Self {
+        Self(Some(Arc::new(config)))
+    }
+
+    /// Whether a client at `ip` may perform `operation`.
+    ///
+    /// Denials are counted in the `acl_denied` metric.
+    pub(crate) fn allows(&self, operation: Operation, ip: IpAddr) -> bool {
+        let Some(config) = &self.0 else {
+            return true;
+        };
+        let allowed = match operation {
+            Operation::Query => &config.query,
+            Operation::Transfer => &config.transfer,
+            Operation::Update => &config.update,
+            Operation::Publish => &config.publish,
+            Operation::Admin => &config.admin,
+        };
+        // IPv4 clients of dual-stack listeners have IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let allows = allowed
+            .as_ref()
+            .is_none_or(|nets| nets.iter().any(|net| net.contains(&ip)));
+        if !allows {
+            debug!(%ip, %operation, "denied by the ACL");
+            AbuseMetrics::count_acl_denied(operation.into());
+        }
+        allows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_listed_networks() {
+        let acl = Acl::new(AclConfig {
+            publish: Some(vec!["192.0.2.0/24".parse().unwrap()]),
+            admin: Some(vec![]),
+            ..Default::default()
+        });
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(acl.allows(Operation::Publish, client));
+        assert!(acl.allows(Operation::Publish, "::ffff:192.0.2.7".parse().unwrap()));
+        assert!(!acl.allows(Operation::Publish, other));
+        assert!(acl.allows(Operation::Query, other));
+        assert!(!acl.allows(Operation::Admin, client));
+        assert!(Acl::default().allows(Operation::Admin, other));
+    }
+}
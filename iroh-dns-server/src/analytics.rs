@@ -0,0 +1,298 @@
+//! Resolution counts for the owners of the keys
+//!
+//! With an [`AnalyticsConfig`], the server counts how often the packet of each pubkey was
+//! resolved per day, by [`Transport`]: DNS over UDP or TCP, DNS-over-HTTPS, or `GET /pkarr`. The
+//! counts are collected in memory, and added to a table of the packet database in an interval.
+//! Days older than the retention are removed.
+//!
+//! The owner of a key gets the counts of the last days with `GET /analytics/{key}`, signed with
+//! the secret key of the pubkey, so that nobody else learns how often a node is looked up. The
+//! query has the number of `days`, the `timestamp` of the request in seconds since the unix epoch
+//! and the hex encoded ed25519 `signature` of [`request_message`], see [`signed_query`]. The
+//! timestamp must be within 5 minutes of the server clock, so that a request can't be replayed
+//! later.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use hickory_server::server::Protocol;
+use iroh_net::key::{PublicKey, SecretKey, Signature};
+use parking_lot::Mutex;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::util::PublicKeyBytes;
+
+/// The resolutions by pubkey, day since the unix epoch and transport
+const RESOLUTIONS_TABLE: TableDefinition<(&[u8; 32], u32, &str), u64> =
+    TableDefinition::new("resolutions-1");
+
+/// Default number of days the counts are kept.
+const DEFAULT_RETENTION_DAYS: u32 = 30;
+/// Default interval in seconds in which the counts are written to the database.
+const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 60;
+/// Maximum difference between the timestamp of a request and the server clock.
+const MAX_REQUEST_SKEW: Duration = Duration::from_secs(300);
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Config for counting the resolutions of the packets
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AnalyticsConfig {
+    /// Number of days the counts are kept, and the most days that can be queried
+    /// (defaults to 30).
+    pub retention_days: Option<u32>,
+    /// Interval in seconds in which the counts are written to the database (defaults to 60).
+    pub flush_interval_secs: Option<u64>,
+}
+
+/// How a packet was resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Transport {
+    /// DNS over UDP
+    Udp,
+    /// DNS over TCP
+    Tcp,
+    /// DNS-over-HTTPS
+    Doh,
+    /// `GET /pkarr/{key}`
+    Http,
+}
+
+impl From<Protocol> for Transport {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Udp => Self::Udp,
+            Protocol::Https => Self::Doh,
+            _ => Self::Tcp,
+        }
+    }
+}
+
+/// The resolutions of a pubkey on one day
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct DayResolutions {
+    /// The day, as `YYYY-MM-DD` in UTC
+    pub date: String,
+    /// The resolutions by transport, `udp`, `tcp`, `doh` or `http`
+    pub transports: BTreeMap<String, u64>,
+    /// The resolutions over all transports
+    pub total: u64,
+}
+
+/// The resolutions of a pubkey over the last days
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct Resolutions {
+    /// The z-base-32 encoded pubkey
+    pub pubkey: String,
+    /// The resolutions per day, the oldest day first, including today
+    pub days: Vec<DayResolutions>,
+    /// The resolutions over all days
+    pub total: u64,
+}
+
+/// Resolution counts by pubkey, day since the unix epoch and transport
+type Counts = HashMap<(PublicKeyBytes, u32, Transport), u64>;
+
+/// The resolution counts, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub(crate) struct Analytics {
+    db: Arc<Database>,
+    /// The counts that are not yet in the database
+    pending: Arc<Mutex<Counts>>,
+    retention_days: u32,
+    flush_interval: Duration,
+}
+
+impl Analytics {
+    /// Open the resolution counts in `db`.
+    pub(crate) fn open(db: Arc<Database>, config: &AnalyticsConfig) -> Result<Self> {
+        let retention_days = config.retention_days.unwrap_or(DEFAULT_RETENTION_DAYS);
+        ensure!(
+            retention_days > 0,
+            "analytics.retention_days must be at least 1"
+        );
+        let write_tx = db.begin_write()?;
+        {
+            let _table = write_tx.open_table(RESOLUTIONS_TABLE)?;
+        }
+        write_tx.commit()?;
+        Ok(Self {
+            db,
+            pending: Default::default(),
+            retention_days,
+            flush_interval: Duration::from_secs(
+                config
+                    .flush_interval_secs
+                    .unwrap_or(DEFAULT_FLUSH_INTERVAL_SECS),
+            ),
+        })
+    }
+
+    /// The number of days the counts are kept, and the most days that can be queried.
+    pub(crate) fn retention_days(&self) -> u32 {
+        self.retention_days
+    }
+
+    /// Count a resolution of the packet of `pubkey` over `transport`.
+    pub(crate) fn record(&self, pubkey: PublicKeyBytes, transport: Transport) {
+        *self
+            .pending
+            .lock()
+            .entry((pubkey, today(), transport))
+            .or_default() += 1;
+    }
+
+    /// Write the counts to the database in the flush interval, until the task is aborted.
+    pub(crate) async fn run(self) {
+        loop {
+            tokio::time::sleep(self.flush_interval).await;
+            let this = self.clone();
+            match tokio::task::spawn_blocking(move || this.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("failed to write the resolution counts: {err:#}"),
+                Err(err) => warn!("failed to write the resolution counts: {err}"),
+            }
+        }
+    }
+
+    /// Add the pending counts to the database, and remove the days older than the retention.
+    pub(crate) fn flush(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let oldest = today().saturating_sub(self.retention_days - 1);
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(RESOLUTIONS_TABLE)?;
+            for ((pubkey, day, transport), count) in &pending {
+                let key = (pubkey.as_bytes(), *day, <&'static str>::from(*transport));
+                let stored = table.get(key)?.map(|v| v.value()).unwrap_or(0);
+                table.insert(key, stored + count)?;
+            }
+            table.retain(|(_, day, _), _| day >= oldest)?;
+        }
+        tx.commit()?;
+        debug!(entries = pending.len(), "wrote the resolution counts");
+        Ok(())
+    }
+
+    /// Get the resolutions of `pubkey` over the last `days`, including today.
+    ///
+    /// The counts of the current flush interval are included.
+    pub(crate) fn get(&self, pubkey: &PublicKeyBytes, days: u32) -> Result<Resolutions> {
+        ensure!(
+            (1..=self.retention_days).contains(&days),
+            "days must be between 1 and {}",
+            self.retention_days
+        );
+        let today = today();
+        let oldest = today.saturating_sub(days - 1);
+        let mut counts: BTreeMap<u32, BTreeMap<String, u64>> =
+            (oldest..=today).map(|day| (day, BTreeMap::new())).collect();
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(RESOLUTIONS_TABLE)?;
+        let start = (pubkey.as_bytes(), oldest, "");
+        let end = (pubkey.as_bytes(), u32::MAX, "");
+        for row in table.range(start..end)? {
+            let (key, count) = row?;
+            let (_, day, transport) = key.value();
+            if let Some(transports) = counts.get_mut(&day) {
+                *transports.entry(transport.to_string()).or_default() += count.value();
+            }
+        }
+        for ((key, day, transport), count) in self.pending.lock().iter() {
+            if key == pubkey && *day >= oldest {
+                let transport = <&'static str>::from(*transport).to_string();
+                *counts
+                    .entry(*day)
+                    .or_default()
+                    .entry(transport)
+                    .or_default() += count;
+            }
+        }
+        let days: Vec<DayResolutions> = counts
+            .into_iter()
+            .map(|(day, transports)| DayResolutions {
+                date: date(day),
+                total: transports.values().sum(),
+                transports,
+            })
+            .collect();
+        Ok(Resolutions {
+            pubkey: pubkey.to_z32(),
+            total: days.iter().map(|day| day.total).sum(),
+            days,
+        })
+    }
+}
+
+/// The message that the owner of `pubkey` signs to get the resolutions of the last `days`.
+pub fn request_message(pubkey: &str, days: u32, timestamp: u64) -> Vec<u8> {
+    format!("iroh-dns-server analytics\n{pubkey}\n{days}\n{timestamp}").into_bytes()
+}
+
+/// The query string of a `GET /analytics/{key}` request for the resolutions of the last `days`,
+/// signed with `secret_key`.
+pub fn signed_query(secret_key: &SecretKey, days: u32) -> String {
+    let pubkey = z32::encode(secret_key.public().as_bytes());
+    let timestamp = unix_secs(SystemTime::now());
+    let signature = secret_key.sign(&request_message(&pubkey, days, timestamp));
+    format!(
+        "days={days}&timestamp={timestamp}&signature={}",
+        hex::encode(signature.to_bytes())
+    )
+}
+
+/// Check that `signature` is a signature of the owner of `pubkey` of a recent request.
+pub(crate) fn verify_request(
+    pubkey: &PublicKeyBytes,
+    days: u32,
+    timestamp: u64,
+    signature: &str,
+) -> Result<()> {
+    let now = unix_secs(SystemTime::now());
+    ensure!(
+        now.abs_diff(timestamp) <= MAX_REQUEST_SKEW.as_secs(),
+        "the timestamp of the request is more than {}s from the server clock",
+        MAX_REQUEST_SKEW.as_secs()
+    );
+    let signature: [u8; 64] = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("the signature must be 64 hex encoded bytes")?;
+    let key = PublicKey::from_bytes(pubkey.as_bytes())?;
+    let message = request_message(&pubkey.to_z32(), days, timestamp);
+    if key
+        .verify(&message, &Signature::from_bytes(&signature))
+        .is_err()
+    {
+        bail!("invalid signature");
+    }
+    Ok(())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The current day, in days since the unix epoch.
+fn today() -> u32 {
+    (unix_secs(SystemTime::now()) / SECS_PER_DAY) as u32
+}
+
+/// Format a day since the unix epoch as `YYYY-MM-DD`.
+fn date(day: u32) -> String {
+    time::OffsetDateTime::from_unix_timestamp(day as i64 * SECS_PER_DAY as i64)
+        .map(|time| time.date().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,145 @@
+//! Opt-in reports of anonymous aggregate statistics
+//!
+//! Only with an [`AnonymousStatsConfig`], the server posts a small JSON object with coarse
+//! statistics to the configured `endpoint` in an interval, to help the project understand how
+//! public deployments are doing. A report has exactly the fields of [`AnonymousStats`]: the
+//! version of the server, and buckets of the DNS queries per second and of the number of packets
+//! in the store. It has no names, keys, addresses or ids of the server, so the reports of a server
+//! can't be linked to each other by their contents.
+//!
+//! The statistics are not reported unless the `[anonymous_stats]` table is in the config.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{dns::DnsHandler, proxy::HttpClient, store::ZoneStore};
+
+/// Default interval in seconds of the reports, one day.
+const DEFAULT_INTERVAL_SECS: u64 = 24 * 60 * 60;
+/// Timeout for the report requests.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Config for the reports of anonymous statistics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnonymousStatsConfig {
+    /// The URL that the statistics are posted to.
+    pub endpoint: Url,
+    /// Interval in seconds of the reports (defaults to one day).
+    pub interval_secs: Option<u64>,
+    /// The proxy for the reports, e.g. `socks5h://127.0.0.1:9050` for Tor.
+    pub proxy: Option<Url>,
+}
+
+/// The statistics in a report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnonymousStats {
+    /// The version of the server
+    pub version: String,
+    /// The DNS queries per second in the last 10 minutes: `0`, `<1`, `1-10`, `10-100`,
+    /// `100-1000` or `1000+`
+    pub qps: String,
+    /// The number of packets in the store: `0`, `1-100`, `100-1k`, `1k-10k`, `10k-100k`,
+    /// `100k-1M` or `1M+`
+    pub packets: String,
+}
+
+impl AnonymousStats {
+    /// The statistics of a server with `queries` in the last `secs` and `packets` in the store.
+    pub fn new(queries: u64, secs: u64, packets: u64) -> Self {
+        let qps = queries as f64 / secs.max(1) as f64;
+        let qps = match qps {
+            _ if queries == 0 => "0",
+            qps if qps < 1.0 => "<1",
+            qps if qps < 10.0 => "1-10",
+            qps if qps < 100.0 => "10-100",
+            qps if qps < 1000.0 => "100-1000",
+            _ => "1000+",
+        };
+        let packets = match packets {
+            0 => "0",
+            1..=99 => "1-100",
+            100..=999 => "100-1k",
+            1_000..=9_999 => "1k-10k",
+            10_000..=99_999 => "10k-100k",
+            100_000..=999_999 => "100k-1M",
+            _ => "1M+",
+        };
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            qps: qps.to_string(),
+            packets: packets.to_string(),
+        }
+    }
+
+    /// The current statistics of the server.
+    async fn collect(dns_handler: &DnsHandler, store: &ZoneStore) -> Result<Self> {
+        let (queries, duration) = dns_handler.traffic().recent_queries();
+        Ok(Self::new(
+            queries,
+            duration.as_secs(),
+            store.packet_count().await?,
+        ))
+    }
+}
+
+/// Post the statistics to the endpoint in the interval, until the task is aborted.
+pub(crate) async fn run(config: AnonymousStatsConfig, dns_handler: DnsHandler, store: ZoneStore) {
+    let client = match HttpClient::new(config.proxy.as_ref(), REQUEST_TIMEOUT) {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("failed to create the client for the anonymous statistics: {err:#}");
+            return;
+        }
+    };
+    let interval = Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS));
+    info!(
+        endpoint = %config.endpoint,
+        "reporting anonymous statistics (version, queries per second and store size buckets)"
+    );
+    loop {
+        tokio::time::sleep(interval).await;
+        let stats = match AnonymousStats::collect(&dns_handler, &store).await {
+            Ok(stats) => stats,
+            Err(err) => {
+                debug!("failed to collect anonymous statistics: {err:#}");
+                continue;
+            }
+        };
+        // a failed report is not retried, the next one is sent in the interval
+        match client
+            .post(config.endpoint.clone())
+            .json(&stats)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+        {
+            Ok(_) => debug!(?stats, "reported anonymous statistics"),
+            Err(err) => debug!("failed to report anonymous statistics: {err:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let stats = AnonymousStats::new(0, 600, 0);
+        assert_eq!((stats.qps.as_str(), stats.packets.as_str()), ("0", "0"));
+        let stats = AnonymousStats::new(300, 600, 100);
+        assert_eq!(
+            (stats.qps.as_str(), stats.packets.as_str()),
+            ("<1", "100-1k")
+        );
+        let stats = AnonymousStats::new(60_000, 600, 1_000_000);
+        assert_eq!(
+            (stats.qps.as_str(), stats.packets.as_str()),
+            ("100-1000", "1M+")
+        );
+    }
+}
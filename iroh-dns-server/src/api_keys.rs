@@ -0,0 +1,382 @@
+//! API keys for authenticating clients of the HTTP API
+//!
+//! API keys are persisted in the same database as the signed packets, or in the Postgres store
+//! if the packets are kept there, so that all servers of the store accept them. Each key has a
+//! random id and secret; only a hash of the secret is stored, so the plain token is shown only
+//! once when the key is created or rotated.
+//!
+//! Keys are scoped: [`ApiKeyScope::Admin`] keys can access the admin API (see
+//! [`crate::http`]), [`ApiKeyScope::Publish`] keys are meant for publishing pkarr packets, and
+//! [`ApiKeyScope::Sync`] keys can read the change feed of the packets.
+
+use std::{path::Path, sync::Arc, time::SystemTime};
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::config::Config;
+#[cfg(feature = "postgres")]
+use crate::store::{StoreBackend, ZoneStore};
+
+const API_KEYS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("api-keys-1");
+
+/// Length of the random key id in bytes.
+const ID_LEN: usize = 8;
+/// Length of the random key secret in bytes.
+const SECRET_LEN: usize = 32;
+
+/// What an API key may be used for
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, strum::Display, strum::EnumString,
+)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// Publish pkarr signed packets
+    Publish,
+    /// Read the packet change feed at `/sync`
+    Sync,
+    /// Access the admin API. Implies all other scopes.
+    Admin,
+}
+
+/// An API key, without its secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct ApiKey {
+    /// The public id of the key
+    pub id: String,
+    /// A human readable name for the key
+    pub name: String,
+    /// The scopes the key is valid for
+    pub scopes: Vec<ApiKeyScope>,
+    /// Creation time, in seconds since the unix epoch
+    pub created_at: u64,
+    /// Time of the last rotation of the secret, in seconds since the unix epoch
+    pub rotated_at: Option<u64>,
+    /// Time of revocation, in seconds since the unix epoch
+    pub revoked_at: Option<u64>,
+}
+
+impl ApiKey {
+    /// Whether the key has not been revoked.
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none()
+    }
+
+    /// Whether the key is valid for `scope`.
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        self.scopes
+            .iter()
+            .any(|s| *s == scope || *s == ApiKeyScope::Admin)
+    }
+}
+
+/// A newly created or rotated API key, together with its token
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct NewApiKey {
+    /// The key
+    #[serde(flatten)]
+    pub key: ApiKey,
+    /// The token to authenticate with, as `<id>.<secret>`
+    ///
+    /// The token cannot be retrieved again later.
+    pub token: String,
+}
+
+/// An [`ApiKey`] as stored in the database.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredApiKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// Hex encoded blake3 hash of the secret
+    secret_hash: String,
+}
+
+/// A store for API keys.
+#[derive(Debug, Clone)]
+pub struct ApiKeyStore {
+    backend: Backend,
+}
+
+/// Where the API keys are persisted
+#[derive(derive_more::Debug, Clone)]
+enum Backend {
+    Database(Arc<Database>),
+    #[cfg(feature = "postgres")]
+    Postgres(#[debug("Pool")] deadpool_postgres::Pool),
+}
+
+impl ApiKeyStore {
+    /// Open the API keys in the database file at `path`.
+    ///
+    /// This fails if the database is already opened by a running server.
+    pub fn persistent(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        info!("loading api keys from {}", path.to_string_lossy());
+        let db = Database::builder()
+            .create(path)
+            .context("failed to open database")?;
+        Self::open(Arc::new(db))
+    }
+
+    /// Open the API keys of the server with `config` while it is not running.
+    ///
+    /// These are in the Postgres store if it is configured, and in the database file in the
+    /// data directory otherwise.
+    pub async fn for_config(config: &Config) -> Result<Self> {
+        #[cfg(feature = "postgres")]
+        if let Some(store) = &config.store {
+            if store.backend == StoreBackend::Postgres {
+                let store = ZoneStore::in_memory()?.with_backend(store).await?;
+                return Ok(store.api_keys().clone());
+            }
+        }
+        Self::persistent(config.signed_packet_store_path()?)
+    }
+
+    /// Open the API keys in `db`.
+    pub fn open(db: Arc<Database>) -> Result<Self> {
+        let write_tx = db.begin_write()?;
+        {
+            let _table = write_tx.open_table(API_KEYS_TABLE)?;
+        }
+        write_tx.commit()?;
+        Ok(Self {
+            backend: Backend::Database(db),
+        })
+    }
+
+    /// Use the API keys in the Postgres store of `pool`, whose schema has the table of the keys.
+    #[cfg(feature = "postgres")]
+    pub(crate) fn postgres(pool: deadpool_postgres::Pool) -> Self {
+        Self {
+            backend: Backend::Postgres(pool),
+        }
+    }
+
+    /// Create a new API key.
+    pub async fn create(&self, name: String, scopes: Vec<ApiKeyScope>) -> Result<NewApiKey> {
+        let id = hex::encode(random_bytes::<ID_LEN>());
+        let (secret, secret_hash) = new_secret();
+        let key = ApiKey {
+            id: id.clone(),
+            name,
+            scopes,
+            created_at: now(),
+            rotated_at: None,
+            revoked_at: None,
+        };
+        self.write(&StoredApiKey {
+            key: key.clone(),
+            secret_hash,
+        })
+        .await?;
+        Ok(NewApiKey {
+            token: token(&id, &secret),
+            key,
+        })
+    }
+
+    /// List all API keys, including revoked ones.
+    pub async fn list(&self) -> Result<Vec<ApiKey>> {
+        let mut values = Vec::new();
+        match &self.backend {
+            Backend::Database(db) => {
+                let tx = db.begin_read()?;
+                let table = tx.open_table(API_KEYS_TABLE)?;
+                for row in table.iter()? {
+                    let (_, value) = row?;
+                    values.push(value.value().to_vec());
+                }
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => {
+                let client = pool.get().await?;
+                for row in client
+                    .query("SELECT key FROM api_keys ORDER BY id", &[])
+                    .await?
+                {
+                    values.push(row.get(0));
+                }
+            }
+        }
+        values
+            .iter()
+            .map(|value| Ok(serde_json::from_slice::<StoredApiKey>(value)?.key))
+            .collect()
+    }
+
+    /// Get an API key by id.
+    pub async fn get(&self, id: &str) -> Result<Option<ApiKey>> {
+        Ok(self.read(id).await?.map(|stored| stored.key))
+    }
+
+    /// Replace the secret of an API key.
+    ///
+    /// Returns `None` if there is no active key with this id.
+    pub async fn rotate(&self, id: &str) -> Result<Option<NewApiKey>> {
+        let Some(mut stored) = self.read(id).await? else {
+            return Ok(None);
+        };
+        if !stored.key.is_active() {
+            return Ok(None);
+        }
+        let (secret, secret_hash) = new_secret();
+        stored.secret_hash = secret_hash;
+        stored.key.rotated_at = Some(now());
+        self.write(&stored).await?;
+        Ok(Some(NewApiKey {
+            token: token(id, &secret),
+            key: stored.key,
+        }))
+    }
+
+    /// Revoke an API key.
+    ///
+    /// Revoked keys are kept in the store, but can no longer be used. Returns whether an active
+    /// key was revoked.
+    pub async fn revoke(&self, id: &str) -> Result<bool> {
+        let Some(mut stored) = self.read(id).await? else {
+            return Ok(false);
+        };
+        if !stored.key.is_active() {
+            return Ok(false);
+        }
+        stored.key.revoked_at = Some(now());
+        self.write(&stored).await?;
+        Ok(true)
+    }
+
+    /// Verify a token and return its key, if the token is valid and the key is active.
+    pub async fn verify(&self, token: &str) -> Result<Option<ApiKey>> {
+        let Some((id, secret)) = token.split_once('.') else {
+            return Ok(None);
+        };
+        let Ok(secret) = hex::decode(secret) else {
+            return Ok(None);
+        };
+        let Some(stored) = self.read(id).await? else {
+            return Ok(None);
+        };
+        let Ok(expected) = blake3::Hash::from_hex(&stored.secret_hash) else {
+            return Ok(None);
+        };
+        // comparing `blake3::Hash`es is constant time
+        if blake3::hash(&secret) != expected || !stored.key.is_active() {
+            return Ok(None);
+        }
+        Ok(Some(stored.key))
+    }
+
+    async fn read(&self, id: &str) -> Result<Option<StoredApiKey>> {
+        let value = match &self.backend {
+            Backend::Database(db) => {
+                let tx = db.begin_read()?;
+                let table = tx.open_table(API_KEYS_TABLE)?;
+                table.get(id)?.map(|row| row.value().to_vec())
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => {
+                let client = pool.get().await?;
+                client
+                    .query_opt("SELECT key FROM api_keys WHERE id = $1", &[&id])
+                    .await?
+                    .map(|row| row.get(0))
+            }
+        };
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        Ok(Some(serde_json::from_slice(&value)?))
+    }
+
+    async fn write(&self, stored: &StoredApiKey) -> Result<()> {
+        let value = serde_json::to_vec(stored)?;
+        match &self.backend {
+            Backend::Database(db) => {
+                let tx = db.begin_write()?;
+                {
+                    let mut table = tx.open_table(API_KEYS_TABLE)?;
+                    table.insert(stored.key.id.as_str(), &value[..])?;
+                }
+                tx.commit()?;
+            }
+            #[cfg(feature = "postgres")]
+            Backend::Postgres(pool) => {
+                let client = pool.get().await?;
+                client
+                    .execute(
+                        "INSERT INTO api_keys (id, key) VALUES ($1, $2)
+                         ON CONFLICT (id) DO UPDATE SET key = EXCLUDED.key",
+                        &[&stored.key.id, &value],
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Create a new secret and its hex encoded hash.
+fn new_secret() -> ([u8; SECRET_LEN], String) {
+    let secret = random_bytes::<SECRET_LEN>();
+    let hash = blake3::hash(&secret).to_hex().to_string();
+    (secret, hash)
+}
+
+fn token(id: &str, secret: &[u8]) -> String {
+    format!("{id}.{}", hex::encode(secret))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::backends::InMemoryBackend;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn create_rotate_revoke() -> Result<()> {
+        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        let store = ApiKeyStore::open(Arc::new(db))?;
+
+        let new = store
+            .create("test".to_string(), vec![ApiKeyScope::Publish])
+            .await?;
+        let key = store.verify(&new.token).await?.expect("valid token");
+        assert!(key.has_scope(ApiKeyScope::Publish));
+        assert!(!key.has_scope(ApiKeyScope::Admin));
+        assert!(store.verify(&format!("{}.00", new.key.id)).await?.is_none());
+
+        let rotated = store.rotate(&new.key.id).await?.expect("active key");
+        assert!(store.verify(&new.token).await?.is_none());
+        assert!(store.verify(&rotated.token).await?.is_some());
+
+        assert!(store.revoke(&new.key.id).await?);
+        assert!(!store.revoke(&new.key.id).await?);
+        assert!(store.verify(&rotated.token).await?.is_none());
+        assert!(store.rotate(&new.key.id).await?.is_none());
+        assert_eq!(store.list().await?.len(), 1);
+        Ok(())
+    }
+}
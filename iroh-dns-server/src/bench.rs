@@ -0,0 +1,267 @@
+//! Load generation against a server, for the `bench` command
+//!
+//! Requests are sent at a fixed rate, independent of how fast the server answers, for a set of
+//! node keys that are published before the run. The latencies of the answered requests are
+//! reported as percentiles, and failed requests by their error.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use iroh_net::{
+    dns::node_info::{self, NodeInfo, IROH_TXT_NAME},
+    key::SecretKey,
+};
+use parking_lot::Mutex;
+use tokio::{net::UdpSocket, sync::Semaphore, task::JoinSet};
+use url::Url;
+
+/// Timeout of each request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// The percentiles of the latencies in the report.
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// What requests to send
+#[derive(Debug, Clone)]
+pub enum BenchTarget {
+    /// TXT queries for the `_iroh` records of the keys, to the DNS server at the address
+    Udp {
+        /// The address of the DNS server
+        addr: SocketAddr,
+        /// The origin to query the keys under
+        origin: String,
+    },
+    /// Publishes of new packets of the keys
+    Put,
+    /// Lookups of the packets of the keys with the pkarr relay API
+    Get,
+}
+
+/// Config of a load test
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// The base URL of the server, for publishes and lookups, and to publish the keys before the
+    /// run
+    pub server: Option<Url>,
+    /// What requests to send
+    pub target: BenchTarget,
+    /// The number of requests per second
+    pub qps: u32,
+    /// How long to send requests for
+    pub duration: Duration,
+    /// The number of keys the requests are spread over
+    pub keys: usize,
+    /// The maximum number of requests that wait for an answer; requests beyond are skipped
+    pub max_in_flight: usize,
+}
+
+/// The result of a load test
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    /// The number of requests that were answered successfully
+    pub ok: u64,
+    /// The number of failed requests, by error
+    pub errors: BTreeMap<String, u64>,
+    /// The number of requests that were not sent, because too many were in flight
+    pub skipped: u64,
+    /// How long the run took, until the last answer
+    pub elapsed: Duration,
+    /// The latencies of the successful requests, sorted
+    pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    /// The latency below which `percentile` percent of the successful requests were answered.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        let index = ((percentile / 100.0) * last as f64).round() as usize;
+        self.latencies.get(index.min(last)).copied()
+    }
+
+    fn record(&mut self, res: Result<Duration>) {
+        match res {
+            Ok(latency) => {
+                self.ok += 1;
+                self.latencies.push(latency);
+            }
+            Err(err) => *self.errors.entry(format!("{err:#}")).or_default() += 1,
+        }
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed: u64 = self.errors.values().sum();
+        let secs = self.elapsed.as_secs_f64();
+        writeln!(
+            f,
+            "requests: {} ok, {failed} failed, {} skipped in {secs:.1}s ({:.0}/s answered)",
+            self.ok,
+            self.skipped,
+            self.ok as f64 / secs.max(f64::EPSILON)
+        )?;
+        for percentile in PERCENTILES {
+            if let Some(latency) = self.percentile(percentile) {
+                writeln!(
+                    f,
+                    "  p{percentile:<5} {:>9.2} ms",
+                    latency.as_secs_f64() * 1000.0
+                )?;
+            }
+        }
+        if let Some(max) = self.latencies.last() {
+            writeln!(f, "  max    {:>9.2} ms", max.as_secs_f64() * 1000.0)?;
+        }
+        for (err, count) in &self.errors {
+            writeln!(f, "  {count} x {err}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Run a load test.
+pub async fn run(config: BenchConfig) -> Result<BenchReport> {
+    if config.qps == 0 || config.keys == 0 {
+        bail!("the rate and the number of keys must be at least 1");
+    }
+    if config.qps > 1_000_000 {
+        bail!("the rate must be at most 1000000 requests per second");
+    }
+    let keys: Arc<Vec<SecretKey>> =
+        Arc::new((0..config.keys).map(|_| SecretKey::generate()).collect());
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+    let server = config.server.clone();
+    if matches!(config.target, BenchTarget::Put | BenchTarget::Get) && server.is_none() {
+        bail!("publishes and lookups need the URL of the server");
+    }
+    if let Some(server) = &server {
+        // lookups of keys that were never published would only measure misses
+        for key in keys.iter() {
+            put(&client, server, key)
+                .await
+                .context("failed to publish the keys before the run")?;
+        }
+    }
+
+    let report = Arc::new(Mutex::new(BenchReport::default()));
+    let in_flight = Arc::new(Semaphore::new(config.max_in_flight));
+    let mut tasks = JoinSet::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1) / config.qps);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Burst);
+    let total = (config.duration.as_secs_f64() * config.qps as f64) as u64;
+    let start = Instant::now();
+    for i in 0..total {
+        ticker.tick().await;
+        let Ok(permit) = in_flight.clone().try_acquire_owned() else {
+            report.lock().skipped += 1;
+            continue;
+        };
+        let key = keys[i as usize % keys.len()].clone();
+        let client = client.clone();
+        let server = server.clone();
+        let target = config.target.clone();
+        let report = report.clone();
+        tasks.spawn(async move {
+            let start = Instant::now();
+            let res = match &target {
+                BenchTarget::Udp { addr, origin } => query(*addr, origin, &key).await,
+                BenchTarget::Put => put(&client, server.as_ref().expect("checked"), &key).await,
+                BenchTarget::Get => get(&client, server.as_ref().expect("checked"), &key).await,
+            };
+            report.lock().record(res.map(|()| start.elapsed()));
+            drop(permit);
+        });
+        // reap the finished tasks, so that long runs don't accumulate them
+        while tasks.try_join_next().is_some() {}
+    }
+    while tasks.join_next().await.is_some() {}
+    let mut report = std::mem::take(&mut *report.lock());
+    report.elapsed = start.elapsed();
+    report.latencies.sort();
+    Ok(report)
+}
+
+async fn put(client: &reqwest::Client, server: &Url, key: &SecretKey) -> Result<()> {
+    let info = NodeInfo::new(
+        key.public(),
+        None,
+        [SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 4433))].into(),
+    );
+    let signed_packet = info.to_pkarr_signed_packet(key, 30)?;
+    let url = server.join(&format!("pkarr/{}", signed_packet.public_key().to_z32()))?;
+    let res = client
+        .put(url)
+        .body(signed_packet.to_relay_payload())
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        bail!("status {}", res.status());
+    }
+    Ok(())
+}
+
+async fn get(client: &reqwest::Client, server: &Url, key: &SecretKey) -> Result<()> {
+    let url = server.join(&format!("pkarr/{}", node_info::to_z32(&key.public())))?;
+    let res = client.get(url).send().await?;
+    if !res.status().is_success() {
+        bail!("status {}", res.status());
+    }
+    res.bytes().await?;
+    Ok(())
+}
+
+async fn query(addr: SocketAddr, origin: &str, key: &SecretKey) -> Result<()> {
+    let name = Name::parse(
+        &format!(
+            "{IROH_TXT_NAME}.{}.{origin}",
+            node_info::to_z32(&key.public())
+        ),
+        Some(&Name::root()),
+    )?;
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .add_query(Query::query(name, RecordType::TXT));
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    socket.send(&message.to_vec()?).await?;
+    let mut buf = vec![0; 4096];
+    let len = tokio::time::timeout(TIMEOUT, socket.recv(&mut buf))
+        .await
+        .context("timed out")??;
+    let response = Message::from_vec(&buf[..len])?;
+    if response.response_code() != ResponseCode::NoError {
+        bail!("{}", response.response_code());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let report = BenchReport {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..Default::default()
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(51)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+        assert_eq!(BenchReport::default().percentile(50.0), None);
+    }
+}
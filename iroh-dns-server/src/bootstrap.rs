@@ -0,0 +1,376 @@
+//! Refresh of the bootstrap nodes of the mainline DHT
+//!
+//! The mainline DHT client joins the DHT through a few bootstrap hosts, which are hard-coded in
+//! pkarr unless configured. To survive these hosts going away, the server periodically asks the
+//! bootstrap nodes for other nodes of the DHT, and persists those that answer in the data
+//! directory. The persisted nodes are used as bootstrap nodes in addition to the configured ones
+//! at the next start, and as the starting point of the next refresh.
+//!
+//! Each refresh also checks the health of the bootstrap nodes: whether each node answered is
+//! exported as the `peer_up` metric, and the learned nodes that stopped answering are removed
+//! from the rotation of the next refreshes. The configured and default nodes are always kept.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use mainline::{
+    rpc::{
+        messages::{
+            FindNodeRequestArguments, Message, MessageType, RequestSpecific, RequestTypeSpecific,
+        },
+        DEFAULT_BOOTSTRAP_NODES,
+    },
+    Id,
+};
+use tokio::{net::UdpSocket, time::Instant};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{BootstrapOption, Config, MainlineConfig},
+    metrics::{PeerKind, PeerMetrics},
+};
+
+/// The file in the data directory with the persisted nodes
+const NODES_FILE: &str = "mainline-bootstrap-nodes.txt";
+/// Default interval of the refresh.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Time to wait for the answers of the nodes.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum number of persisted nodes.
+const MAX_NODES: usize = 32;
+
+/// Get the bootstrap nodes of the mainline DHT client, or `None` if the mainline fallback is
+/// disabled.
+///
+/// These are the nodes of [`MainlineConfig::bootstrap_file`] if set, and otherwise the
+/// configured or default nodes followed by the persisted nodes.
+pub(crate) fn bootstrap_option(config: &Config) -> Result<Option<BootstrapOption>> {
+    let Some(bootstrap) = config.mainline_enabled() else {
+        return Ok(None);
+    };
+    let mainline = config.mainline.as_ref().expect("mainline is enabled");
+    if let Some(path) = &mainline.bootstrap_file {
+        let nodes = read_nodes(path)?
+            .with_context(|| format!("bootstrap file {} not found", path.display()))?;
+        return Ok(Some(BootstrapOption::Custom(nodes)));
+    }
+    let persisted = read_nodes(&nodes_path(config)?)?.unwrap_or_default();
+    if persisted.is_empty() {
+        return Ok(Some(bootstrap));
+    }
+    let mut nodes = match bootstrap {
+        BootstrapOption::Default => DEFAULT_BOOTSTRAP_NODES.map(String::from).to_vec(),
+        BootstrapOption::Custom(nodes) => nodes,
+    };
+    debug!("bootstrapping from {} persisted nodes", persisted.len());
+    nodes.extend(persisted);
+    Ok(Some(BootstrapOption::Custom(nodes)))
+}
+
+/// Refresh the persisted nodes periodically, starting from the bootstrap nodes.
+///
+/// Returns immediately if the refresh is disabled.
+pub(crate) async fn run(config: &MainlineConfig, bootstrap: BootstrapOption, data_dir: &Path) {
+    let interval = match config.bootstrap_refresh_secs {
+        Some(0) => return,
+        Some(secs) => Duration::from_secs(secs),
+        None => DEFAULT_REFRESH_INTERVAL,
+    };
+    let mut nodes = match bootstrap {
+        BootstrapOption::Default => DEFAULT_BOOTSTRAP_NODES.map(String::from).to_vec(),
+        BootstrapOption::Custom(nodes) => nodes,
+    };
+    let path = data_dir.join(NODES_FILE);
+    // the persisted nodes were learned by earlier refreshes, the others are configured
+    let learned = read_nodes(&path)
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let configured = nodes
+        .iter()
+        .filter(|node| !learned.contains(*node))
+        .cloned()
+        .collect::<HashSet<_>>();
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match refresh(&nodes, &path).await {
+            Ok(refreshed) => {
+                for node in &nodes {
+                    let up = refreshed.answered.contains(node);
+                    PeerMetrics::set_up(PeerKind::MainlineBootstrap, node, up);
+                }
+                // learned nodes that don't answer anymore are not asked again
+                nodes.retain(|node| configured.contains(node) || refreshed.answered.contains(node));
+                if refreshed.found.is_empty() {
+                    warn!("no mainline DHT node answered, keeping the persisted bootstrap nodes");
+                    continue;
+                }
+                info!(
+                    "persisted {} mainline DHT bootstrap nodes",
+                    refreshed.found.len()
+                );
+                // later refreshes also start from the persisted nodes
+                for node in refreshed.found {
+                    if !nodes.contains(&node) {
+                        nodes.push(node);
+                    }
+                }
+            }
+            Err(err) => warn!("failed to refresh the mainline DHT bootstrap nodes: {err:#}"),
+        }
+    }
+}
+
+/// The result of a refresh
+#[derive(Debug, Default)]
+struct Refreshed {
+    /// The persisted nodes
+    found: Vec<String>,
+    /// The bootstrap nodes that answered
+    answered: HashSet<String>,
+}
+
+/// Ask the bootstrap nodes of `config` for other nodes, or return `None` if the mainline fallback
+/// is disabled.
+///
+/// Returns the number of addresses of the bootstrap nodes, and how many of them answered.
+pub(crate) async fn check(config: &Config) -> Result<Option<(usize, usize)>> {
+    let nodes = match bootstrap_option(config)? {
+        None => return Ok(None),
+        Some(BootstrapOption::Default) => DEFAULT_BOOTSTRAP_NODES.map(String::from).to_vec(),
+        Some(BootstrapOption::Custom(nodes)) => nodes,
+    };
+    let addrs = resolve_nodes(&nodes)
+        .await
+        .into_iter()
+        .map(|(addr, _)| addr)
+        .collect::<Vec<_>>();
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let answered = find_nodes(&socket, &addrs).await?;
+    Ok(Some((addrs.len(), answered.len())))
+}
+
+/// Look up the nodes that answer, starting from `bootstrap`, and persist them in `path`.
+///
+/// Leaves the file as it is if no node answered.
+async fn refresh(bootstrap: &[String], path: &Path) -> Result<Refreshed> {
+    let resolved = resolve_nodes(bootstrap).await;
+    let addrs = resolved.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+    let names = resolved.into_iter().collect::<HashMap<_, _>>();
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    // the bootstrap nodes return the nodes closest to the target, which are then asked the same
+    let answered = find_nodes(&socket, &addrs).await?;
+    let closest = answered
+        .iter()
+        .flat_map(|(_, nodes)| nodes)
+        .filter(|addr| addr.is_ipv4())
+        .copied()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut good = HashSet::new();
+    let mut found = Vec::new();
+    for addr in answered.iter().map(|(addr, _)| *addr).chain(
+        find_nodes(&socket, &closest)
+            .await?
+            .into_iter()
+            .map(|(addr, _)| addr),
+    ) {
+        if found.len() < MAX_NODES && good.insert(addr) {
+            found.push(addr.to_string());
+        }
+    }
+    if !found.is_empty() {
+        write_nodes(path, &found)?;
+    }
+    let answered = answered
+        .iter()
+        .filter_map(|(addr, _)| names.get(addr).map(|node| node.to_string()))
+        .collect();
+    Ok(Refreshed { found, answered })
+}
+
+/// Resolve the IPv4 addresses of `nodes`, skipping the nodes that don't resolve.
+///
+/// Returns each address with the node it was resolved from.
+async fn resolve_nodes(nodes: &[String]) -> Vec<(SocketAddr, &str)> {
+    let mut addrs = Vec::new();
+    for node in nodes {
+        match tokio::net::lookup_host(node).await {
+            Ok(resolved) => addrs.extend(
+                resolved
+                    .filter(SocketAddr::is_ipv4)
+                    .map(|addr| (addr, node.as_str())),
+            ),
+            Err(err) => debug!("failed to resolve bootstrap node {node}: {err}"),
+        }
+    }
+    addrs
+}
+
+/// Send a `find_node` request to each of `addrs`.
+///
+/// Returns the addresses that answered within [`RESPONSE_TIMEOUT`], with the addresses of the
+/// nodes they returned.
+async fn find_nodes(
+    socket: &UdpSocket,
+    addrs: &[SocketAddr],
+) -> Result<Vec<(SocketAddr, Vec<SocketAddr>)>> {
+    let request = Message {
+        transaction_id: rand::random(),
+        version: None,
+        requester_ip: None,
+        message_type: MessageType::Request(RequestSpecific {
+            requester_id: Id::random(),
+            request_type: RequestTypeSpecific::FindNode(FindNodeRequestArguments {
+                target: Id::random(),
+            }),
+        }),
+        // this is not a DHT node, so it should not be added to routing tables
+        read_only: true,
+    };
+    let bytes = request.to_bytes()?;
+    let mut pending = HashSet::new();
+    for addr in addrs {
+        match socket.send_to(&bytes, addr).await {
+            Ok(_) => {
+                pending.insert(*addr);
+            }
+            Err(err) => debug!("failed to send find_node to {addr}: {err}"),
+        }
+    }
+    let mut answered = Vec::new();
+    let deadline = Instant::now() + RESPONSE_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    while !pending.is_empty() {
+        let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        else {
+            break;
+        };
+        let (len, from) = received?;
+        let Ok(message) = Message::from_bytes(&buf[..len]) else {
+            continue;
+        };
+        if message.transaction_id != request.transaction_id || !pending.remove(&from) {
+            continue;
+        }
+        let Some(nodes) = message.get_closer_nodes() else {
+            continue;
+        };
+        let nodes = nodes.into_iter().map(|node| node.address).collect();
+        answered.push((from, nodes));
+    }
+    Ok(answered)
+}
+
+/// The path of the persisted nodes.
+fn nodes_path(config: &Config) -> Result<PathBuf> {
+    Ok(config.data_dir()?.join(NODES_FILE))
+}
+
+/// Read a file with one node per line, or `None` if it doesn't exist.
+fn read_nodes(path: &Path) -> Result<Option<Vec<String>>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to read {}", path.display()));
+        }
+    };
+    let nodes = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+    Ok(Some(nodes))
+}
+
+/// Replace the file at `path` with the nodes.
+fn write_nodes(path: &Path, nodes: &[String]) -> Result<()> {
+    let mut contents =
+        String::from("# mainline DHT nodes that answered, refreshed by the server\n");
+    for node in nodes {
+        contents.push_str(node);
+        contents.push('\n');
+    }
+    // written to a temporary file first, so that the file is never read half-written
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persisted_nodes_are_added() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bootstrap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let mut config = Config {
+            data_dir: Some(dir.clone()),
+            mainline: Some(MainlineConfig {
+                enabled: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            bootstrap_option(&config)?,
+            Some(BootstrapOption::Default)
+        ));
+
+        write_nodes(&dir.join(NODES_FILE), &["192.0.2.1:6881".to_string()])?;
+        let Some(BootstrapOption::Custom(nodes)) = bootstrap_option(&config)? else {
+            panic!("expected custom bootstrap nodes");
+        };
+        assert_eq!(nodes.len(), DEFAULT_BOOTSTRAP_NODES.len() + 1);
+        assert_eq!(nodes.last().unwrap(), "192.0.2.1:6881");
+
+        // the bootstrap file replaces all other nodes
+        let file = dir.join("bootstrap.txt");
+        std::fs::write(&file, "# override\n198.51.100.1:6881\n\n")?;
+        config.mainline.as_mut().unwrap().bootstrap_file = Some(file);
+        let Some(BootstrapOption::Custom(nodes)) = bootstrap_option(&config)? else {
+            panic!("expected custom bootstrap nodes");
+        };
+        assert_eq!(nodes, vec!["198.51.100.1:6881".to_string()]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_from_testnet() -> Result<()> {
+        let testnet = mainline::Testnet::new(5);
+        // let the nodes find each other
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let dir = std::env::temp_dir().join(format!("bootstrap-refresh-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(NODES_FILE);
+
+        // a node that does not answer is reported as down
+        let gone = "127.0.0.1:1".to_string();
+        let mut bootstrap = testnet.bootstrap.clone();
+        bootstrap.push(gone.clone());
+        let refreshed = refresh(&bootstrap, &path).await?;
+        let found = refreshed.found;
+        assert!(found.len() > 1, "found {found:?}");
+        assert!(found.contains(&testnet.bootstrap[0]));
+        assert!(refreshed.answered.contains(&testnet.bootstrap[0]));
+        assert!(!refreshed.answered.contains(&gone));
+        assert_eq!(read_nodes(&path)?, Some(found));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
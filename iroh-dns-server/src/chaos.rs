@@ -0,0 +1,212 @@
+//! Fault injection, to test the resilience of clients
+//!
+//! With the `chaos` feature, the server can misbehave on purpose: for each [`ChaosEndpoint`],
+//! the admin API sets [`Faults`] that delay the requests, and drop them, answer them with an
+//! error or rate limit them at random. This lets the tests of iroh clients check that the
+//! fallback resolvers and retries work against a realistic server. No faults are set on start.
+//!
+//! The feature is for tests only, and must not be enabled in production builds.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use anyhow::{ensure, Result};
+use hickory_proto::op::{Header, ResponseCode};
+use hickory_server::{
+    authority::MessageResponseBuilder,
+    server::{Request, ResponseHandler, ResponseInfo},
+};
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// An endpoint of the server that faults can be injected into
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, strum::Display,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ChaosEndpoint {
+    /// DNS queries over UDP and TCP
+    Dns,
+    /// DNS-over-HTTPS queries, at `/dns-query` and `/resolve`
+    Doh,
+    /// `GET /pkarr/{key}`
+    PkarrGet,
+    /// `PUT /pkarr/{key}`
+    PkarrPut,
+}
+
+/// The faults of an endpoint
+///
+/// A request is delayed by `latency_ms` plus a random `jitter_ms`, and then dropped, failed or
+/// rate limited with the rates, which must add up to at most 1.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Faults {
+    /// Delay of every request, in milliseconds
+    pub latency_ms: u64,
+    /// Maximum random delay added to the latency, in milliseconds
+    pub jitter_ms: u64,
+    /// Fraction of the requests that are not answered
+    ///
+    /// HTTP requests are held until the request timeout.
+    pub drop_rate: f64,
+    /// Fraction of the requests that are answered with `SERVFAIL`, or `503 Service Unavailable`
+    /// over HTTP
+    pub servfail_rate: f64,
+    /// Fraction of the requests that are answered with `REFUSED`, or `429 Too Many Requests`
+    /// over HTTP
+    pub rate_limit_rate: f64,
+}
+
+impl Faults {
+    /// Check that the rates are fractions that add up to at most 1.
+    pub fn validate(&self) -> Result<()> {
+        let rates = [self.drop_rate, self.servfail_rate, self.rate_limit_rate];
+        ensure!(
+            rates.iter().all(|rate| (0.0..=1.0).contains(rate)),
+            "the rates must be between 0 and 1"
+        );
+        ensure!(
+            rates.iter().sum::<f64>() <= 1.0,
+            "the rates must add up to at most 1"
+        );
+        Ok(())
+    }
+
+    /// Choose the fault of a request, if any.
+    fn choose(&self) -> Option<Fault> {
+        let roll: f64 = rand::thread_rng().gen();
+        if roll < self.drop_rate {
+            Some(Fault::Drop)
+        } else if roll < self.drop_rate + self.servfail_rate {
+            Some(Fault::ServFail)
+        } else if roll < self.drop_rate + self.servfail_rate + self.rate_limit_rate {
+            Some(Fault::RateLimit)
+        } else {
+            None
+        }
+    }
+
+    /// The delay of a request.
+    fn delay(&self) -> Duration {
+        let jitter = match self.jitter_ms {
+            0 => 0,
+            jitter_ms => rand::thread_rng().gen_range(0..=jitter_ms),
+        };
+        Duration::from_millis(self.latency_ms + jitter)
+    }
+}
+
+/// A fault injected into a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// The request is not answered
+    Drop,
+    /// The request fails with a server error
+    ServFail,
+    /// The request is rate limited
+    RateLimit,
+}
+
+/// The faults of the endpoints, shared by the DNS and HTTP servers
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Chaos {
+    faults: Arc<RwLock<BTreeMap<ChaosEndpoint, Faults>>>,
+}
+
+impl Chaos {
+    /// Get the faults of all endpoints that have faults.
+    pub(crate) fn faults(&self) -> BTreeMap<ChaosEndpoint, Faults> {
+        self.faults.read().clone()
+    }
+
+    /// Set the faults of `endpoint`.
+    pub(crate) fn set(&self, endpoint: ChaosEndpoint, faults: Faults) -> Result<()> {
+        faults.validate()?;
+        debug!(%endpoint, ?faults, "injecting faults");
+        self.faults.write().insert(endpoint, faults);
+        Ok(())
+    }
+
+    /// Remove the faults of `endpoint`, or of all endpoints if it is `None`.
+    pub(crate) fn clear(&self, endpoint: Option<ChaosEndpoint>) {
+        match endpoint {
+            Some(endpoint) => {
+                self.faults.write().remove(&endpoint);
+            }
+            None => self.faults.write().clear(),
+        }
+    }
+
+    /// Delay a request to `endpoint`, and choose its fault.
+    pub(crate) async fn inject(&self, endpoint: ChaosEndpoint) -> Option<Fault> {
+        let faults = self.faults.read().get(&endpoint).cloned()?;
+        let delay = faults.delay();
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        faults.choose()
+    }
+}
+
+/// Answer a DNS request with `fault`.
+pub(crate) async fn answer_dns<R: ResponseHandler>(
+    fault: Fault,
+    request: &Request,
+    mut response_handle: R,
+) -> ResponseInfo {
+    let response_code = match fault {
+        Fault::Drop => {
+            debug!("dropping DNS request");
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(ResponseCode::ServFail);
+            return header.into();
+        }
+        Fault::ServFail => ResponseCode::ServFail,
+        Fault::RateLimit => ResponseCode::Refused,
+    };
+    debug!("answering DNS request with injected {response_code}");
+    let response = MessageResponseBuilder::from_message_request(request)
+        .error_msg(request.header(), response_code);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(err) => {
+            debug!("failed to send {response_code} response: {err}");
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(response_code);
+            header.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rates() {
+        let faults = Faults {
+            drop_rate: 0.5,
+            servfail_rate: 0.5,
+            ..Default::default()
+        };
+        assert!(faults.validate().is_ok());
+        let faults = Faults {
+            rate_limit_rate: 0.1,
+            ..faults
+        };
+        assert!(faults.validate().is_err());
+        let faults = Faults {
+            drop_rate: -0.5,
+            ..Default::default()
+        };
+        assert!(faults.validate().is_err());
+        let faults = Faults {
+            servfail_rate: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(faults.choose(), Some(Fault::ServFail));
+    }
+}
@@ -1,21 +1,75 @@
 //! Configuration for the server
 
 use std::{
+    collections::BTreeMap,
     env,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
 use crate::{
-    dns::DnsConfig,
-    http::{CertMode, HttpConfig, HttpsConfig, RateLimitConfig},
+    abuse::{AbuseLogConfig, IpReputationConfig},
+    acl::AclConfig,
+    analytics::AnalyticsConfig,
+    anonymous_stats::AnonymousStatsConfig,
+    dns::{DnsConfig, ReplicasConfig, SecondaryConfig, TransferConfig},
+    gossip::GossipConfig,
+    health::HealthConfig,
+    history::HistoryConfig,
+    hourly_stats::HourlyStatsConfig,
+    http::{
+        AccessLogConfig, AdminConfig, BehindProxyConfig, CertMode, CompressionConfig, HttpConfig,
+        HttpLimitsConfig, HttpsConfig, RateLimitConfig, RateLimitMode,
+    },
+    mirror::MirrorConfig,
+    probe::ProbeConfig,
+    query_log::QueryLogConfig,
+    rate_limit_state::RateLimitStateConfig,
+    retention::RetentionConfig,
+    ring::RingConfig,
+    sandbox::SandboxConfig,
+    secrets::SecretValue,
+    slow_log::SlowLogConfig,
+    store::StoreConfig,
+    sync::SyncConfig,
+    telemetry::{LoggingConfig, OtlpConfig, SentryConfig},
+    tenants::TenantConfig,
+    usage::UsageConfig,
 };
 
+mod check;
+mod init;
+mod interpolate;
+
+pub use self::init::Profile;
+
 const DEFAULT_METRICS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9117);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_QUEUED_MAINLINE_LOOKUPS: usize = 256;
+/// The prefix of environment variables that override config fields, e.g. `IROH_DNS__HTTP__PORT`.
+const ENV_OVERRIDE_PREFIX: &str = "IROH_DNS__";
+/// The separator of the keys in the name of an override variable.
+const ENV_OVERRIDE_SEPARATOR: &str = "__";
+
+/// Overrides of config fields, set with [`set_overrides`].
+static OVERRIDES: OnceLock<Vec<(String, toml::Value)>> = OnceLock::new();
+
+/// Set overrides of config fields, e.g. from command line flags, by dotted key, e.g. `dns.port`.
+///
+/// They take precedence over the config file and the environment variables, and are also
+/// applied when the config is reloaded. They can only be set once.
+pub fn set_overrides(overrides: Vec<(String, toml::Value)>) -> Result<()> {
+    OVERRIDES
+        .set(overrides)
+        .map_err(|_| anyhow!("config overrides are already set"))
+}
 
 /// Server configuration
 ///
@@ -43,15 +97,337 @@ pub struct Config {
 
     /// Config for the mainline lookup.
     pub mainline: Option<MainlineConfig>,
+
+    /// Config for the HTTP access log.
+    ///
+    /// If set to `None` no access log is written.
+    pub access_log: Option<AccessLogConfig>,
+
+    /// Config for compression of HTTP responses.
+    ///
+    /// If set to `None` responses are not compressed.
+    pub compression: Option<CompressionConfig>,
+
+    /// Config for timeouts and connection limits of the HTTP and HTTPS servers.
+    ///
+    /// If set to `None` the default timeouts apply, and the number of connections is not
+    /// limited.
+    pub http_limits: Option<HttpLimitsConfig>,
+
+    /// Config for the limits of concurrent work across all listeners.
+    ///
+    /// If set to `None` the server accepts as much work as it is given.
+    pub resource_limits: Option<ResourceLimitsConfig>,
+
+    /// Config for the accepted clock skew of the timestamps of published packets.
+    ///
+    /// If set to `None` packets with any timestamp are accepted.
+    pub clock_skew: Option<ClockSkewConfig>,
+
+    /// Config for running behind reverse proxies.
+    ///
+    /// If set to `None` proxy headers are ignored, and the connection peer is the client.
+    pub behind_proxy: Option<BehindProxyConfig>,
+
+    /// Config for exporting traces via OpenTelemetry.
+    ///
+    /// If set to `None` no traces are exported.
+    pub otlp: Option<OtlpConfig>,
+
+    /// Config for the log output.
+    ///
+    /// If set to `None` text is logged, filtered by the `RUST_LOG` environment variable.
+    pub logging: Option<LoggingConfig>,
+
+    /// Config for reporting panics and errors to Sentry.
+    ///
+    /// If set to `None` errors are only logged.
+    pub sentry: Option<SentryConfig>,
+
+    /// Config for logging slow DNS queries and publishes.
+    ///
+    /// If set to `None` slow queries are not logged.
+    pub slow_log: Option<SlowLogConfig>,
+
+    /// Config for exporting sampled DNS query records.
+    ///
+    /// If set to `None` no query records are exported.
+    pub query_log: Option<QueryLogConfig>,
+
+    /// Config for the end-to-end self-check probe.
+    ///
+    /// If set to `None` the server does not check itself.
+    pub probe: Option<ProbeConfig>,
+
+    /// Config for replicating published packets with other servers over gossip.
+    ///
+    /// If set to `None` packets are only published to this server.
+    pub gossip: Option<GossipConfig>,
+
+    /// Config for serving zone transfers to secondaries.
+    ///
+    /// If set to `None` zone transfers are refused.
+    pub transfer: Option<TransferConfig>,
+
+    /// Config for running as a read-only secondary that transfers the zones from a primary.
+    ///
+    /// If set to `None` the server serves the packets in its store.
+    pub secondary: Option<SecondaryConfig>,
+
+    /// Config for withdrawing the service while the store or the DHT is unhealthy.
+    ///
+    /// If set to `None` the server is always ready.
+    pub health: Option<HealthConfig>,
+
+    /// Config for partitioning the pubkeys over a fleet of servers with a consistent hash ring.
+    ///
+    /// If set to `None` the server stores the packets of all pubkeys.
+    pub ring: Option<RingConfig>,
+
+    /// Config for tailing the packet change feed of another server.
+    ///
+    /// If set to `None` packets are only stored as they are published to this server.
+    pub sync: Option<SyncConfig>,
+
+    /// Config for advertising the replicas of the server in SRV and HTTPS records.
+    ///
+    /// If set to `None` no replica records are served.
+    pub replicas: Option<ReplicasConfig>,
+
+    /// Config for removing packets that were not republished for a long time.
+    ///
+    /// If set to `None` packets are kept until they are replaced.
+    pub retention: Option<RetentionConfig>,
+
+    /// Config for keeping the previous versions of the packets.
+    ///
+    /// If set to `None` only the current packet of each pubkey is kept.
+    pub history: Option<HistoryConfig>,
+
+    /// Config for counting the resolutions of the packets, for their owners.
+    ///
+    /// If set to `None` resolutions are not counted.
+    pub analytics: Option<AnalyticsConfig>,
+
+    /// Config for the persistence of the rate limits across restarts.
+    ///
+    /// If set to `None` the used budgets of the rate limits are reset on restart.
+    pub rate_limit_state: Option<RateLimitStateConfig>,
+
+    /// Config for the hourly statistics of the queries and publishes.
+    ///
+    /// If set to `None` no statistics are kept.
+    pub hourly_stats: Option<HourlyStatsConfig>,
+
+    /// The tenants of a server that is shared by many customers, as `[[tenants]]` tables.
+    ///
+    /// Each tenant has its own quota, publish rate and metrics labels, and optionally its own
+    /// packet database, see [`crate::tenants`].
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+
+    /// Config for the usage reports of the tenants and API keys, for billing.
+    ///
+    /// If set to `None` the usage is not counted.
+    pub usage: Option<UsageConfig>,
+
+    /// Config for reporting anonymous aggregate statistics to the project.
+    ///
+    /// If set to `None`, the default, no statistics are reported.
+    pub anonymous_stats: Option<AnonymousStatsConfig>,
+
+    /// Config for mirroring the published records to an external DNS provider.
+    ///
+    /// If set to `None` the records are not mirrored.
+    pub mirror: Option<MirrorConfig>,
+
+    /// Config for logging the clients that exceed the rate limits or publish invalid packets.
+    ///
+    /// If set to `None` the events are only counted in the metrics.
+    pub abuse_log: Option<AbuseLogConfig>,
+
+    /// Config for dropping the traffic of the addresses in an IP reputation list.
+    ///
+    /// If set to `None` the traffic of all addresses is handled.
+    pub ip_reputation: Option<IpReputationConfig>,
+
+    /// Config for the networks that may query, transfer, update, publish and call the admin API.
+    ///
+    /// If set to `None` all operations are allowed from anywhere.
+    pub acl: Option<AclConfig>,
+
+    /// Config for the admin tokens and listeners.
+    ///
+    /// If set to `None` the admin endpoints are served under `/admin` of the HTTP and HTTPS
+    /// servers, for admin API keys and client certificates.
+    pub admin: Option<AdminConfig>,
+
+    /// Config for the threads of the async runtime and the concurrency of the DNS handler.
+    ///
+    /// If set to `None` the runtime has a worker thread per CPU core, and DNS requests are
+    /// answered as they come in.
+    pub runtime: Option<RuntimeConfig>,
+
+    /// Time in seconds that in-flight requests are given to finish on shutdown.
+    ///
+    /// Defaults to 30 seconds.
+    pub shutdown_timeout_secs: Option<u64>,
+
+    /// Reload the config file when it is modified, in addition to on `SIGHUP`.
+    ///
+    /// Only some settings are applied without a restart, see the README.
+    #[serde(default)]
+    pub watch_config: bool,
+
+    /// Config files that are merged into this one, e.g. `["zones/*.toml", "secrets.toml"]`.
+    ///
+    /// Each entry is a path or a glob pattern, relative to the directory of the file it is in.
+    /// The files are merged in order after this file, sorted by name for each pattern: tables
+    /// are merged, arrays are appended to, and other values are replaced. Included files can
+    /// include other files, and a file that doesn't match a pattern with wildcards is an error.
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// The IP versions of the listeners without a `bind_addr`.
+    ///
+    /// Defaults to [`IpStack::Dual`].
+    #[serde(default)]
+    pub ip_stack: IpStack,
+
+    /// The directory of the packet store, the certificate cache and the probe key.
+    ///
+    /// A relative path is resolved against the directory of the config file. Defaults to the
+    /// `IROH_DNS_DATA_DIR` environment variable, or `iroh-dns` in the data directory of the
+    /// platform.
+    pub data_dir: Option<PathBuf>,
+
+    /// The path of the packet store database.
+    ///
+    /// A relative path is resolved against the directory of the config file. Defaults to
+    /// `signed-packets-1.db` in the [data directory](Self::data_dir).
+    pub store_path: Option<PathBuf>,
+
+    /// Keep the packets in memory instead of the store database, so that they are lost on
+    /// shutdown.
+    #[serde(default)]
+    pub in_memory_store: bool,
+
+    /// Config for the backend of the signed packets.
+    ///
+    /// If set to `None` the packets are kept in the packet store database.
+    pub store: Option<StoreConfig>,
+
+    /// Config for sandboxing the process once it is started (Linux and OpenBSD only).
+    ///
+    /// If set to `None` the process is not sandboxed.
+    pub sandbox: Option<SandboxConfig>,
+
+    /// The user to switch to once the listeners are bound, by name or id (unix only).
+    ///
+    /// The server must be started as root to switch users.
+    pub user: Option<String>,
+
+    /// The group to switch to once the listeners are bound, by name or id (unix only).
+    ///
+    /// Defaults to the primary group of [`Self::user`].
+    pub group: Option<String>,
+}
+
+/// The IP versions that listeners without configured bind addresses are bound for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum IpStack {
+    /// Bind the IPv4 and the IPv6 wildcard address, or only the IPv4 wildcard address if the
+    /// host doesn't support IPv6
+    #[default]
+    Dual,
+    /// Only bind the IPv4 wildcard address `0.0.0.0`
+    V4,
+    /// Only bind the IPv6 wildcard address `::`
+    V6,
+}
+
+/// An address to bind listeners to: an IP address, or the name of a network interface
+///
+/// The addresses of an interface, e.g. `eth1`, are looked up whenever the listeners are bound,
+/// which is useful on hosts with dynamic addresses. A listener is bound for each address of the
+/// interface of the IP versions of the [`IpStack`], except for IPv6 link-local addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindAddr {
+    /// An IPv4 or IPv6 address
+    Ip(IpAddr),
+    /// The name of a network interface
+    Interface(String),
+}
+
+impl From<IpAddr> for BindAddr {
+    fn from(ip: IpAddr) -> Self {
+        Self::Ip(ip)
+    }
+}
+
+impl FromStr for BindAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(ip) = s.parse() {
+            return Ok(Self::Ip(ip));
+        }
+        // a misspelled IPv4 address is not meant to be an interface name
+        if s.is_empty() || s.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            bail!("{s:?} is neither an IP address nor an interface name");
+        }
+        Ok(Self::Interface(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for BindAddr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<BindAddr> for String {
+    fn from(addr: BindAddr) -> Self {
+        addr.to_string()
+    }
 }
 
 /// The config for the metrics server.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MetricsConfig {
     /// Set to true to disable the metrics server.
+    #[serde(default)]
     pub disabled: bool,
     /// Optionally set a custom address to bind to.
     pub bind_addr: Option<SocketAddr>,
+    /// Optionally serve the metrics on a unix domain socket at this path (unix only).
+    ///
+    /// If set, the metrics are only served on a TCP address if [`Self::bind_addr`] is set too.
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+    /// Optionally require authentication for requests to the metrics server.
+    #[serde(default)]
+    pub auth: Option<MetricsAuth>,
+    /// Optionally push the metrics, and export traces, to an OpenTelemetry collector.
+    #[serde(default)]
+    pub otlp: Option<OtlpMetricsConfig>,
+    /// Optionally push the metrics to Prometheus remote-write or a Pushgateway.
+    #[serde(default)]
+    pub push: Option<PushMetricsConfig>,
+    /// Optionally override the bucket boundaries of histograms, by metric name, e.g.
+    /// `dns_lookup_duration_seconds = [0.001, 0.01, 0.1, 1.0]`.
+    #[serde(default)]
+    pub buckets: BTreeMap<String, Vec<f64>>,
+    /// The maximum number of distinct values of a label of a metric (defaults to 100).
+    ///
+    /// Further values are replaced with `other`.
+    #[serde(default)]
+    pub max_label_values: Option<usize>,
 }
 
 impl MetricsConfig {
@@ -60,12 +436,164 @@ impl MetricsConfig {
         Self {
             disabled: true,
             bind_addr: None,
+            unix_socket: None,
+            auth: None,
+            otlp: None,
+            push: None,
+            buckets: Default::default(),
+            max_label_values: None,
         }
     }
 }
 
+/// Config for pushing metrics and traces to an OpenTelemetry collector via OTLP/HTTP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OtlpMetricsConfig {
+    /// The base URL of the collector, e.g. `http://localhost:4318`
+    ///
+    /// Metrics are pushed to `/v1/metrics`, and traces to `/v1/traces` unless [`Config::otlp`]
+    /// is set.
+    pub endpoint: String,
+    /// The interval in which the metrics are pushed, in seconds (defaults to 60)
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    /// The service name reported with the metrics and traces (defaults to `iroh-dns-server`)
+    #[serde(default)]
+    pub service_name: Option<String>,
+}
+
+/// Config for pushing the metrics to Prometheus, for servers that scrapers can't reach
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushMetricsConfig {
+    /// The protocol of the receiver (defaults to `remote_write`)
+    #[serde(default)]
+    pub protocol: PushProtocol,
+    /// The URL of the receiver, e.g. `http://prometheus:9090/api/v1/write` for remote-write or
+    /// `http://pushgateway:9091` for a Pushgateway
+    pub url: String,
+    /// The interval in which the metrics are pushed, in seconds (defaults to 60)
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    /// The `job` label of the metrics (defaults to `iroh-dns-server`)
+    #[serde(default)]
+    pub job: Option<String>,
+    /// The `instance` label of the metrics, to tell the servers of a job apart
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Optionally authenticate the pushes with these credentials.
+    #[serde(default)]
+    pub auth: Option<MetricsAuth>,
+}
+
+/// The protocol of a metrics push
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PushProtocol {
+    /// Prometheus remote-write 1.0, e.g. to Prometheus, Mimir or VictoriaMetrics
+    #[default]
+    RemoteWrite,
+    /// The text format, to a Prometheus Pushgateway
+    Pushgateway,
+}
+
+/// Authentication for the metrics server, or credentials of the metrics pushes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum MetricsAuth {
+    /// HTTP basic authentication
+    Basic {
+        /// The expected username
+        username: String,
+        /// The expected password
+        password: SecretValue,
+    },
+    /// A bearer token in the `Authorization` header
+    Bearer {
+        /// The expected token
+        token: SecretValue,
+    },
+}
+
+/// Limits of the concurrent work of the whole server
+///
+/// Work above a limit is rejected right away, so that the latency of the accepted work stays
+/// bounded when the server is overloaded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceLimitsConfig {
+    /// Maximum number of DNS requests in flight, including DNS-over-HTTPS (unlimited if unset).
+    ///
+    /// Requests above the limit are answered with `SERVFAIL`.
+    pub max_dns_requests: Option<usize>,
+    /// Maximum number of concurrent connections over all HTTP and HTTPS listeners (unlimited if
+    /// unset).
+    ///
+    /// Requests on connections above the limit are answered with `503 Service Unavailable`, and
+    /// the connections are closed.
+    pub max_http_connections: Option<usize>,
+    /// Maximum number of pending lookups in the mainline DHT (unlimited if unset).
+    ///
+    /// DNS queries that would need a lookup above the limit are answered with `SERVFAIL`.
+    pub max_mainline_lookups: Option<usize>,
+}
+
+/// Config for the threads of the async runtime and the concurrency of the DNS handler
+///
+/// Pins the CPU footprint of the server on shared hosts. Changes only apply on restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuntimeConfig {
+    /// Number of worker threads of the runtime (defaults to the number of CPU cores).
+    pub worker_threads: Option<usize>,
+    /// Maximum number of threads for blocking work, like the access to the packet store
+    /// (defaults to 512).
+    pub max_blocking_threads: Option<usize>,
+    /// Maximum number of DNS requests that are answered concurrently, including DNS-over-HTTPS
+    /// (unlimited if unset).
+    ///
+    /// Further requests wait until a request is answered. Use
+    /// [`ResourceLimitsConfig::max_dns_requests`] to limit the waiting requests too.
+    pub dns_handler_concurrency: Option<usize>,
+}
+
+impl RuntimeConfig {
+    /// Build the multi-threaded runtime with the configured threads.
+    pub fn build_runtime(&self) -> Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(threads) = self.worker_threads {
+            ensure!(threads > 0, "runtime.worker_threads must be at least 1");
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            ensure!(
+                threads > 0,
+                "runtime.max_blocking_threads must be at least 1"
+            );
+            builder.max_blocking_threads(threads);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Config for the accepted clock skew of the timestamps of published packets
+///
+/// The timestamp of a pkarr packet is set by the publishing device. Publishes whose timestamp
+/// is further from the clock of the server than allowed are rejected with `400 Bad Request`,
+/// which tells the device to fix its clock.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClockSkewConfig {
+    /// Maximum time in seconds that the timestamp of a packet can be behind the clock of the
+    /// server (unlimited if unset).
+    pub max_past_secs: Option<u64>,
+    /// Maximum time in seconds that the timestamp of a packet can be ahead of the clock of the
+    /// server (unlimited if unset).
+    ///
+    /// Packets from the future are a problem because they can't be replaced until their
+    /// timestamp has passed, since newer packets must have a later timestamp.
+    pub max_future_secs: Option<u64>,
+}
+
 /// The config for the metrics server.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MainlineConfig {
     /// Set to true to enable the mainline lookup.
     pub enabled: bool,
@@ -75,10 +603,62 @@ pub struct MainlineConfig {
     ///
     /// If empty this will use the default bittorrent mainline bootstrap nodes as defined by pkarr.
     pub bootstrap: Option<Vec<String>>,
+    /// Read the bootstrap nodes from this file instead, one `host:port` per line.
+    ///
+    /// Replaces [`Self::bootstrap`], the default nodes and the persisted nodes. Lines starting
+    /// with `#` are ignored. Relative paths are resolved against the directory of the config file.
+    pub bootstrap_file: Option<PathBuf>,
+    /// Interval in seconds at which responsive DHT nodes are looked up and persisted in the data
+    /// directory, to bootstrap from them if the bootstrap hosts go away (defaults to 3600, 0
+    /// disables the refresh).
+    pub bootstrap_refresh_secs: Option<u64>,
+    /// Maximum number of lookups in the DHT that run at the same time (unlimited if unset).
+    ///
+    /// Lookups above the limit wait in a queue until a running lookup finishes, so that a flood
+    /// of queries for unknown keys can't start an unbounded number of DHT traversals.
+    pub max_concurrent_lookups: Option<usize>,
+    /// Maximum number of lookups that wait for a running lookup to finish (defaults to 256, only
+    /// applies with [`Self::max_concurrent_lookups`]).
+    ///
+    /// DNS queries that would need a lookup above the queue are answered with `SERVFAIL`.
+    pub max_queued_lookups: Option<usize>,
+    /// Participate in the DHT as a storing node, instead of only as a client (disabled if unset).
+    pub node: Option<DhtNodeConfig>,
+    /// Set to true to publish the packets in the store to the DHT, and to republish them
+    /// periodically, so that they can also be resolved by clients of the DHT.
+    #[serde(default)]
+    pub publish: bool,
+    /// Interval in seconds at which the packets in the store are republished to the DHT (defaults
+    /// to 3600, at least 60, only applies with [`Self::publish`]).
+    pub republish_interval_secs: Option<u64>,
+    /// Maximum number of publishes to the DHT that run at the same time (defaults to 16, only
+    /// applies with [`Self::publish`]).
+    ///
+    /// The publishes above the limit are delayed until a running publish finishes.
+    pub max_concurrent_publishes: Option<usize>,
+}
+
+/// The config of the participation in the mainline DHT as a storing node
+///
+/// The DHT client then answers the requests of other DHT nodes, and stores their packets. This
+/// only helps the DHT if the port of the client is reachable from the internet.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DhtNodeConfig {
+    /// UDP port of the DHT node (defaults to 6881, or a random port if it is taken).
+    pub port: Option<u16>,
+    /// Maximum number of values, e.g. pkarr packets, stored for other nodes (defaults to 1000).
+    ///
+    /// The least recently used values are dropped above the limit.
+    pub max_stored_values: Option<usize>,
+    /// Maximum number of requests of other nodes that are answered per second (unlimited if
+    /// unset).
+    ///
+    /// Requests above the limit are dropped, and time out at the requesting node.
+    pub max_requests_per_sec: Option<u32>,
 }
 
 /// Configure the bootstrap servers for mainline DHT resolution.
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub enum BootstrapOption {
     /// Use the default bootstrap servers.
     #[default]
@@ -87,33 +667,127 @@ pub enum BootstrapOption {
     Custom(Vec<String>),
 }
 
+impl MainlineConfig {
+    /// Get the number of concurrent and of queued lookups, if the concurrent lookups are limited.
+    pub(crate) fn lookup_pool(&self) -> Option<(usize, usize)> {
+        let concurrent = self.max_concurrent_lookups?;
+        let queued = self
+            .max_queued_lookups
+            .unwrap_or(DEFAULT_QUEUED_MAINLINE_LOOKUPS);
+        Some((concurrent, queued))
+    }
+}
+
 #[allow(clippy::derivable_impls)]
 impl Default for MainlineConfig {
     fn default() -> Self {
         Self {
             enabled: false,
             bootstrap: None,
+            bootstrap_file: None,
+            bootstrap_refresh_secs: None,
+            max_concurrent_lookups: None,
+            max_queued_lookups: None,
+            node: None,
+            publish: false,
+            republish_interval_secs: None,
+            max_concurrent_publishes: None,
         }
     }
 }
 
 impl Config {
     /// Load the config from a file.
+    ///
+    /// The file is parsed as YAML if its extension is `.yaml` or `.yml`, as JSON if it is
+    /// `.json`, and as TOML otherwise.
+    ///
+    /// The files of [`Self::include`] are merged into the config. Fields are then overridden by
+    /// environment variables, see [`Self::with_env_overrides`], and by the overrides of
+    /// [`set_overrides`]. In all string values, `${env:VAR}` is then replaced with the value of
+    /// the environment variable `VAR`, and `${file:PATH}` with the contents of the file at `PATH`.
+    /// Relative data paths are resolved against the directory of the file.
     pub async fn load(path: impl AsRef<Path>) -> Result<Config> {
         info!(
             "loading config file from {}",
             path.as_ref().to_string_lossy()
         );
-        let s = tokio::fs::read_to_string(path.as_ref())
-            .await
-            .with_context(|| format!("failed to read {}", path.as_ref().to_string_lossy()))?;
-        let config: Config = toml::from_str(&s)?;
+        let path = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || Self::load_blocking(path)).await?
+    }
+
+    /// Load the config from a file, without an async runtime.
+    ///
+    /// See [`Self::load`].
+    pub fn load_blocking(path: impl AsRef<Path>) -> Result<Config> {
+        let dir = path.as_ref().parent().unwrap_or(Path::new(""));
+        let mut value = read_value(path.as_ref(), &mut Vec::new())?;
+        apply_env_overrides(&mut value, env::vars())?;
+        interpolate::interpolate(&mut value, dir)?;
+        let mut config: Config = value.try_into()?;
+        config.resolve_paths(dir);
         Ok(config)
     }
 
+    /// Resolve the relative [`Self::data_dir`] and [`Self::store_path`] against `dir`.
+    fn resolve_paths(&mut self, dir: &Path) {
+        let bootstrap_file = self
+            .mainline
+            .as_mut()
+            .and_then(|mainline| mainline.bootstrap_file.as_mut());
+        for path in [
+            self.data_dir.as_mut(),
+            self.store_path.as_mut(),
+            bootstrap_file,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+
+    /// Create a config for local development, as used by `--dev`.
+    ///
+    /// This is the [`Default`] config with all listeners bound to localhost, without rate limits
+    /// and with an in-memory store.
+    pub fn dev() -> Self {
+        let localhost = vec![BindAddr::from(IpAddr::V4(Ipv4Addr::LOCALHOST))];
+        let mut config = Self::default();
+        if let Some(http) = config.http.as_mut() {
+            http.bind_addr = localhost.clone();
+            // the HTTPS server falls back to the rate limit of the HTTP server
+            http.rate_limit = RateLimitMode::Disabled.into();
+        }
+        if let Some(https) = config.https.as_mut() {
+            https.bind_addr = localhost.clone();
+        }
+        config.dns.bind_addr = localhost;
+        config.in_memory_store = true;
+        config
+    }
+
+    /// Override fields with the values of `IROH_DNS__<SECTION>__<FIELD>` environment variables.
+    ///
+    /// The keys are the config keys in upper case, separated by double underscores, e.g.
+    /// `IROH_DNS__HTTP__PORT=8080` or `IROH_DNS__DNS__ORIGINS='["example.org."]'`. The values are
+    /// parsed as TOML values, and used as strings if that fails. The overrides of
+    /// [`set_overrides`] are applied afterwards, and then the values are interpolated like in
+    /// [`Self::load`].
+    pub fn with_env_overrides(self) -> Result<Config> {
+        let mut value = toml::Value::try_from(&self)?;
+        apply_env_overrides(&mut value, env::vars())?;
+        interpolate::interpolate(&mut value, Path::new(""))?;
+        Ok(value.try_into()?)
+    }
+
     /// Get the data directory.
-    pub fn data_dir() -> Result<PathBuf> {
-        let dir = if let Some(val) = env::var_os("IROH_DNS_DATA_DIR") {
+    pub fn data_dir(&self) -> Result<PathBuf> {
+        let dir = if let Some(dir) = &self.data_dir {
+            dir.clone()
+        } else if let Some(val) = env::var_os("IROH_DNS_DATA_DIR") {
             PathBuf::from(val)
         } else {
             let path = dirs_next::data_dir().ok_or_else(|| {
@@ -125,21 +799,59 @@ impl Config {
     }
 
     /// Get the path to the store database file.
-    pub fn signed_packet_store_path() -> Result<PathBuf> {
-        Ok(Self::data_dir()?.join("signed-packets-1.db"))
+    pub fn signed_packet_store_path(&self) -> Result<PathBuf> {
+        match &self.store_path {
+            Some(path) => Ok(path.clone()),
+            None => Ok(self.data_dir()?.join("signed-packets-1.db")),
+        }
     }
 
     /// Get the address where the metrics server should be bound, if set.
     pub(crate) fn metrics_addr(&self) -> Option<SocketAddr> {
+        if !cfg!(feature = "metrics") {
+            return None;
+        }
         match &self.metrics {
             None => Some(DEFAULT_METRICS_ADDR),
-            Some(conf) => match conf.disabled {
-                true => None,
-                false => Some(conf.bind_addr.unwrap_or(DEFAULT_METRICS_ADDR)),
+            Some(conf) => match (conf.disabled, conf.bind_addr, &conf.unix_socket) {
+                (true, _, _) => None,
+                (false, Some(addr), _) => Some(addr),
+                (false, None, Some(_)) => None,
+                (false, None, None) => Some(DEFAULT_METRICS_ADDR),
             },
         }
     }
 
+    /// Get the unix socket path where the metrics server should be bound, if set.
+    pub(crate) fn metrics_unix_socket(&self) -> Option<PathBuf> {
+        match &self.metrics {
+            Some(conf) if !conf.disabled && cfg!(feature = "metrics") => conf.unix_socket.clone(),
+            _ => None,
+        }
+    }
+
+    /// Get the config for exporting traces: [`Self::otlp`], or the collector of the metrics
+    /// push if only that is set.
+    pub fn traces_config(&self) -> Option<OtlpConfig> {
+        if let Some(otlp) = &self.otlp {
+            return Some(otlp.clone());
+        }
+        let otlp = self.metrics.as_ref()?.otlp.as_ref()?;
+        Some(OtlpConfig {
+            endpoint: format!("{}/v1/traces", otlp.endpoint.trim_end_matches('/')),
+            service_name: otlp.service_name.clone(),
+            sample_ratio: None,
+            sample_overrides: Default::default(),
+        })
+    }
+
+    /// Get the time that in-flight requests are given to finish on shutdown.
+    pub(crate) fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+    }
+
     pub(crate) fn mainline_enabled(&self) -> Option<BootstrapOption> {
         match self.mainline.as_ref() {
             None => None,
@@ -159,34 +871,523 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             http: Some(HttpConfig {
-                port: 8080,
-                bind_addr: None,
+                port: Some(8080),
+                bind_addr: Vec::new(),
+                unix_socket: None,
+                onion_service: None,
                 rate_limit: RateLimitConfig::default(),
+                doh_rate_limit: None,
+                proxy_protocol: false,
             }),
-            https: Some(HttpsConfig {
+            // slim builds without the HTTPS server only serve HTTP by default
+            https: cfg!(feature = "https").then(|| HttpsConfig {
                 port: 8443,
-                bind_addr: None,
+                bind_addr: Vec::new(),
                 domains: vec!["localhost".to_string()],
                 cert_mode: CertMode::SelfSigned,
                 letsencrypt_contact: None,
                 letsencrypt_prod: None,
+                acme: None,
+                cert_path: None,
+                key_path: None,
+                cert_secret: None,
+                key_secret: None,
+                acme_account_key: None,
+                certificates: Vec::new(),
                 rate_limit: None,
+                doh_rate_limit: None,
+                http3: false,
+                proxy_protocol: false,
+                ocsp_stapling: false,
+                tls: Default::default(),
+                client_auth: None,
             }),
             dns: DnsConfig {
                 port: 5300,
-                bind_addr: None,
+                bind_addr: Vec::new(),
                 origins: vec!["irohdns.example.".to_string(), ".".to_string()],
 
                 default_soa: "irohdns.example hostmaster.irohdns.example 0 10800 3600 604800 3600"
                     .to_string(),
                 default_ttl: 900,
+                min_ttl: None,
+                max_ttl: None,
+                ttls: Default::default(),
 
                 rr_a: Some(Ipv4Addr::LOCALHOST),
-                rr_aaaa: None,
+                rr_aaaa: Some(Ipv6Addr::LOCALHOST),
                 rr_ns: Some("ns1.irohdns.example.".to_string()),
+                proxy_protocol: false,
+                tls: None,
+                rate_limit: None,
             },
             metrics: None,
             mainline: None,
+            access_log: None,
+            compression: None,
+            http_limits: None,
+            resource_limits: None,
+            clock_skew: None,
+            behind_proxy: None,
+            otlp: None,
+            logging: None,
+            sentry: None,
+            slow_log: None,
+            query_log: None,
+            probe: None,
+            gossip: None,
+            transfer: None,
+            secondary: None,
+            health: None,
+            ring: None,
+            sync: None,
+            replicas: None,
+            retention: None,
+            history: None,
+            analytics: None,
+            hourly_stats: None,
+            rate_limit_state: None,
+            tenants: Vec::new(),
+            usage: None,
+            anonymous_stats: None,
+            mirror: None,
+            abuse_log: None,
+            ip_reputation: None,
+            acl: None,
+            admin: None,
+            runtime: None,
+            shutdown_timeout_secs: None,
+            watch_config: false,
+            include: Vec::new(),
+            ip_stack: IpStack::Dual,
+            data_dir: None,
+            store_path: None,
+            in_memory_store: false,
+            store: None,
+            sandbox: None,
+            user: None,
+            group: None,
         }
     }
 }
+
+/// Apply the overrides of the `IROH_DNS__` variables in `vars`, and then the overrides of
+/// [`set_overrides`], to `config`.
+/// The format of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// The format by the extension of the file, TOML if it is not `.yaml`, `.yml` or `.json`.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml") => {
+                Self::Yaml
+            }
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+
+    /// Parse a config file into a TOML value, to which the overrides are applied.
+    fn parse(self, s: &str) -> Result<toml::Value> {
+        let value: serde_json::Value = match self {
+            Self::Toml => return Ok(toml::from_str(s)?),
+            #[cfg(feature = "yaml")]
+            Self::Yaml => serde_yaml::from_str(s)?,
+            #[cfg(not(feature = "yaml"))]
+            Self::Yaml => bail!("YAML config files require the `yaml` feature"),
+            Self::Json => serde_json::from_str(s)?,
+        };
+        Ok(toml::Value::try_from(without_nulls(value))?)
+    }
+}
+
+/// Get the config file at `path`, and the files it includes, in the order they are merged.
+pub(crate) fn config_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    read_value(path, &mut files)?;
+    Ok(files)
+}
+
+/// Get the files that are referenced with `${file:PATH}` in the config file at `path`.
+pub(crate) fn interpolated_files(path: &Path) -> Result<Vec<PathBuf>> {
+    let value = read_value(path, &mut Vec::new())?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(interpolate::referenced_files(&value, dir))
+}
+
+/// Read the config file at `path`, and merge the files of its `include` into it.
+///
+/// `files` are the files that were read so far, to which `path` and the included files are
+/// added. The `include` of the included files is removed from the value.
+fn read_value(path: &Path, files: &mut Vec<PathBuf>) -> Result<toml::Value> {
+    // the stack of including files, to detect include cycles
+    fn read(
+        path: &Path,
+        files: &mut Vec<PathBuf>,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<toml::Value> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+        if stack.contains(&canonical) {
+            bail!("{} includes itself", path.to_string_lossy());
+        }
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+        let mut value = ConfigFormat::from_path(path)
+            .parse(&s)
+            .with_context(|| format!("failed to parse {}", path.to_string_lossy()))?;
+        files.push(path.to_path_buf());
+        let include = match value.as_table_mut().and_then(|t| t.remove("include")) {
+            None => Vec::new(),
+            Some(include) => Vec::<String>::deserialize(include)
+                .with_context(|| format!("invalid include in {}", path.to_string_lossy()))?,
+        };
+        if include.is_empty() {
+            return Ok(value);
+        }
+        stack.push(canonical);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for pattern in &include {
+            for included in included_paths(dir, pattern)? {
+                let layer = read(&included, files, stack)?;
+                merge(&mut value, layer);
+            }
+        }
+        stack.pop();
+        if stack.is_empty() {
+            // keep the include of the main file, so that it is part of the config
+            if let Some(table) = value.as_table_mut() {
+                table.insert("include".to_string(), include.into());
+            }
+        }
+        Ok(value)
+    }
+    read(path, files, &mut Vec::new())
+}
+
+/// The files that match the include `pattern`, relative to `dir`, sorted by name.
+fn included_paths(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = dir.join(pattern);
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![path]);
+    }
+    let pattern = path
+        .to_str()
+        .with_context(|| format!("include {pattern} is not valid UTF-8"))?;
+    let mut paths = glob::glob(pattern)
+        .with_context(|| format!("invalid include pattern {pattern}"))?
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Merge `layer` into `base`: tables are merged, arrays are appended to, and other values are
+/// replaced.
+fn merge(base: &mut toml::Value, layer: toml::Value) {
+    match (base, layer) {
+        (toml::Value::Table(base), toml::Value::Table(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(base), toml::Value::Array(layer)) => base.extend(layer),
+        (base, layer) => *base = layer,
+    }
+}
+
+/// Remove the fields that are set to `null`, which TOML can't represent, and which are the same
+/// as unset fields in the config.
+fn without_nulls(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(fields) => fields
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .map(|(key, value)| (key, without_nulls(value)))
+            .collect(),
+        serde_json::Value::Array(values) => values.into_iter().map(without_nulls).collect(),
+        value => value,
+    }
+}
+
+fn apply_env_overrides(
+    config: &mut toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    let mut overrides: Vec<_> = vars
+        .filter_map(|(name, raw)| {
+            let keys = name.strip_prefix(ENV_OVERRIDE_PREFIX)?;
+            let keys: Vec<_> = keys
+                .split(ENV_OVERRIDE_SEPARATOR)
+                .map(str::to_ascii_lowercase)
+                .collect();
+            let value = toml::from_str::<toml::Table>(&format!("value = {raw}"))
+                .ok()
+                .and_then(|mut t| t.remove("value"))
+                .unwrap_or(toml::Value::String(raw));
+            Some((name, keys, value))
+        })
+        .collect();
+    // sections are set before their fields
+    overrides.sort_by(|a, b| a.1.len().cmp(&b.1.len()).then_with(|| a.0.cmp(&b.0)));
+    let flags = OVERRIDES.get().into_iter().flatten().map(|(key, value)| {
+        let keys = key.split('.').map(str::to_string).collect();
+        (format!("flag {key}"), keys, value.clone())
+    });
+    for (name, keys, value) in overrides.into_iter().chain(flags) {
+        if keys.iter().any(String::is_empty) {
+            bail!("invalid config override {name}: empty key");
+        }
+        let (field, sections) = keys.split_last().expect("non-empty");
+        let mut table = config.as_table_mut().context("the config is not a table")?;
+        for section in sections {
+            let value = table
+                .entry(section.as_str())
+                .or_insert_with(|| toml::Value::Table(Default::default()));
+            table = value.as_table_mut().with_context(|| {
+                format!("invalid config override {name}: {section} is not a section")
+            })?;
+        }
+        info!("config field {} overridden by {name}", keys.join("."));
+        table.insert(field.clone(), value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_overrides() -> Result<()> {
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            [http]
+            port = 8080
+            [dns]
+            port = 53
+            default_soa = "ns1 hostmaster 0 10800 3600 604800 3600"
+            default_ttl = 900
+            "#,
+        )?;
+        let vars = [
+            ("IROH_DNS__HTTP__PORT", "8081"),
+            ("IROH_DNS__DNS__ORIGINS", r#"["example.org."]"#),
+            ("IROH_DNS__DNS__RR_NS", "ns1.example.org."),
+            ("IROH_DNS__METRICS__DISABLED", "true"),
+            ("IROH_DNS_DATA_DIR", "/tmp"),
+        ];
+        apply_env_overrides(
+            &mut value,
+            vars.into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string())),
+        )?;
+        let config: Config = value.try_into()?;
+        assert_eq!(config.http.unwrap().port, Some(8081));
+        assert_eq!(config.dns.origins, ["example.org."]);
+        assert_eq!(config.dns.rr_ns.as_deref(), Some("ns1.example.org."));
+        assert!(config.metrics.unwrap().disabled);
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn config_formats() -> Result<()> {
+        let toml = r#"
+            [http]
+            port = 8080
+            bind_addr = ["127.0.0.1", "::1"]
+            [dns]
+            port = 53
+            origins = ["example.org."]
+            default_soa = "ns1 hostmaster 0 10800 3600 604800 3600"
+            default_ttl = 900
+            "#;
+        let yaml = r#"
+            http:
+              port: 8080
+              bind_addr: ["127.0.0.1", "::1"]
+            https: ~
+            dns:
+              port: 53
+              origins: [example.org.]
+              default_soa: ns1 hostmaster 0 10800 3600 604800 3600
+              default_ttl: 900
+              rr_a: null
+            "#;
+        let json = r#"{
+            "http": { "port": 8080, "bind_addr": ["127.0.0.1", "::1"] },
+            "https": null,
+            "dns": {
+                "port": 53,
+                "origins": ["example.org."],
+                "default_soa": "ns1 hostmaster 0 10800 3600 604800 3600",
+                "default_ttl": 900
+            }
+        }"#;
+        let expected = ConfigFormat::Toml.parse(toml)?;
+        for (path, s) in [
+            ("config.yaml", yaml),
+            ("config.YML", yaml),
+            ("config.json", json),
+        ] {
+            let value = ConfigFormat::from_path(Path::new(path)).parse(s)?;
+            assert_eq!(value, expected, "{path}");
+        }
+        let config: Config = expected.try_into()?;
+        assert_eq!(config.http.unwrap().bind_addr.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limit_quotas() -> Result<()> {
+        use crate::http::rate_limiting::RateLimitClass;
+
+        let config: Config = toml::from_str(
+            r#"
+            [http]
+            rate_limit = { mode = "smart", rate = 0.5, burst = 4, get = { rate = 20, burst = 100 } }
+            doh_rate_limit = "smart"
+            [dns]
+            port = 53
+            origins = ["example.org."]
+            default_soa = "ns1 hostmaster 0 10800 3600 604800 3600"
+            default_ttl = 900
+            [dns.rate_limit]
+            exempt = ["192.0.2.0/24"]
+            "#,
+        )?;
+        let http = config.http.unwrap();
+        let rate_limit = http.rate_limit;
+        let quota = rate_limit.quota(RateLimitClass::Publish)?;
+        assert_eq!(quota.burst_size().get(), 4);
+        assert_eq!(quota.replenish_interval(), Duration::from_secs(2));
+        assert!(rate_limit.limits(RateLimitClass::Lookup));
+        assert_eq!(
+            rate_limit.quota(RateLimitClass::Lookup)?.burst_size().get(),
+            100
+        );
+        // only the table form sets a budget for the pkarr lookups
+        assert!(!http.doh_rate_limit.unwrap().limits(RateLimitClass::Lookup));
+        assert_eq!(config.dns.rate_limit.unwrap().exempt.len(), 1);
+
+        let invalid: RateLimitConfig = toml::from_str("rate = 0.0")?;
+        assert!(invalid.quota(RateLimitClass::Publish).is_err());
+        let tiny: RateLimitConfig = toml::from_str("rate = 1e-20")?;
+        assert!(tiny.quota(RateLimitClass::Publish).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bind_addrs() -> Result<()> {
+        assert_eq!(
+            "::1".parse::<BindAddr>()?,
+            BindAddr::Ip(Ipv6Addr::LOCALHOST.into())
+        );
+        assert_eq!(
+            "eth1".parse::<BindAddr>()?,
+            BindAddr::Interface("eth1".to_string())
+        );
+        assert!("10.0.0.256".parse::<BindAddr>().is_err());
+
+        let http: crate::http::HttpConfig = toml::from_str(r#"bind_addr = "lo""#)?;
+        assert_eq!(http.bind_addr, vec![BindAddr::Interface("lo".to_string())]);
+        #[cfg(target_os = "linux")]
+        {
+            let addrs = crate::util::socket_addrs("http", &http.bind_addr, Some(80), IpStack::V4)?;
+            assert!(addrs.contains(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80)));
+            let missing = [BindAddr::Interface("missing0".to_string())];
+            assert!(crate::util::socket_addrs("http", &missing, Some(80), IpStack::V4).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn relative_data_paths() -> Result<()> {
+        let mut config = Config {
+            data_dir: Some("data".into()),
+            store_path: Some("/var/lib/iroh-dns/packets.db".into()),
+            ..Default::default()
+        };
+        config.resolve_paths(Path::new("/etc/iroh-dns"));
+        assert_eq!(config.data_dir()?, Path::new("/etc/iroh-dns/data"));
+        assert_eq!(
+            config.signed_packet_store_path()?,
+            Path::new("/var/lib/iroh-dns/packets.db")
+        );
+        config.store_path = None;
+        assert_eq!(
+            config.signed_packet_store_path()?,
+            Path::new("/etc/iroh-dns/data/signed-packets-1.db")
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn include_files() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("config-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("zones"))?;
+        let main = dir.join("config.toml");
+        std::fs::write(
+            &main,
+            r#"
+            include = ["zones/*.toml", "limits.yaml"]
+
+            [dns]
+            port = 53
+            default_soa = "ns1.example.org hostmaster.example.org 0 10800 3600 604800 3600"
+            default_ttl = 300
+            origins = ["example.org."]
+            "#,
+        )?;
+        std::fs::write(
+            dir.join("zones/b.toml"),
+            "[dns]\norigins = [\"b.example.\"]\ndefault_ttl = 60\n",
+        )?;
+        std::fs::write(
+            dir.join("zones/a.toml"),
+            "[dns]\norigins = [\"a.example.\"]\ndefault_ttl = 30\n",
+        )?;
+        std::fs::write(
+            dir.join("limits.yaml"),
+            "resource_limits:\n  max_dns_requests: 100\n",
+        )?;
+
+        let config = Config::load_blocking(&main)?;
+        assert_eq!(
+            config.dns.origins,
+            vec!["example.org.", "a.example.", "b.example."]
+        );
+        assert_eq!(config.dns.default_ttl, 60);
+        assert_eq!(config.dns.port, 53);
+        assert_eq!(
+            config.resource_limits.and_then(|l| l.max_dns_requests),
+            Some(100)
+        );
+        assert_eq!(config.include, vec!["zones/*.toml", "limits.yaml"]);
+        assert_eq!(config_files(&main)?.len(), 4);
+
+        // a missing file that is not a pattern is an error, and so is a cycle
+        std::fs::write(
+            dir.join("zones/a.toml"),
+            "include = [\"../missing.toml\"]\n",
+        )?;
+        assert!(Config::load_blocking(&main).is_err());
+        std::fs::write(dir.join("zones/a.toml"), "include = [\"../config.toml\"]\n")?;
+        assert!(Config::load_blocking(&main).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
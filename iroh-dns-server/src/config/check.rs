@@ -0,0 +1,349 @@
+//! Validation of a config beyond its syntax
+
+use std::{fmt, net::SocketAddr, path::Path};
+
+use hickory_proto::{
+    rr::{Name, RData, RecordType},
+    serialize::txt::RDataParser,
+};
+
+use super::{BindAddr, Config, IpStack};
+#[cfg(unix)]
+use crate::privileges;
+use crate::{http::CertMode, store::StoreBackend, util};
+
+/// The transport protocol of a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "TCP"),
+            Self::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+/// A socket the server binds.
+struct Listener {
+    server: &'static str,
+    protocol: Protocol,
+    addr: SocketAddr,
+}
+
+impl Listener {
+    /// Whether both listeners can't be bound at the same time.
+    fn conflicts_with(&self, other: &Listener) -> bool {
+        let (a, b) = (self.addr.ip(), other.addr.ip());
+        self.protocol == other.protocol
+            && self.addr.port() == other.addr.port()
+            && self.addr.port() != 0
+            && (a == b
+                || (a.is_ipv4() == b.is_ipv4() && (a.is_unspecified() || b.is_unspecified())))
+    }
+}
+
+impl Config {
+    /// Check the config for problems that would make the server fail on start.
+    ///
+    /// Checks that the origins, the SOA and the NS record are valid DNS names, that no two
+    /// listeners bind the same address, that the certificate and CA files exist, that the user
+    /// and group exist, that the runtime has threads, and that a shared store is used only with
+    /// what it supports. Returns a description of each problem, with the config key it is about.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        self.check_dns(&mut problems);
+        self.check_listeners(&mut problems);
+        self.check_files(&mut problems);
+        self.check_user(&mut problems);
+        self.check_runtime(&mut problems);
+        self.check_store(&mut problems);
+        problems
+    }
+
+    fn check_dns(&self, problems: &mut Vec<String>) {
+        if self.dns.origins.is_empty() {
+            problems.push("dns.origins: at least one origin is required".to_string());
+        }
+        if self.secondary.is_some() && self.transfer.is_some() {
+            problems.push("transfer: a secondary can't serve zone transfers".to_string());
+        }
+        if self.secondary.is_some() && self.replicas.is_some() {
+            problems.push(
+                "replicas: a secondary serves the replica records of its primary".to_string(),
+            );
+        }
+        for replica in self.replicas.iter().flat_map(|r| &r.replicas) {
+            let target = &replica.target;
+            if let Err(err) = Name::parse(target, Some(&Name::root())) {
+                problems.push(format!(
+                    "replicas.replicas: {target:?} is not a valid DNS name: {err}"
+                ));
+            }
+        }
+        for origin in &self.dns.origins {
+            if let Err(err) = Name::from_utf8(origin) {
+                problems.push(format!(
+                    "dns.origins: {origin:?} is not a valid DNS name: {err}"
+                ));
+            }
+        }
+        let soa = &self.dns.default_soa;
+        match RData::parse(RecordType::SOA, soa.split_ascii_whitespace(), None) {
+            Ok(_) => {}
+            Err(err) => problems.push(format!(
+                "dns.default_soa: {soa:?} is not a valid SOA record, expected \"<mname> <rname> <serial> <refresh> <retry> <expire> <minimum>\": {err}"
+            )),
+        }
+        if let Some(ns) = &self.dns.rr_ns {
+            if let Err(err) = Name::parse(ns, Some(&Name::root())) {
+                problems.push(format!("dns.rr_ns: {ns:?} is not a valid DNS name: {err}"));
+            }
+        }
+    }
+
+    fn check_listeners(&self, problems: &mut Vec<String>) {
+        let bind_addrs = [
+            ("http", self.http.as_ref().map(|c| &c.bind_addr)),
+            ("https", self.https.as_ref().map(|c| &c.bind_addr)),
+            ("dns", Some(&self.dns.bind_addr)),
+        ];
+        for (server, addrs) in bind_addrs {
+            for addr in addrs.into_iter().flatten() {
+                // the addresses of interfaces are filtered by the IP stack
+                let BindAddr::Ip(addr) = addr else {
+                    continue;
+                };
+                let forbidden = match self.ip_stack {
+                    IpStack::Dual => false,
+                    IpStack::V4 => addr.is_ipv6(),
+                    IpStack::V6 => addr.is_ipv4(),
+                };
+                if forbidden {
+                    problems.push(format!(
+                        "{server}.bind_addr: {addr} is not an {} address, but ip_stack = \"{}\"",
+                        if self.ip_stack == IpStack::V4 {
+                            "IPv4"
+                        } else {
+                            "IPv6"
+                        },
+                        self.ip_stack
+                    ));
+                }
+            }
+        }
+        let mut socket_addrs = |server, addrs: &[BindAddr], port| {
+            util::socket_addrs(server, addrs, port, self.ip_stack).unwrap_or_else(|err| {
+                problems.push(format!("{err:#}"));
+                Vec::new()
+            })
+        };
+        let http = self
+            .http
+            .as_ref()
+            .map(|config| socket_addrs("http", &config.bind_addr, config.port));
+        let https = self
+            .https
+            .as_ref()
+            .map(|config| socket_addrs("https", &config.bind_addr, Some(config.port)));
+        let dns = socket_addrs("dns", &self.dns.bind_addr, Some(self.dns.port));
+        let mut listeners = Vec::new();
+        let mut add = |server, protocol, addrs: Vec<SocketAddr>| {
+            listeners.extend(addrs.into_iter().map(|addr| Listener {
+                server,
+                protocol,
+                addr,
+            }))
+        };
+        if let Some(addrs) = http {
+            add("http", Protocol::Tcp, addrs);
+        }
+        if let (Some(config), Some(addrs)) = (&self.https, https) {
+            if config.http3 {
+                add("https", Protocol::Udp, addrs.clone());
+            }
+            add("https", Protocol::Tcp, addrs);
+        }
+        add("dns", Protocol::Udp, dns.clone());
+        add("dns", Protocol::Tcp, dns);
+        if let Some(tls) = &self.dns.tls {
+            if let Some(port) = tls.dot_port {
+                add(
+                    "dns.tls",
+                    Protocol::Tcp,
+                    socket_addrs("dns", &self.dns.bind_addr, Some(port)),
+                );
+            }
+            if let Some(port) = tls.doq_port {
+                add(
+                    "dns.tls",
+                    Protocol::Udp,
+                    socket_addrs("dns", &self.dns.bind_addr, Some(port)),
+                );
+            }
+        }
+        if let Some(addr) = self.metrics_addr() {
+            add("metrics", Protocol::Tcp, vec![addr]);
+        }
+        if let Some(admin) = &self.admin {
+            add("admin", Protocol::Tcp, admin.bind_addr.clone());
+        }
+        for (i, a) in listeners.iter().enumerate() {
+            for b in &listeners[i + 1..] {
+                if a.conflicts_with(b) {
+                    problems.push(format!(
+                        "{}.bind_addr and {}.bind_addr: both bind {} {} and {}, use different ports or addresses",
+                        a.server, b.server, a.protocol, a.addr, b.addr
+                    ));
+                }
+            }
+        }
+    }
+
+    fn check_files(&self, problems: &mut Vec<String>) {
+        let Some(https) = &self.https else {
+            return;
+        };
+        for (i, cert) in https.certificates().iter().enumerate() {
+            // the default certificate is configured in the section itself
+            let key = match i {
+                0 => "https".to_string(),
+                i => format!("https.certificates[{}]", i - 1),
+            };
+            if cert.cert_mode != CertMode::Manual {
+                continue;
+            }
+            match (&cert.cert_secret, &cert.key_secret) {
+                (Some(_), Some(_)) => continue,
+                (None, None) => {}
+                _ => {
+                    problems.push(format!(
+                        "{key}: cert_secret and key_secret must be set together"
+                    ));
+                    continue;
+                }
+            }
+            match (&cert.cert_path, &cert.key_path) {
+                (Some(cert_path), Some(key_path)) => {
+                    check_exists(problems, &format!("{key}.cert_path"), cert_path);
+                    check_exists(problems, &format!("{key}.key_path"), key_path);
+                }
+                // the certificate is loaded from the cert cache in the data directory
+                (None, None) if cert.domains.len() == 1 => {}
+                (None, None) => problems.push(format!(
+                    "{key}: cert_path and key_path are required for multiple domains with cert_mode = \"manual\""
+                )),
+                _ => problems.push(format!(
+                    "{key}: cert_path and key_path must be set together"
+                )),
+            }
+        }
+        if let Some(client_auth) = &https.client_auth {
+            check_exists(problems, "https.client_auth.ca_cert", &client_auth.ca_cert);
+        }
+    }
+}
+
+impl Config {
+    fn check_user(&self, problems: &mut Vec<String>) {
+        #[cfg(unix)]
+        {
+            if let Some(Err(err)) = self.user.as_deref().map(privileges::find_user) {
+                problems.push(format!("user: {err}"));
+            }
+            if let Some(Err(err)) = self.group.as_deref().map(privileges::find_group) {
+                problems.push(format!("group: {err}"));
+            }
+        }
+        #[cfg(not(unix))]
+        if self.user.is_some() || self.group.is_some() {
+            problems.push("user: only supported on unix".to_string());
+        }
+    }
+}
+
+impl Config {
+    fn check_runtime(&self, problems: &mut Vec<String>) {
+        let Some(runtime) = &self.runtime else {
+            return;
+        };
+        let limits = [
+            ("worker_threads", runtime.worker_threads),
+            ("max_blocking_threads", runtime.max_blocking_threads),
+            ("dns_handler_concurrency", runtime.dns_handler_concurrency),
+        ];
+        for (key, limit) in limits {
+            if limit == Some(0) {
+                problems.push(format!("runtime.{key}: must be at least 1"));
+            }
+        }
+    }
+}
+
+impl Config {
+    fn check_store(&self, problems: &mut Vec<String>) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        if store.backend != StoreBackend::Postgres {
+            return;
+        }
+        // Both are kept in the database file of a single server.
+        if self.history.is_some() {
+            problems.push("history: not supported with the postgres store".to_string());
+        }
+        if !self.tenants.is_empty() {
+            problems.push("tenants: not supported with the postgres store".to_string());
+        }
+    }
+}
+
+fn check_exists(problems: &mut Vec<String>, key: &str, path: &Path) {
+    if !path.exists() {
+        problems.push(format!("{key}: {} does not exist", path.display()));
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn check_problems() {
+        let mut config = Config::default();
+        assert_eq!(config.check(), Vec::<String>::new());
+        config.dns.origins.push("example..org".to_string());
+        config.metrics = Some(super::super::MetricsConfig {
+            bind_addr: Some(SocketAddr::new(
+                Ipv4Addr::LOCALHOST.into(),
+                config.http.as_ref().unwrap().port.unwrap(),
+            )),
+            ..super::super::MetricsConfig::disabled()
+        });
+        config.metrics.as_mut().unwrap().disabled = false;
+        let problems = config.check();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].starts_with("dns.origins"));
+        assert!(problems[1].starts_with("http.bind_addr and metrics.bind_addr"));
+    }
+
+    #[test]
+    fn check_postgres_store() {
+        let mut config = Config {
+            history: Some(Default::default()),
+            store: Some(crate::store::StoreConfig::default()),
+            ..Default::default()
+        };
+        assert_eq!(config.check(), Vec::<String>::new());
+        config.store.as_mut().unwrap().backend = StoreBackend::Postgres;
+        let problems = config.check();
+        assert_eq!(problems.len(), 1, "{problems:?}");
+        assert!(problems[0].starts_with("history"));
+    }
+}
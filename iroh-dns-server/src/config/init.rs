@@ -0,0 +1,59 @@
+//! Commented config files for common deployments
+
+/// A deployment for which a commented config file can be generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Profile {
+    /// Local development, with all servers on localhost
+    Dev,
+    /// A public server with HTTPS and an authoritative DNS server
+    PublicRelay,
+    /// A server in a private network, without TLS and the mainline DHT
+    PrivateFleet,
+}
+
+impl Profile {
+    /// All profiles.
+    pub const ALL: [Profile; 3] = [Self::Dev, Self::PublicRelay, Self::PrivateFleet];
+
+    /// The config file of the profile, with a comment for each setting.
+    pub fn template(self) -> &'static str {
+        match self {
+            Self::Dev => include_str!("profiles/dev.toml"),
+            Self::PublicRelay => include_str!("profiles/public-relay.toml"),
+            Self::PrivateFleet => include_str!("profiles/private-fleet.toml"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// The dotted keys of all values in a table.
+    fn collect_keys(prefix: &str, value: &toml::Value, keys: &mut Vec<String>) {
+        if let toml::Value::Table(table) = value {
+            for (key, value) in table {
+                keys.push(format!("{prefix}{key}"));
+                collect_keys(&format!("{prefix}{key}."), value, keys);
+            }
+        }
+    }
+
+    #[test]
+    fn templates_are_valid() {
+        for profile in Profile::ALL {
+            let config: Config =
+                toml::from_str(profile.template()).unwrap_or_else(|err| panic!("{profile}: {err}"));
+            assert_eq!(config.check(), Vec::<String>::new(), "{profile}");
+            // unknown keys are ignored when parsing, so typos only show in the parsed config
+            let (mut template, mut parsed) = (Vec::new(), Vec::new());
+            collect_keys("", &profile.template().parse().unwrap(), &mut template);
+            collect_keys("", &toml::Value::try_from(&config).unwrap(), &mut parsed);
+            for key in template {
+                assert!(parsed.contains(&key), "{profile}: unknown key {key}");
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Interpolation of environment variables and files in config values
+//!
+//! String values of the config can contain `${env:VAR}`, which is replaced with the value of the
+//! environment variable `VAR`, and `${file:PATH}`, which is replaced with the contents of the file
+//! at `PATH` without a trailing newline. This keeps secrets like tokens out of config files that
+//! are committed to git. `$${` is replaced with a literal `${`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Interpolate all string values in `value`.
+///
+/// Relative paths of `${file:PATH}` are resolved against `dir`.
+pub(super) fn interpolate(value: &mut toml::Value, dir: &Path) -> Result<()> {
+    fn walk(value: &mut toml::Value, dir: &Path, key: &mut Vec<String>) -> Result<()> {
+        match value {
+            toml::Value::String(s) if s.contains("${") => {
+                *s = interpolate_str(s, dir)
+                    .with_context(|| format!("invalid value of {}", key.join(".")))?;
+            }
+            toml::Value::Array(values) => {
+                for (i, value) in values.iter_mut().enumerate() {
+                    key.push(i.to_string());
+                    walk(value, dir, key)?;
+                    key.pop();
+                }
+            }
+            toml::Value::Table(table) => {
+                for (name, value) in table.iter_mut() {
+                    key.push(name.clone());
+                    walk(value, dir, key)?;
+                    key.pop();
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    walk(value, dir, &mut Vec::new())
+}
+
+/// Get the files of the `${file:PATH}` references in the string values of `value`.
+pub(super) fn referenced_files(value: &toml::Value, dir: &Path) -> Vec<PathBuf> {
+    match value {
+        toml::Value::String(s) => s
+            .split("${file:")
+            .skip(1)
+            .filter_map(|rest| rest.split_once('}'))
+            .map(|(path, _)| dir.join(path))
+            .collect(),
+        toml::Value::Array(values) => values
+            .iter()
+            .flat_map(|value| referenced_files(value, dir))
+            .collect(),
+        toml::Value::Table(table) => table
+            .values()
+            .flat_map(|value| referenced_files(value, dir))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Replace the `${env:VAR}` and `${file:PATH}` references in `s`.
+fn interpolate_str(s: &str, dir: &Path) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        // `$${` is an escaped `${`
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            bail!("unterminated `${{` in config value");
+        };
+        let reference = &rest[start + 2..start + len];
+        match reference.split_once(':') {
+            Some(("env", var)) => {
+                let value = std::env::var(var)
+                    .with_context(|| format!("environment variable {var} is not set"))?;
+                out.push_str(&value);
+            }
+            Some(("file", path)) => {
+                let path = dir.join(path);
+                let value = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                out.push_str(value.strip_suffix('\n').unwrap_or(&value));
+            }
+            _ => bail!(
+                "unknown reference `${{{reference}}}`, expected `${{env:VAR}}` or `${{file:PATH}}`"
+            ),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_values() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("config-interpolate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("token"), "s3cret\n")?;
+        std::env::set_var("IROH_DNS_TEST_INTERPOLATE", "admin");
+
+        let mut value: toml::Value = toml::from_str(
+            r#"
+            user = "${env:IROH_DNS_TEST_INTERPOLATE}"
+            [metrics.auth]
+            password = "pre-${file:token}-post"
+            tokens = ["${file:token}", "$${env:NOT_INTERPOLATED}"]
+            "#,
+        )?;
+        assert_eq!(
+            referenced_files(&value, &dir),
+            vec![dir.join("token"), dir.join("token")]
+        );
+        interpolate(&mut value, &dir)?;
+        assert_eq!(value["user"].as_str(), Some("admin"));
+        assert_eq!(
+            value["metrics"]["auth"]["password"].as_str(),
+            Some("pre-s3cret-post")
+        );
+        assert_eq!(
+            value["metrics"]["auth"]["tokens"][0].as_str(),
+            Some("s3cret")
+        );
+        assert_eq!(
+            value["metrics"]["auth"]["tokens"][1].as_str(),
+            Some("${env:NOT_INTERPOLATED}")
+        );
+
+        for invalid in [
+            "${env:IROH_DNS_TEST_UNSET}",
+            "${file:missing}",
+            "${vault:x}",
+            "${env:X",
+        ] {
+            let mut value = toml::Value::String(invalid.to_string());
+            assert!(interpolate(&mut value, &dir).is_err(), "{invalid}");
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
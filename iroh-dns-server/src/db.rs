@@ -0,0 +1,543 @@
+//! Statistics, export, import and verification of the packet database, for the `db` commands and
+//! the admin API
+//!
+//! An export has one JSON object per line, with the z-base-32 encoded public key, the timestamp
+//! and the base64 encoded signed packet, so that it can be filtered with common tools and
+//! imported into the database of another server.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{BufRead, Write},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::warn;
+use url::Url;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::{
+    store::{PacketSource, ZoneStore},
+    util::PublicKeyBytes,
+};
+
+/// The upper bounds of the packet size buckets, in bytes. Signed packets have at most 1104 bytes.
+const SIZE_BUCKETS: [u32; 5] = [128, 256, 512, 768, 1104];
+/// The number of packets read at a time.
+const PAGE_SIZE: usize = 1000;
+
+/// Statistics of the packet database
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct DbStats {
+    /// The size of the database file in bytes, if the database is persistent
+    pub file_size: Option<u64>,
+    /// The number of signed packets
+    pub packets: u64,
+    /// The oldest packet timestamp, in microseconds since the unix epoch
+    pub oldest_timestamp: Option<u64>,
+    /// The newest packet timestamp, in microseconds since the unix epoch
+    pub newest_timestamp: Option<u64>,
+    /// The number of packets by size, in buckets of at most `max_bytes`
+    pub sizes: Vec<SizeBucket>,
+    /// The number of packets with records of each name, relative to the public key, e.g.
+    /// `_iroh` or `@` for the public key itself
+    ///
+    /// The packets are not stored by origin; each packet is served under all origins.
+    pub record_names: BTreeMap<String, u64>,
+}
+
+/// A bucket of the packet sizes
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct SizeBucket {
+    /// The upper bound of the bucket, in bytes
+    pub max_bytes: u32,
+    /// The number of packets in the bucket
+    pub packets: u64,
+}
+
+impl DbStats {
+    /// Collect the statistics of all packets in `store`.
+    pub(crate) async fn collect(store: &ZoneStore) -> Result<Self> {
+        let file_size = match store.database_path() {
+            Some(path) => Some(tokio::fs::metadata(path).await?.len()),
+            None => None,
+        };
+        let mut stats = Self {
+            file_size,
+            packets: 0,
+            oldest_timestamp: None,
+            newest_timestamp: None,
+            sizes: SIZE_BUCKETS
+                .iter()
+                .map(|max_bytes| SizeBucket {
+                    max_bytes: *max_bytes,
+                    packets: 0,
+                })
+                .collect(),
+            record_names: BTreeMap::new(),
+        };
+        let mut after = None;
+        loop {
+            let packets = store.packets_after(after, PAGE_SIZE).await?;
+            let Some(last) = packets.last() else {
+                break;
+            };
+            after = Some(PublicKeyBytes::from_signed_packet(last));
+            for packet in &packets {
+                stats.packets += 1;
+                let timestamp = packet.timestamp();
+                stats.oldest_timestamp = Some(
+                    stats
+                        .oldest_timestamp
+                        .map_or(timestamp, |t| t.min(timestamp)),
+                );
+                stats.newest_timestamp = Some(
+                    stats
+                        .newest_timestamp
+                        .map_or(timestamp, |t| t.max(timestamp)),
+                );
+                let size = packet.as_bytes().len() as u32;
+                if let Some(bucket) = stats.sizes.iter_mut().find(|b| size <= b.max_bytes) {
+                    bucket.packets += 1;
+                }
+                let z32 = packet.public_key().to_z32();
+                let mut names: Vec<String> = packet
+                    .packet()
+                    .answers
+                    .iter()
+                    .map(|record| relative_name(&record.name.to_string(), &z32))
+                    .collect();
+                names.sort();
+                names.dedup();
+                for name in names {
+                    *stats.record_names.entry(name).or_default() += 1;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Collect the statistics of the packet database at `path`.
+    ///
+    /// This fails if the database is opened by a running server.
+    pub async fn from_store(path: &Path) -> Result<Self> {
+        ensure!(path.exists(), "no packet database at {}", path.display());
+        let store = ZoneStore::persistent(path)
+            .context("failed to open the packet database, is the server running?")?;
+        let stats = Self::collect(&store).await;
+        store.close().await?;
+        stats
+    }
+
+    /// Get the statistics from the admin API of the server at `url`.
+    pub async fn from_server(url: &Url, token: &str) -> Result<Self> {
+        let stats = reqwest::Client::new()
+            .get(url.join("admin/db-stats")?)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(stats)
+    }
+}
+
+/// Which packets to export or import
+#[derive(Debug, Default, Clone)]
+pub struct PacketFilter {
+    /// Only these z-base-32 encoded public keys, or all if empty
+    pub pubkeys: Vec<String>,
+    /// Only packets with a timestamp at most this old
+    pub max_age: Option<Duration>,
+}
+
+impl PacketFilter {
+    fn matcher(&self) -> Result<impl Fn(&SignedPacket) -> bool> {
+        let pubkeys = self
+            .pubkeys
+            .iter()
+            .map(|pubkey| PublicKeyBytes::from_z32(pubkey))
+            .collect::<Result<Vec<_>>>()?;
+        let min_timestamp = match self.max_age {
+            Some(max_age) => {
+                let since = SystemTime::now()
+                    .checked_sub(max_age)
+                    .and_then(|since| since.duration_since(SystemTime::UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                since.as_micros() as u64
+            }
+            None => 0,
+        };
+        Ok(move |packet: &SignedPacket| {
+            packet.timestamp() >= min_timestamp
+                && (pubkeys.is_empty()
+                    || pubkeys.contains(&PublicKeyBytes::from_signed_packet(packet)))
+        })
+    }
+}
+
+/// A line of an export
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedPacket {
+    /// The z-base-32 encoded public key
+    public_key: String,
+    /// The timestamp of the packet, in microseconds since the unix epoch
+    timestamp: u64,
+    /// The signed packet, base64 encoded as public key, signature, timestamp and DNS packet
+    packet: String,
+}
+
+/// The result of an import
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    /// The number of packets that were stored
+    pub imported: u64,
+    /// The number of packets that were filtered out, or not newer than the stored packet
+    pub skipped: u64,
+    /// The number of lines that are not a valid signed packet
+    pub invalid: u64,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "imported {} packets, skipped {}, {} invalid",
+            self.imported, self.skipped, self.invalid
+        )
+    }
+}
+
+/// Write the packets in `store` that match `filter` to `out`, and return how many.
+pub(crate) async fn export(
+    store: &ZoneStore,
+    filter: &PacketFilter,
+    mut out: impl Write,
+) -> Result<u64> {
+    let matches = filter.matcher()?;
+    let mut exported = 0;
+    let mut after = None;
+    loop {
+        let packets = store.packets_after(after, PAGE_SIZE).await?;
+        let Some(last) = packets.last() else {
+            break;
+        };
+        after = Some(PublicKeyBytes::from_signed_packet(last));
+        for packet in packets.iter().filter(|packet| matches(packet)) {
+            let line = ExportedPacket {
+                public_key: packet.public_key().to_z32(),
+                timestamp: packet.timestamp(),
+                packet: STANDARD.encode(packet.as_bytes()),
+            };
+            serde_json::to_writer(&mut out, &line)?;
+            writeln!(out)?;
+            exported += 1;
+        }
+    }
+    out.flush()?;
+    Ok(exported)
+}
+
+/// Store the packets of an export in `input` that match `filter` in `store`.
+///
+/// Invalid lines are skipped, so that one bad packet doesn't stop the import.
+pub(crate) async fn import(
+    store: &ZoneStore,
+    filter: &PacketFilter,
+    input: impl BufRead,
+) -> Result<ImportSummary> {
+    let matches = filter.matcher()?;
+    let mut summary = ImportSummary::default();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let signed_packet = match serde_json::from_str::<ExportedPacket>(&line)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(STANDARD.decode(line.packet)?))
+            .and_then(|bytes| Ok(SignedPacket::from_bytes(&bytes.into())?))
+        {
+            Ok(signed_packet) => signed_packet,
+            Err(err) => {
+                warn!("invalid packet on line {}: {err:#}", i + 1);
+                summary.invalid += 1;
+                continue;
+            }
+        };
+        if !matches(&signed_packet) {
+            summary.skipped += 1;
+            continue;
+        }
+        // re-importing the same packets must not change the store, and its change feed
+        let pubkey = PublicKeyBytes::from_signed_packet(&signed_packet);
+        let stored = store.get_signed_packet(&pubkey).await?;
+        if matches!(stored, Some(stored) if stored.timestamp() >= signed_packet.timestamp()) {
+            summary.skipped += 1;
+        } else if store.insert(signed_packet, PacketSource::Import).await? {
+            summary.imported += 1;
+        } else {
+            summary.skipped += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// Write the packets that match `filter` in the packet database at `path` to `out`, and return
+/// how many.
+///
+/// This fails if the database is opened by a running server.
+pub async fn export_store(path: &Path, filter: &PacketFilter, out: impl Write) -> Result<u64> {
+    ensure!(path.exists(), "no packet database at {}", path.display());
+    let store = ZoneStore::persistent(path)
+        .context("failed to open the packet database, is the server running?")?;
+    let exported = export(&store, filter, out).await;
+    store.close().await?;
+    exported
+}
+
+/// Store the packets of an export in `input` that match `filter` in the packet database at
+/// `path`, which is created if it doesn't exist.
+///
+/// Packets that are not newer than the stored packet of their public key are skipped. This
+/// fails if the database is opened by a running server.
+pub async fn import_store(
+    path: &Path,
+    filter: &PacketFilter,
+    input: impl BufRead,
+) -> Result<ImportSummary> {
+    let store = ZoneStore::persistent(path)
+        .context("failed to open the packet database, is the server running?")?;
+    let summary = import(&store, filter, input).await;
+    store.close().await?;
+    summary
+}
+
+/// The result of a verification of the packet database
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// The number of packets that were checked
+    pub checked: u64,
+    /// The packets with an invalid signature or encoding
+    pub invalid: Vec<InvalidPacket>,
+    /// Whether the invalid packets were removed
+    pub pruned: bool,
+}
+
+/// A stored packet with an invalid signature or encoding
+#[derive(Debug)]
+pub struct InvalidPacket {
+    /// The z-base-32 encoded public key the packet is stored under
+    pub public_key: String,
+    /// Why the packet is invalid
+    pub reason: String,
+}
+
+/// Check the signature and encoding of every packet in `store`, and remove the invalid ones if
+/// `prune` is set.
+pub(crate) async fn verify(store: &ZoneStore, prune: bool) -> Result<VerifyReport> {
+    let mut report = VerifyReport {
+        pruned: prune,
+        ..Default::default()
+    };
+    let mut invalid = Vec::new();
+    let mut after = None;
+    loop {
+        let rows = store.raw_packets_after(after, PAGE_SIZE).await?;
+        let Some((last, _)) = rows.last() else {
+            break;
+        };
+        after = Some(*last);
+        report.checked += rows.len() as u64;
+        for (key, bytes) in rows {
+            let reason = match SignedPacket::from_bytes(&bytes.into()) {
+                Err(err) => format!("{err}"),
+                Ok(packet) if PublicKeyBytes::from_signed_packet(&packet) != key => format!(
+                    "signed by {}, not by the key it is stored under",
+                    packet.public_key().to_z32()
+                ),
+                Ok(_) => continue,
+            };
+            invalid.push((key, reason));
+        }
+    }
+    // removing while paging would skip packets
+    for (key, reason) in invalid {
+        if prune {
+            store.remove(&key).await?;
+        }
+        report.invalid.push(InvalidPacket {
+            public_key: key.to_z32(),
+            reason,
+        });
+    }
+    Ok(report)
+}
+
+/// Check the signature and encoding of every packet in the packet database at `path`, and remove
+/// the invalid ones if `prune` is set.
+///
+/// This fails if the database is opened by a running server.
+pub async fn verify_store(path: &Path, prune: bool) -> Result<VerifyReport> {
+    ensure!(path.exists(), "no packet database at {}", path.display());
+    let store = ZoneStore::persistent(path)
+        .context("failed to open the packet database, is the server running?")?;
+    let report = verify(&store, prune).await;
+    store.close().await?;
+    report
+}
+
+/// The name of a record relative to the public key of its packet, `@` for the key itself.
+fn relative_name(name: &str, z32: &str) -> String {
+    let name = name.trim_end_matches('.');
+    match name.strip_suffix(z32) {
+        Some("") => "@".to_string(),
+        Some(prefix) => prefix.trim_end_matches('.').to_string(),
+        None => name.to_string(),
+    }
+}
+
+impl fmt::Display for DbStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(size) = self.file_size {
+            writeln!(f, "file size:  {size} bytes")?;
+        }
+        writeln!(f, "packets:    {}", self.packets)?;
+        let time = |timestamp: Option<u64>| {
+            timestamp
+                .and_then(|t| OffsetDateTime::from_unix_timestamp_nanos(t as i128 * 1000).ok())
+                .and_then(|time| time.format(&Rfc3339).ok())
+                .unwrap_or_else(|| "-".to_string())
+        };
+        writeln!(f, "oldest:     {}", time(self.oldest_timestamp))?;
+        writeln!(f, "newest:     {}", time(self.newest_timestamp))?;
+        writeln!(f, "sizes:")?;
+        for bucket in &self.sizes {
+            writeln!(f, "  <= {:4} bytes  {}", bucket.max_bytes, bucket.packets)?;
+        }
+        writeln!(f, "record names:")?;
+        for (name, packets) in &self.record_names {
+            writeln!(f, "  {name}  {packets}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pkarr::{
+        dns::{rdata::TXT, Name, Packet, ResourceRecord, CLASS},
+        Keypair,
+    };
+
+    use super::*;
+
+    fn signed_packet(keypair: &Keypair) -> Result<SignedPacket> {
+        let mut packet = Packet::new_reply(0);
+        let txt = TXT::new().with_string("hello")?;
+        packet.answers.push(ResourceRecord::new(
+            Name::new("_iroh").unwrap(),
+            CLASS::IN,
+            30,
+            pkarr::dns::rdata::RData::TXT(txt),
+        ));
+        Ok(SignedPacket::from_packet(keypair, &packet)?)
+    }
+
+    #[tokio::test]
+    async fn collect() -> Result<()> {
+        let store = ZoneStore::in_memory()?;
+        for _ in 0..3 {
+            let signed_packet = signed_packet(&Keypair::random())?;
+            store
+                .insert(signed_packet, PacketSource::PkarrPublish)
+                .await?;
+        }
+        let stats = DbStats::collect(&store).await?;
+        assert_eq!(stats.packets, 3);
+        assert_eq!(stats.file_size, None);
+        assert_eq!(stats.sizes[1].packets, 3);
+        assert_eq!(stats.record_names.get("_iroh"), Some(&3));
+        assert!(stats.oldest_timestamp <= stats.newest_timestamp);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_import() -> Result<()> {
+        let store = ZoneStore::in_memory()?;
+        let keypairs: Vec<_> = (0..3).map(|_| Keypair::random()).collect();
+        for keypair in &keypairs {
+            store
+                .insert(signed_packet(keypair)?, PacketSource::PkarrPublish)
+                .await?;
+        }
+        let mut out = Vec::new();
+        assert_eq!(export(&store, &PacketFilter::default(), &mut out).await?, 3);
+
+        let filter = PacketFilter {
+            pubkeys: vec![keypairs[1].public_key().to_z32()],
+            max_age: None,
+        };
+        out.extend_from_slice(b"not a packet\n");
+        let other = ZoneStore::in_memory()?;
+        let summary = import(&other, &filter, out.as_slice()).await?;
+        assert_eq!(
+            (summary.imported, summary.skipped, summary.invalid),
+            (1, 2, 1)
+        );
+        let pubkey = PublicKeyBytes::from_signed_packet(&signed_packet(&keypairs[1])?);
+        assert!(other.get_signed_packet(&pubkey).await?.is_some());
+
+        // the stored packets are not newer
+        let summary = import(&store, &PacketFilter::default(), out.as_slice()).await?;
+        assert_eq!(summary.imported, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_packets() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("iroh-dns-verify-{}", std::process::id()));
+        let path = dir.join("signed-packets-1.db");
+        let store = ZoneStore::persistent(&path)?;
+        let valid = signed_packet(&Keypair::random())?;
+        store
+            .insert(valid.clone(), PacketSource::PkarrPublish)
+            .await?;
+        store.close().await?;
+
+        // corrupt the database behind the back of the store
+        {
+            let table = redb::TableDefinition::<&[u8; 32], &[u8]>::new("signed-packets-1");
+            let db = redb::Database::create(&path)?;
+            let tx = db.begin_write()?;
+            {
+                let mut table = tx.open_table(table)?;
+                table.insert(&[1; 32], &b"garbage"[..])?;
+                table.insert(&[2; 32], &valid.as_bytes()[..])?;
+            }
+            tx.commit()?;
+        }
+
+        let store = ZoneStore::persistent(&path)?;
+        let report = verify(&store, false).await?;
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.invalid.len(), 2);
+        assert_eq!(store.packet_count().await?, 3);
+        let report = verify(&store, true).await?;
+        assert_eq!(report.invalid.len(), 2);
+        assert_eq!(store.packet_count().await?, 1);
+        store.close().await?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
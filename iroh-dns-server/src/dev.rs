@@ -0,0 +1,292 @@
+//! An embedded DNS server and pkarr relay for development tooling
+//!
+//! [`DevServer`] runs the server in the process of an example app or tutorial, so that it can use
+//! iroh discovery fully offline: the DNS server and the pkarr relay listen on localhost on random
+//! ports, the packets are kept in memory, and nothing is resolved from the mainline DHT. Node
+//! records can be added directly, without publishing them over HTTP.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use iroh_dns_server::dev::DevServer;
+//! use iroh_net::key::SecretKey;
+//!
+//! let secret_key = SecretKey::generate();
+//! let server = DevServer::builder()
+//!     .node(secret_key.clone(), Some("https://relay.example.".parse()?), [])
+//!     .spawn()
+//!     .await?;
+//! println!("pkarr relay at {}", server.pkarr_url());
+//! let discovery = server.discovery(secret_key);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result};
+use hickory_server::resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig},
+    AsyncResolver,
+};
+use iroh_net::{
+    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
+    dns::{node_info::NodeInfo, DnsResolver},
+    key::SecretKey,
+    relay::RelayUrl,
+};
+use pkarr::SignedPacket;
+use url::Url;
+
+use crate::{
+    config::{Config, MetricsConfig},
+    server::Server,
+    store::PacketSource,
+};
+
+/// The origin of the nodes on a [`DevServer`] by default.
+pub const DEFAULT_ORIGIN: &str = "dns.iroh.test";
+/// The TTL of the node records that [`node_record`] creates.
+const NODE_TTL: u32 = 30;
+
+/// The builder of a [`DevServer`]
+#[derive(Debug, Clone)]
+pub struct DevServerBuilder {
+    node_origin: String,
+    dns_port: u16,
+    http_port: u16,
+    nodes: Vec<(SecretKey, Option<RelayUrl>, Vec<SocketAddr>)>,
+    packets: Vec<SignedPacket>,
+}
+
+impl Default for DevServerBuilder {
+    fn default() -> Self {
+        Self {
+            node_origin: DEFAULT_ORIGIN.to_string(),
+            dns_port: 0,
+            http_port: 0,
+            nodes: Vec::new(),
+            packets: Vec::new(),
+        }
+    }
+}
+
+impl DevServerBuilder {
+    /// Set the origin of the node records (defaults to [`DEFAULT_ORIGIN`]).
+    pub fn origin(mut self, node_origin: impl Into<String>) -> Self {
+        self.node_origin = node_origin.into();
+        self
+    }
+
+    /// Set the port of the DNS server (random by default).
+    pub fn dns_port(mut self, port: u16) -> Self {
+        self.dns_port = port;
+        self
+    }
+
+    /// Set the port of the pkarr relay (random by default).
+    pub fn http_port(mut self, port: u16) -> Self {
+        self.http_port = port;
+        self
+    }
+
+    /// Add the node record of `secret_key` on start, see [`DevServer::add_node`].
+    pub fn node(
+        mut self,
+        secret_key: SecretKey,
+        relay_url: Option<RelayUrl>,
+        direct_addresses: impl IntoIterator<Item = SocketAddr>,
+    ) -> Self {
+        self.nodes.push((
+            secret_key,
+            relay_url,
+            direct_addresses.into_iter().collect(),
+        ));
+        self
+    }
+
+    /// Add `packet` on start, see [`DevServer::add_packet`].
+    pub fn packet(mut self, packet: SignedPacket) -> Self {
+        self.packets.push(packet);
+        self
+    }
+
+    /// Spawn the server, and add the nodes and packets.
+    pub async fn spawn(self) -> Result<DevServer> {
+        let config = config(&self.node_origin, self.dns_port, self.http_port);
+        let server = Server::builder().config(config).spawn().await?;
+        let http_addr = server
+            .http_addr()
+            .context("the HTTP server is not running")?;
+        let server = DevServer {
+            node_origin: self.node_origin.trim_end_matches('.').to_string(),
+            nameserver: server.dns_addr(),
+            pkarr_url: format!("http://{http_addr}/pkarr").parse()?,
+            server,
+        };
+        for (secret_key, relay_url, direct_addresses) in self.nodes {
+            server
+                .add_node(&secret_key, relay_url, direct_addresses)
+                .await?;
+        }
+        for packet in self.packets {
+            server.add_packet(packet).await?;
+        }
+        Ok(server)
+    }
+}
+
+/// A DNS server and pkarr relay on localhost, which keeps the packets in memory
+///
+/// The servers run until [`Self::shutdown`] is called.
+#[derive(derive_more::Debug)]
+pub struct DevServer {
+    node_origin: String,
+    nameserver: SocketAddr,
+    pkarr_url: Url,
+    #[debug("Server")]
+    server: Server,
+}
+
+impl DevServer {
+    /// Create a builder for the server.
+    pub fn builder() -> DevServerBuilder {
+        DevServerBuilder::default()
+    }
+
+    /// Spawn the server on random ports, with the node records under [`DEFAULT_ORIGIN`].
+    pub async fn spawn() -> Result<Self> {
+        Self::builder().spawn().await
+    }
+
+    /// The origin of the node records, for [`DnsDiscovery::new`].
+    pub fn node_origin(&self) -> &str {
+        &self.node_origin
+    }
+
+    /// The socket address of the DNS server.
+    pub fn nameserver(&self) -> SocketAddr {
+        self.nameserver
+    }
+
+    /// The URL of the pkarr relay, for [`PkarrPublisher::new`].
+    pub fn pkarr_url(&self) -> &Url {
+        &self.pkarr_url
+    }
+
+    /// The server, e.g. for its store.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Create a [`ConcurrentDiscovery`] with [`DnsDiscovery`] and [`PkarrPublisher`] configured
+    /// to use the servers.
+    ///
+    /// The [`DnsDiscovery`] resolves with the DNS resolver of the endpoint, so the endpoint needs
+    /// the resolver of [`Self::dns_resolver`].
+    pub fn discovery(&self, secret_key: SecretKey) -> Box<ConcurrentDiscovery> {
+        Box::new(ConcurrentDiscovery::from_services(vec![
+            Box::new(DnsDiscovery::new(self.node_origin.clone())),
+            Box::new(PkarrPublisher::new(secret_key, self.pkarr_url.clone())),
+        ]))
+    }
+
+    /// Create a [`DnsResolver`] that only asks the DNS server.
+    pub fn dns_resolver(&self) -> DnsResolver {
+        let mut config = ResolverConfig::new();
+        config.add_name_server(NameServerConfig::new(self.nameserver, Protocol::Udp));
+        AsyncResolver::tokio(config, Default::default())
+    }
+
+    /// Store the node record of `secret_key`, as if the node had published it.
+    ///
+    /// A newer record of the node replaces it, e.g. when the node publishes its own.
+    pub async fn add_node(
+        &self,
+        secret_key: &SecretKey,
+        relay_url: Option<RelayUrl>,
+        direct_addresses: impl IntoIterator<Item = SocketAddr>,
+    ) -> Result<SignedPacket> {
+        let packet = node_record(secret_key, relay_url, direct_addresses)?;
+        self.add_packet(packet.clone()).await?;
+        Ok(packet)
+    }
+
+    /// Store `packet`, as if it had been published.
+    pub async fn add_packet(&self, packet: SignedPacket) -> Result<()> {
+        self.server
+            .store()
+            .insert(packet, PacketSource::PkarrPublish)
+            .await?;
+        Ok(())
+    }
+
+    /// Shut down the servers.
+    pub async fn shutdown(self) -> Result<()> {
+        self.server.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// The config of an embedded server: the [`Config::dev`] config with DNS and HTTP on the ports
+/// on localhost, without HTTPS and metrics.
+///
+/// The ports are random if they are `0`.
+pub fn config(node_origin: &str, dns_port: u16, http_port: u16) -> Config {
+    let mut config = Config::dev();
+    config.dns.port = dns_port;
+    config.dns.origins = vec![format!("{}.", node_origin.trim_end_matches('.'))];
+    let http = config.http.as_mut().expect("http is set by default");
+    http.port = Some(http_port);
+    config.https = None;
+    config.metrics = Some(MetricsConfig::disabled());
+    // offline, the packets are only resolved from the store
+    config.mainline = None;
+    config
+}
+
+/// Create the signed node record of `secret_key`, with the `relay_url` and `direct_addresses`
+/// that iroh discovery resolves.
+pub fn node_record(
+    secret_key: &SecretKey,
+    relay_url: Option<RelayUrl>,
+    direct_addresses: impl IntoIterator<Item = SocketAddr>,
+) -> Result<SignedPacket> {
+    let node_info = NodeInfo::new(
+        secret_key.public(),
+        relay_url.map(Url::from),
+        direct_addresses.into_iter().collect(),
+    );
+    node_info.to_pkarr_signed_packet(secret_key, NODE_TTL)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use iroh_net::dns::ResolverExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_added_node() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr: SocketAddr = (Ipv4Addr::LOCALHOST, 4433).into();
+        let server = DevServer::builder()
+            .origin("dev.example")
+            .node(secret_key, None, [addr])
+            .spawn()
+            .await?;
+        assert_eq!(server.node_origin(), "dev.example");
+
+        let info = server
+            .dns_resolver()
+            .lookup_by_id(&node_id, server.node_origin())
+            .await?;
+        assert_eq!(info.node_id, node_id);
+        assert_eq!(info.info.direct_addresses, [addr].into());
+        server.shutdown().await?;
+        Ok(())
+    }
+}
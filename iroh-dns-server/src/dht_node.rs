@@ -0,0 +1,153 @@
+//! Participation in the mainline DHT as a storing node
+//!
+//! With [`DhtNodeConfig`], the DHT client of the mainline fallback answers the requests of other
+//! DHT nodes and stores their values, like the pkarr packets of nodes that don't publish to this
+//! server, instead of only sending requests. The server then is part of the routing tables of
+//! the DHT, which shortens its own lookups and adds to the capacity of the DHT. The stored values
+//! and the answered requests per second are limited, and the requests are counted in the
+//! `dht_node_requests` metric.
+
+use std::{net::SocketAddr, time::Instant};
+
+use mainline::{
+    rpc::{
+        messages::{RequestSpecific, RequestTypeSpecific},
+        Rpc,
+    },
+    server::{DhtServer, DhtServerSettings, Server},
+};
+use tracing::trace;
+
+use crate::{
+    config::DhtNodeConfig,
+    metrics::{DhtNodeMetrics, DhtRequestKind},
+};
+
+/// A [`DhtServer`] which answers at most a number of requests per second
+#[derive(Debug)]
+pub(crate) struct LimitedDhtServer {
+    inner: DhtServer,
+    max_requests_per_sec: Option<u32>,
+    /// The start of the current second, and the requests answered in it
+    window: (Instant, u32),
+}
+
+impl LimitedDhtServer {
+    pub(crate) fn new(config: &DhtNodeConfig) -> Self {
+        let max_values = config.max_stored_values.unwrap_or_default();
+        let settings = DhtServerSettings {
+            max_immutable_values: max_values,
+            max_mutable_values: max_values,
+            // zero uses the defaults of the mainline crate
+            ..Default::default()
+        };
+        Self {
+            inner: DhtServer::new(&settings),
+            max_requests_per_sec: config.max_requests_per_sec,
+            window: (Instant::now(), 0),
+        }
+    }
+
+    /// Count a request in the current second, and return whether it is within the limit.
+    fn allow(&mut self) -> bool {
+        let Some(max) = self.max_requests_per_sec else {
+            return true;
+        };
+        let (start, count) = &mut self.window;
+        if start.elapsed().as_secs() >= 1 {
+            *start = Instant::now();
+            *count = 0;
+        }
+        *count += 1;
+        *count <= max
+    }
+}
+
+impl Server for LimitedDhtServer {
+    fn handle_request(
+        &mut self,
+        rpc: &mut Rpc,
+        from: SocketAddr,
+        transaction_id: u16,
+        request: &RequestSpecific,
+    ) {
+        let kind = match request.request_type {
+            RequestTypeSpecific::Ping => DhtRequestKind::Ping,
+            RequestTypeSpecific::FindNode(_) => DhtRequestKind::FindNode,
+            RequestTypeSpecific::GetPeers(_) => DhtRequestKind::GetPeers,
+            RequestTypeSpecific::GetValue(_) => DhtRequestKind::GetValue,
+            RequestTypeSpecific::Put(_) => DhtRequestKind::Put,
+        };
+        // this runs on the thread of the DHT client, so it must not block
+        let allowed = self.allow();
+        DhtNodeMetrics::count(kind, !allowed);
+        if !allowed {
+            trace!(?kind, %from, "dropping DHT request above the limit");
+            return;
+        }
+        self.inner
+            .handle_request(rpc, from, transaction_id, request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use mainline::{
+        dht::DhtSettings,
+        rpc::messages::{Message, MessageType},
+        Dht, Id,
+    };
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// Send a ping to `addr`, and return whether it was answered.
+    async fn ping(socket: &UdpSocket, addr: SocketAddr) -> Result<bool> {
+        let request = Message {
+            transaction_id: rand::random(),
+            version: None,
+            requester_ip: None,
+            message_type: MessageType::Request(RequestSpecific {
+                requester_id: Id::random(),
+                request_type: RequestTypeSpecific::Ping,
+            }),
+            read_only: true,
+        };
+        socket.send_to(&request.to_bytes()?, addr).await?;
+        let mut buf = [0u8; 2048];
+        let res = tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf)).await;
+        Ok(res.is_ok())
+    }
+
+    #[tokio::test]
+    async fn requests_above_the_limit_are_dropped() -> Result<()> {
+        let config = DhtNodeConfig {
+            max_requests_per_sec: Some(1),
+            ..Default::default()
+        };
+        let dht = Dht::new(DhtSettings {
+            bootstrap: Some(Vec::new()),
+            server: Some(Box::new(LimitedDhtServer::new(&config))),
+            port: Some(0),
+            ..Default::default()
+        })?;
+        let port = dht.local_addr().expect("dht is running").port();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+
+        assert!(ping(&socket, addr).await?);
+        assert!(!ping(&socket, addr).await?);
+        let dropped = DhtNodeMetrics::get()
+            .dht_node_requests
+            .get_or_create(&crate::metrics::DhtRequestLabels {
+                request: DhtRequestKind::Ping,
+                dropped: true.to_string(),
+            })
+            .get();
+        assert_eq!(dropped, 1);
+        Ok(())
+    }
+}
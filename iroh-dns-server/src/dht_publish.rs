@@ -0,0 +1,331 @@
+//! Publishing of the stored packets to the mainline DHT
+//!
+//! With [`MainlineConfig::publish`], the packets published to this server are pushed to the
+//! mainline DHT, so that nodes which only publish to this server can also be resolved by clients
+//! of the DHT. DHT nodes drop values after a few hours, so the current packet of each key in the
+//! store is republished every `republish_interval_secs`. The packets in the store when the server
+//! starts are spread over the first minutes, and the store is scanned for the packets of other
+//! servers on each interval.
+//!
+//! A failed publish is retried with an exponential backoff, and at most
+//! `max_concurrent_publishes` run at a time. The publishes are counted in the
+//! `mainline_publishes` metric.
+//!
+//! Of the servers that share a packet store, only the leader publishes. A server that becomes the
+//! leader starts like a server that just started.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use anyhow::Result;
+use pkarr::PkarrClientAsync;
+use rand::Rng;
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinSet,
+    time::{Instant, Interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::MainlineConfig,
+    events::ServerEvent,
+    metrics::{DhtPublishOutcome, MainlineMetrics},
+    store::ZoneStore,
+    util::PublicKeyBytes,
+};
+
+/// The interval of the republishes, well within the hours that DHT nodes keep the values.
+const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The shortest interval of the republishes, so that the DHT is not flooded.
+const MIN_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_CONCURRENT_PUBLISHES: usize = 16;
+/// The time the packets of the store are spread over when the server starts.
+const STARTUP_SPREAD: Duration = Duration::from_secs(5 * 60);
+/// The delay of the first retry of a failed publish, doubled with each failure up to the
+/// republish interval.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+/// Interval at which due publishes are started.
+const TICK: Duration = Duration::from_secs(1);
+/// Number of keys that are read from the store at once when scanning it.
+const PAGE_SIZE: usize = 1000;
+/// Interval at which the server checks whether it leads the servers of a shared store.
+const LEADER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The publishes of a key
+#[derive(Debug, Default)]
+struct Entry {
+    /// When the packet is published next, unset while a publish runs
+    due: Option<Instant>,
+    /// The failed publishes since the last successful one
+    failures: u32,
+    /// The timestamp of the packet that was last published successfully
+    published: Option<u64>,
+    /// Whether the packet changed while a publish ran, and is published again right after it
+    changed: bool,
+}
+
+/// Publishes the packets of the store to the mainline DHT, see the [module docs](self).
+#[derive(derive_more::Debug)]
+pub(crate) struct DhtPublisher {
+    #[debug("PkarrClientAsync")]
+    pkarr: PkarrClientAsync,
+    interval: Duration,
+    max_concurrent: usize,
+    entries: HashMap<PublicKeyBytes, Entry>,
+    /// The due publishes, by time
+    queue: BTreeSet<(Instant, PublicKeyBytes)>,
+}
+
+impl DhtPublisher {
+    /// Create a publisher with the DHT client of `store` and the limits of `config`.
+    ///
+    /// Returns `None` if the store does not use the mainline DHT.
+    pub(crate) fn new(config: &MainlineConfig, store: &ZoneStore) -> Option<Self> {
+        let pkarr = store.pkarr_client()?;
+        let mut interval = config
+            .republish_interval_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_REPUBLISH_INTERVAL);
+        if interval < MIN_REPUBLISH_INTERVAL {
+            warn!(
+                "mainline.republish_interval_secs is below the minimum, republishing every {}s",
+                MIN_REPUBLISH_INTERVAL.as_secs()
+            );
+            interval = MIN_REPUBLISH_INTERVAL;
+        }
+        Some(Self {
+            pkarr: pkarr.as_ref().clone().as_async(),
+            interval,
+            max_concurrent: config
+                .max_concurrent_publishes
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_PUBLISHES)
+                .max(1),
+            entries: HashMap::new(),
+            queue: BTreeSet::new(),
+        })
+    }
+
+    /// Publish the packets of `store`, and republish them, while this server leads the servers
+    /// of the store, until the task is aborted.
+    pub(crate) async fn run(mut self, store: ZoneStore) {
+        // subscribe before reading the store, so that no publish is missed
+        let mut events = store.events().subscribe();
+        let mut leader_check = tokio::time::interval(LEADER_CHECK_INTERVAL);
+        leader_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            leader_check.tick().await;
+            if !store.is_leader().await {
+                continue;
+            }
+            self.lead(&store, &mut events, &mut leader_check).await;
+            info!("no longer leading, stopped publishing to the mainline DHT");
+            self.entries.clear();
+            self.queue.clear();
+        }
+    }
+
+    /// Publish the packets of `store`, and republish them, until this server no longer leads the
+    /// servers of the store.
+    async fn lead(
+        &mut self,
+        store: &ZoneStore,
+        events: &mut broadcast::Receiver<ServerEvent>,
+        leader_check: &mut Interval,
+    ) {
+        info!(
+            interval = ?self.interval,
+            "publishing the stored packets to the mainline DHT"
+        );
+        let spread = STARTUP_SPREAD.min(self.interval);
+        self.scan(store, spread).await;
+        let mut publishes = JoinSet::new();
+        let mut tick = tokio::time::interval(TICK);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut rescan = tokio::time::interval_at(Instant::now() + self.interval, self.interval);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(ServerEvent::PacketPublished { pubkey, .. }) => {
+                        if let Ok(key) = PublicKeyBytes::from_z32(&pubkey) {
+                            self.schedule_now(key);
+                        }
+                    }
+                    Ok(
+                        ServerEvent::PacketExpired { pubkey, .. }
+                        | ServerEvent::PacketRemoved { pubkey },
+                    ) => {
+                        if let Ok(key) = PublicKeyBytes::from_z32(&pubkey) {
+                            self.remove(&key);
+                        }
+                    }
+                    Ok(_) => {}
+                    // the missed packets are published with the next scan
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!(missed, "DHT publisher missed publishes");
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = tick.tick() => {
+                    let now = Instant::now();
+                    while publishes.len() < self.max_concurrent {
+                        let Some(key) = self.pop_due(now) else {
+                            break;
+                        };
+                        publishes.spawn(publish(self.pkarr.clone(), store.clone(), key));
+                    }
+                }
+                _ = rescan.tick() => self.scan(store, Duration::ZERO).await,
+                _ = leader_check.tick() => {
+                    if !store.is_leader().await {
+                        return;
+                    }
+                }
+                Some(res) = publishes.join_next() => match res {
+                    Ok((key, res)) => self.complete(key, res),
+                    Err(err) => warn!("DHT publish task failed: {err}"),
+                },
+            }
+        }
+    }
+
+    /// Schedule the keys of the store that are not scheduled yet, within `spread` from now.
+    async fn scan(&mut self, store: &ZoneStore, spread: Duration) {
+        let mut after = None;
+        let now = Instant::now();
+        let mut added = 0;
+        loop {
+            let packets = match store.raw_packets_after(after, PAGE_SIZE).await {
+                Ok(packets) => packets,
+                Err(err) => {
+                    warn!("failed to read the packets to publish to the DHT: {err:#}");
+                    return;
+                }
+            };
+            let Some((last, _)) = packets.last() else {
+                break;
+            };
+            after = Some(*last);
+            for (key, _bytes) in packets {
+                if self.entries.contains_key(&key) {
+                    continue;
+                }
+                let delay = rand::thread_rng().gen_range(Duration::ZERO..=spread);
+                self.schedule(key, Entry::default(), now + delay);
+                added += 1;
+            }
+        }
+        debug!(
+            added,
+            total = self.entries.len(),
+            "scanned the store for the DHT publisher"
+        );
+    }
+
+    /// Publish the packet of `key` as soon as possible.
+    fn schedule_now(&mut self, key: PublicKeyBytes) {
+        match self.entries.get_mut(&key) {
+            // a publish runs, which may have read the previous packet
+            Some(entry) if entry.due.is_none() => entry.changed = true,
+            _ => {
+                let mut entry = self.remove(&key).unwrap_or_default();
+                entry.failures = 0;
+                self.schedule(key, entry, Instant::now());
+            }
+        }
+    }
+
+    fn schedule(&mut self, key: PublicKeyBytes, mut entry: Entry, due: Instant) {
+        entry.due = Some(due);
+        self.queue.insert((due, key));
+        self.entries.insert(key, entry);
+    }
+
+    /// Stop publishing the packet of `key`, and return its entry.
+    fn remove(&mut self, key: &PublicKeyBytes) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(due) = entry.due {
+            self.queue.remove(&(due, *key));
+        }
+        Some(entry)
+    }
+
+    /// Take the next key that is due at `now`, and mark its publish as running.
+    fn pop_due(&mut self, now: Instant) -> Option<PublicKeyBytes> {
+        let (due, key) = *self.queue.first()?;
+        if due > now {
+            return None;
+        }
+        self.queue.pop_first();
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.due = None;
+        }
+        Some(key)
+    }
+
+    /// Record the result of the publish of `key`, the timestamp of the published packet or
+    /// `None` if there is none, and schedule the next publish.
+    fn complete(&mut self, key: PublicKeyBytes, res: Result<Option<u64>>) {
+        // the packet was removed while the publish ran
+        let Some(mut entry) = self.entries.remove(&key) else {
+            return;
+        };
+        let now = Instant::now();
+        let due = match res {
+            Ok(None) if !entry.changed => return,
+            Ok(None) => now,
+            Ok(Some(timestamp)) => {
+                let outcome = match entry.published {
+                    Some(published) if published == timestamp => DhtPublishOutcome::Republished,
+                    _ => DhtPublishOutcome::Published,
+                };
+                MainlineMetrics::count_publish(outcome);
+                entry.failures = 0;
+                entry.published = Some(timestamp);
+                now + self.interval
+            }
+            // the DHT client resolved a more recent packet than the one in the store
+            Err(err) if matches!(err.downcast_ref(), Some(pkarr::Error::NotMostRecent)) => {
+                debug!(key = %key.to_z32(), "not publishing a packet older than the DHT's");
+                now + self.interval
+            }
+            Err(err) => {
+                MainlineMetrics::count_publish(DhtPublishOutcome::Failed);
+                entry.failures += 1;
+                let backoff = RETRY_DELAY
+                    .saturating_mul(1 << entry.failures.min(16))
+                    .min(self.interval);
+                debug!(
+                    key = %key.to_z32(),
+                    failures = entry.failures,
+                    "failed to publish the packet to the DHT: {err:#}"
+                );
+                now + backoff
+            }
+        };
+        let due = match std::mem::take(&mut entry.changed) {
+            true => now,
+            false => due,
+        };
+        self.schedule(key, entry, due);
+    }
+}
+
+/// Publish the current packet of `key` in `store`, and return its timestamp, or `None` if there
+/// is none.
+async fn publish(
+    pkarr: PkarrClientAsync,
+    store: ZoneStore,
+    key: PublicKeyBytes,
+) -> (PublicKeyBytes, Result<Option<u64>>) {
+    let res = async {
+        let Some(packet) = store.get_signed_packet(&key).await? else {
+            return Ok(None);
+        };
+        pkarr.publish(&packet).await?;
+        Ok(Some(packet.timestamp()))
+    };
+    (key, res.await)
+}
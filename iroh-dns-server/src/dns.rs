@@ -3,60 +3,140 @@
 use std::{
     collections::BTreeMap,
     io,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
 use hickory_server::{
-    authority::{Catalog, MessageResponse, ZoneType},
+    authority::{Catalog, MessageRequest, MessageResponse, MessageResponseBuilder, ZoneType},
     proto::{
         self,
         rr::{
             rdata::{self},
             RData, Record, RecordSet, RecordType, RrKey,
         },
-        serialize::{binary::BinEncoder, txt::RDataParser},
+        serialize::{
+            binary::{BinDecodable, BinEncoder},
+            txt::RDataParser,
+        },
     },
     resolver::Name,
-    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+    server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo},
     store::in_memory::InMemoryAuthority,
 };
 
+use ipnet::IpNet;
 use iroh_metrics::inc;
-use proto::{op::ResponseCode, rr::LowerName};
+use proto::{
+    op::{Header, OpCode, ResponseCode},
+    rr::LowerName,
+};
 use serde::{Deserialize, Serialize};
 use tokio::{
-    net::{TcpListener, UdpSocket},
-    sync::broadcast,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{broadcast, mpsc, Semaphore},
+    task::{JoinHandle, JoinSet},
 };
-use tracing::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, info, warn, Instrument};
 
-use crate::{metrics::Metrics, store::ZoneStore};
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, Chaos, ChaosEndpoint};
+use crate::{
+    abuse::IpReputation,
+    acl::{Acl, Operation},
+    analytics::Analytics,
+    config::{BindAddr, IpStack},
+    health::Health,
+    hourly_stats::{Counter, HourlyStats},
+    metrics::{AbuseMetrics, DnsMetrics, LimitedResource, LoadShedMetrics, Metrics},
+    proxy_protocol,
+    query_log::{QueryLog, QueryRecord},
+    server::Shutdown,
+    state::TaskErrors,
+    store::ZoneStore,
+    tail::{self, QueryEvent},
+    usage::Usage,
+    util::{self, PublicKeyBytes},
+};
 
-use self::node_authority::NodeAuthority;
+pub(crate) use self::acme::AcmeChallenges;
+pub(crate) use self::replicas::advertise_replicas;
+pub(crate) use self::transfer::{notify_secondaries, run_secondary};
+use self::{
+    hooks::DnsHooks,
+    names::NameResolvers,
+    node_authority::{NodeAuthority, Ttls},
+    rate_limit::DnsRateLimiter,
+    traffic::TrafficStats,
+    transfer::Secondary,
+};
+pub use self::{
+    hooks::{BeforeLookup, DnsHook, DnsQuery},
+    names::{NameResolver, StaticNames},
+    rate_limit::DnsRateLimitConfig,
+    replicas::{Replica, ReplicasConfig},
+    tls::DnsTlsConfig,
+    transfer::{SecondaryConfig, TransferConfig},
+};
 
+mod acme;
+mod hooks;
+mod names;
 mod node_authority;
+mod rate_limit;
+mod replicas;
+mod tls;
+pub(crate) mod traffic;
+mod transfer;
 
 const DEFAULT_NS_TTL: u32 = 60 * 60 * 12; // 12h
 const DEFAULT_SOA_TTL: u32 = 60 * 60 * 24 * 14; // 14d
 const DEFAULT_A_TTL: u32 = 60 * 60; // 1h
 
+/// Idle timeout for DNS TCP connections.
+const TCP_TIMEOUT: Duration = Duration::from_millis(1000);
+
 /// DNS server settings
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DnsConfig {
     /// The port to serve a local UDP DNS server at
     pub port: u16,
-    /// The IPv4 or IPv6 address to bind the UDP DNS server.
-    /// Uses `0.0.0.0` if unspecified.
-    pub bind_addr: Option<IpAddr>,
+    /// The IPv4 or IPv6 addresses or interface names to bind the DNS server (will use 0.0.0.0 if
+    /// unset)
+    ///
+    /// Can be set to a single address or a list of addresses, e.g. to bind specific IPv4 and
+    /// IPv6 addresses or multiple interfaces, and to the name of an interface to bind all of its
+    /// addresses, see [`BindAddr`]. A UDP socket and a TCP listener are bound for each
+    /// address, and their requests are counted separately in the `dns_socket_requests` metric.
+    #[serde(default, deserialize_with = "util::one_or_many")]
+    pub bind_addr: Vec<BindAddr>,
     /// SOA record data for any authoritative DNS records
     pub default_soa: String,
     /// Default time to live for returned DNS records (TXT & SOA)
     pub default_ttl: u32,
+    /// The lowest TTL of the answers from pkarr packets (defaults to 0).
+    ///
+    /// The answers have the TTLs of the records in the published packets, which are raised to
+    /// `min_ttl` and lowered to `max_ttl`, so that nodes can choose how long their records are
+    /// cached within the limits of the server.
+    #[serde(default)]
+    pub min_ttl: Option<u32>,
+    /// The highest TTL of the answers from pkarr packets (unlimited if unset).
+    #[serde(default)]
+    pub max_ttl: Option<u32>,
+    /// The TTLs of the answers from pkarr packets by record type, e.g. `A` or `TXT`.
+    ///
+    /// The limits of a record type replace `min_ttl` and `max_ttl` for its records, so that e.g.
+    /// the addresses of mobile nodes expire faster than their relay TXT records.
+    #[serde(default)]
+    pub ttls: BTreeMap<String, TtlConfig>,
     /// Domain used for serving the `_iroh_node.<nodeid>.<origin>` DNS TXT entry
     pub origins: Vec<String>,
 
@@ -66,55 +146,181 @@ pub struct DnsConfig {
     pub rr_aaaa: Option<Ipv6Addr>,
     /// `NS` record to set for all origins
     pub rr_ns: Option<String>,
+
+    /// Whether TCP connections start with a PROXY protocol (v1 or v2) header.
+    ///
+    /// See [`crate::http::HttpConfig::proxy_protocol`]. Does not apply to UDP.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+
+    /// Config for DNS-over-TLS and DNS-over-QUIC, as a `[dns.tls]` table.
+    ///
+    /// If set to `None` DNS is only served in plain text.
+    #[serde(default)]
+    pub tls: Option<DnsTlsConfig>,
+
+    /// Config for the rate limit of the requests by source address, as a `[dns.rate_limit]`
+    /// table.
+    ///
+    /// If set to `None` DNS requests are not rate limited, except for DNS-over-HTTPS, see
+    /// [`crate::http::HttpConfig::doh_rate_limit`].
+    #[serde(default)]
+    pub rate_limit: Option<DnsRateLimitConfig>,
+}
+
+/// The TTLs of the answers of a record type, see [`DnsConfig::ttls`]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TtlConfig {
+    /// The TTL of the records with a TTL of 0 in the packet.
+    pub default: Option<u32>,
+    /// The lowest TTL (defaults to `min_ttl`).
+    pub min: Option<u32>,
+    /// The highest TTL (defaults to `max_ttl`).
+    pub max: Option<u32>,
 }
 
 /// A DNS server that serves pkarr signed packets.
 pub struct DnsServer {
-    local_addr: SocketAddr,
-    server: hickory_server::ServerFuture<DnsHandler>,
+    local_addrs: Vec<SocketAddr>,
+    /// Runs a server for each bind address, so that the handler of each knows its socket, until
+    /// `cancel` is cancelled.
+    servers: JoinSet<Result<(), proto::error::ProtoError>>,
+    /// Shuts down the servers gracefully
+    cancel: CancellationToken,
+    /// Serves DNS over TCP if the PROXY protocol is enabled, which hickory does not support.
+    proxied_tcp: Vec<JoinHandle<()>>,
+    /// Serves DNS-over-TLS and DNS-over-QUIC, if they are enabled
+    encrypted: Vec<JoinHandle<()>>,
+    tls_addrs: Vec<SocketAddr>,
+    quic_addrs: Vec<SocketAddr>,
+    /// Removes the idle source addresses from the rate limiter, if there is one
+    rate_limit_gc: Option<JoinHandle<()>>,
 }
 
 impl DnsServer {
     /// Spawn the server.
-    pub async fn spawn(config: DnsConfig, dns_handler: DnsHandler) -> Result<Self> {
-        const TCP_TIMEOUT: Duration = Duration::from_millis(1000);
-        let mut server = hickory_server::ServerFuture::new(dns_handler);
-
-        let bind_addr = SocketAddr::new(
-            config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
-            config.port,
-        );
-
-        let socket = UdpSocket::bind(bind_addr).await?;
-
-        let socket_addr = socket.local_addr()?;
-
-        server.register_socket(socket);
-        server.register_listener(TcpListener::bind(bind_addr).await?, TCP_TIMEOUT);
-        info!("DNS server listening on {}", bind_addr);
+    ///
+    /// Binds a UDP socket and a TCP listener for each of the configured bind addresses, or for
+    /// the wildcard addresses of `ip_stack` if none are configured, and the encrypted listeners
+    /// of [`DnsConfig::tls`] on the same addresses. Their certificate is stored in `data_dir`.
+    /// The errors of the servers are reported to `task_errors`.
+    pub async fn spawn(
+        config: DnsConfig,
+        ip_stack: IpStack,
+        dns_handler: DnsHandler,
+        data_dir: &Path,
+        task_errors: TaskErrors,
+    ) -> Result<Self> {
+        let mut local_addrs = Vec::new();
+        let mut servers = JoinSet::new();
+        let cancel = CancellationToken::new();
+        let mut proxied_tcp = Vec::new();
+        let encrypted = match &config.tls {
+            Some(tls) => spawn_encrypted(tls, &config, ip_stack, &dns_handler, data_dir).await?,
+            None => Default::default(),
+        };
+        for bind_addr in util::socket_addrs("dns", &config.bind_addr, Some(config.port), ip_stack)?
+        {
+            let socket = util::bind_udp(bind_addr)
+                .and_then(UdpSocket::from_std)
+                .with_context(|| format!("failed to bind DNS UDP socket on {bind_addr}"))?;
+            let socket_addr = socket.local_addr()?;
+            // with port 0, the TCP listener is bound to the port of the UDP socket
+            let listener = util::bind_tcp(socket_addr)
+                .and_then(TcpListener::from_std)
+                .with_context(|| format!("failed to bind DNS TCP listener on {socket_addr}"))?;
+            let dns_handler = dns_handler.for_socket(socket_addr);
+            let mut server = hickory_server::ServerFuture::new(dns_handler.clone());
+            server.register_socket(socket);
+            if config.proxy_protocol {
+                proxied_tcp.push(tokio::task::spawn(serve_tcp_proxied(listener, dns_handler)));
+            } else {
+                server.register_listener(listener, TCP_TIMEOUT);
+            }
+            info!("DNS server listening on {}", socket_addr);
+            local_addrs.push(socket_addr);
+            let cancel = cancel.clone();
+            let task_errors = task_errors.clone();
+            servers.spawn(async move {
+                let res = tokio::select! {
+                    res = server.block_until_done() => res,
+                    _ = cancel.cancelled() => server.shutdown_gracefully().await,
+                };
+                if let Err(err) = &res {
+                    task_errors.report("dns", err);
+                }
+                res
+            });
+        }
 
+        let (encrypted, tls_addrs, quic_addrs) = encrypted;
+        let rate_limit_gc = dns_handler
+            .rate_limiter
+            .clone()
+            .map(|limiter| tokio::task::spawn(limiter.run_gc()));
         Ok(Self {
-            server,
-            local_addr: socket_addr,
+            local_addrs,
+            servers,
+            cancel,
+            proxied_tcp,
+            encrypted,
+            tls_addrs,
+            quic_addrs,
+            rate_limit_gc,
         })
     }
 
-    /// Get the local address of the UDP/TCP socket.
+    /// Get the local address of the first UDP/TCP socket.
     pub fn local_addr(&self) -> SocketAddr {
-        self.local_addr
+        self.local_addrs[0]
+    }
+
+    /// Get the local addresses of all UDP/TCP sockets.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Get the local addresses of the DNS-over-TLS listeners.
+    pub fn tls_addrs(&self) -> &[SocketAddr] {
+        &self.tls_addrs
+    }
+
+    /// Get the local addresses of the DNS-over-QUIC endpoints.
+    pub fn quic_addrs(&self) -> &[SocketAddr] {
+        &self.quic_addrs
     }
 
     /// Shutdown the server an wait for all tasks to complete.
-    pub async fn shutdown(mut self) -> Result<()> {
-        self.server.shutdown_gracefully().await?;
-        Ok(())
+    ///
+    /// Stops accepting new requests and waits up to `timeout` for in-flight requests to finish.
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<Shutdown> {
+        let tasks = self.proxied_tcp.iter().chain(&self.encrypted);
+        for task in tasks.chain(&self.rate_limit_gc) {
+            task.abort();
+        }
+        self.cancel.cancel();
+        let shutdown = async {
+            while let Some(res) = self.servers.join_next().await {
+                res??;
+            }
+            anyhow::Ok(())
+        };
+        match tokio::time::timeout(timeout, shutdown).await {
+            Ok(res) => res.map(|()| Shutdown::Clean),
+            Err(_) => {
+                warn!("DNS server did not shut down within {timeout:?}");
+                Ok(Shutdown::Forced)
+            }
+        }
     }
 
     /// Wait for all tasks to complete.
     ///
     /// Runs forever unless tasks fail.
     pub async fn run_until_done(mut self) -> Result<()> {
-        self.server.block_until_done().await?;
+        while let Some(res) = self.servers.join_next().await {
+            res??;
+        }
         Ok(())
     }
 }
@@ -124,6 +330,42 @@ impl DnsServer {
 pub struct DnsHandler {
     #[debug("Catalog")]
     catalog: Arc<Catalog>,
+    authority: Arc<NodeAuthority>,
+    #[cfg_attr(not(feature = "https"), allow(dead_code))]
+    acme_challenges: AcmeChallenges,
+    /// The origins, the most specific first
+    zones: Arc<Vec<LowerName>>,
+    traffic: Arc<TrafficStats>,
+    query_log: Option<QueryLog>,
+    /// The answered queries, for `GET /admin/tail`
+    queries: broadcast::Sender<QueryEvent>,
+    /// Permits for the requests in flight, if they are limited
+    requests: Option<Arc<Semaphore>>,
+    /// Permits for the requests that are answered concurrently, if they are limited
+    answering: Option<Arc<Semaphore>>,
+    /// The local address of the UDP socket and TCP listener, unset for DNS-over-HTTPS
+    socket: Option<SocketAddr>,
+    /// The networks that may transfer the zones, if transfers are enabled
+    transfers: Option<Arc<Vec<IpNet>>>,
+    /// The primary to transfer the zones from, on a secondary
+    secondary: Option<Arc<Secondary>>,
+    /// The health of the server, if queries are not answered while it is unhealthy
+    health: Option<Health>,
+    /// The addresses whose requests are dropped
+    ip_reputation: IpReputation,
+    /// The rate limit of the requests by source address, if they are limited
+    rate_limiter: Option<DnsRateLimiter>,
+    /// The networks that may query, transfer and update the zones
+    acl: Acl,
+    /// The resolution counts, if they are enabled
+    analytics: Option<Analytics>,
+    /// The usage counts, if they are enabled
+    usage: Option<Usage>,
+    /// The hourly statistics, if they are enabled
+    hourly_stats: Option<HourlyStats>,
+    /// The injected faults, for tests of the clients
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
 }
 
 impl DnsHandler {
@@ -136,20 +378,232 @@ impl DnsHandler {
             .map(Name::from_utf8)
             .collect::<Result<Vec<_>, _>>()?;
 
+        let mut zones: Vec<LowerName> = origins.iter().map(LowerName::from).collect();
+        zones.sort_by_key(|zone| std::cmp::Reverse(zone.num_labels()));
         let (static_authority, serial) = create_static_authority(&origins, config)?;
-        let authority = NodeAuthority::new(zone_store, static_authority, origins, serial)?;
+        let acme_challenges = AcmeChallenges::default();
+        let ttls = Ttls::new(config)?;
+        let authority = NodeAuthority::new(
+            zone_store,
+            static_authority,
+            acme_challenges.clone(),
+            origins,
+            serial,
+        )?
+        .with_ttls(ttls);
         let authority = Arc::new(authority);
-
-        let mut catalog = Catalog::new();
-        for origin in authority.origins() {
-            catalog.upsert(LowerName::from(origin), Box::new(Arc::clone(&authority)));
-        }
+        let rate_limiter = config
+            .rate_limit
+            .as_ref()
+            .map(DnsRateLimiter::new)
+            .transpose()?;
 
         Ok(Self {
-            catalog: Arc::new(catalog),
+            catalog: Arc::new(catalog(&authority)),
+            authority,
+            acme_challenges,
+            zones: Arc::new(zones),
+            traffic: Default::default(),
+            query_log: None,
+            queries: broadcast::channel(tail::QUERIES_CAPACITY).0,
+            requests: None,
+            answering: None,
+            socket: None,
+            transfers: None,
+            secondary: None,
+            health: None,
+            ip_reputation: Default::default(),
+            rate_limiter,
+            acl: Default::default(),
+            analytics: None,
+            usage: None,
+            hourly_stats: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
         })
     }
 
+    /// The metrics label of the origin that `name` is in, or `other` if it is in none.
+    pub(crate) fn zone_of(&self, name: &LowerName) -> String {
+        match self.zones.iter().find(|zone| zone.zone_of(name)) {
+            Some(zone) if zone.is_root() => ".".to_string(),
+            Some(zone) => zone.to_string().trim_end_matches('.').to_string(),
+            None => "other".to_string(),
+        }
+    }
+
+    /// Replace the static records (SOA, A, AAAA and NS) of the origins with the ones in `config`.
+    ///
+    /// The origins themselves can't be changed at runtime.
+    pub(crate) fn reload_static_records(&self, config: &DnsConfig) -> Result<()> {
+        if self.authority.is_secondary() {
+            info!("not reloading the static DNS records, they are transferred from the primary");
+            return Ok(());
+        }
+        let origins: Vec<Name> = self.authority.origins().cloned().collect();
+        let (static_authority, serial) = create_static_authority(&origins, config)?;
+        self.authority
+            .replace_static_authority(static_authority, serial);
+        info!(serial, "reloaded static DNS records");
+        Ok(())
+    }
+
+    /// The handler for the requests received on the UDP socket and TCP listener at `addr`.
+    fn for_socket(&self, addr: SocketAddr) -> Self {
+        Self {
+            socket: Some(addr),
+            ..self.clone()
+        }
+    }
+
+    /// Export a sample of the queries to the query log.
+    pub(crate) fn with_query_log(self, query_log: QueryLog) -> Self {
+        Self {
+            query_log: Some(query_log),
+            ..self
+        }
+    }
+
+    /// Answer requests above `max` requests in flight with `SERVFAIL`.
+    pub(crate) fn with_max_requests(self, max: usize) -> Self {
+        Self {
+            requests: Some(Arc::new(Semaphore::new(max))),
+            ..self
+        }
+    }
+
+    /// Answer at most `max` requests concurrently, and let further requests wait.
+    pub(crate) fn with_concurrency(self, max: usize) -> Self {
+        Self {
+            answering: Some(Arc::new(Semaphore::new(max))),
+            ..self
+        }
+    }
+
+    /// Don't answer requests while `health` is unhealthy.
+    pub(crate) fn with_health(self, health: Health) -> Self {
+        Self {
+            health: Some(health),
+            ..self
+        }
+    }
+
+    /// Drop the requests of the addresses in `ip_reputation`.
+    pub(crate) fn with_ip_reputation(self, ip_reputation: IpReputation) -> Self {
+        Self {
+            ip_reputation,
+            ..self
+        }
+    }
+
+    /// Refuse the requests of the networks that `acl` doesn't allow.
+    pub(crate) fn with_acl(self, acl: Acl) -> Self {
+        Self { acl, ..self }
+    }
+
+    /// Count the resolutions of the packets in `analytics`.
+    pub(crate) fn with_analytics(self, analytics: Analytics) -> Self {
+        Self {
+            analytics: Some(analytics),
+            ..self
+        }
+    }
+
+    /// Count the resolutions of the tenants in `usage`.
+    pub(crate) fn with_usage(self, usage: Usage) -> Self {
+        Self {
+            usage: Some(usage),
+            ..self
+        }
+    }
+
+    /// Count the queries and answered pkarr names in `hourly_stats`.
+    pub(crate) fn with_hourly_stats(self, hourly_stats: HourlyStats) -> Self {
+        Self {
+            hourly_stats: Some(hourly_stats),
+            ..self
+        }
+    }
+
+    /// Inject the faults of `chaos` into the queries over UDP and TCP.
+    #[cfg(feature = "chaos")]
+    pub(crate) fn with_chaos(self, chaos: Chaos) -> Self {
+        Self {
+            chaos: Some(chaos),
+            ..self
+        }
+    }
+
+    /// Serve zone transfers to the secondaries in `config`.
+    pub(crate) fn with_transfers(self, config: &TransferConfig) -> Self {
+        Self {
+            transfers: Some(Arc::new(config.allow.clone())),
+            ..self
+        }
+    }
+
+    /// Run `hooks` on the queries for names in the origins, see [`DnsHook`], and resolve the names
+    /// of nodes with `names`, see [`NameResolver`].
+    pub(crate) fn with_extensions(
+        self,
+        hooks: Vec<Arc<dyn DnsHook>>,
+        names: Vec<Arc<dyn NameResolver>>,
+    ) -> Self {
+        let authority = Arc::new(
+            self.authority
+                .with_extensions(DnsHooks::new(hooks), NameResolvers::new(names)),
+        );
+        Self {
+            catalog: Arc::new(catalog(&authority)),
+            authority,
+            ..self
+        }
+    }
+
+    /// Serve the zones transferred from a primary instead of the packets in the store.
+    ///
+    /// The zones are transferred by [`run_secondary`].
+    pub(crate) fn with_secondary(self, config: SecondaryConfig) -> Self {
+        let authority = Arc::new(self.authority.to_secondary());
+        Self {
+            catalog: Arc::new(catalog(&authority)),
+            authority,
+            secondary: Some(Arc::new(Secondary::new(config))),
+            ..self
+        }
+    }
+
+    /// Whether this is a secondary, which serves the zones of a primary read-only.
+    pub(crate) fn is_secondary(&self) -> bool {
+        self.secondary.is_some()
+    }
+
+    /// The pubkey of a pkarr name, i.e. the label before the origin.
+    fn pkarr_pubkey(&self, name: &LowerName) -> Option<PublicKeyBytes> {
+        let zone = self.zones.iter().find(|zone| zone.zone_of(name))?;
+        let name = Name::from(name);
+        let label = name.iter().rev().nth(zone.num_labels() as usize)?;
+        PublicKeyBytes::from_z32(std::str::from_utf8(label).ok()?).ok()
+    }
+
+    /// The rolling statistics of the queries.
+    pub(crate) fn traffic(&self) -> &TrafficStats {
+        &self.traffic
+    }
+
+    /// Receive the queries that are answered from now on.
+    ///
+    /// A subscriber that falls more than 1024 queries behind misses the oldest ones.
+    pub(crate) fn subscribe_queries(&self) -> broadcast::Receiver<QueryEvent> {
+        self.queries.subscribe()
+    }
+
+    /// The ACME DNS-01 challenges served by this handler.
+    #[cfg_attr(not(feature = "https"), allow(dead_code))]
+    pub(crate) fn acme_challenges(&self) -> &AcmeChallenges {
+        &self.acme_challenges
+    }
+
     /// Handle a DNS request
     pub async fn answer_request(&self, request: Request) -> Result<Bytes> {
         let (tx, mut rx) = broadcast::channel(1);
@@ -166,15 +620,112 @@ impl RequestHandler for DnsHandler {
         request: &Request,
         response_handle: R,
     ) -> ResponseInfo {
+        // DNS-over-HTTPS requests are dropped with their connection
+        if self.socket.is_some() && self.ip_reputation.is_listed(request.src().ip()) {
+            AbuseMetrics::count_dropped("dns");
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(ResponseCode::Refused);
+            return header.into();
+        }
+        if self.socket.is_some()
+            && self
+                .rate_limiter
+                .as_ref()
+                .is_some_and(|limiter| !limiter.allows(request.src().ip()))
+        {
+            DnsMetrics::count_rate_limited(request.protocol());
+            debug!(src = %request.src(), "DNS request over the rate limit");
+            // the source address of UDP requests can be spoofed, don't reflect anything to it
+            if request.protocol() == Protocol::Udp {
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::Refused);
+                return header.into();
+            }
+            return refuse_request(request, response_handle).await;
+        }
+        let operation = match request.op_code() {
+            OpCode::Update => Some(Operation::Update),
+            // NOTIFY is only accepted from the primary of a secondary
+            OpCode::Notify => None,
+            _ if matches!(
+                request.query().query_type(),
+                RecordType::AXFR | RecordType::IXFR
+            ) =>
+            {
+                Some(Operation::Transfer)
+            }
+            _ => Some(Operation::Query),
+        };
+        if operation.is_some_and(|operation| !self.acl.allows(operation, request.src().ip())) {
+            return refuse_request(request, response_handle).await;
+        }
+        // the faults of DNS-over-HTTPS are injected by the HTTP server
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = self
+            .chaos
+            .as_ref()
+            .filter(|_| request.protocol() != Protocol::Https)
+        {
+            if let Some(fault) = chaos.inject(ChaosEndpoint::Dns).await {
+                return chaos::answer_dns(fault, request, response_handle).await;
+            }
+        }
+        if self
+            .health
+            .as_ref()
+            .is_some_and(|health| !health.is_healthy())
+        {
+            // like a closed listener, so that clients retry with another server
+            debug!("server is unhealthy, not answering DNS request");
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(ResponseCode::ServFail);
+            return header.into();
+        }
+        let _permit = match self
+            .requests
+            .as_ref()
+            .map(|requests| requests.try_acquire())
+        {
+            Some(Err(_)) => return shed_request(request, response_handle).await,
+            Some(Ok(permit)) => Some(permit),
+            None => None,
+        };
         inc!(Metrics, dns_requests);
         match request.protocol() {
-            hickory_server::server::Protocol::Udp => inc!(Metrics, dns_requests_udp),
-            hickory_server::server::Protocol::Https => inc!(Metrics, dns_requests_https),
+            Protocol::Udp => inc!(Metrics, dns_requests_udp),
+            Protocol::Https => inc!(Metrics, dns_requests_https),
             _ => {}
         }
-        debug!(protocol=%request.protocol(), query=%request.query(), "incoming DNS request");
+        if let Some(socket) = self.socket {
+            DnsMetrics::count_socket_request(socket, request.protocol());
+        }
+        let start = Instant::now();
+        let _answering = match &self.answering {
+            Some(answering) => Some(
+                answering
+                    .acquire()
+                    .await
+                    .expect("semaphore is never closed"),
+            ),
+            None => None,
+        };
+        if request.op_code() == OpCode::Notify {
+            return self.answer_notify(request, response_handle).await;
+        }
+        if matches!(
+            request.query().query_type(),
+            RecordType::AXFR | RecordType::IXFR
+        ) {
+            return self.answer_transfer(request, response_handle).await;
+        }
+        let span = debug_span!("dns_request", protocol=%request.protocol(), query=%request.query());
+        debug!(parent: &span, "incoming DNS request");
 
-        let res = self.catalog.handle_request(request, response_handle).await;
+        let res = self
+            .catalog
+            .handle_request(request, response_handle)
+            .instrument(span)
+            .await;
         match &res.response_code() {
             ResponseCode::NoError => match res.answer_count() {
                 0 => inc!(Metrics, dns_lookup_notfound),
@@ -183,10 +734,247 @@ impl RequestHandler for DnsHandler {
             ResponseCode::NXDomain => inc!(Metrics, dns_lookup_notfound),
             _ => inc!(Metrics, dns_lookup_error),
         }
+        let qtype = match request.query().query_type() {
+            // unknown types are sent by clients, and must not create unbounded label values
+            RecordType::Unknown(_) => "OTHER".to_string(),
+            qtype => qtype.to_string(),
+        };
+        let name = request.query().name();
+        let pubkey = self.pkarr_pubkey(name);
+        self.traffic.record(pubkey, request.src().ip(), &qtype);
+        let answered = res.response_code() == ResponseCode::NoError && res.answer_count() > 0;
+        if let Some(hourly_stats) = &self.hourly_stats {
+            hourly_stats.record(Counter::queries(request.protocol()));
+            if answered && pubkey.is_some() {
+                hourly_stats.record(Counter::PkarrAnswers);
+            }
+        }
+        if let Some(pubkey) = pubkey.filter(|_| answered) {
+            if let Some(analytics) = &self.analytics {
+                analytics.record(pubkey, request.protocol().into());
+            }
+            if let Some(usage) = &self.usage {
+                let tenant = self.authority.zones().tenants().of_name(name);
+                usage.record_resolution(tenant.map(|tenant| tenant.name()));
+            }
+        }
+        let rcode = format!("{:?}", res.response_code());
+        DnsMetrics::count_query(qtype.clone(), rcode.clone(), self.zone_of(name));
+        // the event is only built while someone is tailing
+        if self.queries.receiver_count() > 0 {
+            self.queries
+                .send(QueryEvent {
+                    timestamp_ms: QueryLog::now_ms(),
+                    client: request.src().ip(),
+                    protocol: request.protocol().to_string(),
+                    name: name.to_string(),
+                    pubkey: pubkey.map(|pubkey| pubkey.to_z32()),
+                    qtype: qtype.clone(),
+                    rcode: rcode.clone(),
+                    answers: res.answer_count(),
+                    duration_us: start.elapsed().as_micros() as u64,
+                })
+                .ok();
+        }
+        if let Some(query_log) = self.query_log.as_ref().filter(|log| log.sample()) {
+            query_log.record(QueryRecord {
+                timestamp_ms: QueryLog::now_ms(),
+                client: request.src().ip(),
+                protocol: request.protocol().to_string(),
+                name: name.to_string(),
+                qtype,
+                rcode,
+                answers: res.answer_count(),
+                duration_us: start.elapsed().as_micros() as u64,
+            });
+        }
         res
     }
 }
 
+/// A catalog that serves the origins of `authority`.
+fn catalog(authority: &Arc<NodeAuthority>) -> Catalog {
+    let mut catalog = Catalog::new();
+    for origin in authority.origins() {
+        catalog.upsert(LowerName::from(origin), Box::new(Arc::clone(authority)));
+    }
+    catalog
+}
+
+/// Answer `request` with `SERVFAIL` because too many requests are in flight.
+async fn shed_request<R: ResponseHandler>(
+    request: &Request,
+    mut response_handle: R,
+) -> ResponseInfo {
+    debug!("too many DNS requests in flight, answering with SERVFAIL");
+    LoadShedMetrics::count(LimitedResource::DnsRequests);
+    inc!(Metrics, dns_requests);
+    inc!(Metrics, dns_lookup_error);
+    let response = MessageResponseBuilder::from_message_request(request)
+        .error_msg(request.header(), ResponseCode::ServFail);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(err) => {
+            debug!("failed to send SERVFAIL response: {err}");
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
+    }
+}
+
+/// Answer a request that the ACL denies with REFUSED.
+async fn refuse_request<R: ResponseHandler>(
+    request: &Request,
+    mut response_handle: R,
+) -> ResponseInfo {
+    let response = MessageResponseBuilder::from_message_request(request)
+        .error_msg(request.header(), ResponseCode::Refused);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(err) => {
+            debug!("failed to send REFUSED response: {err}");
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(ResponseCode::Refused);
+            header.into()
+        }
+    }
+}
+
+/// Serve DNS over TCP on `listener`, with a PROXY protocol header on every connection.
+async fn serve_tcp_proxied(listener: TcpListener, dns_handler: DnsHandler) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("failed to accept DNS TCP connection: {err}");
+                continue;
+            }
+        };
+        let dns_handler = dns_handler.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = handle_tcp_proxied(stream, peer, dns_handler).await {
+                debug!(%peer, "DNS TCP connection closed with error: {err:#}");
+            }
+        });
+    }
+}
+
+async fn handle_tcp_proxied(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    dns_handler: DnsHandler,
+) -> Result<()> {
+    let src_addr = tokio::time::timeout(
+        proxy_protocol::PROXY_HEADER_TIMEOUT,
+        proxy_protocol::read_header(&mut stream),
+    )
+    .await??
+    .unwrap_or(peer);
+    serve_stream(stream, src_addr, Protocol::Tcp, TCP_TIMEOUT, dns_handler).await
+}
+
+/// Answer the DNS requests on a stream of `src_addr` over `protocol`, until it is idle for
+/// `timeout`.
+async fn serve_stream(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    src_addr: SocketAddr,
+    protocol: Protocol,
+    timeout: Duration,
+    dns_handler: DnsHandler,
+) -> Result<()> {
+    loop {
+        // DNS messages over TCP are prefixed with their length as u16
+        let len = match tokio::time::timeout(timeout, stream.read_u16()).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(err)) => return Err(err.into()),
+            // idle connection
+            Err(_) => return Ok(()),
+        };
+        let mut buf = vec![0u8; len as usize];
+        tokio::time::timeout(timeout, stream.read_exact(&mut buf)).await??;
+        let message = MessageRequest::from_bytes(&buf)?;
+        let request = Request::new(message, src_addr, protocol);
+        // zone transfers are answered with several messages
+        let (tx, mut rx) = mpsc::channel(1);
+        let answer = async {
+            dns_handler.handle_request(&request, StreamHandle(tx)).await;
+            anyhow::Ok(())
+        };
+        let write = async {
+            while let Some(response) = rx.recv().await {
+                stream.write_u16(response.len().try_into()?).await?;
+                stream.write_all(&response).await?;
+            }
+            anyhow::Ok(())
+        };
+        tokio::try_join!(answer, write)?;
+    }
+}
+
+/// The tasks of the DNS-over-TLS and DNS-over-QUIC listeners, and their local addresses.
+type EncryptedListeners = (Vec<JoinHandle<()>>, Vec<SocketAddr>, Vec<SocketAddr>);
+
+/// Bind and serve the encrypted listeners of `tls`, on the addresses of `config`.
+#[cfg(feature = "https")]
+async fn spawn_encrypted(
+    tls: &DnsTlsConfig,
+    config: &DnsConfig,
+    ip_stack: IpStack,
+    dns_handler: &DnsHandler,
+    data_dir: &Path,
+) -> Result<EncryptedListeners> {
+    let (mut tasks, mut tls_addrs, mut quic_addrs) = EncryptedListeners::default();
+    if tls.dot_port.is_none() && tls.doq_port.is_none() {
+        return Ok((tasks, tls_addrs, quic_addrs));
+    }
+    let events = dns_handler.authority.zones().events();
+    let tls_config =
+        tls::server_config(tls, data_dir, dns_handler.acme_challenges(), events).await?;
+    if let Some(port) = tls.dot_port {
+        for bind_addr in
+            util::socket_addrs("dns-over-tls", &config.bind_addr, Some(port), ip_stack)?
+        {
+            let listener = util::bind_tcp(bind_addr)
+                .and_then(TcpListener::from_std)
+                .with_context(|| format!("failed to bind DNS-over-TLS listener on {bind_addr}"))?;
+            let local_addr = listener.local_addr()?;
+            info!("DNS-over-TLS server listening on {local_addr}");
+            let dns_handler = dns_handler.for_socket(local_addr);
+            tasks.push(tokio::task::spawn(tls::serve_tls(
+                listener,
+                tls_config.clone(),
+                dns_handler,
+            )));
+            tls_addrs.push(local_addr);
+        }
+    }
+    if let Some(port) = tls.doq_port {
+        for bind_addr in
+            util::socket_addrs("dns-over-quic", &config.bind_addr, Some(port), ip_stack)?
+        {
+            let endpoint = tls::bind_quic(bind_addr, tls_config.clone())?;
+            let local_addr = endpoint.local_addr()?;
+            let dns_handler = dns_handler.for_socket(local_addr);
+            tasks.push(tokio::task::spawn(tls::serve_quic(endpoint, dns_handler)));
+            quic_addrs.push(local_addr);
+        }
+    }
+    Ok((tasks, tls_addrs, quic_addrs))
+}
+
+#[cfg(not(feature = "https"))]
+async fn spawn_encrypted(
+    _tls: &DnsTlsConfig,
+    _config: &DnsConfig,
+    _ip_stack: IpStack,
+    _dns_handler: &DnsHandler,
+    _data_dir: &Path,
+) -> Result<EncryptedListeners> {
+    anyhow::bail!("DNS-over-TLS and DNS-over-QUIC require the `https` feature")
+}
+
 /// A handle to the channel over which the response to a DNS request will be sent
 #[derive(Debug, Clone)]
 pub struct Handle(pub broadcast::Sender<Bytes>);
@@ -217,6 +1005,36 @@ impl ResponseHandler for Handle {
     }
 }
 
+/// A handle to the channel over which each message of the response to a DNS request is sent
+#[derive(Debug, Clone)]
+struct StreamHandle(mpsc::Sender<Bytes>);
+
+#[async_trait]
+impl ResponseHandler for StreamHandle {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+            impl Iterator<Item = &'a proto::rr::Record> + Send + 'a,
+        >,
+    ) -> io::Result<ResponseInfo> {
+        let mut bytes = Vec::with_capacity(512);
+        let info = {
+            let mut encoder = BinEncoder::new(&mut bytes);
+            response.destructive_emit(&mut encoder)?
+        };
+        self.0
+            .send(Bytes::from(bytes))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))?;
+        Ok(info)
+    }
+}
+
 fn create_static_authority(
     origins: &[Name],
     config: &DnsConfig,
@@ -0,0 +1,86 @@
+//! TXT records for ACME DNS-01 challenges
+// the challenges are only published by the ACME client of the `https` feature
+#![cfg_attr(not(feature = "https"), allow(dead_code))]
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::Result;
+use hickory_proto::rr::{rdata::TXT, LowerName, Name, RData, Record, RecordSet, RecordType};
+use parking_lot::RwLock;
+
+/// TTL of the challenge records, short because they are only needed during validation.
+const CHALLENGE_TTL: u32 = 60;
+
+/// The pending ACME DNS-01 challenges, served as `_acme-challenge.<domain>` TXT records.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AcmeChallenges(Arc<RwLock<BTreeMap<LowerName, Vec<String>>>>);
+
+impl AcmeChallenges {
+    /// Publish the challenge `value` for `domain`.
+    pub(crate) fn insert(&self, domain: &str, value: String) -> Result<()> {
+        let name = challenge_name(domain)?;
+        self.0.write().entry(name).or_default().push(value);
+        Ok(())
+    }
+
+    /// Remove the challenge `value` for `domain`.
+    pub(crate) fn remove(&self, domain: &str, value: &str) -> Result<()> {
+        let name = challenge_name(domain)?;
+        let mut challenges = self.0.write();
+        if let Some(values) = challenges.get_mut(&name) {
+            values.retain(|v| v != value);
+            if values.is_empty() {
+                challenges.remove(&name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the TXT records for `name`, if there are pending challenges for it.
+    pub(crate) fn lookup(&self, name: &LowerName, serial: u32) -> Option<RecordSet> {
+        let challenges = self.0.read();
+        let values = challenges.get(name)?;
+        let name = Name::from(name);
+        let mut record_set = RecordSet::new(&name, RecordType::TXT, serial);
+        for value in values {
+            let rdata = RData::TXT(TXT::new(vec![value.clone()]));
+            record_set.insert(
+                Record::from_rdata(name.clone(), CHALLENGE_TTL, rdata),
+                serial,
+            );
+        }
+        Some(record_set)
+    }
+}
+
+/// The name of the challenge record for `domain`.
+///
+/// Challenges for wildcard domains are published at the name of the base domain.
+fn challenge_name(domain: &str) -> Result<LowerName> {
+    let domain = domain.strip_prefix("*.").unwrap_or(domain);
+    let mut name = Name::from_utf8(format!("_acme-challenge.{domain}"))?;
+    name.set_fqdn(true);
+    Ok(LowerName::from(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn insert_lookup_remove() -> Result<()> {
+        let challenges = AcmeChallenges::default();
+        let name = LowerName::from_str("_acme-challenge.example.org.")?;
+        challenges.insert("example.org", "a".to_string())?;
+        challenges.insert("*.example.org", "b".to_string())?;
+        let record_set = challenges.lookup(&name, 0).expect("challenges published");
+        assert_eq!(record_set.records_without_rrsigs().count(), 2);
+
+        challenges.remove("example.org", "a")?;
+        challenges.remove("*.example.org", "b")?;
+        assert!(challenges.lookup(&name, 0).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Hooks for embedders to change how DNS queries are answered
+//!
+//! A [`DnsHook`] sees the queries for names in the origins before the records are looked up, and
+//! can rewrite the name or the record type, answer the query itself, or refuse it. After the
+//! lookup it can change the records that were found. The hooks run in the order they were added
+//! to the [`Server::builder`](crate::server::Server::builder).
+
+use std::{collections::BTreeMap, fmt, net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{Name, Record, RecordSet, RecordType, RrKey},
+};
+use hickory_server::{
+    authority::{AuthLookup, LookupError, LookupOptions, LookupRecords},
+    server::Protocol,
+};
+
+/// A query as seen by a [`DnsHook`]
+#[derive(Debug, Clone)]
+pub struct DnsQuery {
+    /// The name that is looked up
+    pub name: Name,
+    /// The record type that is looked up
+    pub record_type: RecordType,
+    /// The address of the client
+    pub client: SocketAddr,
+    /// The protocol of the request
+    pub protocol: Protocol,
+}
+
+/// What to do with a query before the records are looked up
+#[derive(Debug, Clone)]
+pub enum BeforeLookup {
+    /// Look up the records of the (possibly rewritten) query
+    Continue,
+    /// Answer with these records, without looking up the query or running further hooks
+    Answer(Vec<Record>),
+    /// Answer with this response code, e.g. [`ResponseCode::Refused`], and no records
+    Reject(ResponseCode),
+}
+
+/// A hook that intercepts the DNS queries for names in the origins
+#[async_trait]
+pub trait DnsHook: fmt::Debug + Send + Sync + 'static {
+    /// Called before the records of `query` are looked up.
+    ///
+    /// Changes to the name and record type of `query` are looked up instead. The records are
+    /// answered with the name they are found at, so a hook that rewrites the name usually renames
+    /// the records back in [`Self::after_lookup`].
+    async fn before_lookup(&self, _query: &mut DnsQuery) -> BeforeLookup {
+        BeforeLookup::Continue
+    }
+
+    /// Called with the records that were found for `query`, which can be changed.
+    ///
+    /// `records` is empty if none were found, and the query is answered as if there were no hooks,
+    /// e.g. with `NXDOMAIN`, unless a hook adds records. This is not called if the lookup failed
+    /// otherwise, e.g. with `SERVFAIL`.
+    async fn after_lookup(&self, _query: &DnsQuery, _records: &mut Vec<Record>) {}
+}
+
+/// The hooks of a DNS handler, in the order they run
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DnsHooks(Arc<Vec<Arc<dyn DnsHook>>>);
+
+impl DnsHooks {
+    pub(crate) fn new(hooks: Vec<Arc<dyn DnsHook>>) -> Self {
+        Self(Arc::new(hooks))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run the hooks before the lookup, and stop at the first that doesn't continue.
+    pub(crate) async fn before_lookup(&self, query: &mut DnsQuery) -> BeforeLookup {
+        for hook in self.0.iter() {
+            match hook.before_lookup(query).await {
+                BeforeLookup::Continue => {}
+                action => return action,
+            }
+        }
+        BeforeLookup::Continue
+    }
+
+    /// Run the hooks on the result of the lookup of `query`.
+    pub(crate) async fn after_lookup(
+        &self,
+        query: &DnsQuery,
+        res: Result<AuthLookup, LookupError>,
+        lookup_options: LookupOptions,
+        serial: u32,
+    ) -> Result<AuthLookup, LookupError> {
+        let mut records = match &res {
+            Ok(lookup) => lookup.iter().cloned().collect(),
+            Err(err) if err.is_nx_domain() || matches!(err, LookupError::NameExists) => Vec::new(),
+            Err(_) => return res,
+        };
+        for hook in self.0.iter() {
+            hook.after_lookup(query, &mut records).await;
+        }
+        if records.is_empty() && res.is_err() {
+            return res;
+        }
+        Ok(auth_lookup(records, lookup_options, serial))
+    }
+}
+
+/// A lookup that answers with `records`.
+pub(crate) fn auth_lookup(
+    records: Vec<Record>,
+    lookup_options: LookupOptions,
+    serial: u32,
+) -> AuthLookup {
+    let mut record_sets: BTreeMap<RrKey, RecordSet> = BTreeMap::new();
+    for record in records {
+        let key = RrKey::new(record.name().into(), record.record_type());
+        record_sets
+            .entry(key)
+            .or_insert_with(|| RecordSet::new(record.name(), record.record_type(), serial))
+            .insert(record, serial);
+    }
+    let record_sets = record_sets.into_values().map(Arc::new).collect();
+    AuthLookup::answers(
+        LookupRecords::ManyRecords(lookup_options, record_sets),
+        None,
+    )
+}
@@ -0,0 +1,81 @@
+//! Names that embedders map to the pubkeys of nodes
+//!
+//! A [`NameResolver`] maps a human-friendly name in an origin, e.g. `alice.example.org` in the
+//! origin `example.org`, to the pubkey of a node. The queries for the name and the names below it,
+//! e.g. `_iroh.alice.example.org`, are answered with a CNAME record to the pkarr name of the
+//! pubkey, e.g. `_iroh.<z32 pubkey>.example.org`, and the records of the packet of the node at
+//! that name. Clients that only know the name, like the DNS discovery of iroh, thus find the
+//! pubkey in the answer. The resolvers are asked in the order they were added to the
+//! [`Server::builder`](crate::server::Server::builder), before the static records of the origin.
+
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use hickory_proto::rr::{LowerName, Name};
+
+use crate::util::PublicKeyBytes;
+
+/// A resolver of the pubkeys of human-friendly node names
+#[async_trait]
+pub trait NameResolver: fmt::Debug + Send + Sync + 'static {
+    /// Get the pubkey of the node named `name`, or `None` if this resolver doesn't know the name.
+    ///
+    /// `name` is the first label below the origin with the origin, e.g. `alice.example.org` for
+    /// a query for `_iroh.alice.example.org`. An error is answered with `SERVFAIL`.
+    async fn resolve(&self, name: &Name) -> Result<Option<pkarr::PublicKey>>;
+}
+
+/// A [`NameResolver`] with a fixed map of names to pubkeys
+#[derive(Debug, Default, Clone)]
+pub struct StaticNames(BTreeMap<LowerName, pkarr::PublicKey>);
+
+impl StaticNames {
+    /// Map `name` to `pubkey`, and return the pubkey it was mapped to before.
+    ///
+    /// Names are compared case-insensitively.
+    pub fn insert(&mut self, name: Name, pubkey: pkarr::PublicKey) -> Option<pkarr::PublicKey> {
+        self.0.insert(name.into(), pubkey)
+    }
+}
+
+impl FromIterator<(Name, pkarr::PublicKey)> for StaticNames {
+    fn from_iter<T: IntoIterator<Item = (Name, pkarr::PublicKey)>>(iter: T) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(name, pubkey)| (name.into(), pubkey))
+                .collect(),
+        )
+    }
+}
+
+#[async_trait]
+impl NameResolver for StaticNames {
+    async fn resolve(&self, name: &Name) -> Result<Option<pkarr::PublicKey>> {
+        Ok(self.0.get(&LowerName::from(name)).cloned())
+    }
+}
+
+/// The name resolvers of a DNS handler, in the order they are asked
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NameResolvers(Arc<Vec<Arc<dyn NameResolver>>>);
+
+impl NameResolvers {
+    pub(crate) fn new(resolvers: Vec<Arc<dyn NameResolver>>) -> Self {
+        Self(Arc::new(resolvers))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Get the pubkey of the first resolver that knows `name`.
+    pub(crate) async fn resolve(&self, name: &Name) -> Result<Option<PublicKeyBytes>> {
+        for resolver in self.0.iter() {
+            if let Some(pubkey) = resolver.resolve(name).await? {
+                return Ok(Some(pubkey.into()));
+            }
+        }
+        Ok(None)
+    }
+}
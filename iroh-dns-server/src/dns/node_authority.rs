@@ -1,10 +1,23 @@
-use std::{fmt, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime},
+};
 
 use anyhow::{bail, ensure, Context, Result};
 use async_trait::async_trait;
 use hickory_proto::{
     op::ResponseCode,
-    rr::{LowerName, Name, RecordType},
+    rr::{
+        rdata::{CNAME, SOA},
+        LowerName, Name, RData, Record, RecordSet, RecordType,
+    },
 };
 use hickory_server::{
     authority::{
@@ -15,20 +28,106 @@ use hickory_server::{
     store::in_memory::InMemoryAuthority,
 };
 
+use parking_lot::RwLock;
 use tracing::{debug, trace};
 
+use super::{
+    hooks::{self, DnsHooks},
+    names::NameResolvers,
+    AcmeChallenges, BeforeLookup, DnsConfig, DnsQuery,
+};
 use crate::{
-    store::ZoneStore,
+    metrics::{AnswerSource, DnsMetrics, TenantMetrics},
+    slow_log::Timings,
+    store::{MainlineLookupsExceeded, ZoneStore},
     util::{record_set_append_origin, PublicKeyBytes},
 };
 
+/// The default TTL and the range of the TTLs of a record type
+type TypeTtls = (Option<u32>, RangeInclusive<u32>);
+
+/// The TTLs of the answers from pkarr packets, see [`DnsConfig::ttls`]
+#[derive(Debug, Clone)]
+pub(crate) struct Ttls {
+    /// The range of the record types without their own limits
+    range: RangeInclusive<u32>,
+    /// The default TTL and range by record type
+    types: Arc<BTreeMap<RecordType, TypeTtls>>,
+}
+
+impl Default for Ttls {
+    fn default() -> Self {
+        Self {
+            range: 0..=u32::MAX,
+            types: Default::default(),
+        }
+    }
+}
+
+impl Ttls {
+    /// The TTLs of `config`.
+    pub(crate) fn new(config: &DnsConfig) -> Result<Self> {
+        let range = |min: Option<u32>, max: Option<u32>, name: &str| {
+            let (min, max) = (min.unwrap_or(0), max.unwrap_or(u32::MAX));
+            ensure!(
+                min <= max,
+                "the minimum TTL of {name} is higher than its maximum"
+            );
+            Ok(min..=max)
+        };
+        let default = range(config.min_ttl, config.max_ttl, "dns")?;
+        let mut types = BTreeMap::new();
+        for (record_type, ttls) in &config.ttls {
+            let parsed = RecordType::from_str(&record_type.to_uppercase())
+                .ok()
+                .filter(|parsed| !matches!(parsed, RecordType::Unknown(_)))
+                .with_context(|| format!("invalid record type {record_type} in dns.ttls"))?;
+            let range = range(
+                ttls.min.or(config.min_ttl),
+                ttls.max.or(config.max_ttl),
+                &format!("dns.ttls.{record_type}"),
+            )?;
+            types.insert(parsed, (ttls.default, range));
+        }
+        Ok(Self {
+            range: default,
+            types: Arc::new(types),
+        })
+    }
+
+    /// The TTL of an answer of `record_type` with `ttl` in the packet.
+    fn get(&self, record_type: RecordType, ttl: u32) -> u32 {
+        let (default, range) = match self.types.get(&record_type) {
+            Some((default, range)) => (*default, range),
+            None => (None, &self.range),
+        };
+        let ttl = match (ttl, default) {
+            (0, Some(default)) => default,
+            (ttl, _) => ttl,
+        };
+        ttl.clamp(*range.start(), *range.end())
+    }
+}
+
 #[derive(derive_more::Debug)]
 pub struct NodeAuthority {
-    serial: u32,
+    serial: AtomicU32,
     origins: Vec<Name>,
+    /// The static records, which can be replaced at runtime
     #[debug("InMemoryAuthority")]
-    static_authority: InMemoryAuthority,
+    static_authority: RwLock<Arc<InMemoryAuthority>>,
+    /// The SRV and HTTPS records of the healthy replicas
+    replicas: RwLock<Arc<Vec<Record>>>,
+    acme_challenges: AcmeChallenges,
     zones: ZoneStore,
+    /// Whether the records are transferred from a primary instead of resolved from the store
+    secondary: bool,
+    /// Whether the records were transferred from the primary at least once
+    transferred: AtomicBool,
+    hooks: DnsHooks,
+    names: NameResolvers,
+    /// The TTLs of the records from the pkarr packets
+    ttls: Ttls,
     // TODO: This is used by Authority::origin
     // Find out what exactly this is used for - we don't have a primary origin.
     first_origin: LowerName,
@@ -38,26 +137,328 @@ impl NodeAuthority {
     pub fn new(
         zones: ZoneStore,
         static_authority: InMemoryAuthority,
+        acme_challenges: AcmeChallenges,
         origins: Vec<Name>,
         serial: u32,
     ) -> Result<Self> {
         ensure!(!origins.is_empty(), "at least one origin is required");
         let first_origin = LowerName::from(&origins[0]);
         Ok(Self {
-            static_authority,
+            static_authority: RwLock::new(Arc::new(static_authority)),
+            replicas: Default::default(),
+            acme_challenges,
             origins,
-            serial,
+            serial: AtomicU32::new(serial),
             zones,
+            secondary: false,
+            transferred: AtomicBool::new(false),
+            hooks: Default::default(),
+            names: Default::default(),
+            ttls: Default::default(),
             first_origin,
         })
     }
 
+    /// An authority for the same origins that serves the records transferred from a primary.
+    ///
+    /// Lookups fail with `SERVFAIL` until the first transfer, see
+    /// [`Self::replace_transferred_zone`].
+    pub(crate) fn to_secondary(&self) -> Self {
+        Self {
+            serial: AtomicU32::new(0),
+            origins: self.origins.clone(),
+            static_authority: RwLock::new(Arc::new(InMemoryAuthority::empty(
+                Name::root(),
+                ZoneType::Secondary,
+                false,
+            ))),
+            // the records of the replicas are transferred from the primary
+            replicas: Default::default(),
+            acme_challenges: self.acme_challenges.clone(),
+            zones: self.zones.clone(),
+            secondary: true,
+            transferred: AtomicBool::new(false),
+            hooks: self.hooks.clone(),
+            names: self.names.clone(),
+            ttls: self.ttls.clone(),
+            first_origin: self.first_origin.clone(),
+        }
+    }
+
+    /// The same authority, which runs `hooks` on the queries and resolves the names of the nodes
+    /// with `names`.
+    pub(crate) fn with_extensions(&self, hooks: DnsHooks, names: NameResolvers) -> Self {
+        Self {
+            serial: AtomicU32::new(self.serial()),
+            origins: self.origins.clone(),
+            static_authority: RwLock::new(self.static_authority()),
+            replicas: RwLock::new(self.replicas.read().clone()),
+            acme_challenges: self.acme_challenges.clone(),
+            zones: self.zones.clone(),
+            secondary: self.secondary,
+            transferred: AtomicBool::new(self.is_transferred()),
+            hooks,
+            names,
+            ttls: self.ttls.clone(),
+            first_origin: self.first_origin.clone(),
+        }
+    }
+
+    /// Answer with the TTLs of `ttls` for the records from the pkarr packets.
+    pub(crate) fn with_ttls(self, ttls: Ttls) -> Self {
+        Self { ttls, ..self }
+    }
+
+    /// Set the TTL of the records of a pkarr packet to the lowest TTL of the records, within the
+    /// configured limits of their record type.
+    pub(crate) fn clamp_ttl(&self, record_set: &mut RecordSet) {
+        let ttl = record_set
+            .records_without_rrsigs()
+            .map(Record::ttl)
+            .min()
+            .unwrap_or(record_set.ttl());
+        record_set.set_ttl(self.ttls.get(record_set.record_type(), ttl));
+    }
+
+    pub(crate) fn is_secondary(&self) -> bool {
+        self.secondary
+    }
+
+    /// Whether the records were transferred from the primary, on a secondary.
+    pub(crate) fn is_transferred(&self) -> bool {
+        self.transferred.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn zones(&self) -> &ZoneStore {
+        &self.zones
+    }
+
     pub fn origins(&self) -> impl Iterator<Item = &Name> {
         self.origins.iter()
     }
 
     pub fn serial(&self) -> u32 {
-        self.serial
+        self.serial.load(Ordering::Relaxed)
+    }
+
+    /// Replace the static records, and raise the serial to `serial`.
+    ///
+    /// The serial is never lowered, so that it only grows for secondaries.
+    pub(crate) fn replace_static_authority(
+        &self,
+        static_authority: InMemoryAuthority,
+        serial: u32,
+    ) {
+        *self.static_authority.write() = Arc::new(static_authority);
+        self.serial.fetch_max(serial, Ordering::Relaxed);
+    }
+
+    /// Replace all records with the ones transferred from the primary, at its serial.
+    pub(crate) fn replace_transferred_zone(&self, authority: InMemoryAuthority, serial: u32) {
+        *self.static_authority.write() = Arc::new(authority);
+        self.serial.store(serial, Ordering::Relaxed);
+        self.transferred.store(true, Ordering::Relaxed);
+    }
+
+    /// Increase the serial after the zone changed, and return the new serial.
+    ///
+    /// The serial is set to the current unix time, or incremented if it is already at or beyond
+    /// it, so that it is still larger than before after a restart.
+    pub(crate) fn bump_serial(&self) -> u32 {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let next = |serial: u32| now.max(serial.wrapping_add(1));
+        let previous = self
+            .serial
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |serial| {
+                Some(next(serial))
+            })
+            .expect("the update always succeeds");
+        next(previous)
+    }
+
+    /// The SOA record of `origin`, with the current serial.
+    pub(crate) async fn soa(&self, origin: &LowerName) -> Option<Record> {
+        let lookup = self
+            .static_authority()
+            .lookup(origin, RecordType::SOA, LookupOptions::default())
+            .await
+            .ok()?;
+        let record = lookup.iter().next()?;
+        Some(with_serial(record, self.serial()))
+    }
+
+    /// The static records of `origin`, without its SOA record, and the records of the replicas.
+    pub(crate) async fn static_records(&self, origin: &LowerName) -> Vec<Record> {
+        let records = self.static_authority().records().await;
+        let replicas = self.replicas.read().clone();
+        records
+            .values()
+            .filter(|set| {
+                set.record_type() != RecordType::SOA && origin.zone_of(&set.name().into())
+            })
+            .flat_map(|set| set.records_without_rrsigs().cloned())
+            .chain(
+                replicas
+                    .iter()
+                    .filter(|record| origin.zone_of(&record.name().into()))
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /// Replace the SRV and HTTPS records of the replicas, and return whether they changed.
+    pub(crate) fn replace_replica_records(&self, records: Vec<Record>) -> bool {
+        let mut replicas = self.replicas.write();
+        if **replicas == records {
+            return false;
+        }
+        *replicas = Arc::new(records);
+        true
+    }
+
+    /// The records of the replicas with `name` and `record_type`, if there are any.
+    fn replica_records(&self, name: &LowerName, record_type: RecordType) -> Option<RecordSet> {
+        let replicas = self.replicas.read();
+        let mut records = replicas
+            .iter()
+            .filter(|record| {
+                record.record_type() == record_type && LowerName::from(record.name()) == *name
+            })
+            .peekable();
+        records.peek()?;
+        let mut record_set = RecordSet::new(&name.into(), record_type, self.serial());
+        for record in records {
+            record_set.insert(record.clone(), self.serial());
+        }
+        Some(record_set)
+    }
+
+    fn static_authority(&self) -> Arc<InMemoryAuthority> {
+        self.static_authority.read().clone()
+    }
+
+    /// Look up the records, and return where the answer came from.
+    ///
+    /// The time spent in the steps of the lookup is added to `timings`.
+    async fn lookup_inner(
+        &self,
+        name: &LowerName,
+        record_type: RecordType,
+        lookup_options: LookupOptions,
+        timings: &mut Timings,
+    ) -> (Result<AuthLookup, LookupError>, AnswerSource) {
+        if record_type == RecordType::TXT {
+            if let Some(record_set) = self.acme_challenges.lookup(name, self.serial()) {
+                debug!(%name, "resolve in acme challenges");
+                let records = LookupRecords::new(lookup_options, Arc::new(record_set));
+                return (Ok(AuthLookup::answers(records, None)), AnswerSource::Acme);
+            }
+        }
+        if self.secondary {
+            if !self.is_transferred() {
+                return (
+                    Err(err_serv_fail("the zone was not transferred yet")),
+                    AnswerSource::Transfer,
+                );
+            }
+            let res = self
+                .static_authority()
+                .lookup(name, record_type, lookup_options)
+                .await;
+            return (res, AnswerSource::Transfer);
+        }
+        let start = Instant::now();
+        let pkarr_name = match record_type {
+            RecordType::SOA | RecordType::NS => None,
+            _ => match parse_name_as_pkarr_with_origin(name, &self.origins) {
+                Err(err) => {
+                    debug!(%name, failed_with=%err, "not a pkarr name, resolve in static authority");
+                    None
+                }
+                Ok(pkarr_name) => Some(pkarr_name),
+            },
+        };
+        timings.parse += start.elapsed();
+        // a name that a name resolver maps to a pubkey is an alias of the pkarr name
+        let (pkarr_name, alias) = match pkarr_name {
+            None if record_type != RecordType::SOA && record_type != RecordType::NS => {
+                match self.resolve_node_name(name).await {
+                    Ok(pkarr_name) => (pkarr_name, Some(Name::from(name))),
+                    Err(err) => return (Err(err_serv_fail(err)), AnswerSource::Names),
+                }
+            }
+            pkarr_name => (pkarr_name, None),
+        };
+        // the tenant is found by the queried name, which may be in several of the origins
+        let tenant = self.zones.tenants().of_name(name);
+        let Some((name, pubkey, origin)) = pkarr_name else {
+            if let Some(record_set) = self.replica_records(name, record_type) {
+                let records = LookupRecords::new(lookup_options, Arc::new(record_set));
+                return (
+                    Ok(AuthLookup::answers(records, None)),
+                    AnswerSource::StaticZone,
+                );
+            }
+            let res = self
+                .static_authority()
+                .lookup(name, record_type, lookup_options)
+                .await;
+            // the serial of the static SOA records is raised when the zone changes
+            let res = match res {
+                Ok(lookup) if record_type == RecordType::SOA => {
+                    let mut record_set = RecordSet::new(&name.into(), record_type, self.serial());
+                    for record in lookup.iter() {
+                        record_set.insert(with_serial(record, self.serial()), self.serial());
+                    }
+                    let records = LookupRecords::new(lookup_options, Arc::new(record_set));
+                    Ok(AuthLookup::answers(records, None))
+                }
+                res => res,
+            };
+            return (res, AnswerSource::StaticZone);
+        };
+        debug!(%origin, %pubkey, %name, "resolve in pkarr zones");
+        if let Some(tenant) = tenant {
+            TenantMetrics::count_query(tenant.name());
+        }
+        let zones = tenant.map_or(&self.zones, |tenant| tenant.store(&self.zones));
+        let (pkarr_set, source) = match zones
+            .resolve_with_source(&pubkey, &name, record_type, timings)
+            .await
+        {
+            Ok(res) => res,
+            Err(err) if err.is::<MainlineLookupsExceeded>() => {
+                return (Err(err_serv_fail(err)), AnswerSource::Mainline)
+            }
+            Err(err) => return (Err(err_refused(err)), AnswerSource::Store),
+        };
+        let res = match pkarr_set {
+            Some(pkarr_set) => {
+                debug!(%origin, %pubkey, %name, "found {} records in pkarr zone", pkarr_set.records_without_rrsigs().count());
+                Name::parse(&pubkey.to_z32(), Some(&origin))
+                    .map_err(err_refused)
+                    .and_then(|new_origin| {
+                        record_set_append_origin(&pkarr_set, &new_origin, self.serial())
+                            .map_err(err_refused)
+                    })
+                    .map(|mut record_set| {
+                        self.clamp_ttl(&mut record_set);
+                        record_set
+                    })
+                    .map(|record_set| match alias {
+                        Some(alias) => self.alias_lookup(alias, record_set, lookup_options),
+                        None => {
+                            let records = LookupRecords::new(lookup_options, Arc::new(record_set));
+                            AuthLookup::answers(records, None)
+                        }
+                    })
+            }
+            None => Err(err_nx_domain("not found")),
+        };
+        (res, source)
     }
 }
 
@@ -66,7 +467,11 @@ impl Authority for NodeAuthority {
     type Lookup = AuthLookup;
 
     fn zone_type(&self) -> ZoneType {
-        ZoneType::Primary
+        if self.secondary {
+            ZoneType::Secondary
+        } else {
+            ZoneType::Primary
+        }
     }
 
     fn is_axfr_allowed(&self) -> bool {
@@ -88,43 +493,17 @@ impl Authority for NodeAuthority {
         lookup_options: LookupOptions,
     ) -> Result<Self::Lookup, LookupError> {
         debug!(name=%name, "lookup in node authority");
-        match record_type {
-            RecordType::SOA | RecordType::NS => {
-                self.static_authority
-                    .lookup(name, record_type, lookup_options)
-                    .await
-            }
-            _ => match parse_name_as_pkarr_with_origin(name, &self.origins) {
-                Err(err) => {
-                    debug!(%name, failed_with=%err, "not a pkarr name, resolve in static authority");
-                    self.static_authority
-                        .lookup(name, record_type, lookup_options)
-                        .await
-                }
-                Ok((name, pubkey, origin)) => {
-                    debug!(%origin, %pubkey, %name, "resolve in pkarr zones");
-                    match self
-                        .zones
-                        .resolve(&pubkey, &name, record_type)
-                        .await
-                        .map_err(err_refused)?
-                    {
-                        Some(pkarr_set) => {
-                            debug!(%origin, %pubkey, %name, "found {} records in pkarr zone", pkarr_set.records_without_rrsigs().count());
-                            let new_origin = Name::parse(&pubkey.to_z32(), Some(&origin))
-                                .map_err(err_refused)?;
-                            let record_set =
-                                record_set_append_origin(&pkarr_set, &new_origin, self.serial())
-                                    .map_err(err_refused)?;
-                            let records = LookupRecords::new(lookup_options, Arc::new(record_set));
-                            let answers = AuthLookup::answers(records, None);
-                            Ok(answers)
-                        }
-                        None => Err(err_nx_domain("not found")),
-                    }
-                }
-            },
-        }
+        let start = Instant::now();
+        let mut timings = Timings::default();
+        let (res, source) = self
+            .lookup_inner(name, record_type, lookup_options, &mut timings)
+            .await;
+        let elapsed = start.elapsed();
+        DnsMetrics::observe_lookup(source, elapsed);
+        self.zones
+            .slow_log()
+            .query(name, record_type, source, elapsed, &timings);
+        res
     }
 
     async fn search(
@@ -135,15 +514,34 @@ impl Authority for NodeAuthority {
         debug!("search in node authority for {}", request_info.query);
         let lookup_name = request_info.query.name();
         let record_type: RecordType = request_info.query.query_type();
-        match record_type {
-            RecordType::SOA => {
-                self.static_authority
-                    .lookup(self.origin(), record_type, lookup_options)
-                    .await
+        if self.hooks.is_empty() {
+            return self
+                .search_inner(lookup_name, record_type, lookup_options)
+                .await;
+        }
+        let mut query = DnsQuery {
+            name: lookup_name.into(),
+            record_type,
+            client: request_info.src,
+            protocol: request_info.protocol,
+        };
+        match self.hooks.before_lookup(&mut query).await {
+            BeforeLookup::Continue => {}
+            BeforeLookup::Answer(records) => {
+                return Ok(hooks::auth_lookup(records, lookup_options, self.serial()))
             }
-            RecordType::AXFR => Err(LookupError::from(ResponseCode::Refused)),
-            _ => self.lookup(lookup_name, record_type, lookup_options).await,
+            BeforeLookup::Reject(response_code) => return Err(LookupError::from(response_code)),
         }
+        let res = self
+            .search_inner(
+                &query.name.clone().into(),
+                query.record_type,
+                lookup_options,
+            )
+            .await;
+        self.hooks
+            .after_lookup(&query, res, lookup_options, self.serial())
+            .await
     }
 
     async fn get_nsec_records(
@@ -155,6 +553,75 @@ impl Authority for NodeAuthority {
     }
 }
 
+impl NodeAuthority {
+    /// Resolve the pubkey of a name in the origins that is not a pkarr name with the name
+    /// resolvers, and return the name below the node name, the pubkey and the origin.
+    async fn resolve_node_name(
+        &self,
+        name: &LowerName,
+    ) -> Result<Option<(Name, PublicKeyBytes, Name)>> {
+        if self.names.is_empty() {
+            return Ok(None);
+        }
+        let name = Name::from(name);
+        let Some(origin) = self
+            .origins
+            .iter()
+            .filter(|origin| origin.zone_of(&name) && name.num_labels() > origin.num_labels())
+            .max_by_key(|origin| origin.num_labels())
+        else {
+            return Ok(None);
+        };
+        let node_name = name.trim_to(origin.num_labels() as usize + 1);
+        let Some(pubkey) = self.names.resolve(&node_name).await? else {
+            return Ok(None);
+        };
+        debug!(%name, %node_name, %pubkey, "resolved node name");
+        let labels = name
+            .iter()
+            .take((name.num_labels() - node_name.num_labels()) as usize);
+        Ok(Some((Name::from_labels(labels)?, pubkey, origin.clone())))
+    }
+
+    /// The answer to a query for `alias`: a CNAME record to the pkarr name of `record_set`, and
+    /// the records of the set.
+    ///
+    /// Clients like the DNS discovery of iroh read the pubkey from the names of the records, so
+    /// the records keep their pkarr name.
+    fn alias_lookup(
+        &self,
+        alias: Name,
+        record_set: RecordSet,
+        lookup_options: LookupOptions,
+    ) -> AuthLookup {
+        let cname = Record::from_rdata(
+            alias,
+            record_set.ttl(),
+            RData::CNAME(CNAME(record_set.name().clone())),
+        );
+        let records = std::iter::once(cname)
+            .chain(record_set.records_without_rrsigs().cloned())
+            .collect();
+        hooks::auth_lookup(records, lookup_options, self.serial())
+    }
+
+    async fn search_inner(
+        &self,
+        name: &LowerName,
+        record_type: RecordType,
+        lookup_options: LookupOptions,
+    ) -> Result<AuthLookup, LookupError> {
+        match record_type {
+            RecordType::SOA => {
+                self.lookup(self.origin(), record_type, lookup_options)
+                    .await
+            }
+            RecordType::AXFR => Err(LookupError::from(ResponseCode::Refused)),
+            _ => self.lookup(name, record_type, lookup_options).await,
+        }
+    }
+}
+
 fn parse_name_as_pkarr_with_origin(
     name: impl Into<Name>,
     allowed_origins: &[Name],
@@ -182,11 +649,67 @@ fn parse_name_as_pkarr_with_origin(
     bail!("name does not match any allowed origin");
 }
 
+/// A copy of the SOA `record` with `serial`.
+fn with_serial(record: &Record, serial: u32) -> Record {
+    let mut record = record.clone();
+    if let Some(soa) = record.data().as_soa() {
+        let soa = SOA::new(
+            soa.mname().clone(),
+            soa.rname().clone(),
+            serial,
+            soa.refresh(),
+            soa.retry(),
+            soa.expire(),
+            soa.minimum(),
+        );
+        record.set_data(RData::SOA(soa));
+    }
+    record
+}
+
 fn err_refused(e: impl fmt::Debug) -> LookupError {
     trace!("lookup failed (refused): {e:?}");
     LookupError::from(ResponseCode::Refused)
 }
+fn err_serv_fail(e: impl fmt::Debug) -> LookupError {
+    trace!("lookup failed (servfail): {e:?}");
+    LookupError::from(ResponseCode::ServFail)
+}
 fn err_nx_domain(e: impl fmt::Debug) -> LookupError {
     trace!("lookup failed (nxdomain): {e:?}");
     LookupError::from(ResponseCode::NXDomain)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::TtlConfig;
+
+    #[test]
+    fn ttls_by_record_type() -> Result<()> {
+        let mut config = crate::config::Config::default().dns;
+        config.min_ttl = Some(30);
+        config.max_ttl = Some(3600);
+        config.ttls.insert(
+            "a".to_string(),
+            TtlConfig {
+                default: Some(20),
+                min: Some(10),
+                max: Some(60),
+            },
+        );
+        let ttls = Ttls::new(&config)?;
+        assert_eq!(ttls.get(RecordType::A, 600), 60);
+        assert_eq!(ttls.get(RecordType::A, 0), 20);
+        assert_eq!(ttls.get(RecordType::A, 5), 10);
+        // the other record types have the limits of all types
+        assert_eq!(ttls.get(RecordType::TXT, 5), 30);
+        assert_eq!(ttls.get(RecordType::TXT, 86400), 3600);
+
+        config
+            .ttls
+            .insert("bogus".to_string(), TtlConfig::default());
+        assert!(Ttls::new(&config).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Rate limiting of DNS requests by source address
+//!
+//! The requests over UDP, TCP, DNS-over-TLS and DNS-over-QUIC take from a token bucket of their
+//! source address, IPv6 addresses by their `/64` network. Requests above the limit are dropped
+//! over UDP, whose source addresses can be spoofed, so that the server can't be used to amplify
+//! attacks, and answered with `REFUSED` over the other protocols. DNS-over-HTTPS queries are
+//! rate limited by the HTTP server instead, see [`crate::http::HttpConfig::doh_rate_limit`].
+
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use governor::{DefaultKeyedRateLimiter, RateLimiter};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::http::{rate_limiting::GC_INTERVAL, RateLimitQuota};
+
+/// The default quota of a source address: resolvers query several names at once.
+const DEFAULT_QUOTA: RateLimitQuota = RateLimitQuota {
+    rate: 20.0,
+    burst: 100,
+};
+
+/// Config for the rate limit of DNS requests, as a `[dns.rate_limit]` table
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DnsRateLimitConfig {
+    /// Requests per second that the budget of a source address is refilled with (defaults to 20).
+    pub rate: Option<f64>,
+    /// The size of the budget of a source address, the number of requests it can send at once
+    /// (defaults to 100).
+    pub burst: Option<u32>,
+    /// Networks that are exempt from the rate limit, in CIDR notation (e.g. `10.0.0.0/8`), e.g.
+    /// the resolvers of an ISP
+    #[serde(default)]
+    pub exempt: Vec<IpNet>,
+}
+
+/// The rate limiter of the DNS requests, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub(crate) struct DnsRateLimiter(Arc<Inner>);
+
+#[derive(derive_more::Debug)]
+struct Inner {
+    #[debug("KeyedRateLimiter")]
+    limiter: DefaultKeyedRateLimiter<IpAddr>,
+    exempt: Vec<IpNet>,
+}
+
+impl DnsRateLimiter {
+    /// Create the rate limiter of `config`.
+    pub(crate) fn new(config: &DnsRateLimitConfig) -> Result<Self> {
+        let quota = RateLimitQuota {
+            rate: config.rate.unwrap_or(DEFAULT_QUOTA.rate),
+            burst: config.burst.unwrap_or(DEFAULT_QUOTA.burst),
+        };
+        let limiter = RateLimiter::keyed(quota.to_quota().context("invalid dns.rate_limit")?);
+        info!(
+            rate = quota.rate,
+            burst = quota.burst,
+            exempt = config.exempt.len(),
+            "rate limiting DNS requests"
+        );
+        Ok(Self(Arc::new(Inner {
+            limiter,
+            exempt: config.exempt.clone(),
+        })))
+    }
+
+    /// Whether a request from `ip` is within the rate limit, which takes from its budget.
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.0.exempt.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        let key = match ip {
+            // clients usually get a /64 network, and can use any of its addresses
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+            ip => ip,
+        };
+        self.0.limiter.check_key(&key).is_ok()
+    }
+
+    /// Remove the source addresses whose budget is full again in the GC interval, until the task
+    /// is aborted.
+    pub(crate) async fn run_gc(self) {
+        loop {
+            tokio::time::sleep(GC_INTERVAL).await;
+            self.0.limiter.retain_recent();
+            debug!("DNS rate limiting storage size: {}", self.0.limiter.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_by_source_network() -> Result<()> {
+        let limiter = DnsRateLimiter::new(&DnsRateLimitConfig {
+            rate: Some(0.001),
+            burst: Some(2),
+            exempt: vec!["192.0.2.0/24".parse()?],
+        })?;
+        let a: IpAddr = "2001:db8::1".parse()?;
+        let b: IpAddr = "2001:db8::2".parse()?;
+        let other: IpAddr = "2001:db8:1::1".parse()?;
+        assert!(limiter.allows(a));
+        assert!(limiter.allows(b));
+        // the same /64 network
+        assert!(!limiter.allows(a));
+        assert!(limiter.allows(other));
+        for _ in 0..10 {
+            assert!(limiter.allows("192.0.2.1".parse()?));
+        }
+        Ok(())
+    }
+}
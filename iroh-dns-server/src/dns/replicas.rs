@@ -0,0 +1,224 @@
+//! Advertisement of the replicas of the server in SRV and HTTPS records
+//!
+//! A server with a [`ReplicasConfig`] checks the `/readyz` endpoint of its replicas in an
+//! interval, and serves an SRV record and an HTTPS record for each healthy replica under each
+//! origin. Clients that resolve the origin can pick a replica by the priority and weight of the
+//! records, and unhealthy replicas are not offered to them.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use hickory_proto::rr::{
+    rdata::{
+        svcb::{Alpn, SvcParamKey, SvcParamValue, SVCB},
+        HTTPS, SRV,
+    },
+    Name, RData, Record,
+};
+use iroh_metrics::inc;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::DnsHandler;
+use crate::metrics::Metrics;
+
+/// Default interval in seconds of the health checks of the replicas.
+const DEFAULT_INTERVAL_SECS: u64 = 30;
+/// Default TTL of the replica records.
+const DEFAULT_TTL: u32 = 60;
+/// Default name of the SRV records under the origins.
+const DEFAULT_SERVICE: &str = "_pkarr._tcp";
+/// Timeout of the health checks.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Config for advertising replicas in SRV and HTTPS records
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicasConfig {
+    /// The replicas, which may include this server.
+    pub replicas: Vec<Replica>,
+    /// Interval in seconds of the health checks (defaults to 30).
+    pub interval_secs: Option<u64>,
+    /// The TTL of the records, in seconds (defaults to 60).
+    pub ttl: Option<u32>,
+    /// The name of the SRV records under the origins (defaults to `_pkarr._tcp`).
+    pub service: Option<String>,
+}
+
+/// A replica of the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replica {
+    /// The hostname of the replica, e.g. `eu.dns.example.org`
+    pub target: String,
+    /// The HTTPS port of the replica (defaults to 443).
+    pub port: Option<u16>,
+    /// The priority of the records, lower is preferred (defaults to 10).
+    pub priority: Option<u16>,
+    /// The weight of the SRV record among the replicas of the same priority (defaults to 10).
+    pub weight: Option<u16>,
+    /// The URL of the health check (defaults to `https://<target>:<port>/readyz`).
+    ///
+    /// The replica is healthy if it answers with a success status.
+    pub health_url: Option<Url>,
+}
+
+impl Replica {
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(443)
+    }
+
+    fn health_url(&self) -> Result<Url> {
+        match &self.health_url {
+            Some(url) => Ok(url.clone()),
+            None => Ok(format!("https://{}:{}/readyz", self.target, self.port()).parse()?),
+        }
+    }
+}
+
+/// Check the replicas in an interval, and serve the records of the healthy ones.
+pub(crate) async fn advertise_replicas(config: ReplicasConfig, dns_handler: DnsHandler) {
+    let interval = Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS));
+    let client = match reqwest::Client::builder().timeout(CHECK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("failed to create the client for the replica health checks: {err:#}");
+            return;
+        }
+    };
+    let authority = &dns_handler.authority;
+    let origins: Vec<Name> = authority.origins().cloned().collect();
+    loop {
+        let mut healthy = Vec::new();
+        for replica in &config.replicas {
+            match check(&client, replica).await {
+                Ok(()) => healthy.push(replica.clone()),
+                Err(err) => {
+                    inc!(Metrics, replica_checks_failed);
+                    debug!(target = %replica.target, "replica is unhealthy: {err:#}");
+                }
+            }
+        }
+        if healthy.is_empty() {
+            // offering unhealthy replicas is better than offering none
+            warn!("all replicas are unhealthy, advertising all of them");
+            healthy = config.replicas.clone();
+        }
+        match records(&config, &healthy, &origins) {
+            Ok(records) => {
+                if authority.replace_replica_records(records) {
+                    let serial = authority.bump_serial();
+                    info!(
+                        serial,
+                        "advertising {} of {} replicas",
+                        healthy.len(),
+                        config.replicas.len()
+                    );
+                }
+            }
+            Err(err) => warn!("failed to create the replica records: {err:#}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn check(client: &reqwest::Client, replica: &Replica) -> Result<()> {
+    client
+        .get(replica.health_url()?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// The SRV and HTTPS records of `replicas` under each of the `origins`.
+fn records(config: &ReplicasConfig, replicas: &[Replica], origins: &[Name]) -> Result<Vec<Record>> {
+    let ttl = config.ttl.unwrap_or(DEFAULT_TTL);
+    let service = config.service.as_deref().unwrap_or(DEFAULT_SERVICE);
+    let mut records = Vec::new();
+    for origin in origins {
+        let service_name = Name::parse(service, Some(origin))?;
+        for replica in replicas {
+            let target = Name::parse(&replica.target, Some(&Name::root()))?;
+            let priority = replica.priority.unwrap_or(10);
+            let srv = SRV::new(
+                priority,
+                replica.weight.unwrap_or(10),
+                replica.port(),
+                target.clone(),
+            );
+            records.push(Record::from_rdata(
+                service_name.clone(),
+                ttl,
+                RData::SRV(srv),
+            ));
+            let mut params = vec![(
+                SvcParamKey::Alpn,
+                SvcParamValue::Alpn(Alpn(vec!["h2".to_string(), "http/1.1".to_string()])),
+            )];
+            if replica.port() != 443 {
+                params.push((SvcParamKey::Port, SvcParamValue::Port(replica.port())));
+            }
+            // the priority of a service mode record is at least 1
+            let https = HTTPS(SVCB::new(priority.max(1), target, params));
+            records.push(Record::from_rdata(origin.clone(), ttl, RData::HTTPS(https)));
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_proto::rr::RecordType;
+
+    use super::*;
+
+    #[test]
+    fn replica_records() -> Result<()> {
+        let replica = |target: &str, port| Replica {
+            target: target.to_string(),
+            port,
+            priority: None,
+            weight: None,
+            health_url: None,
+        };
+        let config = ReplicasConfig {
+            replicas: vec![
+                replica("eu.example.org", None),
+                replica("us.example.org", Some(8443)),
+            ],
+            interval_secs: None,
+            ttl: None,
+            service: None,
+        };
+        let origin = Name::parse("dns.example.org.", None)?;
+        let records = records(&config, &config.replicas, std::slice::from_ref(&origin))?;
+        assert_eq!(records.len(), 4);
+
+        let srv: Vec<_> = records
+            .iter()
+            .filter(|record| record.record_type() == RecordType::SRV)
+            .collect();
+        assert_eq!(
+            srv[1].name(),
+            &Name::parse("_pkarr._tcp.dns.example.org.", None)?
+        );
+        let RData::SRV(data) = srv[1].data() else {
+            panic!("not an SRV record");
+        };
+        assert_eq!(data.port(), 8443);
+        assert_eq!(data.target(), &Name::parse("us.example.org.", None)?);
+
+        let https: Vec<_> = records
+            .iter()
+            .filter(|record| record.record_type() == RecordType::HTTPS)
+            .collect();
+        assert_eq!(https[0].name(), &origin);
+        let RData::HTTPS(HTTPS(data)) = https[1].data() else {
+            panic!("not an HTTPS record");
+        };
+        assert!(data
+            .svc_params()
+            .contains(&(SvcParamKey::Port, SvcParamValue::Port(8443))));
+        Ok(())
+    }
+}
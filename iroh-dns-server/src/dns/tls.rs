@@ -0,0 +1,239 @@
+//! DNS-over-TLS (RFC 7858) and DNS-over-QUIC (RFC 9250)
+//!
+//! The encrypted listeners are bound to the addresses of the UDP and TCP listeners, on their own
+//! ports, and answer the requests with the same [`DnsHandler`]. Their certificate is created like
+//! the certificates of the HTTPS server, except that [`CertMode::LetsEncrypt`] is not supported:
+//! its TLS-ALPN-01 challenges are validated on port 443, so the certificate is obtained with
+//! [`CertMode::LetsEncryptDns`] instead, whose challenges are answered by this DNS server.
+
+#[cfg(feature = "https")]
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+#[cfg(feature = "https")]
+use anyhow::{bail, ensure, Context, Result};
+#[cfg(feature = "https")]
+use hickory_server::{
+    authority::MessageRequest,
+    proto::serialize::binary::BinDecodable,
+    server::{Protocol, Request, RequestHandler},
+};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "https")]
+use tokio::{net::TcpListener, sync::mpsc, task::JoinSet};
+#[cfg(feature = "https")]
+use tokio_rustls::TlsAcceptor;
+#[cfg(feature = "https")]
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "https")]
+use super::{serve_stream, AcmeChallenges, DnsHandler, StreamHandle};
+use crate::http::CertConfig;
+#[cfg(feature = "https")]
+use crate::{events::ServerEvents, http::CertMode, util};
+
+/// ALPN protocol identifier of DNS-over-TLS.
+#[cfg(feature = "https")]
+const ALPN_DOT: &[u8] = b"dot";
+/// ALPN protocol identifier of DNS-over-QUIC.
+#[cfg(feature = "https")]
+const ALPN_DOQ: &[u8] = b"doq";
+/// Timeout for the TLS handshakes of DNS-over-TLS connections.
+#[cfg(feature = "https")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Idle timeout for DNS-over-TLS connections.
+///
+/// Longer than for plain TCP, so that clients can reuse a connection instead of repeating the
+/// handshake for each query.
+#[cfg(feature = "https")]
+const TLS_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum size of a DNS-over-QUIC request, a DNS message with its length prefix.
+#[cfg(feature = "https")]
+const MAX_QUIC_REQUEST_SIZE: usize = 2 + u16::MAX as usize;
+/// The DoQ error code of a request that violates the protocol.
+#[cfg(feature = "https")]
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+/// Config for DNS-over-TLS and DNS-over-QUIC
+///
+/// The certificate is configured with the same fields as the certificates of the HTTPS server,
+/// see [`CertConfig`]. A listener is only bound if its port is set.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DnsTlsConfig {
+    /// The port to serve DNS-over-TLS at over TCP, usually 853
+    #[serde(default)]
+    pub dot_port: Option<u16>,
+    /// The port to serve DNS-over-QUIC at over UDP, usually 853
+    #[serde(default)]
+    pub doq_port: Option<u16>,
+    /// The certificate of the encrypted listeners
+    #[serde(flatten)]
+    pub cert: CertConfig,
+}
+
+/// Create the rustls server config of the encrypted listeners.
+#[cfg(feature = "https")]
+pub(super) async fn server_config(
+    config: &DnsTlsConfig,
+    data_dir: &Path,
+    acme_challenges: &AcmeChallenges,
+    events: &ServerEvents,
+) -> Result<Arc<rustls::ServerConfig>> {
+    if config.cert.cert_mode == CertMode::LetsEncrypt {
+        bail!("the certificate of DNS-over-TLS requires the lets_encrypt_dns cert mode instead of lets_encrypt");
+    }
+    // separate from the certificates of the HTTPS server, which may be for other domains
+    let cert_cache = data_dir.join("cert_cache").join("dns");
+    let config = config
+        .cert
+        .server_config(&cert_cache, acme_challenges, events)
+        .await?;
+    Ok(Arc::new(config))
+}
+
+/// Accept DNS-over-TLS connections on `listener`, and answer their requests with `dns_handler`.
+///
+/// The connections are closed when the task is aborted.
+#[cfg(feature = "https")]
+pub(super) async fn serve_tls(
+    listener: TcpListener,
+    tls_config: Arc<rustls::ServerConfig>,
+    dns_handler: DnsHandler,
+) {
+    let mut tls_config = (*tls_config).clone();
+    tls_config.alpn_protocols = vec![ALPN_DOT.to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("failed to accept DNS-over-TLS connection: {err}");
+                continue;
+            }
+        };
+        while connections.try_join_next().is_some() {}
+        let acceptor = acceptor.clone();
+        let dns_handler = dns_handler.clone();
+        connections.spawn(async move {
+            let res = async {
+                let stream =
+                    tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await??;
+                serve_stream(stream, peer, Protocol::Tls, TLS_IDLE_TIMEOUT, dns_handler).await
+            };
+            if let Err(err) = res.await {
+                debug!(%peer, "DNS-over-TLS connection closed with error: {err:#}");
+            }
+        });
+    }
+}
+
+/// Bind a QUIC endpoint for DNS-over-QUIC on `bind_addr`.
+#[cfg(feature = "https")]
+pub(super) fn bind_quic(
+    bind_addr: SocketAddr,
+    tls_config: Arc<rustls::ServerConfig>,
+) -> Result<quinn::Endpoint> {
+    let mut tls_config = (*tls_config).clone();
+    tls_config.alpn_protocols = vec![ALPN_DOQ.to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls_config)
+        .context("invalid TLS config for DNS-over-QUIC")?;
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    let socket = util::bind_udp(bind_addr)
+        .with_context(|| format!("failed to bind DNS-over-QUIC socket on {bind_addr}"))?;
+    let endpoint = quinn::Endpoint::new(
+        Default::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+    Ok(endpoint)
+}
+
+/// Accept DNS-over-QUIC connections on `endpoint`, and answer their requests with
+/// `dns_handler`.
+///
+/// The connections are closed when the task is aborted.
+#[cfg(feature = "https")]
+pub(super) async fn serve_quic(endpoint: quinn::Endpoint, dns_handler: DnsHandler) {
+    match endpoint.local_addr() {
+        Ok(addr) => info!("DNS-over-QUIC server listening on {addr}"),
+        Err(err) => warn!("failed to get the address of the DNS-over-QUIC server: {err}"),
+    }
+    let mut connections = JoinSet::new();
+    while let Some(incoming) = endpoint.accept().await {
+        while connections.try_join_next().is_some() {}
+        let peer = incoming.remote_address();
+        let dns_handler = dns_handler.clone();
+        connections.spawn(async move {
+            if let Err(err) = handle_quic_connection(incoming, dns_handler).await {
+                debug!(%peer, "DNS-over-QUIC connection closed with error: {err:#}");
+            }
+        });
+    }
+}
+
+/// Answer the requests of a DNS-over-QUIC connection, one on each bidirectional stream.
+#[cfg(feature = "https")]
+async fn handle_quic_connection(incoming: quinn::Incoming, dns_handler: DnsHandler) -> Result<()> {
+    let conn = incoming.await?;
+    let peer = conn.remote_address();
+    let mut streams = JoinSet::new();
+    loop {
+        let (send, recv) = match conn.accept_bi().await {
+            Ok(streams) => streams,
+            Err(
+                quinn::ConnectionError::ApplicationClosed(_)
+                | quinn::ConnectionError::LocallyClosed
+                | quinn::ConnectionError::TimedOut,
+            ) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        while streams.try_join_next().is_some() {}
+        let dns_handler = dns_handler.clone();
+        let conn = conn.clone();
+        streams.spawn(async move {
+            if let Err(err) = handle_quic_stream(send, recv, peer, dns_handler).await {
+                debug!(%peer, "invalid DNS-over-QUIC request: {err:#}");
+                conn.close(DOQ_PROTOCOL_ERROR.into(), b"invalid request");
+            }
+        });
+    }
+}
+
+/// Answer the request on a DNS-over-QUIC stream.
+///
+/// The client sends a single message with its length prefix and finishes the stream, and the
+/// response is sent the same way. Zone transfers are answered with several messages.
+#[cfg(feature = "https")]
+async fn handle_quic_stream(
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+    peer: SocketAddr,
+    dns_handler: DnsHandler,
+) -> Result<()> {
+    let buf = recv.read_to_end(MAX_QUIC_REQUEST_SIZE).await?;
+    ensure!(buf.len() >= 2, "missing length prefix");
+    let (len, message) = buf.split_at(2);
+    if usize::from(u16::from_be_bytes([len[0], len[1]])) != message.len() {
+        bail!("length prefix does not match the message");
+    }
+    let message = MessageRequest::from_bytes(message)?;
+    // the message ID is always 0 over QUIC, the response echoes it
+    let request = Request::new(message, peer, Protocol::Quic);
+    let (tx, mut rx) = mpsc::channel(1);
+    let answer = async {
+        dns_handler.handle_request(&request, StreamHandle(tx)).await;
+        anyhow::Ok(())
+    };
+    let write = async {
+        while let Some(response) = rx.recv().await {
+            let len: u16 = response.len().try_into()?;
+            send.write_all(&len.to_be_bytes()).await?;
+            send.write_all(&response).await?;
+        }
+        send.finish()?;
+        anyhow::Ok(())
+    };
+    tokio::try_join!(answer, write)?;
+    Ok(())
+}
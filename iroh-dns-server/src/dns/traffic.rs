@@ -0,0 +1,267 @@
+//! Rolling statistics of the DNS traffic
+//!
+//! The most queried pubkeys and the most active client prefixes (`/24` for IPv4, `/48` for IPv6)
+//! are tracked in bounded memory with the Space-Saving algorithm: the reported count of an entry
+//! is an upper bound, and at most `error` higher than the real count. The statistics are kept for
+//! windows of 10 minutes, and the current and the previous window are reported.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use serde::Serialize;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::util::PublicKeyBytes;
+
+/// The duration of a statistics window.
+pub(crate) const WINDOW: Duration = Duration::from_secs(10 * 60);
+/// The number of pubkeys and client prefixes that are tracked per window.
+pub(crate) const CAPACITY: usize = 512;
+
+/// The traffic statistics of the current and the previous window.
+#[derive(Debug)]
+pub(crate) struct TrafficStats {
+    windows: Mutex<Windows>,
+}
+
+#[derive(Debug)]
+struct Windows {
+    current: Window,
+    previous: Option<Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: SystemTime,
+    queries: u64,
+    pubkeys: SpaceSaving<PublicKeyBytes>,
+    clients: SpaceSaving<IpNet>,
+    qtypes: BTreeMap<String, u64>,
+}
+
+impl Window {
+    fn new(started: SystemTime) -> Self {
+        Self {
+            started,
+            queries: 0,
+            pubkeys: SpaceSaving::new(CAPACITY),
+            clients: SpaceSaving::new(CAPACITY),
+            qtypes: BTreeMap::new(),
+        }
+    }
+
+    fn stats(&self, limit: usize) -> TrafficWindow {
+        TrafficWindow {
+            started_at_ms: self
+                .started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            queries: self.queries,
+            top_pubkeys: self.pubkeys.top(limit, |key| key.to_z32()),
+            top_clients: self.clients.top(limit, ToString::to_string),
+            qtypes: self.qtypes.clone(),
+        }
+    }
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self {
+            windows: Mutex::new(Windows {
+                current: Window::new(SystemTime::now()),
+                previous: None,
+            }),
+        }
+    }
+}
+
+impl TrafficStats {
+    /// Count a query from `client`, for `pubkey` if the name is a pkarr name.
+    pub(crate) fn record(&self, pubkey: Option<PublicKeyBytes>, client: IpAddr, qtype: &str) {
+        let mut windows = self.windows.lock();
+        windows.rotate();
+        let window = &mut windows.current;
+        window.queries += 1;
+        if let Some(pubkey) = pubkey {
+            window.pubkeys.insert(pubkey);
+        }
+        window.clients.insert(client_prefix(client));
+        match window.qtypes.get_mut(qtype) {
+            Some(count) => *count += 1,
+            None => {
+                window.qtypes.insert(qtype.to_string(), 1);
+            }
+        }
+    }
+
+    /// Get the statistics of the current window and the previous window, with up to `limit` top
+    /// entries.
+    pub(crate) fn windows(&self, limit: usize) -> Vec<TrafficWindow> {
+        let mut windows = self.windows.lock();
+        windows.rotate();
+        std::iter::once(&windows.current)
+            .chain(windows.previous.as_ref())
+            .map(|window| window.stats(limit))
+            .collect()
+    }
+
+    /// The number of queries in the previous window, or else in the current window, and the
+    /// duration of that window.
+    pub(crate) fn recent_queries(&self) -> (u64, Duration) {
+        let mut windows = self.windows.lock();
+        windows.rotate();
+        match &windows.previous {
+            Some(previous) => (previous.queries, WINDOW),
+            None => {
+                let elapsed = windows.current.started.elapsed().unwrap_or_default();
+                (windows.current.queries, elapsed)
+            }
+        }
+    }
+}
+
+impl Windows {
+    /// Start a new window if the current one is over.
+    fn rotate(&mut self) {
+        let now = SystemTime::now();
+        let elapsed = now.duration_since(self.current.started).unwrap_or_default();
+        if elapsed < WINDOW {
+            return;
+        }
+        // the previous window is only reported if it immediately precedes the current one
+        let previous = std::mem::replace(&mut self.current, Window::new(now));
+        self.previous = (elapsed < 2 * WINDOW).then_some(previous);
+    }
+}
+
+/// The network of a client that is counted: the `/24` for IPv4 and the `/48` for IPv6.
+fn client_prefix(addr: IpAddr) -> IpNet {
+    let prefix_len = match addr {
+        IpAddr::V4(_) => 24,
+        IpAddr::V6(_) => 48,
+    };
+    IpNet::new(addr, prefix_len)
+        .expect("valid prefix length")
+        .trunc()
+}
+
+/// The statistics of a window.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct TrafficWindow {
+    /// Milliseconds since the unix epoch
+    started_at_ms: u64,
+    /// The number of queries in the window
+    queries: u64,
+    /// The most queried pubkeys
+    top_pubkeys: Vec<TopEntry>,
+    /// The client networks that sent the most queries
+    top_clients: Vec<TopEntry>,
+    /// The number of queries by record type
+    qtypes: BTreeMap<String, u64>,
+}
+
+/// An entry of a top list.
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct TopEntry {
+    key: String,
+    /// The number of queries, at most `error` higher than the real number
+    count: u64,
+    error: u64,
+}
+
+/// Space-Saving counter of the most frequent keys.
+#[derive(Debug)]
+struct SpaceSaving<K> {
+    capacity: usize,
+    counts: HashMap<K, Count>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Count {
+    count: u64,
+    error: u64,
+}
+
+impl<K: Hash + Eq + Clone> SpaceSaving<K> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, key: K) {
+        if let Some(count) = self.counts.get_mut(&key) {
+            count.count += 1;
+            return;
+        }
+        if self.counts.len() < self.capacity {
+            self.counts.insert(key, Count { count: 1, error: 0 });
+            return;
+        }
+        // replace the least frequent key, which the new key may have been counted as
+        let Some((min_key, min)) = self
+            .counts
+            .iter()
+            .min_by_key(|(_, count)| count.count)
+            .map(|(key, count)| (key.clone(), *count))
+        else {
+            return;
+        };
+        self.counts.remove(&min_key);
+        self.counts.insert(
+            key,
+            Count {
+                count: min.count + 1,
+                error: min.count,
+            },
+        );
+    }
+
+    fn top(&self, limit: usize, to_string: impl Fn(&K) -> String) -> Vec<TopEntry> {
+        let mut entries: Vec<_> = self.counts.iter().collect();
+        entries.sort_by_key(|(_, count)| std::cmp::Reverse(count.count));
+        entries
+            .into_iter()
+            .take(limit)
+            .map(|(key, count)| TopEntry {
+                key: to_string(key),
+                count: count.count,
+                error: count.error,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn space_saving() {
+        let mut counter = SpaceSaving::new(2);
+        for key in ["a", "a", "a", "b", "c", "a"] {
+            counter.insert(key);
+        }
+        let top = counter.top(2, ToString::to_string);
+        assert_eq!(top[0].key, "a");
+        assert_eq!(top[0].count, 4);
+        // `c` replaced `b`, and may have been counted once before
+        assert_eq!(top[1].key, "c");
+        assert_eq!((top[1].count, top[1].error), (2, 1));
+        assert_eq!(
+            client_prefix("2001:db8:1:2::1".parse().unwrap()).to_string(),
+            "2001:db8:1::/48"
+        );
+    }
+}
@@ -0,0 +1,596 @@
+//! Zone transfers between a primary and its secondaries
+//!
+//! A primary with a [`TransferConfig`] serves the zones of its origins, with the records of all
+//! stored packets, over AXFR to the allowed secondaries, and sends them a NOTIFY when a packet
+//! is published. IXFR requests are answered with the full zone, as there is no journal of the
+//! changes (RFC 1995, section 4).
+//!
+//! A server with a [`SecondaryConfig`] transfers the zones from its primary on start, when
+//! notified by the primary, and when the refresh interval of the SOA record elapsed, and serves
+//! them read-only. This works with any primary that serves AXFR, and any secondary can transfer
+//! the zones from a primary of this server.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use hickory_proto::{
+    op::{Header, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{rdata::SOA, LowerName, Name, Record, RecordSet, RecordType, RrKey},
+};
+use hickory_server::{
+    authority::{MessageResponseBuilder, ZoneType},
+    server::{Protocol, Request, ResponseHandler, ResponseInfo},
+    store::in_memory::InMemoryAuthority,
+};
+use ipnet::IpNet;
+use iroh_metrics::inc;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::{broadcast, Notify},
+};
+use tracing::{debug, info, warn};
+
+use super::{node_authority::NodeAuthority, DnsHandler};
+use crate::{
+    metrics::Metrics,
+    store::ExternalChange,
+    util::PublicKeyBytes,
+    util::{record_set_append_origin, signed_packet_to_hickory_records_without_origin},
+};
+
+/// Number of packets that are read from the store at once for a transfer.
+const TRANSFER_PAGE_SIZE: usize = 1000;
+/// Number of records in each message of a transfer.
+///
+/// The records of a packet are at most 1000 bytes, so that the messages stay below the 64 KiB
+/// limit of DNS over TCP.
+const TRANSFER_MESSAGE_RECORDS: usize = 50;
+/// Timeout for a refresh of a secondary, including the transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+/// Interval between the attempts of a secondary to refresh before it got an SOA record.
+const DEFAULT_RETRY: Duration = Duration::from_secs(30);
+/// Default of [`TransferConfig::notify_interval_secs`].
+const DEFAULT_NOTIFY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Config for the zone transfers of a primary
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransferConfig {
+    /// Networks of the secondaries that may transfer the zones, in CIDR notation (e.g.
+    /// `192.0.2.1/32`)
+    ///
+    /// Transfers are only served over TCP.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// DNS addresses of the secondaries that are sent a NOTIFY when the zones change (e.g.
+    /// `192.0.2.1:53`)
+    #[serde(default)]
+    pub notify: Vec<SocketAddr>,
+    /// Minimum interval between two NOTIFYs, so that bursts of publishes only cause one transfer
+    /// (defaults to 5)
+    pub notify_interval_secs: Option<u64>,
+}
+
+/// Config for running as a read-only secondary of a primary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecondaryConfig {
+    /// The DNS address of the primary (e.g. `192.0.2.1:53`)
+    ///
+    /// NOTIFYs are only accepted from the IP address of the primary.
+    pub primary: SocketAddr,
+    /// Interval between the checks of the SOA serial of the primary (defaults to the refresh
+    /// interval of the SOA record of the primary)
+    pub refresh_secs: Option<u64>,
+    /// Interval between the checks after a failed check (defaults to the retry interval of the
+    /// SOA record of the primary)
+    pub retry_secs: Option<u64>,
+}
+
+/// The state of a secondary that is shared with the DNS handler
+#[derive(Debug)]
+pub(crate) struct Secondary {
+    config: SecondaryConfig,
+    /// Notified when the primary sent a NOTIFY
+    notified: Notify,
+}
+
+impl Secondary {
+    pub(crate) fn new(config: SecondaryConfig) -> Self {
+        Self {
+            config,
+            notified: Notify::new(),
+        }
+    }
+}
+
+impl DnsHandler {
+    /// Answer an AXFR or IXFR request with all records of the zone.
+    pub(super) async fn answer_transfer<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        let src = request.src().ip();
+        let allowed = self
+            .transfers
+            .as_ref()
+            .is_some_and(|allow| allow.iter().any(|net| net.contains(&src)));
+        if !allowed || request.protocol() != Protocol::Tcp || self.authority.is_secondary() {
+            debug!(%src, protocol = %request.protocol(), "refused zone transfer");
+            return send_error(request, response_handle, ResponseCode::Refused).await;
+        }
+        let origin = request.query().name();
+        let Some(soa) = self.authority.soa(origin).await else {
+            return send_error(request, response_handle, ResponseCode::NotAuth).await;
+        };
+        let serial = self.authority.serial();
+        let up_to_date = request.query().query_type() == RecordType::IXFR
+            && request
+                .name_servers()
+                .iter()
+                .filter_map(|record| record.data().as_soa())
+                .any(|soa| soa.serial() == serial);
+        if up_to_date {
+            return match send_records(request, &mut response_handle, &[soa]).await {
+                Ok(info) => info,
+                Err(err) => response_info_for_error(request, err),
+            };
+        }
+        let start = Instant::now();
+        match self
+            .send_zone(request, &mut response_handle, origin, soa)
+            .await
+        {
+            Ok((info, records)) => {
+                inc!(Metrics, dns_zone_transfers_out);
+                info!(%src, %origin, serial, records, elapsed = ?start.elapsed(), "served zone transfer");
+                info
+            }
+            Err(err) => {
+                warn!(%src, %origin, "zone transfer failed: {err:#}");
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::ServFail);
+                header.into()
+            }
+        }
+    }
+
+    /// Send the records of the zone of `origin`, between two copies of its SOA record.
+    ///
+    /// Returns the number of records that were sent.
+    async fn send_zone<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_handle: &mut R,
+        origin: &LowerName,
+        soa: Record,
+    ) -> Result<(ResponseInfo, usize)> {
+        let serial = self.authority.serial();
+        let origin = Name::from(origin);
+        let mut count = 0;
+        let mut batch = vec![soa.clone()];
+        batch.extend(self.authority.static_records(&origin.clone().into()).await);
+        let mut after = None;
+        loop {
+            let packets = self
+                .authority
+                .zones()
+                .packets_after(after, TRANSFER_PAGE_SIZE)
+                .await?;
+            let Some(last) = packets.last() else {
+                break;
+            };
+            after = Some(PublicKeyBytes::from_signed_packet(last));
+            for signed_packet in &packets {
+                let (label, record_sets) = match signed_packet_to_hickory_records_without_origin(
+                    signed_packet,
+                    |_| true,
+                ) {
+                    Ok(records) => records,
+                    Err(err) => {
+                        debug!("skipped invalid packet in zone transfer: {err:#}");
+                        continue;
+                    }
+                };
+                let packet_origin = Name::from_labels([label])?.append_name(&origin)?;
+                for record_set in record_sets.values() {
+                    let mut record_set =
+                        record_set_append_origin(record_set, &packet_origin, serial)?;
+                    self.authority.clamp_ttl(&mut record_set);
+                    batch.extend(record_set.records_without_rrsigs().cloned());
+                }
+                if batch.len() >= TRANSFER_MESSAGE_RECORDS {
+                    send_records(request, response_handle, &batch).await?;
+                    count += batch.len();
+                    batch.clear();
+                }
+            }
+        }
+        batch.push(soa);
+        let info = send_records(request, response_handle, &batch).await?;
+        count += batch.len();
+        Ok((info, count))
+    }
+
+    /// Answer a NOTIFY, and refresh the zones if it was sent by the primary of this secondary.
+    pub(super) async fn answer_notify<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        mut response_handle: R,
+    ) -> ResponseInfo {
+        let src = request.src().ip();
+        let Some(secondary) = self
+            .secondary
+            .as_ref()
+            .filter(|secondary| secondary.config.primary.ip() == src)
+        else {
+            debug!(%src, "refused NOTIFY");
+            return send_error(request, response_handle, ResponseCode::Refused).await;
+        };
+        debug!(%src, "received NOTIFY from the primary");
+        secondary.notified.notify_one();
+        let mut header = Header::response_from_request(request.header());
+        header.set_authoritative(true);
+        let response =
+            MessageResponseBuilder::from_message_request(request).build_no_records(header);
+        match response_handle.send_response(response).await {
+            Ok(info) => info,
+            Err(err) => response_info_for_error(request, err),
+        }
+    }
+}
+
+/// Send the records in one response message.
+async fn send_records<R: ResponseHandler>(
+    request: &Request,
+    response_handle: &mut R,
+    records: &[Record],
+) -> io::Result<ResponseInfo> {
+    let mut header = Header::response_from_request(request.header());
+    header.set_authoritative(true);
+    let response = MessageResponseBuilder::from_message_request(request).build(
+        header,
+        records.iter(),
+        [],
+        [],
+        [],
+    );
+    response_handle.send_response(response).await
+}
+
+async fn send_error<R: ResponseHandler>(
+    request: &Request,
+    mut response_handle: R,
+    response_code: ResponseCode,
+) -> ResponseInfo {
+    let response = MessageResponseBuilder::from_message_request(request)
+        .error_msg(request.header(), response_code);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(err) => {
+            let mut header = Header::response_from_request(request.header());
+            header.set_response_code(response_code);
+            debug!("failed to send {response_code} response: {err}");
+            header.into()
+        }
+    }
+}
+
+fn response_info_for_error(request: &Request, err: io::Error) -> ResponseInfo {
+    debug!("failed to send response: {err}");
+    let mut header = Header::response_from_request(request.header());
+    header.set_response_code(ResponseCode::ServFail);
+    header.into()
+}
+
+/// Send a NOTIFY to the secondaries whenever packets were published, at most once per interval.
+pub(crate) async fn notify_secondaries(config: TransferConfig, dns_handler: DnsHandler) {
+    let interval = config
+        .notify_interval_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_NOTIFY_INTERVAL);
+    let authority = &dns_handler.authority;
+    let mut published = authority.zones().subscribe();
+    // the packets published on the other servers of a shared store
+    let mut changed = authority.zones().watch();
+    loop {
+        let changes_closed = tokio::select! {
+            res = published.recv() => match res {
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => false,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            res = next_change(changed.as_mut()) => {
+                matches!(res, Err(broadcast::error::RecvError::Closed))
+            }
+        };
+        if changes_closed {
+            changed = None;
+            continue;
+        }
+        // wait for more publishes, so that they are transferred at once
+        tokio::time::sleep(interval).await;
+        published = published.resubscribe();
+        changed = changed.as_ref().map(broadcast::Receiver::resubscribe);
+        let serial = authority.bump_serial();
+        // the other servers of the store leave the NOTIFY to the leader
+        if !authority.zones().is_leader().await {
+            debug!(serial, "zone changed, not the leader of the store");
+            continue;
+        }
+        debug!(serial, "zone changed, notifying the secondaries");
+        for origin in authority.origins() {
+            for addr in &config.notify {
+                if let Err(err) = send_notify(*addr, origin).await {
+                    warn!(%addr, %origin, "failed to send NOTIFY: {err:#}");
+                }
+            }
+        }
+    }
+}
+
+/// Receive the next change by the other servers of a shared store, or wait forever if the store
+/// is not shared.
+async fn next_change(
+    changes: Option<&mut broadcast::Receiver<ExternalChange>>,
+) -> Result<ExternalChange, broadcast::error::RecvError> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Send a NOTIFY for the zone of `origin` to `addr`, without waiting for the answer.
+///
+/// The secondaries check the serial of the primary regularly, so a lost NOTIFY only delays the
+/// transfer.
+async fn send_notify(addr: SocketAddr, origin: &Name) -> Result<()> {
+    let message = message(origin, RecordType::SOA, OpCode::Notify);
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.send_to(&message.to_vec()?, addr).await?;
+    inc!(Metrics, dns_notify_sent);
+    Ok(())
+}
+
+/// Transfer the zones from the primary on start, whenever notified, and after the refresh
+/// interval, until the task is aborted.
+pub(crate) async fn run_secondary(dns_handler: DnsHandler) {
+    let secondary = dns_handler
+        .secondary
+        .clone()
+        .expect("the handler is a secondary");
+    let config = &secondary.config;
+    let mut soa: Option<SOA> = None;
+    loop {
+        let res = tokio::time::timeout(
+            TRANSFER_TIMEOUT,
+            refresh(config.primary, &dns_handler.authority),
+        )
+        .await
+        .context("timed out")
+        .and_then(|res| res);
+        let wait = match res {
+            Ok(primary_soa) => {
+                let refresh = config
+                    .refresh_secs
+                    .unwrap_or(primary_soa.refresh().max(1) as u64);
+                soa = Some(primary_soa);
+                Duration::from_secs(refresh)
+            }
+            Err(err) => {
+                inc!(Metrics, dns_zone_transfers_failed);
+                warn!(primary = %config.primary, "failed to refresh the zones: {err:#}");
+                match (config.retry_secs, &soa) {
+                    (Some(retry), _) => Duration::from_secs(retry),
+                    (None, Some(soa)) => Duration::from_secs(soa.retry().max(1) as u64),
+                    (None, None) => DEFAULT_RETRY,
+                }
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = secondary.notified.notified() => {}
+        }
+    }
+}
+
+/// Transfer the zones from the primary if its serial changed, and return its SOA record.
+async fn refresh(primary: SocketAddr, authority: &NodeAuthority) -> Result<SOA> {
+    let first_origin = authority
+        .origins()
+        .next()
+        .expect("there is at least one origin");
+    let soa = query_soa(primary, first_origin).await?;
+    if authority.is_transferred() && soa.serial() == authority.serial() {
+        debug!(serial = soa.serial(), "the zones are up to date");
+        return Ok(soa);
+    }
+    let start = Instant::now();
+    let mut zone = Vec::new();
+    let mut serial = soa.serial();
+    for origin in authority.origins() {
+        let (origin_serial, records) = transfer_zone(primary, origin).await?;
+        // with the lowest serial, a change during the transfers is transferred on the next refresh
+        serial = serial.min(origin_serial);
+        zone.extend(records);
+    }
+    let count = zone.len();
+    let mut records: BTreeMap<RrKey, RecordSet> = BTreeMap::new();
+    for record in zone {
+        let key = RrKey::new(record.name().into(), record.record_type());
+        records
+            .entry(key)
+            .or_insert_with(|| RecordSet::new(record.name(), record.record_type(), serial))
+            .insert(record, serial);
+    }
+    let zone = InMemoryAuthority::new(Name::root(), records, ZoneType::Secondary, false)
+        .map_err(|err| anyhow!(err))?;
+    authority.replace_transferred_zone(zone, serial);
+    inc!(Metrics, dns_zone_transfers_in);
+    info!(%primary, serial, records = count, elapsed = ?start.elapsed(), "transferred the zones from the primary");
+    Ok(soa)
+}
+
+/// Query the SOA record of `origin` from the primary.
+async fn query_soa(primary: SocketAddr, origin: &Name) -> Result<SOA> {
+    let mut stream = TcpStream::connect(primary).await?;
+    let query = message(origin, RecordType::SOA, OpCode::Query);
+    write_message(&mut stream, &query).await?;
+    let response = read_response(&mut stream, &query).await?;
+    response
+        .answers()
+        .iter()
+        .find_map(|record| record.data().as_soa())
+        .cloned()
+        .with_context(|| format!("the primary has no SOA record for {origin}"))
+}
+
+/// Transfer the zone of `origin` from the primary with AXFR, and return its serial and records.
+async fn transfer_zone(primary: SocketAddr, origin: &Name) -> Result<(u32, Vec<Record>)> {
+    let mut stream = TcpStream::connect(primary).await?;
+    let query = message(origin, RecordType::AXFR, OpCode::Query);
+    write_message(&mut stream, &query).await?;
+    let mut serial = None;
+    let mut records = Vec::new();
+    loop {
+        let mut response = read_response(&mut stream, &query).await?;
+        for record in response.take_answers() {
+            match (serial, record.data().as_soa()) {
+                (None, Some(soa)) => serial = Some(soa.serial()),
+                (None, None) => bail!("the transfer of {origin} does not start with an SOA record"),
+                // the transfer ends with the SOA record it started with
+                (Some(serial), Some(_)) => return Ok((serial, records)),
+                (Some(_), None) => {}
+            }
+            records.push(record);
+        }
+    }
+}
+
+fn message(name: &Name, record_type: RecordType, op_code: OpCode) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(op_code)
+        .add_query(Query::query(name.clone(), record_type));
+    message
+}
+
+async fn write_message(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    // DNS messages over TCP are prefixed with their length as u16
+    let bytes = message.to_vec()?;
+    stream.write_u16(bytes.len().try_into()?).await?;
+    stream.write_all(&bytes).await?;
+    Ok(())
+}
+
+async fn read_response(stream: &mut TcpStream, query: &Message) -> Result<Message> {
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    let response = Message::from_vec(&buf)?;
+    ensure!(
+        response.id() == query.id(),
+        "the primary answered another query"
+    );
+    ensure!(
+        response.response_code() == ResponseCode::NoError,
+        "the primary answered with {}",
+        response.response_code()
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        path::Path,
+    };
+
+    use hickory_server::authority::{Authority, LookupOptions};
+    use pkarr::{dns, Keypair, SignedPacket};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        dns::DnsServer,
+        store::{PacketSource, ZoneStore},
+    };
+
+    #[tokio::test]
+    async fn transfer_to_secondary() -> Result<()> {
+        let mut config = Config::default().dns;
+        config.port = 0;
+        config.bind_addr = vec![IpAddr::V4(Ipv4Addr::LOCALHOST).into()];
+        let store = ZoneStore::in_memory()?;
+        let transfers = TransferConfig {
+            allow: vec!["127.0.0.1/32".parse()?],
+            ..Default::default()
+        };
+        let primary = DnsHandler::new(store.clone(), &config)?.with_transfers(&transfers);
+        let server = DnsServer::spawn(
+            config.clone(),
+            Default::default(),
+            primary.clone(),
+            Path::new("."),
+            Default::default(),
+        )
+        .await?;
+
+        let keypair = Keypair::random();
+        let mut packet = dns::Packet::new_reply(0);
+        packet.answers.push(dns::ResourceRecord::new(
+            dns::Name::new("_iroh").unwrap(),
+            dns::CLASS::IN,
+            30,
+            dns::rdata::RData::TXT("node=1".try_into()?),
+        ));
+        let signed_packet = SignedPacket::from_packet(&keypair, &packet)?;
+        store
+            .insert(signed_packet, PacketSource::PkarrPublish)
+            .await?;
+        let serial = primary.authority.bump_serial();
+
+        let secondary =
+            DnsHandler::new(ZoneStore::in_memory()?, &config)?.with_secondary(SecondaryConfig {
+                primary: server.local_addr(),
+                refresh_secs: None,
+                retry_secs: None,
+            });
+        let name = LowerName::from(Name::from_utf8(format!(
+            "_iroh.{}.irohdns.example.",
+            keypair.public_key().to_z32()
+        ))?);
+        let lookup = || {
+            secondary
+                .authority
+                .lookup(&name, RecordType::TXT, LookupOptions::default())
+        };
+        // the zone was not transferred yet
+        assert!(lookup().await.is_err());
+
+        let soa = refresh(server.local_addr(), &secondary.authority).await?;
+        assert_eq!(soa.serial(), serial);
+        assert_eq!(secondary.authority.serial(), serial);
+        let records = lookup().await?;
+        let txt = records.iter().next().context("no TXT record")?;
+        assert_eq!(
+            txt.data().as_txt().context("not TXT")?.to_string(),
+            "node=1"
+        );
+
+        server.shutdown(Duration::from_secs(1)).await?;
+        Ok(())
+    }
+}
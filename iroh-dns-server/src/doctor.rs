@@ -0,0 +1,429 @@
+//! Diagnostics of a deployment, for the `doctor` command
+//!
+//! The checks look at the server from where its clients are: the certificates its HTTPS server
+//! serves, the delegation of its origins in the public DNS, whether its ports are reachable from
+//! outside, and whether the bootstrap nodes of the mainline DHT answer. Each check passes, fails,
+//! warns about a setup that works but is likely not intended, or is skipped if it doesn't apply.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use tokio::net::UdpSocket;
+use url::Url;
+
+#[cfg(feature = "mainline")]
+use crate::bootstrap;
+use crate::config::Config;
+#[cfg(feature = "https")]
+use crate::http::{self, CertMode};
+
+/// The public resolver the delegation is checked with, if none is given.
+pub const DEFAULT_RESOLVER: &str = "1.1.1.1:53";
+/// Timeout of each network request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum Outcome {
+    /// The check passed
+    #[display("PASS")]
+    Pass,
+    /// The check passed, but the setup is likely not intended
+    #[display("WARN")]
+    Warn,
+    /// The check failed
+    #[display("FAIL")]
+    Fail,
+    /// The check doesn't apply to the config
+    #[display("SKIP")]
+    Skip,
+}
+
+/// A check and its outcome
+#[derive(Debug, Clone)]
+pub struct Check {
+    /// What was checked, e.g. `certificate dns.example.org`
+    pub name: String,
+    /// The outcome of the check
+    pub outcome: Outcome,
+    /// Why the check has its outcome
+    pub detail: String,
+}
+
+/// The checks of a `doctor` run
+#[derive(Debug, Default)]
+pub struct DoctorReport {
+    /// The checks, in the order they ran
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    /// Whether any check failed.
+    pub fn failed(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.outcome == Outcome::Fail)
+    }
+
+    fn push(&mut self, name: impl Into<String>, outcome: Outcome, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name: name.into(),
+            outcome,
+            detail: detail.into(),
+        });
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "{}  {}: {}", check.outcome, check.name, check.detail)?;
+        }
+        let count = |outcome| {
+            self.checks
+                .iter()
+                .filter(|check| check.outcome == outcome)
+                .count()
+        };
+        writeln!(
+            f,
+            "{} passed, {} warnings, {} failed, {} skipped",
+            count(Outcome::Pass),
+            count(Outcome::Warn),
+            count(Outcome::Fail),
+            count(Outcome::Skip)
+        )
+    }
+}
+
+/// Where the checks that need a third party are run from
+#[derive(Debug, Clone)]
+pub struct DoctorOptions {
+    /// The public DNS resolver to check the delegation of the origins with
+    pub resolver: SocketAddr,
+    /// A port check service, which is asked with `GET <url>?host=<host>&port=<port>` whether it
+    /// can connect to a TCP port, and answers with a success status if it can
+    pub probe_url: Option<Url>,
+}
+
+/// Run all checks for the server of `config`.
+pub async fn run(config: &Config, options: &DoctorOptions) -> DoctorReport {
+    let mut report = DoctorReport::default();
+    check_certificates(config, &mut report).await;
+    check_delegation(config, options.resolver, &mut report).await;
+    check_reachability(config, options.probe_url.as_ref(), &mut report).await;
+    check_mainline(config, &mut report).await;
+    report
+}
+
+/// Check that the HTTPS server serves a trusted certificate for each domain, that doesn't expire
+/// soon.
+#[cfg(not(feature = "https"))]
+async fn check_certificates(_config: &Config, report: &mut DoctorReport) {
+    report.push(
+        "certificates",
+        Outcome::Skip,
+        "built without the https feature",
+    )
+}
+
+/// Check that the HTTPS server serves a trusted certificate for each domain, that doesn't expire
+/// soon.
+#[cfg(feature = "https")]
+async fn check_certificates(config: &Config, report: &mut DoctorReport) {
+    let Some(https) = &config.https else {
+        report.push("certificates", Outcome::Skip, "no HTTPS server");
+        return;
+    };
+    for cert in https.certificates() {
+        for domain in &cert.domains {
+            let name = format!("certificate {domain}");
+            if cert.cert_mode == CertMode::SelfSigned {
+                report.push(name, Outcome::Warn, "self-signed, clients don't trust it");
+                continue;
+            }
+            if domain.starts_with("*.") {
+                report.push(
+                    name,
+                    Outcome::Skip,
+                    "wildcard domains can't be connected to",
+                );
+                continue;
+            }
+            let res =
+                tokio::time::timeout(TIMEOUT, http::served_cert_not_after(domain, https.port))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+            match res {
+                Ok(not_after) => {
+                    let left = not_after - time::OffsetDateTime::now_utc().unix_timestamp();
+                    let (outcome, detail) = expiry_outcome(left);
+                    report.push(name, outcome, detail);
+                }
+                Err(err) => report.push(
+                    name,
+                    Outcome::Fail,
+                    format!("{domain}:{}: {err:#}", https.port),
+                ),
+            }
+        }
+    }
+}
+
+/// The outcome of a certificate that expires in `left` seconds.
+#[cfg(feature = "https")]
+fn expiry_outcome(left: i64) -> (Outcome, String) {
+    let days = left / (60 * 60 * 24);
+    if left <= 0 {
+        (Outcome::Fail, "expired".to_string())
+    } else if left < http::EXPIRY_ERROR_BEFORE.as_secs() as i64 {
+        (Outcome::Fail, format!("expires in {days} days"))
+    } else if left < http::EXPIRY_WARN_BEFORE.as_secs() as i64 {
+        (Outcome::Warn, format!("expires in {days} days"))
+    } else {
+        (Outcome::Pass, format!("trusted, expires in {days} days"))
+    }
+}
+
+/// Check that the public DNS delegates the origins to the name server of the config, and that it
+/// answers with the SOA record of this server.
+async fn check_delegation(config: &Config, resolver: SocketAddr, report: &mut DoctorReport) {
+    let dns = &config.dns;
+    let expected_ns = dns.rr_ns.as_deref().map(absolute);
+    let expected_mname = dns.default_soa.split_whitespace().next().map(absolute);
+    for origin in dns.origins.iter().filter(|origin| *origin != ".") {
+        let origin = absolute(origin);
+        let name = format!("delegation {origin}");
+        match lookup(resolver, &origin, RecordType::NS).await {
+            Err(err) => report.push(name, Outcome::Fail, format!("{err:#}")),
+            Ok(servers) if servers.is_empty() => report.push(
+                name,
+                Outcome::Fail,
+                "the origin is not delegated, there are no NS records",
+            ),
+            Ok(servers) => {
+                let list = servers.join(", ");
+                match &expected_ns {
+                    None => report.push(
+                        name,
+                        Outcome::Warn,
+                        format!("delegated to {list}, but dns.rr_ns is not set"),
+                    ),
+                    Some(ns) if !servers.contains(ns) => report.push(
+                        name,
+                        Outcome::Fail,
+                        format!("delegated to {list}, not to dns.rr_ns {ns}"),
+                    ),
+                    Some(_) => report.push(name, Outcome::Pass, format!("delegated to {list}")),
+                }
+            }
+        }
+
+        let name = format!("soa {origin}");
+        match lookup(resolver, &origin, RecordType::SOA).await {
+            Err(err) => report.push(name, Outcome::Fail, format!("{err:#}")),
+            Ok(soa) => match (soa.first(), &expected_mname) {
+                (None, _) => report.push(name, Outcome::Fail, "there is no SOA record"),
+                (Some(mname), Some(expected)) if mname != expected => report.push(
+                    name,
+                    Outcome::Fail,
+                    format!("the primary is {mname}, not {expected} of dns.default_soa"),
+                ),
+                (Some(mname), _) => report.push(
+                    name,
+                    Outcome::Pass,
+                    format!("the public DNS reaches this server, the primary is {mname}"),
+                ),
+            },
+        }
+    }
+    let (Some(ns), Some(ip)) = (&expected_ns, dns.rr_a) else {
+        return;
+    };
+    let name = format!("address {ns}");
+    match lookup(resolver, ns, RecordType::A).await {
+        Err(err) => report.push(name, Outcome::Fail, format!("{err:#}")),
+        Ok(addrs) if addrs.contains(&ip.to_string()) => {
+            report.push(name, Outcome::Pass, format!("resolves to dns.rr_a {ip}"))
+        }
+        Ok(addrs) => report.push(
+            name,
+            Outcome::Fail,
+            format!("resolves to [{}], not to dns.rr_a {ip}", addrs.join(", ")),
+        ),
+    }
+}
+
+/// Check with the port check service that the ports of the public addresses are reachable.
+async fn check_reachability(config: &Config, probe_url: Option<&Url>, report: &mut DoctorReport) {
+    let Some(probe_url) = probe_url else {
+        report.push(
+            "reachability",
+            Outcome::Skip,
+            "set --probe-url to check the ports from outside",
+        );
+        return;
+    };
+    let ip: Option<IpAddr> = config
+        .dns
+        .rr_a
+        .map(IpAddr::from)
+        .or(config.dns.rr_aaaa.map(IpAddr::from));
+    let mut targets = Vec::new();
+    if let Some(ip) = ip {
+        targets.push(("dns", ip.to_string(), config.dns.port));
+        if let Some(port) = config.http.as_ref().and_then(|http| http.port) {
+            targets.push(("http", ip.to_string(), port));
+        }
+    }
+    if let Some(https) = &config.https {
+        if let Some(domain) = https.domains.iter().find(|d| !d.starts_with("*.")) {
+            targets.push(("https", domain.clone(), https.port));
+        }
+    }
+    if targets.is_empty() {
+        report.push(
+            "reachability",
+            Outcome::Warn,
+            "no public address in dns.rr_a, dns.rr_aaaa or https.domains",
+        );
+        return;
+    }
+    let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => return report.push("reachability", Outcome::Fail, err.to_string()),
+    };
+    for (server, host, port) in targets {
+        let name = format!("reachability {server}");
+        let res = client
+            .get(probe_url.clone())
+            .query(&[("host", host.as_str()), ("port", &port.to_string())])
+            .send()
+            .await;
+        match res {
+            Ok(res) if res.status().is_success() => {
+                report.push(name, Outcome::Pass, format!("{host}:{port} is reachable"))
+            }
+            Ok(res) => {
+                let status = res.status();
+                let body = res.text().await.unwrap_or_default();
+                report.push(
+                    name,
+                    Outcome::Fail,
+                    format!("{host}:{port}: {status} {}", body.trim()),
+                )
+            }
+            Err(err) => report.push(name, Outcome::Fail, format!("probe service: {err}")),
+        }
+    }
+}
+
+/// Check that the bootstrap nodes of the mainline DHT answer.
+#[cfg(not(feature = "mainline"))]
+async fn check_mainline(_config: &Config, report: &mut DoctorReport) {
+    let name = "mainline bootstrap";
+    report.push(name, Outcome::Skip, "built without the mainline feature")
+}
+
+/// Check that the bootstrap nodes of the mainline DHT answer.
+#[cfg(feature = "mainline")]
+async fn check_mainline(config: &Config, report: &mut DoctorReport) {
+    let name = "mainline bootstrap";
+    match bootstrap::check(config).await {
+        Ok(None) => report.push(name, Outcome::Skip, "the mainline fallback is disabled"),
+        Ok(Some((0, _))) => report.push(name, Outcome::Fail, "no bootstrap node resolves"),
+        Ok(Some((total, 0))) => report.push(
+            name,
+            Outcome::Fail,
+            format!("none of the {total} bootstrap addresses answered"),
+        ),
+        Ok(Some((total, answered))) => {
+            let outcome = if answered * 2 < total {
+                Outcome::Warn
+            } else {
+                Outcome::Pass
+            };
+            let detail = format!("{answered} of {total} bootstrap addresses answered");
+            report.push(name, outcome, detail)
+        }
+        Err(err) => report.push(name, Outcome::Fail, format!("{err:#}")),
+    }
+}
+
+/// The name with a trailing dot, in lower case.
+fn absolute(name: &str) -> String {
+    let name = name.to_lowercase();
+    if name.ends_with('.') {
+        name
+    } else {
+        format!("{name}.")
+    }
+}
+
+/// Ask `resolver` for the records of `name` of type `qtype`, and get their data: the names of
+/// NS records, the primary of SOA records and the addresses of A records.
+///
+/// Returns no records if the name doesn't exist.
+async fn lookup(resolver: SocketAddr, name: &str, qtype: RecordType) -> Result<Vec<String>> {
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_recursion_desired(true)
+        .add_query(Query::query(Name::from_utf8(name)?, qtype));
+    let bind_addr: SocketAddr = match resolver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(resolver).await?;
+    socket.send(&query.to_vec()?).await?;
+    let mut buf = vec![0; 4096];
+    let len = tokio::time::timeout(TIMEOUT, socket.recv(&mut buf))
+        .await
+        .with_context(|| format!("{resolver} did not answer"))??;
+    let response = Message::from_vec(&buf[..len])?;
+    match response.response_code() {
+        ResponseCode::NoError => {}
+        ResponseCode::NXDomain => return Ok(Vec::new()),
+        rcode => bail!("{resolver} answered {rcode} for {name} {qtype}"),
+    }
+    let data = response
+        .answers()
+        .iter()
+        .filter(|record| record.record_type() == qtype)
+        .filter_map(|record| {
+            let data = record.data();
+            match qtype {
+                RecordType::NS => Some(absolute(&data.as_ns()?.0.to_string())),
+                RecordType::SOA => Some(absolute(&data.as_soa()?.mname().to_string())),
+                _ => Some(data.as_a()?.0.to_string()),
+            }
+        })
+        .collect();
+    Ok(data)
+}
+
+#[cfg(all(test, feature = "https"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificate_expiry() {
+        let day = 60 * 60 * 24;
+        assert_eq!(expiry_outcome(-1).0, Outcome::Fail);
+        assert_eq!(expiry_outcome(2 * day).0, Outcome::Fail);
+        assert_eq!(expiry_outcome(10 * day).0, Outcome::Warn);
+        assert_eq!(
+            expiry_outcome(60 * day),
+            (Outcome::Pass, "trusted, expires in 60 days".to_string())
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Events of the server, for embedders and `GET /admin/events`
+//!
+//! The store, the retention, the HTTPS certificates and the sync send [`ServerEvent`]s to the
+//! subscribers of [`ServerEvents`], which embedders get with
+//! [`Server::subscribe_events`](crate::server::Server::subscribe_events), and which
+//! `GET /admin/events` streams over a WebSocket as JSON messages. A subscriber that can't keep up
+//! misses events instead of slowing down the server.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::store::PacketSource;
+
+/// Number of events that are buffered for each subscriber.
+const EVENTS_CAPACITY: usize = 256;
+
+/// An event of the server
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// A packet updated the store
+    PacketPublished {
+        /// The z-base-32 encoded pubkey of the packet
+        pubkey: String,
+        /// Where the packet came from
+        source: PacketSource,
+    },
+    /// A packet older than the maximum age of the retention was removed
+    PacketExpired {
+        /// The z-base-32 encoded pubkey of the packet
+        pubkey: String,
+        /// The timestamp of the packet, in microseconds since the unix epoch
+        timestamp: u64,
+    },
+    /// A packet was removed with `DELETE /admin/packets/{key}`
+    PacketRemoved {
+        /// The z-base-32 encoded pubkey of the packet
+        pubkey: String,
+    },
+    /// A certificate of the HTTPS server was replaced with a new one, e.g. renewed with ACME
+    CertRenewed {
+        /// The domains of the certificate
+        domains: Vec<String>,
+        /// When the new certificate expires, in seconds since the unix epoch
+        not_after: i64,
+    },
+    /// The requests to the change feed of the upstream server started to fail
+    UpstreamUnhealthy {
+        /// The URL of the change feed of the upstream server
+        upstream: String,
+        /// The error of the first failed request
+        error: String,
+    },
+}
+
+/// The sender of the events of the server
+#[derive(Debug, Clone)]
+pub struct ServerEvents(broadcast::Sender<ServerEvent>);
+
+impl Default for ServerEvents {
+    fn default() -> Self {
+        Self(broadcast::channel(EVENTS_CAPACITY).0)
+    }
+}
+
+impl ServerEvents {
+    /// Send `event` to the subscribers.
+    pub(crate) fn send(&self, event: ServerEvent) {
+        // there are no receivers if nothing subscribed
+        self.0.send(event).ok();
+    }
+
+    /// Subscribe to the events that are sent from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.0.subscribe()
+    }
+}
@@ -0,0 +1,112 @@
+//! Replication of published packets between servers over iroh-gossip
+//!
+//! Servers with a [`GossipConfig`] form a mesh: every packet that is published to one of them is
+//! broadcast on a gossip topic, and stored by the others. A received packet is only stored if its
+//! signature is valid and it is newer than the stored packet of its key, so that all servers
+//! converge on the newest packet of each key without a shared database.
+//!
+//! The configured peers are checked periodically: a peer that is not a neighbor in the mesh is
+//! reported as down in the `peer_up` metric, and peers with an address are dialed again.
+
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use anyhow::{Context, Result};
+use iroh_net::NodeId;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "gossip")]
+pub(crate) use self::mesh::spawn;
+
+#[cfg(feature = "gossip")]
+mod mesh;
+
+/// Config for replicating packets between servers over gossip
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GossipConfig {
+    /// The UDP port of the gossip endpoint (defaults to 4919).
+    pub port: Option<u16>,
+    /// The other servers of the mesh, as `<node id>@<ip>:<port>`, or as `<node id>` for servers
+    /// that connect to this one but can't be connected to.
+    ///
+    /// Connections from other nodes are refused. The node id of a server is logged on start,
+    /// and is derived from the `gossip-key` file in its data directory.
+    #[serde(default)]
+    pub peers: Vec<GossipPeer>,
+    /// The name of the mesh (defaults to `iroh-dns-server`).
+    ///
+    /// Servers only replicate packets with servers of the same mesh.
+    pub mesh: Option<String>,
+}
+
+/// A server to replicate packets with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct GossipPeer {
+    /// The node id of the gossip endpoint of the server
+    pub node_id: NodeId,
+    /// The address of the gossip endpoint, if it can be connected to
+    pub addr: Option<SocketAddr>,
+}
+
+impl fmt::Display for GossipPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{}@{addr}", self.node_id),
+            None => write!(f, "{}", self.node_id),
+        }
+    }
+}
+
+impl FromStr for GossipPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (node_id, addr) = match s.split_once('@') {
+            Some((node_id, addr)) => (node_id, Some(addr)),
+            None => (s, None),
+        };
+        let node_id = node_id
+            .parse()
+            .with_context(|| format!("invalid node id in gossip peer {s:?}"))?;
+        let addr = addr
+            .map(str::parse)
+            .transpose()
+            .with_context(|| format!("invalid address in gossip peer {s:?}"))?;
+        Ok(Self { node_id, addr })
+    }
+}
+
+impl TryFrom<String> for GossipPeer {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<GossipPeer> for String {
+    fn from(peer: GossipPeer) -> Self {
+        peer.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn gossip_peer() -> Result<()> {
+        let node_id = SecretKey::generate().public();
+        let peer: GossipPeer = format!("{node_id}@127.0.0.1:4919").parse()?;
+        assert_eq!(peer.node_id, node_id);
+        assert_eq!(peer.addr, Some("127.0.0.1:4919".parse()?));
+        assert_eq!(peer.to_string().parse::<GossipPeer>()?, peer);
+        let peer: GossipPeer = node_id.to_string().parse()?;
+        assert_eq!(peer.addr, None);
+        assert!("foo@127.0.0.1:4919".parse::<GossipPeer>().is_err());
+        assert!(format!("{node_id}@foo").parse::<GossipPeer>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+//! The gossip endpoint and the replication of the packets over the mesh
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use futures_lite::StreamExt;
+use iroh_gossip::{
+    net::{Event, Gossip, GossipEvent, GossipReceiver, GossipSender, JoinOptions, GOSSIP_ALPN},
+    proto::TopicId,
+};
+use iroh_metrics::inc;
+use iroh_net::{
+    endpoint::get_remote_node_id, key::SecretKey, relay::RelayMode, AddrInfo, Endpoint, NodeAddr,
+    NodeId,
+};
+use pkarr::SignedPacket;
+use tokio::{sync::broadcast, task::JoinHandle};
+use tracing::{debug, info, warn};
+
+use super::{GossipConfig, GossipPeer};
+use crate::{
+    metrics::{Metrics, PeerKind, PeerMetrics},
+    store::{PacketSource, ZoneStore},
+};
+
+/// The file in the data directory with the secret key of the gossip endpoint
+const KEY_FILE: &str = "gossip-key";
+/// Default UDP port of the gossip endpoint.
+const DEFAULT_PORT: u16 = 4919;
+/// Default name of the mesh.
+const DEFAULT_MESH: &str = "iroh-dns-server";
+/// Interval in which the connections to the peers are checked.
+const PEER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bind the gossip endpoint, join the mesh and replicate packets until the task is aborted.
+pub(crate) async fn spawn(
+    config: &GossipConfig,
+    store: ZoneStore,
+    data_dir: &Path,
+) -> Result<JoinHandle<()>> {
+    let secret_key = load_or_create_key(&data_dir.join(KEY_FILE))?;
+    let port = config.port.unwrap_or(DEFAULT_PORT);
+    let endpoint = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![GOSSIP_ALPN.to_vec()])
+        // the peers are connected to directly, at their configured addresses
+        .relay_mode(RelayMode::Disabled)
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+        .bind_addr_v6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))
+        .bind()
+        .await
+        .context("failed to bind the gossip endpoint")?;
+    info!(
+        node_id = %endpoint.node_id(),
+        "gossip endpoint listening on {}",
+        endpoint.bound_sockets().0
+    );
+    let gossip = Gossip::from_endpoint(endpoint.clone(), Default::default(), &AddrInfo::default());
+    let mut bootstrap = Vec::new();
+    for peer in &config.peers {
+        if let Some(addr) = peer.addr {
+            endpoint.add_node_addr(NodeAddr::from_parts(peer.node_id, None, vec![addr]))?;
+            bootstrap.push(peer.node_id);
+        }
+    }
+    let mesh = config.mesh.as_deref().unwrap_or(DEFAULT_MESH);
+    let topic = TopicId::from_bytes(*blake3::hash(mesh.as_bytes()).as_bytes());
+    let (sender, receiver) = gossip
+        .join_with_opts(topic, JoinOptions::with_bootstrap(bootstrap))
+        .split();
+    let sender = Arc::new(sender);
+    let node_ids: Vec<NodeId> = config.peers.iter().map(|peer| peer.node_id).collect();
+    let peers = config.peers.clone();
+    let published = store.subscribe();
+    Ok(tokio::spawn(async move {
+        tokio::join!(
+            accept(endpoint, gossip, node_ids),
+            send(sender.clone(), published),
+            receive(receiver, sender, peers, store),
+        );
+    }))
+}
+
+/// Accept the gossip connections of the peers.
+async fn accept(endpoint: Endpoint, gossip: Gossip, peers: Vec<NodeId>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let gossip = gossip.clone();
+        let peers = peers.clone();
+        tokio::spawn(async move {
+            let res = async {
+                let conn = incoming.accept()?.await?;
+                let node_id = get_remote_node_id(&conn)?;
+                if !peers.contains(&node_id) {
+                    conn.close(0u32.into(), b"not a peer");
+                    return Err(anyhow!("refused connection from {node_id}, not a peer"));
+                }
+                gossip.handle_connection(conn).await
+            };
+            if let Err(err) = res.await {
+                debug!("gossip connection failed: {err:#}");
+            }
+        });
+    }
+}
+
+/// Broadcast the packets that are published to this server.
+async fn send(
+    sender: Arc<GossipSender>,
+    mut published: broadcast::Receiver<(SignedPacket, PacketSource)>,
+) {
+    loop {
+        let signed_packet = match published.recv().await {
+            // packets from the mesh are already gossiped by the mesh
+            Ok((signed_packet, PacketSource::PkarrPublish | PacketSource::Sync)) => signed_packet,
+            Ok((_, PacketSource::Gossip | PacketSource::Import)) => continue,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("{missed} published packets were not gossiped, the mesh is too slow");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        match sender.broadcast(signed_packet.as_bytes().clone()).await {
+            Ok(()) => inc!(Metrics, gossip_packets_sent),
+            Err(err) => warn!("failed to gossip packet: {err:#}"),
+        }
+    }
+}
+
+/// Store the packets that are gossiped by the peers, and check the connections to the peers.
+async fn receive(
+    mut receiver: GossipReceiver,
+    sender: Arc<GossipSender>,
+    peers: Vec<GossipPeer>,
+    store: ZoneStore,
+) {
+    let mut check = tokio::time::interval(PEER_CHECK_INTERVAL);
+    // the first tick completes immediately, when no peer can be connected yet
+    check.tick().await;
+    for peer in &peers {
+        PeerMetrics::set_up(PeerKind::Gossip, &peer.node_id.to_string(), false);
+    }
+    loop {
+        let event = tokio::select! {
+            event = receiver.next() => event,
+            _ = check.tick() => {
+                let neighbors: Vec<NodeId> = receiver.neighbors().collect();
+                check_peers(&neighbors, &sender, &peers).await;
+                continue;
+            }
+        };
+        let Some(event) = event else {
+            return;
+        };
+        let message = match event {
+            Ok(Event::Gossip(GossipEvent::Received(message))) => message,
+            Ok(Event::Gossip(GossipEvent::NeighborUp(node_id))) => {
+                info!("gossip peer {node_id} connected");
+                set_peer_up(&peers, node_id, true);
+                continue;
+            }
+            Ok(Event::Gossip(GossipEvent::NeighborDown(node_id))) => {
+                info!("gossip peer {node_id} disconnected");
+                set_peer_up(&peers, node_id, false);
+                continue;
+            }
+            Ok(Event::Gossip(GossipEvent::Joined(node_ids))) => {
+                for node_id in node_ids {
+                    set_peer_up(&peers, node_id, true);
+                }
+                continue;
+            }
+            Ok(Event::Lagged) => {
+                warn!("missed gossiped packets, storing them was too slow");
+                continue;
+            }
+            Err(err) => {
+                warn!("gossip failed: {err:#}");
+                return;
+            }
+        };
+        match store_packet(&store, &message.content).await {
+            Ok(true) => inc!(Metrics, gossip_packets_stored),
+            Ok(false) => {}
+            Err(err) => {
+                inc!(Metrics, gossip_packets_invalid);
+                debug!(from = %message.delivered_from, "invalid gossiped packet: {err:#}");
+            }
+        }
+    }
+}
+
+/// Report the peers that are not neighbors as down, and dial those with an address again.
+async fn check_peers(neighbors: &[NodeId], sender: &GossipSender, peers: &[GossipPeer]) {
+    let mut dial = Vec::new();
+    for peer in peers {
+        let up = neighbors.contains(&peer.node_id);
+        PeerMetrics::set_up(PeerKind::Gossip, &peer.node_id.to_string(), up);
+        if !up {
+            debug!("gossip peer {peer} is not connected");
+            if peer.addr.is_some() {
+                dial.push(peer.node_id);
+            }
+        }
+    }
+    if !dial.is_empty() {
+        if let Err(err) = sender.join_peers(dial).await {
+            warn!("failed to dial the gossip peers: {err:#}");
+        }
+    }
+}
+
+/// Record the connection state of `node_id`, if it is a configured peer.
+fn set_peer_up(peers: &[GossipPeer], node_id: NodeId, up: bool) {
+    // other nodes of the mesh are not labeled, to bound the label values
+    if peers.iter().any(|peer| peer.node_id == node_id) {
+        PeerMetrics::set_up(PeerKind::Gossip, &node_id.to_string(), up);
+    }
+}
+
+/// Store a gossiped packet if its signature is valid and it is newer than the stored packet.
+async fn store_packet(store: &ZoneStore, bytes: &Bytes) -> Result<bool> {
+    let signed_packet = SignedPacket::from_bytes(bytes)?;
+    store.insert(signed_packet, PacketSource::Gossip).await
+}
+
+/// Read the secret key of the gossip endpoint from `path`, or create it if it doesn't exist.
+fn load_or_create_key(path: &Path) -> Result<SecretKey> {
+    if path.exists() {
+        let hex = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read gossip key {}", path.display()))?;
+        let bytes: [u8; 32] = hex::decode(hex.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("invalid gossip key {}", path.display()))?;
+        return Ok(SecretKey::from_bytes(&bytes));
+    }
+    let secret_key = SecretKey::generate();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, hex::encode(secret_key.to_bytes()))
+        .with_context(|| format!("failed to write gossip key {}", path.display()))?;
+    Ok(secret_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pkarr::{dns::Packet, Keypair};
+
+    use super::*;
+    use crate::{metrics::PeerLabels, util::PublicKeyBytes};
+
+    #[tokio::test]
+    async fn replicate_packets() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let dir = std::env::temp_dir().join(format!("iroh-dns-gossip-{}", std::process::id()));
+        let ports = [47191, 47192];
+        let mut node_ids = Vec::new();
+        for port in ports {
+            let key = load_or_create_key(&dir.join(port.to_string()).join(KEY_FILE))?;
+            node_ids.push(key.public());
+        }
+        let mut stores = Vec::new();
+        let mut tasks = Vec::new();
+        for (i, port) in ports.into_iter().enumerate() {
+            let other = 1 - i;
+            let config = GossipConfig {
+                port: Some(port),
+                peers: vec![GossipPeer {
+                    node_id: node_ids[other],
+                    addr: Some((Ipv4Addr::LOCALHOST, ports[other]).into()),
+                }],
+                mesh: None,
+            };
+            let store = ZoneStore::in_memory()?;
+            tasks.push(spawn(&config, store.clone(), &dir.join(port.to_string())).await?);
+            stores.push(store);
+        }
+
+        // packets published before the servers are connected are not replicated, so publish
+        // newer packets until one arrives
+        let keypair = Keypair::random();
+        let pubkey = PublicKeyBytes::from(keypair.public_key());
+        let mut replicated = None;
+        for _ in 0..40 {
+            let signed_packet = SignedPacket::from_packet(&keypair, &Packet::new_reply(0))?;
+            stores[0]
+                .insert(signed_packet.clone(), PacketSource::PkarrPublish)
+                .await?;
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            replicated = stores[1].get_signed_packet(&pubkey).await?;
+            if replicated.is_some() {
+                break;
+            }
+        }
+        let replicated = replicated.context("packet was not replicated")?;
+        assert_eq!(replicated.public_key(), keypair.public_key());
+        let labels = PeerLabels {
+            kind: PeerKind::Gossip,
+            peer: node_ids[0].to_string(),
+        };
+        assert_eq!(PeerMetrics::get().peer_up.get_or_create(&labels).get(), 1);
+
+        for task in tasks {
+            task.abort();
+        }
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
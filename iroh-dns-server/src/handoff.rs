@@ -0,0 +1,194 @@
+//! Zero-downtime upgrades by handing the listeners to a new process
+//!
+//! On `SIGUSR2`, the server starts its executable again with the same arguments, and passes it
+//! the bound listeners like systemd socket activation does (see [`crate::systemd`]). Once the new
+//! process has loaded its config, it tells the old process to shut down gracefully, and opens the
+//! store as soon as the old process has exited. Since both processes share the sockets, DNS
+//! queries and connections that arrive in the meantime wait in the socket queues instead of being
+//! dropped.
+
+use std::{
+    env,
+    io::Write,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    path::Path,
+    process::Command,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+
+use crate::{store::ZoneStore, systemd};
+
+/// The parent process id, set in the environment of the new process.
+const PARENT_PID_VAR: &str = "IROH_DNS_HANDOFF_PARENT_PID";
+
+/// The descriptor on which the new process tells the old process to shut down.
+const TAKE_OVER_FD_VAR: &str = "IROH_DNS_HANDOFF_FD";
+
+/// Time the new process is given to load its config before the upgrade is aborted.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Clones of the bound listeners, or `None` if they are not recorded.
+static LISTENERS: Mutex<Option<Vec<socket2::Socket>>> = Mutex::new(None);
+
+/// Record the listeners that are bound from now on, to pass them to the new process.
+pub(crate) fn record_listeners() {
+    LISTENERS
+        .lock()
+        .expect("poisoned")
+        .get_or_insert_with(Vec::new);
+}
+
+/// Keep a clone of a bound listener, if listeners are recorded.
+pub(crate) fn register(socket: &socket2::Socket) {
+    if let Some(listeners) = LISTENERS.lock().expect("poisoned").as_mut() {
+        match socket.try_clone() {
+            Ok(socket) => listeners.push(socket),
+            Err(err) => warn!("failed to keep the listener for upgrades: {err}"),
+        }
+    }
+}
+
+/// Whether the sockets in `LISTEN_FDS` were passed by the parent process for an upgrade.
+///
+/// This is checked once, before the parent is told to shut down, because this process is
+/// reparented when the parent exits.
+pub(crate) fn passed_by_parent() -> bool {
+    static PASSED_BY_PARENT: OnceLock<bool> = OnceLock::new();
+    *PASSED_BY_PARENT.get_or_init(|| {
+        let parent_pid = env::var(PARENT_PID_VAR)
+            .ok()
+            .and_then(|pid| pid.parse().ok());
+        parent_pid == Some(std::os::unix::process::parent_id())
+    })
+}
+
+/// Start a new process with the recorded listeners, and wait until it is ready to take over.
+///
+/// Returns the process id of the new process. The caller must then shut down, so that the new
+/// process can open the store.
+pub(crate) async fn spawn_successor() -> Result<u32> {
+    let listeners = LISTENERS
+        .lock()
+        .expect("poisoned")
+        .iter()
+        .flatten()
+        .map(|socket| socket.try_clone())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let names = listeners
+        .iter()
+        .map(|socket| {
+            let addr = socket.local_addr().ok().and_then(|addr| addr.as_socket());
+            let ty = socket.r#type().ok();
+            addr.zip(ty)
+                .and_then(|(addr, ty)| systemd::activated_name(addr, ty))
+                .unwrap_or_else(|| "unknown".to_string())
+        })
+        .collect::<Vec<_>>();
+    let (take_over, take_over_child) = UnixStream::pair()?;
+
+    // the descriptors of the listeners, and then the take-over socket
+    let mut fds: Vec<RawFd> = listeners.iter().map(|socket| socket.as_raw_fd()).collect();
+    fds.push(take_over_child.as_raw_fd());
+    let listen_fds = listeners.len();
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env("LISTEN_FDS", listen_fds.to_string())
+        .env("LISTEN_FDNAMES", names.join(":"))
+        .env_remove("LISTEN_PID")
+        .env(PARENT_PID_VAR, std::process::id().to_string())
+        .env(TAKE_OVER_FD_VAR, (3 + listen_fds).to_string());
+    if systemd::watchdog_interval().is_some() {
+        // the new process is watched once it is the main process of the service
+        command.env_remove("WATCHDOG_PID");
+    }
+    // SAFETY: only async-signal-safe functions are called, and `fds` is not reallocated
+    unsafe {
+        command.pre_exec(move || {
+            // move the descriptors above their targets first, so that none is overwritten
+            let first_free = 3 + fds.len() as RawFd;
+            for fd in fds.iter_mut() {
+                *fd = nix::fcntl::fcntl(*fd, nix::fcntl::F_DUPFD_CLOEXEC(first_free))?;
+            }
+            // the targets are not closed on exec
+            for (i, fd) in fds.iter().enumerate() {
+                nix::unistd::dup2(*fd, 3 + i as RawFd)?;
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().context("failed to start the new process")?;
+    drop(take_over_child);
+    info!("started new process {} for the upgrade", child.id());
+
+    take_over.set_nonblocking(true)?;
+    let mut take_over = tokio::net::UnixStream::from_std(take_over)?;
+    let mut message = [0u8];
+    let taken_over = tokio::time::timeout(START_TIMEOUT, take_over.read(&mut message)).await;
+    match taken_over {
+        Ok(Ok(1)) => Ok(child.id()),
+        Ok(Ok(_)) => {
+            let status = child.wait()?;
+            bail!("the new process exited before taking over: {status}")
+        }
+        Ok(Err(err)) => {
+            child.kill().ok();
+            Err(err).context("failed to wait for the new process")
+        }
+        Err(_) => {
+            child.kill().ok();
+            child.wait()?;
+            bail!("the new process didn't take over within {START_TIMEOUT:?}")
+        }
+    }
+}
+
+/// Open the persistent store at `path`.
+///
+/// If this process was started for an upgrade, tells the old process to shut down, and waits up
+/// to `timeout` for it to exit and thereby release the store.
+pub(crate) async fn open_store(path: &Path, timeout: Duration) -> Result<ZoneStore> {
+    if let Some(parent) = take_over()? {
+        let start = Instant::now();
+        // this process is reparented once the parent exits
+        while std::os::unix::process::parent_id() == parent {
+            if start.elapsed() > timeout {
+                bail!("process {parent} didn't shut down within {timeout:?}");
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+    ZoneStore::persistent(path)
+}
+
+/// Tell the parent process to shut down, if it started this process for an upgrade.
+///
+/// Returns the process id of the parent.
+fn take_over() -> Result<Option<u32>> {
+    if !passed_by_parent() {
+        return Ok(None);
+    }
+    let Some(fd) = env::var(TAKE_OVER_FD_VAR)
+        .ok()
+        .and_then(|fd| fd.parse().ok())
+    else {
+        return Ok(None);
+    };
+    // SAFETY: the parent passes the take-over socket on this descriptor, which is not used
+    // anywhere else in this process
+    let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+    stream
+        .write_all(&[1])
+        .context("failed to tell the old process to shut down")?;
+    let parent = std::os::unix::process::parent_id();
+    info!("taking over from process {parent}");
+    Ok(Some(parent))
+}
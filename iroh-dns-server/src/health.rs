@@ -0,0 +1,361 @@
+//! Health gating for anycast deployments
+//!
+//! With a [`HealthConfig`], the server periodically checks that the packet store can be read and
+//! that the mainline DHT client is running, if the mainline fallback is enabled. After
+//! `unhealthy_after` failed checks in a row the server withdraws its service: `/readyz` answers
+//! with `503`, the DNS server optionally stops answering queries, and the anycast routes are
+//! withdrawn through the control socket of BIRD or the command pipe of ExaBGP. After
+//! `healthy_after` successful checks in a row they are announced again.
+//!
+//! The server starts unhealthy, and withdraws the routes on shutdown before it stops serving.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use iroh_metrics::inc;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::{metrics::Metrics, store::ZoneStore};
+
+/// Default interval of the checks.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// Default of [`HealthConfig::unhealthy_after`].
+const DEFAULT_UNHEALTHY_AFTER: u32 = 3;
+/// Default of [`HealthConfig::healthy_after`].
+const DEFAULT_HEALTHY_AFTER: u32 = 2;
+/// Timeout of each check, and of each command to the routing daemon.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Config for the health gating
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HealthConfig {
+    /// The interval of the checks in seconds (defaults to 10)
+    pub interval_secs: Option<u64>,
+    /// Number of failed checks in a row after which the service is withdrawn (defaults to 3)
+    pub unhealthy_after: Option<u32>,
+    /// Number of successful checks in a row after which the service is announced again
+    /// (defaults to 2)
+    pub healthy_after: Option<u32>,
+    /// Stop answering DNS queries while unhealthy, so that clients and load balancers fail over
+    /// to other servers.
+    #[serde(default)]
+    pub stop_dns: bool,
+    /// Enable and disable a protocol of BIRD, e.g. the static protocol of the anycast routes.
+    pub bird: Option<BirdConfig>,
+    /// Announce and withdraw routes with ExaBGP.
+    pub exabgp: Option<ExabgpConfig>,
+}
+
+/// Config for controlling the anycast routes with BIRD
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BirdConfig {
+    /// The control socket of BIRD (e.g. `/run/bird/bird.ctl`)
+    pub socket: PathBuf,
+    /// The protocol that is enabled while healthy, and disabled while unhealthy
+    pub protocol: String,
+}
+
+/// Config for controlling the anycast routes with ExaBGP
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExabgpConfig {
+    /// The named pipe that ExaBGP reads commands from (e.g. `/run/exabgp/exabgp.in`)
+    pub pipe: PathBuf,
+    /// The anycast prefixes to announce (e.g. `192.0.2.53/32`)
+    pub routes: Vec<IpNet>,
+    /// The next hop of the routes (defaults to `self`)
+    pub next_hop: Option<String>,
+}
+
+/// Whether the server is healthy, shared with the DNS and HTTP servers
+#[derive(Debug, Clone)]
+pub(crate) struct Health(Arc<AtomicBool>);
+
+impl Health {
+    pub(crate) fn new(healthy: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(healthy)))
+    }
+
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, healthy: bool) {
+        self.0.store(healthy, Ordering::Relaxed);
+    }
+}
+
+/// The task that checks the health, and announces or withdraws the service
+pub(crate) struct HealthTask {
+    task: JoinHandle<()>,
+    health: Health,
+    routes: Arc<Vec<Routes>>,
+}
+
+impl HealthTask {
+    /// Spawn the checks of `store`, which set `health`.
+    pub(crate) fn spawn(config: &HealthConfig, store: ZoneStore, health: Health) -> Self {
+        let mut routes = Vec::new();
+        if let Some(bird) = &config.bird {
+            routes.push(Routes::Bird(bird.clone()));
+        }
+        if let Some(exabgp) = &config.exabgp {
+            routes.push(Routes::Exabgp(exabgp.clone()));
+        }
+        let routes = Arc::new(routes);
+        let task = tokio::task::spawn(run(config.clone(), store, health.clone(), routes.clone()));
+        Self {
+            task,
+            health,
+            routes,
+        }
+    }
+
+    /// Stop the checks and withdraw the service.
+    pub(crate) async fn shutdown(self) {
+        self.task.abort();
+        self.health.set(false);
+        for routes in self.routes.iter() {
+            if let Err(err) = routes.set(false).await {
+                warn!(
+                    "failed to withdraw the routes via {}: {err:#}",
+                    routes.name()
+                );
+            }
+        }
+    }
+}
+
+async fn run(config: HealthConfig, store: ZoneStore, health: Health, routes: Arc<Vec<Routes>>) {
+    let interval = config
+        .interval_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let unhealthy_after = config.unhealthy_after.unwrap_or(DEFAULT_UNHEALTHY_AFTER);
+    let healthy_after = config.healthy_after.unwrap_or(DEFAULT_HEALTHY_AFTER);
+    let (mut failures, mut successes) = (0, 0);
+    // the state of the routes of each daemon, unknown after a failed command so that it is retried
+    let mut announced: Vec<Option<bool>> = vec![None; routes.len()];
+    loop {
+        match tokio::time::timeout(TIMEOUT, check(&store))
+            .await
+            .context("timed out")
+            .and_then(|res| res)
+        {
+            Ok(()) => {
+                debug!("health check succeeded");
+                successes += 1;
+                failures = 0;
+            }
+            Err(err) => {
+                inc!(Metrics, health_checks_failed);
+                warn!("health check failed: {err:#}");
+                failures += 1;
+                successes = 0;
+            }
+        }
+        if !health.is_healthy() && successes >= healthy_after {
+            info!("server is healthy, announcing the service");
+            health.set(true);
+        } else if health.is_healthy() && failures >= unhealthy_after {
+            warn!("server is unhealthy, withdrawing the service");
+            health.set(false);
+        }
+        let healthy = health.is_healthy();
+        for (routes, announced) in routes.iter().zip(&mut announced) {
+            if *announced == Some(healthy) {
+                continue;
+            }
+            *announced = match routes.set(healthy).await {
+                Ok(()) => Some(healthy),
+                Err(err) => {
+                    warn!("failed to update the routes via {}: {err:#}", routes.name());
+                    None
+                }
+            };
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Check that the store can be read, and that the mainline DHT client is running.
+async fn check(store: &ZoneStore) -> Result<()> {
+    store
+        .packet_count()
+        .await
+        .context("packet store is not readable")?;
+    if store.mainline_enabled() && store.mainline_addr().is_none() {
+        bail!("mainline DHT client is not running");
+    }
+    Ok(())
+}
+
+/// A routing daemon that announces the anycast routes
+#[derive(Debug)]
+enum Routes {
+    Bird(BirdConfig),
+    Exabgp(ExabgpConfig),
+}
+
+impl Routes {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Bird(_) => "BIRD",
+            Self::Exabgp(_) => "ExaBGP",
+        }
+    }
+
+    /// Announce the routes if `announce` is true, or withdraw them.
+    async fn set(&self, announce: bool) -> Result<()> {
+        let res = match self {
+            Self::Bird(config) => {
+                let command = if announce { "enable" } else { "disable" };
+                tokio::time::timeout(
+                    TIMEOUT,
+                    bird_command(config, &format!("{command} {}", config.protocol)),
+                )
+                .await
+            }
+            Self::Exabgp(config) => {
+                let action = if announce { "announce" } else { "withdraw" };
+                let next_hop = config.next_hop.as_deref().unwrap_or("self");
+                let commands: String = config
+                    .routes
+                    .iter()
+                    .map(|route| format!("{action} route {route} next-hop {next_hop}\n"))
+                    .collect();
+                tokio::time::timeout(TIMEOUT, exabgp_commands(config, commands)).await
+            }
+        };
+        res.context("timed out")??;
+        info!(announce, "updated the routes via {}", self.name());
+        Ok(())
+    }
+}
+
+/// Run `command` on the control socket of BIRD.
+#[cfg(unix)]
+async fn bird_command(config: &BirdConfig, command: &str) -> Result<()> {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixStream,
+    };
+
+    let stream = UnixStream::connect(&config.socket)
+        .await
+        .with_context(|| format!("failed to connect to {}", config.socket.display()))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    // the greeting, e.g. `0001 BIRD 2.0.12 ready.`
+    bird_reply(&mut lines).await?;
+    writer.write_all(format!("{command}\n").as_bytes()).await?;
+    let reply = bird_reply(&mut lines).await?;
+    debug!(%command, %reply, "BIRD command succeeded");
+    Ok(())
+}
+
+/// Read a reply of BIRD, and fail if it is an error.
+///
+/// Each line of a reply starts with a four digit code, followed by `-` if more lines follow, or
+/// by a space on the last line. Codes starting with 8 or 9 are errors.
+#[cfg(unix)]
+async fn bird_reply(
+    lines: &mut tokio::io::Lines<tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>>,
+) -> Result<String> {
+    loop {
+        let line = lines
+            .next_line()
+            .await?
+            .context("BIRD closed the connection")?;
+        let (code, rest) = line.split_at(line.len().min(4));
+        if code.len() < 4 || !code.bytes().all(|b| b.is_ascii_digit()) {
+            // a continuation line
+            continue;
+        }
+        if rest.starts_with('-') {
+            continue;
+        }
+        if code.starts_with(['8', '9']) {
+            bail!("BIRD failed: {}", rest.trim());
+        }
+        return Ok(line);
+    }
+}
+
+#[cfg(not(unix))]
+async fn bird_command(_config: &BirdConfig, _command: &str) -> Result<()> {
+    bail!("the BIRD control socket is only supported on unix")
+}
+
+/// Write `commands` to the command pipe of ExaBGP.
+async fn exabgp_commands(config: &ExabgpConfig, commands: String) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    // opening a named pipe for writing waits until ExaBGP opened it for reading
+    let mut pipe = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&config.pipe)
+        .await
+        .with_context(|| format!("failed to open {}", config.pipe.display()))?;
+    pipe.write_all(commands.as_bytes()).await?;
+    pipe.flush().await?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bird_routes() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("iroh-dns-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let socket = dir.join("bird.ctl");
+        let listener = UnixListener::bind(&socket)?;
+        // a BIRD that knows the protocol `anycast` only
+        let bird = tokio::task::spawn(async move {
+            let mut commands = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await?;
+                let (reader, mut writer) = stream.into_split();
+                writer.write_all(b"0001 BIRD 2.0.12 ready.\n").await?;
+                let mut lines = BufReader::new(reader).lines();
+                let command = lines.next_line().await?.unwrap_or_default();
+                let reply: &[u8] = match command.as_str() {
+                    "enable anycast" => b"0011-anycast: enabled\n0000 \n",
+                    _ => b"9001 syntax error\n",
+                };
+                writer.write_all(reply).await?;
+                commands.push(command);
+            }
+            anyhow::Ok(commands)
+        });
+
+        let routes = |protocol: &str| {
+            Routes::Bird(BirdConfig {
+                socket: socket.clone(),
+                protocol: protocol.to_string(),
+            })
+        };
+        routes("anycast").set(true).await?;
+        assert!(routes("other").set(false).await.is_err());
+        assert_eq!(bird.await??, ["enable anycast", "disable other"]);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,400 @@
+//! Retained versions of the packets
+//!
+//! With a [`HistoryConfig`], the server keeps the versions of the packets that replaced each
+//! other, up to `max_versions` per pubkey, and, if `max_age_secs` is set, only those with a
+//! timestamp within the maximum age. The versions are kept in a table of the packet database,
+//! also after the packet itself is removed, until they are too old.
+//!
+//! `GET /admin/history/{key}` returns the retained versions of a pubkey, each with the records
+//! that were added and removed compared to the version before it, to debug nodes whose
+//! announcements flap. For incident forensics, `GET /admin/history/{key}/at?time=` and the
+//! `packet at` command return the version that was current at a time, i.e. the newest version
+//! with a timestamp before it.
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use pkarr::SignedPacket;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{debug, warn};
+use url::Url;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::{inspect::PacketReport, store::ZoneStore, util::PublicKeyBytes};
+
+/// The bytes of the packets by pubkey and timestamp of the packet
+const HISTORY_TABLE: TableDefinition<(&[u8; 32], u64), &[u8]> =
+    TableDefinition::new("packet-history-1");
+
+/// Default number of versions that are kept per pubkey.
+const DEFAULT_MAX_VERSIONS: usize = 10;
+/// Interval in which the versions older than the maximum age are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Config for keeping the previous versions of the packets
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    /// Number of versions that are kept per pubkey, including the current one (defaults to 10).
+    pub max_versions: Option<usize>,
+    /// Remove versions with a timestamp older than this many seconds (kept until they are
+    /// replaced if unset).
+    pub max_age_secs: Option<u64>,
+}
+
+/// A retained version of a packet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct PacketVersion {
+    /// The timestamp of the packet, in microseconds since the unix epoch
+    pub timestamp: u64,
+    /// The records of the packet, in zone file format
+    pub records: Vec<String>,
+    /// The records that are not in the version before, all records for the oldest version
+    pub added: Vec<String>,
+    /// The records of the version before that are not in this version
+    pub removed: Vec<String>,
+}
+
+impl PacketVersion {
+    /// Decode the signed packet `bytes`, with the changes to the `previous` records, and return
+    /// its records too.
+    fn decode(bytes: &[u8], previous: &BTreeSet<String>) -> Result<(Self, BTreeSet<String>)> {
+        let report = PacketReport::decode(bytes)?;
+        let records: Vec<String> = report.records.iter().map(ToString::to_string).collect();
+        let current: BTreeSet<String> = records.iter().cloned().collect();
+        let version = Self {
+            timestamp: report.timestamp,
+            records,
+            added: current.difference(previous).cloned().collect(),
+            removed: previous.difference(&current).cloned().collect(),
+        };
+        Ok((version, current))
+    }
+
+    /// Read the version of the z-base-32 encoded `pubkey` that was current at `time` from the
+    /// packet database at `path`.
+    ///
+    /// This fails if the database is opened by a running server.
+    pub async fn from_store(
+        path: &Path,
+        pubkey: &str,
+        time: OffsetDateTime,
+    ) -> Result<Option<Self>> {
+        let pubkey = PublicKeyBytes::from_z32(pubkey)?;
+        ensure!(path.exists(), "no packet database at {}", path.display());
+        let store = ZoneStore::persistent(path)
+            .context("failed to open the packet database, is the server running?")?;
+        let history = PacketHistory::open(store.database(), &HistoryConfig::default())?;
+        let version = history.at(&pubkey, unix_micros(time));
+        drop(history);
+        store.close().await?;
+        version
+    }
+
+    /// Get the version of the z-base-32 encoded `pubkey` that was current at `time` from the
+    /// admin API of the server at `url`.
+    pub async fn from_server(
+        url: &Url,
+        token: &str,
+        pubkey: &str,
+        time: OffsetDateTime,
+    ) -> Result<Option<Self>> {
+        let pubkey = PublicKeyBytes::from_z32(pubkey)?;
+        let mut url = url.join(&format!("admin/history/{pubkey}/at"))?;
+        url.query_pairs_mut()
+            .append_pair("time", &time.format(&Rfc3339)?);
+        let res = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            // only the error of a server that keeps no history has a detail
+            let error: serde_json::Value = res.json().await?;
+            if let Some(detail) = error["detail"].as_str() {
+                bail!("{detail}");
+            }
+            return Ok(None);
+        }
+        Ok(Some(res.error_for_status()?.json().await?))
+    }
+}
+
+impl fmt::Display for PacketVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = OffsetDateTime::from_unix_timestamp_nanos(self.timestamp as i128 * 1000)
+            .ok()
+            .and_then(|time| time.format(&Rfc3339).ok())
+            .unwrap_or_else(|| "out of range".to_string());
+        writeln!(f, "timestamp: {time} ({})", self.timestamp)?;
+        writeln!(f, "records:   {}", self.records.len())?;
+        for record in &self.records {
+            writeln!(f, "  {record}")?;
+        }
+        for record in &self.added {
+            writeln!(f, "+ {record}")?;
+        }
+        for record in &self.removed {
+            writeln!(f, "- {record}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Microseconds since the unix epoch of `time`, or 0 if it is before the epoch.
+pub(crate) fn unix_micros(time: OffsetDateTime) -> u64 {
+    (time.unix_timestamp_nanos() / 1000).max(0) as u64
+}
+
+/// The retained versions of the packets of a pubkey
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct History {
+    /// The z-base-32 encoded pubkey
+    pub pubkey: String,
+    /// The versions, the oldest first
+    pub versions: Vec<PacketVersion>,
+}
+
+/// The retained versions of the packets, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub(crate) struct PacketHistory {
+    db: Arc<Database>,
+    max_versions: usize,
+    max_age: Option<Duration>,
+}
+
+impl PacketHistory {
+    /// Open the retained versions in `db`.
+    pub(crate) fn open(db: Arc<Database>, config: &HistoryConfig) -> Result<Self> {
+        let max_versions = config.max_versions.unwrap_or(DEFAULT_MAX_VERSIONS);
+        ensure!(max_versions > 0, "history.max_versions must be at least 1");
+        let write_tx = db.begin_write()?;
+        {
+            let _table = write_tx.open_table(HISTORY_TABLE)?;
+        }
+        write_tx.commit()?;
+        Ok(Self {
+            db,
+            max_versions,
+            max_age: config.max_age_secs.map(Duration::from_secs),
+        })
+    }
+
+    /// Add `packet` as the newest version of its pubkey, and remove the versions beyond the
+    /// limits.
+    pub(crate) fn record(&self, packet: &SignedPacket) -> Result<()> {
+        let pubkey = PublicKeyBytes::from_signed_packet(packet);
+        let min_timestamp = self.min_timestamp();
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(HISTORY_TABLE)?;
+            table.insert(
+                (pubkey.as_bytes(), packet.timestamp()),
+                packet.as_bytes().as_ref(),
+            )?;
+            let mut outdated = Vec::new();
+            let versions = table.range((pubkey.as_bytes(), 0)..=(pubkey.as_bytes(), u64::MAX))?;
+            // newest first, so the versions beyond the limit are the last ones
+            for (index, row) in versions.rev().enumerate() {
+                let (_, timestamp) = row?.0.value();
+                if index >= self.max_versions || timestamp < min_timestamp {
+                    outdated.push(timestamp);
+                }
+            }
+            for timestamp in outdated {
+                table.remove((pubkey.as_bytes(), timestamp))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Get the retained packets of `pubkey`, the oldest first.
+    pub(crate) fn packets(&self, pubkey: &PublicKeyBytes) -> Result<Vec<Vec<u8>>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(HISTORY_TABLE)?;
+        let mut packets = Vec::new();
+        for row in table.range((pubkey.as_bytes(), 0)..=(pubkey.as_bytes(), u64::MAX))? {
+            packets.push(row?.1.value().to_vec());
+        }
+        Ok(packets)
+    }
+
+    /// Get the retained versions of `pubkey`, with the changes between them.
+    pub(crate) fn history(&self, pubkey: &PublicKeyBytes) -> Result<History> {
+        let mut versions: Vec<PacketVersion> = Vec::new();
+        let mut previous = BTreeSet::new();
+        for bytes in self.packets(pubkey)? {
+            let (version, records) = PacketVersion::decode(&bytes, &previous)?;
+            versions.push(version);
+            previous = records;
+        }
+        Ok(History {
+            pubkey: pubkey.to_z32(),
+            versions,
+        })
+    }
+
+    /// Get the version of `pubkey` that was current at `timestamp`, in microseconds since the
+    /// unix epoch, with the changes to the version before it.
+    ///
+    /// Returns `None` if the oldest retained version is newer.
+    pub(crate) fn at(
+        &self,
+        pubkey: &PublicKeyBytes,
+        timestamp: u64,
+    ) -> Result<Option<PacketVersion>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(HISTORY_TABLE)?;
+        let mut versions = table
+            .range((pubkey.as_bytes(), 0)..=(pubkey.as_bytes(), timestamp))?
+            .rev();
+        let Some(row) = versions.next() else {
+            return Ok(None);
+        };
+        let bytes = row?.1.value().to_vec();
+        let previous = match versions.next() {
+            Some(row) => PacketVersion::decode(row?.1.value(), &BTreeSet::new())?.1,
+            None => BTreeSet::new(),
+        };
+        Ok(Some(PacketVersion::decode(&bytes, &previous)?.0))
+    }
+
+    /// Remove the versions older than the maximum age in an interval, until the task is aborted.
+    ///
+    /// Returns immediately if there is no maximum age.
+    pub(crate) async fn run(self) {
+        if self.max_age.is_none() {
+            return;
+        }
+        loop {
+            tokio::time::sleep(PRUNE_INTERVAL).await;
+            let this = self.clone();
+            match tokio::task::spawn_blocking(move || this.prune()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("failed to remove old packet versions: {err:#}"),
+                Err(err) => warn!("failed to remove old packet versions: {err}"),
+            }
+        }
+    }
+
+    /// Remove the versions older than the maximum age.
+    fn prune(&self) -> Result<()> {
+        let min_timestamp = self.min_timestamp();
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(HISTORY_TABLE)?;
+            table.retain(|(_, timestamp), _| timestamp >= min_timestamp)?;
+        }
+        tx.commit()?;
+        debug!("removed the old packet versions");
+        Ok(())
+    }
+
+    /// The oldest timestamp of a version that is kept, in microseconds since the unix epoch.
+    fn min_timestamp(&self) -> u64 {
+        let Some(max_age) = self.max_age else {
+            return 0;
+        };
+        SystemTime::now()
+            .checked_sub(max_age)
+            .and_then(|since| since.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default()
+            .as_micros() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pkarr::{
+        dns::{rdata::TXT, Name, Packet, ResourceRecord, CLASS},
+        Keypair,
+    };
+    use redb::backends::InMemoryBackend;
+
+    use super::*;
+
+    fn packet(keypair: &Keypair, txts: &[&str]) -> Result<SignedPacket> {
+        let mut packet = Packet::new_reply(0);
+        for txt in txts {
+            packet.answers.push(ResourceRecord::new(
+                Name::new("_iroh").unwrap(),
+                CLASS::IN,
+                30,
+                pkarr::dns::rdata::RData::TXT(TXT::new().with_string(txt)?),
+            ));
+        }
+        // the versions are keyed by their timestamp in microseconds
+        std::thread::sleep(Duration::from_millis(1));
+        Ok(SignedPacket::from_packet(keypair, &packet)?)
+    }
+
+    #[test]
+    fn versions_with_diffs() -> Result<()> {
+        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        let config = HistoryConfig {
+            max_versions: Some(3),
+            max_age_secs: None,
+        };
+        let history = PacketHistory::open(Arc::new(db), &config)?;
+        let keypair = Keypair::random();
+        let pubkey = PublicKeyBytes::from(keypair.public_key());
+        history.record(&packet(&keypair, &["relay=a"])?)?;
+        history.record(&packet(&keypair, &["relay=a", "addr=1"])?)?;
+        history.record(&packet(&keypair, &["relay=b", "addr=1"])?)?;
+        history.record(&packet(&keypair, &["relay=a", "addr=1"])?)?;
+
+        let versions = history.history(&pubkey)?.versions;
+        assert_eq!(versions.len(), 3);
+        assert!(versions.windows(2).all(|v| v[0].timestamp < v[1].timestamp));
+        // the oldest retained version has all records as added
+        assert_eq!(versions[0].added.len(), 2);
+        assert!(versions[0].removed.is_empty());
+        assert_eq!(versions[1].added.len(), 1);
+        assert!(versions[1].added[0].contains("relay=b"));
+        assert_eq!(versions[1].removed.len(), 1);
+        assert!(versions[1].removed[0].contains("relay=a"));
+        assert!(versions[2].added[0].contains("relay=a"));
+        assert!(versions[2].removed[0].contains("relay=b"));
+        assert_eq!(versions[2].records.len(), 2);
+
+        let other = PublicKeyBytes::from(Keypair::random().public_key());
+        assert!(history.history(&other)?.versions.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn version_at() -> Result<()> {
+        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        let history = PacketHistory::open(Arc::new(db), &HistoryConfig::default())?;
+        let keypair = Keypair::random();
+        let pubkey = PublicKeyBytes::from(keypair.public_key());
+        let first = packet(&keypair, &["relay=a"])?;
+        let second = packet(&keypair, &["relay=b"])?;
+        history.record(&first)?;
+        history.record(&second)?;
+
+        assert!(history.at(&pubkey, first.timestamp() - 1)?.is_none());
+        let version = history.at(&pubkey, first.timestamp())?.unwrap();
+        assert_eq!(version.timestamp, first.timestamp());
+        assert_eq!(version.added.len(), 1);
+        // between the versions, the first one was current
+        let version = history.at(&pubkey, second.timestamp() - 1)?.unwrap();
+        assert_eq!(version.timestamp, first.timestamp());
+        let version = history.at(&pubkey, u64::MAX)?.unwrap();
+        assert_eq!(version.timestamp, second.timestamp());
+        assert!(version.added[0].contains("relay=b"));
+        assert!(version.removed[0].contains("relay=a"));
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+//! Hourly statistics of the queries and publishes
+//!
+//! With an [`HourlyStatsConfig`], the server counts the DNS queries by transport, the answered
+//! pkarr names, the packets served by `GET /pkarr` and the publishes by outcome per hour. The
+//! counts are collected in memory, and added to a table of the packet database in the data
+//! directory in an interval, so that small operators have a history without running Prometheus.
+//! Hours older than the retention are removed. `GET /admin/hourly-stats` returns the counts of a
+//! time range.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Result};
+use hickory_server::server::Protocol;
+use parking_lot::Mutex;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::{debug, warn};
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+/// The counts by hour since the unix epoch and counter
+const HOURLY_STATS_TABLE: TableDefinition<(u32, &str), u64> =
+    TableDefinition::new("hourly-stats-1");
+
+/// Default number of days the counts are kept.
+const DEFAULT_RETENTION_DAYS: u32 = 90;
+/// Default interval in seconds in which the counts are written to the database.
+const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 60;
+const SECS_PER_HOUR: u64 = 60 * 60;
+
+/// Config for the hourly statistics
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HourlyStatsConfig {
+    /// Number of days the counts are kept (defaults to 90).
+    pub retention_days: Option<u32>,
+    /// Interval in seconds in which the counts are written to the database (defaults to 60).
+    pub flush_interval_secs: Option<u64>,
+}
+
+/// A counter of the hourly statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum Counter {
+    /// DNS queries over UDP
+    QueriesUdp,
+    /// DNS queries over TCP
+    QueriesTcp,
+    /// DNS-over-HTTPS queries
+    QueriesDoh,
+    /// DNS queries for pkarr names that were answered
+    PkarrAnswers,
+    /// Packets served by `GET /pkarr/{key}`
+    PkarrGets,
+    /// Publishes that updated the store
+    PublishUpdates,
+    /// Publishes of packets that were already stored
+    PublishNoops,
+    /// Rejected or failed publishes
+    PublishErrors,
+}
+
+impl Counter {
+    /// The counter of the DNS queries over `protocol`.
+    pub(crate) fn queries(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Udp => Self::QueriesUdp,
+            Protocol::Https => Self::QueriesDoh,
+            _ => Self::QueriesTcp,
+        }
+    }
+}
+
+/// The counts of one hour
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct HourStats {
+    /// The start of the hour, in RFC 3339 format
+    pub start: String,
+    /// The counts by counter, e.g. `queries_udp` or `publish_updates`; counters without events
+    /// are left out
+    pub counts: BTreeMap<String, u64>,
+}
+
+/// The counts of a time range
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct HourlyStatsReport {
+    /// The counts per hour, the oldest hour first
+    pub hours: Vec<HourStats>,
+    /// The counts over all hours
+    pub totals: BTreeMap<String, u64>,
+}
+
+/// Counts by hour since the unix epoch and counter
+type Counts = HashMap<(u32, Counter), u64>;
+
+/// The hourly statistics, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub(crate) struct HourlyStats {
+    db: Arc<Database>,
+    /// The counts that are not yet in the database
+    pending: Arc<Mutex<Counts>>,
+    retention_hours: u32,
+    flush_interval: Duration,
+}
+
+impl HourlyStats {
+    /// Open the hourly statistics in `db`.
+    pub(crate) fn open(db: Arc<Database>, config: &HourlyStatsConfig) -> Result<Self> {
+        let retention_days = config.retention_days.unwrap_or(DEFAULT_RETENTION_DAYS);
+        ensure!(
+            retention_days > 0,
+            "hourly_stats.retention_days must be at least 1"
+        );
+        let write_tx = db.begin_write()?;
+        {
+            let _table = write_tx.open_table(HOURLY_STATS_TABLE)?;
+        }
+        write_tx.commit()?;
+        Ok(Self {
+            db,
+            pending: Default::default(),
+            retention_hours: retention_days * 24,
+            flush_interval: Duration::from_secs(
+                config
+                    .flush_interval_secs
+                    .unwrap_or(DEFAULT_FLUSH_INTERVAL_SECS),
+            ),
+        })
+    }
+
+    /// Count an event of `counter` in the current hour.
+    pub(crate) fn record(&self, counter: Counter) {
+        *self
+            .pending
+            .lock()
+            .entry((current_hour(), counter))
+            .or_default() += 1;
+    }
+
+    /// Write the counts to the database in the flush interval, until the task is aborted.
+    pub(crate) async fn run(self) {
+        loop {
+            tokio::time::sleep(self.flush_interval).await;
+            let this = self.clone();
+            match tokio::task::spawn_blocking(move || this.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("failed to write the hourly statistics: {err:#}"),
+                Err(err) => warn!("failed to write the hourly statistics: {err}"),
+            }
+        }
+    }
+
+    /// Add the pending counts to the database, and remove the hours older than the retention.
+    pub(crate) fn flush(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let oldest = self.oldest_hour();
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(HOURLY_STATS_TABLE)?;
+            for ((hour, counter), count) in &pending {
+                let key = (*hour, <&'static str>::from(*counter));
+                let stored = table.get(key)?.map(|v| v.value()).unwrap_or(0);
+                table.insert(key, stored + count)?;
+            }
+            table.retain(|(hour, _), _| hour >= oldest)?;
+        }
+        tx.commit()?;
+        debug!(entries = pending.len(), "wrote the hourly statistics");
+        Ok(())
+    }
+
+    /// Get the counts of the hours from `from` to `to`, including the hours of both.
+    ///
+    /// The range is limited to the retention, and the counts of the current flush interval are
+    /// included.
+    pub(crate) fn get(&self, from: SystemTime, to: SystemTime) -> Result<HourlyStatsReport> {
+        ensure!(
+            from <= to,
+            "the start of the range must not be after its end"
+        );
+        let from = hour(from).max(self.oldest_hour());
+        let to = hour(to);
+        if from > to {
+            // the range is older than the retention
+            return Ok(HourlyStatsReport {
+                hours: Vec::new(),
+                totals: BTreeMap::new(),
+            });
+        }
+        let mut counts: BTreeMap<u32, BTreeMap<String, u64>> =
+            (from..=to).map(|hour| (hour, BTreeMap::new())).collect();
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(HOURLY_STATS_TABLE)?;
+        for row in table.range((from, "")..(to.saturating_add(1), ""))? {
+            let (key, count) = row?;
+            let (hour, counter) = key.value();
+            if let Some(counters) = counts.get_mut(&hour) {
+                *counters.entry(counter.to_string()).or_default() += count.value();
+            }
+        }
+        for ((hour, counter), count) in self.pending.lock().iter() {
+            if let Some(counters) = counts.get_mut(hour) {
+                let counter = <&'static str>::from(*counter).to_string();
+                *counters.entry(counter).or_default() += count;
+            }
+        }
+        let mut totals = BTreeMap::new();
+        let hours = counts
+            .into_iter()
+            .map(|(hour, counts)| {
+                for (counter, count) in &counts {
+                    *totals.entry(counter.clone()).or_default() += count;
+                }
+                HourStats {
+                    start: start_of(hour),
+                    counts,
+                }
+            })
+            .collect();
+        Ok(HourlyStatsReport { hours, totals })
+    }
+
+    /// The oldest hour that is kept.
+    fn oldest_hour(&self) -> u32 {
+        current_hour().saturating_sub(self.retention_hours - 1)
+    }
+}
+
+/// The hour of `time`, in hours since the unix epoch.
+fn hour(time: SystemTime) -> u32 {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (secs / SECS_PER_HOUR) as u32
+}
+
+/// The current hour, in hours since the unix epoch.
+fn current_hour() -> u32 {
+    hour(SystemTime::now())
+}
+
+/// Format the start of an hour since the unix epoch in RFC 3339 format.
+fn start_of(hour: u32) -> String {
+    OffsetDateTime::from_unix_timestamp(hour as i64 * SECS_PER_HOUR as i64)
+        .ok()
+        .and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_by_hour() -> Result<()> {
+        let db = Arc::new(
+            Database::builder().create_with_backend(redb::backends::InMemoryBackend::new())?,
+        );
+        let stats = HourlyStats::open(db.clone(), &HourlyStatsConfig::default())?;
+        stats.record(Counter::QueriesUdp);
+        stats.record(Counter::QueriesUdp);
+        stats.flush()?;
+        stats.record(Counter::QueriesUdp);
+        stats.record(Counter::PublishUpdates);
+
+        let now = SystemTime::now();
+        let report = stats.get(now - Duration::from_secs(2 * SECS_PER_HOUR), now)?;
+        assert_eq!(report.hours.len(), 3);
+        assert!(report.hours[0].counts.is_empty());
+        assert_eq!(report.totals["queries_udp"], 3);
+        assert_eq!(report.totals["publish_updates"], 1);
+
+        // the counts are kept in the database
+        stats.flush()?;
+        let reopened = HourlyStats::open(db, &HourlyStatsConfig::default())?;
+        assert_eq!(reopened.get(now, now)?.totals["queries_udp"], 3);
+        assert!(stats.get(now, now - Duration::from_secs(1)).is_err());
+        Ok(())
+    }
+}
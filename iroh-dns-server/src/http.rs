@@ -1,51 +1,134 @@
 //! HTTP server part of iroh-dns-server
 
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    time::Instant,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context, Result};
+#[cfg(feature = "https")]
+use axum::http::header;
 use axum::{
-    extract::{ConnectInfo, Request},
-    handler::Handler,
-    http::Method,
+    extract::{ConnectInfo, Request, State},
+    http::{Method, StatusCode},
     middleware::{self, Next},
     response::IntoResponse,
-    routing::get,
+    routing::{get, post, put, MethodRouter},
     Router,
 };
+use axum_server::accept::DefaultAcceptor;
 use iroh_metrics::{inc, inc_by};
 use serde::{Deserialize, Serialize};
-use tokio::{net::TcpListener, task::JoinSet};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "https")]
+use tower_http::set_header::SetResponseHeaderLayer;
 use tower_http::{
     cors::{self, CorsLayer},
+    timeout::TimeoutLayer,
     trace::TraceLayer,
 };
-use tracing::{info, span, warn, Level};
+use tracing::{info, span, warn, Level, Span};
 
+mod access_log;
+mod acl;
+mod admin;
+mod analytics;
+mod auth;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod compression;
 mod doh;
 mod error;
+mod forwarded;
+#[cfg(feature = "https")]
+mod http3;
+mod limits;
+mod onion;
+#[cfg(feature = "openapi")]
+mod openapi;
 mod pkarr;
-mod rate_limiting;
+pub(crate) mod rate_limiting;
+mod sync;
 mod tls;
+#[cfg(unix)]
+pub(crate) mod unix;
+mod validate;
 
-use crate::state::AppState;
-use crate::{config::Config, metrics::Metrics};
+use self::limits::LimitsAcceptor;
+use crate::state::{AppState, TaskErrors};
+use crate::{
+    acl::Operation,
+    api_keys::ApiKeyScope,
+    config::{BindAddr, IpStack},
+    metrics::Metrics,
+    proxy_protocol::ConnectInfoAcceptor,
+    secrets::SecretSource,
+    server::Shutdown,
+    telemetry, util,
+};
+#[cfg(feature = "https")]
+use crate::{dns::AcmeChallenges, events::ServerEvents};
 
-pub use self::rate_limiting::RateLimitConfig;
-pub use self::tls::CertMode;
+pub use self::access_log::AccessLogConfig;
+pub use self::admin::AdminConfig;
+pub(crate) use self::auth::AuthProviders;
+pub use self::auth::{
+    ApiKeyAuth, AuthDecision, AuthProvider, AuthRequest, ClientCertAuth, TokenAuth,
+};
+pub use self::compression::CompressionConfig;
+pub use self::forwarded::BehindProxyConfig;
+pub use self::limits::HttpLimitsConfig;
+pub use self::onion::OnionServiceConfig;
+use self::rate_limiting::RateLimitClass;
+pub use self::rate_limiting::{RateLimitConfig, RateLimitMode, RateLimitQuota};
+#[cfg(feature = "https")]
+pub(crate) use self::tls::{served_cert_not_after, EXPIRY_ERROR_BEFORE, EXPIRY_WARN_BEFORE};
+pub use self::tls::{
+    AcmeAccountInfo, AcmeDirectoryConfig, CertConfig, CertMode, ClientAuthConfig,
+    ExternalAccountBinding, TlsConfig, TlsVersion,
+};
+pub(crate) use self::validate::PacketValidators;
+pub use self::validate::{validator_fn, PacketRejected, PacketValidator, Validation};
 
 /// Config for the HTTP server
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct HttpConfig {
     /// Port to bind to
-    pub port: u16,
-    /// Optionally set a custom bind address (will use 0.0.0.0 if unset)
-    pub bind_addr: Option<IpAddr>,
+    ///
+    /// If unset, no TCP listener is started, which is only useful together with
+    /// [`Self::unix_socket`].
+    pub port: Option<u16>,
+    /// Optionally set custom bind addresses (will use 0.0.0.0 if unset)
+    ///
+    /// Can be set to a single address or a list of addresses, to bind to specific IPv4 and IPv6
+    /// addresses, or to the name of a network interface, see [`BindAddr`]. A listener is bound
+    /// for each address.
+    #[serde(default, deserialize_with = "util::one_or_many")]
+    pub bind_addr: Vec<BindAddr>,
+    /// Optionally also serve HTTP on a unix domain socket at this path (unix only)
+    pub unix_socket: Option<PathBuf>,
+    /// Optionally also serve HTTP as a Tor onion service (requires the `tor` feature)
+    pub onion_service: Option<OnionServiceConfig>,
     /// Config for http rate limit
     #[serde(default)]
     pub rate_limit: RateLimitConfig,
+    /// Config for the rate limit of DNS-over-HTTPS queries
+    ///
+    /// DNS-over-HTTPS queries have their own budget, which is much larger than the budget for
+    /// pkarr publishes. If unset, DNS-over-HTTPS queries are not rate limited.
+    #[serde(default)]
+    pub doh_rate_limit: Option<RateLimitConfig>,
+    /// Whether connections start with a PROXY protocol (v1 or v2) header.
+    ///
+    /// Enable this if the server is behind a layer 4 load balancer, so that the client address
+    /// from the PROXY header is used for rate limiting and logs. If enabled, connections without
+    /// a PROXY header are rejected.
+    #[serde(default)]
+    pub proxy_protocol: bool,
 }
 
 /// Config for the HTTPS server
@@ -53,153 +136,677 @@ pub struct HttpConfig {
 pub struct HttpsConfig {
     /// Port to bind to
     pub port: u16,
-    /// Optionally set a custom bind address (will use 0.0.0.0 if unset)
-    pub bind_addr: Option<IpAddr>,
+    /// Optionally set custom bind addresses (will use 0.0.0.0 if unset)
+    ///
+    /// See [`HttpConfig::bind_addr`].
+    #[serde(default, deserialize_with = "util::one_or_many")]
+    pub bind_addr: Vec<BindAddr>,
     /// The list of domains for which SSL certificates should be created.
+    ///
+    /// See [`CertConfig::domains`].
     pub domains: Vec<String>,
     /// The mode of SSL certificate creation
     pub cert_mode: CertMode,
-    /// Letsencrypt contact email address (required if using [`CertMode::LetsEncrypt`])
+    /// Letsencrypt contact email address (required if using [`CertMode::LetsEncrypt`] or
+    /// [`CertMode::LetsEncryptDns`])
     pub letsencrypt_contact: Option<String>,
-    /// Whether to use the letsenrypt production servers (only applies to [`CertMode::LetsEncrypt`])
+    /// Whether to use the letsenrypt production servers (only applies to
+    /// [`CertMode::LetsEncrypt`] and [`CertMode::LetsEncryptDns`])
     pub letsencrypt_prod: Option<bool>,
+    /// Use a custom ACME server instead of LetsEncrypt (only applies to
+    /// [`CertMode::LetsEncrypt`] and [`CertMode::LetsEncryptDns`])
+    #[serde(default)]
+    pub acme: Option<AcmeDirectoryConfig>,
+    /// Path to the PEM certificate chain file (only applies to [`CertMode::Manual`])
+    ///
+    /// The certificate is reloaded when the file changes.
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    /// Path to the PEM secret key file (only applies to [`CertMode::Manual`])
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    /// Load the PEM certificate chain from a secret instead of `cert_path`
+    ///
+    /// The certificate is reloaded when the secret changes.
+    #[serde(default)]
+    pub cert_secret: Option<SecretSource>,
+    /// Load the PEM secret key from a secret instead of `key_path`
+    #[serde(default)]
+    pub key_secret: Option<SecretSource>,
+    /// Load the ACME account key (PKCS#8 PEM) from a secret instead of the `cert_cache` path
+    ///
+    /// The account must already be registered with the CA if it requires external account
+    /// binding.
+    #[serde(default)]
+    pub acme_account_key: Option<SecretSource>,
+    /// Additional certificates, selected by the server name (SNI) sent by the client
+    ///
+    /// The certificate configured above is the default, used for clients whose server name
+    /// doesn't match any of the additional certificates.
+    #[serde(default)]
+    pub certificates: Vec<CertConfig>,
     /// Config for https rate limit
     pub rate_limit: Option<RateLimitConfig>,
+    /// Config for the rate limit of DNS-over-HTTPS queries
+    ///
+    /// See [`HttpConfig::doh_rate_limit`].
+    #[serde(default)]
+    pub doh_rate_limit: Option<RateLimitConfig>,
+    /// Whether to also serve HTTP/3 on the same port (over UDP).
+    ///
+    /// If enabled, HTTPS responses advertise the HTTP/3 endpoint with an `Alt-Svc` header.
+    #[serde(default)]
+    pub http3: bool,
+    /// Whether connections start with a PROXY protocol (v1 or v2) header.
+    ///
+    /// See [`HttpConfig::proxy_protocol`]. Does not apply to HTTP/3.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Whether to staple OCSP responses to the certificates
+    ///
+    /// The responses are fetched from the OCSP responder listed in the certificates, and
+    /// refreshed in the background. Does not apply to self signed certificates.
+    #[serde(default)]
+    pub ocsp_stapling: bool,
+    /// Config for the TLS protocol versions, cipher suites and ALPN protocols
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Config for TLS client certificate authentication
+    ///
+    /// If set, clients that present a verified certificate may access the admin endpoints
+    /// under `/admin`.
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+impl HttpsConfig {
+    /// Get all certificates, the default certificate first.
+    pub fn certificates(&self) -> Vec<CertConfig> {
+        let default = CertConfig {
+            domains: self.domains.clone(),
+            cert_mode: self.cert_mode.clone(),
+            letsencrypt_contact: self.letsencrypt_contact.clone(),
+            letsencrypt_prod: self.letsencrypt_prod,
+            acme: self.acme.clone(),
+            cert_path: self.cert_path.clone(),
+            key_path: self.key_path.clone(),
+            cert_secret: self.cert_secret.clone(),
+            key_secret: self.key_secret.clone(),
+            acme_account_key: self.acme_account_key.clone(),
+        };
+        std::iter::once(default)
+            .chain(self.certificates.iter().cloned())
+            .collect()
+    }
 }
 
 /// The HTTP(S) server part of iroh-dns-server
 pub struct HttpServer {
     tasks: JoinSet<std::io::Result<()>>,
-    http_addr: Option<SocketAddr>,
-    https_addr: Option<SocketAddr>,
+    /// Handle for graceful shutdown of the HTTP and HTTPS servers
+    handle: axum_server::Handle,
+    /// Cancels the unix socket and HTTP/3 servers
+    cancel: CancellationToken,
+    http_addrs: Vec<SocketAddr>,
+    https_addrs: Vec<SocketAddr>,
+    admin_addrs: Vec<SocketAddr>,
 }
 
 impl HttpServer {
     /// Spawn the server
+    ///
+    /// Listeners without configured bind addresses are bound to the wildcard addresses of
+    /// `ip_stack`. With a `tls_config`, the HTTPS server uses it instead of the certificates of
+    /// `https_config`. With the bind addresses of `admin_config`, the admin endpoints are served on
+    /// listeners of their own.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(not(feature = "https"), allow(unused_variables))]
     pub async fn spawn(
         http_config: Option<HttpConfig>,
         https_config: Option<HttpsConfig>,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+        access_log_config: Option<AccessLogConfig>,
+        compression_config: Option<CompressionConfig>,
+        limits_config: Option<HttpLimitsConfig>,
+        max_connections: Option<usize>,
+        behind_proxy_config: Option<BehindProxyConfig>,
+        admin_config: Option<AdminConfig>,
+        ip_stack: IpStack,
+        data_dir: &Path,
         state: AppState,
     ) -> Result<HttpServer> {
+        let limits = limits_config.unwrap_or_default();
+        // shared by the acceptors of all listeners
+        let total_connections = max_connections.map(|max| Arc::new(Semaphore::new(max)));
         if http_config.is_none() && https_config.is_none() {
             bail!("Either http or https config is required");
         }
 
-        let app = create_app(
+        #[cfg(not(feature = "https"))]
+        anyhow::ensure!(
+            https_config.is_none() && tls_config.is_none(),
+            "the HTTPS server requires the `https` feature"
+        );
+        // the TLS acceptor is created first, because the admin endpoints show the cert status
+        #[cfg(feature = "https")]
+        let tls = match (&https_config, tls_config) {
+            (Some(_), Some(tls_config)) => {
+                info!("HTTPS server uses the provided TLS config");
+                Some((tls::TlsAcceptor::from_server_config(tls_config), Vec::new()))
+            }
+            (Some(config), None) => {
+                let acme_challenges = state.dns_handler.acme_challenges().clone();
+                let events = state.store.events().clone();
+                Some(create_tls_acceptor(config, data_dir, acme_challenges, events).await?)
+            }
+            (None, Some(_)) => bail!("a TLS config requires an https config"),
+            (None, None) => None,
+        };
+        #[cfg(feature = "https")]
+        let cert_status = tls
+            .as_ref()
+            .map(|(_, status)| status.clone())
+            .unwrap_or_default();
+        #[cfg(not(feature = "https"))]
+        let cert_status = Vec::new();
+
+        let bound_addrs = state.bound_addrs.clone();
+        let task_errors = state.task_errors.clone();
+        let ip_reputation = state.ip_reputation.clone();
+        // cancelled on shutdown, which also ends the streams of the admin endpoints
+        let cancel = CancellationToken::new();
+        let admin_bind_addrs = admin_config.map(|c| c.bind_addr).unwrap_or_default();
+        let (app, admin_app) = create_app(
             state,
             https_config
                 .as_ref()
                 .and_then(|h| h.rate_limit.as_ref())
                 .or_else(|| http_config.as_ref().map(|h| &h.rate_limit))
                 .unwrap_or_default(),
-        );
+            https_config
+                .as_ref()
+                .and_then(|h| h.doh_rate_limit.as_ref())
+                .or_else(|| http_config.as_ref().and_then(|h| h.doh_rate_limit.as_ref())),
+            access_log_config.as_ref(),
+            compression_config.as_ref(),
+            behind_proxy_config,
+            https_config.as_ref().and_then(|h| h.client_auth.as_ref()),
+            cert_status,
+            limits.request_timeout(),
+            !admin_bind_addrs.is_empty(),
+            cancel.clone(),
+        )?;
 
         let mut tasks = JoinSet::new();
+        let handle = axum_server::Handle::new();
 
         // launch http
-        let http_addr = if let Some(config) = http_config {
-            let bind_addr = SocketAddr::new(
-                config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
-                config.port,
-            );
-            let app = app.clone();
-            let listener = TcpListener::bind(bind_addr).await?.into_std()?;
-            let bound_addr = listener.local_addr()?;
-            let fut = axum_server::from_tcp(listener)
-                .serve(app.into_make_service_with_connect_info::<SocketAddr>());
-            info!("HTTP server listening on {bind_addr}");
-            tasks.spawn(fut);
-            Some(bound_addr)
-        } else {
-            None
-        };
+        let mut http_addrs = Vec::new();
+        if let Some(config) = http_config {
+            for bind_addr in util::socket_addrs("http", &config.bind_addr, config.port, ip_stack)? {
+                let app = app.clone();
+                let listener = bind(bind_addr)?;
+                let bound_addr = listener.local_addr()?;
+                let mut server = axum_server::from_tcp(listener)
+                    .handle(handle.clone())
+                    .acceptor(LimitsAcceptor::new(
+                        ConnectInfoAcceptor::new(
+                            DefaultAcceptor::new(),
+                            config.proxy_protocol,
+                            ip_reputation.clone(),
+                        ),
+                        &limits,
+                        total_connections.clone(),
+                    ));
+                limits::configure_server(&mut server, &limits);
+                let fut = server.serve(app.into_make_service());
+                info!("HTTP server listening on {bound_addr}");
+                bound_addrs.add("http", bound_addr);
+                tasks.spawn(report_error(task_errors.clone(), "http", fut));
+                http_addrs.push(bound_addr);
+            }
+            if let Some(path) = config.unix_socket {
+                #[cfg(unix)]
+                tasks.spawn(report_error(
+                    task_errors.clone(),
+                    "http",
+                    unix::serve(unix::bind(&path)?, app.clone(), cancel.clone()),
+                ));
+                #[cfg(not(unix))]
+                bail!("unix sockets are not supported on this platform: {path:?}");
+            }
+            if let Some(onion_config) = config.onion_service {
+                #[cfg(feature = "tor")]
+                {
+                    let serve = onion::serve(
+                        onion_config,
+                        data_dir.to_path_buf(),
+                        app.clone(),
+                        cancel.clone(),
+                    );
+                    tasks.spawn(report_error(task_errors.clone(), "onion", async move {
+                        serve
+                            .await
+                            .map_err(|err| std::io::Error::other(format!("{err:#}")))
+                    }));
+                }
+                #[cfg(not(feature = "tor"))]
+                bail!("the onion service requires the `tor` feature: {onion_config:?}");
+            }
+        }
 
         // launch https
-        let https_addr = if let Some(config) = https_config {
-            let bind_addr = SocketAddr::new(
-                config.bind_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
-                config.port,
-            );
-            let acceptor = {
-                let cache_path = Config::data_dir()?
-                    .join("cert_cache")
-                    .join(config.cert_mode.to_string());
-                tokio::fs::create_dir_all(&cache_path)
-                    .await
-                    .with_context(|| {
-                        format!("failed to create cert cache dir at {cache_path:?}")
-                    })?;
-                config
-                    .cert_mode
-                    .build(
-                        config.domains,
-                        cache_path,
-                        config.letsencrypt_contact,
-                        config.letsencrypt_prod.unwrap_or(false),
-                    )
-                    .await?
-            };
-            let listener = TcpListener::bind(bind_addr).await?.into_std()?;
-            let bound_addr = listener.local_addr()?;
-            let fut = axum_server::from_tcp(listener)
-                .acceptor(acceptor)
-                .serve(app.into_make_service_with_connect_info::<SocketAddr>());
-            info!("HTTPS server listening on {bind_addr}");
-            tasks.spawn(fut);
-            Some(bound_addr)
-        } else {
-            None
-        };
+        #[allow(unused_mut)]
+        let mut https_addrs = Vec::new();
+        #[cfg(feature = "https")]
+        if let (Some(config), Some((acceptor, _))) = (https_config, tls) {
+            for bind_addr in
+                util::socket_addrs("https", &config.bind_addr, Some(config.port), ip_stack)?
+            {
+                let listener = bind(bind_addr)?;
+                let bound_addr = listener.local_addr()?;
+                let app = if config.http3 {
+                    let endpoint = http3::bind(bound_addr, acceptor.server_config(), &limits)?;
+                    tasks.spawn(report_error(
+                        task_errors.clone(),
+                        "https",
+                        http3::serve(
+                            endpoint,
+                            app.clone(),
+                            ip_reputation.clone(),
+                            limits.clone(),
+                            total_connections.clone(),
+                            cancel.clone(),
+                        ),
+                    ));
+                    app.clone().layer(SetResponseHeaderLayer::if_not_present(
+                        header::ALT_SVC,
+                        http3::alt_svc_header(bound_addr.port()),
+                    ))
+                } else {
+                    app.clone()
+                };
+                let mut server = axum_server::from_tcp(listener)
+                    .handle(handle.clone())
+                    .acceptor(LimitsAcceptor::new(
+                        ConnectInfoAcceptor::new(
+                            acceptor.clone(),
+                            config.proxy_protocol,
+                            ip_reputation.clone(),
+                        ),
+                        &limits,
+                        total_connections.clone(),
+                    ));
+                limits::configure_server(&mut server, &limits);
+                let fut = server.serve(app.into_make_service());
+                info!("HTTPS server listening on {bound_addr}");
+                bound_addrs.add("https", bound_addr);
+                tasks.spawn(report_error(task_errors.clone(), "https", fut));
+                https_addrs.push(bound_addr);
+            }
+        }
+
+        // launch the admin listeners, which are not counted in the total connections, so that
+        // admins can still connect to an overloaded server
+        let mut admin_addrs = Vec::new();
+        if let Some(app) = admin_app {
+            for bind_addr in admin_bind_addrs {
+                if !bind_addr.ip().is_loopback() {
+                    warn!("admin endpoints are served without authorization on {bind_addr}");
+                }
+                let listener = bind(bind_addr)?;
+                let bound_addr = listener.local_addr()?;
+                let mut server = axum_server::from_tcp(listener)
+                    .handle(handle.clone())
+                    .acceptor(LimitsAcceptor::new(
+                        ConnectInfoAcceptor::new(
+                            DefaultAcceptor::new(),
+                            false,
+                            ip_reputation.clone(),
+                        ),
+                        &limits,
+                        None,
+                    ));
+                limits::configure_server(&mut server, &limits);
+                let fut = server.serve(app.clone().into_make_service());
+                info!("admin server listening on {bound_addr}");
+                bound_addrs.add("admin", bound_addr);
+                tasks.spawn(report_error(task_errors.clone(), "admin", fut));
+                admin_addrs.push(bound_addr);
+            }
+        }
 
         Ok(HttpServer {
             tasks,
-            http_addr,
-            https_addr,
+            handle,
+            cancel,
+            http_addrs,
+            https_addrs,
+            admin_addrs,
         })
     }
 
-    /// Get the bound address of the HTTP socket.
+    /// Get the bound address of the first HTTP socket.
     pub fn http_addr(&self) -> Option<SocketAddr> {
-        self.http_addr
+        self.http_addrs.first().copied()
+    }
+
+    /// Get the bound addresses of all HTTP sockets.
+    pub fn http_addrs(&self) -> &[SocketAddr] {
+        &self.http_addrs
+    }
+
+    /// Get the bound address of the first admin socket.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addrs.first().copied()
     }
 
-    /// Get the bound address of the HTTPS socket.
+    /// Get the bound address of the first HTTPS socket.
     pub fn https_addr(&self) -> Option<SocketAddr> {
-        self.https_addr
+        self.https_addrs.first().copied()
+    }
+
+    /// Get the bound addresses of all HTTPS sockets.
+    pub fn https_addrs(&self) -> &[SocketAddr] {
+        &self.https_addrs
     }
 
     /// Shutdown the server and wait for all tasks to complete.
-    pub async fn shutdown(mut self) -> Result<()> {
-        // TODO: Graceful cancellation.
-        self.tasks.abort_all();
-        self.run_until_done().await?;
-        Ok(())
+    ///
+    /// Stops accepting new connections and waits for in-flight requests to finish. Connections
+    /// that are still open after `timeout` are closed.
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<Shutdown> {
+        self.handle.graceful_shutdown(Some(timeout));
+        self.cancel.cancel();
+        match tokio::time::timeout(timeout, join_all(&mut self.tasks)).await {
+            Ok(res) => res.map(|()| Shutdown::Clean),
+            Err(_) => {
+                warn!("HTTP server did not shut down within {timeout:?}, aborting");
+                self.tasks.abort_all();
+                join_all(&mut self.tasks).await?;
+                Ok(Shutdown::Forced)
+            }
+        }
     }
 
     /// Wait for all tasks to complete.
     ///
     /// Runs forever unless tasks fail.
     pub async fn run_until_done(mut self) -> Result<()> {
-        let mut final_res: anyhow::Result<()> = Ok(());
-        while let Some(res) = self.tasks.join_next().await {
-            match res {
-                Ok(Ok(())) => {}
-                Err(err) if err.is_cancelled() => {}
-                Ok(Err(err)) => {
-                    warn!(?err, "task failed");
-                    final_res = Err(anyhow::Error::from(err));
-                }
-                Err(err) => {
-                    warn!(?err, "task panicked");
-                    final_res = Err(err.into());
-                }
+        join_all(&mut self.tasks).await
+    }
+}
+
+/// Bind a TCP listener.
+fn bind(addr: SocketAddr) -> Result<std::net::TcpListener> {
+    util::bind_tcp(addr).with_context(|| format!("failed to bind {addr}"))
+}
+
+/// List the ACME accounts stored in `data_dir`.
+///
+/// The accounts are shared by all certificates that use the same ACME directory.
+#[cfg(feature = "https")]
+pub async fn acme_accounts(data_dir: &Path) -> Result<Vec<AcmeAccountInfo>> {
+    let cert_cache = data_dir.join("cert_cache");
+    tls::AccountStore::new(&cert_cache).list().await
+}
+
+/// List the ACME accounts stored in `data_dir`.
+///
+/// Fails, because ACME requires the `https` feature.
+#[cfg(not(feature = "https"))]
+pub async fn acme_accounts(_data_dir: &Path) -> Result<Vec<AcmeAccountInfo>> {
+    bail!("ACME requires the `https` feature")
+}
+
+/// Create the TLS acceptor for the HTTPS server.
+#[cfg(feature = "https")]
+async fn create_tls_acceptor(
+    config: &HttpsConfig,
+    data_dir: &Path,
+    acme_challenges: AcmeChallenges,
+    events: ServerEvents,
+) -> Result<(tls::TlsAcceptor, Vec<tls::CertStatus>)> {
+    let cert_cache = data_dir.join("cert_cache");
+    tls::TlsAcceptor::new(config, &cert_cache, acme_challenges, events).await
+}
+
+/// Run `task` of `server`, and report its error to `errors` if it fails.
+async fn report_error(
+    errors: TaskErrors,
+    server: &'static str,
+    task: impl Future<Output = std::io::Result<()>>,
+) -> std::io::Result<()> {
+    let res = task.await;
+    if let Err(err) = &res {
+        errors.report(server, err);
+    }
+    res
+}
+
+/// Wait for all tasks to complete, and return an error if any of them failed.
+async fn join_all(tasks: &mut JoinSet<std::io::Result<()>>) -> Result<()> {
+    let mut final_res: anyhow::Result<()> = Ok(());
+    while let Some(res) = tasks.join_next().await {
+        match res {
+            Ok(Ok(())) => {}
+            Err(err) if err.is_cancelled() => {}
+            Ok(Err(err)) => {
+                warn!(?err, "task failed");
+                final_res = Err(anyhow::Error::from(err));
+            }
+            Err(err) => {
+                warn!(?err, "task panicked");
+                final_res = Err(err.into());
             }
         }
-        final_res
     }
+    final_res
+}
+
+/// Check that the server is up
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/healthcheck",
+        tag = "dns",
+        responses((status = 200, description = "The server is up", body = String))
+    )
+)]
+async fn healthcheck() -> &'static str {
+    "OK"
+}
+
+/// Check that the server is ready to serve
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/readyz",
+        tag = "dns",
+        responses(
+            (status = 200, description = "The server is healthy", body = String),
+            (status = 503, description = "The store or the DHT is unhealthy", body = String),
+        )
+    )
+)]
+async fn readyz(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    if state.health.is_healthy() {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unhealthy")
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn create_app(
+    state: AppState,
+    rate_limit_config: &RateLimitConfig,
+    doh_rate_limit_config: Option<&RateLimitConfig>,
+    access_log_config: Option<&AccessLogConfig>,
+    compression_config: Option<&CompressionConfig>,
+    behind_proxy_config: Option<BehindProxyConfig>,
+    client_auth: Option<&ClientAuthConfig>,
+    cert_status: Vec<tls::CertStatus>,
+    request_timeout: Duration,
+    separate_admin: bool,
+    shutdown: CancellationToken,
+) -> Result<(Router, Option<Router>)> {
+    // configure rate limiting middleware
+    let abuse_log = state.abuse_log.clone();
+    let api_keys = state.store.api_keys().clone();
+    let rate_limit = rate_limiting::create(
+        rate_limit_config,
+        RateLimitClass::Publish,
+        api_keys.clone(),
+        abuse_log.clone(),
+    )?;
+    let lookup_rate_limit = rate_limiting::create(
+        rate_limit_config,
+        RateLimitClass::Lookup,
+        api_keys.clone(),
+        abuse_log.clone(),
+    )?;
+    let doh_rate_limit = match doh_rate_limit_config {
+        Some(config) => rate_limiting::create(config, RateLimitClass::Doh, api_keys, abuse_log)?,
+        None => None,
+    };
+    for limiter in rate_limit
+        .iter()
+        .chain(&lookup_rate_limit)
+        .chain(&doh_rate_limit)
+    {
+        state.rate_limiters.add(limiter.clone());
+    }
+
+    // configure the DoH route
+    //
+    // DoH queries get their own rate limit, to not consume the publish budget
+    let mut doh = get(doh::get).post(doh::post);
+    let mut resolve = get(doh::resolve);
+    if let Some(rate_limit) = doh_rate_limit {
+        doh = doh.layer(middleware::from_fn_with_state(
+            rate_limit.clone(),
+            rate_limiting::middleware,
+        ));
+        resolve = resolve.layer(middleware::from_fn_with_state(
+            rate_limit,
+            rate_limiting::middleware,
+        ));
+    }
+
+    // configure the pkarr publish routes
+    //
+    // the pkarr::put route and its dry run get the publish rate limit
+    let publish_route = |route: MethodRouter<AppState>| {
+        let mut route = route.layer(middleware::from_fn_with_state(
+            (state.auth.clone(), ApiKeyScope::Publish),
+            auth::middleware,
+        ));
+        if let Some(rate_limit) = rate_limit.clone() {
+            route = route.layer(middleware::from_fn_with_state(
+                rate_limit,
+                rate_limiting::middleware,
+            ));
+        }
+        if client_auth.is_some_and(|c| c.require_for_publish) {
+            route = route.layer(middleware::from_fn(tls::require_client_cert));
+        }
+        route.layer(middleware::from_fn_with_state(
+            (state.acl.clone(), Operation::Publish),
+            acl::middleware,
+        ))
+    };
+    // lookups get their own rate limit, if it is configured
+    let mut pkarr_get = get(pkarr::get);
+    if let Some(rate_limit) = lookup_rate_limit {
+        pkarr_get = pkarr_get.layer(middleware::from_fn_with_state(
+            rate_limit,
+            rate_limiting::middleware,
+        ));
+    }
+    let pkarr_get = pkarr_get.layer(middleware::from_fn_with_state(
+        (state.acl.clone(), Operation::Query),
+        acl::middleware,
+    ));
+    let pkarr_put = publish_route(put(pkarr::put));
+    let pkarr_validate = publish_route(post(pkarr::validate));
+
+    // configure routes
+    let router = Router::new()
+        .route("/dns-query", doh)
+        .route("/resolve", resolve)
+        .route("/pkarr/validate", pkarr_validate)
+        .route("/pkarr/:key", pkarr_get.merge(pkarr_put))
+        .route("/healthcheck", get(healthcheck))
+        .route("/readyz", get(readyz))
+        .route("/analytics/:key", get(analytics::get))
+        // the change feed is only served to clients authorized for the sync scope
+        .route(
+            "/sync",
+            get(sync::get).route_layer(middleware::from_fn_with_state(
+                (state.auth.clone(), ApiKeyScope::Sync),
+                auth::middleware,
+            )),
+        )
+        .route("/", get(|| async { "Hi!" }));
+    #[cfg(feature = "openapi")]
+    let router = router.route("/openapi.json", get(openapi::get));
+
+    // inject the faults into the public routes, and serve their admin API
+    let admin = admin::router(cert_status, shutdown);
+    #[cfg(feature = "chaos")]
+    let (router, admin) = (
+        router.layer(middleware::from_fn_with_state(
+            state.chaos.clone(),
+            chaos::middleware,
+        )),
+        admin.merge(chaos::router()),
+    );
+
+    // the admin routes are only served to clients authorized for the admin scope, from the
+    // networks of the ACL, or only on the admin listeners, where the address is the authorization
+    let admin_acl =
+        middleware::from_fn_with_state((state.acl.clone(), Operation::Admin), acl::middleware);
+    let (router, admin) = if separate_admin {
+        let admin = Router::new().nest("/admin", admin.route_layer(admin_acl));
+        (
+            router.with_state(state.clone()),
+            Some(admin.with_state(state)),
+        )
+    } else {
+        let admin = admin
+            .route_layer(middleware::from_fn_with_state(
+                (state.auth.clone(), ApiKeyScope::Admin),
+                auth::middleware,
+            ))
+            .route_layer(admin_acl);
+        (router.nest("/admin", admin).with_state(state), None)
+    };
+
+    let access_log = access_log_config
+        .map(access_log::AccessLog::new)
+        .transpose()?;
+    let layers = |router| {
+        add_layers(
+            router,
+            compression_config,
+            access_log.clone(),
+            behind_proxy_config.clone(),
+            request_timeout,
+        )
+    };
+    Ok((layers(router), admin.map(layers)))
 }
 
-pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -> Router {
+/// Add the middlewares that all routes share to `router`.
+fn add_layers(
+    router: Router,
+    compression_config: Option<&CompressionConfig>,
+    access_log: Option<access_log::AccessLog>,
+    behind_proxy_config: Option<BehindProxyConfig>,
+    request_timeout: Duration,
+) -> Router {
     // configure cors middleware
     let cors = CorsLayer::new()
         // allow `GET` and `POST` when accessing the resource
@@ -220,34 +827,48 @@ pub(crate) fn create_app(state: AppState, rate_limit_config: &RateLimitConfig) -
             uri = ?request.uri(),
             src = %conn_info.0,
             );
+        telemetry::set_parent_from_headers(&span, request.headers());
         span
     });
 
-    // configure rate limiting middleware
-    let rate_limit = rate_limiting::create(rate_limit_config);
-
-    // configure routes
-    //
-    // only the pkarr::put route gets a rate limit
-    let router = Router::new()
-        .route("/dns-query", get(doh::get).post(doh::post))
-        .route(
-            "/pkarr/:key",
-            if let Some(rate_limit) = rate_limit {
-                get(pkarr::get).put(pkarr::put.layer(rate_limit))
-            } else {
-                get(pkarr::get).put(pkarr::put)
-            },
-        )
-        .route("/healthcheck", get(|| async { "OK" }))
-        .route("/", get(|| async { "Hi!" }))
-        .with_state(state);
+    // configure compression middleware
+    let router = match compression_config.and_then(compression::layer) {
+        Some(compression) => router.layer(compression),
+        None => router,
+    };
 
     // configure app
-    router
+    let router = router
+        .layer(TimeoutLayer::new(request_timeout))
+        .layer(middleware::from_fn(trace_context_middleware))
         .layer(cors)
         .layer(trace)
-        .route_layer(middleware::from_fn(metrics_middleware))
+        .route_layer(middleware::from_fn(metrics_middleware));
+
+    // reject requests on connections above the limit, before they do any work
+    let router = router.layer(middleware::from_fn(limits::load_shed_middleware));
+
+    // configure access log middleware
+    let router = match access_log {
+        Some(access_log) => router.layer(middleware::from_fn_with_state(
+            access_log,
+            access_log::middleware,
+        )),
+        None => router,
+    };
+
+    // configure the client address from proxy headers, before all other middlewares
+    router.layer(middleware::from_fn_with_state(
+        behind_proxy_config,
+        forwarded::middleware,
+    ))
+}
+
+/// Add the `traceparent` header of the request span to responses.
+async fn trace_context_middleware(req: Request, next: Next) -> impl IntoResponse {
+    let mut response = next.run(req).await;
+    telemetry::inject_headers(&Span::current(), response.headers_mut());
+    response
 }
 
 /// Record request metrics.
@@ -0,0 +1,119 @@
+//! Structured access log for the HTTP server
+//!
+//! The access log emits one JSON record per line for each HTTP request. It is written separately
+//! from the tracing output, so that it can be shipped to standard log pipelines.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::warn;
+
+use crate::util::PublicKeyBytes;
+
+/// Config for the HTTP access log
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AccessLogConfig {
+    /// Path of a file to which access log records are appended.
+    ///
+    /// If unset, access log records are written to stdout.
+    pub path: Option<PathBuf>,
+}
+
+/// A single access log record.
+#[derive(Debug, Serialize)]
+struct AccessLogRecord<'a> {
+    timestamp: String,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    latency_ms: f64,
+    client_ip: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pubkey: Option<String>,
+}
+
+/// Writer for access log records.
+#[derive(Clone, derive_more::Debug)]
+pub(crate) struct AccessLog {
+    #[debug("Writer")]
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    /// Open the access log as configured.
+    pub(crate) fn new(config: &AccessLogConfig) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match &config.path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open access log at {path:?}"))?;
+                Box::new(LineWriter::new(file))
+            }
+            None => Box::new(io::stdout()),
+        };
+        Ok(Self {
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    fn write(&self, record: &AccessLogRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("failed to serialize access log record: {err}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut writer = self.writer.lock().expect("poisoned");
+        if let Err(err) = writer.write_all(&line).and_then(|_| writer.flush()) {
+            warn!("failed to write access log record: {err}");
+        }
+    }
+}
+
+/// Middleware that writes an access log record for each request.
+pub(crate) async fn middleware(
+    State(access_log): State<AccessLog>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let response = next.run(req).await;
+    let pubkey = path
+        .strip_prefix("/pkarr/")
+        .and_then(|key| PublicKeyBytes::from_z32(key).ok())
+        .map(|key| key.to_z32());
+    access_log.write(&AccessLogRecord {
+        timestamp,
+        method: method.as_str(),
+        path: &path,
+        status: response.status().as_u16(),
+        latency_ms: start.elapsed().as_secs_f64() * 1000.,
+        client_ip: addr.ip(),
+        pubkey,
+    });
+    response
+}
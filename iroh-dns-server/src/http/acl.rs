@@ -0,0 +1,33 @@
+//! Access control of the HTTP endpoints by client network
+//!
+//! See [`crate::acl`]. DNS-over-HTTPS queries are checked by the DNS handler.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use super::error::AppError;
+use crate::acl::{Acl, Operation};
+
+/// Middleware that rejects the requests of the clients that may not perform the operation.
+pub(crate) async fn middleware(
+    State((acl, operation)): State<(Acl, Operation)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if acl.allows(operation, peer.ip()) {
+        next.run(req).await
+    } else {
+        AppError::new(
+            StatusCode::FORBIDDEN,
+            Some(format!("{operation} is not allowed from {}", peer.ip())),
+        )
+        .into_response()
+    }
+}
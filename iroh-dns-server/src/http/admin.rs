@@ -0,0 +1,1080 @@
+//! Admin endpoints of the HTTP server
+//!
+//! The admin router is nested under `/admin`. Requests must be authorized for the
+//! [`ApiKeyScope::Admin`] scope, by default with a verified TLS client certificate (see
+//! [`super::ClientAuthConfig`]) or an admin API key as `Authorization: Bearer <token>` header.
+//! See [`super::auth`]. With an [`AdminConfig`], admin tokens can be set in the config, and the
+//! admin router can be served on listeners of its own instead.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use iroh_metrics::core::Core;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use super::{
+    auth::TokenAuth,
+    error::{AppError, AppResult},
+    tls::CertStatus,
+};
+use crate::{
+    api_keys::{ApiKey, ApiKeyScope, NewApiKey},
+    db::DbStats,
+    dns::traffic::{self as traffic_stats, TrafficWindow},
+    events::ServerEvent,
+    history::{self, History, PacketVersion},
+    hourly_stats::HourlyStatsReport,
+    inspect::PacketReport,
+    metrics::{LookupOutcome, MainlineMetrics, Metrics},
+    retention::{self, GcReport},
+    secrets::SecretValue,
+    state::AppState,
+    tail::{self, TailFilter},
+    telemetry::{self, LogFilterStatus},
+    usage::{self as usage_reports, UsageFormat},
+    util::PublicKeyBytes,
+};
+
+/// The embedded status dashboard.
+const DASHBOARD_HTML: &str = include_str!("admin/dashboard.html");
+/// Default number of packets in a page of `GET /admin/packets`.
+const DEFAULT_PACKETS_LIMIT: usize = 100;
+/// Maximum number of packets in a page of `GET /admin/packets`.
+const MAX_PACKETS_LIMIT: usize = 1000;
+
+/// Config for the admin endpoints
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// Bearer tokens that are authorized for the admin endpoints, in addition to the admin API
+    /// keys and client certificates
+    ///
+    /// The tokens are loaded on start.
+    #[serde(default)]
+    pub tokens: Vec<SecretValue>,
+    /// Addresses of plain HTTP listeners that serve the admin endpoints, e.g. `127.0.0.1:9090`
+    ///
+    /// If set, the admin endpoints are only served on these listeners, and not under `/admin` of
+    /// the HTTP and HTTPS servers. Requests on these listeners are not authorized, so they must
+    /// only be reachable by admins.
+    #[serde(default)]
+    pub bind_addr: Vec<SocketAddr>,
+}
+
+impl AdminConfig {
+    /// Load the tokens into a provider for the admin scope, if any are set.
+    pub(crate) async fn token_auth(&self) -> anyhow::Result<Option<TokenAuth>> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+        let mut tokens = Vec::with_capacity(self.tokens.len());
+        for token in &self.tokens {
+            tokens.push(match token {
+                SecretValue::Value(value) => value.clone(),
+                SecretValue::Source(source) => source.load().await?,
+            });
+        }
+        Ok(Some(TokenAuth::new([ApiKeyScope::Admin], tokens)))
+    }
+}
+
+/// Information about the server that is not part of the [`AppState`].
+#[derive(Debug)]
+pub(crate) struct AdminInfo {
+    started: Instant,
+    cert_status: Vec<CertStatus>,
+    /// Cancelled on shutdown, to end the event streams
+    shutdown: CancellationToken,
+}
+
+/// Create the admin router.
+pub(crate) fn router(
+    cert_status: Vec<CertStatus>,
+    shutdown: CancellationToken,
+) -> Router<AppState> {
+    let info = Arc::new(AdminInfo {
+        started: Instant::now(),
+        cert_status,
+        shutdown,
+    });
+    Router::new()
+        .route("/status", get(status))
+        .route("/stats", get(stats))
+        .route("/db-stats", get(db_stats))
+        .route("/db-gc", post(db_gc))
+        .route("/packets", get(list_packets))
+        .route("/packets/:key", get(get_packet).delete(remove_packet))
+        .route("/history/:key", get(history))
+        .route("/history/:key/at", get(history_at))
+        .route("/usage", get(usage))
+        .route("/hourly-stats", get(hourly_stats))
+        .route("/tail", get(tail))
+        .route("/events", get(events))
+        .route("/dashboard", get(dashboard))
+        .route("/traffic", get(traffic))
+        .route("/api-keys", get(list_api_keys).post(create_api_key))
+        .route("/api-keys/:id", delete(revoke_api_key))
+        .route("/api-keys/:id/rotate", post(rotate_api_key))
+        .route(
+            "/log-filter",
+            get(get_log_filter)
+                .put(set_log_filter)
+                .delete(reset_log_filter),
+        )
+        .layer(Extension(info))
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct Status {
+    version: &'static str,
+    uptime_secs: u64,
+    /// The addresses the servers are bound to, by server (`http`, `https`, `dns`, `metrics`)
+    addrs: BTreeMap<String, Vec<String>>,
+    store: StoreStatus,
+    /// The certificates of the HTTPS server, the default certificate first
+    certs: Vec<CertStatus>,
+    dht: DhtStatus,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct StoreStatus {
+    /// The number of signed packets in the store
+    packets: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct DhtStatus {
+    /// Whether the mainline DHT fallback is enabled
+    enabled: bool,
+    /// The local address of the DHT client
+    local_addr: Option<String>,
+    /// Seconds since the unix epoch of the last DHT lookup that found a packet
+    last_found: Option<i64>,
+    /// The number of DHT lookups by outcome
+    lookups: LookupCounts,
+}
+
+impl DhtStatus {
+    fn get(state: &AppState) -> Self {
+        let last_found = MainlineMetrics::get().mainline_last_found_timestamp.get();
+        Self {
+            enabled: state.store.mainline_enabled(),
+            local_addr: state.store.mainline_addr().map(|addr| addr.to_string()),
+            last_found: (last_found > 0).then_some(last_found),
+            lookups: LookupCounts {
+                found: MainlineMetrics::lookups(LookupOutcome::Found),
+                not_found: MainlineMetrics::lookups(LookupOutcome::NotFound),
+                error: MainlineMetrics::lookups(LookupOutcome::Error),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct LookupCounts {
+    found: u64,
+    not_found: u64,
+    error: u64,
+}
+
+/// Get the server status, for health checks of fleets
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/status",
+        tag = "admin",
+        responses(
+            (status = 200, description = "Server status", body = Status),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn status(
+    State(state): State<AppState>,
+    Extension(info): Extension<Arc<AdminInfo>>,
+) -> AppResult<Json<Status>> {
+    let addrs = state
+        .bound_addrs
+        .get()
+        .into_iter()
+        .map(|(server, addrs)| {
+            let addrs = addrs.iter().map(ToString::to_string).collect();
+            (server.to_string(), addrs)
+        })
+        .collect();
+    Ok(Json(Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: info.started.elapsed().as_secs(),
+        addrs,
+        store: StoreStatus {
+            packets: state.store.packet_count().await?,
+        },
+        certs: info.cert_status.clone(),
+        dht: DhtStatus::get(&state),
+    }))
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct Stats {
+    version: &'static str,
+    uptime_secs: u64,
+    store_packets: u64,
+    counters: Counters,
+    recent_publishes: Vec<RecentPublish>,
+    /// The certificates of the HTTPS server, the default certificate first
+    certs: Vec<CertStatus>,
+    dht: DhtStatus,
+}
+
+/// Snapshot of the metrics counters shown on the dashboard.
+///
+/// All counters are zero if metrics collection is not initialized.
+#[derive(Debug, Default, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct Counters {
+    dns_requests: u64,
+    dns_requests_udp: u64,
+    dns_requests_https: u64,
+    dns_lookup_success: u64,
+    dns_lookup_notfound: u64,
+    dns_lookup_error: u64,
+    http_requests: u64,
+    http_requests_error: u64,
+    pkarr_publish_update: u64,
+    pkarr_publish_noop: u64,
+    store_packets_inserted: u64,
+    store_packets_updated: u64,
+    store_packets_removed: u64,
+    store_packets_expired: u64,
+}
+
+impl Counters {
+    fn get() -> Self {
+        let Some(m) = Core::get().and_then(|core| core.get_collector::<Metrics>()) else {
+            return Self::default();
+        };
+        Self {
+            dns_requests: m.dns_requests.get(),
+            dns_requests_udp: m.dns_requests_udp.get(),
+            dns_requests_https: m.dns_requests_https.get(),
+            dns_lookup_success: m.dns_lookup_success.get(),
+            dns_lookup_notfound: m.dns_lookup_notfound.get(),
+            dns_lookup_error: m.dns_lookup_error.get(),
+            http_requests: m.http_requests.get(),
+            http_requests_error: m.http_requests_error.get(),
+            pkarr_publish_update: m.pkarr_publish_update.get(),
+            pkarr_publish_noop: m.pkarr_publish_noop.get(),
+            store_packets_inserted: m.store_packets_inserted.get(),
+            store_packets_updated: m.store_packets_updated.get(),
+            store_packets_removed: m.store_packets_removed.get(),
+            store_packets_expired: m.store_packets_expired.get(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct RecentPublish {
+    pubkey: String,
+    /// Milliseconds since the unix epoch
+    published_at_ms: u64,
+}
+
+/// Default number of top entries in the traffic statistics.
+const DEFAULT_TRAFFIC_LIMIT: usize = 10;
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct TrafficQuery {
+    /// The number of top pubkeys and clients to return (defaults to 10)
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct Traffic {
+    /// The duration of a window
+    window_secs: u64,
+    /// The current window, and the previous window if it immediately precedes it
+    windows: Vec<TrafficWindow>,
+}
+
+/// Get the most queried pubkeys, most active client networks and record types
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/traffic",
+        tag = "admin",
+        params(TrafficQuery),
+        responses(
+            (status = 200, description = "Traffic statistics", body = Traffic),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn traffic(
+    State(state): State<AppState>,
+    Query(query): Query<TrafficQuery>,
+) -> Json<Traffic> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_TRAFFIC_LIMIT)
+        .min(traffic_stats::CAPACITY);
+    Json(Traffic {
+        window_secs: traffic_stats::WINDOW.as_secs(),
+        windows: state.dns_handler.traffic().windows(limit),
+    })
+}
+
+/// Get server statistics, as shown on the dashboard
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/stats",
+        tag = "admin",
+        responses(
+            (status = 200, description = "Server statistics", body = Stats),
+            (status = 401, description = "Client certificate required", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn stats(
+    State(state): State<AppState>,
+    Extension(info): Extension<Arc<AdminInfo>>,
+) -> AppResult<Json<Stats>> {
+    let recent_publishes = state
+        .store
+        .recent_publishes()
+        .into_iter()
+        .map(|p| RecentPublish {
+            pubkey: p.pubkey.to_z32(),
+            published_at_ms: p
+                .published_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        })
+        .collect();
+    Ok(Json(Stats {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: info.started.elapsed().as_secs(),
+        store_packets: state.store.packet_count().await?,
+        counters: Counters::get(),
+        recent_publishes,
+        certs: info.cert_status.clone(),
+        dht: DhtStatus::get(&state),
+    }))
+}
+
+/// Get statistics of the packet database
+///
+/// This reads all packets, so it is slow for large databases.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/db-stats",
+        tag = "admin",
+        responses(
+            (status = 200, description = "Packet database statistics", body = DbStats),
+            (status = 401, description = "Client certificate required", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn db_stats(State(state): State<AppState>) -> AppResult<Json<DbStats>> {
+    Ok(Json(DbStats::collect(&state.store).await?))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct GcQuery {
+    /// Only report the packets that would be removed
+    dry_run: Option<bool>,
+    /// Remove packets older than this many seconds (defaults to `retention.max_age_secs`)
+    max_age_secs: Option<u64>,
+}
+
+/// Remove old packets now
+///
+/// The database is not compacted, which needs the server to be stopped, see `db gc`.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        post,
+        path = "/admin/db-gc",
+        tag = "admin",
+        params(GcQuery),
+        responses(
+            (status = 200, description = "The number of removed packets, and some of them", body = GcReport),
+            (status = 400, description = "No maximum age", body = AppError),
+            (status = 401, description = "Client certificate required", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn db_gc(
+    State(state): State<AppState>,
+    Query(query): Query<GcQuery>,
+) -> AppResult<Json<GcReport>> {
+    let max_age_secs = query
+        .max_age_secs
+        .or(state.retention.as_ref().map(|r| r.max_age_secs))
+        .ok_or_else(|| {
+            AppError::new(
+                StatusCode::BAD_REQUEST,
+                Some("max_age_secs is required without a retention config"),
+            )
+        })?;
+    let max_age = std::time::Duration::from_secs(max_age_secs);
+    let dry_run = query.dry_run.unwrap_or(false);
+    Ok(Json(retention::gc(&state.store, max_age, dry_run).await?))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct PacketsQuery {
+    /// Only list the pubkeys after this z-base-32 encoded pubkey, the `next` of the previous page
+    after: Option<String>,
+    /// The number of packets to return (defaults to 100, at most 1000)
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct PacketList {
+    /// The stored packets, in the order of their pubkeys
+    packets: Vec<StoredPacket>,
+    /// The `after` of the next page, if there may be more packets
+    next: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct StoredPacket {
+    /// The z-base-32 encoded pubkey
+    public_key: String,
+    /// The timestamp of the packet, in microseconds since the unix epoch
+    timestamp: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct PacketRecords {
+    /// The z-base-32 encoded pubkey
+    public_key: String,
+    /// The timestamp of the packet, in microseconds since the unix epoch
+    timestamp: u64,
+    /// The records of the packet, in zone file format
+    records: Vec<String>,
+}
+
+/// List the stored packets, in pages in the order of their pubkeys
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/packets",
+        tag = "admin",
+        params(PacketsQuery),
+        responses(
+            (status = 200, description = "A page of the stored packets", body = PacketList),
+            (status = 400, description = "Invalid pubkey", body = AppError),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn list_packets(
+    State(state): State<AppState>,
+    Query(query): Query<PacketsQuery>,
+) -> AppResult<Json<PacketList>> {
+    let after = query.after.as_deref().map(parse_key).transpose()?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PACKETS_LIMIT)
+        .clamp(1, MAX_PACKETS_LIMIT);
+    let packets: Vec<StoredPacket> = state
+        .store
+        .packets_after(after, limit)
+        .await?
+        .into_iter()
+        .map(|packet| StoredPacket {
+            public_key: packet.public_key().to_z32(),
+            timestamp: packet.timestamp(),
+        })
+        .collect();
+    let next = match packets.last() {
+        Some(last) if packets.len() == limit => Some(last.public_key.clone()),
+        _ => None,
+    };
+    Ok(Json(PacketList { packets, next }))
+}
+
+/// Get the records of the stored packet of a pubkey
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/packets/{key}",
+        tag = "admin",
+        params(("key" = String, Path, description = "The z-base-32 encoded pubkey")),
+        responses(
+            (status = 200, description = "The records of the packet", body = PacketRecords),
+            (status = 400, description = "Invalid pubkey", body = AppError),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "No packet is stored for the pubkey", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn get_packet(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> AppResult<Json<PacketRecords>> {
+    let pubkey = parse_key(&key)?;
+    let Some(packet) = state.store.get_signed_packet(&pubkey).await? else {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    };
+    let report = PacketReport::decode(&packet.as_bytes()[..])?;
+    Ok(Json(PacketRecords {
+        public_key: report.public_key,
+        timestamp: report.timestamp,
+        records: report.records.iter().map(ToString::to_string).collect(),
+    }))
+}
+
+/// Remove the stored packet of a pubkey
+///
+/// The packet is served again if it is published again, or found on the mainline DHT.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        delete,
+        path = "/admin/packets/{key}",
+        tag = "admin",
+        params(("key" = String, Path, description = "The z-base-32 encoded pubkey")),
+        responses(
+            (status = 204, description = "The packet was removed"),
+            (status = 400, description = "Invalid pubkey", body = AppError),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "No packet is stored for the pubkey", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn remove_packet(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> AppResult<StatusCode> {
+    let pubkey = parse_key(&key)?;
+    if !state.store.remove(&pubkey).await? {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    }
+    state.store.events().send(ServerEvent::PacketRemoved {
+        pubkey: pubkey.to_z32(),
+    });
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn parse_key(key: &str) -> AppResult<PublicKeyBytes> {
+    PublicKeyBytes::from_z32(key)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))
+}
+
+/// Get the retained versions of the packets of a pubkey, with the changes between them
+///
+/// Each version has the records that were added and removed since the version before it.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/history/{key}",
+        tag = "admin",
+        params(("key" = String, Path, description = "The z-base-32 encoded pubkey")),
+        responses(
+            (status = 200, description = "The retained versions, the oldest first", body = History),
+            (status = 400, description = "Invalid pubkey", body = AppError),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "The history of the packets is not kept", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn history(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> AppResult<Json<History>> {
+    let Some(history) = state.store.history().cloned() else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            Some("the history of the packets is not kept"),
+        ));
+    };
+    let pubkey = parse_key(&key)?;
+    let history = tokio::task::spawn_blocking(move || history.history(&pubkey))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(Json(history))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct HistoryAtQuery {
+    /// The time in RFC 3339 format, e.g. `2024-05-01T12:00:00Z`
+    time: String,
+}
+
+/// Get the version of the packets of a pubkey that was current at a time
+///
+/// This is the newest retained version with a timestamp before the time, with the records that
+/// were added and removed since the version before it.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/history/{key}/at",
+        tag = "admin",
+        params(
+            ("key" = String, Path, description = "The z-base-32 encoded pubkey"),
+            HistoryAtQuery,
+        ),
+        responses(
+            (status = 200, description = "The version that was current at the time", body = PacketVersion),
+            (status = 400, description = "Invalid pubkey or time", body = AppError),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "No retained version at the time, or the history of the packets is not kept", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn history_at(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<HistoryAtQuery>,
+) -> AppResult<Json<PacketVersion>> {
+    let Some(history) = state.store.history().cloned() else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            Some("the history of the packets is not kept"),
+        ));
+    };
+    let pubkey = parse_key(&key)?;
+    let time = OffsetDateTime::parse(&query.time, &Rfc3339)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid time: {e}"))))?;
+    let timestamp = history::unix_micros(time);
+    let version = tokio::task::spawn_blocking(move || history.at(&pubkey, timestamp))
+        .await
+        .map_err(anyhow::Error::from)??;
+    version
+        .map(Json)
+        .ok_or_else(|| AppError::with_status(StatusCode::NOT_FOUND))
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct UsageQuery {
+    /// The format of the response, `json` or `csv` (defaults to `json`)
+    #[serde(default)]
+    format: UsageFormat,
+}
+
+/// Get the usage of the tenants and API keys in the current interval of the usage reports
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/usage",
+        tag = "admin",
+        params(UsageQuery),
+        responses(
+            (status = 200, description = "The usage since the start of the interval", body = usage_reports::UsageReport),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "The usage is not counted", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn usage(
+    State(state): State<AppState>,
+    Query(query): Query<UsageQuery>,
+) -> AppResult<Response> {
+    let Some(usage) = &state.usage else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            Some("the usage is not counted"),
+        ));
+    };
+    let report = usage.report();
+    Ok(match query.format {
+        UsageFormat::Json => Json(report).into_response(),
+        UsageFormat::Csv => (
+            [(CONTENT_TYPE, "text/csv")],
+            format!("{}{}", usage_reports::CSV_HEADER, report.to_csv()),
+        )
+            .into_response(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct HourlyStatsQuery {
+    /// The start of the range, in RFC 3339 format (defaults to 24 hours before the end)
+    from: Option<String>,
+    /// The end of the range, in RFC 3339 format (defaults to now)
+    to: Option<String>,
+}
+
+/// Get the hourly statistics of the queries and publishes in a time range
+///
+/// The counts of the hours from the hour of `from` to the hour of `to` are returned, limited to
+/// the retention of the statistics.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/hourly-stats",
+        tag = "admin",
+        params(HourlyStatsQuery),
+        responses(
+            (status = 200, description = "The counts of the hours in the range", body = HourlyStatsReport),
+            (status = 400, description = "Invalid time range", body = AppError),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "The hourly statistics are not counted", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn hourly_stats(
+    State(state): State<AppState>,
+    Query(query): Query<HourlyStatsQuery>,
+) -> AppResult<Json<HourlyStatsReport>> {
+    let Some(hourly_stats) = state.hourly_stats.clone() else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            Some("the hourly statistics are not counted"),
+        ));
+    };
+    let parse = |time: &str| {
+        OffsetDateTime::parse(time, &Rfc3339)
+            .map(SystemTime::from)
+            .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid time: {e}"))))
+    };
+    let to = match &query.to {
+        Some(to) => parse(to)?,
+        None => SystemTime::now(),
+    };
+    let from = match &query.from {
+        Some(from) => parse(from)?,
+        None => to - Duration::from_secs(24 * 60 * 60),
+    };
+    if from > to {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            Some("the start of the range must not be after its end"),
+        ));
+    }
+    let report = tokio::task::spawn_blocking(move || hourly_stats.get(from, to))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(Json(report))
+}
+
+/// Stream live query and publish events
+///
+/// The events are streamed as JSON lines until the client disconnects or the server shuts down.
+/// Events that the client doesn't read in time are dropped, and reported as `lagged` events.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/tail",
+        tag = "admin",
+        params(TailFilter),
+        responses(
+            (status = 200, description = "The events as JSON lines", content_type = "application/x-ndjson"),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn tail(
+    State(state): State<AppState>,
+    Extension(info): Extension<Arc<AdminInfo>>,
+    Query(filter): Query<TailFilter>,
+) -> Response {
+    let events = tail::subscribe(&state, &filter, info.shutdown.clone());
+    let lines = ReceiverStream::new(events).map(|event| {
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        Ok::<_, serde_json::Error>(line)
+    });
+    (
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Stream the server events over a WebSocket
+///
+/// Each event is sent as a JSON text message, tagged with its `type`, until the client closes the
+/// WebSocket or the server shuts down. Events that the client doesn't read in time are dropped,
+/// and reported as `lagged` events.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/events",
+        tag = "admin",
+        responses(
+            (status = 101, description = "Switching to a WebSocket with the events as JSON messages"),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn events(
+    State(state): State<AppState>,
+    Extension(info): Extension<Arc<AdminInfo>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let events = state.store.events().subscribe();
+    let shutdown = info.shutdown.clone();
+    ws.on_upgrade(move |socket| stream_events(socket, events, shutdown))
+}
+
+/// Send `events` to `socket` until it is closed or the server shuts down.
+async fn stream_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<ServerEvent>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            // messages of the client are ignored, pings are answered by axum
+            message = socket.recv() => match message {
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return,
+            },
+            event = events.recv() => match event {
+                Ok(event) => serde_json::to_string(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    serde_json::to_string(&serde_json::json!({ "type": "lagged", "missed": missed }))
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        };
+        let message = message.expect("events serialize to JSON");
+        if socket.send(Message::Text(message)).await.is_err() {
+            return;
+        }
+    }
+    socket.send(Message::Close(None)).await.ok();
+}
+
+/// Get the status dashboard
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/dashboard",
+        tag = "admin",
+        responses((status = 200, description = "HTML status dashboard", content_type = "text/html"))
+    )
+)]
+pub(crate) async fn dashboard() -> impl IntoResponse {
+    Html(DASHBOARD_HTML)
+}
+
+/// Request to create an API key
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct CreateApiKey {
+    /// A human readable name for the key
+    name: String,
+    /// The scopes the key is valid for
+    scopes: Vec<ApiKeyScope>,
+}
+
+/// List all API keys
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/api-keys",
+        tag = "admin",
+        responses(
+            (status = 200, description = "All API keys, including revoked ones", body = Vec<ApiKey>),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn list_api_keys(State(state): State<AppState>) -> AppResult<Json<Vec<ApiKey>>> {
+    Ok(Json(state.store.api_keys().list().await?))
+}
+
+/// Create an API key
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        post,
+        path = "/admin/api-keys",
+        tag = "admin",
+        request_body = CreateApiKey,
+        responses(
+            (status = 201, description = "The new key and its token", body = NewApiKey),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn create_api_key(
+    State(state): State<AppState>,
+    Json(req): Json<CreateApiKey>,
+) -> AppResult<(StatusCode, Json<NewApiKey>)> {
+    let key = state.store.api_keys().create(req.name, req.scopes).await?;
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+/// Replace the secret of an API key
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        post,
+        path = "/admin/api-keys/{id}/rotate",
+        tag = "admin",
+        params(("id" = String, Path, description = "The id of the key")),
+        responses(
+            (status = 200, description = "The key and its new token", body = NewApiKey),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "No active key with this id", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn rotate_api_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> AppResult<Json<NewApiKey>> {
+    match state.store.api_keys().rotate(&id).await? {
+        Some(key) => Ok(Json(key)),
+        None => Err(AppError::with_status(StatusCode::NOT_FOUND)),
+    }
+}
+
+/// Revoke an API key
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        delete,
+        path = "/admin/api-keys/{id}",
+        tag = "admin",
+        params(("id" = String, Path, description = "The id of the key")),
+        responses(
+            (status = 204, description = "The key was revoked"),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "No active key with this id", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn revoke_api_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> AppResult<StatusCode> {
+    match state.store.api_keys().revoke(&id).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(AppError::with_status(StatusCode::NOT_FOUND)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct SetLogFilter {
+    /// The filter directives, e.g. `info,iroh_dns_server=debug`
+    filter: String,
+}
+
+/// Get the filter of the log output
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/admin/log-filter",
+        tag = "admin",
+        responses(
+            (status = 200, description = "The current and configured log filter", body = LogFilterStatus),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "The log output is not managed by this server", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn get_log_filter() -> AppResult<Json<LogFilterStatus>> {
+    telemetry::log_filter()
+        .map(Json)
+        .ok_or_else(|| AppError::with_status(StatusCode::NOT_FOUND))
+}
+
+/// Change the filter of the log output until the next restart
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/admin/log-filter",
+        tag = "admin",
+        request_body = SetLogFilter,
+        responses(
+            (status = 200, description = "The new log filter", body = LogFilterStatus),
+            (status = 400, description = "Invalid filter directives", body = AppError),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "The log output is not managed by this server", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn set_log_filter(
+    Json(req): Json<SetLogFilter>,
+) -> AppResult<Json<LogFilterStatus>> {
+    if telemetry::log_filter().is_none() {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    }
+    telemetry::set_log_filter(&req.filter)
+        .map(Json)
+        .map_err(|err| AppError::new(StatusCode::BAD_REQUEST, Some(format!("{err:#}"))))
+}
+
+/// Restore the configured filter of the log output
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        delete,
+        path = "/admin/log-filter",
+        tag = "admin",
+        responses(
+            (status = 200, description = "The configured log filter", body = LogFilterStatus),
+            (status = 401, description = "Not authorized", body = AppError),
+            (status = 404, description = "The log output is not managed by this server", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn reset_log_filter() -> AppResult<Json<LogFilterStatus>> {
+    if telemetry::log_filter().is_none() {
+        return Err(AppError::with_status(StatusCode::NOT_FOUND));
+    }
+    Ok(Json(telemetry::reset_log_filter()?))
+}
@@ -0,0 +1,68 @@
+//! The resolution counts of a key, for its owner, see [`crate::analytics`]
+
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use http::StatusCode;
+use serde::Deserialize;
+
+use super::error::{AppError, AppResult};
+use crate::{analytics, analytics::Resolutions, state::AppState, util::PublicKeyBytes};
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct AnalyticsQuery {
+    /// The number of days, including today
+    days: u32,
+    /// The time of the request, in seconds since the unix epoch
+    timestamp: u64,
+    /// The hex encoded ed25519 signature of the request by the key
+    signature: String,
+}
+
+/// Get how often the packet of a key was resolved per day
+///
+/// The request must be signed with the secret key of the key, see [`analytics::signed_query`].
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/analytics/{key}",
+        tag = "analytics",
+        params(("key" = String, Path, description = "z-base-32 encoded public key"), AnalyticsQuery),
+        responses(
+            (status = 200, description = "The resolutions per day, the oldest day first", body = Resolutions),
+            (status = 400, description = "Invalid key or number of days", body = AppError),
+            (status = 403, description = "The request is not signed by the key, or too old", body = AppError),
+            (status = 404, description = "Resolutions are not counted on this server", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn get(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    Query(query): Query<AnalyticsQuery>,
+) -> AppResult<Json<Resolutions>> {
+    let Some(analytics) = state.analytics.clone() else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            Some("resolutions are not counted on this server"),
+        ));
+    };
+    let pubkey = PublicKeyBytes::from_z32(&key)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
+    let max_days = analytics.retention_days();
+    if !(1..=max_days).contains(&query.days) {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            Some(format!("days must be between 1 and {max_days}")),
+        ));
+    }
+    analytics::verify_request(&pubkey, query.days, query.timestamp, &query.signature)
+        .map_err(|err| AppError::new(StatusCode::FORBIDDEN, Some(err)))?;
+    let resolutions = tokio::task::spawn_blocking(move || analytics.get(&pubkey, query.days))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(Json(resolutions))
+}
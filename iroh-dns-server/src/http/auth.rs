@@ -0,0 +1,227 @@
+//! Authorization of HTTP requests
+//!
+//! The admin endpoints under `/admin`, the change feed at `/sync` and the publishes with
+//! `PUT /pkarr/{key}` are authorized by [`AuthProvider`]s, which run in order until one allows
+//! or denies the request. The first providers are [`ClientCertAuth`], which allows requests with
+//! a verified TLS client certificate, and [`ApiKeyAuth`], which allows requests with an API key of
+//! the [`ApiKeyScope`] of the request. The providers added to the
+//! [`Server::builder`](crate::server::Server::builder) run after these, e.g. to validate the
+//! JWTs of an OIDC provider.
+//!
+//! If no provider decides, publishes are allowed and the other requests are rejected with
+//! `401 Unauthorized`.
+
+use std::{fmt, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header::AUTHORIZATION, request::Parts, HeaderMap, Method, StatusCode, Uri};
+use rustls::pki_types::CertificateDer;
+
+use super::{
+    error::{AppError, AppResult},
+    tls::ClientCertificate,
+};
+use crate::api_keys::{ApiKeyScope, ApiKeyStore};
+
+/// A request as seen by an [`AuthProvider`]
+#[derive(Debug)]
+pub struct AuthRequest<'a> {
+    /// What the request needs to be authorized for
+    pub scope: ApiKeyScope,
+    /// The method of the request
+    pub method: &'a Method,
+    /// The URI of the request
+    pub uri: &'a Uri,
+    /// The headers of the request
+    pub headers: &'a HeaderMap,
+    /// The TLS client certificate, verified against [`super::ClientAuthConfig::ca_cert`]
+    pub client_cert: Option<&'a CertificateDer<'static>>,
+}
+
+impl AuthRequest<'_> {
+    /// Get the token of the `Authorization: Bearer <token>` header, if present.
+    pub fn bearer_token(&self) -> Option<&str> {
+        self.headers
+            .get(AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+    }
+}
+
+/// What to do with a request
+#[derive(Debug, Clone)]
+pub enum AuthDecision {
+    /// Serve the request, without running further providers
+    Allow,
+    /// Answer the request with `403 Forbidden` and this reason, without running further providers
+    Deny(String),
+    /// Leave the decision to the next provider, e.g. because the request has no credentials the
+    /// provider knows
+    Pass,
+}
+
+/// A provider that authorizes the requests to the admin endpoints, the change feed and the
+/// publishes
+#[async_trait]
+pub trait AuthProvider: fmt::Debug + Send + Sync + 'static {
+    /// Decide whether `req` is authorized.
+    ///
+    /// An error is answered with `500 Internal Server Error`.
+    async fn authorize(&self, req: &AuthRequest<'_>) -> Result<AuthDecision>;
+}
+
+/// An [`AuthProvider`] that allows the requests with a verified TLS client certificate
+///
+/// Client certificates are only verified if [`super::HttpsConfig::client_auth`] is set.
+#[derive(Debug, Default, Clone)]
+pub struct ClientCertAuth;
+
+#[async_trait]
+impl AuthProvider for ClientCertAuth {
+    async fn authorize(&self, req: &AuthRequest<'_>) -> Result<AuthDecision> {
+        Ok(match req.client_cert {
+            Some(_) => AuthDecision::Allow,
+            None => AuthDecision::Pass,
+        })
+    }
+}
+
+/// An [`AuthProvider`] that allows the requests with an active API key of their scope as bearer
+/// token
+#[derive(Debug, Clone)]
+pub struct ApiKeyAuth {
+    keys: ApiKeyStore,
+}
+
+impl ApiKeyAuth {
+    /// Create the provider for the keys in `keys`.
+    pub fn new(keys: ApiKeyStore) -> Self {
+        Self { keys }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for ApiKeyAuth {
+    async fn authorize(&self, req: &AuthRequest<'_>) -> Result<AuthDecision> {
+        let Some(token) = req.bearer_token() else {
+            return Ok(AuthDecision::Pass);
+        };
+        Ok(match self.keys.verify(token).await? {
+            Some(key) if key.has_scope(req.scope) => AuthDecision::Allow,
+            _ => AuthDecision::Pass,
+        })
+    }
+}
+
+/// An [`AuthProvider`] that allows the requests with one of a fixed set of bearer tokens, e.g.
+/// from a secret of the deployment
+#[derive(derive_more::Debug, Clone)]
+pub struct TokenAuth {
+    scopes: Vec<ApiKeyScope>,
+    #[debug("[{} tokens]", tokens.len())]
+    tokens: Vec<blake3::Hash>,
+}
+
+impl TokenAuth {
+    /// Create the provider for `tokens`, which are valid for `scopes`.
+    ///
+    /// As for API keys, [`ApiKeyScope::Admin`] implies all other scopes.
+    pub fn new(
+        scopes: impl IntoIterator<Item = ApiKeyScope>,
+        tokens: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        Self {
+            scopes: scopes.into_iter().collect(),
+            // compare hashes to not leak the tokens through timing
+            tokens: tokens
+                .into_iter()
+                .map(|token| blake3::hash(token.as_ref().as_bytes()))
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for TokenAuth {
+    async fn authorize(&self, req: &AuthRequest<'_>) -> Result<AuthDecision> {
+        let in_scope = self
+            .scopes
+            .iter()
+            .any(|s| *s == req.scope || *s == ApiKeyScope::Admin);
+        let Some(token) = req.bearer_token().filter(|_| in_scope) else {
+            return Ok(AuthDecision::Pass);
+        };
+        let hash = blake3::hash(token.as_bytes());
+        Ok(match self.tokens.contains(&hash) {
+            true => AuthDecision::Allow,
+            false => AuthDecision::Pass,
+        })
+    }
+}
+
+/// The providers of the HTTP server, in the order they run
+#[derive(Debug, Clone)]
+pub(crate) struct AuthProviders(Arc<Vec<Arc<dyn AuthProvider>>>);
+
+impl AuthProviders {
+    /// Create the built-in providers for the API keys in `keys`, followed by `providers`.
+    pub(crate) fn new(keys: ApiKeyStore, providers: Vec<Arc<dyn AuthProvider>>) -> Self {
+        let builtin: [Arc<dyn AuthProvider>; 2] =
+            [Arc::new(ClientCertAuth), Arc::new(ApiKeyAuth::new(keys))];
+        Self(Arc::new(builtin.into_iter().chain(providers).collect()))
+    }
+
+    /// Check that the request with `parts` is authorized for `scope`.
+    pub(crate) async fn authorize(&self, parts: &Parts, scope: ApiKeyScope) -> AppResult<()> {
+        let client_cert = match parts.extensions.get::<ClientCertificate>() {
+            Some(ClientCertificate(cert)) => cert.as_ref(),
+            None => None,
+        };
+        let auth_req = AuthRequest {
+            scope,
+            method: &parts.method,
+            uri: &parts.uri,
+            headers: &parts.headers,
+            client_cert,
+        };
+        for provider in self.0.iter() {
+            match provider.authorize(&auth_req).await? {
+                AuthDecision::Allow => return Ok(()),
+                AuthDecision::Deny(reason) => {
+                    return Err(AppError::new(StatusCode::FORBIDDEN, Some(reason)))
+                }
+                AuthDecision::Pass => {}
+            }
+        }
+        match scope {
+            ApiKeyScope::Publish => Ok(()),
+            _ => Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                Some(format!(
+                    "a valid TLS client certificate or {scope} API key is required"
+                )),
+            )),
+        }
+    }
+}
+
+/// Middleware that rejects requests that are not authorized for the scope.
+pub(crate) async fn middleware(
+    State((providers, scope)): State<(AuthProviders, ApiKeyScope)>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // the body is not `Sync`, so it can't be borrowed while the providers run
+    let (parts, body) = req.into_parts();
+    match providers.authorize(&parts, scope).await {
+        Ok(()) => next.run(Request::from_parts(parts, body)).await,
+        Err(err) => err.into_response(),
+    }
+}
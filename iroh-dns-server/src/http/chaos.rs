@@ -0,0 +1,99 @@
+//! Fault injection into the HTTP endpoints, and the admin API of the faults
+//!
+//! See [`crate::chaos`].
+
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header::RETRY_AFTER, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Json, Router,
+};
+
+use super::error::{AppError, AppResult};
+use crate::{
+    chaos::{Chaos, ChaosEndpoint, Fault, Faults},
+    state::AppState,
+};
+
+/// Create the router of the faults, nested in the admin router.
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/chaos", get(get_faults).delete(clear_faults))
+        .route(
+            "/chaos/:endpoint",
+            put(set_faults).delete(clear_endpoint_faults),
+        )
+}
+
+/// Inject the faults into the requests to the DoH and pkarr endpoints.
+pub(crate) async fn middleware(State(chaos): State<Chaos>, req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let endpoint = match *req.method() {
+        _ if path == "/dns-query" || path == "/resolve" => ChaosEndpoint::Doh,
+        // the dry run of publishes is not faulted
+        _ if path == "/pkarr/validate" || !path.starts_with("/pkarr/") => {
+            return next.run(req).await
+        }
+        Method::GET => ChaosEndpoint::PkarrGet,
+        Method::PUT => ChaosEndpoint::PkarrPut,
+        _ => return next.run(req).await,
+    };
+    match chaos.inject(endpoint).await {
+        None => next.run(req).await,
+        // held until the request timeout of the server, or until the client gives up
+        Some(Fault::Drop) => std::future::pending().await,
+        Some(Fault::ServFail) => AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some(format!("injected failure of {endpoint}")),
+        )
+        .into_response(),
+        Some(Fault::RateLimit) => {
+            let mut response = AppError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                Some(format!("injected rate limit of {endpoint}")),
+            )
+            .into_response();
+            response.headers_mut().insert(RETRY_AFTER, 1.into());
+            response
+        }
+    }
+}
+
+/// Get the injected faults by endpoint
+pub(crate) async fn get_faults(
+    State(state): State<AppState>,
+) -> Json<BTreeMap<ChaosEndpoint, Faults>> {
+    Json(state.chaos.faults())
+}
+
+/// Set the injected faults of an endpoint
+pub(crate) async fn set_faults(
+    State(state): State<AppState>,
+    Path(endpoint): Path<ChaosEndpoint>,
+    Json(faults): Json<Faults>,
+) -> AppResult<StatusCode> {
+    state
+        .chaos
+        .set(endpoint, faults)
+        .map_err(|err| AppError::new(StatusCode::BAD_REQUEST, Some(format!("{err:#}"))))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Remove the injected faults of all endpoints
+pub(crate) async fn clear_faults(State(state): State<AppState>) -> StatusCode {
+    state.chaos.clear(None);
+    StatusCode::NO_CONTENT
+}
+
+/// Remove the injected faults of an endpoint
+pub(crate) async fn clear_endpoint_faults(
+    State(state): State<AppState>,
+    Path(endpoint): Path<ChaosEndpoint>,
+) -> StatusCode {
+    state.chaos.clear(Some(endpoint));
+    StatusCode::NO_CONTENT
+}
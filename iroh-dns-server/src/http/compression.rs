@@ -0,0 +1,69 @@
+//! Response compression for the HTTP server
+
+use http::{header::CONTENT_TYPE, Extensions, HeaderMap, StatusCode, Version};
+use serde::{Deserialize, Serialize};
+use tower_http::compression::{
+    predicate::{Predicate, SizeAbove},
+    CompressionLayer,
+};
+
+/// Content types of responses that are compressed.
+///
+/// Pkarr signed packets are not compressed: they consist mostly of a signature and compressed
+/// DNS names, and are limited to 1000 bytes anyway.
+const COMPRESSIBLE_CONTENT_TYPES: &[&str] = &[
+    "application/json",
+    "application/dns-json",
+    "application/dns-message",
+    "text/",
+];
+
+/// Config for compression of HTTP responses
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CompressionConfig {
+    /// Set to true to enable gzip and brotli compression, as negotiated via `Accept-Encoding`.
+    pub enabled: bool,
+    /// Minimum size in bytes of responses to compress (defaults to 1024).
+    ///
+    /// Compressing small responses is usually not worth the CPU time.
+    #[serde(default = "CompressionConfig::default_min_size")]
+    pub min_size: u16,
+}
+
+impl CompressionConfig {
+    fn default_min_size() -> u16 {
+        1024
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_size: Self::default_min_size(),
+        }
+    }
+}
+
+/// Create the compression layer, or `None` if compression is disabled.
+pub(crate) fn layer(config: &CompressionConfig) -> Option<CompressionLayer<impl Predicate>> {
+    if !config.enabled {
+        return None;
+    }
+    let predicate = SizeAbove::new(config.min_size).and(is_compressible);
+    Some(
+        CompressionLayer::new()
+            .gzip(true)
+            .br(true)
+            .compress_when(predicate),
+    )
+}
+
+fn is_compressible(_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    COMPRESSIBLE_CONTENT_TYPES
+        .iter()
+        .any(|ty| content_type.starts_with(ty))
+}
@@ -22,12 +22,79 @@ use super::error::AppResult;
 mod extract;
 mod response;
 
-use self::extract::{DnsMimeType, DnsRequestBody, DnsRequestQuery};
+use self::extract::{DnsJsonQuery, DnsMimeType, DnsRequestBody, DnsRequestQuery};
+#[cfg(feature = "openapi")]
+pub(crate) use self::response::{DnsResponse, DohQuestionJson, DohRecordJson};
 
 /// GET handler for resolving DoH queries
+///
+/// The query is either encoded as DNS wire format message in the `dns` parameter (RFC 8484), or
+/// as JSON query parameters if `Accept: application/dns-json` or `ct=application/dns-json` is set,
+/// or if there is a `name` but no `dns` parameter.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/dns-query",
+        tag = "dns",
+        params(
+            ("dns" = Option<String>, Query, description = "Base64url encoded DNS message"),
+            ("name" = Option<String>, Query, description = "Record name to look up (JSON queries)"),
+            ("type" = Option<String>, Query, description = "Record type, e.g. TXT (JSON queries)"),
+            ("ct" = Option<String>, Query, description = "Response type, `application/dns-message` or `application/dns-json`"),
+        ),
+        responses(
+            (status = 200, description = "The DNS response", content(
+                ("application/dns-message" = Vec<u8>),
+                ("application/dns-json" = DnsResponse),
+            )),
+            (status = 400, description = "Invalid query", body = AppError),
+            (status = 406, description = "Unsupported `Accept` header", body = AppError),
+        )
+    )
+)]
 pub async fn get(
     State(state): State<AppState>,
     DnsRequestQuery(request, accept_type): DnsRequestQuery,
+) -> AppResult<Response> {
+    answer(&state, request, accept_type).await
+}
+
+/// GET handler for resolving JSON queries, like the JSON APIs of public resolvers
+///
+/// The response is always JSON, so that the query can be sent from browsers and with `curl`
+/// without any headers, e.g. `/resolve?name=_iroh.<z32-node-id>.<origin>&type=TXT`.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/resolve",
+        tag = "dns",
+        params(
+            ("name" = String, Query, description = "Record name to look up"),
+            ("type" = Option<String>, Query, description = "Record type, e.g. TXT, as name or number (defaults to A)"),
+            ("cd" = Option<bool>, Query, description = "Disable DNSSEC validation"),
+            ("do" = Option<bool>, Query, description = "Include DNSSEC records"),
+        ),
+        responses(
+            (status = 200, description = "The DNS response", body = DnsResponse,
+                content_type = "application/dns-json"),
+            (status = 400, description = "Invalid query", body = AppError),
+        )
+    )
+)]
+pub async fn resolve(
+    State(state): State<AppState>,
+    DnsJsonQuery(request): DnsJsonQuery,
+) -> AppResult<Response> {
+    answer(&state, request, DnsMimeType::Json).await
+}
+
+/// Answer `request`, in the format of `accept_type`.
+async fn answer(
+    state: &AppState,
+    request: hickory_server::server::Request,
+    accept_type: DnsMimeType,
 ) -> AppResult<Response> {
     let message_bytes = state.dns_handler.answer_request(request).await?;
     let message = proto::op::Message::from_bytes(&message_bytes).map_err(|e| anyhow!(e))?;
@@ -56,6 +123,20 @@ pub async fn get(
 }
 
 /// POST handler for resolvng DoH queries
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        post,
+        path = "/dns-query",
+        tag = "dns",
+        request_body(content = Vec<u8>, content_type = "application/dns-message"),
+        responses(
+            (status = 200, description = "The DNS response", body = Vec<u8>,
+                content_type = "application/dns-message"),
+            (status = 400, description = "Invalid query", body = AppError),
+        )
+    )
+)]
 pub async fn post(
     State(state): State<AppState>,
     DnsRequestBody(request): DnsRequestBody,
@@ -54,6 +54,18 @@ impl DnsMimeType {
             Self::Json => "application/dns-json",
         })
     }
+
+    /// Parse a media type, without its parameters.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let media_type = media_type.split(';').next().unwrap_or_default().trim();
+        match media_type {
+            "application/dns-message" => Some(Self::Message),
+            "application/dns-json" | "application/x-javascript" | "application/json" => {
+                Some(Self::Json)
+            }
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -61,6 +73,14 @@ struct DnsMessageQuery {
     dns: String,
 }
 
+/// The parameters that select the format of a GET query
+#[derive(Debug, Deserialize)]
+struct FormatQuery {
+    dns: Option<String>,
+    name: Option<String>,
+    ct: Option<String>,
+}
+
 // See: https://developers.google.com/speed/public-dns/docs/doh/json#supported_parameters
 #[derive(Debug, Deserialize)]
 pub struct DnsQuery {
@@ -89,9 +109,17 @@ pub struct DnsQuery {
 }
 
 /// A DNS request encoded in the query string
+///
+/// The format is the `ct` parameter if it is set, or else the first supported type of the
+/// `Accept` header. Without either, queries with a `name` and without a `dns` parameter are JSON
+/// queries, so that they can be sent without any headers.
 #[derive(Debug)]
 pub struct DnsRequestQuery(pub(crate) DNSRequest, pub(crate) DnsMimeType);
 
+/// A DNS request as JSON query parameters, for the `/resolve` endpoint
+#[derive(Debug)]
+pub struct DnsJsonQuery(pub(crate) DNSRequest);
+
 /// A DNS request encoded in the body
 #[derive(Debug)]
 pub struct DnsRequestBody(pub(crate) DNSRequest);
@@ -105,23 +133,65 @@ where
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let ConnectInfo(src_addr) = ConnectInfo::from_request_parts(parts, state).await?;
-
-        match parts.headers.get(header::ACCEPT) {
-            Some(content_type) if content_type == "application/dns-message" => {
-                handle_dns_message_query(parts, state, src_addr).await
+        let Query(format) = Query::<FormatQuery>::from_request_parts(parts, state).await?;
+
+        let mime_type = match format.ct.as_deref() {
+            Some(ct) => DnsMimeType::from_media_type(ct)
+                .ok_or_else(|| AppError::with_status(StatusCode::NOT_ACCEPTABLE))?,
+            None => {
+                let accept = parts
+                    .headers
+                    .get(header::ACCEPT)
+                    .and_then(|value| value.to_str().ok());
+                let accepted = accept
+                    .and_then(|accept| accept.split(',').find_map(DnsMimeType::from_media_type));
+                match accepted {
+                    Some(mime_type) => mime_type,
+                    // a missing or any type is answered in the format of the query
+                    None if accepts_any(accept) => {
+                        if format.name.is_some() && format.dns.is_none() {
+                            DnsMimeType::Json
+                        } else {
+                            DnsMimeType::Message
+                        }
+                    }
+                    None => return Err(AppError::with_status(StatusCode::NOT_ACCEPTABLE)),
+                }
             }
-            Some(content_type) if content_type == "application/dns-json" => {
-                handle_dns_json_query(parts, state, src_addr).await
-            }
-            Some(content_type) if content_type == "application/x-javascript" => {
-                handle_dns_json_query(parts, state, src_addr).await
-            }
-            None => handle_dns_message_query(parts, state, src_addr).await,
-            _ => Err(AppError::with_status(StatusCode::NOT_ACCEPTABLE)),
+        };
+        match mime_type {
+            DnsMimeType::Message => handle_dns_message_query(parts, state, src_addr).await,
+            DnsMimeType::Json => handle_dns_json_query(parts, state, src_addr).await,
         }
     }
 }
 
+#[async_trait]
+impl<S> FromRequestParts<S> for DnsJsonQuery
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(src_addr) = ConnectInfo::from_request_parts(parts, state).await?;
+        let DnsRequestQuery(request, _) = handle_dns_json_query(parts, state, src_addr).await?;
+        Ok(DnsJsonQuery(request))
+    }
+}
+
+/// Whether an `Accept` header accepts any type, which is also the case without the header.
+fn accepts_any(accept: Option<&str>) -> bool {
+    accept.is_none_or(|accept| {
+        accept.split(',').any(|media_type| {
+            matches!(
+                media_type.split(';').next().unwrap_or_default().trim(),
+                "*/*" | "application/*"
+            )
+        })
+    })
+}
+
 #[async_trait]
 impl<S> FromRequest<S> for DnsRequestBody
 where
@@ -6,8 +6,11 @@
 use anyhow::{ensure, Result};
 use hickory_proto as proto;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
 /// JSON representation of a DNS response
 /// See: <https://developers.google.com/speed/public-dns/docs/doh/json>
 pub struct DnsResponse {
@@ -93,6 +96,7 @@ impl DnsResponse {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
 /// JSON representation of a DNS question
 pub struct DohQuestionJson {
     /// FQDN with trailing dot
@@ -113,6 +117,7 @@ impl DohQuestionJson {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
 /// JSON representation of a DNS record
 pub struct DohRecordJson {
     /// FQDN with trailing dot
@@ -5,13 +5,20 @@ use axum::{
     Json,
 };
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
 
 pub type AppResult<T> = Result<T, AppError>;
 
+/// An error response
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
 pub struct AppError {
+    /// The HTTP status code
     #[serde(with = "serde_status_code")]
+    #[cfg_attr(feature = "openapi", schema(value_type = String, example = "400"))]
     status: StatusCode,
+    /// Details about the error
     detail: Option<String>,
 }
 
@@ -0,0 +1,211 @@
+//! Client addresses from reverse proxy headers
+//!
+//! When running behind a reverse proxy, the connection peer is the proxy. With a
+//! [`BehindProxyConfig`], the `Forwarded` (RFC 7239) or `X-Forwarded-For`, `X-Forwarded-Proto`
+//! and `X-Forwarded-Host` headers of requests from the trusted proxies are used instead. The
+//! client address replaces the [`ConnectInfo`] of the request, so that rate limiting, the access
+//! log, tracing and DoH all see the same address.
+
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use http::{header::HOST, HeaderMap, HeaderName};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use super::tls::ClientCertificate;
+
+const FORWARDED: HeaderName = HeaderName::from_static("forwarded");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Config for running behind reverse proxies
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BehindProxyConfig {
+    /// Networks of the trusted reverse proxies, in CIDR notation (e.g. `10.0.0.0/8`)
+    ///
+    /// Proxy headers are only read from requests whose connection peer is in one of these
+    /// networks. In a chain of proxies, the client is the last address that is not trusted.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl BehindProxyConfig {
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&addr))
+    }
+}
+
+/// The scheme and host the client used for the request, as seen by the first proxy.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestOrigin {
+    /// `http` or `https`
+    pub(crate) proto: String,
+    /// The host, from the `Host` header if not forwarded
+    pub(crate) host: Option<String>,
+}
+
+impl RequestOrigin {
+    /// The base URL of the server, if the host is known.
+    #[cfg_attr(not(feature = "openapi"), allow(dead_code))]
+    pub(crate) fn base_url(&self) -> Option<String> {
+        self.host
+            .as_ref()
+            .map(|host| format!("{}://{host}", self.proto))
+    }
+}
+
+/// Middleware that sets the client address and the [`RequestOrigin`] of requests.
+///
+/// Without a config, the proxy headers are ignored, and only the [`RequestOrigin`] is set.
+pub(crate) async fn middleware(
+    State(config): State<Option<BehindProxyConfig>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    // the TLS acceptors add the client certificate extension, also if there is no certificate
+    let proto = match req.extensions().get::<ClientCertificate>() {
+        Some(_) => "https",
+        None => "http",
+    };
+    let mut origin = RequestOrigin {
+        proto: proto.to_string(),
+        host: header_str(req.headers(), &HOST).map(ToString::to_string),
+    };
+    if let Some(config) = config.filter(|c| c.is_trusted(peer.ip())) {
+        let forwarded = Forwarded::parse(req.headers());
+        if let Some(client) = forwarded.client(&config) {
+            req.extensions_mut().insert(ConnectInfo(client));
+        }
+        if let Some(proto) = forwarded.proto {
+            origin.proto = proto;
+        }
+        if let Some(host) = forwarded.host {
+            origin.host = Some(host);
+        }
+    }
+    req.extensions_mut().insert(origin);
+    next.run(req).await
+}
+
+/// The information from the proxy headers.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Forwarded {
+    /// The addresses of the clients and proxies, the client first
+    chain: Vec<SocketAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+impl Forwarded {
+    /// Parse the `Forwarded` header, or the `X-Forwarded-*` headers if it is not present.
+    ///
+    /// The protocol and host are taken from the last element, added by the nearest proxy.
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut forwarded = Self::default();
+        let elements: Vec<&str> = headers
+            .get_all(FORWARDED)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+        if !elements.is_empty() {
+            for element in elements {
+                for pair in element.split(';') {
+                    let Some((key, value)) = pair.trim().split_once('=') else {
+                        continue;
+                    };
+                    let value = value.trim_matches('"');
+                    match key.to_ascii_lowercase().as_str() {
+                        "for" => forwarded.chain.extend(parse_addr(value)),
+                        "proto" => forwarded.proto = Some(value.to_ascii_lowercase()),
+                        "host" => forwarded.host = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+            }
+            return forwarded;
+        }
+        forwarded.chain = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|v| parse_addr(v.trim()))
+            .collect();
+        let last = |name: HeaderName| {
+            header_str(headers, &name)
+                .and_then(|v| v.rsplit(',').next())
+                .map(|v| v.trim().to_string())
+        };
+        forwarded.proto = last(X_FORWARDED_PROTO).map(|v| v.to_ascii_lowercase());
+        forwarded.host = last(X_FORWARDED_HOST);
+        forwarded
+    }
+
+    /// Get the client address: the last address in the chain that is not a trusted proxy.
+    fn client(&self, config: &BehindProxyConfig) -> Option<SocketAddr> {
+        self.chain
+            .iter()
+            .rev()
+            .find(|addr| !config.is_trusted(addr.ip()))
+            .or(self.chain.first())
+            .copied()
+    }
+}
+
+/// Parse an address with an optional port, e.g. `192.0.2.1`, `[2001:db8::1]:4711`.
+fn parse_addr(value: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = value.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_headers() {
+        let config = BehindProxyConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            FORWARDED,
+            r#"for=198.51.100.7, for="[2001:db8::1]:4711";proto=http, for=10.0.0.2;proto=https;host=dns.example.org"#
+                .parse()
+                .unwrap(),
+        );
+        let forwarded = Forwarded::parse(&headers);
+        assert_eq!(
+            forwarded.client(&config),
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+        assert_eq!(forwarded.proto.as_deref(), Some("https"));
+        assert_eq!(forwarded.host.as_deref(), Some("dns.example.org"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, "198.51.100.7, 10.0.0.3".parse().unwrap());
+        headers.insert(X_FORWARDED_PROTO, "https".parse().unwrap());
+        let forwarded = Forwarded::parse(&headers);
+        assert_eq!(
+            forwarded.client(&config),
+            Some("198.51.100.7:0".parse().unwrap())
+        );
+        assert_eq!(forwarded.proto.as_deref(), Some("https"));
+        assert_eq!(forwarded.host, None);
+    }
+}
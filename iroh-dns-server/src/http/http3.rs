@@ -0,0 +1,273 @@
+//! Serve the HTTP app over HTTP/3
+//!
+//! The HTTP/3 server shares the port and TLS certificates with the HTTPS server, but listens on
+//! UDP. Clients are pointed to it with an `Alt-Svc` header on HTTPS responses.
+//!
+//! The connections count against the same limits as those of the HTTPS server, but connections
+//! above a limit are refused, as there is no connection to answer with `503` on. Each connection
+//! runs a limited number of requests at a time, and their bodies must arrive within the request
+//! timeout.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use axum::{body::Body, extract::ConnectInfo, Router};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_lite::StreamExt;
+use h3::server::RequestStream;
+use http::{HeaderValue, Request, Response, StatusCode};
+use rustls::pki_types::CertificateDer;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+use super::{limits::HttpLimitsConfig, tls::ClientCertificate};
+use crate::{
+    abuse::IpReputation,
+    metrics::{AbuseMetrics, LimitedResource, LoadShedMetrics},
+    util,
+};
+
+/// Maximum size of a request body received over HTTP/3.
+///
+/// Pkarr signed packets are at most 1000 bytes and DNS messages are at most 64KiB.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// ALPN protocol identifier for HTTP/3.
+const ALPN_H3: &[u8] = b"h3";
+
+/// Maximum number of concurrent requests on a connection.
+const MAX_CONCURRENT_REQUESTS: u32 = 100;
+
+/// Create the `Alt-Svc` header value that advertises HTTP/3 on `port`.
+pub(crate) fn alt_svc_header(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{port}\"; ma=86400")).expect("valid header value")
+}
+
+/// Bind a QUIC endpoint for HTTP/3 on `bind_addr`, which closes connections that are idle for
+/// the idle timeout of `limits`.
+pub(crate) fn bind(
+    bind_addr: SocketAddr,
+    tls_config: Arc<rustls::ServerConfig>,
+    limits: &HttpLimitsConfig,
+) -> Result<quinn::Endpoint> {
+    let mut tls_config = (*tls_config).clone();
+    tls_config.alpn_protocols = vec![ALPN_H3.to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls_config)
+        .context("invalid TLS config for HTTP/3")?;
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(MAX_CONCURRENT_REQUESTS.into())
+        .max_idle_timeout(Some(limits.idle_timeout().try_into()?));
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(transport));
+    let socket = util::bind_udp(bind_addr)?;
+    let endpoint = quinn::Endpoint::new(
+        Default::default(),
+        Some(server_config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+    Ok(endpoint)
+}
+
+/// Accept HTTP/3 connections on `endpoint` and serve requests with `app`.
+///
+/// Connections above the `max_connections` of `limits` or above `total_connections`, which is
+/// shared with the other listeners, are refused. When `cancel` is triggered, stops accepting
+/// connections and waits for open connections to finish their in-flight requests.
+pub(crate) async fn serve(
+    endpoint: quinn::Endpoint,
+    app: Router,
+    ip_reputation: IpReputation,
+    limits: HttpLimitsConfig,
+    total_connections: Option<Arc<Semaphore>>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    info!("HTTP/3 server listening on {}", endpoint.local_addr()?);
+    let connections = limits
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+    let request_timeout = limits.request_timeout();
+    let tasks = TaskTracker::new();
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = cancel.cancelled() => break,
+        };
+        if ip_reputation.is_listed(incoming.remote_address().ip()) {
+            AbuseMetrics::count_dropped("http");
+            incoming.ignore();
+            continue;
+        }
+        let Some(permits) = acquire(&connections, &total_connections) else {
+            incoming.refuse();
+            continue;
+        };
+        let app = app.clone();
+        let cancel = cancel.clone();
+        let request_tasks = tasks.clone();
+        tasks.spawn(async move {
+            let res = handle_connection(incoming, app, request_timeout, cancel, request_tasks);
+            if let Err(err) = res.await {
+                debug!("HTTP/3 connection closed with error: {err:#}");
+            }
+            drop(permits);
+        });
+    }
+    tasks.close();
+    tasks.wait().await;
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Acquire a permit of each limit, or `None` if a limit is reached.
+fn acquire(
+    connections: &Option<Arc<Semaphore>>,
+    total_connections: &Option<Arc<Semaphore>>,
+) -> Option<[Option<OwnedSemaphorePermit>; 2]> {
+    let permit = match connections {
+        None => None,
+        Some(connections) => match connections.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                debug!("HTTP/3 connection limit reached, refusing connection");
+                return None;
+            }
+        },
+    };
+    let total_permit = match total_connections {
+        None => None,
+        Some(connections) => match connections.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                LoadShedMetrics::count(LimitedResource::HttpConnections);
+                debug!("total connection limit reached, refusing HTTP/3 connection");
+                return None;
+            }
+        },
+    };
+    Some([permit, total_permit])
+}
+
+async fn handle_connection(
+    incoming: quinn::Incoming,
+    app: Router,
+    request_timeout: Duration,
+    cancel: CancellationToken,
+    request_tasks: TaskTracker,
+) -> Result<()> {
+    let conn = incoming.await?;
+    let remote_addr = conn.remote_address();
+    let client_cert = ClientCertificate(
+        conn.peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|certs| certs.into_iter().next()),
+    );
+    let mut conn = h3::server::Connection::new(h3_quinn::Connection::new(conn)).await?;
+    let mut shutting_down = false;
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept() => accepted,
+            _ = cancel.cancelled(), if !shutting_down => {
+                // send GOAWAY, `accept` returns `None` once the in-flight requests are done
+                shutting_down = true;
+                conn.shutdown(0).await?;
+                continue;
+            }
+        };
+        match accepted {
+            Ok(Some(resolver)) => {
+                let app = app.clone();
+                let client_cert = client_cert.clone();
+                request_tasks.spawn(async move {
+                    let (req, stream) = match resolver.resolve_request().await {
+                        Ok(req) => req,
+                        Err(err) => {
+                            debug!("failed to receive HTTP/3 request: {err}");
+                            return;
+                        }
+                    };
+                    let res =
+                        handle_request(req, stream, app, request_timeout, remote_addr, client_cert);
+                    if let Err(err) = res.await {
+                        warn!("failed to handle HTTP/3 request: {err:#}");
+                    }
+                });
+            }
+            Ok(None) => break,
+            Err(err) if err.is_h3_no_error() => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+async fn handle_request<S>(
+    req: Request<()>,
+    mut stream: RequestStream<S, Bytes>,
+    app: Router,
+    request_timeout: Duration,
+    remote_addr: SocketAddr,
+    client_cert: ClientCertificate,
+) -> Result<()>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let body = match tokio::time::timeout(request_timeout, recv_body(&mut stream)).await {
+        Ok(Ok(Some(body))) => body,
+        Ok(Ok(None)) => return send_status(stream, StatusCode::PAYLOAD_TOO_LARGE).await,
+        Ok(Err(err)) => return Err(err),
+        Err(_) => {
+            debug!("HTTP/3 request body not received within {request_timeout:?}");
+            return send_status(stream, StatusCode::REQUEST_TIMEOUT).await;
+        }
+    };
+
+    let (parts, ()) = req.into_parts();
+    let mut req = Request::from_parts(parts, Body::from(body));
+    req.extensions_mut().insert(ConnectInfo(remote_addr));
+    req.extensions_mut().insert(client_cert);
+
+    let response = app.oneshot(req).await?;
+    let (parts, body) = response.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+    let mut body = body.into_data_stream();
+    while let Some(chunk) = body.next().await {
+        stream.send_data(chunk?).await?;
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Receive the request body, or `None` if it is larger than [`MAX_BODY_SIZE`].
+async fn recv_body<S>(stream: &mut RequestStream<S, Bytes>) -> Result<Option<Bytes>>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        if body.len() + chunk.remaining() > MAX_BODY_SIZE {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(Some(body.freeze()))
+}
+
+/// Answer a request with `status` and an empty body.
+async fn send_status<S>(mut stream: RequestStream<S, Bytes>, status: StatusCode) -> Result<()>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let response = Response::builder().status(status).body(())?;
+    stream.send_response(response).await?;
+    stream.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,294 @@
+//! Timeouts and connection limits for the HTTP and HTTPS servers
+//!
+//! These protect the servers against slow clients (slowloris) that open many connections and
+//! send their requests byte by byte, to exhaust the available sockets.
+//!
+//! The connections over all listeners are limited by
+//! [`ResourceLimitsConfig::max_http_connections`](crate::config::ResourceLimitsConfig). Requests
+//! on connections above that limit are rejected with `503 Service Unavailable`, which tells
+//! clients to back off, unlike a closed connection.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode, Version},
+    middleware::{AddExtension, Next},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_server::accept::Accept;
+use futures_lite::{future::Boxed as BoxFuture, FutureExt};
+use hyper_util::rt::TokioTimer;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{Instant, Sleep},
+};
+use tower::Layer;
+use tracing::debug;
+
+use crate::metrics::{LimitedResource, LoadShedMetrics};
+
+/// Config for timeouts and connection limits of the HTTP and HTTPS servers
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpLimitsConfig {
+    /// Time in seconds that clients have to send the request headers (defaults to 10).
+    #[serde(default = "HttpLimitsConfig::default_header_read_timeout_secs")]
+    pub header_read_timeout_secs: u64,
+    /// Time in seconds after which a request is answered with `408 Request Timeout`, including
+    /// the time to read the request body (defaults to 30).
+    #[serde(default = "HttpLimitsConfig::default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Time in seconds after which connections without any reads or writes are closed (defaults
+    /// to 60).
+    ///
+    /// Should be larger than [`Self::request_timeout_secs`], so that requests time out before
+    /// their connection.
+    #[serde(default = "HttpLimitsConfig::default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Maximum number of concurrent connections per listener (unlimited if unset).
+    ///
+    /// Connections above the limit are closed right after they are accepted.
+    pub max_connections: Option<usize>,
+}
+
+impl HttpLimitsConfig {
+    fn default_header_read_timeout_secs() -> u64 {
+        10
+    }
+
+    fn default_request_timeout_secs() -> u64 {
+        30
+    }
+
+    fn default_idle_timeout_secs() -> u64 {
+        60
+    }
+
+    pub(crate) fn header_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.header_read_timeout_secs)
+    }
+
+    pub(crate) fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub(crate) fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for HttpLimitsConfig {
+    fn default() -> Self {
+        Self {
+            header_read_timeout_secs: Self::default_header_read_timeout_secs(),
+            request_timeout_secs: Self::default_request_timeout_secs(),
+            idle_timeout_secs: Self::default_idle_timeout_secs(),
+            max_connections: None,
+        }
+    }
+}
+
+/// Configure the header read timeout on an axum-server.
+pub(crate) fn configure_server<A>(server: &mut axum_server::Server<A>, config: &HttpLimitsConfig) {
+    server
+        .http_builder()
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(config.header_read_timeout());
+}
+
+/// Whether a connection was accepted above the limit of connections over all listeners
+///
+/// Added as a request extension by [`LimitsAcceptor`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Overloaded(bool);
+
+/// Answer requests on connections above the limit of connections over all listeners with
+/// `503 Service Unavailable`.
+pub(crate) async fn load_shed_middleware(req: Request, next: Next) -> Response {
+    let Some(Overloaded(true)) = req.extensions().get::<Overloaded>() else {
+        return next.run(req).await;
+    };
+    LoadShedMetrics::count(LimitedResource::HttpConnections);
+    let mut response = (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "1")],
+        "server overloaded",
+    )
+        .into_response();
+    // connection-specific headers are not allowed in HTTP/2
+    if req.version() <= Version::HTTP_11 {
+        response
+            .headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
+/// An [`Accept`] that limits the number of concurrent connections, and closes idle
+/// connections.
+#[derive(Debug, Clone)]
+pub(crate) struct LimitsAcceptor<A> {
+    inner: A,
+    connections: Option<Arc<Semaphore>>,
+    /// The connections over all listeners, shared by their acceptors
+    total_connections: Option<Arc<Semaphore>>,
+    idle_timeout: Duration,
+}
+
+impl<A> LimitsAcceptor<A> {
+    pub(crate) fn new(
+        inner: A,
+        config: &HttpLimitsConfig,
+        total_connections: Option<Arc<Semaphore>>,
+    ) -> Self {
+        Self {
+            inner,
+            connections: config
+                .max_connections
+                .map(|max| Arc::new(Semaphore::new(max))),
+            total_connections,
+            idle_timeout: config.idle_timeout(),
+        }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for LimitsAcceptor<A>
+where
+    A: Accept<TcpStream, AddExtension<S, Overloaded>> + Clone + Send + 'static,
+    A::Future: Send,
+    S: Send + 'static,
+{
+    type Stream = LimitedStream<A::Stream>;
+    type Service = A::Service;
+    type Future = BoxFuture<io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let permit = match &self.connections {
+            None => None,
+            Some(connections) => match connections.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    debug!("connection limit reached, closing connection");
+                    let err = io::Error::other("connection limit reached");
+                    return async move { Err(err) }.boxed();
+                }
+            },
+        };
+        let total_permit = match &self.total_connections {
+            None => None,
+            Some(connections) => connections.clone().try_acquire_owned().ok(),
+        };
+        let overloaded = self.total_connections.is_some() && total_permit.is_none();
+        if overloaded {
+            debug!("total connection limit reached, rejecting requests on connection");
+        }
+        let service = Extension(Overloaded(overloaded)).layer(service);
+        let inner = self.inner.clone();
+        let idle_timeout = self.idle_timeout;
+        async move {
+            let (stream, service) = inner.accept(stream, service).await?;
+            let permits = [permit, total_permit];
+            Ok((LimitedStream::new(stream, idle_timeout, permits), service))
+        }
+        .boxed()
+    }
+}
+
+/// A stream that fails with [`io::ErrorKind::TimedOut`] if there are no reads or writes for
+/// the idle timeout, and holds the connection permits until it is dropped.
+#[derive(Debug)]
+pub(crate) struct LimitedStream<S> {
+    inner: S,
+    idle_timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+    _permits: [Option<OwnedSemaphorePermit>; 2],
+}
+
+impl<S> LimitedStream<S> {
+    fn new(inner: S, idle_timeout: Duration, permits: [Option<OwnedSemaphorePermit>; 2]) -> Self {
+        Self {
+            inner,
+            idle_timeout,
+            idle: Box::pin(tokio::time::sleep(idle_timeout)),
+            _permits: permits,
+        }
+    }
+
+    /// Reset the idle timer if `poll` made progress, or fail if the timer elapsed.
+    fn check_idle<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            let deadline = Instant::now() + self.idle_timeout;
+            self.idle.as_mut().reset(deadline);
+            return poll;
+        }
+        match self.idle.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "connection idle timeout",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for LimitedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.check_idle(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for LimitedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.check_idle(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.check_idle(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
@@ -0,0 +1,150 @@
+//! Serve the HTTP app as a Tor onion service
+//!
+//! With the `tor` feature, the server runs an embedded Tor client (arti) and launches an onion
+//! service for the HTTP app, so that clients can publish and resolve over Tor without revealing
+//! their IP address to the operator. The keys and state of the Tor client are kept in `tor/` in
+//! the data directory, so the onion address stays the same across restarts. It is logged once the
+//! service is launched, and written to `tor/hostname`.
+
+use serde::{Deserialize, Serialize};
+
+/// The default nickname of the onion service.
+#[cfg(feature = "tor")]
+const DEFAULT_NICKNAME: &str = "iroh-dns-server";
+/// The default virtual port of the onion service.
+#[cfg(feature = "tor")]
+const DEFAULT_PORT: u16 = 80;
+
+/// Config for the onion service of the HTTP server (requires the `tor` feature)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OnionServiceConfig {
+    /// The nickname of the service, which names its keys (defaults to `iroh-dns-server`)
+    pub nickname: Option<String>,
+    /// The virtual port of the service (defaults to 80)
+    pub port: Option<u16>,
+}
+
+#[cfg(feature = "tor")]
+pub(crate) use self::service::serve;
+
+#[cfg(feature = "tor")]
+mod service {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        path::PathBuf,
+        pin::pin,
+    };
+
+    use anyhow::{Context, Result};
+    use arti_client::{config::TorClientConfigBuilder, TorClient};
+    use axum::{extract::ConnectInfo, Router};
+    use futures_lite::StreamExt;
+    use hyper::body::Incoming;
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto::Builder,
+    };
+    use safelog::DisplayRedacted;
+    use tokio_util::{sync::CancellationToken, task::TaskTracker};
+    use tor_cell::relaycell::msg::Connected;
+    use tor_hsservice::{config::OnionServiceConfigBuilder, StreamRequest};
+    use tor_proto::stream::IncomingStreamRequest;
+    use tower::ServiceExt;
+    use tracing::{debug, info};
+
+    use super::{OnionServiceConfig, DEFAULT_NICKNAME, DEFAULT_PORT};
+
+    /// The peer address reported for connections of the onion service.
+    ///
+    /// Like for the unix socket, the clients have no address, so they share the rate limits of
+    /// the loopback address.
+    const ONION_PEER_ADDR: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+    /// Launch the onion service of `config` and serve requests with `app`.
+    ///
+    /// Bootstrapping the Tor client can take a while, so this runs in a task of the HTTP server.
+    /// When `cancel` is triggered, stops accepting streams and waits for open connections to
+    /// finish their in-flight requests.
+    pub(crate) async fn serve(
+        config: OnionServiceConfig,
+        data_dir: PathBuf,
+        app: Router,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        let port = config.port.unwrap_or(DEFAULT_PORT);
+        let dir = data_dir.join("tor");
+        let client = TorClient::create_bootstrapped(
+            TorClientConfigBuilder::from_directories(dir.join("state"), dir.join("cache"))
+                .build()
+                .context("invalid Tor client config")?,
+        )
+        .await
+        .context("failed to bootstrap the Tor client")?;
+        let nickname = config.nickname.as_deref().unwrap_or(DEFAULT_NICKNAME);
+        let service_config = OnionServiceConfigBuilder::default()
+            .nickname(nickname.parse().context("invalid onion service nickname")?)
+            .build()
+            .context("invalid onion service config")?;
+        let (service, requests) = client
+            .launch_onion_service(service_config)
+            .context("failed to launch the onion service")?
+            .context("the onion service is disabled")?;
+        let mut requests = pin!(tor_hsservice::handle_rend_requests(requests));
+        match service.onion_address() {
+            Some(address) => {
+                let address = address.display_unredacted().to_string();
+                info!("HTTP onion service at http://{address}:{port}");
+                std::fs::write(dir.join("hostname"), format!("{address}\n"))?;
+            }
+            None => info!("HTTP onion service launched"),
+        }
+        let connections = TaskTracker::new();
+        loop {
+            let request: StreamRequest = tokio::select! {
+                request = requests.next() => match request {
+                    Some(request) => request,
+                    None => break,
+                },
+                _ = cancel.cancelled() => break,
+            };
+            // only accept streams to the port of the service, like other onion services
+            if !matches!(request.request(), IncomingStreamRequest::Begin(begin) if begin.port() == port)
+            {
+                request.shutdown_circuit().ok();
+                continue;
+            }
+            let app = app.clone();
+            let cancel = cancel.clone();
+            connections.spawn(async move {
+                let stream = match request.accept(Connected::new_empty()).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        debug!("failed to accept onion service stream: {err:#}");
+                        return;
+                    }
+                };
+                let service =
+                    hyper::service::service_fn(move |mut req: http::Request<Incoming>| {
+                        req.extensions_mut().insert(ConnectInfo(ONION_PEER_ADDR));
+                        app.clone().oneshot(req)
+                    });
+                let builder = Builder::new(TokioExecutor::new());
+                let mut conn = pin!(builder.serve_connection(TokioIo::new(stream), service));
+                let res = tokio::select! {
+                    res = conn.as_mut() => res,
+                    _ = cancel.cancelled() => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(err) = res {
+                    debug!("onion service connection closed with error: {err:#}");
+                }
+            });
+        }
+        connections.close();
+        connections.wait().await;
+        Ok(())
+    }
+}
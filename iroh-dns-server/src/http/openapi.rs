@@ -0,0 +1,113 @@
+//! OpenAPI document of the HTTP API
+//!
+//! The document is derived from the `#[utoipa::path]` annotations on the handlers and served at
+//! `/openapi.json`.
+
+use axum::{Extension, Json};
+use utoipa::{openapi::Server, OpenApi};
+
+use super::{admin, analytics, doh, error::AppError, forwarded::RequestOrigin, pkarr, sync, tls};
+use crate::{api_keys, dns, telemetry};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "iroh-dns-server"),
+    paths(
+        pkarr::put,
+        pkarr::validate,
+        pkarr::get,
+        doh::get,
+        doh::post,
+        doh::resolve,
+        super::healthcheck,
+        super::readyz,
+        sync::get,
+        analytics::get,
+        admin::status,
+        admin::stats,
+        admin::db_stats,
+        admin::db_gc,
+        admin::list_packets,
+        admin::get_packet,
+        admin::remove_packet,
+        admin::history,
+        admin::history_at,
+        admin::usage,
+        admin::hourly_stats,
+        admin::tail,
+        admin::events,
+        admin::dashboard,
+        admin::list_api_keys,
+        admin::create_api_key,
+        admin::rotate_api_key,
+        admin::revoke_api_key,
+        admin::traffic,
+        admin::get_log_filter,
+        admin::set_log_filter,
+        admin::reset_log_filter,
+    ),
+    components(schemas(
+        AppError,
+        pkarr::PublishDiagnostics,
+        pkarr::PublishCheck,
+        doh::DnsResponse,
+        doh::DohQuestionJson,
+        doh::DohRecordJson,
+        admin::Status,
+        admin::StoreStatus,
+        admin::DhtStatus,
+        admin::LookupCounts,
+        admin::PacketList,
+        admin::StoredPacket,
+        admin::PacketRecords,
+        admin::Stats,
+        admin::Counters,
+        admin::RecentPublish,
+        admin::CreateApiKey,
+        admin::SetLogFilter,
+        admin::Traffic,
+        dns::traffic::TrafficWindow,
+        dns::traffic::TopEntry,
+        telemetry::LogFilterStatus,
+        api_keys::ApiKey,
+        api_keys::ApiKeyScope,
+        api_keys::NewApiKey,
+        tls::CertStatus,
+        tls::CertMode,
+        crate::sync::SyncBatch,
+        crate::analytics::Resolutions,
+        crate::analytics::DayResolutions,
+        crate::history::History,
+        crate::history::PacketVersion,
+        crate::usage::UsageReport,
+        crate::usage::UsageRow,
+        crate::usage::UsageFormat,
+        crate::hourly_stats::HourlyStatsReport,
+        crate::hourly_stats::HourStats,
+        crate::db::DbStats,
+        crate::db::SizeBucket,
+        crate::retention::GcReport,
+        crate::retention::ExpiredPacket,
+    )),
+    tags(
+        (name = "pkarr", description = "Pkarr relay API for publishing and resolving signed packets"),
+        (name = "dns", description = "DNS over HTTPS (RFC 8484)"),
+        (name = "sync", description = "Packet change feed for other servers, requires a TLS client certificate or a sync API key"),
+        (name = "analytics", description = "Resolution counts of a key, for requests signed by the key"),
+        (name = "admin", description = "Admin API, requires a TLS client certificate, an admin API key or an admin token"),
+    )
+)]
+struct ApiDoc;
+
+/// Get the OpenAPI document of the HTTP API
+///
+/// The document lists the URL the client used to reach the server.
+pub(crate) async fn get(
+    origin: Option<Extension<RequestOrigin>>,
+) -> Json<utoipa::openapi::OpenApi> {
+    let mut doc = ApiDoc::openapi();
+    if let Some(url) = origin.and_then(|Extension(origin)| origin.base_url()) {
+        doc.servers = Some(vec![Server::new(url)]);
+    }
+    Json(doc)
+}
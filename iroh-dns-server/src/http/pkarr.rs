@@ -1,51 +1,469 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
 use anyhow::Result;
-use axum::extract::Path;
-use axum::{extract::State, response::IntoResponse};
+use axum::extract::{ConnectInfo, Path, Query};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+};
+use axum::{Extension, Json};
 use bytes::Bytes;
 
-use http::{header, StatusCode};
-
+use hickory_proto::rr::{LowerName, Name};
+use http::{header, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
 use tracing::info;
+use url::Url;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
 
+use crate::abuse::AbuseKind;
+use crate::analytics::Transport;
+use crate::hourly_stats::Counter;
+use crate::metrics::{DnsMetrics, TenantMetrics};
+use crate::ring::{Ring, FORWARDED_HEADER};
+use crate::slow_log::Timings;
+use crate::tenants::{Tenant, TenantRejected};
 use crate::util::PublicKeyBytes;
-use crate::{state::AppState, store::PacketSource};
+use crate::{
+    state::AppState,
+    store::{PacketSource, TimestampSkewed},
+};
 
-use super::error::AppError;
+use super::{error::AppError, forwarded::RequestOrigin, PacketRejected};
 
+/// Publish a pkarr signed packet
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        put,
+        path = "/pkarr/{key}",
+        tag = "pkarr",
+        params(("key" = String, Path, description = "z-base-32 encoded public key")),
+        request_body(
+            content = Vec<u8>,
+            content_type = "application/pkarr.org/relays#payload",
+            description = "Signature, timestamp and encoded DNS packet, as specified by the pkarr relay spec"
+        ),
+        responses(
+            (status = 204, description = "The packet was accepted"),
+            (status = 400, description = "Invalid key or payload, or a timestamp too far from the server clock", body = AppError),
+            (status = 401, description = "Client certificate required", body = AppError),
+            (status = 403, description = "The server is a read-only secondary, or a validator rejected the packet", body = AppError),
+            (status = 429, description = "Rate limited", body = AppError),
+            (status = 502, description = "The server that owns the key on the ring failed", body = AppError),
+        )
+    )
+)]
 pub async fn put(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    origin: Option<Extension<RequestOrigin>>,
+    headers: HeaderMap,
     Path(key): Path<String>,
     body: Bytes,
-) -> Result<impl IntoResponse, AppError> {
-    let key = pkarr::PublicKey::try_from(key.as_str())
-        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
+) -> Result<Response, AppError> {
+    let host = request_host(origin.as_ref().map(|o| &o.0));
+    let zone = publish_zone(&state, host.as_ref());
+    let start = Instant::now();
+    let mut timings = Timings::default();
+    if let Some((ring, owner, pubkey)) = remote_owner(&state, &headers, &key) {
+        let res = ring.forward_publish(owner, &pubkey, body).await;
+        timings.ring += start.elapsed();
+        state
+            .store
+            .slow_log()
+            .publish(&key, start.elapsed(), &timings);
+        let outcome = if res.is_ok() { "forwarded" } else { "error" };
+        DnsMetrics::count_publish(zone, outcome);
+        // forwarded publishes are counted by their owner
+        if res.is_err() {
+            record_publish(&state, outcome);
+        }
+        return res
+            .map(|response| response.map(axum::body::Body::from).into_response())
+            .map_err(|err| AppError::new(StatusCode::BAD_GATEWAY, Some(err)));
+    }
+    let api_key = publish_api_key(&state, &headers).await?;
+    let tenant = state
+        .store
+        .tenants()
+        .of_publish(api_key.as_deref(), host.as_ref());
+    // invalid keys are rejected by the publish
+    if let (Some(tenant), Ok(pubkey)) = (tenant, PublicKeyBytes::from_z32(&key)) {
+        if let Some(rejected) = tenant.rejects(&pubkey)? {
+            let (status, outcome) = match rejected {
+                TenantRejected::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+                TenantRejected::QuotaExceeded(_) => (StatusCode::FORBIDDEN, "quota_exceeded"),
+            };
+            DnsMetrics::count_publish(zone, "error");
+            record_publish(&state, "error");
+            TenantMetrics::count_publish(tenant.name(), outcome);
+            return Err(AppError::new(status, Some(rejected)));
+        }
+    }
+    let res = publish(&state, tenant, client.ip(), &key, &body, &mut timings).await;
+    state
+        .store
+        .slow_log()
+        .publish(&key, start.elapsed(), &timings);
+    let outcome = match &res {
+        Ok(true) => "update",
+        Ok(false) => "noop",
+        Err(_) => "error",
+    };
+    DnsMetrics::count_publish(zone, outcome);
+    record_publish(&state, outcome);
+    if let Some(tenant) = tenant {
+        TenantMetrics::count_publish(tenant.name(), outcome);
+    }
+    if let (Some(usage), Ok(_)) = (&state.usage, &res) {
+        usage.record_publish(tenant.map(|tenant| tenant.name()), api_key.as_deref());
+    }
+    res.map(|_| StatusCode::NO_CONTENT.into_response())
+}
+
+/// Count a publish with the `outcome` of its metrics in the hourly statistics.
+fn record_publish(state: &AppState, outcome: &str) {
+    let Some(hourly_stats) = &state.hourly_stats else {
+        return;
+    };
+    hourly_stats.record(match outcome {
+        "update" => Counter::PublishUpdates,
+        "noop" => Counter::PublishNoops,
+        _ => Counter::PublishErrors,
+    });
+}
+
+/// The id of the API key of a publish, if the key is needed for the tenants or the usage counts.
+async fn publish_api_key(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<Option<String>, AppError> {
+    if state.store.tenants().is_empty() && state.usage.is_none() {
+        return Ok(None);
+    }
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // only the id of a valid key is trusted, the request may be authorized otherwise
+    let api_key = match token {
+        Some(token) => state.store.api_keys().verify(token).await?,
+        None => None,
+    };
+    Ok(api_key.map(|key| key.id))
+}
+
+/// The server that owns `key` on the ring, if it is another server and the request was not
+/// forwarded by a server of the ring.
+fn remote_owner<'a>(
+    state: &'a AppState,
+    headers: &HeaderMap,
+    key: &str,
+) -> Option<(&'a Ring, &'a Url, PublicKeyBytes)> {
+    if headers.contains_key(FORWARDED_HEADER) {
+        return None;
+    }
+    let ring = state.store.ring()?;
+    // invalid keys are rejected locally
+    let pubkey = PublicKeyBytes::from_z32(key).ok()?;
+    let owner = ring.owner(&pubkey)?;
+    Some((ring, owner, pubkey))
+}
+
+/// Insert the signed packet, and return whether it updated the store.
+///
+/// Invalid keys and packets of `client` are recorded in the abuse log.
+async fn publish(
+    state: &AppState,
+    tenant: Option<&Arc<Tenant>>,
+    client: IpAddr,
+    key: &str,
+    body: &Bytes,
+    timings: &mut Timings,
+) -> Result<bool, AppError> {
+    if state.dns_handler.is_secondary() {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            Some("this server is a read-only secondary, publish to its primary".to_string()),
+        ));
+    }
+    let start = Instant::now();
+    let key = pkarr::PublicKey::try_from(key).map_err(|e| {
+        let message = format!("invalid key: {e}");
+        state
+            .abuse_log
+            .record(AbuseKind::MalformedPacket, client, &message);
+        AppError::new(StatusCode::BAD_REQUEST, Some(message))
+    })?;
     let label = &key.to_z32()[..10];
-    let signed_packet = pkarr::SignedPacket::from_relay_payload(&key, &body).map_err(|e| {
-        AppError::new(
-            StatusCode::BAD_REQUEST,
-            Some(format!("invalid body payload: {e}")),
-        )
+    let signed_packet = pkarr::SignedPacket::from_relay_payload(&key, body).map_err(|e| {
+        let kind = match e {
+            pkarr::Error::InvalidEd25519Signature => AbuseKind::InvalidSignature,
+            _ => AbuseKind::MalformedPacket,
+        };
+        let message = format!("invalid body payload: {e}");
+        state.abuse_log.record(kind, client, &message);
+        AppError::new(StatusCode::BAD_REQUEST, Some(message))
     })?;
+    timings.parse += start.elapsed();
+    let signed_packet = state
+        .packet_validators
+        .validate(signed_packet)
+        .await
+        .map_err(|err| match err.downcast::<PacketRejected>() {
+            Ok(err) => AppError::new(StatusCode::FORBIDDEN, Some(err)),
+            Err(err) => AppError::from(err),
+        })?;
 
-    let updated = state
-        .store
+    let pubkey = PublicKeyBytes::from(key);
+    let store = tenant.map_or(&state.store, |tenant| tenant.store(&state.store));
+    let start = Instant::now();
+    let updated = store
         .insert(signed_packet, PacketSource::PkarrPublish)
-        .await?;
+        .await;
+    timings.store += start.elapsed();
+    let updated = updated.map_err(|err| match err.downcast::<TimestampSkewed>() {
+        Ok(err) => AppError::new(StatusCode::BAD_REQUEST, Some(err)),
+        Err(err) => AppError::from(err),
+    })?;
+    if let Some(tenant) = tenant {
+        tenant.add_key(&pubkey)?;
+    }
     info!(key = %label, ?updated, "pkarr upsert");
-    Ok(StatusCode::NO_CONTENT)
+    Ok(updated)
 }
 
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct ValidateQuery {
+    /// z-base-32 encoded public key of the packet
+    key: String,
+}
+
+/// A check of a dry-run publish
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct PublishCheck {
+    /// The check: `server`, `key`, `payload`, `signature`, `tenant`, `validators`, `timestamp` or
+    /// `store`
+    pub check: String,
+    /// Whether the packet passed the check
+    pub ok: bool,
+    /// Why the packet failed the check, or a note on the outcome
+    pub message: Option<String>,
+}
+
+/// The diagnostics of a dry-run publish
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct PublishDiagnostics {
+    /// Whether the publish would be accepted
+    pub accepted: bool,
+    /// Whether the publish would update the stored packet of the key
+    pub updated: bool,
+    /// The checks in the order of a publish; the checks that need a valid key and payload are
+    /// skipped if they are invalid
+    pub checks: Vec<PublishCheck>,
+}
+
+impl PublishDiagnostics {
+    /// Add the outcome of `check`, failed with `failure` if it is set.
+    fn push(&mut self, check: &str, failure: Option<String>) {
+        self.checks.push(PublishCheck {
+            check: check.to_string(),
+            ok: failure.is_none(),
+            message: failure,
+        });
+    }
+
+    /// Set whether the publish would be accepted, from the outcomes of the checks.
+    fn finish(mut self) -> Json<Self> {
+        self.accepted = self.checks.iter().all(|check| check.ok);
+        self.updated &= self.accepted;
+        Json(self)
+    }
+}
+
+/// Check a pkarr signed packet like a publish, without storing it
+///
+/// Runs the checks of `PUT /pkarr/{key}` on the packet: the key, the size and signature of the
+/// payload, the quota of the tenant, the packet validators and the clock skew of the timestamp.
+/// The rate limit of the tenant is not taken from. The diagnostics are returned with status 200,
+/// also if the publish would be rejected.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        post,
+        path = "/pkarr/validate",
+        tag = "pkarr",
+        params(ValidateQuery),
+        request_body(
+            content = Vec<u8>,
+            content_type = "application/pkarr.org/relays#payload",
+            description = "Signature, timestamp and encoded DNS packet, like the body of a publish"
+        ),
+        responses(
+            (status = 200, description = "The outcome of the checks", body = PublishDiagnostics),
+            (status = 401, description = "Client certificate required", body = AppError),
+            (status = 429, description = "Rate limited", body = AppError),
+        )
+    )
+)]
+pub async fn validate(
+    State(state): State<AppState>,
+    origin: Option<Extension<RequestOrigin>>,
+    headers: HeaderMap,
+    Query(query): Query<ValidateQuery>,
+    body: Bytes,
+) -> Result<Json<PublishDiagnostics>, AppError> {
+    let mut diagnostics = PublishDiagnostics::default();
+    let secondary = state.dns_handler.is_secondary();
+    diagnostics.push(
+        "server",
+        secondary.then(|| "this server is a read-only secondary, publish to its primary".into()),
+    );
+    let key = match pkarr::PublicKey::try_from(query.key.as_str()) {
+        Ok(key) => key,
+        Err(err) => {
+            diagnostics.push("key", Some(format!("invalid key: {err}")));
+            return Ok(diagnostics.finish());
+        }
+    };
+    diagnostics.push("key", None);
+    let signed_packet = match pkarr::SignedPacket::from_relay_payload(&key, &body) {
+        Ok(signed_packet) => signed_packet,
+        Err(err @ pkarr::Error::InvalidEd25519Signature) => {
+            diagnostics.push("payload", None);
+            diagnostics.push("signature", Some(format!("invalid body payload: {err}")));
+            return Ok(diagnostics.finish());
+        }
+        Err(err) => {
+            diagnostics.push("payload", Some(format!("invalid body payload: {err}")));
+            return Ok(diagnostics.finish());
+        }
+    };
+    diagnostics.push("payload", None);
+    diagnostics.push("signature", None);
+
+    let pubkey = PublicKeyBytes::from(key);
+    let host = request_host(origin.as_ref().map(|o| &o.0));
+    let api_key = publish_api_key(&state, &headers).await?;
+    let tenant = state
+        .store
+        .tenants()
+        .of_publish(api_key.as_deref(), host.as_ref());
+    if let Some(tenant) = tenant {
+        let rejected = tenant.quota_rejects(&pubkey)?;
+        diagnostics.push("tenant", rejected.map(|rejected| rejected.to_string()));
+    }
+    let signed_packet = match state
+        .packet_validators
+        .validate(signed_packet.clone())
+        .await
+    {
+        Ok(validated) => {
+            diagnostics.push("validators", None);
+            validated
+        }
+        Err(err) => {
+            diagnostics.push("validators", Some(format!("{err:#}")));
+            signed_packet
+        }
+    };
+    let store = tenant.map_or(&state.store, |tenant| tenant.store(&state.store));
+    let skewed = store.timestamp_skew(&signed_packet);
+    diagnostics.push("timestamp", skewed.map(|skewed| skewed.to_string()));
+    diagnostics.updated = store.would_update(&signed_packet).await?;
+    diagnostics.checks.push(PublishCheck {
+        check: "store".to_string(),
+        ok: true,
+        message: (!diagnostics.updated).then(|| {
+            "a more recent packet of the key is stored, the publish would not change it".into()
+        }),
+    });
+    Ok(diagnostics.finish())
+}
+
+/// The host of a request as a DNS name, without the port.
+fn request_host(origin: Option<&RequestOrigin>) -> Option<LowerName> {
+    let host = origin.and_then(|o| o.host.as_deref()).unwrap_or_default();
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+    Name::from_utf8(host).ok().map(LowerName::from)
+}
+
+/// The zone a publish is attributed to in the metrics: the origin of the request host.
+fn publish_zone(state: &AppState, host: Option<&LowerName>) -> String {
+    match host {
+        Some(host) => state.dns_handler.zone_of(host),
+        None => "other".to_string(),
+    }
+}
+
+/// Get the latest pkarr signed packet for a public key
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/pkarr/{key}",
+        tag = "pkarr",
+        params(("key" = String, Path, description = "z-base-32 encoded public key")),
+        responses(
+            (status = 200, description = "The latest signed packet", body = Vec<u8>,
+                content_type = "application/x-pkarr-signed-packet"),
+            (status = 400, description = "Invalid key", body = AppError),
+            (status = 404, description = "No packet found for the key", body = AppError),
+            (status = 502, description = "The server that owns the key on the ring failed", body = AppError),
+        )
+    )
+)]
 pub async fn get(
     State(state): State<AppState>,
-    Path(pubkey): Path<String>,
+    origin: Option<Extension<RequestOrigin>>,
+    headers: HeaderMap,
+    Path(key): Path<String>,
 ) -> Result<impl IntoResponse, AppError> {
-    let pubkey = PublicKeyBytes::from_z32(&pubkey)
-        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}"))))?;
-    let signed_packet = state
-        .store
-        .get_signed_packet(&pubkey)
-        .await?
-        .ok_or_else(|| AppError::with_status(StatusCode::NOT_FOUND))?;
+    let signed_packet = match remote_owner(&state, &headers, &key) {
+        Some((ring, owner, pubkey)) => ring
+            .fetch(owner, &pubkey)
+            .await
+            .map_err(|err| AppError::new(StatusCode::BAD_GATEWAY, Some(err)))?,
+        None => {
+            let pubkey = PublicKeyBytes::from_z32(&key).map_err(|e| {
+                AppError::new(StatusCode::BAD_REQUEST, Some(format!("invalid key: {e}")))
+            })?;
+            // the packets of a tenant are looked up at a host in its origins
+            let host = request_host(origin.as_ref().map(|o| &o.0));
+            let tenant = host.and_then(|host| state.store.tenants().of_name(&host));
+            if let Some(tenant) = tenant {
+                TenantMetrics::count_query(tenant.name());
+            }
+            let store = tenant.map_or(&state.store, |tenant| tenant.store(&state.store));
+            let signed_packet = store.get_signed_packet(&pubkey).await?;
+            if let (Some(usage), Some(_)) = (&state.usage, &signed_packet) {
+                usage.record_resolution(tenant.map(|tenant| tenant.name()));
+            }
+            signed_packet
+        }
+    }
+    .ok_or_else(|| AppError::with_status(StatusCode::NOT_FOUND))?;
+    if let Some(hourly_stats) = &state.hourly_stats {
+        hourly_stats.record(Counter::PkarrGets);
+    }
+    if let Some(analytics) = &state.analytics {
+        analytics.record(
+            PublicKeyBytes::from_signed_packet(&signed_packet),
+            Transport::Http,
+        );
+    }
     let body = signed_packet.to_relay_payload();
     let headers = [(header::CONTENT_TYPE, "application/x-pkarr-signed-packet")];
     Ok((headers, body))
@@ -1,17 +1,125 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use governor::{clock::QuantaInstant, middleware::NoOpMiddleware};
+use anyhow::{ensure, Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use governor::{
+    clock::{Clock, DefaultClock},
+    middleware::StateInformationMiddleware,
+    state::{keyed::DefaultKeyedStateStore, InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use http::{
+    header::{AUTHORIZATION, RETRY_AFTER},
+    HeaderMap, HeaderName, HeaderValue, StatusCode,
+};
+use ipnet::IpNet;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use tower_governor::{
-    governor::GovernorConfigBuilder,
-    key_extractor::{PeerIpKeyExtractor, SmartIpKeyExtractor},
-    GovernorLayer,
+use tower_governor::key_extractor::{KeyExtractor, SmartIpKeyExtractor};
+
+use super::error::AppError;
+use crate::{
+    abuse::{AbuseKind, AbuseLog},
+    api_keys::ApiKeyStore,
+    metrics::RateLimitMetrics,
+};
+
+const X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+const X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+
+/// Maximum length of a bearer token that is used as a rate limiting key.
+const MAX_TOKEN_LEN: usize = 256;
+
+/// Interval in which the expired keys of the rate limiters are removed.
+pub(crate) const GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The default rate limit config: rate limit by connection peer IP address.
+static DEFAULT_RATE_LIMIT_CONFIG: RateLimitConfig = RateLimitConfig {
+    mode: RateLimitMode::Simple,
+    tokens: Vec::new(),
+    api_keys: BTreeMap::new(),
+    exempt: Vec::new(),
+    rate: None,
+    burst: None,
+    put: None,
+    get: None,
 };
 
 /// Config for http rate limit.
-#[derive(Debug, Deserialize, Default, Serialize, Clone)]
+///
+/// For backwards compatibility, the config can also be set to just a [`RateLimitMode`], e.g.
+/// `rate_limit = "smart"`.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[serde(from = "RateLimitConfigRepr")]
+pub struct RateLimitConfig {
+    /// How requests are keyed for rate limiting.
+    pub mode: RateLimitMode,
+    /// Bearer tokens that get their own rate limit budget (only applies to [`RateLimitMode::Token`]).
+    pub tokens: Vec<String>,
+    /// The quotas of the requests with these API keys, by the id of the key, instead of the
+    /// quota of the route (only applies to [`RateLimitMode::Token`]).
+    pub api_keys: BTreeMap<String, RateLimitQuota>,
+    /// Networks that are exempt from rate limiting, in CIDR notation (e.g. `10.0.0.0/8`).
+    ///
+    /// The exemption is checked against the client IP address, which is only read from proxy
+    /// headers of trusted proxies (see [`super::BehindProxyConfig`]), never against IP addresses
+    /// from other proxy headers, because these are controlled by the client.
+    pub exempt: Vec<IpNet>,
+    /// Requests per second that the budget of a key is refilled with, e.g. `0.25` for one request
+    /// every four seconds (defaults to 0.25 for publishes and 20 for DNS-over-HTTPS queries).
+    pub rate: Option<f64>,
+    /// The size of the budget of a key, the number of requests it can send at once (defaults to
+    /// 2 for publishes and 100 for DNS-over-HTTPS queries).
+    pub burst: Option<u32>,
+    /// The quota of the publishes with `PUT /pkarr/{key}` and their dry runs, instead of `rate`
+    /// and `burst`.
+    pub put: Option<RateLimitQuota>,
+    /// The quota of the lookups with `GET /pkarr/{key}`, which are only rate limited if it is set.
+    pub get: Option<RateLimitQuota>,
+}
+
+/// The quota of the requests of a route, see [`RateLimitConfig::put`] and
+/// [`RateLimitConfig::get`]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct RateLimitQuota {
+    /// Requests per second that the budget of a key is refilled with.
+    pub rate: f64,
+    /// The size of the budget of a key.
+    pub burst: u32,
+}
+
+impl RateLimitQuota {
+    /// The quota as limit of a [`RateLimiter`].
+    pub(crate) fn to_quota(self) -> Result<Quota> {
+        ensure!(
+            self.rate.is_finite() && self.rate > 0.0,
+            "the rate must be positive, got {}",
+            self.rate
+        );
+        let burst = NonZeroU32::new(self.burst).context("the burst must be at least 1")?;
+        let period = Duration::try_from_secs_f64(1.0 / self.rate)
+            .with_context(|| format!("the rate {} is too low", self.rate))?;
+        let quota = Quota::with_period(period)
+            .with_context(|| format!("the rate {} is too high", self.rate))?;
+        Ok(quota.allow_burst(burst))
+    }
+}
+
+/// The rate limit mode for the http server.
+#[derive(Debug, Deserialize, Default, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
-pub enum RateLimitConfig {
+pub enum RateLimitMode {
     /// Disable rate limit for http server.
     Disabled,
     /// Enable rate limit for http server based on the connection peer IP address.
@@ -20,65 +128,754 @@ pub enum RateLimitConfig {
     Simple,
     /// Enable rate limit for http server based on a smart logic for extracting the connection original IP address, useful for reverse proxies.
     /// https://docs.rs/tower_governor/latest/tower_governor/key_extractor/struct.SmartIpKeyExtractor.html
+    ///
+    /// This trusts proxy headers from any client. Prefer [`RateLimitMode::Simple`] with a
+    /// [`super::BehindProxyConfig`], which only trusts the headers of the configured proxies.
     Smart,
+    /// Enable rate limit for http server based on the bearer token presented in the
+    /// `Authorization` header.
+    ///
+    /// Requests with an active API key are rate limited by the id of the key, and the tokens
+    /// listed in [`RateLimitConfig::tokens`] by the token, so that each of them gets its own
+    /// budget, e.g. for clients behind a carrier-grade NAT. Requests without a token or with an
+    /// unknown token are rate limited by their connection peer IP address, which prevents evading
+    /// the limit by rotating tokens.
+    Token,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RateLimitConfigRepr {
+    Mode(RateLimitMode),
+    Full {
+        #[serde(default)]
+        mode: RateLimitMode,
+        #[serde(default)]
+        tokens: Vec<String>,
+        #[serde(default)]
+        api_keys: BTreeMap<String, RateLimitQuota>,
+        #[serde(default)]
+        exempt: Vec<IpNet>,
+        #[serde(default)]
+        rate: Option<f64>,
+        #[serde(default)]
+        burst: Option<u32>,
+        #[serde(default)]
+        put: Option<RateLimitQuota>,
+        #[serde(default)]
+        get: Option<RateLimitQuota>,
+    },
+}
+
+impl From<RateLimitConfigRepr> for RateLimitConfig {
+    fn from(value: RateLimitConfigRepr) -> Self {
+        match value {
+            RateLimitConfigRepr::Mode(mode) => mode.into(),
+            RateLimitConfigRepr::Full {
+                mode,
+                tokens,
+                api_keys,
+                exempt,
+                rate,
+                burst,
+                put,
+                get,
+            } => Self {
+                mode,
+                tokens,
+                api_keys,
+                exempt,
+                rate,
+                burst,
+                put,
+                get,
+            },
+        }
+    }
+}
+
+impl From<RateLimitMode> for RateLimitConfig {
+    fn from(mode: RateLimitMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
 }
 
 impl Default for &RateLimitConfig {
     fn default() -> Self {
-        &RateLimitConfig::Simple
+        &DEFAULT_RATE_LIMIT_CONFIG
+    }
+}
+
+impl RateLimitConfig {
+    /// Whether the requests of `class` are rate limited.
+    pub(crate) fn limits(&self, class: RateLimitClass) -> bool {
+        self.mode != RateLimitMode::Disabled
+            && (class != RateLimitClass::Lookup || self.get.is_some())
+    }
+
+    /// The quota of the requests of `class`.
+    pub(crate) fn quota(&self, class: RateLimitClass) -> Result<Quota> {
+        let route = match class {
+            RateLimitClass::Publish => self.put,
+            RateLimitClass::Lookup => self.get,
+            RateLimitClass::Doh => None,
+        };
+        let default = class.default_quota();
+        let quota = route.unwrap_or(RateLimitQuota {
+            rate: self.rate.unwrap_or(default.rate),
+            burst: self.burst.unwrap_or(default.burst),
+        });
+        quota
+            .to_quota()
+            .with_context(|| format!("invalid rate limit of {class} requests"))
+    }
+
+    /// Check that the quotas of the requests of `class` and of the API keys are valid.
+    pub(crate) fn validate(&self, class: RateLimitClass) -> Result<()> {
+        if self.limits(class) {
+            self.quota(class)?;
+        }
+        self.api_key_quotas()?;
+        Ok(())
+    }
+
+    /// The quotas of the API keys with their own quota.
+    fn api_key_quotas(&self) -> Result<HashMap<String, Quota>> {
+        self.api_keys
+            .iter()
+            .map(|(id, quota)| {
+                let quota = quota
+                    .to_quota()
+                    .with_context(|| format!("invalid rate limit of API key {id}"))?;
+                Ok((id.clone(), quota))
+            })
+            .collect()
+    }
+
+    fn is_exempt(&self, addr: IpAddr) -> bool {
+        self.exempt.iter().any(|net| net.contains(&addr))
+    }
+
+    /// Extract the rate limiting key from a request.
+    fn extract_key(&self, req: &Request, peer: IpAddr) -> (Option<RateLimitKey>, KeyOutcome) {
+        match self.mode {
+            RateLimitMode::Disabled | RateLimitMode::Simple => {
+                (Some(RateLimitKey::Ip(peer)), KeyOutcome::PeerIp)
+            }
+            RateLimitMode::Smart => match SmartIpKeyExtractor.extract(req) {
+                Ok(ip) => (Some(RateLimitKey::Ip(ip)), KeyOutcome::SmartIp),
+                Err(_) => (None, KeyOutcome::Failed),
+            },
+            RateLimitMode::Token => match bearer_token(req) {
+                Some(token) if self.tokens.iter().any(|t| t == token) => (
+                    Some(RateLimitKey::Token(token.to_string())),
+                    KeyOutcome::Token,
+                ),
+                _ => (Some(RateLimitKey::Ip(peer)), KeyOutcome::TokenFallback),
+            },
+        }
     }
 }
 
-/// Create the default rate-limiting layer.
+/// The class of requests a rate limiter applies to.
 ///
-/// This spawns a background thread to clean up the rate limiting cache.
-pub fn create(
+/// Each class has its own limiter, so that requests of one class never consume the budget of
+/// another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::Display, strum::IntoStaticStr)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum RateLimitClass {
+    /// Pkarr publishes
+    Publish,
+    /// Pkarr lookups
+    Lookup,
+    /// DNS-over-HTTPS queries
+    Doh,
+}
+
+impl RateLimitClass {
+    fn default_quota(self) -> RateLimitQuota {
+        match self {
+            // * allow bursts with up to two requests per key
+            // * replenish one element every four seconds
+            Self::Publish => RateLimitQuota {
+                rate: 0.25,
+                burst: 2,
+            },
+            // resolvers send many more queries than nodes publish, often for several names at
+            // once:
+            // * allow bursts with up to 100 requests per key
+            // * replenish 20 elements per second
+            Self::Lookup | Self::Doh => RateLimitQuota {
+                rate: 20.0,
+                burst: 100,
+            },
+        }
+    }
+}
+
+/// How the rate limiting key of a request was determined, for the metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum KeyOutcome {
+    /// The client is exempt from rate limiting
+    Exempt,
+    /// The connection peer IP address
+    PeerIp,
+    /// The IP address from proxy headers, see [`RateLimitMode::Smart`]
+    SmartIp,
+    /// A known bearer token
+    Token,
+    /// The id of an active API key
+    ApiKey,
+    /// The peer IP address, because the request has no known bearer token
+    TokenFallback,
+    /// No key could be extracted
+    Failed,
+}
+
+/// The key by which requests are rate limited.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RateLimitKey {
+    /// The client IP address.
+    Ip(IpAddr),
+    /// A known bearer token.
+    Token(String),
+    /// The id of an active API key.
+    ApiKey(String),
+}
+
+/// Get the bearer token from the `Authorization` header, if present.
+fn bearer_token(req: &Request) -> Option<&str> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?.trim();
+    if token.is_empty() || token.len() > MAX_TOKEN_LEN {
+        None
+    } else {
+        Some(token)
+    }
+}
+
+type KeyedRateLimiter = RateLimiter<
+    RateLimitKey,
+    DefaultKeyedStateStore<RateLimitKey>,
+    DefaultClock,
+    StateInformationMiddleware,
+>;
+
+type DirectRateLimiter =
+    RateLimiter<NotKeyed, InMemoryState, DefaultClock, StateInformationMiddleware>;
+
+/// The quotas and limiters of the API keys with their own quota, by key id
+type ApiKeyLimiters = HashMap<String, (Quota, Arc<DirectRateLimiter>)>;
+
+/// A rate limiter for HTTP requests.
+#[derive(derive_more::Debug)]
+pub struct HttpRateLimiter {
+    /// The mode, tokens, quotas and exempt networks, which can be changed at runtime
+    config: RwLock<RateLimitConfig>,
+    class: RateLimitClass,
+    /// The quota and the limiter of the keys, which is replaced when the quota changes
+    #[debug(skip)]
+    limiter: RwLock<(Quota, Arc<KeyedRateLimiter>)>,
+    /// The limiters of the API keys with their own quota, by key id
+    #[debug(skip)]
+    api_key_limiters: RwLock<ApiKeyLimiters>,
+    /// The API keys that requests are rate limited by in [`RateLimitMode::Token`]
+    api_keys: ApiKeyStore,
+    abuse_log: AbuseLog,
+    /// When the budgets of the client IP addresses are full again, if they are persisted
+    ///
+    /// The state of the limiter can't be read, so this is tracked on the side.
+    #[debug(skip)]
+    used_budgets: Mutex<Option<HashMap<IpAddr, SystemTime>>>,
+}
+
+/// Create the rate limiter for a class of requests.
+///
+/// The expired keys are removed by [`HttpRateLimiter::gc`], which the server calls in the
+/// [`GC_INTERVAL`].
+pub(crate) fn create(
     rate_limit_config: &RateLimitConfig,
-) -> Option<GovernorLayer<'static, PeerIpKeyExtractor, NoOpMiddleware<QuantaInstant>>> {
-    let use_smart_extractor = match rate_limit_config {
-        RateLimitConfig::Disabled => {
-            tracing::info!("Rate limiting disabled");
-            return None;
-        }
-        // By default apply rate limit
-        RateLimitConfig::Simple => false,
-        RateLimitConfig::Smart => true,
+    class: RateLimitClass,
+    api_keys: ApiKeyStore,
+    abuse_log: AbuseLog,
+) -> Result<Option<Arc<HttpRateLimiter>>> {
+    if !rate_limit_config.limits(class) {
+        tracing::info!("Rate limiting for {class} requests disabled");
+        return Ok(None);
+    }
+
+    let quota = rate_limit_config.quota(class)?;
+    let api_key_limiters = api_key_limiters(rate_limit_config.api_key_quotas()?, &HashMap::new());
+    tracing::info!(
+        "Rate limiting for {class} requests enabled ({:?}, burst of {} every {:?}, {} exempt networks)",
+        rate_limit_config.mode,
+        quota.burst_size(),
+        quota.replenish_interval() * quota.burst_size().get(),
+        rate_limit_config.exempt.len()
+    );
+
+    Ok(Some(Arc::new(HttpRateLimiter {
+        config: RwLock::new(rate_limit_config.clone()),
+        class,
+        limiter: RwLock::new((quota, keyed_limiter(quota))),
+        api_key_limiters: RwLock::new(api_key_limiters),
+        api_keys,
+        abuse_log,
+        used_budgets: Mutex::new(None),
+    })))
+}
+
+fn keyed_limiter(quota: Quota) -> Arc<KeyedRateLimiter> {
+    Arc::new(RateLimiter::keyed(quota).with_middleware())
+}
+
+/// Create the limiters of the API keys with `quotas`, keeping those of `current` whose quota is
+/// unchanged with the budget they used.
+fn api_key_limiters(quotas: HashMap<String, Quota>, current: &ApiKeyLimiters) -> ApiKeyLimiters {
+    quotas
+        .into_iter()
+        .map(|(id, quota)| {
+            let limiter = match current.get(&id) {
+                Some((current_quota, limiter)) if *current_quota == quota => limiter.clone(),
+                _ => Arc::new(RateLimiter::direct(quota).with_middleware()),
+            };
+            (id, (quota, limiter))
+        })
+        .collect()
+}
+
+impl HttpRateLimiter {
+    /// The class of requests this limiter applies to.
+    pub(crate) fn class(&self) -> RateLimitClass {
+        self.class
+    }
+
+    /// Remove the keys whose budget is full again.
+    pub(crate) fn gc(&self) {
+        let limiter = self.limiter.read().1.clone();
+        tracing::debug!("rate limiting storage size: {}", limiter.len());
+        limiter.retain_recent();
+        RateLimitMetrics::set_keys(self.class, limiter.len());
+        if let Some(used_budgets) = self.used_budgets.lock().as_mut() {
+            let now = SystemTime::now();
+            used_budgets.retain(|_, full_at| *full_at > now);
+        }
+    }
+
+    /// When the used budgets of the client IP addresses are full again.
+    ///
+    /// This is empty until [`Self::restore`] is called. The budgets of the bearer tokens are not
+    /// included, these are tied to known clients.
+    pub(crate) fn used_budgets(&self) -> Vec<(IpAddr, SystemTime)> {
+        let now = SystemTime::now();
+        self.used_budgets
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|(_, full_at)| **full_at > now)
+            .map(|(ip, full_at)| (*ip, *full_at))
+            .collect()
+    }
+
+    /// Use up the budgets of the client IP addresses until they are full again at the times of
+    /// `used_budgets`, e.g. from before a restart, and track the used budgets from now on.
+    pub(crate) fn restore(&self, used_budgets: impl IntoIterator<Item = (IpAddr, SystemTime)>) {
+        let (quota, limiter) = self.limiter.read().clone();
+        let now = SystemTime::now();
+        let mut tracked = HashMap::new();
+        for (ip, full_at) in used_budgets {
+            let Ok(left) = full_at.duration_since(now) else {
+                continue;
+            };
+            let cells = left
+                .as_nanos()
+                .div_ceil(quota.replenish_interval().as_nanos());
+            let cells = (cells.min(quota.burst_size().get() as u128) as u32).max(1);
+            let cells = NonZeroU32::new(cells).expect("at least one cell");
+            limiter.check_key_n(&RateLimitKey::Ip(ip), cells).ok();
+            tracked.insert(ip, full_at);
+        }
+        tracing::debug!(
+            "restored the used budgets of {} clients for {} requests",
+            tracked.len(),
+            self.class
+        );
+        *self.used_budgets.lock() = Some(tracked);
+    }
+
+    /// Replace the mode, tokens, quotas and exempt networks.
+    ///
+    /// With a different quota the limiter starts over with full budgets, except for the used
+    /// budgets of the client IP addresses if they are persisted, which are carried over. The API
+    /// keys whose quota is unchanged keep the budget they used. Fails without any change if a
+    /// quota of `config` is invalid.
+    pub(crate) fn reload(&self, config: &RateLimitConfig) -> Result<()> {
+        config.validate(self.class)?;
+        let quotas = config.api_key_quotas()?;
+        {
+            let mut limiters = self.api_key_limiters.write();
+            *limiters = api_key_limiters(quotas, &limiters);
+        }
+        if config.limits(self.class) {
+            let quota = config.quota(self.class)?;
+            if quota != self.limiter.read().0 {
+                *self.limiter.write() = (quota, keyed_limiter(quota));
+                let used_budgets = self.used_budgets.lock().take();
+                if let Some(used_budgets) = used_budgets {
+                    self.restore(used_budgets);
+                }
+            }
+        }
+        *self.config.write() = config.clone();
+        let quota = self.limiter.read().0;
+        tracing::info!(
+            "Rate limiting for {} requests changed ({:?}, burst of {} every {:?}, {} exempt networks)",
+            self.class,
+            config.mode,
+            quota.burst_size(),
+            quota.replenish_interval() * quota.burst_size().get(),
+            config.exempt.len()
+        );
+        Ok(())
+    }
+}
+
+/// Middleware that applies the [`HttpRateLimiter`] to requests.
+///
+/// Responses carry `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset` headers,
+/// throttled responses additionally carry a `Retry-After` header. All durations are in seconds.
+pub async fn middleware(
+    State(rate_limiter): State<Arc<HttpRateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let class = rate_limiter.class;
+    let checked = {
+        let config = rate_limiter.config.read();
+        if !config.limits(class) {
+            // the rate limit was disabled by a config reload
+            None
+        } else if config.is_exempt(peer.ip()) {
+            Some((None, KeyOutcome::Exempt))
+        } else {
+            Some(config.extract_key(&req, peer.ip()))
+        }
+    };
+    let Some((mut key, mut outcome)) = checked else {
+        return next.run(req).await;
+    };
+    if outcome == KeyOutcome::TokenFallback {
+        // the token is not listed, but may be an API key
+        if let Some(token) = bearer_token(&req) {
+            match rate_limiter.api_keys.verify(token).await {
+                Ok(Some(api_key)) => {
+                    key = Some(RateLimitKey::ApiKey(api_key.id));
+                    outcome = KeyOutcome::ApiKey;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::warn!("failed to verify the API key for rate limiting: {err:#}")
+                }
+            }
+        }
+    }
+    if outcome == KeyOutcome::Exempt {
+        RateLimitMetrics::count(class, outcome, false);
+        return next.run(req).await;
+    }
+    let Some(key) = key else {
+        RateLimitMetrics::count(class, outcome, false);
+        return AppError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Some("unable to extract rate limiting key"),
+        )
+        .into_response();
+    };
+    let api_key_limiter = match &key {
+        RateLimitKey::ApiKey(id) => rate_limiter
+            .api_key_limiters
+            .read()
+            .get(id)
+            .map(|(_, limiter)| limiter.clone()),
+        _ => None,
+    };
+    let checked = match api_key_limiter {
+        Some(limiter) => limiter.check(),
+        None => rate_limiter.limiter.read().1.check_key(&key),
     };
+    match checked {
+        Ok(snapshot) => {
+            RateLimitMetrics::count(class, outcome, false);
+            let quota = snapshot.quota();
+            let remaining = snapshot.remaining_burst_capacity();
+            let used = quota.burst_size().get().saturating_sub(remaining);
+            let reset = quota.replenish_interval() * used;
+            if let (RateLimitKey::Ip(ip), Some(used_budgets)) =
+                (&key, rate_limiter.used_budgets.lock().as_mut())
+            {
+                used_budgets.insert(*ip, SystemTime::now() + reset);
+            }
+            let mut response = next.run(req).await;
+            insert_headers(response.headers_mut(), &quota, remaining, reset);
+            response
+        }
+        Err(not_until) => {
+            RateLimitMetrics::count(class, outcome, true);
+            let ip = match key {
+                RateLimitKey::Ip(ip) => ip,
+                RateLimitKey::Token(_) | RateLimitKey::ApiKey(_) => peer.ip(),
+            };
+            rate_limiter
+                .abuse_log
+                .record(AbuseKind::RateLimited, ip, class.into());
+            let quota = not_until.quota();
+            let wait_time = not_until.wait_time_from(DefaultClock::default().now());
+            let reset = wait_time + quota.replenish_interval() * (quota.burst_size().get() - 1);
+            let mut response = AppError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                Some(format!(
+                    "Too Many Requests! Wait for {}s",
+                    ceil_secs(wait_time)
+                )),
+            )
+            .into_response();
+            let headers = response.headers_mut();
+            insert_headers(headers, &quota, 0, reset);
+            headers.insert(RETRY_AFTER, HeaderValue::from(ceil_secs(wait_time)));
+            response
+        }
+    }
+}
 
-    tracing::info!("Rate limiting enabled ({rate_limit_config:?})");
-
-    // Configure rate limiting:
-    // * allow bursts with up to five requests per IP address
-    // * replenish one element every two seconds
-    let mut governor_conf_builder = GovernorConfigBuilder::default();
-    // governor_conf_builder.use_headers()
-    governor_conf_builder.per_second(4);
-    governor_conf_builder.burst_size(2);
-
-    if use_smart_extractor {
-        governor_conf_builder.key_extractor(SmartIpKeyExtractor);
-    }
-
-    let governor_conf = governor_conf_builder
-        .finish()
-        .expect("failed to build rate-limiting governor");
-
-    // The governor layer needs a reference that outlives the layer.
-    // The tower_governor crate recommends in its examples to use Box::leak here.
-    // In the unreleased v0.4 of tower_governor this was changed to use an Arc instead.
-    // https://github.com/benwis/tower-governor/pull/27
-    let governor_conf = Box::leak(Box::new(governor_conf));
-
-    // The governor needs a background task for garbage collection (to clear expired records)
-    let gc_interval = Duration::from_secs(60);
-    let governor_limiter = governor_conf.limiter().clone();
-    std::thread::spawn(move || loop {
-        std::thread::sleep(gc_interval);
-        tracing::debug!("rate limiting storage size: {}", governor_limiter.len());
-        governor_limiter.retain_recent();
-    });
-
-    Some(GovernorLayer {
-        config: &*governor_conf,
-    })
+fn insert_headers(headers: &mut HeaderMap, quota: &Quota, remaining: u32, reset: Duration) {
+    headers.insert(
+        X_RATELIMIT_LIMIT,
+        HeaderValue::from(quota.burst_size().get()),
+    );
+    headers.insert(X_RATELIMIT_REMAINING, HeaderValue::from(remaining));
+    headers.insert(X_RATELIMIT_RESET, HeaderValue::from(ceil_secs(reset)));
+}
+
+/// Round a duration up to full seconds, as required by `Retry-After`.
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{body::Body, middleware, routing::get, Router};
+    use redb::{backends::InMemoryBackend, Database};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::api_keys::ApiKeyScope;
+
+    /// A router with the rate limit of `config`, and the API keys of `api_keys`.
+    fn app(config: &RateLimitConfig, api_keys: ApiKeyStore) -> Result<Router> {
+        Ok(app_with_limiter(config, api_keys)?.0)
+    }
+
+    fn app_with_limiter(
+        config: &RateLimitConfig,
+        api_keys: ApiKeyStore,
+    ) -> Result<(Router, Arc<HttpRateLimiter>)> {
+        let limiter = create(
+            config,
+            RateLimitClass::Publish,
+            api_keys,
+            AbuseLog::default(),
+        )?
+        .expect("rate limiting is enabled");
+        let app =
+            Router::new()
+                .route("/", get(|| async { "ok" }))
+                .layer(middleware::from_fn_with_state(
+                    limiter.clone(),
+                    super::middleware,
+                ));
+        Ok((app, limiter))
+    }
+
+    /// Send a request from `peer` with `token`, and return its status.
+    async fn send(app: &Router, peer: &str, token: Option<&str>) -> Result<StatusCode> {
+        let mut req = Request::builder().uri("/");
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let mut req = req.body(Body::empty())?;
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(peer.parse()?, 1234)));
+        Ok(app.clone().oneshot(req).await?.status())
+    }
+
+    fn api_key_store() -> Result<ApiKeyStore> {
+        let db = Database::builder().create_with_backend(InMemoryBackend::new())?;
+        ApiKeyStore::open(Arc::new(db))
+    }
+
+    #[tokio::test]
+    async fn api_keys_behind_shared_ip() -> Result<()> {
+        let api_keys = api_key_store()?;
+        let a = api_keys
+            .create("a".into(), vec![ApiKeyScope::Publish])
+            .await?;
+        let b = api_keys
+            .create("b".into(), vec![ApiKeyScope::Publish])
+            .await?;
+        let config = RateLimitConfig {
+            mode: RateLimitMode::Token,
+            put: Some(RateLimitQuota {
+                rate: 0.001,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+        let app = app(&config, api_keys)?;
+
+        // clients behind the same carrier-grade NAT have their own budget per API key
+        let peer = "100.64.0.1";
+        assert_eq!(send(&app, peer, Some(&a.token)).await?, StatusCode::OK);
+        assert_eq!(
+            send(&app, peer, Some(&a.token)).await?,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(send(&app, peer, Some(&b.token)).await?, StatusCode::OK);
+        // requests without a valid key share the budget of the IP address
+        assert_eq!(send(&app, peer, None).await?, StatusCode::OK);
+        assert_eq!(
+            send(&app, peer, Some("unknown.00")).await?,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // the budget of a key is the same from any IP address
+        assert_eq!(
+            send(&app, "100.64.0.2", Some(&a.token)).await?,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn api_key_quota() -> Result<()> {
+        let api_keys = api_key_store()?;
+        let key = api_keys
+            .create("bulk".into(), vec![ApiKeyScope::Publish])
+            .await?;
+        let config = RateLimitConfig {
+            mode: RateLimitMode::Token,
+            api_keys: BTreeMap::from([(
+                key.key.id.clone(),
+                RateLimitQuota {
+                    rate: 0.001,
+                    burst: 3,
+                },
+            )]),
+            put: Some(RateLimitQuota {
+                rate: 0.001,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+        let app = app(&config, api_keys)?;
+        for _ in 0..3 {
+            assert_eq!(
+                send(&app, "192.0.2.1", Some(&key.token)).await?,
+                StatusCode::OK
+            );
+        }
+        assert_eq!(
+            send(&app, "192.0.2.1", Some(&key.token)).await?,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // an invalid quota of a key is rejected
+        let mut config = config;
+        config.api_keys.get_mut(&key.key.id).unwrap().burst = 0;
+        assert!(create(
+            &config,
+            RateLimitClass::Publish,
+            api_key_store()?,
+            AbuseLog::default()
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_quota() -> Result<()> {
+        let quota = |burst| RateLimitQuota { rate: 0.001, burst };
+        let mut config = RateLimitConfig {
+            put: Some(quota(1)),
+            ..Default::default()
+        };
+        let (app, limiter) = app_with_limiter(&config, api_key_store()?)?;
+        let peer = "192.0.2.1";
+        assert_eq!(send(&app, peer, None).await?, StatusCode::OK);
+        assert_eq!(send(&app, peer, None).await?, StatusCode::TOO_MANY_REQUESTS);
+
+        // the new quota applies right away, with full budgets
+        config.put = Some(quota(3));
+        limiter.reload(&config)?;
+        for _ in 0..3 {
+            assert_eq!(send(&app, peer, None).await?, StatusCode::OK);
+        }
+        assert_eq!(send(&app, peer, None).await?, StatusCode::TOO_MANY_REQUESTS);
+
+        // an invalid quota is rejected, and the limiter is kept
+        config.put = Some(quota(0));
+        assert!(limiter.reload(&config).is_err());
+        assert_eq!(send(&app, peer, None).await?, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(&app, "192.0.2.2", None).await?, StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exempt_networks() -> Result<()> {
+        let config = RateLimitConfig {
+            mode: RateLimitMode::Smart,
+            exempt: vec!["10.0.0.0/8".parse()?, "fd00::/8".parse()?],
+            put: Some(RateLimitQuota {
+                rate: 0.001,
+                burst: 1,
+            }),
+            ..Default::default()
+        };
+        let app = app(&config, api_key_store()?)?;
+
+        // exempt IPv4 and IPv6 peers are never throttled
+        for peer in ["10.1.2.3", "fd00::1"] {
+            for _ in 0..3 {
+                assert_eq!(send(&app, peer, None).await?, StatusCode::OK);
+            }
+        }
+        // other peers are
+        for peer in ["192.0.2.1", "2001:db8::1"] {
+            assert_eq!(send(&app, peer, None).await?, StatusCode::OK);
+            assert_eq!(send(&app, peer, None).await?, StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        // a forwarded address of an exempt network doesn't exempt a peer, as the header is set
+        // by the client or an untrusted proxy
+        let forwarded = |peer: &str| -> Result<Request> {
+            let mut req = Request::builder()
+                .uri("/")
+                .header("x-forwarded-for", "10.0.0.1")
+                .body(Body::empty())?;
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::new(peer.parse()?, 1234)));
+            Ok(req)
+        };
+        // the header is the key in smart mode, so both peers share its budget
+        let res = app.clone().oneshot(forwarded("192.0.2.2")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.clone().oneshot(forwarded("192.0.2.3")?).await?;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        Ok(())
+    }
 }
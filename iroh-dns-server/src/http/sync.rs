@@ -0,0 +1,62 @@
+//! The packet change feed, for other servers to tail with [`crate::sync`]
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+
+use super::error::AppResult;
+use crate::{
+    state::AppState,
+    sync::{SyncBatch, MAX_BATCH},
+};
+
+#[derive(Debug, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub(crate) struct SyncQuery {
+    /// The cursor of the previous batch, or 0 to start with the oldest change
+    since: Option<u64>,
+    /// The maximum number of packets to return (defaults to and at most 1000)
+    limit: Option<usize>,
+}
+
+/// Get the packets that changed after a cursor
+///
+/// Only the latest change of each key is kept, so a packet that was updated since the cursor
+/// is returned once, at the position of its latest change. Removed packets are not returned.
+#[cfg_attr(
+    feature = "openapi",
+    utoipa::path(
+        get,
+        path = "/sync",
+        tag = "sync",
+        params(SyncQuery),
+        responses(
+            (status = 200, description = "The changed packets, oldest change first", body = SyncBatch),
+            (status = 401, description = "Not authorized", body = AppError),
+        )
+    )
+)]
+pub(crate) async fn get(
+    State(state): State<AppState>,
+    Query(query): Query<SyncQuery>,
+) -> AppResult<Json<SyncBatch>> {
+    let since = query.since.unwrap_or(0);
+    let limit = query.limit.unwrap_or(MAX_BATCH).min(MAX_BATCH);
+    let (changes, last) = state.store.changes_after(since, limit).await?;
+    // a cursor beyond the last change means that the store of this server was replaced
+    let cursor = changes
+        .last()
+        .map(|(seq, _)| *seq)
+        .unwrap_or(since.min(last));
+    Ok(Json(SyncBatch {
+        cursor,
+        more: cursor < last,
+        packets: changes
+            .iter()
+            .map(|(_, packet)| STANDARD.encode(packet.as_bytes()))
+            .collect(),
+    }))
+}
@@ -1,95 +1,938 @@
+#[cfg(feature = "https")]
 use std::{
     borrow::Cow,
     io,
-    path::{Path, PathBuf},
-    sync::{Arc, OnceLock},
+    path::Path,
+    sync::OnceLock,
+    time::{Duration, SystemTime},
 };
+use std::{path::PathBuf, sync::Arc};
 
+#[cfg(feature = "https")]
 use anyhow::{bail, Context, Result};
-use axum_server::{
-    accept::Accept,
-    tls_rustls::{RustlsAcceptor, RustlsConfig},
+use axum::{
+    extract::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
+#[cfg(feature = "https")]
+use axum::{middleware::AddExtension, Extension};
+#[cfg(feature = "https")]
+use axum_server::accept::Accept;
+#[cfg(feature = "https")]
 use futures_lite::{future::Boxed as BoxFuture, FutureExt};
-use serde::{Deserialize, Serialize};
+use http::StatusCode;
+#[cfg(feature = "https")]
+use iroh_metrics::inc;
+use parking_lot::RwLock;
+use rustls::pki_types::CertificateDer;
+#[cfg(feature = "https")]
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::PrivateKeyDer,
+    server::{
+        Acceptor, ClientHello, ResolvesServerCert, ServerSessionMemoryCache, WebPkiClientVerifier,
+    },
+    sign::CertifiedKey,
+    SupportedProtocolVersion,
+};
+use serde::{Deserialize, Serialize, Serializer};
+use time::format_description::well_known::Rfc3339;
+#[cfg(feature = "https")]
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_rustls_acme::{axum::AxumAcceptor, caches::DirCache, AcmeConfig};
+#[cfg(feature = "https")]
+use tokio_rustls::LazyConfigAcceptor;
+#[cfg(feature = "https")]
+use tokio_rustls_acme::{
+    acme::ACME_TLS_ALPN_NAME, caches::DirCache, AcmeConfig, CertCache, EventOk,
+};
+#[cfg(feature = "https")]
 use tokio_stream::StreamExt;
-use tracing::{debug, error, info_span, Instrument};
+#[cfg(feature = "https")]
+use tower::Layer;
+#[cfg(feature = "https")]
+use tracing::{debug, error, info, info_span, warn, Instrument};
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+#[cfg(feature = "https")]
+pub(crate) use self::acme::AccountStore;
+#[cfg(feature = "https")]
+use self::{
+    acme::{AccountKeyCache, AcmeServer},
+    acme_dns::AcmeDns,
+    tickets::SharedTicketer,
+};
+use super::error::AppError;
+#[cfg(feature = "https")]
+use super::HttpsConfig;
+#[cfg(feature = "https")]
+use crate::{
+    dns::AcmeChallenges,
+    events::ServerEvent,
+    metrics::{CertMetrics, Metrics},
+    secrets,
+};
+use crate::{
+    events::ServerEvents,
+    secrets::{SecretSource, SecretValue},
+};
+
+#[cfg(feature = "https")]
+mod acme;
+#[cfg(feature = "https")]
+mod acme_dns;
+#[cfg(feature = "https")]
+mod ocsp;
+#[cfg(feature = "https")]
+mod tickets;
+
+/// Interval in which manual certificate files are checked for changes.
+#[cfg(feature = "https")]
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+/// Interval in which the expiry of the certificates is checked.
+#[cfg(feature = "https")]
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60 * 6);
+/// Log warnings if a certificate expires within this time.
+#[cfg(feature = "https")]
+pub(crate) const EXPIRY_WARN_BEFORE: Duration = Duration::from_secs(60 * 60 * 24 * 14);
+/// Log errors if a certificate expires within this time.
+#[cfg(feature = "https")]
+pub(crate) const EXPIRY_ERROR_BEFORE: Duration = Duration::from_secs(60 * 60 * 24 * 3);
+/// Interval in which session ticket keys derived from configured keys are rotated.
+#[cfg(feature = "https")]
+const DEFAULT_TICKET_ROTATION: Duration = Duration::from_secs(60 * 60 * 6);
+/// Time after which TLS handshakes are aborted.
+#[cfg(feature = "https")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Config for TLS client certificate authentication (mTLS)
+///
+/// If set, clients may present a certificate signed by one of the configured CAs when connecting.
+/// A verified client certificate is required for the admin endpoints, and optionally for
+/// publishing pkarr packets. Other endpoints can still be used without a client certificate.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientAuthConfig {
+    /// Path to a PEM file with the CA certificates used to verify client certificates
+    pub ca_cert: PathBuf,
+    /// Whether a verified client certificate is also required to publish pkarr packets
+    #[serde(default)]
+    pub require_for_publish: bool,
+}
+
+/// Config for the TLS protocol versions, cipher suites and ALPN protocols
+///
+/// The rustls defaults are used for unset values.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// The minimum TLS version to accept
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// The cipher suites to offer, by their IANA names, e.g. `TLS13_AES_256_GCM_SHA384`
+    #[serde(default)]
+    pub cipher_suites: Option<Vec<String>>,
+    /// The ALPN protocols to advertise, e.g. `["h2", "http/1.1"]`
+    ///
+    /// Does not apply to HTTP/3, which always uses `h3`.
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+    /// Whether to issue session tickets, for cheaper handshakes of reconnecting clients
+    ///
+    /// Without `session_ticket_keys`, the ticket keys are random and rotated every 6 hours.
+    #[serde(default)]
+    pub session_tickets: bool,
+    /// Keys for the session tickets, to accept tickets issued by other servers of a fleet
+    ///
+    /// The keys are base64 encoded and at least 32 bytes long. The first key encrypts new
+    /// tickets, all keys decrypt them.
+    #[serde(default)]
+    pub session_ticket_keys: Vec<SecretValue>,
+    /// Interval in which the keys derived from `session_ticket_keys` are rotated, in seconds
+    ///
+    /// Defaults to 6 hours.
+    #[serde(default)]
+    pub session_ticket_rotation_secs: Option<u64>,
+    /// Number of sessions kept in memory for resumption without tickets
+    ///
+    /// Defaults to 256.
+    #[serde(default)]
+    pub session_cache_size: Option<usize>,
+}
+
+/// A TLS protocol version
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    #[serde(rename = "1.2")]
+    Tls12,
+    /// TLS 1.3
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[cfg(feature = "https")]
+impl TlsConfig {
+    /// Create the crypto provider with the configured cipher suites.
+    fn crypto_provider(&self) -> Result<CryptoProvider> {
+        let mut provider = rustls::crypto::ring::default_provider();
+        if let Some(names) = &self.cipher_suites {
+            let mut cipher_suites = Vec::new();
+            for name in names {
+                let suite = provider
+                    .cipher_suites
+                    .iter()
+                    .find(|s| s.suite().as_str() == Some(name.as_str()))
+                    .with_context(|| format!("unsupported cipher suite: {name}"))?;
+                cipher_suites.push(*suite);
+            }
+            provider.cipher_suites = cipher_suites;
+        }
+        Ok(provider)
+    }
+
+    /// Configure session resumption on `config`.
+    async fn configure_resumption(&self, config: &mut rustls::ServerConfig) -> Result<()> {
+        if let Some(size) = self.session_cache_size {
+            config.session_storage = ServerSessionMemoryCache::new(size);
+        }
+        if !self.session_tickets {
+            return Ok(());
+        }
+        config.ticketer = if self.session_ticket_keys.is_empty() {
+            rustls::crypto::ring::Ticketer::new()?
+        } else {
+            let rotation = self
+                .session_ticket_rotation_secs
+                .map_or(DEFAULT_TICKET_ROTATION, Duration::from_secs);
+            Arc::new(SharedTicketer::new(&self.session_ticket_keys, rotation).await?)
+        };
+        Ok(())
+    }
+
+    /// Get the protocol versions of at least the configured minimum version.
+    fn protocol_versions(&self) -> Vec<&'static SupportedProtocolVersion> {
+        let min_version = self.min_version.unwrap_or(TlsVersion::Tls12);
+        let mut versions = vec![&rustls::version::TLS13];
+        if min_version <= TlsVersion::Tls12 {
+            versions.push(&rustls::version::TLS12);
+        }
+        versions
+    }
+}
+
+/// Config for a custom ACME server, used instead of the LetsEncrypt servers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcmeDirectoryConfig {
+    /// URL of the ACME directory, e.g. `https://acme.zerossl.com/v2/DV90`
+    pub directory_url: String,
+    /// Path to a PEM file with additional CA certificates to trust for the ACME server
+    ///
+    /// Needed for internal CAs like Pebble or step-ca.
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+    /// Credentials for the external account binding, required by some CAs
+    #[serde(default)]
+    pub eab: Option<ExternalAccountBinding>,
+}
+
+/// Credentials for binding the ACME account to an account at the CA (RFC 8555, section 7.3.4)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalAccountBinding {
+    /// The key identifier provided by the CA
+    pub key_id: String,
+    /// The base64url encoded HMAC key provided by the CA
+    pub hmac_key: String,
+}
+
+/// Information about a stored ACME account
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcmeAccountInfo {
+    /// URL of the ACME directory the account belongs to
+    pub directory_url: String,
+    /// The contacts of the account
+    pub contact: Vec<String>,
+    /// When the account key was created (RFC 3339)
+    pub created: String,
+    /// The URL of the account, once it is registered with the CA
+    ///
+    /// This is only recorded by the `lets_encrypt_dns` cert mode.
+    #[serde(default)]
+    pub kid: Option<String>,
+}
+
+/// The certificate presented by the client of a TLS connection, if any.
+///
+/// The certificate has been verified against the configured [`ClientAuthConfig::ca_cert`].
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub(crate) Option<CertificateDer<'static>>);
+
+/// Middleware that rejects requests on connections without a verified client certificate.
+pub(crate) async fn require_client_cert(req: Request, next: Next) -> Response {
+    match req.extensions().get::<ClientCertificate>() {
+        Some(ClientCertificate(Some(_))) => next.run(req).await,
+        _ => AppError::new(
+            StatusCode::UNAUTHORIZED,
+            Some("a valid TLS client certificate is required"),
+        )
+        .into_response(),
+    }
+}
+
+/// Status of the TLS certificate, as shown on the admin dashboard.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub(crate) struct CertStatus {
+    /// The mode of certificate creation
+    pub(crate) mode: CertMode,
+    /// The domains the certificate is for
+    pub(crate) domains: Vec<String>,
+    /// Expiry of the certificate, if known (RFC 3339)
+    ///
+    /// Not known until the first certificate is loaded or obtained.
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+    pub(crate) not_after: CertExpiry,
+}
+
+/// The expiry of the current certificate, updated when the certificate is reloaded.
+///
+/// The expiry is also exported as the `cert_not_after_timestamp` metric for the domains.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "https"), allow(dead_code))]
+pub(crate) struct CertExpiry {
+    /// Seconds since the unix epoch
+    not_after: Arc<RwLock<Option<i64>>>,
+    domains: Arc<Vec<String>>,
+    events: ServerEvents,
+}
+
+#[cfg(feature = "https")]
+impl CertExpiry {
+    fn new(domains: Vec<String>, events: ServerEvents) -> Self {
+        Self {
+            not_after: Default::default(),
+            domains: Arc::new(domains),
+            events,
+        }
+    }
+
+    fn set(&self, cert: &CertificateDer) {
+        let not_after = cert_not_after(cert);
+        let previous = std::mem::replace(&mut *self.not_after.write(), not_after);
+        if let Some(not_after) = not_after {
+            let gauge = &CertMetrics::get().cert_not_after_timestamp;
+            CertMetrics::set(gauge, &self.domains, not_after);
+            // the first certificate after the start is not a renewal
+            if previous.is_some_and(|previous| previous != not_after) {
+                self.events.send(ServerEvent::CertRenewed {
+                    domains: self.domains.to_vec(),
+                    not_after,
+                });
+            }
+        }
+    }
+
+    /// Log a warning if the certificate expires soon, with a higher level the closer it is.
+    fn warn_if_expiring(&self) {
+        let Some(not_after) = *self.not_after.read() else {
+            return;
+        };
+        let left = not_after - time::OffsetDateTime::now_utc().unix_timestamp();
+        let days = left / (60 * 60 * 24);
+        let domains = &self.domains;
+        if left <= 0 {
+            error!(?domains, "certificate expired");
+        } else if left < EXPIRY_ERROR_BEFORE.as_secs() as i64 {
+            error!(?domains, "certificate expires in {days} days");
+        } else if left < EXPIRY_WARN_BEFORE.as_secs() as i64 {
+            warn!(?domains, "certificate expires in {days} days");
+        }
+    }
+}
+
+impl Serialize for CertExpiry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let not_after = *self.not_after.read();
+        not_after
+            .and_then(|t| time::OffsetDateTime::from_unix_timestamp(t).ok())
+            .and_then(|t| t.format(&Rfc3339).ok())
+            .serialize(serializer)
+    }
+}
+
+/// Check the expiry of the certificates in an interval, and log warnings if they expire soon.
+#[cfg(feature = "https")]
+async fn check_expiry(statuses: Vec<CertStatus>) {
+    loop {
+        for status in &statuses {
+            status.not_after.warn_if_expiring();
+        }
+        tokio::time::sleep(EXPIRY_CHECK_INTERVAL).await;
+    }
+}
 
 /// The mode how SSL certificates should be created.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, strum::Display)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
 #[serde(rename_all = "snake_case")]
 pub enum CertMode {
-    /// Certs are loaded from a the `cert_cache` path
+    /// Certs are loaded from files, and reloaded when the files change
+    ///
+    /// The files are set with [`CertConfig::cert_path`] and [`CertConfig::key_path`], and
+    /// default to `<domain>.crt` and `<domain>.key` in the `cert_cache` path.
     Manual,
-    /// ACME with LetsEncrypt servers
+    /// ACME with LetsEncrypt servers, or the server set with [`CertConfig::acme`]
     LetsEncrypt,
+    /// Like [`CertMode::LetsEncrypt`], with DNS-01 challenges answered by the DNS server
+    ///
+    /// All domains must be in one of the DNS origins of this server, and the server must be
+    /// their authoritative name server. The certificate and the ACME account key are stored in
+    /// the `cert_cache` path.
+    ///
+    /// This mode can obtain wildcard certificates, e.g. for `*.dns.example.org` to terminate
+    /// HTTPS for the node names under the origin `dns.example.org`.
+    LetsEncryptDns,
     /// Create self-signed certificates and store them in the `cert_cache` path
     SelfSigned,
 }
 
-impl CertMode {
-    /// Build the [`TlsAcceptor`] for this mode.
-    pub(crate) async fn build(
+/// Config for a certificate of the HTTPS server
+///
+/// See [`HttpsConfig`] for the documentation of the fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CertConfig {
+    /// The list of domains the certificate is for
+    ///
+    /// Domains may be wildcards (`*.example.org`), which match a single label. LetsEncrypt only
+    /// issues wildcard certificates with [`CertMode::LetsEncryptDns`].
+    pub domains: Vec<String>,
+    /// The mode of SSL certificate creation
+    pub cert_mode: CertMode,
+    /// Letsencrypt contact email address
+    pub letsencrypt_contact: Option<String>,
+    /// Whether to use the letsenrypt production servers
+    pub letsencrypt_prod: Option<bool>,
+    /// Use a custom ACME server instead of LetsEncrypt
+    #[serde(default)]
+    pub acme: Option<AcmeDirectoryConfig>,
+    /// Path to the PEM certificate chain file (only applies to [`CertMode::Manual`])
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    /// Path to the PEM secret key file (only applies to [`CertMode::Manual`])
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    /// Load the PEM certificate chain from a secret instead of `cert_path`
+    #[serde(default)]
+    pub cert_secret: Option<SecretSource>,
+    /// Load the PEM secret key from a secret instead of `key_path`
+    #[serde(default)]
+    pub key_secret: Option<SecretSource>,
+    /// Load the ACME account key (PKCS#8 PEM) from a secret
+    #[serde(default)]
+    pub acme_account_key: Option<SecretSource>,
+}
+
+#[cfg(feature = "https")]
+impl CertConfig {
+    /// Create the resolver for this certificate, and return it with the status of the certificate.
+    ///
+    /// State of the certificate is stored in a directory for the mode in `cert_cache`.
+    async fn build(
         &self,
-        domains: Vec<String>,
-        cert_cache: PathBuf,
-        letsencrypt_contact: Option<String>,
-        letsencrypt_prod: bool,
-    ) -> Result<TlsAcceptor> {
-        Ok(match self {
-            CertMode::Manual => TlsAcceptor::manual(domains, cert_cache).await?,
-            CertMode::SelfSigned => TlsAcceptor::self_signed(domains).await?,
+        cert_cache: &Path,
+        acme_challenges: &AcmeChallenges,
+        events: &ServerEvents,
+    ) -> Result<(Arc<dyn ResolvesServerCert>, CertStatus)> {
+        let accounts = AccountStore::new(cert_cache);
+        let cert_cache = cert_cache.join(self.cert_mode.to_string());
+        tokio::fs::create_dir_all(&cert_cache)
+            .await
+            .with_context(|| format!("failed to create cert cache dir at {cert_cache:?}"))?;
+        let domains = self.domains.clone();
+        let status = CertStatus {
+            mode: self.cert_mode.clone(),
+            domains: domains.clone(),
+            not_after: CertExpiry::new(domains.clone(), events.clone()),
+        };
+        let resolver: Arc<dyn ResolvesServerCert> = match self.cert_mode {
+            CertMode::Manual => match (&self.cert_secret, &self.key_secret) {
+                (Some(cert_secret), Some(key_secret)) => {
+                    manual_secrets(
+                        cert_secret.clone(),
+                        key_secret.clone(),
+                        status.not_after.clone(),
+                    )
+                    .await?
+                }
+                (None, None) => {
+                    let (cert_path, key_path) = match (&self.cert_path, &self.key_path) {
+                        (Some(cert_path), Some(key_path)) => (cert_path.clone(), key_path.clone()),
+                        (None, None) => {
+                            if domains.len() != 1 {
+                                bail!("Multiple domains in manual mode require cert_path and key_path");
+                            }
+                            let keyname = escape_hostname(&domains[0]);
+                            (
+                                cert_cache.join(format!("{keyname}.crt")),
+                                cert_cache.join(format!("{keyname}.key")),
+                            )
+                        }
+                        _ => bail!("cert_path and key_path must be set together"),
+                    };
+                    manual(cert_path, key_path, status.not_after.clone()).await?
+                }
+                _ => bail!("cert_secret and key_secret must be set together"),
+            },
+            CertMode::SelfSigned => {
+                let key = self_signed(domains)?;
+                status.not_after.set(&key.cert[0]);
+                Arc::new(ReloadingCertResolver {
+                    key: RwLock::new(Some(key)),
+                })
+            }
             CertMode::LetsEncrypt => {
-                let contact =
-                    letsencrypt_contact.context("contact is required for letsencrypt cert mode")?;
-                TlsAcceptor::letsencrypt(domains, &contact, letsencrypt_prod, cert_cache)?
+                if domains.iter().any(|d| d.starts_with("*.")) {
+                    bail!("wildcard domains require the lets_encrypt_dns cert mode");
+                }
+                let contact = self
+                    .letsencrypt_contact
+                    .as_ref()
+                    .context("contact is required for letsencrypt cert mode")?;
+                let prod = self.letsencrypt_prod.unwrap_or(false);
+                let server = AcmeServer::new(
+                    self.acme.as_ref(),
+                    prod,
+                    self.acme_account_key.clone(),
+                    accounts,
+                )
+                .await?;
+                letsencrypt(
+                    domains,
+                    contact,
+                    server,
+                    cert_cache,
+                    status.not_after.clone(),
+                )
             }
-        })
+            CertMode::LetsEncryptDns => {
+                let contact = self
+                    .letsencrypt_contact
+                    .clone()
+                    .context("contact is required for letsencrypt_dns cert mode")?;
+                let prod = self.letsencrypt_prod.unwrap_or(false);
+                let server = AcmeServer::new(
+                    self.acme.as_ref(),
+                    prod,
+                    self.acme_account_key.clone(),
+                    accounts,
+                )
+                .await?;
+                let domain = domains.first().context("no domains configured")?;
+                // `*` is not allowed in file names on all platforms
+                let keyname = escape_hostname(&domain.replacen("*.", "wildcard.", 1)).into_owned();
+                let files = CertFiles {
+                    cert_path: cert_cache.join(format!("{keyname}.crt")),
+                    key_path: cert_cache.join(format!("{keyname}.key")),
+                };
+                let acme = AcmeDns::new(
+                    server,
+                    domains,
+                    &contact,
+                    cert_cache,
+                    acme_challenges.clone(),
+                );
+                letsencrypt_dns(acme, files, status.not_after.clone()).await
+            }
+        };
+        Ok((resolver, status))
     }
 }
 
-/// TLS Certificate Authority acceptor.
+#[cfg(feature = "https")]
+impl CertConfig {
+    /// Create the server config of this certificate for the listeners of other protocols, and
+    /// check its expiry like the certificates of the HTTPS server.
+    pub(crate) async fn server_config(
+        &self,
+        cert_cache: &Path,
+        acme_challenges: &AcmeChallenges,
+        events: &ServerEvents,
+    ) -> Result<rustls::ServerConfig> {
+        let (resolver, status) = self.build(cert_cache, acme_challenges, events).await?;
+        tokio::spawn(check_expiry(vec![status]).instrument(info_span!("cert_expiry")));
+        Ok(rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver))
+    }
+}
+
+/// TLS acceptor for the HTTPS server.
+///
+/// The certificate is selected by the server name sent by the client. ACME TLS-ALPN-01
+/// validation requests are answered if a certificate uses [`CertMode::LetsEncrypt`].
+#[cfg(feature = "https")]
 #[derive(Clone)]
-pub enum TlsAcceptor {
-    LetsEncrypt(AxumAcceptor),
-    Manual(RustlsAcceptor),
+pub struct TlsAcceptor {
+    config: Arc<rustls::ServerConfig>,
+    /// The config for TLS-ALPN-01 validation handshakes
+    acme_config: Option<Arc<rustls::ServerConfig>>,
 }
 
+#[cfg(feature = "https")]
 impl<I: AsyncRead + AsyncWrite + Unpin + Send + 'static, S: Send + 'static> Accept<I, S>
     for TlsAcceptor
 {
     type Stream = tokio_rustls::server::TlsStream<I>;
-    type Service = S;
+    type Service = AddExtension<S, ClientCertificate>;
     type Future = BoxFuture<io::Result<(Self::Stream, Self::Service)>>;
 
     fn accept(&self, stream: I, service: S) -> Self::Future {
-        match self {
-            Self::LetsEncrypt(a) => a.accept(stream, service).boxed(),
-            Self::Manual(a) => a.accept(stream, service).boxed(),
+        let this = self.clone();
+        let handshake = async move {
+            let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+            let is_validation = start
+                .client_hello()
+                .alpn()
+                .into_iter()
+                .flatten()
+                .eq([ACME_TLS_ALPN_NAME]);
+            if let (true, Some(acme_config)) = (is_validation, this.acme_config) {
+                start.into_stream(acme_config).await?;
+                return Err(io::Error::other("TLS-ALPN-01 validation request"));
+            }
+            start.into_stream(this.config).await
+        };
+        async move {
+            let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake)
+                .await
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+                })??;
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| cert.clone().into_owned());
+            Ok((
+                stream,
+                Extension(ClientCertificate(client_cert)).layer(service),
+            ))
         }
+        .boxed()
     }
 }
 
+#[cfg(feature = "https")]
 impl TlsAcceptor {
-    async fn self_signed(domains: Vec<String>) -> Result<Self> {
-        let tls_cert = rcgen::generate_simple_self_signed(domains)?;
-        let key = tls_cert.serialize_private_key_der();
-        let config = RustlsConfig::from_der(vec![tls_cert.serialize_der()?], key).await?;
-        let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
-    }
-
-    async fn manual(domains: Vec<String>, dir: PathBuf) -> Result<Self> {
-        let config = rustls::ServerConfig::builder().with_no_client_auth();
-        if domains.len() != 1 {
-            bail!("Multiple domains in manual mode are not supported");
+    /// Create the acceptor for the certificates of `https_config`, and return it with the
+    /// status of the certificates.
+    pub(crate) async fn new(
+        https_config: &HttpsConfig,
+        cert_cache: &Path,
+        acme_challenges: AcmeChallenges,
+        events: ServerEvents,
+    ) -> Result<(Self, Vec<CertStatus>)> {
+        let mut certs = Vec::new();
+        let mut statuses = Vec::new();
+        let mut tls_alpn_01 = false;
+        for cert in https_config.certificates() {
+            let (resolver, status) = cert.build(cert_cache, &acme_challenges, &events).await?;
+            tls_alpn_01 |= cert.cert_mode == CertMode::LetsEncrypt;
+            let resolver = match cert.cert_mode {
+                CertMode::SelfSigned => resolver,
+                _ if !https_config.ocsp_stapling => resolver,
+                _ => ocsp::StaplingResolver::spawn(resolver, cert.domains.clone()),
+            };
+            certs.push((cert.domains, resolver));
+            statuses.push(status);
+        }
+        tokio::spawn(check_expiry(statuses.clone()).instrument(info_span!("cert_expiry")));
+        let resolver = Arc::new(SniResolver { certs });
+
+        let mut config =
+            server_config_builder(&https_config.tls, https_config.client_auth.as_ref())
+                .await?
+                .with_cert_resolver(resolver.clone());
+        config.alpn_protocols = https_config
+            .tls
+            .alpn_protocols
+            .iter()
+            .map(|p| p.as_bytes().to_vec())
+            .collect();
+        https_config.tls.configure_resumption(&mut config).await?;
+        let acme_config = tls_alpn_01.then(|| {
+            let mut config = rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(resolver);
+            config.alpn_protocols = vec![ACME_TLS_ALPN_NAME.to_vec()];
+            Arc::new(config)
+        });
+        let acceptor = Self {
+            config: Arc::new(config),
+            acme_config,
+        };
+        Ok((acceptor, statuses))
+    }
+
+    /// Create the acceptor for a server config that was set up elsewhere, e.g. by an embedder.
+    ///
+    /// The certificates of the config are not shown in the admin endpoints and metrics.
+    pub(crate) fn from_server_config(config: Arc<rustls::ServerConfig>) -> Self {
+        Self {
+            config,
+            acme_config: None,
         }
-        let keyname = escape_hostname(&domains[0]);
-        let cert_path = dir.join(format!("{keyname}.crt"));
-        let key_path = dir.join(format!("{keyname}.key"));
+    }
+
+    /// Get the rustls server config used by this acceptor.
+    pub(crate) fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        self.config.clone()
+    }
+}
+
+/// Create a self-signed certificate.
+#[cfg(feature = "https")]
+fn self_signed(domains: Vec<String>) -> Result<Arc<CertifiedKey>> {
+    let tls_cert = rcgen::generate_simple_self_signed(domains)?;
+    let key = PrivateKeyDer::Pkcs8(tls_cert.serialize_private_key_der().into());
+    let cert = CertificateDer::from(tls_cert.serialize_der()?);
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+    Ok(Arc::new(CertifiedKey::new(vec![cert], signing_key)))
+}
+
+/// Create a resolver with the certificate and key from the given files.
+///
+/// This spawns a task that reloads the certificate when the files change.
+#[cfg(feature = "https")]
+async fn manual(
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    expiry: CertExpiry,
+) -> Result<Arc<dyn ResolvesServerCert>> {
+    let files = CertFiles {
+        cert_path,
+        key_path,
+    };
+    let mut modified = files.modified().await;
+    let resolver = Arc::new(ReloadingCertResolver {
+        key: RwLock::new(Some(files.load(&expiry).await?)),
+    });
+
+    let reloading = resolver.clone();
+    tokio::spawn(
+        async move {
+            loop {
+                tokio::time::sleep(CERT_RELOAD_INTERVAL).await;
+                let current = files.modified().await;
+                if current == modified {
+                    continue;
+                }
+                match files.load(&expiry).await {
+                    Ok(key) => {
+                        info!("reloaded certificate from {}", files.cert_path.display());
+                        *reloading.key.write() = Some(key);
+                        modified = current;
+                    }
+                    // the files might be written non-atomically, retry in the next interval
+                    Err(err) => warn!("failed to reload certificate: {err:#}"),
+                }
+            }
+        }
+        .instrument(info_span!("cert_reload")),
+    );
+    Ok(resolver)
+}
+
+/// Create a resolver with the certificate and key loaded from secrets.
+///
+/// The secrets are reloaded in an interval, and the certificate is replaced if they changed.
+#[cfg(feature = "https")]
+async fn manual_secrets(
+    cert_secret: SecretSource,
+    key_secret: SecretSource,
+    expiry: CertExpiry,
+) -> Result<Arc<dyn ResolvesServerCert>> {
+    let load = move |pem: &(String, String)| {
+        let certs = read_certs(&mut pem.0.as_bytes())?;
+        let secret_key = read_secret_key(&mut pem.1.as_bytes())?;
+        certified_key(certs, secret_key, &expiry)
+    };
+    let mut current = tokio::try_join!(cert_secret.load(), key_secret.load())?;
+    let resolver = Arc::new(ReloadingCertResolver {
+        key: RwLock::new(Some(load(&current)?)),
+    });
+
+    let reloading = resolver.clone();
+    tokio::spawn(
+        async move {
+            loop {
+                tokio::time::sleep(secrets::REFRESH_INTERVAL).await;
+                let pem = match tokio::try_join!(cert_secret.load(), key_secret.load()) {
+                    Ok(pem) if pem == current => continue,
+                    Ok(pem) => pem,
+                    Err(err) => {
+                        warn!("failed to reload certificate secrets: {err:#}");
+                        continue;
+                    }
+                };
+                match load(&pem) {
+                    Ok(key) => {
+                        info!("reloaded certificate from secrets");
+                        *reloading.key.write() = Some(key);
+                        current = pem;
+                    }
+                    // the secrets might not be rotated together, retry in the next interval
+                    Err(err) => warn!("failed to reload certificate: {err:#}"),
+                }
+            }
+        }
+        .instrument(info_span!("cert_reload")),
+    );
+    Ok(resolver)
+}
+
+/// Create a resolver with a certificate obtained with DNS-01 challenges.
+///
+/// A cached certificate is used right away. This spawns a task that obtains the certificate
+/// if there is none, and renews it before it expires.
+#[cfg(feature = "https")]
+async fn letsencrypt_dns(
+    acme: AcmeDns,
+    files: CertFiles,
+    expiry: CertExpiry,
+) -> Arc<dyn ResolvesServerCert> {
+    let resolver = Arc::new(ReloadingCertResolver {
+        key: RwLock::new(files.load(&expiry).await.ok()),
+    });
+    tokio::spawn(
+        acme.run(files, resolver.clone(), expiry)
+            .instrument(info_span!("acme_dns")),
+    );
+    resolver
+}
+
+/// Create a resolver with a certificate obtained with TLS-ALPN-01 challenges.
+///
+/// The resolver also resolves the certificates for the validation requests.
+#[cfg(feature = "https")]
+fn letsencrypt(
+    domains: Vec<String>,
+    contact: &str,
+    server: AcmeServer,
+    dir: PathBuf,
+    expiry: CertExpiry,
+) -> Arc<dyn ResolvesServerCert> {
+    let directory_url = server.directory_url.clone();
+    let cache = DirCache::new(dir.clone());
+    let mut state = AcmeConfig::new(domains.clone())
+        .contact([format!("mailto:{contact}")])
+        .directory(&server.directory_url)
+        .client_tls_config(server.client_config.clone())
+        .cache_compose(
+            DirCache::new(dir.clone()),
+            AccountKeyCache::new(dir, server),
+        )
+        .state();
+    let resolver = state.resolver();
+
+    tokio::spawn(
+        async move {
+            loop {
+                match state.next().await.unwrap() {
+                    Ok(ok) => {
+                        debug!("acme event: {:?}", ok);
+                        if matches!(ok, EventOk::DeployedNewCert) {
+                            inc!(Metrics, acme_renewal_success);
+                        }
+                        if matches!(ok, EventOk::DeployedCachedCert | EventOk::DeployedNewCert) {
+                            // the deployed certificate is not exposed, read it from the cache
+                            match cache.load_cert(&domains, &directory_url).await {
+                                Ok(Some(pem)) => {
+                                    let cert = rustls_pemfile::certs(&mut pem.as_slice()).next();
+                                    if let Some(Ok(cert)) = cert {
+                                        expiry.set(&cert);
+                                    }
+                                }
+                                Ok(None) => {}
+                                Err(err) => warn!("failed to read cached certificate: {err}"),
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        inc!(Metrics, acme_renewal_error);
+                        error!("error: {:?}", err);
+                    }
+                }
+            }
+        }
+        .instrument(info_span!("acme")),
+    );
+    resolver
+}
+
+/// Resolves the certificate by the server name sent by the client.
+#[cfg(feature = "https")]
+#[derive(Debug)]
+struct SniResolver {
+    /// The domains and resolvers of the certificates, the default certificate first
+    certs: Vec<(Vec<String>, Arc<dyn ResolvesServerCert>)>,
+}
+
+#[cfg(feature = "https")]
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let (_, default) = self.certs.first()?;
+        let resolver = client_hello
+            .server_name()
+            .and_then(|name| {
+                self.certs
+                    .iter()
+                    .find(|(domains, _)| domains.iter().any(|d| domain_matches(d, name)))
+            })
+            .map_or(default, |(_, resolver)| resolver);
+        resolver.resolve(client_hello)
+    }
+}
 
+/// Whether `name` matches `domain`, which may be a wildcard domain.
+#[cfg(feature = "https")]
+fn domain_matches(domain: &str, name: &str) -> bool {
+    match domain.strip_prefix("*.") {
+        Some(base) => name
+            .split_once('.')
+            .is_some_and(|(_, rest)| rest.eq_ignore_ascii_case(base)),
+        None => domain.eq_ignore_ascii_case(name),
+    }
+}
+
+/// The certificate and key files of [`CertMode::Manual`] and [`CertMode::LetsEncryptDns`].
+#[cfg(feature = "https")]
+#[derive(Debug)]
+struct CertFiles {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+#[cfg(feature = "https")]
+impl CertFiles {
+    /// Get the modification times of the files.
+    async fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| {
+            let path = path.to_owned();
+            async move {
+                tokio::fs::metadata(path)
+                    .await
+                    .and_then(|m| m.modified())
+                    .ok()
+            }
+        };
+        tokio::join!(modified(&self.cert_path), modified(&self.key_path))
+    }
+
+    /// Load the certificate and key, and update `expiry`.
+    async fn load(&self, expiry: &CertExpiry) -> Result<Arc<CertifiedKey>> {
+        let cert_path = self.cert_path.clone();
+        let key_path = self.key_path.clone();
         let (certs, secret_key) = tokio::task::spawn_blocking(move || {
             let certs = load_certs(cert_path)?;
             let key = load_secret_key(key_path)?;
@@ -97,87 +940,153 @@ impl TlsAcceptor {
         })
         .await??;
 
-        let config = config.with_single_cert(certs, secret_key)?;
-        let config = RustlsConfig::from_config(Arc::new(config));
-        let acceptor = RustlsAcceptor::new(config);
-        Ok(Self::Manual(acceptor))
-    }
-
-    fn letsencrypt(
-        domains: Vec<String>,
-        contact: &str,
-        is_production: bool,
-        dir: PathBuf,
-    ) -> Result<Self> {
-        let config = rustls::ServerConfig::builder().with_no_client_auth();
-        let mut state = AcmeConfig::new(domains)
-            .contact([format!("mailto:{contact}")])
-            .cache_option(Some(DirCache::new(dir)))
-            .directory_lets_encrypt(is_production)
-            .state();
-
-        let config = config.with_cert_resolver(state.resolver());
-        let acceptor = state.acceptor();
-
-        tokio::spawn(
-            async move {
-                loop {
-                    match state.next().await.unwrap() {
-                        Ok(ok) => debug!("acme event: {:?}", ok),
-                        Err(err) => error!("error: {:?}", err),
-                    }
-                }
-            }
-            .instrument(info_span!("acme")),
-        );
-        let config = Arc::new(config);
-        let acceptor = AxumAcceptor::new(acceptor, config);
-        Ok(Self::LetsEncrypt(acceptor))
+        certified_key(certs, secret_key, expiry)
     }
 }
 
-fn load_certs(
-    filename: impl AsRef<Path>,
-) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
+/// Create the certified key from the certificate chain and its secret key, and update `expiry`.
+#[cfg(feature = "https")]
+fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    secret_key: PrivateKeyDer<'static>,
+    expiry: &CertExpiry,
+) -> Result<Arc<CertifiedKey>> {
+    let cert = certs.first().cloned().context("no certificates found")?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&secret_key)
+        .context("unsupported secret key")?;
+    let key = CertifiedKey::new(certs, signing_key);
+    key.keys_match()
+        .context("secret key does not match certificate")?;
+    expiry.set(&cert);
+    Ok(Arc::new(key))
+}
+
+/// Resolves to a certificate that can be replaced at runtime.
+///
+/// Handshakes fail while there is no certificate yet.
+#[cfg(feature = "https")]
+#[derive(Debug)]
+struct ReloadingCertResolver {
+    key: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+#[cfg(feature = "https")]
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.key.read().clone()
+    }
+}
+
+/// Get the expiry of a certificate, in seconds since the unix epoch.
+#[cfg(feature = "https")]
+fn cert_not_after(cert: &CertificateDer) -> Option<i64> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(cert.validity().not_after.timestamp())
+}
+
+/// Connect to the HTTPS server of `domain` on `port`, verify its certificate against the web PKI
+/// roots like a client would, and get the expiry of the certificate, in seconds since the unix
+/// epoch.
+#[cfg(feature = "https")]
+pub(crate) async fn served_cert_not_after(domain: &str, port: u16) -> Result<i64> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_name = rustls::pki_types::ServerName::try_from(domain.to_string())?;
+    let stream = tokio::net::TcpStream::connect((domain, port)).await?;
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+        .connect(server_name, stream)
+        .await?;
+    let (_, connection) = stream.get_ref();
+    connection
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(cert_not_after)
+        .context("the server sent no valid certificate")
+}
+
+#[cfg(feature = "https")]
+type ServerConfigBuilder =
+    rustls::ConfigBuilder<rustls::ServerConfig, rustls::server::WantsServerCert>;
+
+/// Create the builder for the rustls server config, with client authentication if configured.
+#[cfg(feature = "https")]
+async fn server_config_builder(
+    tls: &TlsConfig,
+    client_auth: Option<&ClientAuthConfig>,
+) -> Result<ServerConfigBuilder> {
+    let builder = rustls::ServerConfig::builder_with_provider(Arc::new(tls.crypto_provider()?))
+        .with_protocol_versions(&tls.protocol_versions())
+        .context("invalid TLS config")?;
+    let Some(client_auth) = client_auth else {
+        return Ok(builder.with_no_client_auth());
+    };
+    let ca_path = client_auth.ca_cert.clone();
+    let ca_certs = tokio::task::spawn_blocking(move || load_certs(ca_path)).await??;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in ca_certs {
+        roots
+            .add(cert)
+            .context("invalid CA certificate for client authentication")?;
+    }
+    // Client certificates are optional on the TLS level, they are only required on some routes.
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()
+        .context("failed to create client certificate verifier")?;
+    Ok(builder.with_client_cert_verifier(verifier))
+}
+
+#[cfg(feature = "https")]
+fn load_certs(filename: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
     let certfile = std::fs::File::open(filename).context("cannot open certificate file")?;
     let mut reader = std::io::BufReader::new(certfile);
+    read_certs(&mut reader)
+}
 
-    let certs: Result<Vec<_>, std::io::Error> = rustls_pemfile::certs(&mut reader).collect();
+#[cfg(feature = "https")]
+fn read_certs(reader: &mut dyn io::BufRead) -> Result<Vec<CertificateDer<'static>>> {
+    let certs: Result<Vec<_>, std::io::Error> = rustls_pemfile::certs(reader).collect();
     let certs = certs?;
 
     Ok(certs)
 }
 
-fn load_secret_key(
-    filename: impl AsRef<Path>,
-) -> Result<rustls::pki_types::PrivateKeyDer<'static>> {
+#[cfg(feature = "https")]
+fn load_secret_key(filename: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>> {
     let keyfile = std::fs::File::open(filename.as_ref()).context("cannot open secret key file")?;
     let mut reader = std::io::BufReader::new(keyfile);
+    read_secret_key(&mut reader)
+        .with_context(|| format!("no keys found in {}", filename.as_ref().display()))
+}
 
+#[cfg(feature = "https")]
+fn read_secret_key(reader: &mut dyn io::BufRead) -> Result<PrivateKeyDer<'static>> {
     loop {
-        match rustls_pemfile::read_one(&mut reader).context("cannot parse secret key .pem file")? {
+        match rustls_pemfile::read_one(reader).context("cannot parse secret key .pem file")? {
             Some(rustls_pemfile::Item::Pkcs1Key(key)) => {
-                return Ok(rustls::pki_types::PrivateKeyDer::Pkcs1(key));
+                return Ok(PrivateKeyDer::Pkcs1(key));
             }
             Some(rustls_pemfile::Item::Pkcs8Key(key)) => {
-                return Ok(rustls::pki_types::PrivateKeyDer::Pkcs8(key));
+                return Ok(PrivateKeyDer::Pkcs8(key));
             }
             Some(rustls_pemfile::Item::Sec1Key(key)) => {
-                return Ok(rustls::pki_types::PrivateKeyDer::Sec1(key));
+                return Ok(PrivateKeyDer::Sec1(key));
             }
             None => break,
             _ => {}
         }
     }
 
-    bail!(
-        "no keys found in {} (encrypted keys not supported)",
-        filename.as_ref().display()
-    );
+    bail!("no keys found (encrypted keys not supported)");
 }
 
+#[cfg(feature = "https")]
 static UNSAFE_HOSTNAME_CHARACTERS: OnceLock<regex::Regex> = OnceLock::new();
 
+#[cfg(feature = "https")]
 fn escape_hostname(hostname: &str) -> Cow<'_, str> {
     let regex = UNSAFE_HOSTNAME_CHARACTERS
         .get_or_init(|| regex::Regex::new(r"[^a-zA-Z0-9-\.]").expect("valid regex"));
@@ -0,0 +1,395 @@
+//! ACME servers other than LetsEncrypt, and external account binding
+
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    hmac,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{pki_types::PrivateKeyDer, ClientConfig, RootCertStore};
+use serde_json::{json, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio_rustls_acme::{
+    acme::{Account, Directory, LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY},
+    caches::DirCache,
+    AccountCache,
+};
+use tracing::info;
+
+use super::{load_certs, AcmeAccountInfo, AcmeDirectoryConfig, ExternalAccountBinding};
+use crate::secrets::SecretSource;
+
+/// The ACME account keys, one for each ACME directory.
+///
+/// The accounts are shared by all certificates, in all cert modes, so that redeploys and new
+/// domains don't register new accounts.
+#[derive(Debug, Clone)]
+pub(crate) struct AccountStore {
+    dir: PathBuf,
+}
+
+impl AccountStore {
+    /// Open the store in the `cert_cache` path.
+    pub(crate) fn new(cert_cache: &Path) -> Self {
+        Self {
+            dir: cert_cache.join("acme_accounts"),
+        }
+    }
+
+    fn path(&self, directory_url: &str, extension: &str) -> PathBuf {
+        let id = blake3::hash(directory_url.as_bytes());
+        let id = hex::encode(&id.as_bytes()[..8]);
+        self.dir.join(format!("account-{id}.{extension}"))
+    }
+
+    /// Load the account key (PKCS#8) for `directory_url`.
+    async fn load_key(&self, directory_url: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.path(directory_url, "pk8")).await {
+            Ok(key) => Ok(Some(key)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context("failed to read account key"),
+        }
+    }
+
+    /// Store the account key (PKCS#8) for `directory_url`.
+    async fn store_key(&self, directory_url: &str, contact: &[String], key: &[u8]) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.path(directory_url, "pk8"), key)
+            .await
+            .context("failed to write account key")?;
+        let info = AcmeAccountInfo {
+            directory_url: directory_url.to_string(),
+            contact: contact.to_vec(),
+            created: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            kid: None,
+        };
+        self.write_info(&info).await
+    }
+
+    /// Record the account URL returned by the CA.
+    pub(super) async fn set_kid(&self, directory_url: &str, kid: &str) -> Result<()> {
+        let mut info = match self.read_info(&self.path(directory_url, "json")).await {
+            Ok(info) => info,
+            // keys of previous versions were stored without info
+            Err(_) => AcmeAccountInfo {
+                directory_url: directory_url.to_string(),
+                contact: Vec::new(),
+                created: String::new(),
+                kid: None,
+            },
+        };
+        if info.kid.as_deref() == Some(kid) {
+            return Ok(());
+        }
+        info.kid = Some(kid.to_string());
+        self.write_info(&info).await
+    }
+
+    async fn read_info(&self, path: &Path) -> Result<AcmeAccountInfo> {
+        let info = tokio::fs::read(path).await?;
+        Ok(serde_json::from_slice(&info)?)
+    }
+
+    async fn write_info(&self, info: &AcmeAccountInfo) -> Result<()> {
+        let path = self.path(&info.directory_url, "json");
+        tokio::fs::write(path, serde_json::to_vec_pretty(info)?)
+            .await
+            .context("failed to write account info")?;
+        Ok(())
+    }
+
+    /// List the stored accounts.
+    pub(crate) async fn list(&self) -> Result<Vec<AcmeAccountInfo>> {
+        let mut accounts = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(accounts),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                accounts.push(self.read_info(&path).await?);
+            }
+        }
+        Ok(accounts)
+    }
+}
+
+/// An ACME server and how to connect to it.
+#[derive(Debug, Clone)]
+pub(super) struct AcmeServer {
+    pub(super) directory_url: String,
+    pub(super) client_config: Arc<ClientConfig>,
+    pub(super) accounts: AccountStore,
+    eab: Option<ExternalAccountBinding>,
+    account_key: Option<SecretSource>,
+}
+
+impl AcmeServer {
+    /// Create the server from `config`, or the LetsEncrypt production or staging server if unset.
+    ///
+    /// If `account_key` is set, the account key is loaded from it instead of `accounts`.
+    pub(super) async fn new(
+        config: Option<&AcmeDirectoryConfig>,
+        letsencrypt_prod: bool,
+        account_key: Option<SecretSource>,
+        accounts: AccountStore,
+    ) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ca_path) = config.and_then(|c| c.ca_cert.clone()) {
+            let ca_certs = tokio::task::spawn_blocking(move || load_certs(ca_path)).await??;
+            for cert in ca_certs {
+                roots
+                    .add(cert)
+                    .context("invalid CA certificate for ACME server")?;
+            }
+        }
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let directory_url = match (config, letsencrypt_prod) {
+            (Some(config), _) => config.directory_url.clone(),
+            (None, true) => LETS_ENCRYPT_PRODUCTION_DIRECTORY.to_string(),
+            (None, false) => LETS_ENCRYPT_STAGING_DIRECTORY.to_string(),
+        };
+        Ok(Self {
+            directory_url,
+            client_config: Arc::new(client_config),
+            accounts,
+            eab: config.and_then(|c| c.eab.clone()),
+            account_key,
+        })
+    }
+
+    /// Get the account key (PKCS#8) from the configured secret or the account store, or create
+    /// a new one.
+    ///
+    /// `legacy` looks up a key stored by previous versions, which is moved to the store.
+    pub(super) async fn account_key(
+        &self,
+        contact: &[String],
+        legacy: impl Future<Output = Result<Option<Vec<u8>>>>,
+    ) -> Result<Vec<u8>> {
+        if let Some(key) = self.configured_account_key().await? {
+            return Ok(key);
+        }
+        if let Some(key) = self.accounts.load_key(&self.directory_url).await? {
+            return Ok(key);
+        }
+        let key = match legacy.await? {
+            Some(key) => key,
+            None => self.new_account_key(contact).await?,
+        };
+        self.accounts
+            .store_key(&self.directory_url, contact, &key)
+            .await?;
+        Ok(key)
+    }
+
+    /// Load the account key (PKCS#8) from the configured secret, if any.
+    ///
+    /// The secret is loaded on every call, so rotated keys are picked up.
+    async fn configured_account_key(&self) -> Result<Option<Vec<u8>>> {
+        let Some(source) = &self.account_key else {
+            return Ok(None);
+        };
+        let pem = source.load().await?;
+        match rustls_pemfile::private_key(&mut pem.as_bytes())
+            .context("invalid ACME account key")?
+        {
+            Some(PrivateKeyDer::Pkcs8(key)) => Ok(Some(key.secret_pkcs8_der().to_vec())),
+            _ => bail!("the ACME account key must be a PKCS#8 PEM key"),
+        }
+    }
+
+    /// Create a new account key (PKCS#8).
+    ///
+    /// With external account binding, the account is registered right away, because the
+    /// binding is only needed to create the account.
+    async fn new_account_key(&self, contact: &[String]) -> Result<Vec<u8>> {
+        let key_pair = Account::generate_key_pair();
+        if let Some(eab) = &self.eab {
+            self.register(&key_pair, contact, eab)
+                .await
+                .context("failed to register ACME account with external account binding")?;
+            info!("registered ACME account with key id {}", eab.key_id);
+        }
+        Ok(key_pair)
+    }
+
+    async fn register(
+        &self,
+        key_pair: &[u8],
+        contact: &[String],
+        eab: &ExternalAccountBinding,
+    ) -> Result<()> {
+        let rng = SystemRandom::new();
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, key_pair, &rng)
+            .map_err(|err| anyhow!("invalid account key: {err}"))?;
+        let directory = Directory::discover(&self.client_config, &self.directory_url).await?;
+        let nonce = directory.nonce(&self.client_config).await?;
+        let url = &directory.new_account;
+
+        // the binding is a JWS over the account key, signed with the HMAC key of the CA
+        let hmac_key = URL_SAFE_NO_PAD
+            .decode(eab.hmac_key.trim_end_matches('='))
+            .context("invalid EAB HMAC key")?;
+        let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, &hmac_key);
+        let protected = json!({ "alg": "HS256", "kid": eab.key_id, "url": url });
+        let binding = jws(protected, &jwk(&key_pair), |input| {
+            Ok(hmac::sign(&hmac_key, input).as_ref().to_vec())
+        })?;
+
+        let protected =
+            json!({ "alg": "ES256", "jwk": jwk(&key_pair), "nonce": nonce, "url": url });
+        let payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": contact,
+            "externalAccountBinding": binding,
+        });
+        let body = jws(protected, &payload, |input| {
+            let signature = key_pair
+                .sign(&rng, input)
+                .map_err(|_| anyhow!("failed to sign request"))?;
+            Ok(signature.as_ref().to_vec())
+        })?;
+
+        let client = reqwest::Client::builder()
+            .use_preconfigured_tls((*self.client_config).clone())
+            .build()?;
+        let res = client
+            .post(url)
+            .header(http::header::CONTENT_TYPE, "application/jose+json")
+            .body(body.to_string())
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            bail!("{status}: {}", res.text().await.unwrap_or_default());
+        }
+        Ok(())
+    }
+}
+
+/// The JWK of an account key (RFC 7517).
+///
+/// Contains only the required members, which are serialized in lexicographic order, so that
+/// the serialization can also be used for the JWK thumbprint (RFC 7638).
+pub(super) fn jwk(key_pair: &EcdsaKeyPair) -> Value {
+    // the public key is encoded uncompressed, as 0x04 || x || y
+    let (x, y) = key_pair.public_key().as_ref()[1..].split_at(32);
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": URL_SAFE_NO_PAD.encode(x),
+        "y": URL_SAFE_NO_PAD.encode(y),
+    })
+}
+
+/// Create a JWS in flattened JSON serialization (RFC 7515).
+fn jws(
+    protected: Value,
+    payload: &Value,
+    sign: impl FnOnce(&[u8]) -> Result<Vec<u8>>,
+) -> Result<Value> {
+    let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+    let payload = URL_SAFE_NO_PAD.encode(payload.to_string());
+    let signature = sign(format!("{protected}.{payload}").as_bytes())?;
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": URL_SAFE_NO_PAD.encode(signature),
+    }))
+}
+
+/// An [`AccountCache`] that gets the account keys with [`AcmeServer::account_key`].
+///
+/// Keys of previous versions are looked up in the [`DirCache`].
+#[derive(derive_more::Debug)]
+pub(super) struct AccountKeyCache {
+    #[debug("DirCache")]
+    inner: DirCache<PathBuf>,
+    server: AcmeServer,
+}
+
+impl AccountKeyCache {
+    /// Create a cache for the accounts at `server`, which is stored in `dir`.
+    pub(super) fn new(dir: PathBuf, server: AcmeServer) -> Self {
+        Self {
+            inner: DirCache::new(dir),
+            server,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountCache for AccountKeyCache {
+    type EA = anyhow::Error;
+
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let legacy = async {
+            let key = self.inner.load_account(contact, directory_url).await?;
+            Ok(key)
+        };
+        let key = self.server.account_key(contact, legacy).await?;
+        Ok(Some(key))
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<()> {
+        self.server
+            .accounts
+            .store_key(directory_url, contact, account)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn account_store() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("acme-accounts-{}", std::process::id()));
+        let store = AccountStore::new(&dir);
+        let url = "https://acme.example.org/directory";
+        assert_eq!(store.load_key(url).await?, None);
+
+        let contact = vec!["mailto:admin@example.org".to_string()];
+        store.store_key(url, &contact, b"key").await?;
+        store
+            .set_kid(url, "https://acme.example.org/acct/1")
+            .await?;
+        assert_eq!(store.load_key(url).await?.as_deref(), Some(&b"key"[..]));
+        let accounts = store.list().await?;
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].contact, contact);
+        assert_eq!(
+            accounts[0].kid.as_deref(),
+            Some("https://acme.example.org/acct/1")
+        );
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
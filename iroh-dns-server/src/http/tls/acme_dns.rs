@@ -0,0 +1,348 @@
+//! ACME certificates with DNS-01 challenges
+//!
+//! The server is the authoritative name server for its origins, so it can answer DNS-01
+//! challenges itself: while an order is validated, the `_acme-challenge.<domain>` TXT records
+//! are served by the [`crate::dns::DnsHandler`]. This allows certificates for domains whose
+//! HTTPS port is not reachable by the CA.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use iroh_metrics::inc;
+use ring::{
+    digest::{digest, SHA256},
+    signature::EcdsaKeyPair,
+};
+use rustls::pki_types::CertificateDer;
+use serde::{Deserialize, Serialize};
+use tokio_rustls_acme::acme::{
+    Account, AuthStatus, ChallengeType, Directory, Identifier, Order, OrderStatus,
+};
+use tracing::{debug, info, warn};
+
+use super::{
+    acme::{jwk, AcmeServer},
+    CertExpiry, CertFiles, ReloadingCertResolver,
+};
+use crate::{dns::AcmeChallenges, metrics::Metrics};
+
+/// Renew certificates this long before they expire.
+const RENEW_BEFORE: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+/// Interval in which the certificate is checked for renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60 * 12);
+/// Interval in which failed orders are retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Interval in which the status of an order is polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Number of status polls after which an order is given up.
+const MAX_POLLS: usize = 60;
+
+/// Obtains and renews a certificate from an ACME CA with DNS-01 challenges.
+#[derive(Debug)]
+pub(super) struct AcmeDns {
+    server: AcmeServer,
+    contact: Vec<String>,
+    domains: Vec<String>,
+    dir: PathBuf,
+    challenges: AcmeChallenges,
+}
+
+impl AcmeDns {
+    /// Create a client for `server`.
+    ///
+    /// Pending orders are stored in `dir`.
+    pub(super) fn new(
+        server: AcmeServer,
+        domains: Vec<String>,
+        contact: &str,
+        dir: PathBuf,
+        challenges: AcmeChallenges,
+    ) -> Self {
+        Self {
+            server,
+            contact: vec![format!("mailto:{contact}")],
+            domains,
+            dir,
+            challenges,
+        }
+    }
+
+    /// Keep the certificate in `files` valid and serve it with `resolver`, forever.
+    pub(super) async fn run(
+        self,
+        files: CertFiles,
+        resolver: Arc<ReloadingCertResolver>,
+        expiry: CertExpiry,
+    ) {
+        loop {
+            let wait = match self.renew_if_needed(&files, &resolver, &expiry).await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(err) => {
+                    inc!(Metrics, acme_renewal_error);
+                    warn!("failed to obtain certificate: {err:#}");
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn renew_if_needed(
+        &self,
+        files: &CertFiles,
+        resolver: &ReloadingCertResolver,
+        expiry: &CertExpiry,
+    ) -> Result<()> {
+        match files.load(expiry).await {
+            Ok(key) => {
+                let expires_soon = key.cert.first().is_none_or(expires_soon);
+                *resolver.key.write() = Some(key);
+                if !expires_soon {
+                    return Ok(());
+                }
+                info!("certificate expires soon, renewing");
+            }
+            Err(err) => debug!("no cached certificate: {err:#}"),
+        }
+        self.order(files).await?;
+        inc!(Metrics, acme_renewal_success);
+        *resolver.key.write() = Some(files.load(expiry).await?);
+        info!("obtained certificate for {:?}", self.domains);
+        Ok(())
+    }
+
+    /// Order a certificate, and write it to `files`.
+    ///
+    /// The order is stored next to `files` until it completes, so that an order interrupted by
+    /// a restart is resumed instead of creating a new one.
+    async fn order(&self, files: &CertFiles) -> Result<()> {
+        let client_config = &self.server.client_config;
+        let account = self.account().await?;
+        let pending = PendingOrder::path(files);
+        let (state, mut order) = match self.resume_order(&account, &pending).await {
+            Some(resumed) => {
+                info!("resuming pending order {}", resumed.0.url);
+                resumed
+            }
+            None => {
+                let (url, order) = account
+                    .new_order(client_config, self.domains.clone())
+                    .await?;
+                let state = PendingOrder {
+                    url,
+                    domains: self.domains.clone(),
+                    key: rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?.serialize_pem(),
+                };
+                state.store(&pending).await?;
+                (state, order)
+            }
+        };
+
+        if order.status == OrderStatus::Processing {
+            order = self.poll_order(&account, &state.url).await?;
+        }
+        if order.status == OrderStatus::Pending {
+            let mut published = Vec::new();
+            let res = self
+                .validate(&account, &state.url, order, &mut published)
+                .await;
+            for (domain, value) in published {
+                self.challenges.remove(&domain, &value)?;
+            }
+            order = res?;
+        }
+        let mut params = rcgen::CertificateParams::new(self.domains.clone());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+        params.key_pair = Some(rcgen::KeyPair::from_pem(&state.key)?);
+        let cert = rcgen::Certificate::from_params(params)?;
+        if order.status == OrderStatus::Ready {
+            let csr = cert.serialize_request_der()?;
+            account
+                .finalize(client_config, &order.finalize, csr)
+                .await?;
+            order = self.poll_order(&account, &state.url).await?;
+        }
+        let OrderStatus::Valid { certificate } = order.status else {
+            // the order can't be completed anymore, the next attempt creates a new one
+            PendingOrder::remove(&pending).await;
+            bail!("order failed: {:?} {:?}", order.status, order.error);
+        };
+        let chain = account.certificate(client_config, certificate).await?;
+
+        tokio::fs::write(&files.key_path, cert.serialize_private_key_pem())
+            .await
+            .context("failed to write secret key")?;
+        tokio::fs::write(&files.cert_path, chain)
+            .await
+            .context("failed to write certificate")?;
+        PendingOrder::remove(&pending).await;
+        Ok(())
+    }
+
+    /// Look up the stored pending order, if it is for the configured domains and can still be
+    /// completed.
+    async fn resume_order(&self, account: &Account, path: &Path) -> Option<(PendingOrder, Order)> {
+        let state = PendingOrder::load(path).await?;
+        if state.domains != self.domains {
+            PendingOrder::remove(path).await;
+            return None;
+        }
+        match account.order(&self.server.client_config, &state.url).await {
+            Ok(order) if order.status != OrderStatus::Invalid => Some((state, order)),
+            Ok(_) => {
+                debug!("pending order is invalid");
+                PendingOrder::remove(path).await;
+                None
+            }
+            Err(err) => {
+                debug!("failed to look up pending order: {err:#}");
+                PendingOrder::remove(path).await;
+                None
+            }
+        }
+    }
+
+    /// Publish the DNS-01 challenges of `order`, and wait for the CA to validate them.
+    ///
+    /// The published challenges are added to `published`, so that the caller can remove them
+    /// also if validation fails.
+    async fn validate(
+        &self,
+        account: &Account,
+        url: &str,
+        order: Order,
+        published: &mut Vec<(String, String)>,
+    ) -> Result<Order> {
+        let client_config = &self.server.client_config;
+        let mut challenge_urls = Vec::new();
+        for auth_url in &order.authorizations {
+            let auth = account.auth(client_config, auth_url).await?;
+            if !matches!(auth.status, AuthStatus::Pending) {
+                continue;
+            }
+            let Identifier::Dns(domain) = auth.identifier;
+            let challenge = auth
+                .challenges
+                .iter()
+                .find(|c| c.typ == ChallengeType::Dns01)
+                .with_context(|| format!("no dns-01 challenge offered for {domain}"))?;
+            let value = dns_01_value(&account.key_pair, &challenge.token);
+            debug!(%domain, "publishing dns-01 challenge");
+            self.challenges.insert(&domain, value.clone())?;
+            published.push((domain, value));
+            challenge_urls.push(challenge.url.clone());
+        }
+        for challenge_url in challenge_urls {
+            account.challenge(client_config, challenge_url).await?;
+        }
+        self.poll_order(account, url).await
+    }
+
+    /// Poll the order until it is no longer pending or processing.
+    async fn poll_order(&self, account: &Account, url: &str) -> Result<Order> {
+        for _ in 0..MAX_POLLS {
+            let order = account.order(&self.server.client_config, url).await?;
+            match order.status {
+                OrderStatus::Pending | OrderStatus::Processing => {
+                    tokio::time::sleep(POLL_INTERVAL).await
+                }
+                _ => return Ok(order),
+            }
+        }
+        bail!("order did not complete in time");
+    }
+
+    /// Create the ACME account, or look it up if the account key already exists.
+    async fn account(&self) -> Result<Account> {
+        let client_config = &self.server.client_config;
+        // previous versions stored the account key in the directory of this cert mode
+        let id = blake3::hash(self.server.directory_url.as_bytes());
+        let legacy_path = self
+            .dir
+            .join(format!("account-{}.pk8", hex::encode(&id.as_bytes()[..8])));
+        let legacy = async {
+            match tokio::fs::read(&legacy_path).await {
+                Ok(key_pair) => Ok(Some(key_pair)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err).context("failed to read account key"),
+            }
+        };
+        let key_pair = self.server.account_key(&self.contact, legacy).await?;
+        let directory = Directory::discover(client_config, &self.server.directory_url).await?;
+        let account =
+            Account::create_with_keypair(client_config, directory, &self.contact, &key_pair)
+                .await?;
+        self.server
+            .accounts
+            .set_kid(&self.server.directory_url, &account.kid)
+            .await?;
+        Ok(account)
+    }
+}
+
+/// An order that has not completed yet.
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingOrder {
+    /// The URL of the order
+    url: String,
+    /// The domains of the order
+    domains: Vec<String>,
+    /// The secret key of the certificate (PEM)
+    key: String,
+}
+
+impl PendingOrder {
+    /// The path of the pending order of the certificate in `files`.
+    fn path(files: &CertFiles) -> PathBuf {
+        files.cert_path.with_extension("order.json")
+    }
+
+    async fn load(path: &Path) -> Option<Self> {
+        let state = tokio::fs::read(path).await.ok()?;
+        serde_json::from_slice(&state)
+            .inspect_err(|err| warn!("invalid pending order at {}: {err}", path.display()))
+            .ok()
+    }
+
+    async fn store(&self, path: &Path) -> Result<()> {
+        tokio::fs::write(path, serde_json::to_vec(self)?)
+            .await
+            .context("failed to write pending order")
+    }
+
+    async fn remove(path: &Path) {
+        if let Err(err) = tokio::fs::remove_file(path).await {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!(
+                    "failed to remove pending order at {}: {err}",
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+/// Whether the certificate expires within [`RENEW_BEFORE`].
+fn expires_soon(cert: &CertificateDer) -> bool {
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return true;
+    };
+    cert.validity()
+        .time_to_expiration()
+        .is_none_or(|left| left.whole_seconds() < RENEW_BEFORE.as_secs() as i64)
+}
+
+/// The TXT record value for a DNS-01 challenge (RFC 8555, section 8.4).
+fn dns_01_value(key_pair: &EcdsaKeyPair, token: &str) -> String {
+    let b64 = |data: &[u8]| URL_SAFE_NO_PAD.encode(data);
+    let thumbprint = b64(digest(&SHA256, jwk(key_pair).to_string().as_bytes()).as_ref());
+    let key_authorization = format!("{token}.{thumbprint}");
+    b64(digest(&SHA256, key_authorization.as_bytes()).as_ref())
+}
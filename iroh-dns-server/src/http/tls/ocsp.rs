@@ -0,0 +1,404 @@
+//! OCSP stapling
+//!
+//! The OCSP response for a certificate is fetched from the responder listed in the certificate
+//! once it is first served, and refreshed in the background before it expires. Clients that
+//! check revocation then don't need to contact the responder themselves.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use iroh_metrics::inc;
+use parking_lot::RwLock;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use rustls::{
+    pki_types::CertificateDer,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::sync::Notify;
+use tokio_rustls_acme::acme::ACME_TLS_ALPN_NAME;
+use tracing::{debug, info_span, warn, Instrument};
+use x509_parser::{
+    certificate::X509Certificate, extensions::ParsedExtension, oid_registry, prelude::GeneralName,
+};
+
+use crate::metrics::{CertMetrics, Metrics};
+
+/// Interval in which failed fetches are retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 10);
+/// Interval in which responses without a next update time are refreshed.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60 * 12);
+/// Timeout for requests to the OCSP responder.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Staples OCSP responses to the certificates of an inner resolver.
+#[derive(Debug)]
+pub(super) struct StaplingResolver {
+    inner: Arc<dyn ResolvesServerCert>,
+    domains: Vec<String>,
+    current: RwLock<Option<Stapled>>,
+    changed: Notify,
+}
+
+/// The certificate that was served last, and its OCSP response.
+#[derive(Debug)]
+struct Stapled {
+    /// The certificate as resolved by the inner resolver
+    key: Arc<CertifiedKey>,
+    /// The certificate with the OCSP response, if there is a valid one
+    stapled: Arc<CertifiedKey>,
+    /// Expiry of the OCSP response, in seconds since the unix epoch
+    next_update: Option<i64>,
+}
+
+impl StaplingResolver {
+    /// Wrap `inner`, and spawn a task that fetches the OCSP responses for its certificates.
+    pub(super) fn spawn(inner: Arc<dyn ResolvesServerCert>, domains: Vec<String>) -> Arc<Self> {
+        let resolver = Arc::new(Self {
+            inner,
+            domains,
+            current: RwLock::new(None),
+            changed: Notify::new(),
+        });
+        let span = info_span!("ocsp", domains = ?resolver.domains);
+        tokio::spawn(resolver.clone().refresh_loop().instrument(span));
+        resolver
+    }
+
+    async fn refresh_loop(self: Arc<Self>) {
+        loop {
+            let key = self.current.read().as_ref().map(|c| c.key.clone());
+            let wait = match key {
+                None => None,
+                Some(key) => match fetch(&key.cert).await {
+                    Ok(Some(response)) => {
+                        inc!(Metrics, ocsp_refresh_success);
+                        let wait = response.refresh_in();
+                        debug!("fetched OCSP response, refreshing in {wait:?}");
+                        self.staple(&key, response);
+                        Some(wait)
+                    }
+                    Ok(None) => {
+                        debug!("certificate has no OCSP responder");
+                        None
+                    }
+                    Err(err) => {
+                        inc!(Metrics, ocsp_refresh_error);
+                        warn!("failed to fetch OCSP response: {err:#}");
+                        self.remove_expired();
+                        Some(RETRY_INTERVAL)
+                    }
+                },
+            };
+            match wait {
+                Some(wait) => tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = self.changed.notified() => {}
+                },
+                None => self.changed.notified().await,
+            }
+        }
+    }
+
+    /// Staple `response` to `key`, unless the certificate changed in the meantime.
+    fn staple(&self, key: &Arc<CertifiedKey>, response: OcspResponse) {
+        let mut current = self.current.write();
+        let Some(current) = current.as_mut().filter(|c| Arc::ptr_eq(&c.key, key)) else {
+            return;
+        };
+        let mut stapled = CertifiedKey::clone(key);
+        stapled.ocsp = Some(response.der);
+        current.stapled = Arc::new(stapled);
+        current.next_update = response.next_update;
+        if let Some(next_update) = response.next_update {
+            let gauge = &CertMetrics::get().ocsp_next_update_timestamp;
+            CertMetrics::set(gauge, &self.domains, next_update);
+        }
+    }
+
+    /// Stop stapling the OCSP response if it expired.
+    fn remove_expired(&self) {
+        let mut current = self.current.write();
+        let Some(current) = current.as_mut() else {
+            return;
+        };
+        if current.next_update.is_some_and(|t| t < now()) {
+            warn!("OCSP response expired, no longer stapling it");
+            current.stapled = current.key.clone();
+            current.next_update = None;
+        }
+    }
+}
+
+impl ResolvesServerCert for StaplingResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let is_validation = client_hello
+            .alpn()
+            .into_iter()
+            .flatten()
+            .eq([ACME_TLS_ALPN_NAME]);
+        let key = self.inner.resolve(client_hello)?;
+        if is_validation {
+            return Some(key);
+        }
+        if let Some(current) = self.current.read().as_ref() {
+            if Arc::ptr_eq(&current.key, &key) {
+                return Some(current.stapled.clone());
+            }
+        }
+        // a new certificate, the refresh task fetches its OCSP response
+        *self.current.write() = Some(Stapled {
+            key: key.clone(),
+            stapled: key.clone(),
+            next_update: None,
+        });
+        self.changed.notify_one();
+        Some(key)
+    }
+}
+
+/// A DER encoded OCSP response.
+#[derive(Debug)]
+struct OcspResponse {
+    der: Vec<u8>,
+    /// Seconds since the unix epoch
+    this_update: i64,
+    /// Seconds since the unix epoch
+    next_update: Option<i64>,
+}
+
+impl OcspResponse {
+    /// Parse the parts of the response that are needed to staple and refresh it.
+    ///
+    /// The signature is not verified, this is left to the clients.
+    fn parse(der: Vec<u8>) -> Result<Self> {
+        let (_, response, _) = read_tlv(&der, TAG_SEQUENCE)?;
+        let (_, status, rest) = read_tlv(response, TAG_ENUMERATED)?;
+        ensure!(status == [0], "OCSP responder returned status {status:?}");
+        let (_, bytes, _) = read_tlv(rest, 0xa0)?;
+        let (_, bytes, _) = read_tlv(bytes, TAG_SEQUENCE)?;
+        let (_, _response_type, rest) = read_tlv(bytes, TAG_OID)?;
+        let (_, basic, _) = read_tlv(rest, TAG_OCTET_STRING)?;
+        let (_, basic, _) = read_tlv(basic, TAG_SEQUENCE)?;
+        let (_, response_data, _) = read_tlv(basic, TAG_SEQUENCE)?;
+        // skip the optional version, the responder id and the production time
+        let mut rest = response_data;
+        let responses = loop {
+            let (tag, value, next) = read_tlv(rest, None)?;
+            if tag == TAG_SEQUENCE {
+                break value;
+            }
+            rest = next;
+        };
+        let (_, single, _) = read_tlv(responses, TAG_SEQUENCE)?;
+        let (_, _cert_id, rest) = read_tlv(single, TAG_SEQUENCE)?;
+        let (cert_status, _, rest) = read_tlv(rest, None)?;
+        match cert_status {
+            0x80 => {}
+            0xa1 => warn!("OCSP responder reports the certificate as revoked"),
+            _ => bail!("OCSP responder does not know the certificate"),
+        }
+        let (_, this_update, rest) = read_tlv(rest, TAG_GENERALIZED_TIME)?;
+        let next_update = match read_tlv(rest, 0xa0) {
+            Ok((_, next_update, _)) => {
+                let (_, next_update, _) = read_tlv(next_update, TAG_GENERALIZED_TIME)?;
+                Some(parse_generalized_time(next_update)?)
+            }
+            Err(_) => None,
+        };
+        Ok(Self {
+            this_update: parse_generalized_time(this_update)?,
+            next_update,
+            der,
+        })
+    }
+
+    /// Time after which the response should be refreshed: half way through its validity.
+    fn refresh_in(&self) -> Duration {
+        let Some(next_update) = self.next_update else {
+            return DEFAULT_REFRESH_INTERVAL;
+        };
+        let refresh_at = self.this_update + (next_update - self.this_update) / 2;
+        let secs = (refresh_at - now()).max(RETRY_INTERVAL.as_secs() as i64);
+        Duration::from_secs(secs as u64)
+    }
+}
+
+/// Fetch the OCSP response for the leaf of `chain`.
+///
+/// Returns `None` if the certificate lists no OCSP responder.
+async fn fetch(chain: &[CertificateDer<'static>]) -> Result<Option<OcspResponse>> {
+    let Some(cert) = chain.first() else {
+        return Ok(None);
+    };
+    let cert = parse_cert(cert)?;
+    let Some(url) = responder_url(&cert) else {
+        return Ok(None);
+    };
+    let issuer = chain
+        .get(1)
+        .context("certificate chain does not contain the issuer")?;
+    let issuer = parse_cert(issuer)?;
+
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    let res = client
+        .post(&url)
+        .header(http::header::CONTENT_TYPE, "application/ocsp-request")
+        .body(request(&cert, &issuer))
+        .send()
+        .await?
+        .error_for_status()?;
+    let der = res.bytes().await?.to_vec();
+    let response = OcspResponse::parse(der).context("invalid OCSP response")?;
+    Ok(Some(response))
+}
+
+fn parse_cert<'a>(cert: &'a CertificateDer) -> Result<X509Certificate<'a>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|err| anyhow!("invalid certificate: {err}"))?;
+    Ok(cert)
+}
+
+/// Get the URL of the OCSP responder from the authority information access extension.
+fn responder_url(cert: &X509Certificate) -> Option<String> {
+    cert.extensions()
+        .iter()
+        .find_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(aia) => aia.accessdescs.iter().find_map(|desc| {
+                match (&desc.access_method, &desc.access_location) {
+                    (method, GeneralName::URI(uri))
+                        if *method == oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP =>
+                    {
+                        Some(uri.to_string())
+                    }
+                    _ => None,
+                }
+            }),
+            _ => None,
+        })
+}
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_NULL: u8 = 0x05;
+const TAG_OID: u8 = 0x06;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+
+/// DER encoding of the SHA-1 OID (1.3.14.3.2.26).
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+
+/// Create a DER encoded OCSP request for `cert` (RFC 6960, section 4.1).
+fn request(cert: &X509Certificate, issuer: &X509Certificate) -> Vec<u8> {
+    let sha1 = |data: &[u8]| digest(&SHA1_FOR_LEGACY_USE_ONLY, data).as_ref().to_vec();
+    let hash_algorithm = der(
+        TAG_SEQUENCE,
+        &[der(TAG_OID, OID_SHA1), der(TAG_NULL, &[])].concat(),
+    );
+    let cert_id = der(
+        TAG_SEQUENCE,
+        &[
+            hash_algorithm,
+            der(TAG_OCTET_STRING, &sha1(issuer.subject().as_raw())),
+            der(
+                TAG_OCTET_STRING,
+                &sha1(&issuer.public_key().subject_public_key.data),
+            ),
+            der(TAG_INTEGER, cert.raw_serial()),
+        ]
+        .concat(),
+    );
+    // OCSPRequest { TBSRequest { requestList { Request { CertID } } } }
+    let request = der(TAG_SEQUENCE, &cert_id);
+    let request_list = der(TAG_SEQUENCE, &request);
+    let tbs_request = der(TAG_SEQUENCE, &request_list);
+    der(TAG_SEQUENCE, &tbs_request)
+}
+
+/// Encode a DER value.
+fn der(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes = len.to_be_bytes();
+        let len_bytes = &len_bytes[len_bytes.iter().take_while(|b| **b == 0).count()..];
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend_from_slice(len_bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+/// Read a DER value, and return its tag, its value and the remaining input.
+///
+/// Fails if the tag is not `expected`.
+fn read_tlv(input: &[u8], expected: impl Into<Option<u8>>) -> Result<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first().context("unexpected end of input")?;
+    if let Some(expected) = expected.into() {
+        ensure!(
+            tag == expected,
+            "expected tag {expected:#x}, found {tag:#x}"
+        );
+    }
+    let (&len, mut rest) = rest.split_first().context("unexpected end of input")?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let num_bytes = (len & 0x7f) as usize;
+        ensure!(num_bytes <= 4 && rest.len() >= num_bytes, "invalid length");
+        let (len_bytes, r) = rest.split_at(num_bytes);
+        rest = r;
+        len_bytes.iter().fold(0, |len, b| (len << 8) | *b as usize)
+    };
+    ensure!(rest.len() >= len, "unexpected end of input");
+    let (value, rest) = rest.split_at(len);
+    Ok((tag, value, rest))
+}
+
+/// Parse a GeneralizedTime (`YYYYMMDDHHMMSSZ`) to seconds since the unix epoch.
+fn parse_generalized_time(value: &[u8]) -> Result<i64> {
+    let value = std::str::from_utf8(value)?;
+    let digits = value
+        .strip_suffix('Z')
+        .filter(|d| d.len() == 14)
+        .context("unsupported time format")?;
+    let num = |range: std::ops::Range<usize>| -> Result<u32> { Ok(digits[range].parse()?) };
+    let month = time::Month::try_from(num(4..6)? as u8)?;
+    let date = time::Date::from_calendar_date(num(0..4)? as i32, month, num(6..8)? as u8)?;
+    let time = time::Time::from_hms(num(8..10)? as u8, num(10..12)? as u8, num(12..14)? as u8)?;
+    Ok(date.with_time(time).assume_utc().unix_timestamp())
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn der_roundtrip() -> Result<()> {
+        for len in [0, 1, 0x7f, 0x80, 0x1234] {
+            let value = vec![7u8; len];
+            let encoded = [der(TAG_OCTET_STRING, &value), vec![1, 2]].concat();
+            let (tag, decoded, rest) = read_tlv(&encoded, TAG_OCTET_STRING)?;
+            assert_eq!(tag, TAG_OCTET_STRING);
+            assert_eq!(decoded, &value[..]);
+            assert_eq!(rest, &[1, 2]);
+        }
+        assert_eq!(parse_generalized_time(b"20240301120000Z")?, 1709294400);
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! TLS session tickets with keys shared by all servers of a fleet
+//!
+//! The ticket keys are derived from the configured keys for each rotation period, so that
+//! servers with the same keys accept each other's tickets without coordination. Tickets of the
+//! previous period are still accepted, older tickets are rejected. A leaked ticket key only
+//! exposes the sessions of two periods, but the configured keys can decrypt all tickets, so
+//! they should be rotated as well.
+
+use std::time::{Duration, SystemTime};
+
+use anyhow::{ensure, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::server::ProducesTickets;
+use tracing::debug;
+
+use crate::secrets::{RefreshingSecret, SecretValue};
+
+/// Minimum length of the configured keys.
+const MIN_KEY_LEN: usize = 32;
+/// Salt for the derivation of the ticket keys.
+const KEY_SALT: &[u8] = b"iroh-dns-server session ticket key";
+/// Length of the rotation period prefix of a ticket.
+const PERIOD_LEN: usize = 8;
+/// Maximum lifetime of a ticket in TLS 1.3 (RFC 8446, section 4.6.1).
+const MAX_LIFETIME_SECS: u64 = 60 * 60 * 24 * 7;
+
+/// Encrypts session tickets with keys derived from shared secrets.
+///
+/// The first key encrypts new tickets, all keys decrypt them. To replace a key without
+/// breaking resumption, add the new key after it on all servers, then move it to the front.
+#[derive(derive_more::Debug)]
+pub(super) struct SharedTicketer {
+    #[debug(skip)]
+    keys: Vec<RefreshingSecret>,
+    rotation: Duration,
+    #[debug(skip)]
+    rng: SystemRandom,
+}
+
+impl SharedTicketer {
+    /// Load the keys, which are base64 encoded and at least 32 bytes long.
+    pub(super) async fn new(keys: &[SecretValue], rotation: Duration) -> Result<Self> {
+        ensure!(
+            rotation.as_secs() > 0,
+            "session ticket rotation must be at least one second"
+        );
+        let mut secrets = Vec::new();
+        for key in keys {
+            let secret = RefreshingSecret::new(key).await?;
+            decode_key(&secret.get()).context("invalid session ticket key")?;
+            secrets.push(secret);
+        }
+        Ok(Self {
+            keys: secrets,
+            rotation,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    fn period(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs() / self.rotation.as_secs()
+    }
+
+    /// Derive the ticket key of `period` from the configured key.
+    fn ticket_key(secret: &RefreshingSecret, period: u64) -> Option<LessSafeKey> {
+        let ikm = decode_key(&secret.get())
+            .inspect_err(|err| debug!("invalid session ticket key: {err:#}"))
+            .ok()?;
+        let info = period.to_be_bytes();
+        let info = [info.as_slice()];
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, KEY_SALT).extract(&ikm);
+        let okm = prk.expand(&info, &AES_256_GCM).ok()?;
+        Some(LessSafeKey::new(UnboundKey::from(okm)))
+    }
+}
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs().min(MAX_LIFETIME_SECS) as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let period = self.period();
+        let key = Self::ticket_key(self.keys.first()?, period)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+
+        let prefix = period.to_be_bytes();
+        let mut in_out = plain.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(prefix),
+            &mut in_out,
+        )
+        .ok()?;
+        Some([prefix.as_slice(), &nonce, &in_out].concat())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < PERIOD_LEN + NONCE_LEN + aead::MAX_TAG_LEN {
+            return None;
+        }
+        let (prefix, rest) = cipher.split_at(PERIOD_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let period = u64::from_be_bytes(prefix.try_into().ok()?);
+        let current = self.period();
+        if period != current && period + 1 != current {
+            return None;
+        }
+        self.keys.iter().find_map(|secret| {
+            let key = Self::ticket_key(secret, period)?;
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut in_out = ciphertext.to_vec();
+            let plain = key
+                .open_in_place(nonce, Aad::from(prefix), &mut in_out)
+                .ok()?;
+            Some(plain.to_vec())
+        })
+    }
+}
+
+fn decode_key(key: &str) -> Result<Vec<u8>> {
+    let key = STANDARD.decode(key.trim())?;
+    ensure!(
+        key.len() >= MIN_KEY_LEN,
+        "key must be at least {MIN_KEY_LEN} bytes"
+    );
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shared_keys() -> Result<()> {
+        let key_a = SecretValue::Value(STANDARD.encode([1u8; 32]));
+        let key_b = SecretValue::Value(STANDARD.encode([2u8; 32]));
+        let rotation = Duration::from_secs(3600);
+        let server_a = SharedTicketer::new(std::slice::from_ref(&key_a), rotation).await?;
+        let server_b = SharedTicketer::new(&[key_b.clone(), key_a], rotation).await?;
+        let server_c = SharedTicketer::new(&[key_b], rotation).await?;
+
+        let ticket = server_a.encrypt(b"session").expect("encrypted");
+        assert_eq!(server_a.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+        assert_eq!(server_b.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+        assert_eq!(server_c.decrypt(&ticket), None);
+
+        assert!(
+            SharedTicketer::new(&[SecretValue::Value("short".into())], rotation)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
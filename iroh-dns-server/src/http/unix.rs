@@ -0,0 +1,81 @@
+//! Serve the HTTP app on a unix domain socket
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    pin::pin,
+};
+
+use anyhow::{Context, Result};
+use axum::{extract::ConnectInfo, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use tokio::net::UnixListener;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tower::ServiceExt;
+use tracing::{debug, info};
+
+/// The peer address reported for connections on the unix socket.
+///
+/// Unix socket peers are on the local host, so they are reported as loopback. Deployments that
+/// put a reverse proxy in front of the socket should use the smart rate limiting mode to
+/// recover the original client IP from the proxy headers.
+const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Bind a unix domain socket at `path`.
+///
+/// A stale socket file from a previous run is removed before binding.
+pub(crate) fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale socket at {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind unix socket at {}", path.display()))?;
+    Ok(listener)
+}
+
+/// Accept connections on `listener` and serve requests with `app`.
+///
+/// When `cancel` is triggered, stops accepting connections and waits for open connections to
+/// finish their in-flight requests.
+pub(crate) async fn serve(
+    listener: UnixListener,
+    app: Router,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    info!("HTTP server listening on {:?}", listener.local_addr()?);
+    let connections = TaskTracker::new();
+    loop {
+        let stream = tokio::select! {
+            res = listener.accept() => res?.0,
+            _ = cancel.cancelled() => break,
+        };
+        let app = app.clone();
+        let cancel = cancel.clone();
+        connections.spawn(async move {
+            let service = hyper::service::service_fn(move |mut req: http::Request<Incoming>| {
+                req.extensions_mut().insert(ConnectInfo(UNIX_PEER_ADDR));
+                app.clone().oneshot(req)
+            });
+            let builder = Builder::new(TokioExecutor::new());
+            let mut conn = pin!(builder.serve_connection(TokioIo::new(stream), service));
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = cancel.cancelled() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = res {
+                debug!("unix socket connection closed with error: {err:#}");
+            }
+        });
+    }
+    connections.close();
+    connections.wait().await;
+    Ok(())
+}
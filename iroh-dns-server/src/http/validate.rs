@@ -0,0 +1,101 @@
+//! Validation of published packets by embedders
+//!
+//! A [`PacketValidator`] sees every packet that is published with `PUT /pkarr/{key}` after its
+//! signature was verified and before it is stored, and can reject it or store another packet
+//! instead. The validators run in the order they were added to the
+//! [`Server::builder`](crate::server::Server::builder).
+
+use std::{fmt, sync::Arc};
+
+use anyhow::{ensure, Result};
+use async_trait::async_trait;
+use pkarr::SignedPacket;
+
+/// What to do with a published packet
+#[derive(Debug, Clone)]
+pub enum Validation {
+    /// Store the packet
+    Accept,
+    /// Store this packet instead, which must be signed by the same key
+    ///
+    /// Clients verify the signature of the packets they resolve, so the server can't change a
+    /// packet itself. An organization that holds the keys of its nodes can sign a changed packet.
+    Replace(SignedPacket),
+    /// Don't store the packet, and answer the publish with `403 Forbidden` and this reason
+    Reject(String),
+}
+
+/// A validator of the packets that are published with `PUT /pkarr/{key}`
+#[async_trait]
+pub trait PacketValidator: fmt::Debug + Send + Sync + 'static {
+    /// Validate a packet whose signature was verified, before it is stored.
+    async fn validate(&self, packet: &SignedPacket) -> Validation;
+}
+
+/// A [`PacketValidator`] that calls a function, see [`validator_fn`]
+struct FnValidator<F>(F);
+
+impl<F> fmt::Debug for FnValidator<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FnValidator").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<F> PacketValidator for FnValidator<F>
+where
+    F: Fn(&SignedPacket) -> Validation + Send + Sync + 'static,
+{
+    async fn validate(&self, packet: &SignedPacket) -> Validation {
+        (self.0)(packet)
+    }
+}
+
+/// A [`PacketValidator`] that validates the packets with the synchronous function `f`.
+pub fn validator_fn(
+    f: impl Fn(&SignedPacket) -> Validation + Send + Sync + 'static,
+) -> impl PacketValidator {
+    FnValidator(f)
+}
+
+/// The error of a publish that a [`PacketValidator`] rejected
+#[derive(Debug)]
+pub struct PacketRejected(pub String);
+
+impl fmt::Display for PacketRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "packet rejected: {}", self.0)
+    }
+}
+
+impl std::error::Error for PacketRejected {}
+
+/// The validators of the published packets, in the order they run
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PacketValidators(Arc<Vec<Arc<dyn PacketValidator>>>);
+
+impl PacketValidators {
+    pub(crate) fn new(validators: Vec<Arc<dyn PacketValidator>>) -> Self {
+        Self(Arc::new(validators))
+    }
+
+    /// Run the validators on `packet`, and return the packet to store.
+    ///
+    /// Fails with [`PacketRejected`] if a validator rejected the packet.
+    pub(crate) async fn validate(&self, mut packet: SignedPacket) -> Result<SignedPacket> {
+        for validator in self.0.iter() {
+            match validator.validate(&packet).await {
+                Validation::Accept => {}
+                Validation::Replace(replacement) => {
+                    ensure!(
+                        replacement.public_key() == packet.public_key(),
+                        "{validator:?} replaced the packet with one of another key"
+                    );
+                    packet = replacement;
+                }
+                Validation::Reject(reason) => return Err(PacketRejected(reason).into()),
+            }
+        }
+        Ok(packet)
+    }
+}
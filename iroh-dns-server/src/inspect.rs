@@ -0,0 +1,147 @@
+//! Decoding of signed packets, for the `packet inspect` command
+
+use std::{fmt, path::Path};
+
+use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
+use hickory_proto::{op::Message, rr::Record, serialize::binary::BinDecodable};
+use pkarr::SignedPacket;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use url::Url;
+
+use crate::{store::ZoneStore, util::PublicKeyBytes};
+
+/// Length of the public key, signature and timestamp before the DNS packet.
+const HEADER_LEN: usize = 32 + 64 + 8;
+
+/// A decoded signed packet
+#[derive(Debug)]
+pub struct PacketReport {
+    /// The z-base-32 encoded public key the packet claims to be signed by
+    pub public_key: String,
+    /// The timestamp of the packet, in microseconds since the unix epoch
+    pub timestamp: u64,
+    /// Why the signature or encoding is invalid, or `None` if the packet is valid
+    pub invalid: Option<String>,
+    /// The records of the DNS packet
+    pub records: Vec<Record>,
+}
+
+impl PacketReport {
+    /// Decode a signed packet, as public key, signature, timestamp and DNS packet.
+    ///
+    /// Packets with an invalid signature are decoded too, see [`Self::invalid`].
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() >= HEADER_LEN,
+            "a signed packet has at least {HEADER_LEN} bytes, got {}",
+            bytes.len()
+        );
+        let public_key: [u8; 32] = bytes[..32].try_into().expect("checked the length");
+        let timestamp = u64::from_be_bytes(bytes[96..104].try_into().expect("checked the length"));
+        let invalid = SignedPacket::from_bytes(&Bytes::copy_from_slice(bytes))
+            .err()
+            .map(|err| err.to_string());
+        let message =
+            Message::from_bytes(&bytes[HEADER_LEN..]).context("failed to decode the DNS packet")?;
+        let records = message
+            .answers()
+            .iter()
+            .chain(message.name_servers())
+            .chain(message.additionals())
+            .cloned()
+            .collect();
+        Ok(Self {
+            public_key: z32::encode(&public_key),
+            timestamp,
+            invalid,
+            records,
+        })
+    }
+
+    /// Read the packet of the z-base-32 encoded `pubkey` from the packet database at `path`.
+    ///
+    /// This fails if the database is opened by a running server.
+    pub async fn from_store(path: &Path, pubkey: &str) -> Result<Option<Self>> {
+        let pubkey = PublicKeyBytes::from_z32(pubkey)?;
+        ensure!(path.exists(), "no packet database at {}", path.display());
+        let store = ZoneStore::persistent(path)
+            .context("failed to open the packet database, is the server running?")?;
+        let packet = store.get_signed_packet(&pubkey).await?;
+        store.close().await?;
+        packet
+            .map(|packet| Self::decode(packet.as_bytes()))
+            .transpose()
+    }
+
+    /// Get the packet of the z-base-32 encoded `pubkey` from the pkarr relay API of the server
+    /// at `url`.
+    pub async fn from_server(url: &Url, pubkey: &str) -> Result<Option<Self>> {
+        let pubkey = PublicKeyBytes::from_z32(pubkey)?;
+        let url = url.join(&format!("pkarr/{pubkey}"))?;
+        let res = reqwest::get(url).await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let payload = res.error_for_status()?.bytes().await?;
+        let mut bytes = pubkey.as_bytes().to_vec();
+        bytes.extend_from_slice(&payload);
+        Self::decode(&bytes).map(Some)
+    }
+}
+
+impl fmt::Display for PacketReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "public key: {}", self.public_key)?;
+        let time = OffsetDateTime::from_unix_timestamp_nanos(self.timestamp as i128 * 1000)
+            .ok()
+            .and_then(|time| time.format(&Rfc3339).ok())
+            .unwrap_or_else(|| "out of range".to_string());
+        writeln!(f, "timestamp:  {time} ({})", self.timestamp)?;
+        match &self.invalid {
+            None => writeln!(f, "signature:  valid")?,
+            Some(err) => writeln!(f, "signature:  INVALID ({err})")?,
+        }
+        writeln!(f, "records:    {}", self.records.len())?;
+        for record in &self.records {
+            writeln!(f, "  {record}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pkarr::{
+        dns::{rdata::TXT, Name, Packet, ResourceRecord, CLASS},
+        Keypair,
+    };
+
+    use super::*;
+
+    #[test]
+    fn decode() -> Result<()> {
+        let keypair = Keypair::random();
+        let mut packet = Packet::new_reply(0);
+        let txt = TXT::new().with_string("hello")?;
+        packet.answers.push(ResourceRecord::new(
+            Name::new("_iroh").unwrap(),
+            CLASS::IN,
+            30,
+            pkarr::dns::rdata::RData::TXT(txt),
+        ));
+        let signed_packet = SignedPacket::from_packet(&keypair, &packet)?;
+        let report = PacketReport::decode(signed_packet.as_bytes())?;
+        assert_eq!(report.public_key, keypair.public_key().to_z32());
+        assert_eq!(report.timestamp, signed_packet.timestamp());
+        assert!(report.invalid.is_none());
+        assert_eq!(report.records.len(), 1);
+
+        // flip a bit of the DNS packet
+        let mut bytes = signed_packet.as_bytes().to_vec();
+        *bytes.last_mut().unwrap() ^= 1;
+        let report = PacketReport::decode(&bytes)?;
+        assert!(report.invalid.is_some());
+        Ok(())
+    }
+}
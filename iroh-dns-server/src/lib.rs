@@ -2,18 +2,71 @@
 
 #![deny(missing_docs, rustdoc::broken_intra_doc_links)]
 
+pub mod abuse;
+pub mod acl;
+pub mod analytics;
+pub mod anonymous_stats;
+pub mod api_keys;
+pub mod bench;
+#[cfg(feature = "mainline")]
+mod bootstrap;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 pub mod config;
+pub mod db;
+#[cfg(any(test, feature = "dev"))]
+pub mod dev;
+#[cfg(feature = "mainline")]
+mod dht_node;
+#[cfg(feature = "mainline")]
+mod dht_publish;
 pub mod dns;
+pub mod doctor;
+pub mod events;
+pub mod gossip;
+#[cfg(unix)]
+mod handoff;
+pub mod health;
+pub mod history;
+pub mod hourly_stats;
 pub mod http;
+pub mod inspect;
 pub mod metrics;
+pub mod mirror;
+mod privileges;
+pub mod probe;
+mod proxy;
+mod proxy_protocol;
+pub mod publish;
+pub mod query_log;
+pub mod rate_limit_state;
+mod reload;
+pub mod resolve;
+pub mod retention;
+pub mod ring;
+pub mod sandbox;
+pub mod secrets;
 pub mod server;
+#[cfg(windows)]
+pub mod service;
+pub mod slow_log;
 pub mod state;
 mod store;
+pub mod sync;
+mod systemd;
+pub mod tail;
+pub mod telemetry;
+pub mod tenants;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod usage;
 mod util;
 
+pub use self::store::{PacketSource, ZoneStore};
+
 #[cfg(test)]
 mod tests {
-    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
     use anyhow::Result;
     use hickory_resolver::{
@@ -25,10 +78,15 @@ mod tests {
         dns::{node_info::NodeInfo, DnsResolver, ResolverExt},
         key::SecretKey,
     };
-    use pkarr::{PkarrClient, SignedPacket};
+    use pkarr::SignedPacket;
     use url::Url;
 
-    use crate::{config::BootstrapOption, server::Server};
+    use crate::{
+        acl::AclConfig,
+        config::{Config, MetricsConfig, ResourceLimitsConfig},
+        dns::DnsRateLimitConfig,
+        server::Server,
+    };
 
     #[tokio::test]
     async fn pkarr_publish_dns_resolve() -> Result<()> {
@@ -176,7 +234,12 @@ mod tests {
     }
 
     #[tokio::test]
+    #[cfg(feature = "mainline")]
     async fn integration_mainline() -> Result<()> {
+        use pkarr::PkarrClient;
+
+        use crate::config::BootstrapOption;
+
         iroh_test::logging::setup_multithreaded();
 
         // run a mainline testnet
@@ -219,6 +282,1287 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[cfg(feature = "mainline")]
+    async fn mainline_publish() -> Result<()> {
+        use pkarr::PkarrClient;
+
+        use crate::config::MainlineConfig;
+
+        iroh_test::logging::setup_multithreaded();
+
+        let testnet = mainline::dht::Testnet::new(5);
+        let mut config = test_config();
+        config.mainline = Some(MainlineConfig {
+            enabled: true,
+            bootstrap: Some(testnet.bootstrap.clone()),
+            bootstrap_refresh_secs: Some(0),
+            publish: true,
+            ..Default::default()
+        });
+        let server = Server::builder().config(config).spawn().await?;
+        let http_addr = server.http_addr().unwrap();
+
+        // publish to our server only
+        let secret_key = SecretKey::generate();
+        let relay_url: Url = "https://relay.example.".parse()?;
+        let node_info = NodeInfo::new(secret_key.public(), Some(relay_url), Default::default());
+        let signed_packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let pkarr = PkarrRelayClient::new(format!("http://{http_addr}/pkarr").parse()?);
+        pkarr.publish(&signed_packet).await?;
+
+        // the server pushes the packet to the DHT
+        let client = PkarrClient::builder()
+            .dht_settings(mainline::dht::DhtSettings {
+                bootstrap: Some(testnet.bootstrap.clone()),
+                ..Default::default()
+            })
+            .build()?
+            .as_async();
+        let resolved = tokio::time::timeout(std::time::Duration::from_secs(30), async {
+            loop {
+                if let Some(packet) = client.resolve(&signed_packet.public_key()).await? {
+                    return anyhow::Ok(packet);
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            }
+        })
+        .await??;
+        assert_eq!(resolved.timestamp(), signed_packet.timestamp());
+
+        server.shutdown().await?;
+        for mut node in testnet.nodes {
+            node.shutdown()?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pkarr_publish_rate_limit_headers() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let (server, _nameserver, http_url) = Server::spawn_for_tests().await?;
+
+        let secret_key = SecretKey::generate();
+        let node_info = NodeInfo::new(secret_key.public(), None, Default::default());
+        let signed_packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let url = http_url.join(&format!("/pkarr/{}", signed_packet.public_key().to_z32()))?;
+        let client = reqwest::Client::new();
+        let publish = || {
+            client
+                .put(url.clone())
+                .body(signed_packet.to_relay_payload())
+                .send()
+        };
+
+        // the default rate limit allows a burst of two requests
+        for remaining in ["1", "0"] {
+            let res = publish().await?;
+            assert!(res.status().is_success());
+            assert_eq!(res.headers()["x-ratelimit-limit"], "2");
+            assert_eq!(res.headers()["x-ratelimit-remaining"], remaining);
+        }
+        let res = publish().await?;
+        assert_eq!(res.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+        let retry_after: u64 = res.headers()["retry-after"].to_str()?.parse()?;
+        assert!((1..=4).contains(&retry_after));
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resource_limits_shed_load() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let limits = ResourceLimitsConfig {
+            max_dns_requests: Some(0),
+            max_http_connections: Some(0),
+            max_mainline_lookups: None,
+        };
+        let (server, nameserver, http_url) =
+            Server::spawn_for_tests_with(None, Some(limits)).await?;
+
+        let resolver = test_resolver(nameserver);
+        assert!(resolver.ipv4_lookup("irohdns.example.").await.is_err());
+
+        let res = reqwest::get(http_url.join("/healthcheck")?).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()["connection"], "close");
+        assert_eq!(res.headers()["retry-after"], "1");
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stalled_clients_are_disconnected() -> Result<()> {
+        use std::time::{Duration, Instant};
+
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+
+        use crate::http::HttpLimitsConfig;
+
+        /// Wait until the server closes `stream`, and return how long that took.
+        async fn closed_after(mut stream: TcpStream) -> Result<Duration> {
+            let start = Instant::now();
+            let mut buf = Vec::new();
+            // the server may answer with an error before it closes the connection
+            let _ =
+                tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut buf)).await?;
+            Ok(start.elapsed())
+        }
+
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.http_limits = Some(HttpLimitsConfig {
+            header_read_timeout_secs: 1,
+            request_timeout_secs: 1,
+            idle_timeout_secs: 2,
+            max_connections: None,
+        });
+        let server = Server::builder().config(config).spawn().await?;
+        let http_addr = server.http_addr().expect("http is set");
+
+        // a client that stalls in the middle of the request headers is closed after the header
+        // read timeout
+        let mut stream = TcpStream::connect(http_addr).await?;
+        stream
+            .write_all(b"GET /healthcheck HTTP/1.1\r\nHost: ")
+            .await?;
+        let elapsed = closed_after(stream).await?;
+        assert!(
+            elapsed >= Duration::from_millis(900),
+            "closed after {elapsed:?}"
+        );
+        assert!(elapsed < Duration::from_secs(5), "closed after {elapsed:?}");
+
+        // a client that sends nothing after a request is closed after the idle timeout
+        let mut stream = TcpStream::connect(http_addr).await?;
+        stream
+            .write_all(b"GET /healthcheck HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = [0u8; 12];
+        stream.read_exact(&mut response).await?;
+        assert_eq!(&response, b"HTTP/1.1 200");
+        let elapsed = closed_after(stream).await?;
+        assert!(
+            elapsed >= Duration::from_millis(1900),
+            "closed after {elapsed:?}"
+        );
+        assert!(elapsed < Duration::from_secs(5), "closed after {elapsed:?}");
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn acl_denies_operations() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.acl = Some(AclConfig {
+            query: Some(vec!["192.0.2.0/24".parse()?]),
+            admin: Some(vec![]),
+            ..Default::default()
+        });
+        let server = Server::builder().config(config).spawn().await?;
+        let http_addr = server.http_addr().unwrap();
+
+        let resolver = test_resolver(server.dns_addr());
+        let err = resolver.txt_lookup("irohdns.example.").await.unwrap_err();
+        assert!(format!("{err:?}").contains("Refused"), "{err:?}");
+        let secret_key = SecretKey::generate();
+        let key = z32::encode(secret_key.public().as_bytes());
+        let res = reqwest::get(format!("http://{http_addr}/pkarr/{key}")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        let res = reqwest::get(format!("http://{http_addr}/admin/status")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // publishes are allowed from anywhere
+        let node_info = NodeInfo::new(secret_key.public(), None, Default::default());
+        let signed_packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let pkarr = PkarrRelayClient::new(format!("http://{http_addr}/pkarr").parse()?);
+        pkarr.publish(&signed_packet).await?;
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_rate_limit() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.dns.rate_limit = Some(DnsRateLimitConfig {
+            rate: Some(0.001),
+            burst: Some(1),
+            ..Default::default()
+        });
+        let server = Server::builder().config(config).spawn().await?;
+
+        // over TCP, the requests over the limit are refused instead of dropped
+        let mut resolver_config = ResolverConfig::new();
+        resolver_config.add_name_server(NameServerConfig::new(server.dns_addr(), Protocol::Tcp));
+        let resolver = AsyncResolver::tokio(resolver_config, Default::default());
+        let res = resolver.txt_lookup("a.irohdns.example.").await;
+        assert!(!format!("{res:?}").contains("Refused"), "{res:?}");
+        let err = resolver.txt_lookup("b.irohdns.example.").await.unwrap_err();
+        assert!(format!("{err:?}").contains("Refused"), "{err:?}");
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn owner_analytics() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.analytics = Some(Default::default());
+        let server = Server::builder().config(config).spawn().await?;
+        let http_url: Url =
+            format!("http://{}", server.http_addr().expect("http is set")).parse()?;
+
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let z32 = z32::encode(node_id.as_bytes());
+        let pkarr = PkarrRelayClient::new(http_url.join("/pkarr")?);
+        let relay_url: Url = "https://relay.example.".parse()?;
+        let node_info = NodeInfo::new(node_id, Some(relay_url), Default::default());
+        pkarr
+            .publish(&node_info.to_pkarr_signed_packet(&secret_key, 30)?)
+            .await?;
+        let resolver = test_resolver(server.dns_addr());
+        resolver.lookup_by_id(&node_id, "irohdns.example.").await?;
+        reqwest::get(http_url.join(&format!("/pkarr/{z32}"))?)
+            .await?
+            .error_for_status()?;
+
+        let url = http_url.join(&format!("/analytics/{z32}"))?;
+        let query = crate::analytics::signed_query(&secret_key, 7);
+        let res: crate::analytics::Resolutions = reqwest::get(format!("{url}?{query}"))
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        assert_eq!(res.days.len(), 7);
+        let today = res.days.last().expect("today is included");
+        assert_eq!(today.transports["http"], 1);
+        assert!(today.transports["udp"] >= 1);
+
+        // only the owner of the key may get the counts
+        let other = crate::analytics::signed_query(&SecretKey::generate(), 7);
+        let res = reqwest::get(format!("{url}?{other}")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tenant_quota_and_isolated_store() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        // the origins are matched in order, so the origin of the tenant is before the root
+        config.dns.origins.insert(0, "acme.example.".to_string());
+        config.http.as_mut().unwrap().rate_limit = crate::http::RateLimitMode::Disabled.into();
+        config.tenants = vec![crate::tenants::TenantConfig {
+            name: "acme".to_string(),
+            origins: vec!["acme.example.".to_string()],
+            api_keys: vec![],
+            max_packets: Some(1),
+            publishes_per_minute: None,
+            isolated_store: true,
+        }];
+        let server = Server::builder().config(config).spawn().await?;
+        let http_url: Url =
+            format!("http://{}", server.http_addr().expect("http is set")).parse()?;
+        let relay_url: Url = "https://relay.example.".parse()?;
+        let publish = |secret_key: SecretKey, host: &'static str| {
+            let node_info = NodeInfo::new(
+                secret_key.public(),
+                Some(relay_url.clone()),
+                Default::default(),
+            );
+            let url = http_url.join(&format!(
+                "/pkarr/{}",
+                z32::encode(secret_key.public().as_bytes())
+            ));
+            async move {
+                let packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+                let res = reqwest::Client::new()
+                    .put(url?)
+                    .header(reqwest::header::HOST, host)
+                    .body(packet.to_relay_payload())
+                    .send()
+                    .await?;
+                anyhow::Ok(res.status())
+            }
+        };
+
+        let first = SecretKey::generate();
+        assert_eq!(
+            publish(first.clone(), "acme.example").await?,
+            reqwest::StatusCode::NO_CONTENT
+        );
+        let resolver = test_resolver(server.dns_addr());
+        resolver
+            .lookup_by_id(&first.public(), "acme.example.")
+            .await?;
+        // the packets of the tenant are only in its isolated store
+        assert!(resolver
+            .lookup_by_id(&first.public(), "irohdns.example.")
+            .await
+            .is_err());
+
+        // the quota of the tenant is used up, but the shared store has no quota
+        let second = SecretKey::generate();
+        assert_eq!(
+            publish(second.clone(), "acme.example").await?,
+            reqwest::StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            publish(second.clone(), "irohdns.example").await?,
+            reqwest::StatusCode::NO_CONTENT
+        );
+        resolver
+            .lookup_by_id(&second.public(), "irohdns.example.")
+            .await?;
+        // republishing a key of the tenant does not need more quota
+        assert_eq!(
+            publish(first, "acme.example").await?,
+            reqwest::StatusCode::NO_CONTENT
+        );
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn packet_ttls_are_clamped() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.dns.min_ttl = Some(60);
+        config.dns.max_ttl = Some(3600);
+        config.http.as_mut().unwrap().rate_limit = crate::http::RateLimitMode::Disabled.into();
+        let server = Server::builder().config(config).spawn().await?;
+        let http_url: Url =
+            format!("http://{}", server.http_addr().expect("http is set")).parse()?;
+        let client = reqwest::Client::new();
+        for (packet_ttl, ttl) in [(30, 60), (600, 600), (86400, 3600)] {
+            let secret_key = SecretKey::generate();
+            let node_info = NodeInfo::new(
+                secret_key.public(),
+                Some("https://relay.example.".parse()?),
+                Default::default(),
+            );
+            let packet = node_info.to_pkarr_signed_packet(&secret_key, packet_ttl)?;
+            let key = z32::encode(secret_key.public().as_bytes());
+            client
+                .put(http_url.join(&format!("/pkarr/{key}"))?)
+                .body(packet.to_relay_payload())
+                .send()
+                .await?
+                .error_for_status()?;
+            let mut url = http_url.join("/resolve")?;
+            url.query_pairs_mut()
+                .append_pair("name", &format!("_iroh.{key}.irohdns.example."))
+                .append_pair("type", "TXT");
+            let response: serde_json::Value = client.get(url).send().await?.json().await?;
+            assert_eq!(response["Answer"][0]["TTL"], ttl, "{response}");
+        }
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn validate_publishes() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.http.as_mut().unwrap().rate_limit = crate::http::RateLimitMode::Disabled.into();
+        let server = Server::builder().config(config).spawn().await?;
+        let http_url: Url =
+            format!("http://{}", server.http_addr().expect("http is set")).parse()?;
+        let secret_key = SecretKey::generate();
+        let node_info = NodeInfo::new(
+            secret_key.public(),
+            Some("https://relay.example.".parse()?),
+            Default::default(),
+        );
+        let older = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let key = z32::encode(secret_key.public().as_bytes());
+        let client = reqwest::Client::new();
+        let validate = |key: &str, body: Vec<u8>| {
+            let mut url = http_url.join("/pkarr/validate").unwrap();
+            url.query_pairs_mut().append_pair("key", key);
+            let request = client.post(url).body(body).send();
+            async move {
+                let response: serde_json::Value = request.await?.error_for_status()?.json().await?;
+                let failed: Vec<String> = response["checks"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .filter(|check| check["ok"] == false)
+                    .map(|check| check["check"].as_str().unwrap().to_string())
+                    .collect();
+                anyhow::Ok((
+                    response["accepted"] == true,
+                    response["updated"] == true,
+                    failed,
+                ))
+            }
+        };
+
+        assert_eq!(
+            validate(&key, packet.to_relay_payload().to_vec()).await?,
+            (true, true, vec![])
+        );
+        // the dry run doesn't store the packet
+        let stored = server
+            .store()
+            .get_signed_packet(&crate::util::PublicKeyBytes::from_signed_packet(&packet))
+            .await?;
+        assert!(stored.is_none());
+        let mut payload = packet.to_relay_payload().to_vec();
+        payload[0] ^= 1;
+        assert_eq!(
+            validate(&key, payload).await?,
+            (false, false, vec!["signature".to_string()])
+        );
+        assert_eq!(
+            validate(&key, vec![0; 10]).await?,
+            (false, false, vec!["payload".to_string()])
+        );
+        assert_eq!(
+            validate("invalid", vec![]).await?,
+            (false, false, vec!["key".to_string()])
+        );
+
+        client
+            .put(http_url.join(&format!("/pkarr/{key}"))?)
+            .body(packet.to_relay_payload())
+            .send()
+            .await?
+            .error_for_status()?;
+        assert_eq!(
+            validate(&key, older.to_relay_payload().to_vec()).await?,
+            (true, false, vec![])
+        );
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn doh_json_queries() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let server = Server::builder().config(test_config()).spawn().await?;
+        let http_url: Url =
+            format!("http://{}", server.http_addr().expect("http is set")).parse()?;
+        let secret_key = SecretKey::generate();
+        let node_info = NodeInfo::new(
+            secret_key.public(),
+            Some("https://relay.example.".parse()?),
+            Default::default(),
+        );
+        let packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let key = z32::encode(secret_key.public().as_bytes());
+        let client = reqwest::Client::new();
+        client
+            .put(http_url.join(&format!("/pkarr/{key}"))?)
+            .body(packet.to_relay_payload())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let name = format!("_iroh.{key}.irohdns.example.");
+        // without headers, `/resolve` and `/dns-query` with a `name` answer in JSON
+        for path in ["/resolve", "/dns-query"] {
+            let mut url = http_url.join(path)?;
+            url.query_pairs_mut()
+                .append_pair("name", &name)
+                .append_pair("type", "TXT");
+            let res = client.get(url).send().await?.error_for_status()?;
+            assert_eq!(
+                res.headers()[reqwest::header::CONTENT_TYPE],
+                "application/dns-json"
+            );
+            let response: serde_json::Value = res.json().await?;
+            assert_eq!(response["Status"], 0);
+            let data = response["Answer"][0]["data"].as_str().unwrap_or_default();
+            assert!(data.contains("relay=https://relay.example."), "{response}");
+        }
+        let mut url = http_url.join("/dns-query")?;
+        url.query_pairs_mut().append_pair("name", &name);
+        let res = client
+            .get(url)
+            .header(reqwest::header::ACCEPT, "text/html")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_ACCEPTABLE);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn inject_faults() -> Result<()> {
+        use crate::{api_keys::ApiKeyScope, http::TokenAuth};
+
+        iroh_test::logging::setup_multithreaded();
+        let server = Server::builder()
+            .config(test_config())
+            .auth_provider(TokenAuth::new([ApiKeyScope::Admin], ["admin-token"]))
+            .spawn()
+            .await?;
+        let http_addr = server.http_addr().unwrap();
+        let client = reqwest::Client::new();
+        let set_faults = |endpoint: &str, faults: serde_json::Value| {
+            client
+                .put(format!("http://{http_addr}/admin/chaos/{endpoint}"))
+                .bearer_auth("admin-token")
+                .json(&faults)
+                .send()
+        };
+        let res = set_faults("pkarr_get", serde_json::json!({ "drop_rate": 1.5 })).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::BAD_REQUEST);
+        let res = set_faults("pkarr_get", serde_json::json!({ "servfail_rate": 1.0 })).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+        let res = set_faults("dns", serde_json::json!({ "rate_limit_rate": 1.0 })).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+
+        let key = z32::encode(SecretKey::generate().public().as_bytes());
+        let res = reqwest::get(format!("http://{http_addr}/pkarr/{key}")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        let resolver = test_resolver(server.dns_addr());
+        let err = resolver.txt_lookup("irohdns.example.").await.unwrap_err();
+        assert!(format!("{err:?}").contains("Refused"), "{err:?}");
+
+        // without faults the requests are answered again
+        let res = client
+            .delete(format!("http://{http_addr}/admin/chaos"))
+            .bearer_auth("admin-token")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+        let res = reqwest::get(format!("http://{http_addr}/pkarr/{key}")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    /// A config with the DNS and HTTP servers on random ports on localhost.
+    fn test_config() -> Config {
+        let localhost = vec![IpAddr::V4(Ipv4Addr::LOCALHOST).into()];
+        let mut config = Config::default();
+        config.dns.port = 0;
+        config.dns.bind_addr = localhost.clone();
+        let http = config.http.as_mut().unwrap();
+        http.port = Some(0);
+        http.bind_addr = localhost;
+        config.https = None;
+        config.metrics = Some(MetricsConfig::disabled());
+        config.in_memory_store = true;
+        config
+    }
+
+    #[cfg(feature = "https")]
+    #[tokio::test]
+    async fn dns_over_tls_and_quic() -> Result<()> {
+        use std::sync::Arc;
+
+        use hickory_proto::{
+            op::{Message, Query},
+            rr::{Name, RecordType},
+            serialize::binary::BinDecodable,
+        };
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        use crate::{
+            dns::DnsTlsConfig,
+            http::{CertConfig, CertMode},
+            secrets::SecretSource,
+        };
+
+        iroh_test::logging::setup_multithreaded();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        std::env::set_var("DNS_TLS_TEST_CERT", cert.serialize_pem()?);
+        std::env::set_var("DNS_TLS_TEST_KEY", cert.serialize_private_key_pem());
+        let mut config = test_config();
+        config.dns.tls = Some(DnsTlsConfig {
+            dot_port: Some(0),
+            doq_port: Some(0),
+            cert: CertConfig {
+                domains: vec!["localhost".to_string()],
+                cert_mode: CertMode::Manual,
+                letsencrypt_contact: None,
+                letsencrypt_prod: None,
+                acme: None,
+                cert_path: None,
+                key_path: None,
+                cert_secret: Some(SecretSource::Env("DNS_TLS_TEST_CERT".to_string())),
+                key_secret: Some(SecretSource::Env("DNS_TLS_TEST_KEY".to_string())),
+                acme_account_key: None,
+            },
+        });
+        let server = Server::builder().config(config).spawn().await?;
+        let bound_addrs = server.bound_addrs();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(rustls::pki_types::CertificateDer::from(
+            cert.serialize_der()?,
+        ))?;
+        let client_config = |alpn: &[u8]| {
+            let mut config = rustls::ClientConfig::builder()
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth();
+            config.alpn_protocols = vec![alpn.to_vec()];
+            Arc::new(config)
+        };
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_ascii("irohdns.example.")?,
+            RecordType::A,
+        ));
+        let query = query.to_vec()?;
+        let mut prefixed = (query.len() as u16).to_be_bytes().to_vec();
+        prefixed.extend_from_slice(&query);
+
+        // DNS-over-TLS
+        let connector = tokio_rustls::TlsConnector::from(client_config(b"dot"));
+        let stream = tokio::net::TcpStream::connect(bound_addrs["dns_tls"][0]).await?;
+        let mut stream = connector.connect("localhost".try_into()?, stream).await?;
+        stream.write_all(&prefixed).await?;
+        let mut response = vec![0u8; stream.read_u16().await? as usize];
+        stream.read_exact(&mut response).await?;
+        assert_eq!(Message::from_bytes(&response)?.answer_count(), 1);
+
+        // DNS-over-QUIC
+        let mut endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into())?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(client_config(b"doq"))?,
+        )));
+        let conn = endpoint
+            .connect(bound_addrs["dns_quic"][0], "localhost")?
+            .await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send.write_all(&prefixed).await?;
+        send.finish()?;
+        let response = recv.read_to_end(u16::MAX as usize + 2).await?;
+        assert_eq!(Message::from_bytes(&response[2..])?.answer_count(), 1);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "metrics")]
+    async fn builder_bound_addrs() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.metrics = Some(MetricsConfig {
+            disabled: false,
+            bind_addr: Some((Ipv4Addr::LOCALHOST, 0).into()),
+            ..MetricsConfig::disabled()
+        });
+        let server = Server::builder().config(config).spawn().await?;
+        let errors = server.subscribe_errors();
+
+        let addrs = server.bound_addrs();
+        assert_ne!(server.dns_addr().port(), 0);
+        assert_eq!(addrs["dns"], vec![server.dns_addr()]);
+        let http_addr = server.http_addr().expect("http is set");
+        assert_eq!(addrs["http"], vec![http_addr]);
+        assert_eq!(server.https_addr(), None);
+        let metrics_addr = addrs["metrics"][0];
+        assert_ne!(metrics_addr.port(), 0);
+        // the metrics server listens on the bound address
+        reqwest::get(format!("http://{metrics_addr}/metrics")).await?;
+
+        server.shutdown().await?;
+        assert!(errors.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "https")]
+    async fn builder_tls_config() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert_der = rustls::pki_types::CertificateDer::from(cert.serialize_der()?);
+        let key_der =
+            rustls::pki_types::PrivateKeyDer::Pkcs8(cert.serialize_private_key_der().into());
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)?;
+        let mut config = test_config();
+        let mut https = Config::default().https.expect("https is set by default");
+        https.port = 0;
+        https.bind_addr = vec![IpAddr::V4(Ipv4Addr::LOCALHOST).into()];
+        config.https = Some(https);
+        let server = Server::builder()
+            .config(config)
+            .tls_config(std::sync::Arc::new(tls_config))
+            .spawn()
+            .await?;
+
+        // the client only trusts the certificate of the provided config
+        let https_addr = server.https_addr().expect("https is set");
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(&cert_der)?)
+            .build()?;
+        let url = format!("https://localhost:{}/healthcheck", https_addr.port());
+        let res = client.get(url).send().await?;
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "https")]
+    async fn http3_alt_svc() -> Result<()> {
+        use std::sync::Arc;
+
+        use crate::http::HttpLimitsConfig;
+
+        iroh_test::logging::setup_multithreaded();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        let cert_der = rustls::pki_types::CertificateDer::from(cert.serialize_der()?);
+        let key_der =
+            rustls::pki_types::PrivateKeyDer::Pkcs8(cert.serialize_private_key_der().into());
+        let tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key_der)?;
+        let mut config = test_config();
+        let mut https = Config::default().https.expect("https is set by default");
+        https.port = 0;
+        https.bind_addr = vec![IpAddr::V4(Ipv4Addr::LOCALHOST).into()];
+        https.http3 = true;
+        config.https = Some(https);
+        config.http_limits = Some(HttpLimitsConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        });
+        let server = Server::builder()
+            .config(config)
+            .tls_config(Arc::new(tls_config))
+            .spawn()
+            .await?;
+        let https_addr = server.https_addr().expect("https is set");
+        let url = format!("https://localhost:{}/healthcheck", https_addr.port());
+
+        // HTTPS responses point to HTTP/3 on the same port
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_der(&cert_der)?)
+            .build()?;
+        let res = client.get(&url).send().await?;
+        assert_eq!(
+            res.headers()[http::header::ALT_SVC],
+            format!("h3=\":{}\"; ma=86400", https_addr.port())
+        );
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert_der)?;
+        let mut client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h3".to_vec()];
+        let mut endpoint = quinn::Endpoint::client((Ipv4Addr::LOCALHOST, 0).into())?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(client_config)?,
+        )));
+        let conn = endpoint.connect(https_addr, "localhost")?.await?;
+        let (mut driver, mut send_request) =
+            h3::client::new(h3_quinn::Connection::new(conn)).await?;
+        let drive =
+            tokio::spawn(
+                async move { futures_lite::future::poll_fn(|cx| driver.poll_close(cx)).await },
+            );
+        let mut stream = send_request
+            .send_request(http::Request::get(&url).body(())?)
+            .await?;
+        stream.finish().await?;
+        let res = stream.recv_response().await?;
+        assert_eq!(res.status(), http::StatusCode::OK);
+
+        // a second connection is above the connection limit of the listener
+        assert!(endpoint.connect(https_addr, "localhost")?.await.is_err());
+
+        drop(send_request);
+        drive.abort();
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_hooks() -> Result<()> {
+        use hickory_server::proto::{
+            op::ResponseCode,
+            rr::{rdata::A, Name, RData, Record, RecordType},
+        };
+
+        use crate::dns::{BeforeLookup, DnsHook, DnsQuery};
+
+        /// Refuses `blocked.`, answers `injected.` itself, and resolves `alias.` as the origin.
+        #[derive(Debug)]
+        struct TestHook;
+
+        #[async_trait::async_trait]
+        impl DnsHook for TestHook {
+            async fn before_lookup(&self, query: &mut DnsQuery) -> BeforeLookup {
+                let name = query.name.to_ascii();
+                if name == "blocked.irohdns.example." {
+                    BeforeLookup::Reject(ResponseCode::Refused)
+                } else if name == "injected.irohdns.example." {
+                    let rdata = RData::A(A::new(10, 0, 0, 1));
+                    BeforeLookup::Answer(vec![Record::from_rdata(query.name.clone(), 30, rdata)])
+                } else {
+                    if name == "alias.irohdns.example." {
+                        query.name = Name::from_ascii("irohdns.example.").unwrap();
+                    }
+                    BeforeLookup::Continue
+                }
+            }
+
+            async fn after_lookup(&self, query: &DnsQuery, records: &mut Vec<Record>) {
+                if query.record_type == RecordType::A {
+                    for record in records {
+                        record.set_name(Name::from_ascii("alias.irohdns.example.").unwrap());
+                    }
+                }
+            }
+        }
+
+        iroh_test::logging::setup_multithreaded();
+        let server = Server::builder()
+            .config(test_config())
+            .dns_hook(TestHook)
+            .spawn()
+            .await?;
+        let resolver = test_resolver(server.dns_addr());
+
+        assert!(resolver
+            .ipv4_lookup("blocked.irohdns.example.")
+            .await
+            .is_err());
+        let res = resolver.ipv4_lookup("injected.irohdns.example.").await?;
+        let records = res.iter().map(|a| a.0).collect::<Vec<_>>();
+        assert_eq!(records, vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        let res = resolver.ipv4_lookup("alias.irohdns.example.").await?;
+        let records = res.iter().map(|a| a.0).collect::<Vec<_>>();
+        assert_eq!(records, vec![Ipv4Addr::LOCALHOST]);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn name_resolvers() -> Result<()> {
+        use hickory_server::proto::rr::Name;
+
+        use crate::{dns::StaticNames, store::PacketSource};
+
+        iroh_test::logging::setup_multithreaded();
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr: SocketAddr = (Ipv4Addr::LOCALHOST, 4433).into();
+        let packet =
+            NodeInfo::new(node_id, None, [addr].into()).to_pkarr_signed_packet(&secret_key, 30)?;
+        let names: StaticNames = [(
+            Name::from_ascii("alice.irohdns.example.")?,
+            packet.public_key(),
+        )]
+        .into_iter()
+        .collect();
+        let server = Server::builder()
+            .config(test_config())
+            .name_resolver(names)
+            .spawn()
+            .await?;
+        server
+            .store()
+            .insert(packet, PacketSource::PkarrPublish)
+            .await?;
+        let resolver = test_resolver(server.dns_addr());
+
+        // names are compared case-insensitively
+        let res = resolver.lookup_by_name("Alice.irohdns.example.").await?;
+        assert_eq!(res.node_id, node_id);
+        assert_eq!(res.info.direct_addresses, [addr].into());
+        assert!(resolver
+            .lookup_by_name("bob.irohdns.example.")
+            .await
+            .is_err());
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn packet_validators() -> Result<()> {
+        use crate::http::{validator_fn, Validation};
+
+        fn txt_packet(keypair: &pkarr::Keypair, txt: &str) -> Result<SignedPacket> {
+            use pkarr::dns;
+            let mut packet = dns::Packet::new_reply(0);
+            packet.answers.push(dns::ResourceRecord::new(
+                dns::Name::new("").unwrap(),
+                dns::CLASS::IN,
+                30,
+                dns::rdata::RData::TXT(txt.try_into()?),
+            ));
+            Ok(SignedPacket::from_packet(keypair, &packet)?)
+        }
+
+        iroh_test::logging::setup_multithreaded();
+        let fleet = pkarr::Keypair::random();
+        let other = pkarr::Keypair::random();
+        let fleet_key = fleet.public_key();
+        let replacement = txt_packet(&fleet, "replaced")?;
+        let validator = {
+            let replacement = replacement.clone();
+            validator_fn(move |packet| {
+                if packet.public_key() == fleet_key {
+                    Validation::Replace(replacement.clone())
+                } else {
+                    Validation::Reject("not in the fleet".to_string())
+                }
+            })
+        };
+        let server = Server::builder()
+            .config(test_config())
+            .packet_validator(validator)
+            .spawn()
+            .await?;
+        let url: Url = format!("http://{}/pkarr/", server.http_addr().unwrap()).parse()?;
+        let client = reqwest::Client::new();
+
+        let packet = txt_packet(&other, "published")?;
+        let res = client
+            .put(url.join(&other.public_key().to_z32())?)
+            .body(packet.to_relay_payload())
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let packet = txt_packet(&fleet, "published")?;
+        let key_url = url.join(&fleet.public_key().to_z32())?;
+        let res = client
+            .put(key_url.clone())
+            .body(packet.to_relay_payload())
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+        let stored = client.get(key_url).send().await?.bytes().await?;
+        assert_eq!(stored, replacement.to_relay_payload());
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_events() -> Result<()> {
+        use futures_lite::StreamExt;
+        use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+        use crate::{api_keys::ApiKeyScope, events::ServerEvent, http::TokenAuth};
+
+        iroh_test::logging::setup_multithreaded();
+        let server = Server::builder()
+            .config(test_config())
+            .auth_provider(TokenAuth::new([ApiKeyScope::Admin], ["admin-token"]))
+            .spawn()
+            .await?;
+        let http_addr = server.http_addr().unwrap();
+        let mut events = server.subscribe_events();
+        let mut request = format!("ws://{http_addr}/admin/events").into_client_request()?;
+        request
+            .headers_mut()
+            .insert("authorization", "Bearer admin-token".parse()?);
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+
+        let secret_key = SecretKey::generate();
+        let packet = NodeInfo::new(secret_key.public(), None, Default::default())
+            .to_pkarr_signed_packet(&secret_key, 30)?;
+        let pubkey = packet.public_key().to_z32();
+        PkarrRelayClient::new(format!("http://{http_addr}/pkarr").parse()?)
+            .publish(&packet)
+            .await?;
+
+        let event = events.recv().await?;
+        assert!(matches!(event, ServerEvent::PacketPublished { pubkey: p, .. } if p == pubkey));
+        let message = socket.next().await.expect("the socket is open")?;
+        let Message::Text(message) = message else {
+            panic!("unexpected message {message:?}");
+        };
+        let event: serde_json::Value = serde_json::from_str(&message)?;
+        assert_eq!(event["type"], "packet_published");
+        assert_eq!(event["pubkey"], pubkey);
+        assert_eq!(event["source"], "publish");
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn auth_providers() -> Result<()> {
+        use crate::{
+            api_keys::ApiKeyScope,
+            http::{AuthDecision, AuthProvider, AuthRequest, TokenAuth},
+        };
+
+        /// Denies the publishes that no provider before allowed.
+        #[derive(Debug)]
+        struct RequirePublishToken;
+
+        #[async_trait::async_trait]
+        impl AuthProvider for RequirePublishToken {
+            async fn authorize(&self, req: &AuthRequest<'_>) -> Result<AuthDecision> {
+                Ok(match req.scope {
+                    ApiKeyScope::Publish => AuthDecision::Deny("unknown publisher".to_string()),
+                    _ => AuthDecision::Pass,
+                })
+            }
+        }
+
+        iroh_test::logging::setup_multithreaded();
+        let server = Server::builder()
+            .config(test_config())
+            .auth_provider(TokenAuth::new([ApiKeyScope::Publish], ["publish-token"]))
+            .auth_provider(RequirePublishToken)
+            .spawn()
+            .await?;
+        let http_addr = server.http_addr().unwrap();
+        let client = reqwest::Client::new();
+        let secret_key = SecretKey::generate();
+        let packet = NodeInfo::new(secret_key.public(), None, Default::default())
+            .to_pkarr_signed_packet(&secret_key, 30)?;
+        let url = format!("http://{http_addr}/pkarr/{}", packet.public_key().to_z32());
+
+        let res = client
+            .put(&url)
+            .body(packet.to_relay_payload())
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::FORBIDDEN);
+        let res = client
+            .put(&url)
+            .bearer_auth("publish-token")
+            .body(packet.to_relay_payload())
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+        // the token is not valid for the admin endpoints
+        let res = client
+            .get(format!("http://{http_addr}/admin/status"))
+            .bearer_auth("publish-token")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "https")]
+    async fn client_cert_auth() -> Result<()> {
+        use rcgen::{
+            BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa,
+        };
+
+        use crate::{
+            http::{CertMode, ClientAuthConfig},
+            secrets::SecretSource,
+        };
+
+        /// A client certificate signed by `ca`, with its key, as PEM.
+        fn client_identity(ca: &Certificate) -> Result<reqwest::Identity> {
+            let mut params = CertificateParams::new(vec!["client".to_string()]);
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let cert = Certificate::from_params(params)?;
+            let pem = cert.serialize_pem_with_signer(ca)? + &cert.serialize_private_key_pem();
+            Ok(reqwest::Identity::from_pem(pem.as_bytes())?)
+        }
+
+        iroh_test::logging::setup_multithreaded();
+        let ca_params = |name: &str| {
+            let mut params = CertificateParams::new(vec![name.to_string()]);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+        };
+        let ca = Certificate::from_params(ca_params("client-ca"))?;
+        let unknown_ca = Certificate::from_params(ca_params("unknown-ca"))?;
+        let dir = std::env::temp_dir().join(format!("client-cert-auth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let ca_path = dir.join("ca.pem");
+        std::fs::write(&ca_path, ca.serialize_pem()?)?;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+        std::env::set_var("MTLS_TEST_CERT", cert.serialize_pem()?);
+        std::env::set_var("MTLS_TEST_KEY", cert.serialize_private_key_pem());
+        let mut config = test_config();
+        let mut https = Config::default().https.expect("https is set by default");
+        https.port = 0;
+        https.bind_addr = vec![IpAddr::V4(Ipv4Addr::LOCALHOST).into()];
+        https.domains = vec!["localhost".to_string()];
+        https.cert_mode = CertMode::Manual;
+        https.cert_secret = Some(SecretSource::Env("MTLS_TEST_CERT".to_string()));
+        https.key_secret = Some(SecretSource::Env("MTLS_TEST_KEY".to_string()));
+        https.client_auth = Some(ClientAuthConfig {
+            ca_cert: ca_path,
+            require_for_publish: true,
+        });
+        config.https = Some(https);
+        let server = Server::builder().config(config).spawn().await?;
+        let port = server.https_addr().expect("https is set").port();
+        let client = |identity: Option<reqwest::Identity>| {
+            let builder = reqwest::Client::builder()
+                .add_root_certificate(reqwest::Certificate::from_der(&cert.serialize_der()?)?);
+            let builder = match identity {
+                Some(identity) => builder.identity(identity),
+                None => builder,
+            };
+            anyhow::Ok(builder.build()?)
+        };
+        let status_url = format!("https://localhost:{port}/admin/status");
+        let secret_key = SecretKey::generate();
+        let packet = NodeInfo::new(secret_key.public(), None, Default::default())
+            .to_pkarr_signed_packet(&secret_key, 30)?;
+        let publish_url = format!(
+            "https://localhost:{port}/pkarr/{}",
+            packet.public_key().to_z32()
+        );
+
+        // a certificate of the configured CA is accepted
+        let accepted = client(Some(client_identity(&ca)?))?;
+        let res = accepted.get(&status_url).send().await?;
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let res = accepted
+            .put(&publish_url)
+            .body(packet.to_relay_payload())
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // a certificate of another CA fails the handshake
+        let unknown = client(Some(client_identity(&unknown_ca)?))?;
+        assert!(unknown.get(&status_url).send().await.is_err());
+
+        // without a certificate, the admin endpoints and the required publishes are refused
+        let missing = client(None)?;
+        let res = missing.get(&status_url).send().await?;
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let res = missing
+            .put(&publish_url)
+            .body(packet.to_relay_payload())
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        server.shutdown().await?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_packets() -> Result<()> {
+        use crate::{http::AdminConfig, secrets::SecretValue};
+
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.admin = Some(AdminConfig {
+            tokens: vec![SecretValue::Value("admin-token".to_string())],
+            ..Default::default()
+        });
+        let server = Server::builder().config(config).spawn().await?;
+        let http_addr = server.http_addr().unwrap();
+        let client = reqwest::Client::new();
+        let secret_key = SecretKey::generate();
+        let relay_url: Url = "https://relay.example.".parse()?;
+        let packet = NodeInfo::new(secret_key.public(), Some(relay_url), Default::default())
+            .to_pkarr_signed_packet(&secret_key, 30)?;
+        let pubkey = packet.public_key().to_z32();
+        PkarrRelayClient::new(format!("http://{http_addr}/pkarr").parse()?)
+            .publish(&packet)
+            .await?;
+
+        let res = client
+            .get(format!("http://{http_addr}/admin/packets"))
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let list: serde_json::Value = client
+            .get(format!("http://{http_addr}/admin/packets?limit=1"))
+            .bearer_auth("admin-token")
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(list["packets"][0]["public_key"], pubkey);
+        assert_eq!(list["packets"][0]["timestamp"], packet.timestamp());
+        assert_eq!(list["next"], pubkey);
+        let url = format!("http://{http_addr}/admin/packets/{pubkey}");
+        let records: serde_json::Value = client
+            .get(&url)
+            .bearer_auth("admin-token")
+            .send()
+            .await?
+            .json()
+            .await?;
+        let txt = records["records"][0].as_str().unwrap();
+        assert!(txt.starts_with(&format!("_iroh.{pubkey}")), "{txt}");
+
+        let res = client
+            .delete(&url)
+            .bearer_auth("admin-token")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NO_CONTENT);
+        let res = client.get(&url).bearer_auth("admin-token").send().await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+        let res = client
+            .delete(&url)
+            .bearer_auth("admin-token")
+            .send()
+            .await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_listener() -> Result<()> {
+        use crate::http::AdminConfig;
+
+        iroh_test::logging::setup_multithreaded();
+        let mut config = test_config();
+        config.admin = Some(AdminConfig {
+            bind_addr: vec![(Ipv4Addr::LOCALHOST, 0).into()],
+            ..Default::default()
+        });
+        let server = Server::builder().config(config).spawn().await?;
+        let http_addr = server.http_addr().unwrap();
+        let admin_addr = server.admin_addr().unwrap();
+
+        let res = reqwest::get(format!("http://{http_addr}/admin/status")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+        let res = reqwest::get(format!("http://{admin_addr}/admin/stats")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::OK);
+        let stats: serde_json::Value = res.json().await?;
+        assert_eq!(stats["store_packets"], 0);
+        assert!(stats["dht"]["lookups"]["found"].is_u64());
+        let res = reqwest::get(format!("http://{admin_addr}/healthcheck")).await?;
+        assert_eq!(res.status(), reqwest::StatusCode::NOT_FOUND);
+
+        server.shutdown().await?;
+        Ok(())
+    }
+
     fn test_resolver(nameserver: SocketAddr) -> DnsResolver {
         let mut config = ResolverConfig::new();
         let nameserver_config = NameServerConfig::new(nameserver, Protocol::Udp);
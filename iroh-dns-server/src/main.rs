@@ -1,40 +1,950 @@
 #![allow(unused_imports)]
 
 use std::future::Future;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use axum::{routing::get, Router};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use futures_lite::FutureExt;
 use iroh_dns_server::{
-    config::Config, metrics::init_metrics, server::run_with_config_until_ctrl_c,
+    api_keys::{ApiKeyScope, ApiKeyStore},
+    bench::{self, BenchConfig, BenchTarget},
+    config::{self, BindAddr, Config, Profile},
+    db::{self, DbStats, PacketFilter},
+    doctor::{self, DoctorOptions},
+    history::PacketVersion,
+    http,
+    inspect::PacketReport,
+    metrics::init_metrics,
+    publish, resolve, retention, sandbox,
+    server::{run_with_config_file_until_ctrl_c, run_with_config_until_ctrl_c, Shutdown},
+    tail::{self, TailFilter},
+    telemetry,
 };
+use iroh_net::{dns::node_info, key::SecretKey};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, debug_span, error, error_span, Instrument, Span};
+use url::Url;
 
 #[derive(Parser, Debug)]
 struct Cli {
     /// Path to config file
     #[clap(short, long)]
     config: Option<PathBuf>,
+    /// Run a server for local development, with all listeners on localhost, a self-signed
+    /// certificate, no rate limits and an in-memory store
+    #[clap(long, conflicts_with = "config")]
+    dev: bool,
+    #[clap(flatten)]
+    overrides: ConfigOverrides,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+// Overrides of config fields, which take precedence over the config file and the environment.
+#[derive(clap::Args, Debug)]
+#[clap(next_help_heading = "Config overrides")]
+struct ConfigOverrides {
+    /// Port of the HTTP server
+    #[clap(long)]
+    http_port: Option<u16>,
+    /// Address or interface name to bind the HTTP server to, can be repeated
+    #[clap(long)]
+    http_bind_addr: Vec<BindAddr>,
+    /// Port of the HTTPS server
+    #[clap(long)]
+    https_port: Option<u16>,
+    /// Address or interface name to bind the HTTPS server to, can be repeated
+    #[clap(long)]
+    https_bind_addr: Vec<BindAddr>,
+    /// Port of the DNS server
+    #[clap(long)]
+    dns_port: Option<u16>,
+    /// Address or interface name to bind the DNS server to, can be repeated
+    #[clap(long)]
+    dns_bind_addr: Vec<BindAddr>,
+    /// Origin to serve, can be repeated to replace all origins of the config
+    #[clap(long)]
+    origin: Vec<String>,
+    /// Address to serve the metrics on
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Disable the metrics server
+    #[clap(long)]
+    no_metrics: bool,
+}
+
+impl ConfigOverrides {
+    /// The overrides by dotted config key.
+    fn fields(&self) -> Vec<(String, toml::Value)> {
+        let string = |value: &dyn ToString| toml::Value::String(value.to_string());
+        let list =
+            |values: &[BindAddr]| toml::Value::Array(values.iter().map(|v| string(v)).collect());
+        let mut fields = Vec::new();
+        let mut set = |key: &str, value| fields.push((key.to_string(), value));
+        if let Some(port) = self.http_port {
+            set("http.port", toml::Value::Integer(port.into()));
+        }
+        if !self.http_bind_addr.is_empty() {
+            set("http.bind_addr", list(&self.http_bind_addr));
+        }
+        if let Some(port) = self.https_port {
+            set("https.port", toml::Value::Integer(port.into()));
+        }
+        if !self.https_bind_addr.is_empty() {
+            set("https.bind_addr", list(&self.https_bind_addr));
+        }
+        if let Some(port) = self.dns_port {
+            set("dns.port", toml::Value::Integer(port.into()));
+        }
+        if !self.dns_bind_addr.is_empty() {
+            set("dns.bind_addr", list(&self.dns_bind_addr));
+        }
+        if !self.origin.is_empty() {
+            let origins = self.origin.iter().map(|o| string(o)).collect();
+            set("dns.origins", toml::Value::Array(origins));
+        }
+        if let Some(addr) = &self.metrics_addr {
+            set("metrics.bind_addr", string(addr));
+        }
+        if self.no_metrics {
+            set("metrics.disabled", toml::Value::Boolean(true));
+        }
+        fields
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage API keys.
+    ///
+    /// This opens the database in the data directory, and thus only works while the server is
+    /// not running, unless the keys are kept in the Postgres store. Use the admin API to manage
+    /// keys of a running server.
+    #[clap(subcommand)]
+    ApiKey(ApiKeyCommand),
+    /// Show the ACME accounts stored in the data directory.
+    AcmeAccounts,
+    /// Send requests to a server at a fixed rate, and print the latency percentiles.
+    ///
+    /// The requests are spread over `--keys` new node keys, which are published with `--server`
+    /// before the run. This works from any host, and needs no config file.
+    Bench {
+        /// The requests to send (`udp`, `put` or `get`)
+        #[clap(long, default_value = "udp")]
+        mode: BenchMode,
+        /// The base URL of the server, for `put` and `get`, and to publish the keys before a
+        /// `udp` run
+        #[clap(long, required_if_eq_any([("mode", "put"), ("mode", "get")]))]
+        server: Option<Url>,
+        /// The address of the DNS server, for `udp`
+        #[clap(long, required_if_eq("mode", "udp"))]
+        dns: Option<SocketAddr>,
+        /// The origin to query the keys under, for `udp`
+        #[clap(long, required_if_eq("mode", "udp"))]
+        origin: Option<String>,
+        /// The number of requests per second
+        #[clap(long, default_value_t = 100)]
+        qps: u32,
+        /// How long to send requests for, in seconds
+        #[clap(long, default_value_t = 10)]
+        duration_secs: u64,
+        /// The number of keys the requests are spread over
+        #[clap(long, default_value_t = 100)]
+        keys: usize,
+        /// The maximum number of requests waiting for an answer, further requests are skipped
+        #[clap(long, default_value_t = 1000)]
+        max_in_flight: usize,
+    },
+    /// Work with config files.
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Work with the packet database.
+    #[clap(subcommand)]
+    Db(DbCommand),
+    /// Check the deployment of the `--config` file from outside, and print a pass/fail report.
+    ///
+    /// Checks the certificates the HTTPS server serves, the delegation of the origins and the SOA
+    /// record with a public resolver, the reachability of the ports with a port check service,
+    /// and whether the bootstrap nodes of the mainline DHT answer. Exits with an error if any
+    /// check fails.
+    Doctor {
+        /// The public DNS resolver to check the delegation with
+        #[clap(long, default_value = doctor::DEFAULT_RESOLVER)]
+        resolver: SocketAddr,
+        /// A port check service, asked with `GET <url>?host=<host>&port=<port>`, that answers
+        /// with a success status if it can connect to the port
+        #[clap(long)]
+        probe_url: Option<Url>,
+    },
+    /// Generate node keys, and print them with the DNS names of their records.
+    ///
+    /// Prints a line per key with the secret key, the node id, the z-base-32 encoded public key,
+    /// and the name of the `_iroh` record under each origin, separated by tabs. The secret key
+    /// can be used with `publish --secret` or as `IROH_SECRET` of an iroh node.
+    Keygen {
+        /// The number of keys
+        #[clap(long, default_value_t = 1)]
+        count: usize,
+        /// The origins of the DNS names (defaults to the origins of the config)
+        #[clap(long = "origin")]
+        origins: Vec<String>,
+    },
+    /// Decode signed packets.
+    #[clap(subcommand)]
+    Packet(PacketCommand),
+    /// Publish a node announcement to a server, and check that the server serves it.
+    ///
+    /// The announcement is signed with a new throwaway key, unless `--secret` or the
+    /// `IROH_SECRET` environment variable is set. This works from any host, and needs no config
+    /// file.
+    Publish {
+        /// The base URL of the server, e.g. `https://dns.example.org`
+        #[clap(long)]
+        server: Url,
+        /// The secret key of the node, hex or base32 encoded
+        #[clap(long)]
+        secret: Option<String>,
+        /// The home relay to announce
+        #[clap(long)]
+        relay: Option<Url>,
+        /// The direct addresses to announce (defaults to `192.0.2.1:4433`, from the documentation
+        /// range, if there is no `--relay` either)
+        #[clap(long = "addr", value_delimiter = ',')]
+        addrs: Vec<SocketAddr>,
+        /// The TTL of the records, in seconds
+        #[clap(long, default_value_t = 30)]
+        ttl: u32,
+    },
+    /// Resolve a node id with a server, and print the node info and how long it took.
+    ///
+    /// The node info is resolved with a DNS query with `--dns`, and with the pkarr relay API with
+    /// `--server`, or both. This works from any host, and needs no config file.
+    Resolve {
+        /// The node id, as printed by iroh or z-base-32 encoded
+        node_id: String,
+        /// The address of the DNS server, e.g. `127.0.0.1:53`
+        #[clap(long, requires = "origin")]
+        dns: Option<SocketAddr>,
+        /// The origin to resolve the node id under with `--dns`, e.g. `dns.example.org`
+        #[clap(long)]
+        origin: Option<String>,
+        /// The base URL of the server, e.g. `https://dns.example.org`
+        #[clap(long, required_unless_present = "dns")]
+        server: Option<Url>,
+    },
+    /// Run the server as a Windows service.
+    #[cfg(windows)]
+    #[clap(subcommand)]
+    Service(ServiceCommand),
+    /// Stream live query and publish events from a running server, with the admin API.
+    ///
+    /// Prints a line per event until the server shuts down. Events that can't be printed in time
+    /// are dropped by the server, and reported as missed.
+    Tail {
+        /// The base URL of the server, e.g. `http://localhost:8080`
+        #[clap(long)]
+        server: Url,
+        /// An API key with the `admin` scope
+        #[clap(long)]
+        token: String,
+        /// Only events of these z-base-32 encoded pubkeys
+        #[clap(long, value_delimiter = ',')]
+        pubkey: Vec<String>,
+        /// Only queries of these record types, e.g. `TXT`, without publishes
+        #[clap(long, value_delimiter = ',')]
+        qtype: Vec<String>,
+        /// The fraction of the events that are streamed, from 0.0 to 1.0
+        #[clap(long)]
+        sample_rate: Option<f64>,
+        /// Print the events as JSON lines
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+enum ServiceCommand {
+    /// Register the server as a service that is started at boot, with the `--config` file.
+    Install {
+        /// The name of the service
+        #[clap(long, default_value = iroh_dns_server::service::DEFAULT_SERVICE_NAME)]
+        name: String,
+    },
+    /// Stop the service and remove it.
+    Uninstall {
+        /// The name of the service
+        #[clap(long, default_value = iroh_dns_server::service::DEFAULT_SERVICE_NAME)]
+        name: String,
+    },
+    /// Run the server as the service, when started by the service control manager.
+    Run {
+        /// The name of the service
+        #[clap(long, default_value = iroh_dns_server::service::DEFAULT_SERVICE_NAME)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Check a config file for problems, and exit with an error if there are any.
+    ///
+    /// Checks the syntax, the DNS names of the origins and static records, that no two servers
+    /// bind the same address, and that the certificate files exist.
+    Check {
+        /// Path to the config file (defaults to the `--config` file)
+        path: Option<PathBuf>,
+    },
+    /// Write a config file for a profile, with a comment for each setting.
+    Init {
+        /// The profile (`dev`, `public-relay` or `private-fleet`)
+        #[clap(long, default_value = "dev")]
+        profile: Profile,
+        /// Path to write the config file to (defaults to stdout)
+        path: Option<PathBuf>,
+        /// Overwrite the file if it exists
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum BenchMode {
+    /// TXT queries to the DNS server
+    Udp,
+    /// Publishes with the pkarr relay API
+    Put,
+    /// Lookups with the pkarr relay API
+    Get,
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Print the size of the packet database, the number of packets, their oldest and newest
+    /// timestamps, and how many packets there are by size and record name.
+    ///
+    /// The database in the data directory is read, which only works while the server is not
+    /// running, or the statistics are requested from a running server with `--server`.
+    Stats {
+        /// Get the statistics from the server with this base URL, e.g. `http://localhost:8080`
+        #[clap(long, requires = "token")]
+        server: Option<Url>,
+        /// An API key with the `admin` scope, for `--server`
+        #[clap(long)]
+        token: Option<String>,
+    },
+    /// Remove packets older than a maximum age, and compact the database.
+    ///
+    /// This only works while the server is not running. Use `POST /admin/db-gc` to remove old
+    /// packets from a running server, without compaction.
+    Gc {
+        /// Only print the packets that would be removed
+        #[clap(long)]
+        dry_run: bool,
+        /// Remove packets older than this many seconds (defaults to `retention.max_age_secs` of
+        /// the config)
+        #[clap(long)]
+        max_age_secs: Option<u64>,
+    },
+    /// Check the signature and encoding of every packet in the database, and exit with an error
+    /// if there are invalid ones.
+    ///
+    /// This only works while the server is not running.
+    Verify {
+        /// Remove the invalid packets
+        #[clap(long)]
+        prune: bool,
+    },
+    /// Write the packets in the database to a file, one JSON object per line.
+    ///
+    /// This only works while the server is not running.
+    Export {
+        /// The file to write to (defaults to stdout)
+        path: Option<PathBuf>,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    /// Store the packets of an export in the database, unless the stored packets are newer.
+    ///
+    /// The database is created if it doesn't exist. This only works while the server is not
+    /// running.
+    Import {
+        /// The file to read from (defaults to stdin)
+        path: Option<PathBuf>,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct FilterArgs {
+    /// Only the packets of these z-base-32 encoded public keys
+    #[clap(long = "pubkey", value_delimiter = ',')]
+    pubkeys: Vec<String>,
+    /// Only packets with a timestamp at most this many seconds old
+    #[clap(long)]
+    max_age_secs: Option<u64>,
+}
+
+impl From<FilterArgs> for PacketFilter {
+    fn from(args: FilterArgs) -> Self {
+        Self {
+            pubkeys: args.pubkeys,
+            max_age: args.max_age_secs.map(std::time::Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum PacketCommand {
+    /// Print the public key, timestamp, signature validity and DNS records of a signed packet.
+    ///
+    /// The packet of a public key is read from the database in the data directory, which only
+    /// works while the server is not running, or from a running server with `--server`.
+    Inspect {
+        /// A z-base-32 encoded public key, or the path of a file with a signed packet (public
+        /// key, signature, timestamp and DNS packet, as stored in the database)
+        packet: String,
+        /// Get the packet from the server with this base URL, e.g. `http://localhost:8080`
+        #[clap(long)]
+        server: Option<Url>,
+    },
+    /// Print the records of the packet of a public key that was current at a time, with the
+    /// records that were added and removed since the version before it.
+    ///
+    /// The versions are retained with a `[history]` config. They are read from the database in
+    /// the data directory, which only works while the server is not running, or from a running
+    /// server with `--server`.
+    At {
+        /// A z-base-32 encoded public key
+        pubkey: String,
+        /// The time in RFC 3339 format, e.g. `2024-05-01T12:00:00Z`
+        time: String,
+        /// Get the version from the server with this base URL, e.g. `http://localhost:8080`
+        #[clap(long, requires = "token")]
+        server: Option<Url>,
+        /// An API key with the `admin` scope, for `--server`
+        #[clap(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ApiKeyCommand {
+    /// Create a new API key and print its token.
+    Create {
+        /// A human readable name for the key
+        name: String,
+        /// The scopes of the key (`publish`, `sync` or `admin`)
+        #[clap(long, value_delimiter = ',', default_value = "publish")]
+        scopes: Vec<ApiKeyScope>,
+    },
+    /// List all API keys.
+    List,
+    /// Replace the secret of an API key and print the new token.
+    Rotate {
+        /// The id of the key
+        id: String,
+    },
+    /// Revoke an API key.
+    Revoke {
+        /// The id of the key
+        id: String,
+    },
+}
+
+fn main() -> Result<ExitCode> {
     let args = Cli::parse();
+    config::set_overrides(args.overrides.fields())?;
+    if let Some(Command::Config(command)) = &args.command {
+        tokio::runtime::Runtime::new()?
+            .block_on(config_command(command, args.config.as_deref()))?;
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    let config = if let Some(path) = args.config {
-        debug!("loading config from {:?}", path);
-        Config::load(path).await?
+    // the config is loaded before tracing is set up, because it contains the tracing config
+    let config = if let Some(path) = &args.config {
+        Config::load_blocking(path)?
+    } else if args.dev {
+        Config::dev().with_env_overrides()?
     } else {
-        debug!("using default config");
-        Config::default()
+        Config::default().with_env_overrides()?
     };
+    if args.command.is_none() {
+        // before the runtime is started, so that all of its threads inherit the restriction
+        sandbox::restrict_files(&config, args.config.as_deref())?;
+    }
+    let runtime = config.runtime.clone().unwrap_or_default().build_runtime()?;
+    let exit_code = runtime.block_on(run(args, config))?;
+    // tasks that are still running after a forced shutdown are not waited for
+    runtime.shutdown_background();
+    Ok(exit_code)
+}
+
+async fn run(args: Cli, config: Config) -> Result<ExitCode> {
+    let _telemetry = telemetry::init(
+        config.traces_config().as_ref(),
+        config.logging.as_ref(),
+        config.sentry.as_ref(),
+    )?;
+    match &args.config {
+        Some(path) => debug!("loaded config from {:?}", path),
+        None => debug!("using default config"),
+    }
+
+    match args.command {
+        None => {
+            init_metrics(config.metrics.as_ref())?;
+            if args.dev {
+                print_dev_info(&config);
+            }
+            let shutdown = match args.config {
+                Some(path) => run_with_config_file_until_ctrl_c(config, path).await?,
+                None => run_with_config_until_ctrl_c(config).await?,
+            };
+            return Ok(shutdown.exit_code());
+        }
+        Some(Command::ApiKey(command)) => api_key(command, &config).await?,
+        Some(Command::AcmeAccounts) => acme_accounts(&config).await?,
+        Some(Command::Bench {
+            mode,
+            server,
+            dns,
+            origin,
+            qps,
+            duration_secs,
+            keys,
+            max_in_flight,
+        }) => {
+            let target = match (mode, dns, origin) {
+                (BenchMode::Udp, Some(addr), Some(origin)) => BenchTarget::Udp { addr, origin },
+                (BenchMode::Udp, _, _) => bail!("udp needs --dns and --origin"),
+                (BenchMode::Put, _, _) => BenchTarget::Put,
+                (BenchMode::Get, _, _) => BenchTarget::Get,
+            };
+            let report = bench::run(BenchConfig {
+                server,
+                target,
+                qps,
+                duration: std::time::Duration::from_secs(duration_secs),
+                keys,
+                max_in_flight,
+            })
+            .await?;
+            print!("{report}");
+        }
+        Some(Command::Db(command)) => db_command(command, &config).await?,
+        Some(Command::Doctor {
+            resolver,
+            probe_url,
+        }) => {
+            let options = DoctorOptions {
+                resolver,
+                probe_url,
+            };
+            let report = doctor::run(&config, &options).await;
+            print!("{report}");
+            if report.failed() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(Command::Keygen { count, origins }) => {
+            let origins = if origins.is_empty() {
+                &config.dns.origins
+            } else {
+                &origins
+            };
+            keygen(count, origins)
+        }
+        Some(Command::Packet(command)) => packet_command(command, &config).await?,
+        Some(Command::Publish {
+            server,
+            secret,
+            relay,
+            addrs,
+            ttl,
+        }) => publish_node(server, secret, relay, addrs, ttl).await?,
+        Some(Command::Resolve {
+            node_id,
+            dns,
+            origin,
+            server,
+        }) => resolve_node(&node_id, dns, origin, server).await?,
+        Some(Command::Config(_)) => unreachable!("handled before loading the config"),
+        #[cfg(windows)]
+        Some(Command::Service(command)) => service_command(command, config, args.config).await?,
+        Some(Command::Tail {
+            server,
+            token,
+            pubkey,
+            qtype,
+            sample_rate,
+            json,
+        }) => {
+            let list = |items: Vec<String>| (!items.is_empty()).then(|| items.join(","));
+            let filter = TailFilter {
+                pubkey: list(pubkey),
+                qtype: list(qtype),
+                sample_rate,
+            };
+            tail::follow(&server, &token, &filter, |event| {
+                if json {
+                    println!("{}", serde_json::to_string(&event).expect("serializable"));
+                } else {
+                    println!("{event}");
+                }
+            })
+            .await
+            .context("failed to stream the events")?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(windows)]
+async fn service_command(
+    command: ServiceCommand,
+    config: Config,
+    config_path: Option<PathBuf>,
+) -> Result<()> {
+    use iroh_dns_server::service;
+
+    match command {
+        ServiceCommand::Install { name } => {
+            service::install(&name, config_path.as_deref())?;
+            println!("installed service {name}, start it with `sc.exe start {name}`");
+            Ok(())
+        }
+        ServiceCommand::Uninstall { name } => {
+            service::uninstall(&name)?;
+            println!("removed service {name}");
+            Ok(())
+        }
+        ServiceCommand::Run { name } => {
+            init_metrics(config.metrics.as_ref())?;
+            service::run(&name, config, config_path).await
+        }
+    }
+}
+
+async fn config_command(command: &ConfigCommand, config_path: Option<&Path>) -> Result<()> {
+    match command {
+        ConfigCommand::Check { path } => {
+            let path = path
+                .as_deref()
+                .or(config_path)
+                .context("no config file to check, pass a path or --config")?;
+            let config = Config::load(path).await?;
+            let problems = config.check();
+            for problem in &problems {
+                eprintln!("{}: {problem}", path.display());
+            }
+            if !problems.is_empty() {
+                let noun = if problems.len() == 1 {
+                    "problem"
+                } else {
+                    "problems"
+                };
+                bail!("{}: found {} {noun}", path.display(), problems.len());
+            }
+            println!("{}: ok", path.display());
+            Ok(())
+        }
+        ConfigCommand::Init {
+            profile,
+            path,
+            force,
+        } => {
+            let template = profile.template();
+            let Some(path) = path else {
+                print!("{template}");
+                return Ok(());
+            };
+            if path.exists() && !force {
+                bail!(
+                    "{} already exists, pass --force to overwrite it",
+                    path.display()
+                );
+            }
+            tokio::fs::write(path, template)
+                .await
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            println!("wrote {profile} config to {}", path.display());
+            Ok(())
+        }
+    }
+}
+
+async fn acme_accounts(config: &Config) -> Result<()> {
+    for account in http::acme_accounts(&config.data_dir()?).await? {
+        let kid = account.kid.as_deref().unwrap_or("unknown");
+        println!(
+            "{}\t{}\t{}\t{}",
+            account.directory_url,
+            kid,
+            account.created,
+            account.contact.join(",")
+        );
+    }
+    Ok(())
+}
+
+/// Print the addresses of the servers of `--dev`, to point a local iroh node at.
+fn print_dev_info(config: &Config) {
+    let ip = match config.dns.bind_addr.first() {
+        Some(BindAddr::Ip(ip)) => *ip,
+        _ => Ipv4Addr::LOCALHOST.into(),
+    };
+    println!("dev mode, the packets are kept in memory and lost on shutdown");
+    if let Some(port) = config.http.as_ref().and_then(|http| http.port) {
+        println!("  pkarr relay:  http://localhost:{port}/pkarr");
+    }
+    if let Some(https) = &config.https {
+        println!(
+            "  pkarr relay:  https://localhost:{}/pkarr (self-signed certificate)",
+            https.port
+        );
+    }
+    println!("  DNS resolver: {}", SocketAddr::new(ip, config.dns.port));
+    if let Some(origin) = config.dns.origins.iter().find(|origin| *origin != ".") {
+        println!("  origin:       {}", origin.trim_end_matches('.'));
+    }
+}
+
+fn keygen(count: usize, origins: &[String]) {
+    for _ in 0..count {
+        let secret_key = SecretKey::generate();
+        let z32 = node_info::to_z32(&secret_key.public());
+        let names: Vec<String> = origins
+            .iter()
+            .map(|origin| match origin.trim_end_matches('.') {
+                "" => format!("_iroh.{z32}."),
+                origin => format!("_iroh.{z32}.{origin}."),
+            })
+            .collect();
+        println!(
+            "{secret_key}\t{}\t{z32}\t{}",
+            secret_key.public(),
+            names.join("\t")
+        );
+    }
+}
+
+async fn db_command(command: DbCommand, config: &Config) -> Result<()> {
+    match command {
+        DbCommand::Stats { server, token } => {
+            let stats = match (server, token) {
+                (Some(url), Some(token)) => DbStats::from_server(&url, &token).await,
+                _ => DbStats::from_store(&config.signed_packet_store_path()?).await,
+            };
+            print!(
+                "{}",
+                stats.context("failed to get the database statistics")?
+            );
+        }
+        DbCommand::Gc {
+            dry_run,
+            max_age_secs,
+        } => {
+            let max_age_secs = max_age_secs
+                .or(config.retention.as_ref().map(|r| r.max_age_secs))
+                .context("--max-age-secs is required without a retention config")?;
+            let max_age = std::time::Duration::from_secs(max_age_secs);
+            let path = config.signed_packet_store_path()?;
+            let report = retention::gc_store(&path, max_age, dry_run).await?;
+            for packet in &report.sample {
+                println!("{} {}", packet.public_key, packet.timestamp);
+            }
+            if report.expired > report.sample.len() as u64 {
+                println!("...");
+            }
+            let verb = if dry_run { "would remove" } else { "removed" };
+            eprintln!(
+                "{verb} {} of {} packets older than {max_age_secs}s",
+                report.expired, report.checked
+            );
+        }
+        DbCommand::Verify { prune } => {
+            let path = config.signed_packet_store_path()?;
+            let report = db::verify_store(&path, prune).await?;
+            for packet in &report.invalid {
+                println!("{}: {}", packet.public_key, packet.reason);
+            }
+            match (report.invalid.len(), prune) {
+                (0, _) => eprintln!("all {} packets are valid", report.checked),
+                (invalid, true) => eprintln!(
+                    "removed {invalid} invalid packets, of {} packets",
+                    report.checked
+                ),
+                (invalid, false) => bail!(
+                    "{invalid} of {} packets are invalid, remove them with --prune",
+                    report.checked
+                ),
+            }
+        }
+        DbCommand::Export { path, filter } => {
+            let store_path = config.signed_packet_store_path()?;
+            let filter = filter.into();
+            let exported = match path {
+                Some(path) => {
+                    let file = std::fs::File::create(&path)
+                        .with_context(|| format!("failed to create {}", path.display()))?;
+                    db::export_store(&store_path, &filter, std::io::BufWriter::new(file)).await?
+                }
+                None => db::export_store(&store_path, &filter, std::io::stdout().lock()).await?,
+            };
+            eprintln!("exported {exported} packets");
+        }
+        DbCommand::Import { path, filter } => {
+            let store_path = config.signed_packet_store_path()?;
+            let filter = filter.into();
+            let summary = match path {
+                Some(path) => {
+                    let file = std::fs::File::open(&path)
+                        .with_context(|| format!("failed to open {}", path.display()))?;
+                    db::import_store(&store_path, &filter, std::io::BufReader::new(file)).await?
+                }
+                None => db::import_store(&store_path, &filter, std::io::stdin().lock()).await?,
+            };
+            eprintln!("{summary}");
+        }
+    }
+    Ok(())
+}
+
+async fn publish_node(
+    server: Url,
+    secret: Option<String>,
+    relay: Option<Url>,
+    addrs: Vec<SocketAddr>,
+    ttl: u32,
+) -> Result<()> {
+    let secret_key = match secret.or_else(|| std::env::var("IROH_SECRET").ok()) {
+        Some(secret) => secret.parse().context("invalid secret key")?,
+        None => SecretKey::generate(),
+    };
+    let info = publish::node_info(&secret_key, relay, addrs.into_iter().collect());
+    let elapsed = publish::publish(&server, &secret_key, &info, ttl)
+        .await
+        .with_context(|| format!("failed to publish to {server}"))?;
+    println!(
+        "published {} to {server} ({} ms)",
+        info.node_id,
+        elapsed.as_millis()
+    );
+    let resolution = publish::verify(&server, &info).await;
+    print!("{resolution}");
+    if resolution.info.is_err() {
+        bail!("the server does not serve the announcement");
+    }
+    Ok(())
+}
+
+async fn resolve_node(
+    node_id: &str,
+    dns: Option<SocketAddr>,
+    origin: Option<String>,
+    server: Option<Url>,
+) -> Result<()> {
+    let node_id = resolve::parse_node_id(node_id)?;
+    let mut resolutions = Vec::new();
+    if let (Some(addr), Some(origin)) = (dns, origin) {
+        resolutions.push(resolve::resolve_dns(addr, &origin, &node_id).await);
+    }
+    if let Some(url) = server {
+        resolutions.push(resolve::resolve_http(&url, &node_id).await);
+    }
+    for resolution in &resolutions {
+        print!("{resolution}");
+    }
+    if resolutions
+        .iter()
+        .any(|resolution| resolution.info.is_err())
+    {
+        bail!("failed to resolve {node_id}");
+    }
+    Ok(())
+}
+
+async fn packet_command(command: PacketCommand, config: &Config) -> Result<()> {
+    match command {
+        PacketCommand::Inspect { packet, server } => {
+            let path = Path::new(&packet);
+            let report = if path.is_file() {
+                let bytes = tokio::fs::read(path)
+                    .await
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                Some(PacketReport::decode(&bytes)?)
+            } else {
+                let res = match server {
+                    Some(url) => PacketReport::from_server(&url, &packet).await,
+                    None => {
+                        let path = config.signed_packet_store_path()?;
+                        PacketReport::from_store(&path, &packet).await
+                    }
+                };
+                res.with_context(|| format!("failed to get the packet of {packet}"))?
+            };
+            match report {
+                Some(report) => print!("{report}"),
+                None => bail!("no packet found for {packet}"),
+            }
+        }
+        PacketCommand::At {
+            pubkey,
+            time,
+            server,
+            token,
+        } => {
+            let time = OffsetDateTime::parse(&time, &Rfc3339)
+                .with_context(|| format!("invalid time {time}, expected RFC 3339"))?;
+            let version = match (server, token) {
+                (Some(url), Some(token)) => {
+                    PacketVersion::from_server(&url, &token, &pubkey, time).await
+                }
+                _ => {
+                    let path = config.signed_packet_store_path()?;
+                    PacketVersion::from_store(&path, &pubkey, time).await
+                }
+            };
+            match version.with_context(|| format!("failed to get the history of {pubkey}"))? {
+                Some(version) => print!("{version}"),
+                None => bail!("no retained version of {pubkey} at {time}"),
+            }
+        }
+    }
+    Ok(())
+}
 
-    init_metrics();
-    run_with_config_until_ctrl_c(config).await
+async fn api_key(command: ApiKeyCommand, config: &Config) -> Result<()> {
+    let store = ApiKeyStore::for_config(config).await?;
+    match command {
+        ApiKeyCommand::Create { name, scopes } => {
+            let key = store.create(name, scopes).await?;
+            println!("created api key {}", key.key.id);
+            println!("token: {}", key.token);
+        }
+        ApiKeyCommand::List => {
+            for key in store.list().await? {
+                let scopes: Vec<_> = key.scopes.iter().map(ToString::to_string).collect();
+                let state = if key.is_active() { "active" } else { "revoked" };
+                println!("{}\t{}\t{}\t{}", key.id, state, scopes.join(","), key.name);
+            }
+        }
+        ApiKeyCommand::Rotate { id } => match store.rotate(&id).await? {
+            Some(key) => println!("token: {}", key.token),
+            None => anyhow::bail!("no active api key with id {id}"),
+        },
+        ApiKeyCommand::Revoke { id } => match store.revoke(&id).await? {
+            true => println!("revoked api key {id}"),
+            false => anyhow::bail!("no active api key with id {id}"),
+        },
+    }
+    Ok(())
 }
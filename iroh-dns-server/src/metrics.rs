@@ -1,8 +1,58 @@
 //! Metrics support for the server
+//!
+//! No metric is labeled by pubkey or client address. The label values that are taken from
+//! requests, like the query type, are capped at [`MetricsConfig::max_label_values`] per metric, so
+//! that unusual traffic can't create an unbounded number of series.
 
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{ensure, Result};
+use hickory_server::server::Protocol;
 use iroh_metrics::core::{Core, Counter, Metric};
+use parking_lot::RwLock;
+#[cfg(feature = "metrics")]
+use prometheus_client::{
+    encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter as LabeledCounter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
 use struct_iterable::Iterable;
 
+#[cfg(not(feature = "metrics"))]
+use self::local::{exponential_buckets, Counter as LabeledCounter, Family, Gauge, Histogram};
+
+use crate::{
+    abuse::AbuseKind,
+    config::MetricsConfig,
+    http::rate_limiting::{KeyOutcome, RateLimitClass},
+};
+
+#[cfg(feature = "metrics")]
+pub(crate) use self::{
+    otlp::push as push_otlp,
+    push::push as push_prometheus,
+    server::{bind, serve},
+};
+
+#[cfg(not(feature = "metrics"))]
+mod local;
+#[cfg(feature = "metrics")]
+mod otlp;
+#[cfg(feature = "metrics")]
+mod push;
+#[cfg(feature = "metrics")]
+mod server;
+
 /// Metrics for iroh-dns-server
 #[derive(Debug, Clone, Iterable)]
 #[allow(missing_docs)]
@@ -21,7 +71,27 @@ pub struct Metrics {
     pub http_requests_duration_ms: Counter,
     pub store_packets_inserted: Counter,
     pub store_packets_removed: Counter,
+    pub store_packets_expired: Counter,
     pub store_packets_updated: Counter,
+    pub ocsp_refresh_success: Counter,
+    pub ocsp_refresh_error: Counter,
+    pub acme_renewal_success: Counter,
+    pub acme_renewal_error: Counter,
+    pub dns_zone_transfers_out: Counter,
+    pub dns_zone_transfers_in: Counter,
+    pub dns_zone_transfers_failed: Counter,
+    pub dns_notify_sent: Counter,
+    pub health_checks_failed: Counter,
+    pub gossip_packets_sent: Counter,
+    pub gossip_packets_stored: Counter,
+    pub gossip_packets_invalid: Counter,
+    pub ring_publishes_forwarded: Counter,
+    pub ring_lookups_forwarded: Counter,
+    pub ring_requests_failed: Counter,
+    pub sync_packets_stored: Counter,
+    pub sync_packets_invalid: Counter,
+    pub sync_requests_failed: Counter,
+    pub replica_checks_failed: Counter,
 }
 
 impl Default for Metrics {
@@ -43,7 +113,43 @@ impl Default for Metrics {
             http_requests_duration_ms: Counter::new("Total duration of all HTTP requests"),
             store_packets_inserted: Counter::new("Signed packets inserted into the store"),
             store_packets_removed: Counter::new("Signed packets removed from the store"),
+            store_packets_expired: Counter::new(
+                "Signed packets removed from the store for being older than the maximum age",
+            ),
             store_packets_updated: Counter::new("Number of updates to existing packets"),
+            ocsp_refresh_success: Counter::new("Number of fetched OCSP responses"),
+            ocsp_refresh_error: Counter::new("Number of failed OCSP response fetches"),
+            acme_renewal_success: Counter::new("Number of certificates obtained from the ACME CA"),
+            acme_renewal_error: Counter::new("Number of failed ACME certificate orders"),
+            dns_zone_transfers_out: Counter::new("Zone transfers served to secondaries"),
+            dns_zone_transfers_in: Counter::new("Zone transfers from the primary, on a secondary"),
+            dns_zone_transfers_failed: Counter::new(
+                "Failed refreshes of the zones from the primary, on a secondary",
+            ),
+            dns_notify_sent: Counter::new("NOTIFY messages sent to secondaries"),
+            health_checks_failed: Counter::new("Failed health checks of the store and the DHT"),
+            gossip_packets_sent: Counter::new("Published packets broadcast to the gossip mesh"),
+            gossip_packets_stored: Counter::new(
+                "Packets from the gossip mesh that updated the store",
+            ),
+            gossip_packets_invalid: Counter::new(
+                "Packets from the gossip mesh with an invalid signature or encoding",
+            ),
+            ring_publishes_forwarded: Counter::new(
+                "Publishes forwarded to the server that owns the key on the ring",
+            ),
+            ring_lookups_forwarded: Counter::new(
+                "Packets fetched from the server that owns the key on the ring",
+            ),
+            ring_requests_failed: Counter::new("Failed requests to other servers of the ring"),
+            sync_packets_stored: Counter::new(
+                "Packets from the upstream change feed that updated the store",
+            ),
+            sync_packets_invalid: Counter::new(
+                "Packets from the upstream change feed with an invalid signature or encoding",
+            ),
+            sync_requests_failed: Counter::new("Failed requests to the upstream change feed"),
+            replica_checks_failed: Counter::new("Failed health checks of the advertised replicas"),
         }
     }
 }
@@ -54,9 +160,1258 @@ impl Metric for Metrics {
     }
 }
 
-/// Init the metrics collection core.
-pub fn init_metrics() {
+/// The histograms whose buckets can be configured.
+const HISTOGRAMS: [&str; 3] = [
+    "dns_lookup_duration_seconds",
+    "mainline_lookup_duration_seconds",
+    "probe_duration_seconds",
+];
+/// Default maximum number of distinct values of a label of a metric.
+const DEFAULT_MAX_LABEL_VALUES: usize = 100;
+/// The label value that replaces values over the limit.
+const OVERFLOW_LABEL_VALUE: &str = "other";
+
+/// The configured histogram buckets and label limits, set in [`init_metrics`].
+static OPTIONS: OnceLock<MetricsOptions> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct MetricsOptions {
+    buckets: BTreeMap<String, Vec<f64>>,
+    max_label_values: Option<usize>,
+}
+
+/// The buckets of the histogram `name`: the configured ones, or `default`.
+fn buckets(name: &str, default: impl Iterator<Item = f64>) -> Histogram {
+    match OPTIONS.get().and_then(|options| options.buckets.get(name)) {
+        Some(buckets) => Histogram::new(buckets.iter().copied()),
+        None => Histogram::new(default),
+    }
+}
+
+/// The values seen per label of a metric, to cap the number of series.
+#[derive(Debug, Default)]
+struct LabelValues(RwLock<HashMap<(&'static str, &'static str), SeenValues>>);
+
+#[derive(Debug, Default)]
+struct SeenValues {
+    values: HashSet<String>,
+    overflowed: bool,
+}
+
+impl LabelValues {
+    /// `value` if it was seen before or the limit is not reached, otherwise `other`.
+    fn guard(&self, metric: &'static str, label: &'static str, value: String) -> String {
+        let max = OPTIONS
+            .get()
+            .and_then(|options| options.max_label_values)
+            .unwrap_or(DEFAULT_MAX_LABEL_VALUES);
+        if let Some(seen) = self.0.read().get(&(metric, label)) {
+            if seen.values.contains(&value) {
+                return value;
+            }
+        }
+        let mut map = self.0.write();
+        let seen = map.entry((metric, label)).or_default();
+        if seen.values.len() < max || seen.values.contains(&value) {
+            seen.values.insert(value.clone());
+            return value;
+        }
+        if !seen.overflowed {
+            seen.overflowed = true;
+            tracing::warn!(
+                metric,
+                label,
+                max,
+                "too many label values, further values are counted as `other`"
+            );
+        }
+        OVERFLOW_LABEL_VALUE.to_string()
+    }
+}
+
+/// Labels of the per-certificate metrics
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct CertLabels {
+    pub(crate) domain: String,
+}
+
+/// Metrics of the TLS certificates, labeled by domain
+#[derive(Debug, Default)]
+#[cfg_attr(not(any(feature = "https", feature = "metrics")), allow(dead_code))]
+pub(crate) struct CertMetrics {
+    pub(crate) cert_not_after_timestamp: Family<CertLabels, Gauge>,
+    pub(crate) ocsp_next_update_timestamp: Family<CertLabels, Gauge>,
+}
+
+#[cfg_attr(not(any(feature = "https", feature = "metrics")), allow(dead_code))]
+impl CertMetrics {
+    /// Get the certificate metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<CertMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Set `gauge` for all `domains`.
+    #[cfg_attr(not(feature = "https"), allow(dead_code))]
+    pub(crate) fn set(gauge: &Family<CertLabels, Gauge>, domains: &[String], value: i64) {
+        for domain in domains {
+            let labels = CertLabels {
+                domain: domain.clone(),
+            };
+            gauge.get_or_create(&labels).set(value);
+        }
+    }
+}
+
+/// Labels of the DNS query counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct QueryLabels {
+    /// The query type, e.g. `TXT`, or `OTHER` for unknown types
+    pub(crate) qtype: String,
+    /// The response code, e.g. `NoError`
+    pub(crate) rcode: String,
+    /// The origin the query name is in, see [`crate::dns::DnsHandler::zone_of`]
+    pub(crate) zone: String,
+}
+
+/// Labels of the DNS request counter of the UDP sockets and TCP listeners
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct SocketLabels {
+    /// The local address of the socket, e.g. `192.0.2.1:53`
+    pub(crate) socket: String,
+    /// `udp` or `tcp`
+    pub(crate) protocol: String,
+}
+
+/// Labels of the counter of DNS requests over the rate limit
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct ProtocolLabels {
+    /// `udp`, `tcp`, `tls` or `quic`
+    pub(crate) protocol: String,
+}
+
+/// Labels of the pkarr publish counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct PublishLabels {
+    /// The origin the publish was sent to, by the host of the request
+    pub(crate) zone: String,
+    /// `update`, `noop`, `forwarded` or `error`
+    pub(crate) outcome: String,
+}
+
+/// Where the answer to a DNS lookup came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum AnswerSource {
+    /// The in-memory zone cache
+    Cache,
+    /// The signed packet store
+    Store,
+    /// The mainline DHT, for packets that are not in the store
+    Mainline,
+    /// The static records of the origins
+    StaticZone,
+    /// The ACME DNS-01 challenges
+    Acme,
+    /// The records transferred from the primary, on a secondary
+    Transfer,
+    /// The server that owns the key on the ring
+    Ring,
+    /// The name resolvers of an embedder, for the names they failed to resolve
+    Names,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for AnswerSource {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the DNS lookup latency histogram
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct SourceLabels {
+    pub(crate) source: AnswerSource,
+}
+
+/// Whether a rejected publish timestamp is behind or ahead of the clock of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum SkewDirection {
+    /// The timestamp is behind the clock
+    Past,
+    /// The timestamp is ahead of the clock
+    Future,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for SkewDirection {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the counter of publishes rejected for their clock skew
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct SkewLabels {
+    pub(crate) direction: SkewDirection,
+}
+
+/// Metrics of DNS queries and pkarr publishes, labeled by zone, query type, response code and
+/// answer source
+#[derive(Debug)]
+pub(crate) struct DnsMetrics {
+    pub(crate) dns_queries: Family<QueryLabels, LabeledCounter>,
+    pub(crate) dns_socket_requests: Family<SocketLabels, LabeledCounter>,
+    pub(crate) dns_rate_limited: Family<ProtocolLabels, LabeledCounter>,
+    pub(crate) dns_lookup_duration_seconds: Family<SourceLabels, Histogram, fn() -> Histogram>,
+    pub(crate) pkarr_publishes: Family<PublishLabels, LabeledCounter>,
+    pub(crate) pkarr_publish_skew_rejections: Family<SkewLabels, LabeledCounter>,
+    label_values: LabelValues,
+}
+
+impl Default for DnsMetrics {
+    fn default() -> Self {
+        Self {
+            dns_queries: Default::default(),
+            dns_socket_requests: Default::default(),
+            dns_rate_limited: Default::default(),
+            pkarr_publishes: Default::default(),
+            pkarr_publish_skew_rejections: Default::default(),
+            // 0.5ms to ~4s
+            dns_lookup_duration_seconds: Family::new_with_constructor(|| {
+                buckets(
+                    "dns_lookup_duration_seconds",
+                    exponential_buckets(0.0005, 2.0, 14),
+                )
+            }),
+            label_values: Default::default(),
+        }
+    }
+}
+
+impl DnsMetrics {
+    /// Get the DNS metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<DnsMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Record the latency of a lookup answered from `source`.
+    pub(crate) fn observe_lookup(source: AnswerSource, duration: Duration) {
+        Self::get()
+            .dns_lookup_duration_seconds
+            .get_or_create(&SourceLabels { source })
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Count a DNS query.
+    pub(crate) fn count_query(qtype: String, rcode: String, zone: String) {
+        let metrics = Self::get();
+        let guard = &metrics.label_values;
+        let labels = QueryLabels {
+            qtype: guard.guard("dns_queries", "qtype", qtype),
+            rcode: guard.guard("dns_queries", "rcode", rcode),
+            zone: guard.guard("dns_queries", "zone", zone),
+        };
+        metrics.dns_queries.get_or_create(&labels).inc();
+    }
+
+    /// Count a DNS request received on the UDP socket or TCP listener at `socket`.
+    ///
+    /// The sockets are set in the config, so their label values are not capped.
+    pub(crate) fn count_socket_request(socket: SocketAddr, protocol: Protocol) {
+        let labels = SocketLabels {
+            socket: socket.to_string(),
+            protocol: protocol.to_string().to_ascii_lowercase(),
+        };
+        Self::get().dns_socket_requests.get_or_create(&labels).inc();
+    }
+
+    /// Count a DNS request over the rate limit of its source address.
+    pub(crate) fn count_rate_limited(protocol: Protocol) {
+        let labels = ProtocolLabels {
+            protocol: protocol.to_string().to_ascii_lowercase(),
+        };
+        Self::get().dns_rate_limited.get_or_create(&labels).inc();
+    }
+
+    /// Count a pkarr publish to `zone`.
+    pub(crate) fn count_publish(zone: String, outcome: &str) {
+        let metrics = Self::get();
+        let labels = PublishLabels {
+            zone: metrics.label_values.guard("pkarr_publishes", "zone", zone),
+            outcome: outcome.to_string(),
+        };
+        metrics.pkarr_publishes.get_or_create(&labels).inc();
+    }
+
+    /// Count a pkarr publish rejected because its timestamp is too far in the `direction`.
+    pub(crate) fn count_skew_rejection(direction: SkewDirection) {
+        Self::get()
+            .pkarr_publish_skew_rejections
+            .get_or_create(&SkewLabels { direction })
+            .inc();
+    }
+}
+
+/// The outcome of a mainline DHT lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+#[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+pub(crate) enum LookupOutcome {
+    /// A packet was found
+    Found,
+    /// No packet was found before the lookup timed out
+    NotFound,
+    /// The lookup failed, e.g. because the DHT client is shut down
+    Error,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for LookupOutcome {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the mainline lookup counts and latency histogram
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct OutcomeLabels {
+    pub(crate) outcome: LookupOutcome,
+}
+
+/// The outcome of a publish of a stored packet to the mainline DHT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+#[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+pub(crate) enum DhtPublishOutcome {
+    /// A packet was published for the first time
+    Published,
+    /// A packet was published again before the DHT nodes drop it
+    Republished,
+    /// The publish failed, and is retried later
+    Failed,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for DhtPublishOutcome {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the counter of the publishes to the mainline DHT
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct PublishOutcomeLabels {
+    pub(crate) outcome: DhtPublishOutcome,
+}
+
+/// Metrics of the mainline DHT fallback and of the publishes to the DHT
+///
+/// The pkarr client does not expose its routing table, so the time of the last lookup that
+/// found a packet is the best indicator of a working DHT connection.
+#[derive(Debug)]
+pub(crate) struct MainlineMetrics {
+    pub(crate) mainline_lookups: Family<OutcomeLabels, LabeledCounter>,
+    pub(crate) mainline_lookup_duration_seconds:
+        Family<OutcomeLabels, Histogram, fn() -> Histogram>,
+    pub(crate) mainline_last_found_timestamp: Gauge,
+    pub(crate) mainline_publishes: Family<PublishOutcomeLabels, LabeledCounter>,
+}
+
+impl Default for MainlineMetrics {
+    fn default() -> Self {
+        Self {
+            mainline_lookups: Default::default(),
+            // 10ms to ~80s, DHT lookups take seconds
+            mainline_lookup_duration_seconds: Family::new_with_constructor(|| {
+                buckets(
+                    "mainline_lookup_duration_seconds",
+                    exponential_buckets(0.01, 2.0, 14),
+                )
+            }),
+            mainline_last_found_timestamp: Default::default(),
+            mainline_publishes: Default::default(),
+        }
+    }
+}
+
+impl MainlineMetrics {
+    /// Get the mainline metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<MainlineMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Record a lookup with `outcome` that took `duration`.
+    #[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+    pub(crate) fn observe_lookup(outcome: LookupOutcome, duration: Duration) {
+        let metrics = Self::get();
+        metrics
+            .mainline_lookups
+            .get_or_create(&OutcomeLabels { outcome })
+            .inc();
+        metrics
+            .mainline_lookup_duration_seconds
+            .get_or_create(&OutcomeLabels { outcome })
+            .observe(duration.as_secs_f64());
+        if outcome == LookupOutcome::Found {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            metrics.mainline_last_found_timestamp.set(now);
+        }
+    }
+
+    /// Count a publish of a stored packet to the DHT with `outcome`.
+    #[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+    pub(crate) fn count_publish(outcome: DhtPublishOutcome) {
+        Self::get()
+            .mainline_publishes
+            .get_or_create(&PublishOutcomeLabels { outcome })
+            .inc();
+    }
+
+    /// Get the number of lookups with `outcome`.
+    pub(crate) fn lookups(outcome: LookupOutcome) -> u64 {
+        Self::get()
+            .mainline_lookups
+            .get_or_create(&OutcomeLabels { outcome })
+            .get()
+    }
+}
+
+/// The kind of a request of another node of the mainline DHT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+#[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+pub(crate) enum DhtRequestKind {
+    /// A check whether the node is alive
+    Ping,
+    /// A request for the nodes closest to an id
+    FindNode,
+    /// A request for the peers of a torrent
+    GetPeers,
+    /// A request for a stored value, e.g. a pkarr packet
+    GetValue,
+    /// A request to store a value or to announce a peer
+    Put,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for DhtRequestKind {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the DHT request counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct DhtRequestLabels {
+    pub(crate) request: DhtRequestKind,
+    /// Whether the request was dropped above the request limit
+    pub(crate) dropped: String,
+}
+
+/// Metrics of the requests of other nodes, if the server is a storing node of the mainline DHT
+#[derive(Debug, Default)]
+pub(crate) struct DhtNodeMetrics {
+    pub(crate) dht_node_requests: Family<DhtRequestLabels, LabeledCounter>,
+}
+
+impl DhtNodeMetrics {
+    /// Get the DHT node metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<DhtNodeMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Count a request of `kind` from another node, which was answered unless `dropped`.
+    #[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+    pub(crate) fn count(request: DhtRequestKind, dropped: bool) {
+        let labels = DhtRequestLabels {
+            request,
+            dropped: dropped.to_string(),
+        };
+        Self::get().dht_node_requests.get_or_create(&labels).inc();
+    }
+}
+
+/// Labels of the rate limit counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct RateLimitLabels {
+    /// The class of the rate limiter, e.g. `publish`
+    pub(crate) class: String,
+    /// How the rate limiting key was determined, e.g. `peer_ip`
+    pub(crate) key: String,
+    /// Whether the request was throttled
+    pub(crate) throttled: String,
+}
+
+/// Labels of the rate limiter size gauge
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct RateLimitClassLabels {
+    pub(crate) class: String,
+}
+
+/// Metrics of the HTTP rate limiters, labeled by class
+#[derive(Debug, Default)]
+pub(crate) struct RateLimitMetrics {
+    pub(crate) rate_limit_requests: Family<RateLimitLabels, LabeledCounter>,
+    pub(crate) rate_limit_keys: Family<RateLimitClassLabels, Gauge>,
+}
+
+impl RateLimitMetrics {
+    /// Get the rate limit metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<RateLimitMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Count a request checked by the rate limiter of `class`.
+    pub(crate) fn count(class: RateLimitClass, key: KeyOutcome, throttled: bool) {
+        let labels = RateLimitLabels {
+            class: class.to_string(),
+            key: <&'static str>::from(key).to_string(),
+            throttled: throttled.to_string(),
+        };
+        Self::get().rate_limit_requests.get_or_create(&labels).inc();
+    }
+
+    /// Set the number of keys tracked by the rate limiter of `class`.
+    pub(crate) fn set_keys(class: RateLimitClass, keys: usize) {
+        let labels = RateLimitClassLabels {
+            class: class.to_string(),
+        };
+        Self::get()
+            .rate_limit_keys
+            .get_or_create(&labels)
+            .set(keys as i64);
+    }
+}
+
+/// A resource whose limit is configured in [`ResourceLimitsConfig`](crate::config::ResourceLimitsConfig)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum LimitedResource {
+    /// DNS requests in flight
+    DnsRequests,
+    /// Connections over all HTTP and HTTPS listeners
+    HttpConnections,
+    /// Pending mainline DHT lookups
+    #[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+    MainlineLookups,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for LimitedResource {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the load shedding counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct LoadShedLabels {
+    pub(crate) resource: LimitedResource,
+}
+
+/// Metrics of the work rejected above the resource limits
+#[derive(Debug, Default)]
+pub(crate) struct LoadShedMetrics {
+    pub(crate) load_shed: Family<LoadShedLabels, LabeledCounter>,
+}
+
+impl LoadShedMetrics {
+    /// Get the load shedding metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<LoadShedMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Count a request or connection rejected because the limit of `resource` is reached.
+    pub(crate) fn count(resource: LimitedResource) {
+        Self::get()
+            .load_shed
+            .get_or_create(&LoadShedLabels { resource })
+            .inc();
+    }
+}
+
+/// Labels of the abuse event counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct AbuseLabels {
+    pub(crate) kind: String,
+}
+
+/// Labels of the counter of the traffic dropped by the IP reputation list
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct ReputationLabels {
+    pub(crate) listener: String,
+}
+
+/// Labels of the counter of the requests denied by the ACL
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct AclLabels {
+    pub(crate) operation: String,
+}
+
+/// Metrics of the abuse log, the IP reputation list and the ACL
+#[derive(Debug, Default)]
+pub(crate) struct AbuseMetrics {
+    pub(crate) abuse_events: Family<AbuseLabels, LabeledCounter>,
+    pub(crate) ip_reputation_dropped: Family<ReputationLabels, LabeledCounter>,
+    pub(crate) ip_reputation_entries: Gauge,
+    pub(crate) acl_denied: Family<AclLabels, LabeledCounter>,
+}
+
+impl AbuseMetrics {
+    /// Get the abuse metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<AbuseMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Count an event of the abuse log.
+    pub(crate) fn count(kind: AbuseKind) {
+        let labels = AbuseLabels {
+            kind: <&'static str>::from(kind).to_string(),
+        };
+        Self::get().abuse_events.get_or_create(&labels).inc();
+    }
+
+    /// Count a request of `operation` denied by the ACL.
+    pub(crate) fn count_acl_denied(operation: &'static str) {
+        let labels = AclLabels {
+            operation: operation.to_string(),
+        };
+        Self::get().acl_denied.get_or_create(&labels).inc();
+    }
+
+    /// Count a connection or DNS request dropped by the IP reputation list at `listener`.
+    pub(crate) fn count_dropped(listener: &'static str) {
+        let labels = ReputationLabels {
+            listener: listener.to_string(),
+        };
+        Self::get()
+            .ip_reputation_dropped
+            .get_or_create(&labels)
+            .inc();
+    }
+}
+
+/// Labels of the metrics of a tenant
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct TenantLabels {
+    pub(crate) tenant: String,
+}
+
+/// Metrics of the packets in the store
+#[derive(Debug, Default)]
+pub(crate) struct StoreMetrics {
+    pub(crate) store_packets_active: Gauge,
+}
+
+impl StoreMetrics {
+    /// Get the store metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<StoreMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Set the number of packets that are younger than the maximum age.
+    pub(crate) fn set_active_packets(packets: u64) {
+        Self::get().store_packets_active.set(packets as i64);
+    }
+}
+
+/// Labels of the publish counter of a tenant
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct TenantPublishLabels {
+    pub(crate) tenant: String,
+    pub(crate) outcome: String,
+}
+
+/// Metrics of the tenants
+#[derive(Debug, Default)]
+pub(crate) struct TenantMetrics {
+    pub(crate) tenant_publishes: Family<TenantPublishLabels, LabeledCounter>,
+    pub(crate) tenant_queries: Family<TenantLabels, LabeledCounter>,
+    pub(crate) tenant_packets: Family<TenantLabels, Gauge>,
+}
+
+impl TenantMetrics {
+    /// Get the tenant metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<TenantMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Count a publish of `tenant` with `outcome`.
+    pub(crate) fn count_publish(tenant: &str, outcome: &str) {
+        let labels = TenantPublishLabels {
+            tenant: tenant.to_string(),
+            outcome: outcome.to_string(),
+        };
+        Self::get().tenant_publishes.get_or_create(&labels).inc();
+    }
+
+    /// Count a lookup of a packet of `tenant`.
+    pub(crate) fn count_query(tenant: &str) {
+        let labels = TenantLabels {
+            tenant: tenant.to_string(),
+        };
+        Self::get().tenant_queries.get_or_create(&labels).inc();
+    }
+
+    /// Set the number of pubkeys `tenant` published.
+    pub(crate) fn set_packets(tenant: &str, packets: u64) {
+        let labels = TenantLabels {
+            tenant: tenant.to_string(),
+        };
+        Self::get()
+            .tenant_packets
+            .get_or_create(&labels)
+            .set(packets as i64);
+    }
+}
+
+/// What happened to a record set that is mirrored to a DNS provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum MirrorOutcome {
+    /// The record set was created or replaced
+    Upserted,
+    /// The record set was deleted
+    Deleted,
+    /// The change of the record set failed
+    Failed,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for MirrorOutcome {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the mirror counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct MirrorLabels {
+    pub(crate) outcome: MirrorOutcome,
+}
+
+/// Metrics of the mirror to a DNS provider
+#[derive(Debug, Default)]
+pub(crate) struct MirrorMetrics {
+    pub(crate) mirror_rrsets: Family<MirrorLabels, LabeledCounter>,
+}
+
+impl MirrorMetrics {
+    /// Get the mirror metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<MirrorMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Count `rrsets` record sets with `outcome`.
+    pub(crate) fn count(outcome: MirrorOutcome, rrsets: usize) {
+        Self::get()
+            .mirror_rrsets
+            .get_or_create(&MirrorLabels { outcome })
+            .inc_by(rrsets as u64);
+    }
+}
+
+/// What happened to an exported query record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum QueryLogOutcome {
+    /// The record was written to the sink
+    Written,
+    /// The record was dropped because the queue was full
+    Dropped,
+    /// Writing the record to the sink failed
+    Failed,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for QueryLogOutcome {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the query log counter
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct QueryLogLabels {
+    pub(crate) outcome: QueryLogOutcome,
+}
+
+/// Metrics of the query log export
+#[derive(Debug, Default)]
+pub(crate) struct QueryLogMetrics {
+    pub(crate) query_log_records: Family<QueryLogLabels, LabeledCounter>,
+}
+
+impl QueryLogMetrics {
+    /// Get the query log metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<QueryLogMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Count `records` query records with `outcome`.
+    pub(crate) fn count(outcome: QueryLogOutcome, records: usize) {
+        Self::get()
+            .query_log_records
+            .get_or_create(&QueryLogLabels { outcome })
+            .inc_by(records as u64);
+    }
+}
+
+/// A check of the self-check probe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum ProbeCheck {
+    /// Publish of the canary packet via HTTP(S)
+    Publish,
+    /// Resolve of the canary record via UDP
+    Udp,
+    /// Resolve of the canary record via DoH
+    Doh,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for ProbeCheck {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the self-check probe metrics
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct ProbeLabels {
+    pub(crate) check: ProbeCheck,
+}
+
+/// Metrics of the self-check probe, labeled by check
+#[derive(Debug)]
+pub(crate) struct ProbeMetrics {
+    pub(crate) probe_duration_seconds: Family<ProbeLabels, Histogram, fn() -> Histogram>,
+    pub(crate) probe_success: Family<ProbeLabels, Gauge>,
+    pub(crate) probe_last_success_timestamp: Family<ProbeLabels, Gauge>,
+}
+
+impl Default for ProbeMetrics {
+    fn default() -> Self {
+        Self {
+            // 1ms to ~4s
+            probe_duration_seconds: Family::new_with_constructor(|| {
+                buckets(
+                    "probe_duration_seconds",
+                    exponential_buckets(0.001, 2.0, 13),
+                )
+            }),
+            probe_success: Default::default(),
+            probe_last_success_timestamp: Default::default(),
+        }
+    }
+}
+
+impl ProbeMetrics {
+    /// Get the probe metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<ProbeMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Record a run of `check` that took `duration`.
+    pub(crate) fn observe(check: ProbeCheck, success: bool, duration: Duration) {
+        let metrics = Self::get();
+        let labels = ProbeLabels { check };
+        metrics
+            .probe_duration_seconds
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+        metrics
+            .probe_success
+            .get_or_create(&labels)
+            .set(success as i64);
+        if success {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            metrics
+                .probe_last_success_timestamp
+                .get_or_create(&labels)
+                .set(now);
+        }
+    }
+}
+
+/// A kind of peer of the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum PeerKind {
+    /// A bootstrap node of the mainline DHT
+    #[cfg_attr(not(feature = "mainline"), allow(dead_code))]
+    MainlineBootstrap,
+    /// A server of the gossip mesh
+    #[cfg_attr(not(feature = "gossip"), allow(dead_code))]
+    Gossip,
+    /// The upstream server of the change feed
+    Sync,
+    /// Another server of the hash ring
+    Ring,
+}
+
+#[cfg(feature = "metrics")]
+impl EncodeLabelValue for PeerKind {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> std::fmt::Result {
+        EncodeLabelValue::encode(&<&'static str>::from(*self), encoder)
+    }
+}
+
+/// Labels of the peer metrics
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "metrics", derive(EncodeLabelSet))]
+pub(crate) struct PeerLabels {
+    pub(crate) kind: PeerKind,
+    pub(crate) peer: String,
+}
+
+/// Metrics of the health of the peers, labeled by kind and peer
+#[derive(Debug, Default)]
+pub(crate) struct PeerMetrics {
+    pub(crate) peer_up: Family<PeerLabels, Gauge>,
+    pub(crate) peer_last_success_timestamp: Family<PeerLabels, Gauge>,
+}
+
+impl PeerMetrics {
+    /// Get the peer metrics.
+    ///
+    /// They are only exported if the metrics collection is initialized with [`init_metrics`].
+    pub(crate) fn get() -> &'static Self {
+        static METRICS: OnceLock<PeerMetrics> = OnceLock::new();
+        METRICS.get_or_init(Default::default)
+    }
+
+    /// Record whether `peer` of `kind` is reachable.
+    pub(crate) fn set_up(kind: PeerKind, peer: &str, up: bool) {
+        let metrics = Self::get();
+        let labels = PeerLabels {
+            kind,
+            peer: peer.to_string(),
+        };
+        metrics.peer_up.get_or_create(&labels).set(up as i64);
+        if up {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            metrics
+                .peer_last_success_timestamp
+                .get_or_create(&labels)
+                .set(now);
+        }
+    }
+}
+
+/// Init the metrics collection core, with the histogram buckets and label limits of `config`.
+///
+/// Fails if the configured buckets are invalid.
+pub fn init_metrics(config: Option<&MetricsConfig>) -> Result<()> {
+    if let Some(config) = config {
+        configure_metrics(config)?;
+    }
+    #[cfg(feature = "metrics")]
+    Core::init(|reg, metrics| {
+        metrics.insert(register_metrics(reg));
+    });
+    // the counters are not recorded, but read as zero from the core
+    #[cfg(not(feature = "metrics"))]
     Core::init(|reg, metrics| {
         metrics.insert(Metrics::new(reg));
     });
+    Ok(())
+}
+
+/// Use the histogram buckets and label limits of `config` for the metrics.
+///
+/// This is only needed when the metrics core is initialized by the embedding application, see
+/// [`register_metrics`], and only has an effect before the first metrics are recorded.
+///
+/// Fails if the configured buckets are invalid.
+pub fn configure_metrics(config: &MetricsConfig) -> Result<()> {
+    for (name, buckets) in &config.buckets {
+        ensure!(
+            HISTOGRAMS.contains(&name.as_str()),
+            "unknown histogram {name}, expected one of {}",
+            HISTOGRAMS.join(", ")
+        );
+        ensure!(
+            !buckets.is_empty()
+                && buckets.iter().all(|b| b.is_finite())
+                && buckets.windows(2).all(|w| w[0] < w[1]),
+            "the buckets of {name} must be finite and increasing"
+        );
+    }
+    let _ = OPTIONS.set(MetricsOptions {
+        buckets: config.buckets.clone(),
+        max_label_values: config.max_label_values,
+    });
+    Ok(())
+}
+
+/// Register all metrics of the server in `reg`, and return the counters to insert in the
+/// metrics core.
+///
+/// An application that embeds the server and initializes the metrics core itself registers the
+/// metrics of the server along with its own, instead of calling [`init_metrics`]:
+///
+/// ```no_run
+/// iroh_metrics::core::Core::init(|reg, metrics| {
+///     metrics.insert(iroh_dns_server::metrics::register_metrics(reg));
+/// });
+/// ```
+///
+/// The metrics are then exported by the application; disable the metrics server of the server
+/// with [`MetricsConfig::disabled`].
+#[cfg(feature = "metrics")]
+pub fn register_metrics(reg: &mut Registry) -> Metrics {
+    let metrics = Metrics::new(reg);
+    let cert_metrics = CertMetrics::get();
+    let reg = reg.sub_registry_with_prefix(Metrics::name());
+    reg.register(
+        "cert_not_after_timestamp",
+        "Expiry of the certificate, in seconds since the unix epoch",
+        cert_metrics.cert_not_after_timestamp.clone(),
+    );
+    reg.register(
+        "ocsp_next_update_timestamp",
+        "Time until which the stapled OCSP response is valid, in seconds since the unix epoch",
+        cert_metrics.ocsp_next_update_timestamp.clone(),
+    );
+    let dns_metrics = DnsMetrics::get();
+    reg.register(
+        "dns_queries",
+        "DNS queries by query type and response code",
+        dns_metrics.dns_queries.clone(),
+    );
+    reg.register(
+        "dns_socket_requests",
+        "DNS requests by the UDP socket or TCP listener they were received on",
+        dns_metrics.dns_socket_requests.clone(),
+    );
+    reg.register(
+        "dns_rate_limited",
+        "DNS requests over the rate limit of their source address, by protocol",
+        dns_metrics.dns_rate_limited.clone(),
+    );
+    reg.register(
+        "dns_lookup_duration_seconds",
+        "Duration of DNS lookups by answer source",
+        dns_metrics.dns_lookup_duration_seconds.clone(),
+    );
+    reg.register(
+        "pkarr_publishes",
+        "Pkarr publishes by zone and outcome",
+        dns_metrics.pkarr_publishes.clone(),
+    );
+    reg.register(
+        "pkarr_publish_skew_rejections",
+        "Pkarr publishes rejected because their timestamp is too far from the server clock, by direction",
+        dns_metrics.pkarr_publish_skew_rejections.clone(),
+    );
+    let rate_limit_metrics = RateLimitMetrics::get();
+    reg.register(
+        "rate_limit_requests",
+        "Requests checked by the rate limiters, by class, key extraction and whether they were throttled",
+        rate_limit_metrics.rate_limit_requests.clone(),
+    );
+    reg.register(
+        "rate_limit_keys",
+        "Number of keys tracked by the rate limiters, by class",
+        rate_limit_metrics.rate_limit_keys.clone(),
+    );
+    let mainline_metrics = MainlineMetrics::get();
+    reg.register(
+        "mainline_lookups",
+        "Number of mainline DHT lookups by outcome",
+        mainline_metrics.mainline_lookups.clone(),
+    );
+    reg.register(
+        "mainline_lookup_duration_seconds",
+        "Duration of mainline DHT lookups by outcome",
+        mainline_metrics.mainline_lookup_duration_seconds.clone(),
+    );
+    reg.register(
+        "mainline_last_found_timestamp",
+        "Time of the last mainline DHT lookup that found a packet, in seconds since the unix epoch",
+        mainline_metrics.mainline_last_found_timestamp.clone(),
+    );
+    reg.register(
+        "mainline_publishes",
+        "Number of publishes of stored packets to the mainline DHT by outcome",
+        mainline_metrics.mainline_publishes.clone(),
+    );
+    reg.register(
+        "dht_node_requests",
+        "Requests of other mainline DHT nodes by request type, and whether they were dropped",
+        DhtNodeMetrics::get().dht_node_requests.clone(),
+    );
+    reg.register(
+        "query_log_records",
+        "Exported query records by outcome",
+        QueryLogMetrics::get().query_log_records.clone(),
+    );
+    reg.register(
+        "mirror_rrsets",
+        "Record sets mirrored to the DNS provider by outcome",
+        MirrorMetrics::get().mirror_rrsets.clone(),
+    );
+    let abuse_metrics = AbuseMetrics::get();
+    reg.register(
+        "abuse_events",
+        "Events of the abuse log by kind",
+        abuse_metrics.abuse_events.clone(),
+    );
+    reg.register(
+        "ip_reputation_dropped",
+        "Connections and DNS requests dropped by the IP reputation list, by listener",
+        abuse_metrics.ip_reputation_dropped.clone(),
+    );
+    reg.register(
+        "ip_reputation_entries",
+        "Number of networks in the IP reputation list",
+        abuse_metrics.ip_reputation_entries.clone(),
+    );
+    reg.register(
+        "acl_denied",
+        "Requests denied by the ACL, by operation",
+        abuse_metrics.acl_denied.clone(),
+    );
+    reg.register(
+        "store_packets_active",
+        "Number of packets younger than the maximum age, as of the last removal of old packets",
+        StoreMetrics::get().store_packets_active.clone(),
+    );
+    let tenant_metrics = TenantMetrics::get();
+    reg.register(
+        "tenant_publishes",
+        "Publishes of the tenants by tenant and outcome",
+        tenant_metrics.tenant_publishes.clone(),
+    );
+    reg.register(
+        "tenant_queries",
+        "Lookups of the packets of the tenants via DNS and GET /pkarr, by tenant",
+        tenant_metrics.tenant_queries.clone(),
+    );
+    reg.register(
+        "tenant_packets",
+        "Number of pubkeys the tenants published, by tenant",
+        tenant_metrics.tenant_packets.clone(),
+    );
+    reg.register(
+        "load_shed",
+        "Work rejected because a resource limit is reached, by resource",
+        LoadShedMetrics::get().load_shed.clone(),
+    );
+    let probe_metrics = ProbeMetrics::get();
+    reg.register(
+        "probe_duration_seconds",
+        "Duration of the self-check probe by check",
+        probe_metrics.probe_duration_seconds.clone(),
+    );
+    reg.register(
+        "probe_success",
+        "Whether the last self-check probe succeeded, by check",
+        probe_metrics.probe_success.clone(),
+    );
+    reg.register(
+        "probe_last_success_timestamp",
+        "Time of the last successful self-check probe by check, in seconds since the unix epoch",
+        probe_metrics.probe_last_success_timestamp.clone(),
+    );
+    let peer_metrics = PeerMetrics::get();
+    reg.register(
+        "peer_up",
+        "Whether a peer was reachable at its last check, by kind and peer",
+        peer_metrics.peer_up.clone(),
+    );
+    reg.register(
+        "peer_last_success_timestamp",
+        "Time a peer was last reachable, by kind and peer, in seconds since the unix epoch",
+        peer_metrics.peer_last_success_timestamp.clone(),
+    );
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_capped() {
+        let guard = LabelValues::default();
+        for i in 0..DEFAULT_MAX_LABEL_VALUES {
+            assert_eq!(guard.guard("test", "label", i.to_string()), i.to_string());
+        }
+        assert_eq!(guard.guard("test", "label", "new".to_string()), "other");
+        assert_eq!(guard.guard("test", "label", "0".to_string()), "0");
+        assert_eq!(guard.guard("test", "other_label", "new".to_string()), "new");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn register_in_registry() -> Result<()> {
+        let mut reg = Registry::default();
+        let metrics = register_metrics(&mut reg);
+        metrics.pkarr_publish_update.inc();
+        let mut out = String::new();
+        prometheus_client::encoding::text::encode(&mut out, &reg)?;
+        assert!(out.contains("dns_server_pkarr_publish_update_total 1"));
+        assert!(out.contains("dns_server_cert_not_after_timestamp"));
+        Ok(())
+    }
 }
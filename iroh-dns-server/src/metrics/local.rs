@@ -0,0 +1,126 @@
+//! Stand-ins for the metric types of prometheus-client, without the `metrics` feature
+//!
+//! The counters and gauges are recorded, so that the values that the server reads itself, e.g.
+//! the time of the last DHT lookup, are kept. Nothing is exported, and histograms are not
+//! recorded at all.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::RwLock;
+
+/// A metric per label set.
+pub(crate) struct Family<S, M, C = fn() -> M> {
+    metrics: Arc<RwLock<HashMap<S, M>>>,
+    constructor: C,
+}
+
+impl<S, M: Default> Default for Family<S, M> {
+    fn default() -> Self {
+        Self::new_with_constructor(M::default)
+    }
+}
+
+impl<S, M, C> Family<S, M, C> {
+    pub(crate) fn new_with_constructor(constructor: C) -> Self {
+        Self {
+            metrics: Default::default(),
+            constructor,
+        }
+    }
+}
+
+impl<S: Clone + Hash + Eq, M: Clone, C: Fn() -> M> Family<S, M, C> {
+    /// The metric of `labels`, created if it does not exist yet.
+    pub(crate) fn get_or_create(&self, labels: &S) -> M {
+        if let Some(metric) = self.metrics.read().get(labels) {
+            return metric.clone();
+        }
+        self.metrics
+            .write()
+            .entry(labels.clone())
+            .or_insert_with(&self.constructor)
+            .clone()
+    }
+}
+
+impl<S, M, C: Clone> Clone for Family<S, M, C> {
+    fn clone(&self) -> Self {
+        Self {
+            metrics: self.metrics.clone(),
+            constructor: self.constructor.clone(),
+        }
+    }
+}
+
+impl<S, M, C> fmt::Debug for Family<S, M, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Family").finish_non_exhaustive()
+    }
+}
+
+/// A counter with labels.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    /// Increase the counter by 1, returning the previous value.
+    pub(crate) fn inc(&self) -> u64 {
+        self.inc_by(1)
+    }
+
+    /// Increase the counter by `v`, returning the previous value.
+    pub(crate) fn inc_by(&self, v: u64) -> u64 {
+        self.0.fetch_add(v, Ordering::Relaxed)
+    }
+
+    /// The value of the counter, which is only read in tests.
+    #[allow(dead_code)]
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A gauge.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    /// Set the gauge to `v`, returning the previous value.
+    pub(crate) fn set(&self, v: i64) -> i64 {
+        self.0.swap(v, Ordering::Relaxed)
+    }
+
+    /// The value of the gauge.
+    pub(crate) fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A histogram, which is not recorded.
+#[derive(Debug, Clone)]
+pub(crate) struct Histogram;
+
+impl Histogram {
+    pub(crate) fn new(_buckets: impl Iterator<Item = f64>) -> Self {
+        Self
+    }
+
+    pub(crate) fn observe(&self, _v: f64) {}
+}
+
+/// The `length` buckets from `start` with a `factor` between them.
+pub(crate) fn exponential_buckets(
+    start: f64,
+    factor: f64,
+    length: u16,
+) -> impl Iterator<Item = f64> {
+    (0..length as i32).map(move |i| start * factor.powi(i))
+}
@@ -0,0 +1,337 @@
+//! Push of the metrics to an OpenTelemetry collector via OTLP/HTTP
+//!
+//! The metrics are encoded in the OpenMetrics text format, like for the Prometheus endpoint,
+//! and converted to the JSON encoding of OTLP. Counters are exported as cumulative monotonic
+//! sums, gauges as gauges and histograms as cumulative explicit bucket histograms.
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::config::OtlpMetricsConfig;
+
+/// Default interval in which the metrics are pushed.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+/// Timeout for requests to the collector.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SERVICE_NAME: &str = "iroh-dns-server";
+
+/// Push the metrics to the collector in `config`, forever.
+pub(crate) async fn push(config: OtlpMetricsConfig) -> Result<()> {
+    let url = format!("{}/v1/metrics", config.endpoint.trim_end_matches('/'));
+    let interval = config
+        .interval_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let service_name = config
+        .service_name
+        .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    let start = unix_nanos(SystemTime::now());
+    info!("Pushing metrics to {url} every {interval:?}");
+    loop {
+        tokio::time::sleep(interval).await;
+        let res = async {
+            let text = super::server::encode()?;
+            let body = to_otlp(&text, &service_name, start, unix_nanos(SystemTime::now()));
+            client
+                .post(&url)
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+            anyhow::Ok(())
+        };
+        match res.await {
+            Ok(()) => debug!("pushed metrics"),
+            Err(err) => warn!("failed to push metrics to {url}: {err:#}"),
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// A metric family of the OpenMetrics text format.
+#[derive(Debug, Default)]
+pub(super) struct MetricFamily {
+    pub(super) name: String,
+    pub(super) help: String,
+    pub(super) kind: String,
+    pub(super) samples: Vec<Sample>,
+}
+
+#[derive(Debug)]
+pub(super) struct Sample {
+    /// The sample name, e.g. `<family>_total` or `<family>_bucket`
+    pub(super) name: String,
+    pub(super) labels: Vec<(String, String)>,
+    pub(super) value: f64,
+}
+
+/// Parse the OpenMetrics text format.
+pub(super) fn parse(text: &str) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (Some(keyword), Some(name)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let rest = parts.next().unwrap_or_default();
+            if families.last().is_none_or(|f| f.name != name) {
+                families.push(MetricFamily {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+            }
+            let family = families.last_mut().expect("just pushed");
+            match keyword {
+                "HELP" => family.help = rest.trim_end_matches('.').to_string(),
+                "TYPE" => family.kind = rest.to_string(),
+                _ => {}
+            }
+            continue;
+        }
+        let (Some(family), Some(sample)) = (families.last_mut(), parse_sample(line)) else {
+            continue;
+        };
+        family.samples.push(sample);
+    }
+    families
+}
+
+/// Parse a sample line, e.g. `name{label="value"} 1`.
+fn parse_sample(line: &str) -> Option<Sample> {
+    let (name, rest) = match line.find('{') {
+        Some(start) => line.split_at(start),
+        None => line.split_once(' ')?,
+    };
+    let mut labels = Vec::new();
+    let mut rest = rest;
+    if let Some(mut inner) = rest.strip_prefix('{') {
+        loop {
+            inner = inner.trim_start_matches(',');
+            if let Some(after) = inner.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            let (key, after) = inner.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next()? {
+                    (_, '\\') => match chars.next()?.1 {
+                        'n' => value.push('\n'),
+                        c => value.push(c),
+                    },
+                    (i, '"') => break i,
+                    (_, c) => value.push(c),
+                }
+            };
+            labels.push((key.to_string(), value));
+            inner = &after[end + 1..];
+        }
+    }
+    let value = rest.split_whitespace().next()?;
+    let value = match value {
+        "+Inf" => f64::INFINITY,
+        "-Inf" => f64::NEG_INFINITY,
+        value => value.parse().ok()?,
+    };
+    Some(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+    })
+}
+
+fn attributes(labels: &[(String, String)]) -> Value {
+    labels
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+/// Convert the OpenMetrics text to an OTLP `ExportMetricsServiceRequest` in JSON.
+fn to_otlp(text: &str, service_name: &str, start: u128, now: u128) -> Value {
+    // 64 bit integers are strings in the JSON encoding of OTLP
+    let (start, now) = (start.to_string(), now.to_string());
+    let point = |labels: &[(String, String)], value: f64| {
+        json!({
+            "attributes": attributes(labels),
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "asDouble": value,
+        })
+    };
+    let mut metrics = Vec::new();
+    for family in parse(text) {
+        let data = match family.kind.as_str() {
+            "counter" => {
+                let points: Vec<_> = family
+                    .samples
+                    .iter()
+                    .filter(|s| s.name.ends_with("_total"))
+                    .map(|s| point(&s.labels, s.value))
+                    .collect();
+                json!({ "sum": {
+                    "dataPoints": points,
+                    "aggregationTemporality": 2,
+                    "isMonotonic": true,
+                }})
+            }
+            "gauge" => {
+                let points: Vec<_> = family
+                    .samples
+                    .iter()
+                    .map(|s| point(&s.labels, s.value))
+                    .collect();
+                json!({ "gauge": { "dataPoints": points } })
+            }
+            "histogram" => {
+                json!({ "histogram": {
+                    "dataPoints": histogram_points(&family, &start, &now),
+                    "aggregationTemporality": 2,
+                }})
+            }
+            kind => {
+                debug!("not exporting metric {} of type {kind}", family.name);
+                continue;
+            }
+        };
+        let mut metric = json!({ "name": family.name, "description": family.help });
+        metric
+            .as_object_mut()
+            .expect("object")
+            .extend(data.as_object().expect("object").clone());
+        metrics.push(metric);
+    }
+    json!({ "resourceMetrics": [{
+        "resource": { "attributes": [
+            { "key": "service.name", "value": { "stringValue": service_name } },
+        ]},
+        "scopeMetrics": [{
+            "scope": { "name": DEFAULT_SERVICE_NAME },
+            "metrics": metrics,
+        }],
+    }]})
+}
+
+/// The data points of a histogram family, one for each label set.
+///
+/// OpenMetrics buckets are cumulative, OTLP buckets count the samples in each bucket.
+fn histogram_points(family: &MetricFamily, start: &str, now: &str) -> Vec<Value> {
+    #[derive(Default)]
+    struct Point {
+        sum: f64,
+        count: f64,
+        buckets: Vec<(f64, f64)>,
+    }
+    let mut points: BTreeMap<Vec<(String, String)>, Point> = BTreeMap::new();
+    for sample in &family.samples {
+        let Some(suffix) = sample.name.strip_prefix(&family.name) else {
+            continue;
+        };
+        let mut labels = sample.labels.clone();
+        let le = labels
+            .iter()
+            .position(|(key, _)| key == "le")
+            .map(|i| labels.remove(i).1);
+        let point = points.entry(labels).or_default();
+        match (suffix, le) {
+            ("_sum", _) => point.sum = sample.value,
+            ("_count", _) => point.count = sample.value,
+            ("_bucket", Some(le)) => {
+                let bound = if le == "+Inf" {
+                    f64::INFINITY
+                } else {
+                    le.parse().unwrap_or(f64::INFINITY)
+                };
+                point.buckets.push((bound, sample.value));
+            }
+            _ => {}
+        }
+    }
+    points
+        .into_iter()
+        .map(|(labels, mut point)| {
+            point.buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let bounds: Vec<f64> = point
+                .buckets
+                .iter()
+                .map(|(bound, _)| *bound)
+                .filter(|bound| bound.is_finite())
+                .collect();
+            let mut previous = 0.0;
+            let mut counts: Vec<String> = point
+                .buckets
+                .iter()
+                .map(|(_, cumulative)| {
+                    let count = cumulative - previous;
+                    previous = *cumulative;
+                    (count as u64).to_string()
+                })
+                .collect();
+            // the last bucket is the overflow bucket, which OpenMetrics always includes as `+Inf`
+            counts.resize(bounds.len() + 1, "0".to_string());
+            json!({
+                "attributes": attributes(&labels),
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "count": (point.count as u64).to_string(),
+                "sum": point.sum,
+                "bucketCounts": counts,
+                "explicitBounds": bounds,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn openmetrics_to_otlp() {
+        let text = r#"# HELP dns_server_dns_requests DNS requests (total).
+# TYPE dns_server_dns_requests counter
+dns_server_dns_requests_total 3
+# HELP dns_server_cert_not_after_timestamp Expiry of the certificate.
+# TYPE dns_server_cert_not_after_timestamp gauge
+dns_server_cert_not_after_timestamp{domain="dns.example.org"} 1700000000
+# HELP dns_server_dns_lookup_duration_seconds Duration of DNS lookups.
+# TYPE dns_server_dns_lookup_duration_seconds histogram
+dns_server_dns_lookup_duration_seconds_sum{source="store"} 0.5
+dns_server_dns_lookup_duration_seconds_count{source="store"} 3
+dns_server_dns_lookup_duration_seconds_bucket{le="0.1",source="store"} 1
+dns_server_dns_lookup_duration_seconds_bucket{le="1.0",source="store"} 3
+dns_server_dns_lookup_duration_seconds_bucket{le="+Inf",source="store"} 3
+# EOF
+"#;
+        let otlp = to_otlp(text, "test", 1, 2);
+        let metrics = &otlp["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "dns_server_dns_requests");
+        assert_eq!(metrics[0]["description"], "DNS requests (total)");
+        assert_eq!(metrics[0]["sum"]["dataPoints"][0]["asDouble"], 3.0);
+        assert_eq!(
+            metrics[1]["gauge"]["dataPoints"][0]["attributes"][0]["value"]["stringValue"],
+            "dns.example.org"
+        );
+        let point = &metrics[2]["histogram"]["dataPoints"][0];
+        assert_eq!(point["count"], "3");
+        assert_eq!(point["explicitBounds"], json!([0.1, 1.0]));
+        assert_eq!(point["bucketCounts"], json!(["1", "2", "0"]));
+        assert_eq!(point["attributes"][0]["key"], "source");
+    }
+}
@@ -0,0 +1,266 @@
+//! Push of the metrics to Prometheus remote-write or a Pushgateway
+//!
+//! The metrics are encoded in the OpenMetrics text format, like for the Prometheus endpoint.
+//! For remote-write, the samples are converted to a snappy compressed protobuf `WriteRequest`
+//! with the time of the push. For a Pushgateway, they are converted to the Prometheus text
+//! format, and replace the metrics of the job and instance with a `PUT`.
+
+use std::{
+    fmt::Write,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::{
+    otlp::{parse, MetricFamily},
+    server::Authorization,
+};
+use crate::config::{PushMetricsConfig, PushProtocol};
+
+/// Default interval in which the metrics are pushed.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+/// Timeout for requests to the receiver.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_JOB: &str = "iroh-dns-server";
+/// The content type of the Prometheus text format.
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+
+/// Push the metrics to the receiver in `config`, forever.
+pub(crate) async fn push(config: PushMetricsConfig) -> Result<()> {
+    let job = config.job.as_deref().unwrap_or(DEFAULT_JOB);
+    let url = match config.protocol {
+        PushProtocol::RemoteWrite => config.url.parse()?,
+        PushProtocol::Pushgateway => pushgateway_url(&config.url, job, config.instance.as_deref())?,
+    };
+    let interval = config
+        .interval_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    let auth = match &config.auth {
+        Some(auth) => Some(Authorization::new(auth).await?),
+        None => None,
+    };
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    let mut labels = vec![("job".to_string(), job.to_string())];
+    if let Some(instance) = &config.instance {
+        labels.push(("instance".to_string(), instance.clone()));
+    }
+    info!("Pushing metrics to {url} every {interval:?}");
+    loop {
+        tokio::time::sleep(interval).await;
+        let res = async {
+            let families = parse(&super::server::encode()?);
+            let request = match config.protocol {
+                PushProtocol::RemoteWrite => {
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis() as i64;
+                    let body = write_request(&families, &labels, now);
+                    let body = snap::raw::Encoder::new()
+                        .compress_vec(&body)
+                        .context("failed to compress the write request")?;
+                    client
+                        .post(url.clone())
+                        .header(CONTENT_TYPE, "application/x-protobuf")
+                        .header(CONTENT_ENCODING, "snappy")
+                        .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                        .body(body)
+                }
+                PushProtocol::Pushgateway => client
+                    .put(url.clone())
+                    .header(CONTENT_TYPE, TEXT_FORMAT)
+                    .body(text_format(&families)),
+            };
+            let request = match &auth {
+                Some(auth) => request.header(AUTHORIZATION, auth.expected()),
+                None => request,
+            };
+            request.send().await?.error_for_status()?;
+            anyhow::Ok(())
+        };
+        match res.await {
+            Ok(()) => debug!("pushed metrics"),
+            Err(err) => warn!("failed to push metrics to {url}: {err:#}"),
+        }
+    }
+}
+
+/// The URL of the group of `job` and `instance` on the Pushgateway at `base`.
+fn pushgateway_url(base: &str, job: &str, instance: Option<&str>) -> Result<Url> {
+    let mut url: Url = base.parse()?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid Pushgateway URL {base}"))?;
+        segments.pop_if_empty().extend(["metrics", "job", job]);
+        if let Some(instance) = instance {
+            segments.extend(["instance", instance]);
+        }
+    }
+    Ok(url)
+}
+
+/// Whether a sample of `family` is pushed.
+///
+/// The `_created` samples of counters are not, like Prometheus doesn't ingest them by default.
+fn is_pushed(family: &MetricFamily, sample_name: &str) -> bool {
+    family.kind != "counter" || sample_name.ends_with("_total")
+}
+
+/// Convert the metric families to the Prometheus text format.
+///
+/// Unlike in OpenMetrics, the families of counters are named with the `_total` suffix.
+fn text_format(families: &[MetricFamily]) -> String {
+    let mut text = String::new();
+    for family in families {
+        let (name, kind) = match family.kind.as_str() {
+            "counter" => (format!("{}_total", family.name), "counter"),
+            kind @ ("gauge" | "histogram" | "summary") => (family.name.clone(), kind),
+            _ => (family.name.clone(), "untyped"),
+        };
+        let help = family.help.replace('\\', r"\\").replace('\n', r"\n");
+        writeln!(text, "# HELP {name} {help}").ok();
+        writeln!(text, "# TYPE {name} {kind}").ok();
+        for sample in &family.samples {
+            if !is_pushed(family, &sample.name) {
+                continue;
+            }
+            text.push_str(&sample.name);
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(key, value)| {
+                        let value = value
+                            .replace('\\', r"\\")
+                            .replace('"', "\\\"")
+                            .replace('\n', r"\n");
+                        format!("{key}=\"{value}\"")
+                    })
+                    .collect();
+                write!(text, "{{{}}}", labels.join(",")).ok();
+            }
+            writeln!(text, " {}", format_value(sample.value)).ok();
+        }
+    }
+    text
+}
+
+fn format_value(value: f64) -> String {
+    match value {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Encode the samples of the metric families as a remote-write `WriteRequest` protobuf, with
+/// the extra `labels` and the `timestamp` in milliseconds since the unix epoch.
+fn write_request(
+    families: &[MetricFamily],
+    labels: &[(String, String)],
+    timestamp: i64,
+) -> Vec<u8> {
+    let mut request = Vec::new();
+    for family in families {
+        for sample in &family.samples {
+            if !is_pushed(family, &sample.name) {
+                continue;
+            }
+            let mut series_labels: Vec<(&str, &str)> = vec![("__name__", &sample.name)];
+            series_labels.extend(labels.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            series_labels.extend(sample.labels.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+            // remote-write requires the labels sorted by name
+            series_labels.sort_by_key(|(key, _)| *key);
+            series_labels.dedup_by_key(|(key, _)| *key);
+
+            let mut series = Vec::new();
+            for (key, value) in series_labels {
+                let mut label = Vec::new();
+                encode_bytes(&mut label, 1, key.as_bytes());
+                encode_bytes(&mut label, 2, value.as_bytes());
+                encode_bytes(&mut series, 1, &label);
+            }
+            let mut point = Vec::new();
+            // field 1, a double
+            point.push(1 << 3 | 1);
+            point.extend_from_slice(&sample.value.to_le_bytes());
+            // field 2, an int64
+            point.push(2 << 3);
+            encode_varint(&mut point, timestamp as u64);
+            encode_bytes(&mut series, 2, &point);
+            encode_bytes(&mut request, 1, &series);
+        }
+    }
+    request
+}
+
+/// Encode a length-delimited protobuf field.
+fn encode_bytes(buf: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    buf.push(field << 3 | 2);
+    encode_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = r#"# HELP dns_server_dns_requests DNS requests (total).
+# TYPE dns_server_dns_requests counter
+dns_server_dns_requests_total{protocol="udp"} 3
+dns_server_dns_requests_created{protocol="udp"} 1700000000
+# HELP dns_server_cert_not_after_timestamp Expiry of the certificate.
+# TYPE dns_server_cert_not_after_timestamp gauge
+dns_server_cert_not_after_timestamp 1700000000
+# EOF
+"#;
+
+    #[test]
+    fn openmetrics_to_push_formats() {
+        let families = parse(TEXT);
+        assert_eq!(
+            text_format(&families),
+            r#"# HELP dns_server_dns_requests_total DNS requests (total)
+# TYPE dns_server_dns_requests_total counter
+dns_server_dns_requests_total{protocol="udp"} 3
+# HELP dns_server_cert_not_after_timestamp Expiry of the certificate
+# TYPE dns_server_cert_not_after_timestamp gauge
+dns_server_cert_not_after_timestamp 1700000000
+"#
+        );
+
+        let labels = [("job".to_string(), "dns".to_string())];
+        let request = write_request(&families, &labels, 1);
+        let mut label = Vec::new();
+        encode_bytes(&mut label, 1, b"__name__");
+        encode_bytes(&mut label, 2, b"dns_server_dns_requests_total");
+        let mut series = Vec::new();
+        encode_bytes(&mut series, 1, &label);
+        // the first series starts with its name label
+        assert_eq!(request[0], 1 << 3 | 2);
+        assert!(request.windows(series.len()).any(|w| w == series));
+        assert!(!request.windows(b"_created".len()).any(|w| w == b"_created"));
+
+        let url = pushgateway_url("http://localhost:9091/", "dns", Some("a b")).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:9091/metrics/job/dns/instance/a%20b"
+        );
+    }
+}
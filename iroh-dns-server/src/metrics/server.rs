@@ -0,0 +1,167 @@
+//! HTTP server for the Prometheus metrics endpoint
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::{Request, State},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use base64::Engine;
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    HeaderValue, StatusCode,
+};
+use iroh_metrics::core::Core;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::{config::MetricsAuth, secrets::RefreshingSecret};
+
+/// The bound listeners of the metrics server
+#[derive(Debug)]
+pub(crate) struct Listeners {
+    tcp: Option<TcpListener>,
+    #[cfg(unix)]
+    unix: Option<tokio::net::UnixListener>,
+}
+
+impl Listeners {
+    /// Get the bound address of the TCP listener, if there is one.
+    pub(crate) fn tcp_addr(&self) -> Result<Option<SocketAddr>> {
+        Ok(self.tcp.as_ref().map(|tcp| tcp.local_addr()).transpose()?)
+    }
+}
+
+/// Bind the listeners of the metrics server on `addr` and/or `unix_socket`.
+pub(crate) fn bind(addr: Option<SocketAddr>, unix_socket: Option<PathBuf>) -> Result<Listeners> {
+    let tcp = match addr {
+        Some(addr) => Some(
+            crate::util::bind_tcp(addr)
+                .and_then(TcpListener::from_std)
+                .with_context(|| format!("failed to bind metrics on {addr}"))?,
+        ),
+        None => None,
+    };
+    #[cfg(unix)]
+    let unix = unix_socket
+        .map(|path| crate::http::unix::bind(&path))
+        .transpose()?;
+    #[cfg(not(unix))]
+    if let Some(path) = unix_socket {
+        anyhow::bail!("unix sockets are not supported on this platform: {path:?}");
+    }
+    Ok(Listeners {
+        tcp,
+        #[cfg(unix)]
+        unix,
+    })
+}
+
+/// Serve the metrics on the `listeners`, until the returned future is dropped.
+pub(crate) async fn serve(listeners: Listeners, auth: Option<MetricsAuth>) -> Result<()> {
+    let mut app = Router::new().fallback(handler);
+    if let Some(auth) = auth {
+        app = app.layer(middleware::from_fn_with_state(
+            Authorization::new(&auth).await?,
+            auth_middleware,
+        ));
+    }
+
+    let tcp = async {
+        let Some(listener) = listeners.tcp else {
+            return Ok(());
+        };
+        info!("Starting metrics server on {}", listener.local_addr()?);
+        axum::serve(listener, app.clone()).await?;
+        anyhow::Ok(())
+    };
+    let unix = async {
+        #[cfg(unix)]
+        if let Some(listener) = listeners.unix {
+            crate::http::unix::serve(listener, app.clone(), Default::default()).await?;
+        }
+        anyhow::Ok(())
+    };
+    tokio::try_join!(tcp, unix)?;
+    Ok(())
+}
+
+/// The expected credentials, with secrets kept up to date with their sources.
+///
+/// The credentials of the pushes are sent with the same header.
+#[derive(Debug, Clone)]
+pub(super) enum Authorization {
+    Basic {
+        username: String,
+        password: RefreshingSecret,
+    },
+    Bearer {
+        token: RefreshingSecret,
+    },
+}
+
+impl Authorization {
+    pub(super) async fn new(auth: &MetricsAuth) -> Result<Self> {
+        let auth = match auth {
+            MetricsAuth::Basic { username, password } => Self::Basic {
+                username: username.clone(),
+                password: RefreshingSecret::new(password).await?,
+            },
+            MetricsAuth::Bearer { token } => Self::Bearer {
+                token: RefreshingSecret::new(token).await?,
+            },
+        };
+        Ok(auth)
+    }
+
+    /// The expected value of the `Authorization` header.
+    pub(super) fn expected(&self) -> String {
+        match self {
+            Self::Basic { username, password } => {
+                let password = password.get();
+                let credentials = base64::engine::general_purpose::STANDARD
+                    .encode(format!("{username}:{password}"));
+                format!("Basic {credentials}")
+            }
+            Self::Bearer { token } => format!("Bearer {}", token.get()),
+        }
+    }
+}
+
+async fn auth_middleware(State(auth): State<Authorization>, req: Request, next: Next) -> Response {
+    let expected = auth.expected();
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        // compare hashes to not leak the expected value through timing
+        .is_some_and(|v| blake3::hash(v.as_bytes()) == blake3::hash(expected.as_bytes()));
+    if authorized {
+        return next.run(req).await;
+    }
+    let mut res = StatusCode::UNAUTHORIZED.into_response();
+    if expected.starts_with("Basic ") {
+        res.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"metrics\""),
+        );
+    }
+    res
+}
+
+/// Respond with the OpenMetrics encoding of the metrics.
+async fn handler() -> Response {
+    match encode() {
+        Ok(body) => ([(CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+pub(super) fn encode() -> Result<String> {
+    let core = Core::get().ok_or_else(|| anyhow!("metrics disabled"))?;
+    let mut buf = String::new();
+    prometheus_client::encoding::text::encode(&mut buf, core.registry())?;
+    Ok(buf)
+}
@@ -0,0 +1,549 @@
+//! Mirror of the published records to an external DNS provider
+//!
+//! With a [`MirrorConfig`], the records of the packets in the store are copied to a zone of
+//! Route53, Cloudflare or PowerDNS, for organizations that must serve the discovery records from
+//! their managed DNS. The records of the packet of a pubkey are mirrored as
+//! `<name>.<z32-pubkey>.<origin>`, like the server answers them for an origin, optionally only for
+//! the `pubkeys` in the config.
+//!
+//! On start, all packets of the store are mirrored, and afterwards each packet that updates the
+//! store. The record sets of a packet replace the ones of its previous version, and are deleted
+//! when the retention removes the packet. The mirrored record sets are kept in memory, so record
+//! sets of packets that were removed while the server was not running stay in the provider. The
+//! changes are counted in the `mirror_rrsets` metric.
+//!
+//! Route53 requests are signed with the credentials in `AWS_ACCESS_KEY_ID`,
+//! `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use hickory_proto::rr::{Name, RData, RecordType};
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+use crate::{
+    events::ServerEvent,
+    metrics::{MirrorMetrics, MirrorOutcome},
+    secrets::{self, AwsCredentials, RefreshingSecret, SecretValue},
+    store::ZoneStore,
+    util::PublicKeyBytes,
+    util::{record_set_append_origin, signed_packet_to_hickory_records_without_origin},
+};
+
+/// Timeout for the requests to the provider.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of packets that are read from the store at once on start.
+const PAGE_SIZE: usize = 1000;
+/// The lowest TTL Cloudflare accepts, other than 1 for automatic.
+const CLOUDFLARE_MIN_TTL: u32 = 60;
+
+/// Config for mirroring the published records to a DNS provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorConfig {
+    /// The DNS provider
+    pub provider: MirrorProvider,
+    /// The origin of the mirrored names, within the zone of the provider, e.g.
+    /// `nodes.example.org`
+    pub origin: String,
+    /// Only mirror the packets of these z-base-32 encoded pubkeys (all packets if empty)
+    #[serde(default)]
+    pub pubkeys: Vec<String>,
+}
+
+/// A DNS provider that the records are mirrored to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MirrorProvider {
+    /// A hosted zone of Amazon Route53
+    Route53 {
+        /// The id of the hosted zone, e.g. `Z0123456789ABCDEFGHIJ`
+        hosted_zone_id: String,
+    },
+    /// A zone of Cloudflare
+    Cloudflare {
+        /// The id of the zone
+        zone_id: String,
+        /// An API token with the `Zone.DNS` edit permission
+        token: SecretValue,
+    },
+    /// A zone of a PowerDNS authoritative server, via its HTTP API
+    Powerdns {
+        /// The URL of the API, e.g. `http://127.0.0.1:8081`
+        url: String,
+        /// The id of the server (defaults to `localhost`)
+        #[serde(default)]
+        server_id: Option<String>,
+        /// The zone, e.g. `example.org.`
+        zone: String,
+        /// The API key
+        api_key: SecretValue,
+    },
+}
+
+/// A record set of a packet, with its absolute name
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RrSet {
+    name: Name,
+    record_type: RecordType,
+    ttl: u32,
+    rdata: Vec<RData>,
+}
+
+impl RrSet {
+    /// The values of the record set in zone file presentation format.
+    fn values(&self) -> impl Iterator<Item = String> + '_ {
+        self.rdata.iter().map(|rdata| match rdata {
+            RData::TXT(txt) => txt
+                .txt_data()
+                .iter()
+                .map(|data| {
+                    let text = String::from_utf8_lossy(data);
+                    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+            rdata => rdata.to_string(),
+        })
+    }
+}
+
+/// An opened [`MirrorProvider`]
+#[derive(Debug)]
+enum Provider {
+    Route53 {
+        client: reqwest::Client,
+        hosted_zone_id: String,
+    },
+    Cloudflare {
+        client: reqwest::Client,
+        zone_id: String,
+        token: RefreshingSecret,
+    },
+    Powerdns {
+        client: reqwest::Client,
+        /// The URL of the zone
+        url: String,
+        api_key: RefreshingSecret,
+    },
+}
+
+impl Provider {
+    async fn open(config: &MirrorProvider) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let provider = match config {
+            MirrorProvider::Route53 { hosted_zone_id } => {
+                // fail on start and not on the first change if the credentials are missing
+                AwsCredentials::from_env()?;
+                Self::Route53 {
+                    client,
+                    hosted_zone_id: hosted_zone_id
+                        .trim_start_matches("/hostedzone/")
+                        .to_string(),
+                }
+            }
+            MirrorProvider::Cloudflare { zone_id, token } => Self::Cloudflare {
+                client,
+                zone_id: zone_id.clone(),
+                token: RefreshingSecret::new(token).await?,
+            },
+            MirrorProvider::Powerdns {
+                url,
+                server_id,
+                zone,
+                api_key,
+            } => Self::Powerdns {
+                client,
+                url: format!(
+                    "{}/api/v1/servers/{}/zones/{}",
+                    url.trim_end_matches('/'),
+                    server_id.as_deref().unwrap_or("localhost"),
+                    zone
+                ),
+                api_key: RefreshingSecret::new(api_key).await?,
+            },
+        };
+        Ok(provider)
+    }
+
+    /// Create or replace the `upserts`, and delete the `deletes`.
+    async fn apply(&self, upserts: &[RrSet], deletes: &[RrSet]) -> Result<()> {
+        match self {
+            Self::Route53 {
+                client,
+                hosted_zone_id,
+            } => {
+                let body = route53_change_batch(upserts, deletes);
+                let host = "route53.amazonaws.com";
+                let path = format!("/2013-04-01/hostedzone/{hosted_zone_id}/rrset");
+                let credentials = AwsCredentials::from_env()?;
+                let amz_date = secrets::amz_date(time::OffsetDateTime::now_utc());
+                let mut headers = vec![
+                    ("content-type", "application/xml".to_string()),
+                    ("host", host.to_string()),
+                    ("x-amz-date", amz_date.clone()),
+                ];
+                if let Some(token) = &credentials.session_token {
+                    headers.push(("x-amz-security-token", token.clone()));
+                }
+                // Route53 is a global service, signed for us-east-1
+                let authorization = credentials.sign(
+                    "route53",
+                    "us-east-1",
+                    "POST",
+                    &path,
+                    &amz_date,
+                    &headers,
+                    &body,
+                );
+                let mut req = client.post(format!("https://{host}{path}"));
+                for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+                    req = req.header(name, value);
+                }
+                let res = req
+                    .header(http::header::AUTHORIZATION, authorization)
+                    .body(body)
+                    .send()
+                    .await?;
+                if !res.status().is_success() {
+                    let status = res.status();
+                    bail!("{status}: {}", res.text().await.unwrap_or_default());
+                }
+            }
+            Self::Cloudflare {
+                client,
+                zone_id,
+                token,
+            } => {
+                let url =
+                    format!("https://api.cloudflare.com/client/v4/zones/{zone_id}/dns_records");
+                for rrset in upserts.iter().chain(deletes) {
+                    let name = rrset.name.to_string();
+                    let name = name.trim_end_matches('.');
+                    let existing: Value = client
+                        .get(&url)
+                        .bearer_auth(token.get())
+                        .query(&[("name", name), ("type", &rrset.record_type.to_string())])
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await?;
+                    let ids = existing["result"]
+                        .as_array()
+                        .context("invalid response of Cloudflare")?
+                        .iter()
+                        .filter_map(|record| record["id"].as_str());
+                    for id in ids {
+                        client
+                            .delete(format!("{url}/{id}"))
+                            .bearer_auth(token.get())
+                            .send()
+                            .await?
+                            .error_for_status()?;
+                    }
+                }
+                for rrset in upserts {
+                    let name = rrset.name.to_string();
+                    for rdata in &rrset.rdata {
+                        let record = json!({
+                            "type": rrset.record_type.to_string(),
+                            "name": name.trim_end_matches('.'),
+                            // the text of TXT records without the quotes
+                            "content": rdata.to_string(),
+                            "ttl": rrset.ttl.max(CLOUDFLARE_MIN_TTL),
+                        });
+                        client
+                            .post(&url)
+                            .bearer_auth(token.get())
+                            .json(&record)
+                            .send()
+                            .await?
+                            .error_for_status()?;
+                    }
+                }
+            }
+            Self::Powerdns {
+                client,
+                url,
+                api_key,
+            } => {
+                let rrsets: Vec<Value> = upserts
+                    .iter()
+                    .map(|rrset| {
+                        let records: Vec<Value> = rrset
+                            .values()
+                            .map(|content| json!({ "content": content, "disabled": false }))
+                            .collect();
+                        json!({
+                            "name": rrset.name.to_string(),
+                            "type": rrset.record_type.to_string(),
+                            "ttl": rrset.ttl,
+                            "changetype": "REPLACE",
+                            "records": records,
+                        })
+                    })
+                    .chain(deletes.iter().map(|rrset| {
+                        json!({
+                            "name": rrset.name.to_string(),
+                            "type": rrset.record_type.to_string(),
+                            "changetype": "DELETE",
+                        })
+                    }))
+                    .collect();
+                client
+                    .patch(url.as_str())
+                    .header("X-API-Key", api_key.get())
+                    .json(&json!({ "rrsets": rrsets }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The body of a Route53 `ChangeResourceRecordSets` request.
+fn route53_change_batch(upserts: &[RrSet], deletes: &[RrSet]) -> String {
+    let escape = |value: &str| {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let change = |action: &str, rrset: &RrSet| {
+        let records: String = rrset
+            .values()
+            .map(|value| {
+                format!(
+                    "<ResourceRecord><Value>{}</Value></ResourceRecord>",
+                    escape(&value)
+                )
+            })
+            .collect();
+        format!(
+            "<Change><Action>{action}</Action><ResourceRecordSet><Name>{}</Name><Type>{}</Type>\
+             <TTL>{}</TTL><ResourceRecords>{records}</ResourceRecords></ResourceRecordSet></Change>",
+            escape(&rrset.name.to_string()),
+            rrset.record_type,
+            rrset.ttl
+        )
+    };
+    let changes: String = upserts
+        .iter()
+        .map(|rrset| change("UPSERT", rrset))
+        .chain(deletes.iter().map(|rrset| change("DELETE", rrset)))
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <ChangeResourceRecordSetsRequest xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\">\
+         <ChangeBatch><Changes>{changes}</Changes></ChangeBatch></ChangeResourceRecordSetsRequest>"
+    )
+}
+
+/// The record sets of `packet`, for `origin`.
+fn rrsets(packet: &SignedPacket, origin: &Name) -> Result<Vec<RrSet>> {
+    let (label, record_sets) = signed_packet_to_hickory_records_without_origin(packet, |_| true)?;
+    let origin = Name::from_labels([label])?.append_name(origin)?;
+    record_sets
+        .values()
+        .map(|record_set| {
+            let record_set = record_set_append_origin(record_set, &origin, 0)?;
+            let records: Vec<_> = record_set.records_without_rrsigs().collect();
+            Ok(RrSet {
+                name: record_set.name().clone(),
+                record_type: record_set.record_type(),
+                ttl: records.iter().map(|record| record.ttl()).min().unwrap_or(0),
+                rdata: records.iter().map(|record| record.data().clone()).collect(),
+            })
+        })
+        .collect()
+}
+
+/// The mirror of the records, see the [module docs](self).
+#[derive(Debug)]
+pub(crate) struct Mirror {
+    provider: Provider,
+    origin: Name,
+    /// The mirrored pubkeys, or `None` for all
+    pubkeys: Option<HashSet<PublicKeyBytes>>,
+    /// The record sets in the provider by pubkey
+    mirrored: HashMap<PublicKeyBytes, Vec<RrSet>>,
+}
+
+impl Mirror {
+    /// Open the provider of `config`.
+    pub(crate) async fn open(config: &MirrorConfig) -> Result<Self> {
+        let origin = Name::from_utf8(&config.origin)
+            .context("invalid mirror.origin")?
+            .append_domain(&Name::root())?;
+        let pubkeys = config
+            .pubkeys
+            .iter()
+            .map(|key| PublicKeyBytes::from_z32(key).with_context(|| format!("invalid key {key}")))
+            .collect::<Result<HashSet<_>>>()?;
+        Ok(Self {
+            provider: Provider::open(&config.provider).await?,
+            origin,
+            pubkeys: (!pubkeys.is_empty()).then_some(pubkeys),
+            mirrored: HashMap::new(),
+        })
+    }
+
+    /// Mirror the packets of `store`, and then their changes until the task is aborted.
+    pub(crate) async fn run(mut self, store: ZoneStore) {
+        // subscribe before reading the store, so that no change is missed
+        let mut events = store.events().subscribe();
+        info!(origin = %self.origin, "mirroring the records to the DNS provider");
+        self.mirror_all(&store).await;
+        loop {
+            match events.recv().await {
+                Ok(ServerEvent::PacketPublished { pubkey, .. }) => {
+                    let Ok(pubkey) = PublicKeyBytes::from_z32(&pubkey) else {
+                        continue;
+                    };
+                    match store.get_signed_packet(&pubkey).await {
+                        Ok(packet) => self.mirror(pubkey, packet.as_ref()).await,
+                        Err(err) => warn!("failed to read the packet to mirror: {err:#}"),
+                    }
+                }
+                Ok(
+                    ServerEvent::PacketExpired { pubkey, .. }
+                    | ServerEvent::PacketRemoved { pubkey },
+                ) => {
+                    if let Ok(pubkey) = PublicKeyBytes::from_z32(&pubkey) {
+                        self.mirror(pubkey, None).await;
+                    }
+                }
+                Ok(_) => {}
+                // the unchanged record sets are skipped, so catching up is cheap
+                Err(RecvError::Lagged(missed)) => {
+                    debug!(missed, "mirror missed changes, mirroring all packets");
+                    self.mirror_all(&store).await;
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    /// Mirror all packets of the store.
+    async fn mirror_all(&mut self, store: &ZoneStore) {
+        let mut after = None;
+        loop {
+            let packets = match store.packets_after(after, PAGE_SIZE).await {
+                Ok(packets) => packets,
+                Err(err) => {
+                    warn!("failed to read the packets to mirror: {err:#}");
+                    return;
+                }
+            };
+            let Some(last) = packets.last() else {
+                return;
+            };
+            after = Some(PublicKeyBytes::from_signed_packet(last));
+            for packet in &packets {
+                self.mirror(PublicKeyBytes::from_signed_packet(packet), Some(packet))
+                    .await;
+            }
+        }
+    }
+
+    /// Mirror the current `packet` of `pubkey`, or delete its records if it has none.
+    async fn mirror(&mut self, pubkey: PublicKeyBytes, packet: Option<&SignedPacket>) {
+        if self
+            .pubkeys
+            .as_ref()
+            .is_some_and(|pubkeys| !pubkeys.contains(&pubkey))
+        {
+            return;
+        }
+        let current = match packet.map(|packet| rrsets(packet, &self.origin)) {
+            Some(Ok(rrsets)) => rrsets,
+            Some(Err(err)) => {
+                debug!("skipped invalid packet in the mirror: {err:#}");
+                return;
+            }
+            None => Vec::new(),
+        };
+        let previous = self
+            .mirrored
+            .get(&pubkey)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let upserts: Vec<RrSet> = current
+            .iter()
+            .filter(|rrset| !previous.contains(rrset))
+            .cloned()
+            .collect();
+        let deletes: Vec<RrSet> = previous
+            .iter()
+            .filter(|rrset| {
+                !current
+                    .iter()
+                    .any(|c| c.name == rrset.name && c.record_type == rrset.record_type)
+            })
+            .cloned()
+            .collect();
+        if upserts.is_empty() && deletes.is_empty() {
+            return;
+        }
+        match self.provider.apply(&upserts, &deletes).await {
+            Ok(()) => {
+                MirrorMetrics::count(MirrorOutcome::Upserted, upserts.len());
+                MirrorMetrics::count(MirrorOutcome::Deleted, deletes.len());
+                if current.is_empty() {
+                    self.mirrored.remove(&pubkey);
+                } else {
+                    self.mirrored.insert(pubkey, current);
+                }
+            }
+            Err(err) => {
+                MirrorMetrics::count(MirrorOutcome::Failed, upserts.len() + deletes.len());
+                warn!(pubkey = %pubkey.to_z32(), "failed to mirror the records: {err:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::{dns::node_info::NodeInfo, key::SecretKey, NodeId};
+
+    use super::*;
+
+    #[test]
+    fn route53_changes_of_a_packet() -> Result<()> {
+        let secret_key = SecretKey::generate();
+        let node_info = NodeInfo::new(
+            NodeId::from(secret_key.public()),
+            Some("https://relay.example.".parse()?),
+            Default::default(),
+        );
+        let packet = node_info.to_pkarr_signed_packet(&secret_key, 30)?;
+        let origin = Name::from_utf8("nodes.example.org.")?;
+        let rrsets = rrsets(&packet, &origin)?;
+        assert_eq!(rrsets.len(), 1);
+        let key = z32::encode(secret_key.public().as_bytes());
+        assert_eq!(
+            rrsets[0].name.to_string(),
+            format!("_iroh.{key}.nodes.example.org.")
+        );
+        assert_eq!(rrsets[0].ttl, 30);
+        let values: Vec<_> = rrsets[0].values().collect();
+        assert_eq!(values, ["\"relay=https://relay.example./\""]);
+
+        let body = route53_change_batch(&rrsets, &[]);
+        assert!(body.contains("<Action>UPSERT</Action>"));
+        assert!(body.contains("<Value>\"relay=https://relay.example./\"</Value>"));
+        Ok(())
+    }
+}
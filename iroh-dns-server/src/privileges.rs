@@ -0,0 +1,193 @@
+//! Switch to an unprivileged user after binding the listeners
+//!
+//! This way the server can be started as root to bind privileged ports like 53 and 443, but serves
+//! requests as the configured [`Config::user`](crate::config::Config::user).
+//!
+//! The files the server created as root before, e.g. the database in the data directory, are
+//! handed to the user, and the server fails to start if it could not write to them afterwards.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+/// Switch the process to `user` and `group`, given by name or id.
+///
+/// The group defaults to the primary group of `user`, and the supplementary groups are dropped.
+/// Does nothing if neither is set.
+///
+/// Before the switch, the paths in `owned` are handed to the user and group with everything in
+/// them, and `owned` and `writable` are checked to be writable by them.
+#[cfg(unix)]
+pub(crate) fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    owned: &[PathBuf],
+    writable: &[PathBuf],
+) -> Result<()> {
+    use anyhow::{bail, ensure, Context};
+    use nix::unistd::{self, Uid};
+    use tracing::info;
+
+    let user = user.map(find_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => find_group(group)?.gid,
+        (None, Some(user)) => user.gid,
+        (None, None) => return Ok(()),
+    };
+    let uid = user.as_ref().map(|user| user.uid);
+    if !unistd::geteuid().is_root() {
+        if uid.unwrap_or(unistd::geteuid()) == unistd::geteuid() && gid == unistd::getegid() {
+            return Ok(());
+        }
+        bail!("switching the user or group requires the server to be started as root");
+    }
+
+    for path in owned.iter().filter(|path| path.exists()) {
+        chown_all(path, uid, gid)
+            .with_context(|| format!("failed to change the owner of {}", path.display()))?;
+    }
+    let new_uid = uid.unwrap_or(unistd::geteuid());
+    for path in owned.iter().chain(writable).filter(|path| path.exists()) {
+        let metadata = std::fs::metadata(path)?;
+        ensure!(
+            is_writable(&metadata, new_uid, gid),
+            "{} is not writable by user {new_uid}, group {gid}",
+            path.display()
+        );
+    }
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    unistd::setgroups(&[gid]).context("failed to drop the supplementary groups")?;
+    unistd::setgid(gid).with_context(|| format!("failed to switch to group {gid}"))?;
+    if let Some(uid) = uid {
+        unistd::setuid(uid).with_context(|| format!("failed to switch to user {uid}"))?;
+        // a process that can become root again has not dropped its privileges
+        if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+            bail!("still able to switch back to root after switching to user {uid}");
+        }
+    }
+    info!(
+        "switched to user {}, group {gid}",
+        uid.unwrap_or(unistd::getuid())
+    );
+    Ok(())
+}
+
+/// Switching the user is not supported on this platform.
+#[cfg(not(unix))]
+pub(crate) fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    _owned: &[PathBuf],
+    _writable: &[PathBuf],
+) -> Result<()> {
+    if user.is_some() || group.is_some() {
+        anyhow::bail!("user and group are only supported on unix");
+    }
+    Ok(())
+}
+
+/// Change the owner of `path` and everything in it, without following symlinks.
+#[cfg(unix)]
+fn chown_all(
+    path: &std::path::Path,
+    uid: Option<nix::unistd::Uid>,
+    gid: nix::unistd::Gid,
+) -> std::io::Result<()> {
+    std::os::unix::fs::lchown(path, uid.map(|uid| uid.as_raw()), Some(gid.as_raw()))?;
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            chown_all(&entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a process of `uid` and `gid` may write to the file or directory with `metadata`.
+#[cfg(unix)]
+fn is_writable(metadata: &std::fs::Metadata, uid: nix::unistd::Uid, gid: nix::unistd::Gid) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // creating files in a directory also requires searching it
+    let (write, search) = match metadata.uid() == uid.as_raw() {
+        true => (0o200, 0o100),
+        false if metadata.gid() == gid.as_raw() => (0o020, 0o010),
+        false => (0o002, 0o001),
+    };
+    let required = match metadata.is_dir() {
+        true => write | search,
+        false => write,
+    };
+    uid.is_root() || metadata.mode() & required == required
+}
+
+/// Look up a user by name or id.
+#[cfg(unix)]
+pub(crate) fn find_user(user: &str) -> Result<nix::unistd::User> {
+    use nix::unistd::{Uid, User};
+
+    let found = match user.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+        Err(_) => User::from_name(user)?,
+    };
+    found.ok_or_else(|| anyhow::anyhow!("no such user: {user}"))
+}
+
+/// Look up a group by name or id.
+#[cfg(unix)]
+pub(crate) fn find_group(group: &str) -> Result<nix::unistd::Group> {
+    use nix::unistd::{Gid, Group};
+
+    let found = match group.parse() {
+        Ok(gid) => Group::from_gid(Gid::from_raw(gid))?,
+        Err(_) => Group::from_name(group)?,
+    };
+    found.ok_or_else(|| anyhow::anyhow!("no such group: {group}"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_by_name_or_id() {
+        assert_eq!(find_user("root").unwrap().uid.as_raw(), 0);
+        assert_eq!(find_user("0").unwrap().name, "root");
+        assert_eq!(find_group("0").unwrap().gid.as_raw(), 0);
+        assert!(find_user("no-such-user-for-iroh-dns").is_err());
+        assert!(find_group("no-such-group-for-iroh-dns").is_err());
+    }
+
+    #[test]
+    fn writable_by_user() -> Result<()> {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        use nix::unistd::{getegid, geteuid, Gid, Uid};
+
+        let dir = std::env::temp_dir().join(format!("privileges-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("db"))?;
+        let nobody = (Uid::from_raw(65534), Gid::from_raw(65534));
+        let metadata = || fs::metadata(&dir);
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755))?;
+        assert!(is_writable(&metadata()?, geteuid(), getegid()));
+        assert!(!is_writable(&metadata()?, nobody.0, nobody.1));
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o775))?;
+        assert!(is_writable(&metadata()?, nobody.0, getegid()));
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o764))?;
+        assert!(!is_writable(&metadata()?, nobody.0, getegid()));
+
+        // only root can hand the files to another user
+        if geteuid().is_root() {
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o755))?;
+            chown_all(&dir, Some(nobody.0), nobody.1)?;
+            assert!(is_writable(&metadata()?, nobody.0, nobody.1));
+            assert_eq!(
+                std::os::unix::fs::MetadataExt::uid(&fs::metadata(dir.join("db"))?),
+                nobody.0.as_raw()
+            );
+        }
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! End-to-end self-check of the server
+//!
+//! With a [`ProbeConfig`], the server periodically publishes a canary packet to its own HTTP(S)
+//! listener, and resolves the canary record through its own UDP and DoH listeners. The outcome and
+//! latency of each check are exported in the `probe_*` metrics, so that breakage is detected also
+//! when each component looks healthy on its own.
+//!
+//! The canary packet is signed with a key that is stored in the data directory, and has a TXT
+//! record `_canary` with the time of the publish. A resolve only succeeds if it returns the value
+//! of the latest publish.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use hickory_proto::{
+    op::{Message, Query},
+    rr::{Name, RData, RecordType},
+};
+use pkarr::{dns, Keypair, SignedPacket};
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::metrics::{ProbeCheck, ProbeMetrics};
+
+/// Default interval of the checks.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+/// Timeout of each check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// The name of the canary record in the canary packet.
+const CANARY_LABEL: &str = "_canary";
+
+/// Config for the self-check probe
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProbeConfig {
+    /// The interval of the checks in seconds (defaults to 60)
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+}
+
+/// The listeners of the server to check.
+#[derive(Debug, Clone)]
+pub(crate) struct ProbeTargets {
+    /// The base URL of the HTTP or HTTPS listener, for publishes and DoH
+    pub(crate) http_url: Option<String>,
+    /// The address of the DNS server
+    pub(crate) dns_addr: SocketAddr,
+    /// The origin the canary name is resolved under
+    pub(crate) origin: Name,
+}
+
+impl ProbeTargets {
+    /// The targets for the bound addresses, with wildcard addresses replaced by the loopback
+    /// address.
+    pub(crate) fn new(
+        http_addr: Option<SocketAddr>,
+        https_addr: Option<SocketAddr>,
+        dns_addr: SocketAddr,
+        origin: Name,
+    ) -> Self {
+        let http_url = match (http_addr, https_addr) {
+            (Some(addr), _) => Some(format!("http://{}", loopback(addr))),
+            (None, Some(addr)) => Some(format!("https://{}", loopback(addr))),
+            (None, None) => None,
+        };
+        Self {
+            http_url,
+            dns_addr: loopback(dns_addr),
+            origin,
+        }
+    }
+}
+
+fn loopback(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+/// Run the checks forever.
+pub(crate) async fn run(config: ProbeConfig, targets: ProbeTargets, data_dir: &Path) -> Result<()> {
+    let keypair = load_or_create_key(&data_dir.join("probe-key"))?;
+    let interval = config
+        .interval_secs
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INTERVAL);
+    // the probe checks the server's own listeners, which may have self-signed certificates
+    let client = reqwest::Client::builder()
+        .timeout(CHECK_TIMEOUT)
+        .danger_accept_invalid_certs(true)
+        .build()?;
+    let name = Name::parse(CANARY_LABEL, None)?
+        .append_name(&Name::parse(&keypair.public_key().to_z32(), None)?)?
+        .append_name(&targets.origin)?;
+    debug!(%name, "self-check probe started");
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let Some(http_url) = targets.http_url.as_deref() else {
+            // without HTTP, nothing can be published, and an older canary is checked
+            let _ = check(ProbeCheck::Udp, resolve_udp(targets.dns_addr, &name)).await;
+            continue;
+        };
+        let value = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        let publish = publish(&client, http_url, &keypair, &value);
+        if check(ProbeCheck::Publish, publish).await.is_err() {
+            continue;
+        }
+        let expect = |values: Vec<String>| {
+            ensure!(
+                values.contains(&value),
+                "unexpected canary value {values:?}"
+            );
+            Ok(())
+        };
+        let udp = async { expect(resolve_udp(targets.dns_addr, &name).await?) };
+        let doh = async { expect(resolve_doh(&client, http_url, &name).await?) };
+        let _ = tokio::join!(check(ProbeCheck::Udp, udp), check(ProbeCheck::Doh, doh));
+    }
+}
+
+/// Run a check with a timeout, and record its outcome.
+async fn check<T>(
+    check: ProbeCheck,
+    fut: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    let start = Instant::now();
+    let res = match tokio::time::timeout(CHECK_TIMEOUT, fut).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow::anyhow!("timed out")),
+    };
+    ProbeMetrics::observe(check, res.is_ok(), start.elapsed());
+    if let Err(err) = &res {
+        warn!(
+            check = <&'static str>::from(check),
+            "self-check failed: {err:#}"
+        );
+    }
+    res
+}
+
+fn load_or_create_key(path: &Path) -> Result<Keypair> {
+    if path.exists() {
+        let hex = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read probe key {}", path.display()))?;
+        let bytes: [u8; 32] = hex::decode(hex.trim())?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("invalid probe key {}", path.display()))?;
+        return Ok(Keypair::from_secret_key(&bytes));
+    }
+    let keypair = Keypair::from_secret_key(&rand::random());
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, hex::encode(keypair.secret_key()))
+        .with_context(|| format!("failed to write probe key {}", path.display()))?;
+    Ok(keypair)
+}
+
+async fn publish(
+    client: &reqwest::Client,
+    http_url: &str,
+    keypair: &Keypair,
+    value: &str,
+) -> Result<()> {
+    let mut packet = dns::Packet::new_reply(0);
+    packet.answers.push(dns::ResourceRecord::new(
+        dns::Name::new(CANARY_LABEL)?,
+        dns::CLASS::IN,
+        30,
+        dns::rdata::RData::TXT(value.try_into()?),
+    ));
+    let signed_packet = SignedPacket::from_packet(keypair, &packet)?;
+    let url = format!("{http_url}/pkarr/{}", keypair.public_key().to_z32());
+    client
+        .put(url)
+        .body(signed_packet.to_relay_payload())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn query(name: &Name) -> Result<Vec<u8>> {
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .add_query(Query::query(name.clone(), RecordType::TXT));
+    Ok(message.to_vec()?)
+}
+
+/// The values of the TXT records in the response.
+fn txt_values(response: &[u8]) -> Result<Vec<String>> {
+    let message = Message::from_vec(response)?;
+    Ok(message
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            RData::TXT(txt) => Some(txt.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+async fn resolve_udp(dns_addr: SocketAddr, name: &Name) -> Result<Vec<String>> {
+    let bind_addr: SocketAddr = match dns_addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.send_to(&query(name)?, dns_addr).await?;
+    let mut buf = vec![0; 4096];
+    let (len, from) = socket.recv_from(&mut buf).await?;
+    if from != dns_addr {
+        bail!("response from unexpected address {from}");
+    }
+    txt_values(&buf[..len])
+}
+
+async fn resolve_doh(client: &reqwest::Client, http_url: &str, name: &Name) -> Result<Vec<String>> {
+    let url = format!(
+        "{http_url}/dns-query?dns={}",
+        base64_url::encode(&query(name)?)
+    );
+    let response = client
+        .get(url)
+        .header(http::header::ACCEPT, "application/dns-message")
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    txt_values(&response)
+}
@@ -0,0 +1,355 @@
+//! Requests to other servers through a proxy
+//!
+//! The components that make requests to other servers, the change feed of a
+//! [`SyncConfig`](crate::sync::SyncConfig), the forwarding of a
+//! [`RingConfig`](crate::ring::RingConfig) and the reports of an
+//! [`AnonymousStatsConfig`](crate::anonymous_stats::AnonymousStatsConfig), can send them through
+//! a proxy with their `proxy` URL, e.g. to Tor or a corporate egress proxy. `http://` and
+//! `https://` proxies are used by the HTTP client directly. For `socks5://` and `socks5h://` proxies, a bridge on localhost takes the
+//! proxy requests of the HTTP client and opens the connections through the SOCKS5 proxy, which
+//! also resolves the host names, with either scheme. Credentials in the URL are sent with
+//! username and password authentication.
+//!
+//! The mainline DHT can't be proxied: it uses UDP, which most SOCKS5 proxies, including Tor,
+//! don't forward.
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    ops::Deref,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, warn};
+use url::Url;
+
+/// Maximum size of the head of a request to the bridge.
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// An HTTP client which sends its requests through the proxy it was created with
+///
+/// Dereferences to the [`reqwest::Client`]. The bridge to a SOCKS5 proxy runs until the last
+/// clone of the client is dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    _bridge: Option<Arc<AbortOnDropHandle<()>>>,
+}
+
+impl Deref for HttpClient {
+    type Target = reqwest::Client;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+impl HttpClient {
+    /// Create a client with `timeout`, which sends its requests through `proxy` if set.
+    ///
+    /// Must be called within a tokio runtime if `proxy` is a SOCKS5 proxy.
+    pub(crate) fn new(proxy: Option<&Url>, timeout: Duration) -> Result<Self> {
+        let builder = reqwest::Client::builder().timeout(timeout);
+        let Some(proxy) = proxy else {
+            return Ok(Self {
+                client: builder.build()?,
+                _bridge: None,
+            });
+        };
+        match proxy.scheme() {
+            "http" | "https" => Ok(Self {
+                client: builder.proxy(reqwest::Proxy::all(proxy.clone())?).build()?,
+                _bridge: None,
+            }),
+            "socks5" | "socks5h" => {
+                let socks = Socks5Proxy::from_url(proxy)?;
+                let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+                listener.set_nonblocking(true)?;
+                let bridge_url = format!("http://{}", listener.local_addr()?);
+                let listener = TcpListener::from_std(listener)?;
+                let bridge = tokio::spawn(run_bridge(listener, Arc::new(socks)));
+                Ok(Self {
+                    client: builder.proxy(reqwest::Proxy::all(bridge_url)?).build()?,
+                    _bridge: Some(Arc::new(AbortOnDropHandle::new(bridge))),
+                })
+            }
+            scheme => bail!("unsupported proxy scheme {scheme}, use http, https or socks5"),
+        }
+    }
+}
+
+/// A SOCKS5 proxy, with the credentials to authenticate with
+#[derive(derive_more::Debug)]
+struct Socks5Proxy {
+    host: String,
+    port: u16,
+    #[debug("{:?}", auth.as_ref().map(|(user, _)| user))]
+    auth: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    fn from_url(url: &Url) -> Result<Self> {
+        let host = url.host_str().context("the proxy URL has no host")?;
+        let auth = match url.username() {
+            "" => None,
+            user => Some((
+                percent_decode(user)?,
+                percent_decode(url.password().unwrap_or_default())?,
+            )),
+        };
+        Ok(Self {
+            host: host.trim_matches(['[', ']']).to_string(),
+            port: url.port().unwrap_or(1080),
+            auth,
+        })
+    }
+
+    /// Open a connection to `host:port` through the proxy.
+    async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .context("failed to connect to the SOCKS5 proxy")?;
+        // offer no authentication, or only username and password if there are credentials
+        let method = match self.auth {
+            None => 0x00,
+            Some(_) => 0x02,
+        };
+        stream.write_all(&[0x05, 0x01, method]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        ensure!(reply[0] == 0x05, "the proxy is not a SOCKS5 proxy");
+        ensure!(
+            reply[1] == method,
+            "the SOCKS5 proxy does not accept the authentication method"
+        );
+        if let Some((user, password)) = &self.auth {
+            ensure!(
+                user.len() <= 255 && password.len() <= 255,
+                "the SOCKS5 credentials are too long"
+            );
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            stream.read_exact(&mut reply).await?;
+            ensure!(
+                reply[1] == 0x00,
+                "the SOCKS5 proxy rejected the credentials"
+            );
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            // the proxy resolves the name, so that no DNS query leaves through the host
+            Err(_) => {
+                ensure!(host.len() <= 255, "the host name is too long");
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        ensure!(
+            reply[1] == 0x00,
+            "the SOCKS5 proxy failed to connect to {host}:{port} (reply {})",
+            reply[1]
+        );
+        // skip the address the proxy bound for the connection
+        let addr_len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => stream.read_u8().await? as usize,
+            atyp => bail!("invalid SOCKS5 address type {atyp}"),
+        };
+        let mut bound = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(stream)
+    }
+}
+
+/// Accept the proxy requests of the HTTP client, and open their connections through `socks`.
+async fn run_bridge(listener: TcpListener, socks: Arc<Socks5Proxy>) {
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("failed to accept a connection of the proxy bridge: {err}");
+                continue;
+            }
+        };
+        let socks = socks.clone();
+        tokio::spawn(async move {
+            if let Err(err) = bridge_connection(stream, &socks).await {
+                debug!(%from, "proxy bridge connection failed: {err:#}");
+            }
+        });
+    }
+}
+
+/// Open the connection of a proxy request through `socks`, and copy the data in both directions.
+///
+/// `CONNECT host:port` requests, for `https` URLs, open a tunnel. Other requests, for `http`
+/// URLs, have a full URL in their request line, which is kept since servers must accept it.
+async fn bridge_connection(mut client: TcpStream, socks: &Socks5Proxy) -> Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    let head_len = loop {
+        let n = client.read(&mut buf).await?;
+        ensure!(n > 0, "connection closed before the request head");
+        head.extend_from_slice(&buf[..n]);
+        if let Some(pos) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        ensure!(head.len() <= MAX_HEAD_SIZE, "request head too large");
+    };
+    let line = std::str::from_utf8(&head[..head_len])?
+        .lines()
+        .next()
+        .unwrap_or_default();
+    let mut parts = line.split(' ');
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+    let target = target.context("invalid request line")?;
+    let (host, port) = match method {
+        "CONNECT" => {
+            let (host, port) = target.rsplit_once(':').context("invalid CONNECT target")?;
+            (host.to_string(), port.parse()?)
+        }
+        _ => {
+            let url = Url::parse(target).context("invalid request target")?;
+            let host = url.host_str().context("request target without host")?;
+            let port = url.port_or_known_default().context("unknown port")?;
+            (host.to_string(), port)
+        }
+    };
+    let mut upstream = match socks.connect(&host, port).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .ok();
+            return Err(err);
+        }
+    };
+    if method == "CONNECT" {
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        upstream.write_all(&head[head_len..]).await?;
+    } else {
+        upstream.write_all(&head).await?;
+    }
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+/// Decode the percent-encoded `s`, e.g. the username of a URL.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next().unwrap_or(0), iter.next().unwrap_or(0)];
+                let hex = std::str::from_utf8(&hex)?;
+                bytes.push(u8::from_str_radix(hex, 16).context("invalid percent encoding")?);
+            }
+            b => bytes.push(b),
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    const TEST_BIND: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+    /// Run a SOCKS5 proxy without authentication, which records the requested targets.
+    async fn run_socks5_proxy() -> Result<(SocketAddr, Arc<Mutex<Vec<String>>>)> {
+        let listener = TcpListener::bind(TEST_BIND).await?;
+        let addr = listener.local_addr()?;
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn({
+            let targets = targets.clone();
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let targets = targets.clone();
+                    tokio::spawn(async move {
+                        let mut greeting = [0u8; 3];
+                        stream.read_exact(&mut greeting).await?;
+                        stream.write_all(&[0x05, 0x00]).await?;
+                        let mut request = [0u8; 4];
+                        stream.read_exact(&mut request).await?;
+                        let host = match request[3] {
+                            0x01 => {
+                                let mut ip = [0u8; 4];
+                                stream.read_exact(&mut ip).await?;
+                                Ipv4Addr::from(ip).to_string()
+                            }
+                            _ => {
+                                let len = stream.read_u8().await? as usize;
+                                let mut name = vec![0u8; len];
+                                stream.read_exact(&mut name).await?;
+                                String::from_utf8(name)?
+                            }
+                        };
+                        let port = stream.read_u16().await?;
+                        targets.lock().push(format!("{host}:{port}"));
+                        let ip = host.parse().unwrap_or(Ipv4Addr::LOCALHOST);
+                        let mut upstream = TcpStream::connect((ip, port)).await?;
+                        stream
+                            .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                            .await?;
+                        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+                        anyhow::Ok(())
+                    });
+                }
+            }
+        });
+        Ok((addr, targets))
+    }
+
+    #[tokio::test]
+    async fn requests_through_socks5_proxy() -> Result<()> {
+        let listener = TcpListener::bind(TEST_BIND).await?;
+        let server_addr = listener.local_addr()?;
+        let app = axum::Router::new().route("/hello", axum::routing::get(|| async { "hi" }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let (proxy_addr, targets) = run_socks5_proxy().await?;
+
+        let proxy: Url = format!("socks5h://{proxy_addr}").parse()?;
+        let client = HttpClient::new(Some(&proxy), Duration::from_secs(5))?;
+        let res = client
+            .get(format!("http://{server_addr}/hello"))
+            .send()
+            .await?;
+        assert_eq!(res.text().await?, "hi");
+        assert_eq!(*targets.lock(), vec![server_addr.to_string()]);
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Support for the [PROXY protocol] v1 and v2
+//!
+//! Load balancers that operate on layer 4 (HAProxy, AWS NLB, ..) can prepend a PROXY protocol
+//! header to each TCP connection, which carries the address of the original client. When enabled
+//! for a listener, every connection must start with such a header.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, middleware::AddExtension, Extension};
+use axum_server::accept::Accept;
+use futures_lite::{future::Boxed as BoxFuture, FutureExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+};
+use tower::Layer;
+
+use crate::{abuse::IpReputation, metrics::AbuseMetrics};
+
+/// Time after which a connection is dropped if it did not send a complete PROXY header.
+pub(crate) const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read a PROXY protocol header from `stream`.
+///
+/// Returns the source address of the proxied connection, or `None` if the header does not carry
+/// an address (v1 `UNKNOWN` or v2 `LOCAL` connections, commonly used for health checks).
+///
+/// Exactly the bytes of the header are consumed from the stream.
+pub(crate) async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if prefix == V2_SIGNATURE[..5] {
+        let mut header = [0u8; 16];
+        header[..5].copy_from_slice(&prefix);
+        stream.read_exact(&mut header[5..]).await?;
+        if header[..12] != V2_SIGNATURE[..] {
+            return Err(invalid("invalid PROXY v2 signature"));
+        }
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        parse_v2(header[12], header[13], &payload)
+    } else {
+        Err(invalid("missing PROXY header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let mut parts = line.trim_end().split(' ').skip(1);
+    match parts.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4") | Some("TCP6") => {
+            let src: IpAddr = parse_part(parts.next())?;
+            let _dst: IpAddr = parse_part(parts.next())?;
+            let src_port: u16 = parse_part(parts.next())?;
+            Ok(Some(SocketAddr::new(src, src_port)))
+        }
+        _ => Err(invalid("unsupported PROXY v1 protocol")),
+    }
+}
+
+fn parse_part<T: std::str::FromStr>(part: Option<&str>) -> io::Result<T> {
+    part.and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid PROXY v1 header"))
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, payload: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY v2 version"));
+    }
+    match ver_cmd & 0x0f {
+        // LOCAL: the connection was established by the proxy itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+    match family >> 4 {
+        // AF_UNSPEC
+        0x0 => Ok(None),
+        // AF_INET
+        0x1 if payload.len() >= 12 => {
+            let ip: [u8; 4] = payload[..4].try_into().expect("length checked");
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        // AF_INET6
+        0x2 if payload.len() >= 36 => {
+            let ip: [u8; 16] = payload[..16].try_into().expect("length checked");
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNIX and truncated addresses
+        _ => Err(invalid("unsupported PROXY v2 address family")),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// An acceptor that provides the client address to the HTTP app as [`ConnectInfo`].
+///
+/// If the PROXY protocol is enabled, the client address is read from the PROXY header, otherwise
+/// the connection peer address is used. Connections of clients in the IP reputation list are
+/// closed, the others are then handed to the inner acceptor.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectInfoAcceptor<A> {
+    inner: A,
+    proxy_protocol: bool,
+    ip_reputation: IpReputation,
+}
+
+impl<A> ConnectInfoAcceptor<A> {
+    pub(crate) fn new(inner: A, proxy_protocol: bool, ip_reputation: IpReputation) -> Self {
+        Self {
+            inner,
+            proxy_protocol,
+            ip_reputation,
+        }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for ConnectInfoAcceptor<A>
+where
+    A: Accept<TcpStream, AddExtension<S, ConnectInfo<SocketAddr>>> + Clone + Send + 'static,
+    A::Future: Send,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        let proxy_protocol = self.proxy_protocol;
+        let ip_reputation = self.ip_reputation.clone();
+        async move {
+            let mut addr = stream.peer_addr()?;
+            if proxy_protocol {
+                let header = tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_header(&mut stream))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY header timeout"))?;
+                if let Some(src) = header? {
+                    addr = src;
+                }
+            }
+            if ip_reputation.is_listed(addr.ip()) {
+                AbuseMetrics::count_dropped("http");
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "client is in the IP reputation list",
+                ));
+            }
+            let service = Extension(ConnectInfo(addr)).layer(service);
+            inner.accept(stream, service).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_proxy_headers() {
+        let mut v1: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+        let addr = read_header(&mut v1).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(v1, b"GET /");
+
+        let mut v1: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut v1).await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend_from_slice(b"GET /");
+        let mut v2 = &v2[..];
+        let addr = read_header(&mut v2).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(v2, b"GET /");
+
+        let mut missing: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_header(&mut missing).await.is_err());
+    }
+}
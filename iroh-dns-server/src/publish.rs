@@ -0,0 +1,62 @@
+//! Publishing node announcements to a server, for the `publish` command
+//!
+//! The announcement is signed and published like iroh nodes do, and then resolved from the server
+//! again, so that one command checks that a new deployment accepts, stores and serves packets.
+
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use iroh_net::{discovery::pkarr::PkarrRelayClient, dns::node_info::NodeInfo, key::SecretKey};
+use url::Url;
+
+use crate::resolve::{self, Resolution};
+
+/// The direct address that is announced if neither a relay nor addresses are given, from the
+/// documentation range.
+const PLACEHOLDER_ADDR: &str = "192.0.2.1:4433";
+
+/// Build the announcement of a node, with a placeholder address if it has neither a relay nor
+/// direct addresses.
+pub fn node_info(
+    secret_key: &SecretKey,
+    relay_url: Option<Url>,
+    direct_addresses: BTreeSet<SocketAddr>,
+) -> NodeInfo {
+    let direct_addresses = if relay_url.is_none() && direct_addresses.is_empty() {
+        BTreeSet::from([PLACEHOLDER_ADDR.parse().expect("valid address")])
+    } else {
+        direct_addresses
+    };
+    NodeInfo::new(secret_key.public(), relay_url, direct_addresses)
+}
+
+/// Publish `info` to the pkarr relay API of the server at `url`, and return how long it took.
+pub async fn publish(
+    url: &Url,
+    secret_key: &SecretKey,
+    info: &NodeInfo,
+    ttl: u32,
+) -> Result<Duration> {
+    let signed_packet = info.to_pkarr_signed_packet(secret_key, ttl)?;
+    let client = PkarrRelayClient::new(url.join("pkarr")?);
+    let start = Instant::now();
+    client.publish(&signed_packet).await?;
+    Ok(start.elapsed())
+}
+
+/// Resolve the node of `info` from the server at `url`, and check that it serves `info`.
+pub async fn verify(url: &Url, info: &NodeInfo) -> Resolution {
+    let mut resolution = resolve::resolve_http(url, &info.node_id).await;
+    if let Ok(resolved) = &resolution.info {
+        if resolved != info {
+            resolution.info = Err(anyhow::anyhow!(
+                "the server serves a different announcement: {resolved:?}"
+            ));
+        }
+    }
+    resolution
+}
@@ -0,0 +1,344 @@
+//! Export of sampled DNS query records
+//!
+//! With a [`QueryLogConfig`], a sample of the DNS queries is exported to a [`QueryLogSink`]: a
+//! file with tab separated values, a ClickHouse table via its HTTP interface, or a Kafka topic via
+//! a Kafka REST proxy. The records are queued and written in batches by a background task. If the
+//! sink can't keep up and the queue is full, records are dropped instead of slowing down the DNS
+//! server, and counted in the `query_log_records` metric.
+//!
+//! Each record has the fields `timestamp_ms`, `client`, `protocol`, `name`, `qtype`, `rcode`,
+//! `answers` and `duration_us`, in this order in the TSV formats.
+
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tracing::{debug, warn};
+
+use crate::{
+    metrics::{QueryLogMetrics, QueryLogOutcome},
+    secrets::{RefreshingSecret, SecretValue},
+};
+
+/// Default number of records that are queued for the sink.
+const DEFAULT_BUFFER_SIZE: usize = 10_000;
+/// Default maximum number of records written at once.
+const DEFAULT_BATCH_SIZE: usize = 1_000;
+/// Default interval in which the queued records are written.
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Number of attempts to write a batch before it is dropped.
+const WRITE_ATTEMPTS: u32 = 3;
+/// Timeout for requests to ClickHouse and Kafka.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Config for the export of DNS query records
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueryLogConfig {
+    /// Where to write the records
+    pub sink: QueryLogSink,
+    /// The fraction of queries that are exported, from `0.0` to `1.0` (defaults to `1.0`)
+    #[serde(default)]
+    pub sample_rate: Option<f64>,
+    /// The number of records that are queued before records are dropped (defaults to 10000)
+    #[serde(default)]
+    pub buffer_size: Option<usize>,
+    /// The maximum number of records written at once (defaults to 1000)
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    /// The interval in seconds in which the queued records are written (defaults to 5)
+    #[serde(default)]
+    pub flush_interval_secs: Option<u64>,
+}
+
+/// Where the query records are written to
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QueryLogSink {
+    /// Append tab separated values to a file
+    File {
+        /// The path of the file
+        path: PathBuf,
+    },
+    /// Insert into a ClickHouse table via the HTTP interface
+    Clickhouse {
+        /// The URL of the HTTP interface, e.g. `http://localhost:8123`
+        url: String,
+        /// The table, e.g. `dns.queries`
+        table: String,
+        /// The user, `default` if not set
+        #[serde(default)]
+        user: Option<String>,
+        /// The password of the user
+        #[serde(default)]
+        password: Option<SecretValue>,
+    },
+    /// Produce JSON records to a Kafka topic via a Kafka REST proxy (v2 API)
+    Kafka {
+        /// The URL of the REST proxy, e.g. `http://localhost:8082`
+        rest_url: String,
+        /// The topic
+        topic: String,
+    },
+}
+
+/// A DNS query to export.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct QueryRecord {
+    pub(crate) timestamp_ms: u64,
+    pub(crate) client: IpAddr,
+    pub(crate) protocol: String,
+    pub(crate) name: String,
+    pub(crate) qtype: String,
+    pub(crate) rcode: String,
+    pub(crate) answers: u16,
+    pub(crate) duration_us: u64,
+}
+
+impl QueryRecord {
+    /// The record as a line of tab separated values.
+    fn to_tsv(&self) -> String {
+        let fields = [
+            self.timestamp_ms.to_string(),
+            self.client.to_string(),
+            escape_tsv(&self.protocol),
+            escape_tsv(&self.name),
+            escape_tsv(&self.qtype),
+            escape_tsv(&self.rcode),
+            self.answers.to_string(),
+            self.duration_us.to_string(),
+        ];
+        let mut line = fields.join("\t");
+        line.push('\n');
+        line
+    }
+}
+
+/// Escape the characters that are special in the TSV format of ClickHouse.
+fn escape_tsv(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// The sender of the query records to the background task.
+#[derive(Debug, Clone)]
+pub(crate) struct QueryLog {
+    tx: mpsc::Sender<QueryRecord>,
+    sample_rate: f64,
+}
+
+impl QueryLog {
+    /// Spawn the task that writes the records to the sink.
+    pub(crate) async fn spawn(config: &QueryLogConfig) -> Result<Self> {
+        let sink = Sink::open(&config.sink).await?;
+        let (tx, rx) = mpsc::channel(config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE));
+        let batch_size = config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+        let flush_interval = config
+            .flush_interval_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_FLUSH_INTERVAL);
+        tokio::spawn(write_batches(rx, sink, batch_size, flush_interval));
+        Ok(Self {
+            tx,
+            sample_rate: config.sample_rate.unwrap_or(1.0),
+        })
+    }
+
+    /// The current time, for [`QueryRecord::timestamp_ms`].
+    pub(crate) fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// Whether the next query is in the sample.
+    pub(crate) fn sample(&self) -> bool {
+        self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Queue a record, or drop it if the queue is full.
+    pub(crate) fn record(&self, record: QueryRecord) {
+        if self.tx.try_send(record).is_err() {
+            QueryLogMetrics::count(QueryLogOutcome::Dropped, 1);
+        }
+    }
+}
+
+/// An opened [`QueryLogSink`].
+#[derive(Debug)]
+enum Sink {
+    File(tokio::fs::File),
+    Clickhouse {
+        client: reqwest::Client,
+        url: String,
+        user: Option<String>,
+        password: Option<RefreshingSecret>,
+    },
+    Kafka {
+        client: reqwest::Client,
+        url: String,
+    },
+}
+
+impl Sink {
+    async fn open(config: &QueryLogSink) -> Result<Self> {
+        let client = || reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build();
+        let sink = match config {
+            QueryLogSink::File { path } => {
+                let file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("failed to open query log {}", path.display()))?;
+                Self::File(file)
+            }
+            QueryLogSink::Clickhouse {
+                url,
+                table,
+                user,
+                password,
+            } => {
+                let password = match password {
+                    Some(password) => Some(RefreshingSecret::new(password).await?),
+                    None => None,
+                };
+                let mut url = url::Url::parse(url).context("invalid ClickHouse URL")?;
+                url.query_pairs_mut()
+                    .append_pair("query", &format!("INSERT INTO {table} FORMAT TabSeparated"));
+                Self::Clickhouse {
+                    client: client()?,
+                    url: url.to_string(),
+                    user: user.clone(),
+                    password,
+                }
+            }
+            QueryLogSink::Kafka { rest_url, topic } => Self::Kafka {
+                client: client()?,
+                url: format!("{}/topics/{topic}", rest_url.trim_end_matches('/')),
+            },
+        };
+        Ok(sink)
+    }
+
+    async fn write(&mut self, records: &[QueryRecord]) -> Result<()> {
+        match self {
+            Self::File(file) => {
+                let tsv: String = records.iter().map(QueryRecord::to_tsv).collect();
+                file.write_all(tsv.as_bytes()).await?;
+                file.flush().await?;
+            }
+            Self::Clickhouse {
+                client,
+                url,
+                user,
+                password,
+            } => {
+                let tsv: String = records.iter().map(QueryRecord::to_tsv).collect();
+                let mut req = client.post(url.as_str()).body(tsv);
+                if user.is_some() || password.is_some() {
+                    let user = user.as_deref().unwrap_or("default");
+                    req = req.basic_auth(user, password.as_ref().map(RefreshingSecret::get));
+                }
+                req.send().await?.error_for_status()?;
+            }
+            Self::Kafka { client, url } => {
+                let records: Vec<_> = records
+                    .iter()
+                    .map(|record| json!({ "value": record }))
+                    .collect();
+                client
+                    .post(url.as_str())
+                    .header(
+                        http::header::CONTENT_TYPE,
+                        "application/vnd.kafka.json.v2+json",
+                    )
+                    .json(&json!({ "records": records }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the records from `rx` in batches, until all senders are dropped.
+async fn write_batches(
+    mut rx: mpsc::Receiver<QueryRecord>,
+    mut sink: Sink,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    loop {
+        tokio::time::sleep(flush_interval).await;
+        // write everything that was queued during the interval
+        loop {
+            if rx.recv_many(&mut batch, batch_size).await == 0 {
+                return;
+            }
+            write_batch(&mut sink, &batch).await;
+            let full = batch.len() == batch_size;
+            batch.clear();
+            if !full || rx.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+/// Write a batch, with retries.
+async fn write_batch(sink: &mut Sink, batch: &[QueryRecord]) {
+    let mut backoff = Duration::from_millis(500);
+    for attempt in 1..=WRITE_ATTEMPTS {
+        match sink.write(batch).await {
+            Ok(()) => {
+                debug!(records = batch.len(), "wrote query log batch");
+                QueryLogMetrics::count(QueryLogOutcome::Written, batch.len());
+                return;
+            }
+            Err(err) if attempt < WRITE_ATTEMPTS => {
+                debug!("failed to write query log batch, retrying: {err:#}");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => warn!(
+                records = batch.len(),
+                "failed to write query log batch: {err:#}"
+            ),
+        }
+    }
+    QueryLogMetrics::count(QueryLogOutcome::Failed, batch.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tsv() {
+        let record = QueryRecord {
+            timestamp_ms: 1700000000000,
+            client: "192.0.2.1".parse().unwrap(),
+            protocol: "udp".to_string(),
+            name: "_iroh.a\tb.dns.example.org.".to_string(),
+            qtype: "TXT".to_string(),
+            rcode: "NoError".to_string(),
+            answers: 1,
+            duration_us: 250,
+        };
+        assert_eq!(
+            record.to_tsv(),
+            "1700000000000\t192.0.2.1\tudp\t_iroh.a\\tb.dns.example.org.\tTXT\tNoError\t1\t250\n"
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Persistence of the rate limits across restarts
+//!
+//! With a [`RateLimitStateConfig`], the server keeps the used budgets of the HTTP rate limits of
+//! the client IP addresses, and of the publishes of the tenants, in a table of the packet
+//! database. They are saved in an interval and on shutdown, and restored on start, so that
+//! clients can't reset their budgets by waiting for a deploy. Only the time when each used
+//! budget is full again is kept, the budgets that are full are not.
+
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use redb::{Database, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{state::RateLimiters, tenants::Tenants};
+
+/// When the used budget is full again, in milliseconds since the unix epoch, by limiter and key
+///
+/// The limiter is the class of an HTTP rate limit, e.g. `publish`, with the client IP address as
+/// key, or `tenant` with the name of the tenant as key.
+const RATE_LIMIT_STATE_TABLE: TableDefinition<(&str, &str), u64> =
+    TableDefinition::new("rate-limit-state-1");
+
+/// The limiter of the publishes of the tenants in the table.
+const TENANT_LIMITER: &str = "tenant";
+/// Default interval in seconds in which the used budgets are saved.
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+/// Config for the persistence of the rate limits
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RateLimitStateConfig {
+    /// Interval in seconds in which the used budgets are saved (defaults to 60).
+    pub interval_secs: Option<u64>,
+}
+
+/// The persisted rate limits, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimitState {
+    db: Arc<Database>,
+    interval: Duration,
+    rate_limiters: RateLimiters,
+    tenants: Tenants,
+}
+
+impl RateLimitState {
+    /// Open the persisted rate limits of `rate_limiters` and `tenants` in `db`.
+    pub(crate) fn open(
+        db: Arc<Database>,
+        config: &RateLimitStateConfig,
+        rate_limiters: RateLimiters,
+        tenants: Tenants,
+    ) -> Result<Self> {
+        let write_tx = db.begin_write()?;
+        {
+            let _table = write_tx.open_table(RATE_LIMIT_STATE_TABLE)?;
+        }
+        write_tx.commit()?;
+        Ok(Self {
+            db,
+            interval: Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS)),
+            rate_limiters,
+            tenants,
+        })
+    }
+
+    /// Use up the budgets that were used when the state was saved, and track the used budgets
+    /// from now on.
+    pub(crate) fn restore(&self) -> Result<()> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(RATE_LIMIT_STATE_TABLE)?;
+        for limiter in self.rate_limiters.all() {
+            let class: &'static str = limiter.class().into();
+            let mut used_budgets = Vec::new();
+            for row in table.range((class, "")..(class, "\u{10ffff}"))? {
+                let (key, full_at) = row?;
+                let (_, ip) = key.value();
+                match ip.parse::<IpAddr>() {
+                    Ok(ip) => used_budgets.push((ip, unix_millis_to_time(full_at.value()))),
+                    Err(err) => debug!("invalid rate limiting key {ip}: {err}"),
+                }
+            }
+            limiter.restore(used_budgets);
+        }
+        for tenant in self.tenants.iter() {
+            if let Some(full_at) = table.get((TENANT_LIMITER, tenant.name()))? {
+                tenant.restore_budget(unix_millis_to_time(full_at.value()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the saved state with the budgets that are used now.
+    pub(crate) fn save(&self) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        let mut saved = 0;
+        {
+            let mut table = tx.open_table(RATE_LIMIT_STATE_TABLE)?;
+            table.retain(|_, _| false)?;
+            for limiter in self.rate_limiters.all() {
+                let class: &'static str = limiter.class().into();
+                for (ip, full_at) in limiter.used_budgets() {
+                    table.insert(
+                        (class, ip.to_string().as_str()),
+                        time_to_unix_millis(full_at),
+                    )?;
+                    saved += 1;
+                }
+            }
+            for tenant in self.tenants.iter() {
+                if let Some(full_at) = tenant.used_budget() {
+                    table.insert(
+                        (TENANT_LIMITER, tenant.name()),
+                        time_to_unix_millis(full_at),
+                    )?;
+                    saved += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        debug!(saved, "saved the used rate limit budgets");
+        Ok(())
+    }
+
+    /// Save the used budgets in the interval, until the task is aborted.
+    pub(crate) async fn run(self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let this = self.clone();
+            match tokio::task::spawn_blocking(move || this.save()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("failed to save the rate limits: {err:#}"),
+                Err(err) => warn!("failed to save the rate limits: {err}"),
+            }
+        }
+    }
+}
+
+fn time_to_unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn unix_millis_to_time(millis: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        abuse::AbuseLog,
+        api_keys::ApiKeyStore,
+        http::{
+            rate_limiting::{self, RateLimitClass},
+            RateLimitConfig,
+        },
+    };
+
+    #[test]
+    fn restore_saved_budgets() -> Result<()> {
+        let db = Arc::new(
+            Database::builder().create_with_backend(redb::backends::InMemoryBackend::new())?,
+        );
+        let open = || {
+            let rate_limiters = RateLimiters::default();
+            let limiter = rate_limiting::create(
+                &RateLimitConfig::default(),
+                RateLimitClass::Publish,
+                ApiKeyStore::open(db.clone())?,
+                AbuseLog::default(),
+            )?
+            .expect("rate limiting is enabled");
+            rate_limiters.add(limiter.clone());
+            let state = RateLimitState::open(
+                db.clone(),
+                &RateLimitStateConfig::default(),
+                rate_limiters,
+                Tenants::default(),
+            )?;
+            state.restore()?;
+            anyhow::Ok((state, limiter))
+        };
+        let (state, limiter) = open()?;
+        assert!(limiter.used_budgets().is_empty());
+        let ip: IpAddr = "192.0.2.1".parse()?;
+        let full_at = unix_millis_to_time(time_to_unix_millis(SystemTime::now()) + 8_000);
+        limiter.restore([(ip, full_at)]);
+        state.save()?;
+
+        // a new server uses up the budget again
+        let (_state, limiter) = open()?;
+        assert_eq!(limiter.used_budgets(), vec![(ip, full_at)]);
+        Ok(())
+    }
+}
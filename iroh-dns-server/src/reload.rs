@@ -0,0 +1,330 @@
+//! Reload of the config file at runtime
+//!
+//! On `SIGHUP`, and with [`Config::watch_config`] whenever the file or one of the files of
+//! [`Config::include`] is modified, the config file is loaded again and the changes that can be
+//! applied without a restart are applied:
+//!
+//! * the `level` and `levels` of the log output,
+//! * the HTTP rate limits, whose budgets start over if their quota changed,
+//! * the static records of the origins (`default_soa`, `rr_a`, `rr_aaaa` and `rr_ns`).
+//!
+//! Changes to all other settings, e.g. listen addresses, are logged as needing a restart. A
+//! config that fails to load or apply is logged, and the running config is kept.
+//!
+//! The network interfaces in the `bind_addr` of the listeners are looked up again, and if their
+//! addresses changed, the listeners are handed to a new process like on `SIGUSR2`, which binds
+//! the new addresses.
+
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    config::{BindAddr, Config},
+    http::{rate_limiting::RateLimitClass, RateLimitConfig, RateLimitMode},
+    state::{AppState, BoundAddrs},
+    util,
+};
+
+/// The interval in which the config file is checked for changes, with [`Config::watch_config`].
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The settings that are applied by a reload, as JSON pointers into the config.
+const RELOADABLE: [&str; 13] = [
+    "/logging/level",
+    "/logging/levels",
+    "/http/rate_limit",
+    "/http/doh_rate_limit",
+    "/https/rate_limit",
+    "/https/doh_rate_limit",
+    "/dns/default_soa",
+    "/dns/default_ttl",
+    "/dns/rr_a",
+    "/dns/rr_aaaa",
+    "/dns/rr_ns",
+    "/watch_config",
+    "/include",
+];
+
+/// Reload the config from `path` on `SIGHUP`, or when the file changes if `watch` is set.
+///
+/// `current` is the running config, as JSON value.
+pub(crate) async fn run(path: PathBuf, current: Value, state: AppState, watch: bool) {
+    let mut reloader = Reloader {
+        modified: modified(&path),
+        path,
+        current,
+        state,
+    };
+    let mut hangup = hangup_signal();
+    let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+    loop {
+        tokio::select! {
+            res = recv(&mut hangup) => {
+                if res.is_none() {
+                    warn!("stopped listening for SIGHUP");
+                    hangup = None;
+                    continue;
+                }
+                info!("reloading config on SIGHUP");
+            }
+            _ = ticker.tick(), if watch => {
+                let modified = modified(&reloader.path);
+                if modified == reloader.modified {
+                    continue;
+                }
+                reloader.modified = modified;
+                info!("config file changed, reloading");
+            }
+        }
+        if let Err(err) = reloader.reload().await {
+            warn!("failed to reload config, keeping the running config: {err:#}");
+        }
+    }
+}
+
+struct Reloader {
+    path: PathBuf,
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+    current: Value,
+    state: AppState,
+}
+
+impl Reloader {
+    async fn reload(&mut self) -> Result<()> {
+        let config = Config::load(&self.path).await?;
+        let new = serde_json::to_value(&config)?;
+        let mut restart = restart_required(&self.current, &new);
+
+        if let Some(logging) = &config.logging {
+            crate::telemetry::reload_log_levels(logging)?;
+        }
+        self.state
+            .dns_handler
+            .reload_static_records(&config.dns)
+            .context("invalid static DNS records")?;
+        let (publish, doh) = rate_limit_configs(&config);
+        let rate_limits = [
+            (RateLimitClass::Publish, publish, "rate_limit"),
+            (RateLimitClass::Lookup, publish, "rate_limit.get"),
+            (RateLimitClass::Doh, doh, "doh_rate_limit"),
+        ];
+        // check all quotas first, to not reload only some of the rate limiters
+        for (class, rate_limit, setting) in rate_limits {
+            if let Some(rate_limit) = rate_limit {
+                rate_limit
+                    .validate(class)
+                    .with_context(|| format!("invalid {setting}"))?;
+            }
+        }
+        for (class, rate_limit, setting) in rate_limits {
+            match (self.state.rate_limiters.get(class), rate_limit) {
+                (Some(limiter), rate_limit) => {
+                    limiter.reload(rate_limit.unwrap_or(&RateLimitMode::Disabled.into()))?;
+                }
+                // there is no rate limiter to configure, it is created on start
+                (None, Some(rate_limit)) if rate_limit.limits(class) => {
+                    restart.push(setting.to_string())
+                }
+                (None, _) => {}
+            }
+        }
+
+        if restart.is_empty() {
+            info!("reloaded config");
+        } else {
+            warn!(
+                settings = restart.join(", "),
+                "reloaded config, but some changed settings only apply after a restart"
+            );
+        }
+        self.current = new;
+        match moved_interfaces(&config, &self.state.bound_addrs) {
+            Ok(moved) if moved.is_empty() => {}
+            Ok(moved) => rebind(&moved),
+            Err(err) => warn!("failed to look up the addresses of the interfaces: {err:#}"),
+        }
+        Ok(())
+    }
+}
+
+/// The servers with an interface in their `bind_addr` whose addresses differ from the bound
+/// addresses.
+fn moved_interfaces(config: &Config, bound: &BoundAddrs) -> Result<Vec<&'static str>> {
+    let bound = bound.get();
+    let listeners = [
+        ("http", config.http.as_ref().map(|c| (&c.bind_addr, c.port))),
+        (
+            "https",
+            config.https.as_ref().map(|c| (&c.bind_addr, Some(c.port))),
+        ),
+        ("dns", Some((&config.dns.bind_addr, Some(config.dns.port)))),
+    ];
+    let ips = |addrs: &[SocketAddr]| addrs.iter().map(SocketAddr::ip).collect::<BTreeSet<_>>();
+    let mut moved = Vec::new();
+    for (server, listener) in listeners {
+        let Some((addrs, port)) = listener else {
+            continue;
+        };
+        if !addrs.iter().any(|a| matches!(a, BindAddr::Interface(_))) {
+            continue;
+        }
+        let resolved = util::socket_addrs(server, addrs, port, config.ip_stack)?;
+        let bound = bound.get(server).map(Vec::as_slice).unwrap_or_default();
+        if ips(&resolved) != ips(bound) {
+            moved.push(server);
+        }
+    }
+    Ok(moved)
+}
+
+/// Hand the listeners to a new process, which binds the new addresses of the interfaces.
+#[cfg(unix)]
+fn rebind(servers: &[&str]) {
+    use nix::sys::signal::{raise, Signal};
+    info!(
+        servers = servers.join(", "),
+        "addresses of the bound interfaces changed, upgrading to bind them"
+    );
+    if let Err(err) = raise(Signal::SIGUSR2) {
+        warn!("failed to start the upgrade: {err}");
+    }
+}
+
+/// Listeners can only be bound anew by a restart on this platform.
+#[cfg(not(unix))]
+fn rebind(servers: &[&str]) {
+    warn!(
+        servers = servers.join(", "),
+        "addresses of the bound interfaces changed, restart to bind them"
+    );
+}
+
+/// The rate limit configs of publishes and DoH queries, as used by the HTTP server.
+fn rate_limit_configs(config: &Config) -> (Option<&RateLimitConfig>, Option<&RateLimitConfig>) {
+    let https = config.https.as_ref();
+    let http = config.http.as_ref();
+    let publish = https
+        .and_then(|h| h.rate_limit.as_ref())
+        .or_else(|| http.map(|h| &h.rate_limit));
+    let doh = https
+        .and_then(|h| h.doh_rate_limit.as_ref())
+        .or_else(|| http.and_then(|h| h.doh_rate_limit.as_ref()));
+    (publish, doh)
+}
+
+/// The changed settings, except for the ones that are applied by a reload, e.g. `dns.port`.
+fn restart_required(old: &Value, new: &Value) -> Vec<String> {
+    let strip = |value: &Value| {
+        let mut value = value.clone();
+        for pointer in RELOADABLE {
+            let (parent, key) = pointer.rsplit_once('/').expect("pointer");
+            if let Some(Value::Object(parent)) = value.pointer_mut(parent) {
+                parent.remove(key);
+            }
+        }
+        value
+    };
+    let (old, new) = (strip(old), strip(new));
+    let fields = |value: &Value| value.as_object().cloned().unwrap_or_default();
+    let (old, new) = (fields(&old), fields(&new));
+    let mut changed = Vec::new();
+    for key in old
+        .keys()
+        .chain(new.keys().filter(|key| !old.contains_key(*key)))
+    {
+        let (a, b) = (old.get(key), new.get(key));
+        if a == b {
+            continue;
+        }
+        match (a.and_then(Value::as_object), b.and_then(Value::as_object)) {
+            // list the changed fields of sections that are set in both configs
+            (Some(a), Some(b)) => {
+                for field in a.keys().chain(b.keys().filter(|f| !a.contains_key(*f))) {
+                    if a.get(field) != b.get(field) {
+                        changed.push(format!("{key}.{field}"));
+                    }
+                }
+            }
+            _ => changed.push(key.clone()),
+        }
+    }
+    changed
+}
+
+/// The modification times of the config file at `path` and the files it includes.
+///
+/// Changes if an included file is modified, added or removed.
+fn modified(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let files = crate::config::config_files(path).unwrap_or_else(|_| vec![path.to_path_buf()]);
+    files
+        .into_iter()
+        .map(|file| {
+            let modified = std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+            (file, modified)
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+type Hangup = tokio::signal::unix::Signal;
+
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup_signal() -> Option<Hangup> {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(err) => {
+            warn!("failed to listen for SIGHUP: {err}");
+            None
+        }
+    }
+}
+
+/// There are no signals on this platform, the config is only reloaded if it is watched.
+#[cfg(not(unix))]
+fn hangup_signal() -> Option<Hangup> {
+    None
+}
+
+/// Wait for the next `SIGHUP`, or forever if there is no signal.
+async fn recv(hangup: &mut Option<Hangup>) -> Option<()> {
+    match hangup {
+        #[cfg(unix)]
+        Some(signal) => signal.recv().await,
+        _ => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn restart_required_settings() {
+        let old = json!({
+            "dns": { "port": 53, "rr_a": "192.0.2.1" },
+            "logging": { "level": "info", "format": "text" },
+            "metrics": null,
+        });
+        let new = json!({
+            "dns": { "port": 5353, "rr_a": "192.0.2.2" },
+            "logging": { "level": "debug", "format": "text" },
+            "metrics": { "disabled": true },
+        });
+        assert_eq!(restart_required(&old, &new), ["dns.port", "metrics"]);
+        assert!(restart_required(&old, &old).is_empty());
+    }
+}
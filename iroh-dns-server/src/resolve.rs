@@ -0,0 +1,151 @@
+//! Resolving node infos from a server, for the `resolve` command
+//!
+//! The node info is resolved like iroh nodes do: with a TXT query for `_iroh.<node id>.<origin>`
+//! to the DNS server, and with a GET of the pkarr relay API. Both are timed, so that operators can
+//! check a deployment, and compare the answers of the DNS and HTTP listeners.
+
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use hickory_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use iroh_net::{
+    discovery::pkarr::PkarrRelayClient,
+    dns::node_info::{self, NodeInfo, IROH_TXT_NAME},
+    NodeId,
+};
+use tokio::net::UdpSocket;
+use url::Url;
+
+/// Timeout of each lookup.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The result of resolving a node info
+#[derive(Debug)]
+pub struct Resolution {
+    /// How the node info was resolved, e.g. `dns 127.0.0.1:53`
+    pub via: String,
+    /// How long the lookup took
+    pub elapsed: Duration,
+    /// The node info, or why it could not be resolved
+    pub info: Result<NodeInfo>,
+}
+
+/// Parse a node id, as printed by iroh or z-base-32 encoded as in DNS names.
+pub fn parse_node_id(node_id: &str) -> Result<NodeId> {
+    match (node_id.parse::<NodeId>(), node_info::from_z32(node_id)) {
+        (Ok(a), Ok(b)) if a != b => {
+            bail!("ambiguous node id {node_id}, it is valid in both base32 and z-base-32")
+        }
+        (Ok(id), _) | (_, Ok(id)) => Ok(id),
+        (Err(err), Err(_)) => Err(err).with_context(|| format!("invalid node id {node_id}")),
+    }
+}
+
+/// Resolve the node info of `node_id` under `origin` from the DNS server at `addr`.
+pub async fn resolve_dns(addr: SocketAddr, origin: &str, node_id: &NodeId) -> Resolution {
+    let start = Instant::now();
+    let info = match tokio::time::timeout(TIMEOUT, lookup_dns(addr, origin, node_id)).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow::anyhow!("timed out")),
+    };
+    Resolution {
+        via: format!("dns {addr}"),
+        elapsed: start.elapsed(),
+        info,
+    }
+}
+
+/// Resolve the node info of `node_id` from the pkarr relay API of the server at `url`.
+pub async fn resolve_http(url: &Url, node_id: &NodeId) -> Resolution {
+    let start = Instant::now();
+    let lookup = async {
+        let client = PkarrRelayClient::new(url.join("pkarr")?);
+        let signed_packet = client.resolve(*node_id).await?;
+        NodeInfo::from_pkarr_signed_packet(&signed_packet)
+    };
+    let info = match tokio::time::timeout(TIMEOUT, lookup).await {
+        Ok(res) => res,
+        Err(_) => Err(anyhow::anyhow!("timed out")),
+    };
+    Resolution {
+        via: format!("http {url}"),
+        elapsed: start.elapsed(),
+        info,
+    }
+}
+
+async fn lookup_dns(addr: SocketAddr, origin: &str, node_id: &NodeId) -> Result<NodeInfo> {
+    let name = Name::parse(
+        &format!("{IROH_TXT_NAME}.{}.{origin}", node_info::to_z32(node_id)),
+        Some(&Name::root()),
+    )?;
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .add_query(Query::query(name, RecordType::TXT));
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+    socket.send(&query.to_vec()?).await?;
+    let mut buf = vec![0; 4096];
+    let len = socket.recv(&mut buf).await?;
+    let response = Message::from_vec(&buf[..len])?;
+    if response.response_code() != ResponseCode::NoError {
+        bail!("the server answered {}", response.response_code());
+    }
+    NodeInfo::from_hickory_records(response.answers())
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({} ms)", self.via, self.elapsed.as_millis())?;
+        match &self.info {
+            Ok(info) => {
+                writeln!(f, "  node id:   {}", info.node_id)?;
+                writeln!(f, "  z32:       {}", node_info::to_z32(&info.node_id))?;
+                match &info.relay_url {
+                    Some(url) => writeln!(f, "  relay:     {url}")?,
+                    None => writeln!(f, "  relay:     -")?,
+                }
+                for addr in &info.direct_addresses {
+                    writeln!(f, "  address:   {addr}")?;
+                }
+            }
+            Err(err) => writeln!(f, "  failed: {err:#}")?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_net::key::SecretKey;
+
+    use super::*;
+
+    #[test]
+    fn node_id_encodings() -> Result<()> {
+        let node_id = SecretKey::generate().public();
+        let z32 = node_info::to_z32(&node_id);
+        let base32 = node_id.to_string();
+        // a few keys are valid, and different, in both encodings
+        if z32.parse::<NodeId>().is_err() {
+            assert_eq!(parse_node_id(&z32)?, node_id);
+        }
+        if node_info::from_z32(&base32).is_err() {
+            assert_eq!(parse_node_id(&base32)?, node_id);
+        }
+        assert!(parse_node_id("not a node id").is_err());
+        Ok(())
+    }
+}
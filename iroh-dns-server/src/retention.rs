@@ -0,0 +1,250 @@
+//! Removal of old packets from the store
+//!
+//! Nodes republish their packets while they are online, so a packet that was not republished for
+//! a long time most likely belongs to a node that is gone. With a [`RetentionConfig`], the server
+//! removes packets older than the maximum age in an interval. `db gc` and `POST /admin/db-gc` run
+//! the removal immediately, and can show what would be removed without removing it.
+//!
+//! Of the servers that share a packet store, only the leader removes old packets in an interval.
+
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{ensure, Context, Result};
+use iroh_metrics::inc_by;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::{
+    events::ServerEvent,
+    metrics::{Metrics, StoreMetrics},
+    store::ZoneStore,
+};
+
+/// Default interval in seconds in which old packets are removed.
+const DEFAULT_INTERVAL_SECS: u64 = 60 * 60;
+/// The number of packets read, and removed, at a time.
+const PAGE_SIZE: usize = 1000;
+/// The number of expired packets that are listed in a [`GcReport`].
+const SAMPLE_SIZE: usize = 100;
+
+/// Config for removing old packets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Remove packets with a timestamp older than this many seconds.
+    pub max_age_secs: u64,
+    /// Interval in seconds in which old packets are removed (defaults to 3600).
+    pub interval_secs: Option<u64>,
+}
+
+/// The packets a garbage collection removed, or would remove in a dry run
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct GcReport {
+    /// Whether this was a dry run, which removed nothing
+    pub dry_run: bool,
+    /// The number of packets that were checked
+    pub checked: u64,
+    /// The number of packets older than the maximum age
+    pub expired: u64,
+    /// The first 100 of the packets older than the maximum age
+    pub sample: Vec<ExpiredPacket>,
+}
+
+/// A packet older than the maximum age
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct ExpiredPacket {
+    /// The z-base-32 encoded public key
+    pub public_key: String,
+    /// The timestamp of the packet, in microseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+/// Get the oldest timestamp of a packet younger than `max_age`, in microseconds since the unix
+/// epoch.
+pub(crate) fn min_timestamp(max_age: Duration) -> u64 {
+    SystemTime::now()
+        .checked_sub(max_age)
+        .and_then(|since| since.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Remove the packets older than `max_age` from `store`, or only find them if `dry_run` is set.
+///
+/// The age is the time since the timestamp of a packet, so a republish with a new timestamp
+/// resets it. The packets are removed a page at a time, and only a sample of them is kept in the
+/// report.
+pub(crate) async fn gc(store: &ZoneStore, max_age: Duration, dry_run: bool) -> Result<GcReport> {
+    let min_timestamp = min_timestamp(max_age);
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut after = None;
+    loop {
+        let packets = store.raw_packets_after(after, PAGE_SIZE).await?;
+        let Some((last, _)) = packets.last() else {
+            break;
+        };
+        after = Some(*last);
+        report.checked += packets.len() as u64;
+        let mut expired: Vec<_> = packets
+            .into_iter()
+            .filter(|(_, bytes)| timestamp(bytes).is_some_and(|t| t < min_timestamp))
+            .collect();
+        if !dry_run {
+            // the next page starts after this one, so removing its packets skips none.
+            // a packet that was republished since is kept
+            let removed: HashSet<_> = store
+                .remove_unchanged(&expired)
+                .await?
+                .into_iter()
+                .collect();
+            expired.retain(|(pubkey, _)| removed.contains(pubkey));
+        }
+        for (pubkey, bytes) in expired {
+            let timestamp = timestamp(&bytes).expect("checked above");
+            if !dry_run {
+                store.events().send(ServerEvent::PacketExpired {
+                    pubkey: pubkey.to_z32(),
+                    timestamp,
+                });
+            }
+            report.expired += 1;
+            if report.sample.len() < SAMPLE_SIZE {
+                report.sample.push(ExpiredPacket {
+                    public_key: pubkey.to_z32(),
+                    timestamp,
+                });
+            }
+        }
+    }
+    if !dry_run {
+        inc_by!(Metrics, store_packets_expired, report.expired);
+        StoreMetrics::set_active_packets(report.checked - report.expired);
+    }
+    Ok(report)
+}
+
+/// Get the timestamp of the bytes of a signed packet, which follows the key and the signature.
+fn timestamp(bytes: &[u8]) -> Option<u64> {
+    let timestamp = bytes.get(96..104)?;
+    Some(u64::from_be_bytes(timestamp.try_into().ok()?))
+}
+
+/// Remove old packets in an interval, until the task is aborted.
+pub(crate) async fn run(config: RetentionConfig, store: ZoneStore) {
+    let max_age = Duration::from_secs(config.max_age_secs);
+    let interval = Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS));
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if !store.is_leader().await {
+            continue;
+        }
+        match gc(&store, max_age, false).await {
+            Ok(report) if report.expired == 0 => {}
+            Ok(report) => info!(
+                "removed {} of {} packets older than {}s",
+                report.expired, report.checked, config.max_age_secs
+            ),
+            Err(err) => warn!("failed to remove old packets: {err:#}"),
+        }
+    }
+}
+
+/// Remove the packets older than `max_age` from the packet database at `path`, or only find them
+/// if `dry_run` is set, and compact the database after removing packets.
+///
+/// This fails if the database is opened by a running server.
+pub async fn gc_store(path: &Path, max_age: Duration, dry_run: bool) -> Result<GcReport> {
+    ensure!(path.exists(), "no packet database at {}", path.display());
+    let store = ZoneStore::persistent(path)
+        .context("failed to open the packet database, is the server running?")?;
+    let report = gc(&store, max_age, dry_run).await;
+    store.close().await?;
+    let report = report?;
+    if !dry_run {
+        // compaction needs exclusive access to the database, which the server never has
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut db = redb::Database::create(&path)?;
+            db.compact()?;
+            Ok(())
+        })
+        .await?
+        .context("failed to compact the packet database")?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use pkarr::{Keypair, SignedPacket};
+
+    use super::*;
+    use crate::store::PacketSource;
+
+    /// A signed packet without records, with its timestamp `offset_secs` from now.
+    fn packet_at(keypair: &Keypair, offset_secs: i64) -> Result<SignedPacket> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+        let timestamp = (now.as_micros() as i64 + offset_secs * 1_000_000) as u64;
+        let encoded = pkarr::dns::Packet::new_reply(0).build_bytes_vec_compressed()?;
+        let mut signable = format!("3:seqi{timestamp}e1:v{}:", encoded.len()).into_bytes();
+        signable.extend(&encoded);
+        let signature = keypair.sign(&signable);
+        let mut bytes = keypair.public_key().to_bytes().to_vec();
+        bytes.extend(signature.to_bytes());
+        bytes.extend(timestamp.to_be_bytes());
+        bytes.extend(encoded);
+        Ok(SignedPacket::from_bytes(&bytes.into())?)
+    }
+
+    #[tokio::test]
+    async fn gc_old_packets() -> Result<()> {
+        let store = ZoneStore::in_memory()?;
+        let old = Keypair::random();
+        let new = Keypair::random();
+        store
+            .insert(packet_at(&old, -7200)?, PacketSource::Import)
+            .await?;
+        store
+            .insert(packet_at(&new, -60)?, PacketSource::Import)
+            .await?;
+        let max_age = Duration::from_secs(3600);
+
+        let report = gc(&store, max_age, true).await?;
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.expired, 1);
+        assert_eq!(report.sample[0].public_key, old.public_key().to_z32());
+        assert_eq!(store.packet_count().await?, 2);
+
+        let report = gc(&store, max_age, false).await?;
+        assert_eq!(report.expired, 1);
+        assert_eq!(store.packet_count().await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gc_sample() -> Result<()> {
+        let store = ZoneStore::in_memory()?;
+        let count = SAMPLE_SIZE as u64 + 20;
+        for _ in 0..count {
+            let packet = packet_at(&Keypair::random(), -7200)?;
+            store.insert(packet, PacketSource::Import).await?;
+        }
+
+        let report = gc(&store, Duration::from_secs(3600), false).await?;
+        assert_eq!(report.expired, count);
+        assert_eq!(report.sample.len(), SAMPLE_SIZE);
+        assert_eq!(store.packet_count().await?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+//! Partitioning of the pubkeys over a fleet of servers with a consistent hash ring
+//!
+//! Servers with a [`RingConfig`] place themselves on a hash ring, each at a number of points
+//! derived from its URL. A pubkey is owned by the server of the first point at or after the hash
+//! of the pubkey, and only the owner stores its packet: publishes to other servers are forwarded
+//! to the owner, and lookups of keys owned by other servers fetch the packet from the owner. This
+//! spreads the writes over the fleet, and adding a server only moves the keys of its own points.
+//!
+//! The other servers are checked periodically, and the points of servers that fail their check or
+//! a request are skipped, so their keys are owned by the server of the next point until they are
+//! healthy again.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{ensure, Context, Result};
+use bytes::Bytes;
+use http::{header, StatusCode};
+use iroh_metrics::inc;
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{
+    metrics::{Metrics, PeerKind, PeerMetrics},
+    proxy::HttpClient,
+    util::PublicKeyBytes,
+};
+
+/// The header that marks a request forwarded by another server of the ring.
+///
+/// Forwarded requests are always handled locally, so that servers with different views of the
+/// ring don't forward a request in circles.
+pub const FORWARDED_HEADER: &str = "x-iroh-dns-ring-forwarded";
+/// Default number of points of each server on the ring.
+const DEFAULT_VNODES: u32 = 64;
+/// Default time in seconds that packets fetched from their owner are cached.
+const DEFAULT_CACHE_TTL_SECS: u64 = 30;
+/// Default interval in seconds of the health checks of the other servers.
+const DEFAULT_HEALTH_CHECK_SECS: u64 = 10;
+/// Timeout of the requests to other servers.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Config for partitioning the pubkeys over a fleet of servers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RingConfig {
+    /// The HTTP base URLs of all servers of the ring, including this one.
+    ///
+    /// All servers must have the same list, in any order, to agree on the owners of the keys.
+    pub nodes: Vec<Url>,
+    /// The URL of this server, one of `nodes`.
+    pub local: Url,
+    /// The number of points of each server on the ring (defaults to 64).
+    ///
+    /// More points spread the keys more evenly over the servers.
+    pub vnodes: Option<u32>,
+    /// Time in seconds that packets fetched from their owner are cached for DNS queries
+    /// (defaults to 30).
+    pub cache_ttl_secs: Option<u64>,
+    /// Interval in seconds of the health checks of the other servers (defaults to 10, disabled
+    /// if 0).
+    ///
+    /// Servers that fail a check or a request don't own keys until they pass a check again.
+    #[serde(default)]
+    pub health_check_secs: Option<u64>,
+    /// The proxy for the requests to the other servers (direct if unset).
+    ///
+    /// `http://`, `https://`, `socks5://` and `socks5h://` proxies are supported. The mainline
+    /// DHT is never proxied.
+    #[serde(default)]
+    pub proxy: Option<Url>,
+}
+
+/// A consistent hash ring of servers, see the [module docs](self).
+#[derive(Debug)]
+pub struct Ring {
+    nodes: Vec<Url>,
+    /// The index in `nodes` of the server of each point
+    points: BTreeMap<u64, usize>,
+    /// The index of this server in `nodes`
+    local: usize,
+    /// Whether each server in `nodes` passed its last health check or request
+    up: Vec<AtomicBool>,
+    cache_ttl: Duration,
+    health_check_interval: Duration,
+    client: HttpClient,
+}
+
+impl Ring {
+    /// Create the ring of `config`.
+    pub fn new(config: &RingConfig) -> Result<Self> {
+        let local = config
+            .nodes
+            .iter()
+            .position(|node| node == &config.local)
+            .with_context(|| format!("ring.local {} is not one of ring.nodes", config.local))?;
+        let vnodes = config.vnodes.unwrap_or(DEFAULT_VNODES);
+        ensure!(vnodes > 0, "ring.vnodes must be at least 1");
+        let mut points = BTreeMap::new();
+        for (index, node) in config.nodes.iter().enumerate() {
+            for vnode in 0..vnodes {
+                points.insert(hash(format!("{node}#{vnode}").as_bytes()), index);
+            }
+        }
+        let client = HttpClient::new(config.proxy.as_ref(), REQUEST_TIMEOUT)?;
+        Ok(Self {
+            nodes: config.nodes.clone(),
+            points,
+            local,
+            up: config.nodes.iter().map(|_| AtomicBool::new(true)).collect(),
+            cache_ttl: Duration::from_secs(config.cache_ttl_secs.unwrap_or(DEFAULT_CACHE_TTL_SECS)),
+            health_check_interval: Duration::from_secs(
+                config
+                    .health_check_secs
+                    .unwrap_or(DEFAULT_HEALTH_CHECK_SECS),
+            ),
+            client,
+        })
+    }
+
+    /// Get the URL of the server that owns `pubkey`, or `None` if it is this server.
+    ///
+    /// The points of unhealthy servers are skipped.
+    pub fn owner(&self, pubkey: &PublicKeyBytes) -> Option<&Url> {
+        let hash = hash(pubkey.as_bytes());
+        let index = self
+            .points
+            .range(hash..)
+            .chain(self.points.range(..hash))
+            .map(|(_, index)| *index)
+            .find(|index| *index == self.local || self.up[*index].load(Ordering::Relaxed))
+            .expect("this server is on the ring");
+        (index != self.local).then(|| &self.nodes[index])
+    }
+
+    /// Record whether the server `node` is healthy.
+    fn set_up(&self, node: &Url, up: bool) {
+        let Some(index) = self.nodes.iter().position(|n| n == node) else {
+            return;
+        };
+        if self.up[index].swap(up, Ordering::Relaxed) != up {
+            if up {
+                info!(%node, "ring server is healthy again");
+            } else {
+                warn!(%node, "ring server is unhealthy, skipping it");
+            }
+        }
+        PeerMetrics::set_up(PeerKind::Ring, node.as_str(), up);
+    }
+
+    /// Check the health of the other servers periodically.
+    ///
+    /// Returns immediately if the health checks are disabled.
+    pub(crate) async fn run_health_checks(self: Arc<Self>) {
+        if self.health_check_interval.is_zero() {
+            return;
+        }
+        let mut interval = tokio::time::interval(self.health_check_interval);
+        loop {
+            interval.tick().await;
+            for (index, node) in self.nodes.iter().enumerate() {
+                if index == self.local {
+                    continue;
+                }
+                let res = self.check(node).await;
+                if let Err(err) = &res {
+                    debug!(%node, "ring health check failed: {err:#}");
+                }
+                self.set_up(node, res.is_ok());
+            }
+        }
+    }
+
+    /// Check that the server `node` is up.
+    async fn check(&self, node: &Url) -> Result<()> {
+        self.client
+            .get(node.join("healthcheck")?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Time that packets fetched from their owner are cached.
+    pub(crate) fn cache_ttl(&self) -> Duration {
+        self.cache_ttl
+    }
+
+    /// Fetch the packet of `pubkey` from its owner `node`.
+    pub(crate) async fn fetch(
+        &self,
+        node: &Url,
+        pubkey: &PublicKeyBytes,
+    ) -> Result<Option<SignedPacket>> {
+        inc!(Metrics, ring_lookups_forwarded);
+        debug!(%node, %pubkey, "fetch packet from the owner");
+        let res = async {
+            let res = self
+                .client
+                .get(pkarr_url(node, pubkey)?)
+                .header(FORWARDED_HEADER, "1")
+                .send()
+                .await?;
+            if res.status() == StatusCode::NOT_FOUND {
+                return anyhow::Ok(None);
+            }
+            let body = res.error_for_status()?.bytes().await?;
+            let key = pkarr::PublicKey::try_from(pubkey.as_bytes())?;
+            Ok(Some(SignedPacket::from_relay_payload(&key, &body)?))
+        }
+        .await;
+        if res.is_err() {
+            inc!(Metrics, ring_requests_failed);
+            self.set_up(node, false);
+        }
+        res.with_context(|| format!("failed to fetch the packet of {pubkey} from {node}"))
+    }
+
+    /// Forward the publish of `body` for `pubkey` to its owner `node`, and return its response.
+    pub(crate) async fn forward_publish(
+        &self,
+        node: &Url,
+        pubkey: &PublicKeyBytes,
+        body: Bytes,
+    ) -> Result<http::Response<Bytes>> {
+        inc!(Metrics, ring_publishes_forwarded);
+        debug!(%node, %pubkey, "forward publish to the owner");
+        let res = async {
+            let res = self
+                .client
+                .put(pkarr_url(node, pubkey)?)
+                .header(FORWARDED_HEADER, "1")
+                .body(body)
+                .send()
+                .await?;
+            let mut response = http::Response::builder().status(res.status());
+            if let Some(content_type) = res.headers().get(header::CONTENT_TYPE) {
+                response = response.header(header::CONTENT_TYPE, content_type);
+            }
+            anyhow::Ok(response.body(res.bytes().await?)?)
+        }
+        .await;
+        if res.is_err() {
+            inc!(Metrics, ring_requests_failed);
+            self.set_up(node, false);
+        }
+        res.with_context(|| format!("failed to forward the publish of {pubkey} to {node}"))
+    }
+}
+
+fn pkarr_url(node: &Url, pubkey: &PublicKeyBytes) -> Result<Url> {
+    Ok(node.join(&format!("pkarr/{}", pubkey.to_z32()))?)
+}
+
+/// The position of `data` on the ring.
+fn hash(data: &[u8]) -> u64 {
+    let hash = blake3::hash(data);
+    u64::from_be_bytes(hash.as_bytes()[..8].try_into().expect("hash is 32 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(nodes: &[&str], local: &str) -> Result<Ring> {
+        Ring::new(&RingConfig {
+            nodes: nodes
+                .iter()
+                .map(|node| node.parse())
+                .collect::<Result<_, _>>()?,
+            local: local.parse()?,
+            vnodes: None,
+            cache_ttl_secs: None,
+            health_check_secs: None,
+            proxy: None,
+        })
+    }
+
+    #[test]
+    fn owners() -> Result<()> {
+        let nodes = ["http://a:8080/", "http://b:8080/", "http://c:8080/"];
+        let rings = nodes
+            .iter()
+            .map(|local| ring(&nodes, local))
+            .collect::<Result<Vec<_>>>()?;
+        let mut owned = [0; 3];
+        for _ in 0..300 {
+            let pubkey = PublicKeyBytes::from(pkarr::Keypair::random().public_key());
+            // exactly one server owns the key, and the others agree on it
+            let local: Vec<_> = rings
+                .iter()
+                .enumerate()
+                .filter(|(_, ring)| ring.owner(&pubkey).is_none())
+                .map(|(index, _)| index)
+                .collect();
+            assert_eq!(local.len(), 1);
+            for ring in &rings {
+                if let Some(owner) = ring.owner(&pubkey) {
+                    assert_eq!(owner.as_str(), nodes[local[0]]);
+                }
+            }
+            owned[local[0]] += 1;
+        }
+        assert!(owned.iter().all(|count| *count > 30), "{owned:?}");
+
+        assert!(ring(&nodes, "http://d:8080/").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unhealthy_owners() -> Result<()> {
+        let nodes = ["http://a:8080/", "http://b:8080/", "http://c:8080/"];
+        let a = ring(&nodes, nodes[0])?;
+        let pubkeys: Vec<_> = (0..300)
+            .map(|_| PublicKeyBytes::from(pkarr::Keypair::random().public_key()))
+            .collect();
+        let owners: Vec<_> = pubkeys
+            .iter()
+            .map(|pubkey| a.owner(pubkey).cloned())
+            .collect();
+        let b: Url = nodes[1].parse()?;
+        assert!(owners.contains(&Some(b.clone())));
+
+        // the keys of an unhealthy server move to the other servers, the others stay
+        a.set_up(&b, false);
+        for (pubkey, owner) in pubkeys.iter().zip(&owners) {
+            match owner {
+                Some(owner) if owner == &b => assert_ne!(a.owner(pubkey), Some(&b)),
+                owner => assert_eq!(a.owner(pubkey), owner.as_ref()),
+            }
+        }
+
+        // and move back once it is healthy again
+        a.set_up(&b, true);
+        for (pubkey, owner) in pubkeys.iter().zip(&owners) {
+            assert_eq!(a.owner(pubkey), owner.as_ref());
+        }
+
+        // this server owns all keys if all others are unhealthy
+        a.set_up(&b, false);
+        a.set_up(&nodes[2].parse()?, false);
+        assert!(pubkeys.iter().all(|pubkey| a.owner(pubkey).is_none()));
+        Ok(())
+    }
+}
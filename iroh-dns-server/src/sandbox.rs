@@ -0,0 +1,640 @@
+//! Sandboxing of the server process
+//!
+//! With a [`SandboxConfig`], the process can only access the files it needs, and can't make
+//! system calls that a DNS server has no use for, e.g. to start other programs, to listen on new
+//! sockets or to debug other processes. This limits what an attacker can do after exploiting a
+//! vulnerability in the server.
+//!
+//! On Linux, the file access is restricted with Landlock, and the system calls with a seccomp
+//! filter, which also denies new IP sockets unless the server connects to other servers, e.g. with
+//! the mainline DHT or a Postgres store. On OpenBSD, the file access is restricted with
+//! `unveil(2)`, and the system calls with `pledge(2)`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::Config;
+
+/// Config for sandboxing the server process
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SandboxConfig {
+    /// Fail to start if the platform or the kernel doesn't support the sandbox, instead of
+    /// logging a warning.
+    #[serde(default)]
+    pub required: bool,
+    /// Additional files and directories that can be read.
+    ///
+    /// The config file, the data directory, the certificate files and the system directories of
+    /// the resolver and the TLS roots can always be read.
+    #[serde(default)]
+    pub read_paths: Vec<PathBuf>,
+    /// Additional files and directories that can be read and written.
+    ///
+    /// The data directory, and the directories of the log files and unix sockets can always be
+    /// written.
+    #[serde(default)]
+    pub write_paths: Vec<PathBuf>,
+    /// Allow starting the new process of an upgrade on `SIGUSR2`, which also needs to listen on
+    /// new sockets.
+    #[serde(default)]
+    pub allow_upgrades: bool,
+}
+
+/// The files and directories that are read at startup on Linux
+#[cfg(target_os = "linux")]
+const SYSTEM_READ_PATHS: &[&str] = &[
+    "/etc",
+    "/usr",
+    "/lib",
+    "/lib64",
+    "/proc/self",
+    "/sys/fs/cgroup",
+    "/dev/urandom",
+];
+
+/// The files and directories that are read at startup on OpenBSD
+#[cfg(target_os = "openbsd")]
+const SYSTEM_READ_PATHS: &[&str] = &["/etc", "/usr/lib", "/usr/libexec", "/dev/urandom"];
+
+/// The files that are written by the libraries
+#[cfg(any(target_os = "linux", target_os = "openbsd"))]
+const SYSTEM_WRITE_PATHS: &[&str] = &["/dev/null"];
+
+/// How [`restrict_files`] restricted the file access, logged by [`restrict_syscalls`] once the
+/// logging is set up
+static FILE_ACCESS: OnceLock<Result<&str, &str>> = OnceLock::new();
+
+/// Restrict the files that the process can access to the ones needed by `config`.
+///
+/// Does nothing if [`Config::sandbox`] is not set. The restriction can't be lifted, and is
+/// inherited by new threads, so this is called before the async runtime starts its threads.
+/// Since this usually happens before the logging is set up, the outcome is logged later, by the
+/// server.
+#[cfg(target_os = "linux")]
+pub fn restrict_files(config: &Config, config_path: Option<&Path>) -> Result<()> {
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+
+    let Some(sandbox) = &config.sandbox else {
+        return Ok(());
+    };
+    let paths = Paths::new(config, config_path, sandbox)?;
+    paths.create_write_dirs()?;
+    let abi = ABI::V5;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(&paths.read, AccessFs::from_read(abi)))?
+        .add_rules(path_beneath_rules(&paths.write, AccessFs::from_all(abi)))?
+        .restrict_self()?;
+    let outcome = match status.ruleset {
+        RulesetStatus::NotEnforced => {
+            check_required(sandbox, "Landlock is not enabled in the kernel")?;
+            Err("Landlock is not enabled in the kernel, file access is not restricted")
+        }
+        RulesetStatus::PartiallyEnforced => {
+            Ok("restricted file access, partially because the kernel has an older Landlock")
+        }
+        RulesetStatus::FullyEnforced => Ok("restricted file access"),
+    };
+    FILE_ACCESS.set(outcome).ok();
+    Ok(())
+}
+
+/// Restrict the files that the process can access to the ones needed by `config`.
+///
+/// Does nothing if [`Config::sandbox`] is not set. The restriction can't be lifted.
+#[cfg(target_os = "openbsd")]
+pub fn restrict_files(config: &Config, config_path: Option<&Path>) -> Result<()> {
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt};
+
+    use anyhow::Context;
+
+    let Some(sandbox) = &config.sandbox else {
+        return Ok(());
+    };
+    let paths = Paths::new(config, config_path, sandbox)?;
+    paths.create_write_dirs()?;
+    let read_permissions = if sandbox.allow_upgrades { "rx" } else { "r" };
+    for (paths, permissions) in [(&paths.read, read_permissions), (&paths.write, "rwc")] {
+        let permissions = CString::new(permissions)?;
+        for path in paths {
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: both arguments are valid C strings
+            if unsafe { nix::libc::unveil(c_path.as_ptr(), permissions.as_ptr()) } == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err)
+                        .with_context(|| format!("failed to unveil {}", path.display()));
+                }
+            }
+        }
+    }
+    // SAFETY: null pointers lock the unveiled paths
+    if unsafe { nix::libc::unveil(std::ptr::null(), std::ptr::null()) } == -1 {
+        return Err(io::Error::last_os_error()).context("failed to lock the unveiled paths");
+    }
+    FILE_ACCESS.set(Ok("restricted file access")).ok();
+    Ok(())
+}
+
+/// Restricting the file access is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+pub fn restrict_files(config: &Config, _config_path: Option<&Path>) -> Result<()> {
+    match &config.sandbox {
+        Some(sandbox) => {
+            check_required(sandbox, "sandboxing is not supported on this platform")?;
+            FILE_ACCESS
+                .set(Err("file access is not restricted on this platform"))
+                .ok();
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// The system calls that are denied by the seccomp filter
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const DENIED_SYSCALLS: &[nix::libc::c_long] = {
+    use nix::libc::*;
+    &[
+        // inspecting and modifying other processes
+        SYS_ptrace,
+        SYS_process_vm_readv,
+        SYS_process_vm_writev,
+        // changing the namespaces and mounts
+        SYS_unshare,
+        SYS_setns,
+        SYS_mount,
+        SYS_umount2,
+        SYS_pivot_root,
+        SYS_chroot,
+        SYS_open_by_handle_at,
+        // changing the kernel and the system
+        SYS_init_module,
+        SYS_finit_module,
+        SYS_delete_module,
+        SYS_kexec_load,
+        SYS_bpf,
+        SYS_perf_event_open,
+        SYS_userfaultfd,
+        SYS_keyctl,
+        SYS_add_key,
+        SYS_request_key,
+        SYS_reboot,
+        SYS_swapon,
+        SYS_swapoff,
+        SYS_acct,
+        SYS_quotactl,
+        SYS_sethostname,
+        SYS_setdomainname,
+        SYS_settimeofday,
+        SYS_clock_settime,
+        SYS_clock_adjtime,
+        SYS_adjtimex,
+    ]
+};
+
+/// The system calls that are denied by the seccomp filter unless upgrades are allowed
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const UPGRADE_SYSCALLS: &[nix::libc::c_long] = &[
+    nix::libc::SYS_execve,
+    nix::libc::SYS_execveat,
+    nix::libc::SYS_listen,
+];
+
+/// Deny the system calls that the server doesn't make once it is started.
+///
+/// Applies to all threads of the process. Also logs how [`restrict_files`] restricted the file
+/// access.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub(crate) fn restrict_syscalls(sandbox: &SandboxConfig, connects: bool) -> Result<()> {
+    use anyhow::Context;
+    use tracing::info;
+
+    report_file_access();
+    let program = seccomp_filter(sandbox, connects)?;
+    seccompiler::apply_filter_all_threads(&program).context("failed to install seccomp filter")?;
+    info!(connects, "restricted system calls");
+    Ok(())
+}
+
+/// The seccomp filter of the system calls that are denied once the server is started.
+///
+/// New sockets can only be unix, IP and netlink sockets, and no raw IP sockets. Unless the server
+/// `connects` to other servers or upgrades are allowed, IP sockets can't be created or bound at
+/// all.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn seccomp_filter(sandbox: &SandboxConfig, connects: bool) -> Result<seccompiler::BpfProgram> {
+    use nix::libc::{SYS_bind, SYS_socket, AF_INET, AF_INET6, AF_NETLINK, AF_UNIX, SOCK_RAW};
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule,
+    };
+
+    let mut denied = DENIED_SYSCALLS.to_vec();
+    if !sandbox.allow_upgrades {
+        denied.extend(UPGRADE_SYSCALLS);
+    }
+    let mut rules: std::collections::BTreeMap<_, _> = denied
+        .into_iter()
+        .map(|syscall| (syscall, vec![]))
+        .collect();
+
+    let domain =
+        |op, domain: i32| SeccompCondition::new(0, SeccompCmpArgLen::Dword, op, domain as u64);
+    let raw = || {
+        SeccompCondition::new(
+            1,
+            SeccompCmpArgLen::Dword,
+            SeccompCmpOp::MaskedEq(0xf),
+            SOCK_RAW as u64,
+        )
+    };
+    let mut socket_rules = vec![
+        SeccompRule::new(vec![
+            domain(SeccompCmpOp::Ne, AF_UNIX)?,
+            domain(SeccompCmpOp::Ne, AF_INET)?,
+            domain(SeccompCmpOp::Ne, AF_INET6)?,
+            domain(SeccompCmpOp::Ne, AF_NETLINK)?,
+        ])?,
+        SeccompRule::new(vec![domain(SeccompCmpOp::Eq, AF_INET)?, raw()?])?,
+        SeccompRule::new(vec![domain(SeccompCmpOp::Eq, AF_INET6)?, raw()?])?,
+    ];
+    if !connects && !sandbox.allow_upgrades {
+        socket_rules.push(SeccompRule::new(vec![domain(SeccompCmpOp::Eq, AF_INET)?])?);
+        socket_rules.push(SeccompRule::new(vec![domain(SeccompCmpOp::Eq, AF_INET6)?])?);
+        rules.insert(SYS_bind, vec![]);
+    }
+    rules.insert(SYS_socket, socket_rules);
+
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(nix::libc::EPERM as u32),
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    Ok(BpfProgram::try_from(filter)?)
+}
+
+/// Deny the system calls that the server doesn't make once it is started.
+///
+/// Also logs how [`restrict_files`] restricted the file access.
+#[cfg(target_os = "openbsd")]
+pub(crate) fn restrict_syscalls(sandbox: &SandboxConfig, _connects: bool) -> Result<()> {
+    use std::{ffi::CString, io};
+
+    use anyhow::Context;
+    use tracing::info;
+
+    report_file_access();
+    let mut promises = String::from("stdio rpath wpath cpath flock unix inet dns");
+    if sandbox.allow_upgrades {
+        promises.push_str(" proc exec");
+    }
+    let promises = CString::new(promises)?;
+    // SAFETY: the promises are a valid C string, and a null pointer keeps the exec promises
+    if unsafe { nix::libc::pledge(promises.as_ptr(), std::ptr::null()) } == -1 {
+        return Err(io::Error::last_os_error()).context("failed to pledge");
+    }
+    info!("restricted system calls");
+    Ok(())
+}
+
+/// Restricting the system calls is not supported on this platform.
+#[cfg(not(any(
+    all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ),
+    target_os = "openbsd"
+)))]
+pub(crate) fn restrict_syscalls(sandbox: &SandboxConfig, _connects: bool) -> Result<()> {
+    report_file_access();
+    check_required(
+        sandbox,
+        "restricting system calls is not supported on this platform",
+    )?;
+    tracing::warn!("system calls are not restricted on this platform");
+    Ok(())
+}
+
+/// The sections of the config whose tasks connect to other servers once the server is started, as
+/// JSON pointers
+const CONNECTING_SECTIONS: &[&str] = &[
+    "/otlp",
+    "/sentry",
+    "/probe",
+    "/gossip",
+    "/sync",
+    "/replicas",
+    "/secondary",
+    "/query_log",
+    "/usage",
+    "/anonymous_stats",
+    "/mirror",
+    "/metrics/push",
+];
+
+/// Whether the server connects to other servers once it is started, e.g. to look up packets on
+/// the mainline DHT, to query a Postgres store or to renew certificates with ACME.
+pub(crate) fn connects(config: &Config) -> Result<bool> {
+    let value = serde_json::to_value(config)?;
+    let is = |pointer, expected: Value| value.pointer(pointer) == Some(&expected);
+    let set = |pointer: &&str| value.pointer(pointer).is_some_and(|v| !v.is_null());
+    Ok(is("/mainline/enabled", Value::Bool(true))
+        || is("/store/backend", "postgres".into())
+        || CONNECTING_SECTIONS.iter().any(set)
+        || any_field(&value, &|key, value| match key {
+            "cert_mode" => value == "lets_encrypt" || value == "lets_encrypt_dns",
+            "ocsp_stapling" => value == true,
+            // secrets that are fetched again in an interval
+            "vault" | "aws" => true,
+            _ => false,
+        }))
+}
+
+/// Whether `value` has a field, at any depth, for which `f` is true.
+fn any_field(value: &Value, f: &dyn Fn(&str, &Value) -> bool) -> bool {
+    match value {
+        Value::Object(fields) => fields
+            .iter()
+            .any(|(key, value)| f(key, value) || any_field(value, f)),
+        Value::Array(values) => values.iter().any(|value| any_field(value, f)),
+        _ => false,
+    }
+}
+
+/// Log how [`restrict_files`] restricted the file access.
+fn report_file_access() {
+    match FILE_ACCESS.get() {
+        Some(Ok(outcome)) => tracing::info!("{outcome}"),
+        Some(Err(outcome)) => tracing::warn!("{outcome}"),
+        None => tracing::warn!("file access is not restricted"),
+    }
+}
+
+/// Fail if the sandbox is required, since it is not supported for `reason`.
+#[cfg(not(target_os = "openbsd"))]
+fn check_required(sandbox: &SandboxConfig, reason: &str) -> Result<()> {
+    if sandbox.required {
+        anyhow::bail!("{reason}, but the sandbox is required");
+    }
+    Ok(())
+}
+
+/// The files and directories that the server accesses
+#[cfg(any(target_os = "linux", target_os = "openbsd"))]
+#[derive(Debug, Default, PartialEq)]
+struct Paths {
+    /// Can be read
+    read: Vec<PathBuf>,
+    /// Can be read, written, created and removed
+    write: Vec<PathBuf>,
+    /// Directories that are created if they don't exist, so that they can be allowed
+    write_dirs: Vec<PathBuf>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "openbsd"))]
+impl Paths {
+    fn new(config: &Config, config_path: Option<&Path>, sandbox: &SandboxConfig) -> Result<Self> {
+        let mut paths = Self::default();
+        paths
+            .read
+            .extend(SYSTEM_READ_PATHS.iter().map(PathBuf::from));
+        if let Some(path) = config_path {
+            // the config file and the files it includes
+            let files = crate::config::config_files(path).unwrap_or_else(|_| vec![path.into()]);
+            paths.read.extend(files);
+            // read again when the config is reloaded
+            paths
+                .read
+                .extend(crate::config::interpolated_files(path).unwrap_or_default());
+        }
+        if let Some(https) = &config.https {
+            let acme = https
+                .acme
+                .iter()
+                .chain(https.certificates.iter().flat_map(|c| &c.acme));
+            paths.read.extend(
+                [&https.cert_path, &https.key_path]
+                    .into_iter()
+                    .chain(
+                        https
+                            .certificates
+                            .iter()
+                            .flat_map(|c| [&c.cert_path, &c.key_path]),
+                    )
+                    .chain(acme.map(|acme| &acme.ca_cert))
+                    .flatten()
+                    .cloned(),
+            );
+            paths
+                .read
+                .extend(https.client_auth.iter().map(|auth| auth.ca_cert.clone()));
+        }
+        paths.read.extend(
+            config
+                .mainline
+                .as_ref()
+                .and_then(|mainline| mainline.bootstrap_file.clone()),
+        );
+        // the list is read again after it is replaced in its directory
+        paths.read.extend(
+            config
+                .ip_reputation
+                .as_ref()
+                .and_then(|reputation| reputation.path.parent())
+                .map(|dir| match dir.as_os_str().is_empty() {
+                    true => PathBuf::from("."),
+                    false => dir.to_path_buf(),
+                }),
+        );
+        if sandbox.allow_upgrades {
+            paths.read.push(std::env::current_exe()?);
+        }
+        paths.read.extend(sandbox.read_paths.iter().cloned());
+
+        paths
+            .write
+            .extend(SYSTEM_WRITE_PATHS.iter().map(PathBuf::from));
+        paths.write_dirs.push(config.data_dir()?);
+        let files = [
+            Some(config.signed_packet_store_path()?),
+            config
+                .logging
+                .as_ref()
+                .and_then(|logging| logging.file.as_ref())
+                .map(|file| file.path.clone()),
+            config
+                .access_log
+                .as_ref()
+                .and_then(|access_log| access_log.path.clone()),
+            config
+                .abuse_log
+                .as_ref()
+                .and_then(|abuse_log| abuse_log.path.clone()),
+            config
+                .query_log
+                .as_ref()
+                .and_then(|query_log| match &query_log.sink {
+                    crate::query_log::QueryLogSink::File { path } => Some(path.clone()),
+                    _ => None,
+                }),
+            config
+                .http
+                .as_ref()
+                .and_then(|http| http.unix_socket.clone()),
+            config.metrics_unix_socket(),
+        ];
+        // the files are created, and rotated or replaced, in their directories
+        paths
+            .write_dirs
+            .extend(files.into_iter().flatten().map(|file| match file.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            }));
+        paths.write.extend(paths.write_dirs.iter().cloned());
+        // the control socket of BIRD and the command pipe of ExaBGP are written to
+        if let Some(health) = &config.health {
+            let bird = health.bird.as_ref().map(|bird| bird.socket.clone());
+            let exabgp = health.exabgp.as_ref().map(|exabgp| exabgp.pipe.clone());
+            paths.write.extend(bird.into_iter().chain(exabgp));
+        }
+        paths.write.extend(sandbox.write_paths.iter().cloned());
+        Ok(paths)
+    }
+
+    /// Create the directories that are written, so that access to them can be allowed.
+    fn create_write_dirs(&self) -> Result<()> {
+        use anyhow::Context;
+
+        for dir in &self.write_dirs {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "openbsd")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_of_config() -> Result<()> {
+        let config = Config {
+            data_dir: Some("/srv/iroh-dns".into()),
+            ..Default::default()
+        };
+        let sandbox = SandboxConfig {
+            read_paths: vec!["/srv/zones".into()],
+            ..Default::default()
+        };
+        let paths = Paths::new(&config, Some(Path::new("/etc/iroh-dns.toml")), &sandbox)?;
+        assert!(paths.read.contains(&"/etc/iroh-dns.toml".into()));
+        assert!(paths.read.contains(&"/srv/zones".into()));
+        assert!(paths.write.contains(&"/srv/iroh-dns".into()));
+        assert!(paths.write_dirs.contains(&"/srv/iroh-dns".into()));
+        assert!(!paths.write.contains(&"/srv/zones".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn connecting_configs() -> Result<()> {
+        use crate::config::MainlineConfig;
+
+        assert!(!connects(&Config::default())?);
+        let config = Config {
+            mainline: Some(MainlineConfig {
+                enabled: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(connects(&config)?);
+        let config: Config = toml::from_str(
+            r#"
+            [https]
+            port = 443
+            domains = ["dns.example.org"]
+            cert_mode = "lets_encrypt"
+            [dns]
+            port = 53
+            origins = ["dns.example.org."]
+            default_soa = "ns1 hostmaster 0 10800 3600 604800 3600"
+            default_ttl = 900
+            "#,
+        )?;
+        assert!(connects(&config)?);
+        Ok(())
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn denied_syscalls() -> Result<()> {
+        use nix::{
+            errno::Errno,
+            sys::socket::{listen, socket, AddressFamily, SockFlag, SockType},
+        };
+
+        // the filter applies to the thread that installs it, and the threads it spawns
+        let sandboxed = |connects| {
+            let program = seccomp_filter(&SandboxConfig::default(), connects)?;
+            let handle = std::thread::spawn(move || {
+                seccompiler::apply_filter(&program)?;
+                let open = |family, ty| socket(family, ty, SockFlag::empty(), None).map(drop);
+                anyhow::Ok([
+                    open(AddressFamily::Unix, SockType::Datagram),
+                    open(AddressFamily::Inet, SockType::Datagram),
+                    open(AddressFamily::Inet6, SockType::Stream),
+                    open(AddressFamily::Inet, SockType::Raw),
+                    open(AddressFamily::Packet, SockType::Raw),
+                    socket(
+                        AddressFamily::Unix,
+                        SockType::Stream,
+                        SockFlag::empty(),
+                        None,
+                    )
+                    .and_then(|socket| listen(&socket, 1)),
+                ])
+            });
+            handle.join().expect("sandboxed thread panicked")
+        };
+        let [unix, udp, tcp, raw, packet, listen] = sandboxed(false)?;
+        assert_eq!(unix, Ok(()));
+        assert_eq!(udp, Err(Errno::EPERM));
+        assert_eq!(tcp, Err(Errno::EPERM));
+        assert_eq!(raw, Err(Errno::EPERM));
+        assert_eq!(packet, Err(Errno::EPERM));
+        assert_eq!(listen, Err(Errno::EPERM));
+
+        let [unix, udp, tcp, raw, packet, _] = sandboxed(true)?;
+        assert_eq!((unix, udp, tcp), (Ok(()), Ok(()), Ok(())));
+        assert_eq!((raw, packet), (Err(Errno::EPERM), Err(Errno::EPERM)));
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+//! Secrets loaded from files, environment variables, HashiCorp Vault or AWS Secrets Manager
+//!
+//! Secrets in the config, like the metrics credentials, can be set inline or with a
+//! [`SecretSource`]. Secrets from a source are refreshed in an interval, so that rotated
+//! secrets are picked up without a restart.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use parking_lot::RwLock;
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, info_span, warn, Instrument};
+
+/// Interval in which secrets from a [`SecretSource`] are reloaded.
+pub(crate) const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 5);
+/// Timeout for requests to Vault and AWS.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to load a secret from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretSource {
+    /// A file, e.g. provided by systemd credentials or a Kubernetes secret volume
+    ///
+    /// A trailing newline is removed.
+    File(PathBuf),
+    /// An environment variable
+    Env(String),
+    /// A field of a secret in the KV version 2 secrets engine of HashiCorp Vault
+    Vault(VaultSecret),
+    /// A secret in AWS Secrets Manager
+    Aws(AwsSecret),
+}
+
+/// A secret in HashiCorp Vault
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VaultSecret {
+    /// The address of the Vault server, e.g. `https://vault.example.org:8200`
+    pub address: String,
+    /// The API path of the secret, e.g. `secret/data/iroh-dns-server`
+    pub path: String,
+    /// The field of the secret to use
+    pub field: String,
+    /// The environment variable with the Vault token, `VAULT_TOKEN` by default
+    #[serde(default)]
+    pub token_env: Option<String>,
+}
+
+/// A secret in AWS Secrets Manager
+///
+/// The credentials are read from the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// optionally `AWS_SESSION_TOKEN` environment variables.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AwsSecret {
+    /// The name or ARN of the secret
+    pub secret_id: String,
+    /// The AWS region, e.g. `eu-central-1`
+    pub region: String,
+    /// If the secret is a JSON object, the field to use
+    #[serde(default)]
+    pub field: Option<String>,
+}
+
+/// A secret in the config, either set inline or loaded from a [`SecretSource`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum SecretValue {
+    /// The secret itself
+    Value(String),
+    /// Where to load the secret from
+    Source(SecretSource),
+}
+
+impl SecretSource {
+    /// Load the secret.
+    pub async fn load(&self) -> Result<String> {
+        match self {
+            SecretSource::File(path) => {
+                let value = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("failed to read secret from {}", path.display()))?;
+                Ok(value.strip_suffix('\n').unwrap_or(&value).to_string())
+            }
+            SecretSource::Env(var) => std::env::var(var)
+                .with_context(|| format!("failed to read secret from environment variable {var}")),
+            SecretSource::Vault(secret) => secret
+                .load()
+                .await
+                .with_context(|| format!("failed to load secret {} from Vault", secret.path)),
+            SecretSource::Aws(secret) => secret.load().await.with_context(|| {
+                format!(
+                    "failed to load secret {} from AWS Secrets Manager",
+                    secret.secret_id
+                )
+            }),
+        }
+    }
+}
+
+impl VaultSecret {
+    async fn load(&self) -> Result<String> {
+        let token_env = self.token_env.as_deref().unwrap_or("VAULT_TOKEN");
+        let token = std::env::var(token_env)
+            .with_context(|| format!("Vault token not set in {token_env}"))?;
+        let url = format!(
+            "{}/v1/{}",
+            self.address.trim_end_matches('/'),
+            self.path.trim_start_matches('/')
+        );
+        let res: Value = client()?
+            .get(url)
+            .header("X-Vault-Token", token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match &res["data"]["data"][&self.field] {
+            Value::String(value) => Ok(value.clone()),
+            Value::Null => bail!("field {} not found", self.field),
+            _ => bail!("field {} is not a string", self.field),
+        }
+    }
+}
+
+impl AwsSecret {
+    async fn load(&self) -> Result<String> {
+        let credentials = AwsCredentials::from_env()?;
+        let host = format!("secretsmanager.{}.amazonaws.com", self.region);
+        let body = json!({ "SecretId": self.secret_id }).to_string();
+        let amz_date = amz_date(time::OffsetDateTime::now_utc());
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", "secretsmanager.GetSecretValue".to_string()));
+        let authorization = credentials.sign(
+            "secretsmanager",
+            &self.region,
+            "POST",
+            "/",
+            &amz_date,
+            &headers,
+            &body,
+        );
+
+        let mut req = client()?.post(format!("https://{host}/"));
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            req = req.header(name, value);
+        }
+        let res = req
+            .header(http::header::AUTHORIZATION, authorization)
+            .body(body)
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            bail!("{status}: {}", res.text().await.unwrap_or_default());
+        }
+        let res: Value = res.json().await?;
+        let value = res["SecretString"]
+            .as_str()
+            .context("secret has no string value")?;
+        let Some(field) = &self.field else {
+            return Ok(value.to_string());
+        };
+        let value: Value = serde_json::from_str(value).context("secret is not a JSON object")?;
+        value[field]
+            .as_str()
+            .map(ToString::to_string)
+            .with_context(|| format!("field {field} not found"))
+    }
+}
+
+/// The time of a request to AWS, in the format of the `x-amz-date` header.
+pub(crate) fn amz_date(now: time::OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )
+}
+
+/// AWS credentials for signing requests
+pub(crate) struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    pub(crate) session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    /// Read the credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// `AWS_SESSION_TOKEN`.
+    pub(crate) fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).with_context(|| format!("{name} not set"));
+        Ok(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// Create the `Authorization` header for a request without query to `path` of `service`,
+    /// with signature version 4.
+    ///
+    /// The `headers` are signed, and must be lowercase and sorted by name.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn sign(
+        &self,
+        service: &str,
+        region: &str,
+        method: &str,
+        path: &str,
+        amz_date: &str,
+        headers: &[(&str, String)],
+        body: &str,
+    ) -> String {
+        let sha256 = |data: &[u8]| hex::encode(digest(&SHA256, data));
+        let hmac = |key: &[u8], data: &str| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+            hmac::sign(&key, data.as_bytes()).as_ref().to_vec()
+        };
+        let date = &amz_date[..8];
+        let scope = format!("{date}/{region}/{service}/aws4_request");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{}",
+            sha256(body.as_bytes())
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            sha256(canonical_request.as_bytes())
+        );
+        let key = format!("AWS4{}", self.secret_access_key);
+        let key = hmac(key.as_bytes(), date);
+        let key = hmac(&key, region);
+        let key = hmac(&key, service);
+        let key = hmac(&key, "aws4_request");
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key_id
+        )
+    }
+}
+
+fn client() -> Result<reqwest::Client> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    Ok(client)
+}
+
+/// A secret that is kept up to date with its source.
+#[derive(Debug, Clone)]
+pub(crate) struct RefreshingSecret(Arc<RwLock<String>>);
+
+impl RefreshingSecret {
+    /// Load the secret, and reload it in the background if it is from a [`SecretSource`].
+    pub(crate) async fn new(value: &SecretValue) -> Result<Self> {
+        let source = match value {
+            SecretValue::Value(value) => return Ok(Self(Arc::new(RwLock::new(value.clone())))),
+            SecretValue::Source(source) => source.clone(),
+        };
+        let secret = Self(Arc::new(RwLock::new(source.load().await?)));
+        let inner = Arc::downgrade(&secret.0);
+        tokio::spawn(
+            async move {
+                loop {
+                    tokio::time::sleep(REFRESH_INTERVAL).await;
+                    let Some(inner) = inner.upgrade() else {
+                        break;
+                    };
+                    match source.load().await {
+                        Ok(value) if value != *inner.read() => {
+                            info!("secret changed");
+                            *inner.write() = value;
+                        }
+                        Ok(_) => {}
+                        Err(err) => warn!("failed to refresh secret: {err:#}"),
+                    }
+                }
+            }
+            .instrument(info_span!("secret_refresh")),
+        );
+        Ok(secret)
+    }
+
+    /// Get the current value of the secret.
+    pub(crate) fn get(&self) -> String {
+        self.0.read().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn secret_values() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Config {
+            inline: SecretValue,
+            file: SecretValue,
+            vault: SecretValue,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            inline = "hunter2"
+            file = { file = "/run/secrets/token" }
+            vault = { vault = { address = "http://127.0.0.1:8200", path = "secret/data/dns", field = "token" } }
+            "#,
+        )?;
+        assert_eq!(config.inline, SecretValue::Value("hunter2".to_string()));
+        assert_eq!(
+            config.file,
+            SecretValue::Source(SecretSource::File("/run/secrets/token".into()))
+        );
+        assert!(matches!(
+            config.vault,
+            SecretValue::Source(SecretSource::Vault(VaultSecret { field, .. })) if field == "token"
+        ));
+
+        let dir = std::env::temp_dir().join(format!("secret-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join("token");
+        tokio::fs::write(&path, "s3cret\n").await?;
+        let secret = RefreshingSecret::new(&SecretValue::Source(SecretSource::File(path))).await?;
+        assert_eq!(secret.get(), "s3cret");
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -1,73 +1,1080 @@
 //! The main server which combines the DNS and HTTP(S) servers.
 
-use anyhow::Result;
-use iroh_metrics::metrics::start_metrics_server;
-use tracing::info;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Result};
+use hickory_proto::rr::Name;
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
 
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+#[cfg(unix)]
+use crate::handoff;
 use crate::{
-    config::Config,
-    dns::{DnsHandler, DnsServer},
-    http::HttpServer,
-    state::AppState,
-    store::ZoneStore,
+    abuse::{AbuseLog, IpReputation},
+    acl::Acl,
+    analytics::Analytics,
+    anonymous_stats,
+    config::{Config, MetricsConfig},
+    dns::{self, DnsConfig, DnsHandler, DnsHook, DnsServer, NameResolver},
+    events::ServerEvent,
+    health::{Health, HealthTask},
+    history::PacketHistory,
+    hourly_stats::HourlyStats,
+    http::{
+        AuthProvider, AuthProviders, HttpConfig, HttpServer, HttpsConfig, PacketValidator,
+        PacketValidators,
+    },
+    mirror::Mirror,
+    privileges,
+    probe::{self, ProbeTargets},
+    query_log::QueryLog,
+    rate_limit_state::RateLimitState,
+    reload, retention,
+    ring::Ring,
+    sandbox,
+    state::{AppState, TaskError},
+    store::{StoreBackend, ZoneStore},
+    sync, systemd,
+    tenants::Tenants,
+    usage::Usage,
 };
+#[cfg(feature = "mainline")]
+use crate::{bootstrap, dht_publish::DhtPublisher};
+
+/// Spawn the server and run until the `Ctrl-C` or `SIGTERM` signal is received, then shutdown.
+pub async fn run_with_config_until_ctrl_c(config: Config) -> Result<Shutdown> {
+    run_until_ctrl_c(config, None).await
+}
 
-/// Spawn the server and run until the `Ctrl-C` signal is received, then shutdown.
-pub async fn run_with_config_until_ctrl_c(config: Config) -> Result<()> {
-    let mut store = ZoneStore::persistent(Config::signed_packet_store_path()?)?;
-    if let Some(bootstrap) = config.mainline_enabled() {
-        info!("mainline fallback enabled");
-        store = store.with_mainline_fallback(bootstrap);
+/// Like [`run_with_config_until_ctrl_c`], and reload the config from `path` on `SIGHUP`, or when
+/// the file changes if [`Config::watch_config`] is set.
+pub async fn run_with_config_file_until_ctrl_c(config: Config, path: PathBuf) -> Result<Shutdown> {
+    run_until_ctrl_c(config, Some(path)).await
+}
+
+async fn run_until_ctrl_c(config: Config, config_path: Option<PathBuf>) -> Result<Shutdown> {
+    let signal = async {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            res = terminate_signal() => res?,
+        }
+        Ok(())
     };
-    let server = Server::spawn(config, store).await?;
-    tokio::signal::ctrl_c().await?;
-    info!("shutdown");
-    server.shutdown().await?;
+    run_with_config_until(config, config_path, signal).await
+}
+
+/// Open the store database of `config`.
+async fn open_store(config: &Config) -> Result<ZoneStore> {
+    let store_path = config.signed_packet_store_path()?;
+    #[cfg(unix)]
+    {
+        // the old process is given its shutdown timeout to release the store
+        let timeout = config.shutdown_timeout() + Duration::from_secs(30);
+        handoff::open_store(&store_path, timeout).await
+    }
+    #[cfg(not(unix))]
+    ZoneStore::persistent(store_path)
+}
+
+/// Bind the metrics server of `config`, and spawn the task that serves and pushes the metrics.
+///
+/// Returns the bound TCP address of the metrics server, if any.
+#[cfg(feature = "metrics")]
+fn spawn_metrics(
+    config: &Config,
+    task_errors: crate::state::TaskErrors,
+) -> Result<(Option<SocketAddr>, tokio::task::JoinHandle<Result<()>>)> {
+    let metrics_addr = config.metrics_addr();
+    let metrics_listeners = crate::metrics::bind(metrics_addr, config.metrics_unix_socket())?;
+    let metrics_addr = metrics_listeners.tcp_addr()?;
+    let metrics_auth = config.metrics.as_ref().and_then(|m| m.auth.clone());
+    let metrics_otlp = config.metrics.as_ref().and_then(|m| m.otlp.clone());
+    let metrics_push = config.metrics.as_ref().and_then(|m| m.push.clone());
+    let metrics_task = tokio::task::spawn(async move {
+        let push = async {
+            let otlp = async {
+                match metrics_otlp {
+                    Some(otlp) => crate::metrics::push_otlp(otlp).await,
+                    None => Ok(()),
+                }
+            };
+            let prometheus = async {
+                match metrics_push {
+                    Some(push) => crate::metrics::push_prometheus(push).await,
+                    None => Ok(()),
+                }
+            };
+            tokio::try_join!(otlp, prometheus).map(|_| ())
+        };
+        let res = tokio::try_join!(crate::metrics::serve(metrics_listeners, metrics_auth), push);
+        if let Err(err) = &res {
+            task_errors.report("metrics", err);
+        }
+        res?;
+        Ok(())
+    });
+    Ok((metrics_addr, metrics_task))
+}
+
+/// Spawn the server and run until `shutdown` resolves, then shutdown.
+///
+/// If `config_path` is set, the config is reloaded like in [`run_with_config_file_until_ctrl_c`].
+/// `shutdown` is first polled once the server is ready, i.e. when all listeners are bound and
+/// the store is open. On unix, the server hands its listeners to a new process on `SIGUSR2`,
+/// and shuts down once the new process takes over.
+///
+/// Returns whether the shutdown finished within the shutdown timeout.
+pub async fn run_with_config_until(
+    config: Config,
+    config_path: Option<PathBuf>,
+    shutdown: impl Future<Output = Result<()>>,
+) -> Result<Shutdown> {
+    let running_config = serde_json::to_value(&config)?;
+    let watch_config = config.watch_config;
+    let sandbox = match config.sandbox.clone() {
+        Some(sandbox) => Some((sandbox, sandbox::connects(&config)?)),
+        None => None,
+    };
+    #[cfg(unix)]
+    handoff::record_listeners();
+    let store = if config.in_memory_store {
+        ZoneStore::in_memory()?
+    } else {
+        open_store(&config).await?
+    };
+    let server = Server::builder()
+        .config(config)
+        .store(store)
+        .spawn()
+        .await?;
+    systemd::close_unused();
+    if let Some((sandbox, connects)) = &sandbox {
+        sandbox::restrict_syscalls(sandbox, *connects)?;
+    }
+    tokio::spawn(toggle_debug_logging_on_signal());
+    // the reload task holds a clone of the store, which is closed on shutdown
+    let reload_task = config_path.map(|path| {
+        let state = server.state.clone();
+        tokio::spawn(reload::run(path, running_config, state, watch_config))
+    });
+    // the listeners are bound and the store is open
+    systemd::notify("READY=1");
+    let watchdog_interval = systemd::watchdog_interval();
+    let mut watchdog = tokio::time::interval(watchdog_interval.unwrap_or(Duration::from_secs(1)));
+    let upgrade = upgrade_on_signal();
+    tokio::pin!(shutdown, upgrade);
+    loop {
+        tokio::select! {
+            res = &mut shutdown => {
+                res?;
+                info!("shutdown");
+                systemd::notify("STOPPING=1");
+                break;
+            }
+            pid = &mut upgrade => {
+                info!("shutdown, process {pid} took over");
+                systemd::notify(&format!("MAINPID={pid}"));
+                break;
+            }
+            _ = watchdog.tick(), if watchdog_interval.is_some() => systemd::notify("WATCHDOG=1"),
+        }
+    }
+    if let Some(reload_task) = reload_task {
+        reload_task.abort();
+        reload_task.await.ok();
+    }
+    let shutdown = server.shutdown().await?;
+    if shutdown == Shutdown::Forced {
+        warn!("shutdown timed out, in-flight requests were aborted");
+    }
+    Ok(shutdown)
+}
+
+/// Start a new process on every `SIGUSR2` signal, until one takes over the listeners.
+///
+/// Returns the process id of the new process.
+#[cfg(unix)]
+async fn upgrade_on_signal() -> u32 {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut signal = match signal(SignalKind::user_defined2()) {
+        Ok(signal) => signal,
+        Err(err) => {
+            warn!("failed to listen for SIGUSR2, upgrades are disabled: {err}");
+            return std::future::pending().await;
+        }
+    };
+    loop {
+        signal.recv().await;
+        info!("upgrading on SIGUSR2");
+        match handoff::spawn_successor().await {
+            Ok(pid) => return pid,
+            Err(err) => error!("upgrade failed, continuing to serve: {err:#}"),
+        }
+    }
+}
+
+/// Upgrades are not supported on this platform.
+#[cfg(not(unix))]
+async fn upgrade_on_signal() -> u32 {
+    std::future::pending().await
+}
+
+/// Wait for the `SIGTERM` signal.
+#[cfg(unix)]
+async fn terminate_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::terminate())?.recv().await;
     Ok(())
 }
 
+/// Wait for the `SIGTERM` signal (never resolves on this platform).
+#[cfg(not(unix))]
+async fn terminate_signal() -> std::io::Result<()> {
+    std::future::pending().await
+}
+
+/// Switch between the configured log filter and debug logging on every `SIGUSR1` signal.
+#[cfg(unix)]
+async fn toggle_debug_logging_on_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut signal = match signal(SignalKind::user_defined1()) {
+        Ok(signal) => signal,
+        Err(err) => return warn!("failed to listen for SIGUSR1: {err}"),
+    };
+    while signal.recv().await.is_some() {
+        if let Err(err) = crate::telemetry::toggle_debug_logging() {
+            warn!("failed to change log filter: {err:#}");
+        }
+    }
+}
+
+/// Switch the log filter on `SIGUSR1` (no signals on this platform).
+#[cfg(not(unix))]
+async fn toggle_debug_logging_on_signal() {}
+
+/// The exit code of the process after a [forced](Shutdown::Forced) shutdown.
+///
+/// A clean shutdown exits with `0`, and an error with `1`.
+pub const FORCED_SHUTDOWN_EXIT_CODE: u8 = 3;
+
+/// How the server shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// All in-flight requests finished and the store was closed within the shutdown timeout.
+    Clean,
+    /// The shutdown timeout passed, and the remaining requests were aborted.
+    Forced,
+}
+
+impl Shutdown {
+    /// Combine the outcomes of two parts of the shutdown.
+    pub(crate) fn and(self, other: Shutdown) -> Shutdown {
+        match (self, other) {
+            (Shutdown::Clean, Shutdown::Clean) => Shutdown::Clean,
+            _ => Shutdown::Forced,
+        }
+    }
+
+    /// The exit code of the process, `0` or [`FORCED_SHUTDOWN_EXIT_CODE`].
+    pub fn exit_code(self) -> std::process::ExitCode {
+        match self {
+            Shutdown::Clean => std::process::ExitCode::SUCCESS,
+            Shutdown::Forced => FORCED_SHUTDOWN_EXIT_CODE.into(),
+        }
+    }
+}
+
+/// Builder for a [`Server`] that is embedded in another service
+///
+/// The builder starts from the [`Default`] config without the HTTPS server and the metrics
+/// server, and with the packets kept in memory: a DNS server on port 5300 and an HTTP server on
+/// port 8080. The servers are replaced with [`Self::dns`], [`Self::http`] and [`Self::https`],
+/// and all other settings with [`Self::config`]. At least one of the HTTP and HTTPS servers is
+/// required.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use iroh_dns_server::{server::Server, ZoneStore};
+///
+/// let mut dns = iroh_dns_server::config::Config::default().dns;
+/// dns.port = 0;
+/// let server = Server::builder()
+///     .dns(dns)
+///     .store(ZoneStore::persistent("signed-packets-1.db")?)
+///     .spawn()
+///     .await?;
+/// server.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ServerBuilder {
+    config: Config,
+    store: Option<ZoneStore>,
+    hooks: Hooks,
+}
+
+/// The extensions of an embedded server
+#[derive(Debug, Default)]
+struct Hooks {
+    dns: Vec<Arc<dyn DnsHook>>,
+    names: Vec<Arc<dyn NameResolver>>,
+    packet_validators: Vec<Arc<dyn PacketValidator>>,
+    auth_providers: Vec<Arc<dyn AuthProvider>>,
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            config: Config {
+                https: None,
+                metrics: Some(MetricsConfig::disabled()),
+                in_memory_store: true,
+                ..Default::default()
+            },
+            store: None,
+            hooks: Default::default(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Replace the whole config, including the servers that were set before.
+    pub fn config(self, config: Config) -> Self {
+        Self { config, ..self }
+    }
+
+    /// Set the config of the DNS server.
+    pub fn dns(mut self, config: DnsConfig) -> Self {
+        self.config.dns = config;
+        self
+    }
+
+    /// Set the config of the HTTP server, or `None` to not start it.
+    pub fn http(mut self, config: impl Into<Option<HttpConfig>>) -> Self {
+        self.config.http = config.into();
+        self
+    }
+
+    /// Set the config of the HTTPS server, or `None` to not start it.
+    pub fn https(mut self, config: impl Into<Option<HttpsConfig>>) -> Self {
+        self.config.https = config.into();
+        self
+    }
+
+    /// Use `store` for the packets.
+    ///
+    /// Without a store, the packets are kept in memory if [`Config::in_memory_store`] is set, as
+    /// it is by default, and in the database at [`Config::store_path`] otherwise.
+    pub fn store(self, store: ZoneStore) -> Self {
+        Self {
+            store: Some(store),
+            ..self
+        }
+    }
+
+    /// Run `hook` on the DNS queries for names in the origins, after the hooks added before.
+    pub fn dns_hook(mut self, hook: impl DnsHook) -> Self {
+        self.hooks.dns.push(Arc::new(hook));
+        self
+    }
+
+    /// Answer the queries for the names that `resolver` maps to pubkeys with the packets of the
+    /// nodes, if the resolvers added before don't know the names.
+    ///
+    /// See [`crate::dns::NameResolver`] for the names that are resolved.
+    pub fn name_resolver(mut self, resolver: impl NameResolver) -> Self {
+        self.hooks.names.push(Arc::new(resolver));
+        self
+    }
+
+    /// Serve HTTPS with `tls_config` instead of the certificates of the HTTPS config, e.g. with
+    /// keys in an HSM or with workload certificates that are rotated by the application.
+    ///
+    /// The HTTPS server still needs to be set with [`Self::https`] or in the config, for its
+    /// ports and bind addresses. Its certificate settings are ignored, and no ACME certificates
+    /// are ordered. HTTP/3 uses the config too, with the `h3` ALPN protocol.
+    pub fn tls_config(mut self, tls_config: Arc<rustls::ServerConfig>) -> Self {
+        self.hooks.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Run `validator` on the packets published with `PUT /pkarr/{key}`, after the validators
+    /// added before.
+    ///
+    /// A synchronous function is turned into a validator with [`crate::http::validator_fn`].
+    pub fn packet_validator(mut self, validator: impl PacketValidator) -> Self {
+        self.hooks.packet_validators.push(Arc::new(validator));
+        self
+    }
+
+    /// Authorize the requests to the admin endpoints, the change feed and the publishes with
+    /// `provider`, after the client certificates, the API keys and the providers added before.
+    ///
+    /// See [`crate::http::AuthProvider`] for when requests are allowed.
+    pub fn auth_provider(mut self, provider: impl AuthProvider) -> Self {
+        self.hooks.auth_providers.push(Arc::new(provider));
+        self
+    }
+
+    /// Spawn the server.
+    ///
+    /// The mainline fallback and the slow log of the config are added to the store, and the
+    /// mainline bootstrap nodes are refreshed in the background while the server runs.
+    pub async fn spawn(self) -> Result<Server> {
+        let config = self.config;
+        let mut store = match self.store {
+            Some(store) => store,
+            None if config.in_memory_store => ZoneStore::in_memory()?,
+            None => ZoneStore::persistent(config.signed_packet_store_path()?)?,
+        };
+        if let Some(store_config) = &config.store {
+            ensure!(
+                store_config.backend != StoreBackend::Postgres
+                    || (config.history.is_none() && config.tenants.is_empty()),
+                "the history and the tenants are not supported with the postgres store"
+            );
+            store = store.with_backend(store_config).await?;
+        }
+        if let Some(retention) = &config.retention {
+            store = store.with_max_packet_age(Duration::from_secs(retention.max_age_secs));
+        }
+        #[cfg(feature = "mainline")]
+        let bootstrap = bootstrap::bootstrap_option(&config)?;
+        #[cfg(feature = "mainline")]
+        if let Some(bootstrap) = &bootstrap {
+            match config.mainline.as_ref().and_then(|m| m.node.as_ref()) {
+                Some(node) => {
+                    info!("mainline fallback enabled, as a storing DHT node");
+                    store = store.with_mainline_node(bootstrap.clone(), node)?;
+                }
+                None => {
+                    info!("mainline fallback enabled");
+                    store = store.with_mainline_fallback(bootstrap.clone())?;
+                }
+            }
+        };
+        #[cfg(not(feature = "mainline"))]
+        ensure!(
+            config.mainline_enabled().is_none(),
+            "the mainline fallback requires the `mainline` feature"
+        );
+        if let Some(slow_log) = &config.slow_log {
+            store = store.with_slow_log(slow_log);
+        }
+        if let Some(history) = &config.history {
+            let history = PacketHistory::open(store.database(), history)?;
+            store = store.with_history(history);
+        }
+        if !config.tenants.is_empty() {
+            for tenant in &config.tenants {
+                for origin in &tenant.origins {
+                    let same = |other: &String| {
+                        other
+                            .trim_end_matches('.')
+                            .eq_ignore_ascii_case(origin.trim_end_matches('.'))
+                    };
+                    ensure!(
+                        config.dns.origins.iter().any(same),
+                        "origin {origin} of tenant {} is not an origin of the DNS server",
+                        tenant.name
+                    );
+                }
+            }
+            let dir = match config.in_memory_store {
+                true => None,
+                false => Some(config.data_dir()?.join("tenants")),
+            };
+            let tenants = Tenants::open(store.database(), &config.tenants, dir.as_deref())?;
+            store = store.with_tenants(tenants);
+        }
+        #[cfg(feature = "mainline")]
+        let refresh = match (config.mainline.clone(), bootstrap) {
+            (Some(mainline), Some(bootstrap)) => Some((mainline, bootstrap, config.data_dir()?)),
+            _ => None,
+        };
+        #[allow(unused_mut)]
+        let mut server = Server::spawn_with_hooks(config, store, self.hooks).await?;
+        #[cfg(feature = "mainline")]
+        {
+            server.bootstrap_task = refresh.map(|(mainline, bootstrap, data_dir)| {
+                tokio::task::spawn(
+                    async move { bootstrap::run(&mainline, bootstrap, &data_dir).await },
+                )
+            });
+        }
+        Ok(server)
+    }
+}
+
 /// The iroh-dns server.
 pub struct Server {
     http_server: HttpServer,
     dns_server: DnsServer,
-    metrics_task: tokio::task::JoinHandle<anyhow::Result<()>>,
+    metrics_task: Option<tokio::task::JoinHandle<anyhow::Result<()>>>,
+    probe_task: Option<tokio::task::JoinHandle<()>>,
+    gossip_task: Option<tokio::task::JoinHandle<()>>,
+    sync_task: Option<tokio::task::JoinHandle<()>>,
+    transfer_task: Option<tokio::task::JoinHandle<()>>,
+    replicas_task: Option<tokio::task::JoinHandle<()>>,
+    retention_task: Option<tokio::task::JoinHandle<()>>,
+    history_task: Option<tokio::task::JoinHandle<()>>,
+    ring_task: Option<tokio::task::JoinHandle<()>>,
+    ip_reputation_task: Option<tokio::task::JoinHandle<()>>,
+    analytics_task: Option<tokio::task::JoinHandle<()>>,
+    usage_task: Option<tokio::task::JoinHandle<()>>,
+    hourly_stats_task: Option<tokio::task::JoinHandle<()>>,
+    rate_limit_gc_task: Option<tokio::task::JoinHandle<()>>,
+    rate_limit_state_task: Option<tokio::task::JoinHandle<()>>,
+    /// The persisted rate limits, saved on shutdown
+    rate_limit_state: Option<RateLimitState>,
+    anonymous_stats_task: Option<tokio::task::JoinHandle<()>>,
+    mirror_task: Option<tokio::task::JoinHandle<()>>,
+    bootstrap_task: Option<tokio::task::JoinHandle<()>>,
+    dht_publish_task: Option<tokio::task::JoinHandle<()>>,
+    health_task: Option<HealthTask>,
+    shutdown_timeout: Duration,
+    state: AppState,
 }
 
 impl Server {
-    /// Spawn the server.
+    /// Create a builder for a server that runs in this process, see [`ServerBuilder`].
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Spawn the server with a store that is already set up for `config`.
+    ///
+    /// Unlike [`ServerBuilder::spawn`], this neither adds the mainline fallback nor the slow log
+    /// to `store`, and doesn't refresh the mainline bootstrap nodes.
     ///
     /// This will spawn several background tasks:
     /// * A DNS server task
     /// * A HTTP server task, if `config.http` is not empty
     /// * A HTTPS server task, if `config.https` is not empty
     pub async fn spawn(config: Config, store: ZoneStore) -> Result<Self> {
-        let dns_handler = DnsHandler::new(store.clone(), &config.dns)?;
+        Self::spawn_with_hooks(config, store, Hooks::default()).await
+    }
 
-        let state = AppState { store, dns_handler };
+    /// Spawn the server with the extensions in `hooks`.
+    async fn spawn_with_hooks(config: Config, mut store: ZoneStore, hooks: Hooks) -> Result<Self> {
+        let resource_limits = config.resource_limits.clone().unwrap_or_default();
+        let data_dir = config.data_dir()?;
+        if let Some(max) = resource_limits.max_mainline_lookups {
+            store = store.with_max_mainline_lookups(max);
+        }
+        if let Some((concurrent, queued)) = config.mainline.as_ref().and_then(|m| m.lookup_pool()) {
+            store = store.with_mainline_lookup_pool(concurrent, queued);
+        }
+        if let Some(clock_skew) = &config.clock_skew {
+            store = store.with_clock_skew(clock_skew);
+        }
+        if let Some(ring) = &config.ring {
+            store = store.with_ring(Ring::new(ring)?);
+        }
+        let mut dns_handler = DnsHandler::new(store.clone(), &config.dns)?;
+        if !hooks.dns.is_empty() || !hooks.names.is_empty() {
+            dns_handler = dns_handler.with_extensions(hooks.dns, hooks.names);
+        }
+        if let Some(query_log) = &config.query_log {
+            dns_handler = dns_handler.with_query_log(QueryLog::spawn(query_log).await?);
+        }
+        if let Some(max) = resource_limits.max_dns_requests {
+            dns_handler = dns_handler.with_max_requests(max);
+        }
+        if let Some(max) = config
+            .runtime
+            .as_ref()
+            .and_then(|r| r.dns_handler_concurrency)
+        {
+            ensure!(
+                max > 0,
+                "runtime.dns_handler_concurrency must be at least 1"
+            );
+            dns_handler = dns_handler.with_concurrency(max);
+        }
+        let transfer_task = match (config.transfer.clone(), config.secondary.clone()) {
+            (Some(_), Some(_)) => bail!("a secondary can't serve zone transfers"),
+            (Some(transfer), None) => {
+                dns_handler = dns_handler.with_transfers(&transfer);
+                let dns_handler = dns_handler.clone();
+                Some(tokio::task::spawn(dns::notify_secondaries(
+                    transfer,
+                    dns_handler,
+                )))
+            }
+            (None, Some(secondary)) => {
+                dns_handler = dns_handler.with_secondary(secondary);
+                Some(tokio::task::spawn(dns::run_secondary(dns_handler.clone())))
+            }
+            (None, None) => None,
+        };
+        let replicas_task = match &config.replicas {
+            // a secondary serves the replica records of its primary
+            Some(replicas) if config.secondary.is_none() => Some(tokio::task::spawn(
+                dns::advertise_replicas(replicas.clone(), dns_handler.clone()),
+            )),
+            _ => None,
+        };
 
-        let metrics_addr = config.metrics_addr();
-        let metrics_task = tokio::task::spawn(async move {
-            if let Some(addr) = metrics_addr {
-                start_metrics_server(addr).await?;
+        let ip_reputation = match &config.ip_reputation {
+            Some(reputation) => IpReputation::load(reputation)?,
+            None => IpReputation::default(),
+        };
+        if config.ip_reputation.is_some() {
+            dns_handler = dns_handler.with_ip_reputation(ip_reputation.clone());
+        }
+        let acl = config.acl.clone().map(Acl::new).unwrap_or_default();
+        dns_handler = dns_handler.with_acl(acl.clone());
+        let analytics = match &config.analytics {
+            Some(analytics) => Some(Analytics::open(store.database(), analytics)?),
+            None => None,
+        };
+        if let Some(analytics) = &analytics {
+            dns_handler = dns_handler.with_analytics(analytics.clone());
+        }
+        let hourly_stats = match &config.hourly_stats {
+            Some(hourly_stats) => Some(HourlyStats::open(store.database(), hourly_stats)?),
+            None => None,
+        };
+        if let Some(hourly_stats) = &hourly_stats {
+            dns_handler = dns_handler.with_hourly_stats(hourly_stats.clone());
+        }
+        let usage = match &config.usage {
+            Some(usage) => Some(Usage::open(usage).await?),
+            None => None,
+        };
+        if let Some(usage) = &usage {
+            dns_handler = dns_handler.with_usage(usage.clone());
+        }
+        let abuse_log = match &config.abuse_log {
+            Some(abuse_log) => AbuseLog::open(abuse_log)?,
+            None => AbuseLog::default(),
+        };
+
+        // without health checks the server is always ready
+        let health = Health::new(config.health.is_none());
+        if config.health.as_ref().is_some_and(|health| health.stop_dns) {
+            dns_handler = dns_handler.with_health(health.clone());
+        }
+
+        #[cfg(feature = "chaos")]
+        let chaos = {
+            warn!("fault injection is enabled, the faults are set with /admin/chaos");
+            let chaos = Chaos::default();
+            dns_handler = dns_handler.with_chaos(chaos.clone());
+            chaos
+        };
+
+        let mut auth_providers = hooks.auth_providers;
+        // the admin tokens of the config run before the providers of the embedder
+        if let Some(token_auth) = match &config.admin {
+            Some(admin) => admin.token_auth().await?,
+            None => None,
+        } {
+            auth_providers.insert(0, Arc::new(token_auth));
+        }
+        let auth = AuthProviders::new(store.api_keys().clone(), auth_providers);
+        let state = AppState {
+            store,
+            auth,
+            dns_handler,
+            bound_addrs: Default::default(),
+            task_errors: Default::default(),
+            packet_validators: PacketValidators::new(hooks.packet_validators),
+            rate_limiters: Default::default(),
+            health: health.clone(),
+            retention: config.retention.clone(),
+            abuse_log,
+            ip_reputation: ip_reputation.clone(),
+            acl,
+            analytics: analytics.clone(),
+            usage: usage.clone(),
+            hourly_stats: hourly_stats.clone(),
+            #[cfg(feature = "chaos")]
+            chaos,
+        };
+
+        #[cfg(feature = "metrics")]
+        let (metrics_addr, metrics_task) = spawn_metrics(&config, state.task_errors.clone())
+            .map(|(addr, task)| (addr, Some(task)))?;
+        #[cfg(not(feature = "metrics"))]
+        let (metrics_addr, metrics_task): (Option<SocketAddr>, _) = {
+            ensure!(
+                config
+                    .metrics
+                    .as_ref()
+                    .is_none_or(|m| m.disabled && m.otlp.is_none() && m.push.is_none()),
+                "the metrics server and push require the `metrics` feature, set `metrics.disabled`"
+            );
+            (None, None)
+        };
+        let shutdown_timeout = config.shutdown_timeout();
+        let http_server = HttpServer::spawn(
+            config.http,
+            config.https,
+            hooks.tls_config,
+            config.access_log,
+            config.compression,
+            config.http_limits,
+            resource_limits.max_http_connections,
+            config.behind_proxy,
+            config.admin,
+            config.ip_stack,
+            &data_dir,
+            state.clone(),
+        )
+        .await?;
+        // the rate limiters are created with the HTTP app
+        let rate_limit_gc_task = Some(tokio::task::spawn(state.rate_limiters.clone().run_gc()));
+        let rate_limit_state = match &config.rate_limit_state {
+            Some(rate_limit_state) => {
+                let rate_limit_state = RateLimitState::open(
+                    state.store.database(),
+                    rate_limit_state,
+                    state.rate_limiters.clone(),
+                    state.store.tenants().clone(),
+                )?;
+                rate_limit_state.restore()?;
+                Some(rate_limit_state)
+            }
+            None => None,
+        };
+        let rate_limit_state_task = rate_limit_state
+            .clone()
+            .map(|rate_limit_state| tokio::task::spawn(rate_limit_state.run()));
+        let origin = config.dns.origins.first().cloned();
+        let dns_server = DnsServer::spawn(
+            config.dns,
+            config.ip_stack,
+            state.dns_handler.clone(),
+            &data_dir,
+            state.task_errors.clone(),
+        )
+        .await?;
+        for addr in dns_server.local_addrs() {
+            state.bound_addrs.add("dns", *addr);
+        }
+        for addr in dns_server.tls_addrs() {
+            state.bound_addrs.add("dns_tls", *addr);
+        }
+        for addr in dns_server.quic_addrs() {
+            state.bound_addrs.add("dns_quic", *addr);
+        }
+        #[cfg(feature = "gossip")]
+        let gossip_task = match &config.gossip {
+            Some(gossip) => {
+                Some(crate::gossip::spawn(gossip, state.store.clone(), &data_dir).await?)
             }
-            Ok(())
+            None => None,
+        };
+        #[cfg(not(feature = "gossip"))]
+        let gossip_task = {
+            ensure!(
+                config.gossip.is_none(),
+                "gossip requires the `gossip` feature"
+            );
+            None
+        };
+        let sync_task = match &config.sync {
+            Some(sync) => Some(sync::spawn(sync, state.store.clone(), &data_dir).await?),
+            None => None,
+        };
+        let retention_task = state
+            .retention
+            .clone()
+            .map(|retention| tokio::task::spawn(retention::run(retention, state.store.clone())));
+        let history_task = state
+            .store
+            .history()
+            .cloned()
+            .map(|history| tokio::task::spawn(history.run()));
+        let ring_task = state
+            .store
+            .ring()
+            .cloned()
+            .map(|ring| tokio::task::spawn(ring.run_health_checks()));
+        let analytics_task = analytics.map(|analytics| tokio::task::spawn(analytics.run()));
+        let usage_task = usage.map(|usage| tokio::task::spawn(usage.run()));
+        let hourly_stats_task =
+            hourly_stats.map(|hourly_stats| tokio::task::spawn(hourly_stats.run()));
+        let anonymous_stats_task = config.anonymous_stats.clone().map(|config| {
+            tokio::task::spawn(anonymous_stats::run(
+                config,
+                state.dns_handler.clone(),
+                state.store.clone(),
+            ))
         });
-        let http_server = HttpServer::spawn(config.http, config.https, state.clone()).await?;
-        let dns_server = DnsServer::spawn(config.dns, state.dns_handler.clone()).await?;
+        let mirror_task = match &config.mirror {
+            Some(mirror) => {
+                let mirror = Mirror::open(mirror).await?;
+                Some(tokio::task::spawn(mirror.run(state.store.clone())))
+            }
+            None => None,
+        };
+        let ip_reputation_task = config
+            .ip_reputation
+            .clone()
+            .map(|config| tokio::task::spawn(ip_reputation.run(config)));
+        #[cfg(feature = "mainline")]
+        let dht_publish_task = match config.mainline.as_ref() {
+            Some(mainline) if mainline.enabled && mainline.publish => {
+                let Some(publisher) = DhtPublisher::new(mainline, &state.store) else {
+                    bail!("mainline.publish requires a store with the mainline fallback");
+                };
+                Some(tokio::task::spawn(publisher.run(state.store.clone())))
+            }
+            _ => None,
+        };
+        #[cfg(not(feature = "mainline"))]
+        let dht_publish_task = None;
+        // all listeners are bound
+        let log_file = config.logging.as_ref().and_then(|l| l.file.as_ref());
+        let owned: Vec<_> = [
+            Some(data_dir.clone()),
+            config.store_path.clone(),
+            log_file.map(|f| f.path.clone()),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        // a rotated log file is renamed in its directory, which may be shared, e.g. `/var/log`
+        let writable: Vec<_> = log_file
+            .filter(|f| f.rotates())
+            .and_then(|f| f.path.parent())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .into_iter()
+            .collect();
+        privileges::drop_privileges(
+            config.user.as_deref(),
+            config.group.as_deref(),
+            &owned,
+            &writable,
+        )?;
+        if let Some(addr) = metrics_addr {
+            state.bound_addrs.add("metrics", addr);
+        }
+        let probe_task = match (config.probe, origin) {
+            (Some(probe), Some(origin)) => {
+                let targets = ProbeTargets::new(
+                    http_server.http_addr(),
+                    http_server.https_addr(),
+                    dns_server.local_addr(),
+                    Name::from_utf8(origin)?,
+                );
+                Some(tokio::task::spawn(async move {
+                    if let Err(err) = probe::run(probe, targets, &data_dir).await {
+                        warn!("self-check probe stopped: {err:#}");
+                    }
+                }))
+            }
+            _ => None,
+        };
+        let health_task = config
+            .health
+            .as_ref()
+            .map(|config| HealthTask::spawn(config, state.store.clone(), health));
         Ok(Self {
             http_server,
             dns_server,
             metrics_task,
+            probe_task,
+            gossip_task,
+            sync_task,
+            transfer_task,
+            replicas_task,
+            retention_task,
+            history_task,
+            ring_task,
+            ip_reputation_task,
+            analytics_task,
+            usage_task,
+            hourly_stats_task,
+            rate_limit_gc_task,
+            rate_limit_state_task,
+            rate_limit_state,
+            anonymous_stats_task,
+            mirror_task,
+            bootstrap_task: None,
+            dht_publish_task,
+            health_task,
+            shutdown_timeout,
+            state,
         })
     }
 
+    /// Get the addresses the servers are bound to, by server: `dns`, `dns_tls`, `dns_quic`,
+    /// `http`, `https` and `metrics`.
+    ///
+    /// For the ports that are set to 0 in the config, these are the ports that were picked.
+    pub fn bound_addrs(&self) -> BTreeMap<&'static str, Vec<SocketAddr>> {
+        self.state.bound_addrs.get()
+    }
+
+    /// Get the store of the packets.
+    pub fn store(&self) -> &ZoneStore {
+        &self.state.store
+    }
+
+    /// Get the bound address of the first DNS socket.
+    pub fn dns_addr(&self) -> SocketAddr {
+        self.dns_server.local_addr()
+    }
+
+    /// Get the bound address of the first HTTP socket, if the HTTP server runs.
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.http_server.http_addr()
+    }
+
+    /// Get the bound address of the first admin socket, if the admin endpoints are served on
+    /// listeners of their own.
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.http_server.admin_addr()
+    }
+
+    /// Get the bound address of the first HTTPS socket, if the HTTPS server runs.
+    pub fn https_addr(&self) -> Option<SocketAddr> {
+        self.http_server.https_addr()
+    }
+
+    /// Subscribe to the errors of the server tasks.
+    ///
+    /// A task that fails stops serving, e.g. the listener of one address, while the other tasks
+    /// keep running. A server that is embedded may want to [`Self::shutdown`] then.
+    pub fn subscribe_errors(&self) -> broadcast::Receiver<TaskError> {
+        self.state.task_errors.subscribe()
+    }
+
+    /// Subscribe to the events of the server, e.g. the published packets.
+    ///
+    /// The events are also streamed by `GET /admin/events`. See [`crate::events`].
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ServerEvent> {
+        self.state.store.events().subscribe()
+    }
+
     /// Cancel the server tasks and wait for all tasks to complete.
-    pub async fn shutdown(self) -> Result<()> {
-        self.metrics_task.abort();
-        let (res1, res2) = tokio::join!(self.dns_server.shutdown(), self.http_server.shutdown(),);
-        res1?;
-        res2?;
-        Ok(())
+    ///
+    /// New connections are no longer accepted, and in-flight requests are given up to the
+    /// configured shutdown timeout to finish. The store is closed once all requests are done,
+    /// within the same timeout.
+    pub async fn shutdown(self) -> Result<Shutdown> {
+        let deadline = tokio::time::Instant::now() + self.shutdown_timeout;
+        // withdraw the anycast routes first, so that clients move to other servers
+        if let Some(health_task) = self.health_task {
+            health_task.shutdown().await;
+        }
+        if let Some(metrics_task) = &self.metrics_task {
+            metrics_task.abort();
+        }
+        if let Some(probe_task) = &self.probe_task {
+            probe_task.abort();
+        }
+        if let Some(gossip_task) = &self.gossip_task {
+            gossip_task.abort();
+        }
+        if let Some(sync_task) = &self.sync_task {
+            sync_task.abort();
+        }
+        if let Some(transfer_task) = &self.transfer_task {
+            transfer_task.abort();
+        }
+        if let Some(replicas_task) = &self.replicas_task {
+            replicas_task.abort();
+        }
+        if let Some(retention_task) = &self.retention_task {
+            retention_task.abort();
+        }
+        if let Some(history_task) = &self.history_task {
+            history_task.abort();
+        }
+        if let Some(ring_task) = &self.ring_task {
+            ring_task.abort();
+        }
+        if let Some(ip_reputation_task) = &self.ip_reputation_task {
+            ip_reputation_task.abort();
+        }
+        if let Some(analytics_task) = &self.analytics_task {
+            analytics_task.abort();
+        }
+        if let Some(usage_task) = &self.usage_task {
+            usage_task.abort();
+        }
+        if let Some(hourly_stats_task) = &self.hourly_stats_task {
+            hourly_stats_task.abort();
+        }
+        if let Some(rate_limit_gc_task) = &self.rate_limit_gc_task {
+            rate_limit_gc_task.abort();
+        }
+        if let Some(rate_limit_state_task) = &self.rate_limit_state_task {
+            rate_limit_state_task.abort();
+        }
+        if let Some(anonymous_stats_task) = &self.anonymous_stats_task {
+            anonymous_stats_task.abort();
+        }
+        if let Some(mirror_task) = &self.mirror_task {
+            mirror_task.abort();
+        }
+        if let Some(bootstrap_task) = &self.bootstrap_task {
+            bootstrap_task.abort();
+        }
+        if let Some(dht_publish_task) = &self.dht_publish_task {
+            dht_publish_task.abort();
+        }
+        let (res1, res2) = tokio::join!(
+            self.dns_server.shutdown(self.shutdown_timeout),
+            self.http_server.shutdown(self.shutdown_timeout),
+        );
+        let shutdown = res1?.and(res2?);
+        // the counts since the last flush are written before the store is closed
+        if let Some(analytics) = self.state.analytics.clone() {
+            if let Err(err) = tokio::task::spawn_blocking(move || analytics.flush()).await? {
+                warn!("failed to write the resolution counts: {err:#}");
+            }
+        }
+        if let Some(hourly_stats) = self.state.hourly_stats.clone() {
+            if let Err(err) = tokio::task::spawn_blocking(move || hourly_stats.flush()).await? {
+                warn!("failed to write the hourly statistics: {err:#}");
+            }
+        }
+        if let Some(rate_limit_state) = self.rate_limit_state.clone() {
+            if let Err(err) = tokio::task::spawn_blocking(move || rate_limit_state.save()).await? {
+                warn!("failed to save the rate limits: {err:#}");
+            }
+        }
+        // the usage of the unfinished interval is billed too
+        if let Some(usage) = &self.state.usage {
+            if tokio::time::timeout_at(deadline, usage.flush())
+                .await
+                .is_err()
+            {
+                warn!("usage report was not exported before the shutdown deadline");
+            }
+        }
+        // the DNS handler of the state holds a clone of the store too
+        let store = self.state.store.clone();
+        drop(self.state);
+        match tokio::time::timeout_at(deadline, store.close()).await {
+            Ok(res) => res?,
+            Err(_) => {
+                warn!(
+                    "packet store was not closed within {:?}",
+                    self.shutdown_timeout
+                );
+                return Ok(Shutdown::Forced);
+            }
+        }
+        Ok(shutdown)
     }
 
     /// Wait for all tasks to complete.
@@ -78,7 +1085,66 @@ impl Server {
             res = self.dns_server.run_until_done() => res?,
             res = self.http_server.run_until_done() => res?,
         }
-        self.metrics_task.abort();
+        if let Some(health_task) = self.health_task {
+            health_task.shutdown().await;
+        }
+        if let Some(metrics_task) = &self.metrics_task {
+            metrics_task.abort();
+        }
+        if let Some(probe_task) = &self.probe_task {
+            probe_task.abort();
+        }
+        if let Some(gossip_task) = &self.gossip_task {
+            gossip_task.abort();
+        }
+        if let Some(sync_task) = &self.sync_task {
+            sync_task.abort();
+        }
+        if let Some(transfer_task) = &self.transfer_task {
+            transfer_task.abort();
+        }
+        if let Some(replicas_task) = &self.replicas_task {
+            replicas_task.abort();
+        }
+        if let Some(retention_task) = &self.retention_task {
+            retention_task.abort();
+        }
+        if let Some(history_task) = &self.history_task {
+            history_task.abort();
+        }
+        if let Some(ring_task) = &self.ring_task {
+            ring_task.abort();
+        }
+        if let Some(ip_reputation_task) = &self.ip_reputation_task {
+            ip_reputation_task.abort();
+        }
+        if let Some(analytics_task) = &self.analytics_task {
+            analytics_task.abort();
+        }
+        if let Some(usage_task) = &self.usage_task {
+            usage_task.abort();
+        }
+        if let Some(hourly_stats_task) = &self.hourly_stats_task {
+            hourly_stats_task.abort();
+        }
+        if let Some(rate_limit_gc_task) = &self.rate_limit_gc_task {
+            rate_limit_gc_task.abort();
+        }
+        if let Some(rate_limit_state_task) = &self.rate_limit_state_task {
+            rate_limit_state_task.abort();
+        }
+        if let Some(anonymous_stats_task) = &self.anonymous_stats_task {
+            anonymous_stats_task.abort();
+        }
+        if let Some(mirror_task) = &self.mirror_task {
+            mirror_task.abort();
+        }
+        if let Some(bootstrap_task) = &self.bootstrap_task {
+            bootstrap_task.abort();
+        }
+        if let Some(dht_publish_task) = &self.dht_publish_task {
+            dht_publish_task.abort();
+        }
         Ok(())
     }
 
@@ -98,26 +1164,43 @@ impl Server {
     #[cfg(test)]
     pub async fn spawn_for_tests_with_mainline(
         mainline: Option<crate::config::BootstrapOption>,
+    ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
+        Self::spawn_for_tests_with(mainline, None).await
+    }
+
+    /// Spawn a server suitable for testing, optionally with mainline and resource limits.
+    #[cfg(test)]
+    pub async fn spawn_for_tests_with(
+        mainline: Option<crate::config::BootstrapOption>,
+        resource_limits: Option<crate::config::ResourceLimitsConfig>,
     ) -> Result<(Self, std::net::SocketAddr, url::Url)> {
         use crate::config::MetricsConfig;
         use std::net::{IpAddr, Ipv4Addr};
 
         let mut config = Config::default();
         config.dns.port = 0;
-        config.dns.bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
-        config.http.as_mut().unwrap().port = 0;
-        config.http.as_mut().unwrap().bind_addr = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        config.dns.bind_addr = vec![IpAddr::V4(Ipv4Addr::LOCALHOST).into()];
+        config.http.as_mut().unwrap().port = Some(0);
+        config.http.as_mut().unwrap().bind_addr = vec![IpAddr::V4(Ipv4Addr::LOCALHOST).into()];
         config.https = None;
         config.metrics = Some(MetricsConfig::disabled());
+        config.resource_limits = resource_limits;
 
+        #[allow(unused_mut)]
         let mut store = ZoneStore::in_memory()?;
+        #[cfg(feature = "mainline")]
         if let Some(bootstrap) = mainline {
             info!("mainline fallback enabled");
-            store = store.with_mainline_fallback(bootstrap);
+            store = store.with_mainline_fallback(bootstrap)?;
         }
+        #[cfg(not(feature = "mainline"))]
+        anyhow::ensure!(
+            mainline.is_none(),
+            "mainline requires the `mainline` feature"
+        );
         let server = Self::spawn(config, store).await?;
-        let dns_addr = server.dns_server.local_addr();
-        let http_addr = server.http_server.http_addr().expect("http is set");
+        let dns_addr = server.dns_addr();
+        let http_addr = server.http_addr().expect("http is set");
         let http_url = format!("http://{http_addr}").parse()?;
         Ok((server, dns_addr, http_url))
     }
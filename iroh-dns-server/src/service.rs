@@ -0,0 +1,208 @@
+//! Run the server as a Windows service
+//!
+//! [`install`] registers the server with the service control manager (SCM), which then starts
+//! it with `service run`, i.e. in [`run`]. A stop request from the SCM, or the shutdown of the
+//! host, shuts the server down gracefully like `Ctrl-C` does on a console.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::{
+    config::Config,
+    server::{self, Shutdown},
+};
+
+/// The default name of the service
+pub const DEFAULT_SERVICE_NAME: &str = "iroh-dns-server";
+
+/// Time the SCM waits for the server to start before it considers the start failed.
+const START_WAIT_HINT: Duration = Duration::from_secs(30);
+
+/// The server to run once the SCM calls the service main function.
+static SERVICE: Mutex<Option<Service>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Register the server as a service named `name`, started at boot with the config file at
+/// `config_path`.
+pub fn install(name: &str, config_path: Option<&Path>) -> Result<()> {
+    let mut launch_arguments = Vec::new();
+    if let Some(path) = config_path {
+        let path = std::fs::canonicalize(path)
+            .with_context(|| format!("failed to find config file {}", path.display()))?;
+        launch_arguments.extend([OsString::from("--config"), path.into_os_string()]);
+    }
+    launch_arguments.extend(["service", "run", "--name", name].map(OsString::from));
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .context("failed to connect to the service control manager")?;
+    let info = ServiceInfo {
+        name: OsString::from(name),
+        display_name: OsString::from("iroh DNS server"),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .with_context(|| format!("failed to install service {name}"))?;
+    service.set_description("A pkarr relay and DNS server for iroh node discovery")?;
+    Ok(())
+}
+
+/// Stop the service named `name` if it is running, and remove it.
+pub fn uninstall(name: &str) -> Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("failed to connect to the service control manager")?;
+    let service = manager
+        .open_service(
+            name,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .with_context(|| format!("failed to open service {name}"))?;
+    // the service is removed once it is stopped and all handles to it are closed
+    service.delete()?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    Ok(())
+}
+
+/// Run the server as the service named `name`, until the SCM stops it.
+///
+/// This only works if the process was started by the SCM.
+pub async fn run(name: &str, config: Config, config_path: Option<PathBuf>) -> Result<()> {
+    let (done, result) = oneshot::channel();
+    *SERVICE.lock().expect("poisoned") = Some(Service {
+        name: name.to_string(),
+        config,
+        config_path,
+        runtime: tokio::runtime::Handle::current(),
+        done,
+    });
+    let name = name.to_string();
+    // blocks until the service is stopped, while the server runs on the thread of `service_main`
+    tokio::task::spawn_blocking(move || service_dispatcher::start(name, ffi_service_main))
+        .await?
+        .context("failed to connect to the service control manager")?;
+    result.await.unwrap_or(Ok(()))
+}
+
+/// The server to run in the service main function
+struct Service {
+    name: String,
+    config: Config,
+    config_path: Option<PathBuf>,
+    runtime: tokio::runtime::Handle,
+    /// Receives the result of the server once the service is stopped
+    done: oneshot::Sender<Result<()>>,
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let Some(service) = SERVICE.lock().expect("poisoned").take() else {
+        return;
+    };
+    let Service {
+        name,
+        config,
+        config_path,
+        runtime,
+        done,
+    } = service;
+    let result = run_service(&name, config, config_path, &runtime);
+    if let Err(err) = &result {
+        error!("service {name} failed: {err:#}");
+    }
+    done.send(result).ok();
+}
+
+fn run_service(
+    name: &str,
+    config: Config,
+    config_path: Option<PathBuf>,
+    runtime: &tokio::runtime::Handle,
+) -> Result<()> {
+    let cancel = CancellationToken::new();
+    let status = {
+        let cancel = cancel.clone();
+        service_control_handler::register(name, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                cancel.cancel();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?
+    };
+    set_status(&status, ServiceState::StartPending, START_WAIT_HINT, 0)?;
+
+    let stop_wait_hint = config.shutdown_timeout() + Duration::from_secs(5);
+    let shutdown = async {
+        // polled once the server is ready
+        set_status(&status, ServiceState::Running, Duration::ZERO, 0)?;
+        cancel.cancelled().await;
+        info!("service {name} stopped by the service control manager");
+        set_status(&status, ServiceState::StopPending, stop_wait_hint, 0)?;
+        Ok(())
+    };
+    let result = runtime.block_on(server::run_with_config_until(config, config_path, shutdown));
+    let exit_code = match &result {
+        Ok(Shutdown::Clean) => 0,
+        Ok(Shutdown::Forced) => server::FORCED_SHUTDOWN_EXIT_CODE.into(),
+        Err(_) => 1,
+    };
+    set_status(&status, ServiceState::Stopped, Duration::ZERO, exit_code)?;
+    result.map(|_| ())
+}
+
+/// Report the state of the service to the SCM.
+fn set_status(
+    status: &ServiceStatusHandle,
+    state: ServiceState,
+    wait_hint: Duration,
+    exit_code: u32,
+) -> Result<()> {
+    let controls_accepted = match state {
+        ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        _ => ServiceControlAccept::empty(),
+    };
+    status.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: match exit_code {
+            0 => ServiceExitCode::Win32(0),
+            code => ServiceExitCode::ServiceSpecific(code),
+        },
+        checkpoint: 0,
+        wait_hint,
+        process_id: None,
+    })?;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Log of slow DNS queries and pkarr publishes
+//!
+//! Queries and publishes that take longer than the configured threshold are logged on the `warn`
+//! level with the target `iroh_dns_server::slow_log`, together with the time spent parsing the
+//! name or packet, in the store (including the in-memory cache) and in the mainline DHT.
+
+use std::time::Duration;
+
+use hickory_proto::rr::{LowerName, RecordType};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::metrics::AnswerSource;
+
+/// Config for the slow query log
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SlowLogConfig {
+    /// Log DNS queries and publishes that take longer than this many milliseconds
+    pub threshold_ms: u64,
+}
+
+/// The slow query log, disabled by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SlowLog {
+    threshold: Option<Duration>,
+}
+
+/// Time spent in the steps of a query or publish.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Timings {
+    /// Parsing the query name or the signed packet
+    pub(crate) parse: Duration,
+    /// Reading from or writing to the store, including the cache
+    pub(crate) store: Duration,
+    /// Resolving from the mainline DHT
+    pub(crate) dht: Duration,
+    /// Fetching from or forwarding to the owner of the key on the ring
+    pub(crate) ring: Duration,
+}
+
+impl SlowLog {
+    pub(crate) fn new(config: &SlowLogConfig) -> Self {
+        Self {
+            threshold: Some(Duration::from_millis(config.threshold_ms)),
+        }
+    }
+
+    fn is_slow(&self, total: Duration) -> bool {
+        self.threshold.is_some_and(|threshold| total > threshold)
+    }
+
+    /// Log a DNS lookup if it was slow.
+    pub(crate) fn query(
+        &self,
+        name: &LowerName,
+        qtype: RecordType,
+        source: AnswerSource,
+        total: Duration,
+        timings: &Timings,
+    ) {
+        if self.is_slow(total) {
+            warn!(
+                %name,
+                %qtype,
+                source = <&'static str>::from(source),
+                total_ms = millis(total),
+                parse_ms = millis(timings.parse),
+                store_ms = millis(timings.store),
+                dht_ms = millis(timings.dht),
+                ring_ms = millis(timings.ring),
+                "slow DNS query"
+            );
+        }
+    }
+
+    /// Log a pkarr publish if it was slow.
+    pub(crate) fn publish(&self, key: &str, total: Duration, timings: &Timings) {
+        if self.is_slow(total) {
+            warn!(
+                key,
+                total_ms = millis(total),
+                parse_ms = millis(timings.parse),
+                store_ms = millis(timings.store),
+                ring_ms = millis(timings.ring),
+                "slow pkarr publish"
+            );
+        }
+    }
+}
+
+/// Milliseconds, with microsecond precision.
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
@@ -1,6 +1,27 @@
 //! Shared state and store for the iroh-dns-server
 
-use crate::{dns::DnsHandler, store::ZoneStore};
+use std::{collections::BTreeMap, fmt, net::SocketAddr, sync::Arc};
+
+use parking_lot::RwLock;
+use tokio::sync::broadcast;
+
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::{
+    abuse::{AbuseLog, IpReputation},
+    acl::Acl,
+    analytics::Analytics,
+    dns::DnsHandler,
+    health::Health,
+    hourly_stats::HourlyStats,
+    http::{
+        rate_limiting::{self, HttpRateLimiter, RateLimitClass},
+        AuthProviders, PacketValidators,
+    },
+    retention::RetentionConfig,
+    store::ZoneStore,
+    usage::Usage,
+};
 
 /// The shared app state.
 #[derive(Clone)]
@@ -9,4 +30,130 @@ pub struct AppState {
     pub store: ZoneStore,
     /// Handler for DNS requests
     pub dns_handler: DnsHandler,
+    /// The addresses the servers are bound to
+    pub bound_addrs: BoundAddrs,
+    /// The errors of the server tasks
+    pub task_errors: TaskErrors,
+    /// The rate limiters of the HTTP server, to change their config at runtime
+    pub(crate) rate_limiters: RateLimiters,
+    /// Whether the server is healthy, for `/readyz`
+    pub(crate) health: Health,
+    /// The providers that authorize the requests to the admin endpoints, the change feed and the
+    /// publishes
+    pub(crate) auth: AuthProviders,
+    /// The validators of the packets published with `PUT /pkarr/{key}`
+    pub(crate) packet_validators: PacketValidators,
+    /// The maximum age of packets, for `/admin/db-gc`
+    pub(crate) retention: Option<RetentionConfig>,
+    /// The log of the clients that exceed the rate limits or publish invalid packets
+    pub(crate) abuse_log: AbuseLog,
+    /// The addresses whose connections are dropped by the HTTP and HTTPS listeners
+    pub(crate) ip_reputation: IpReputation,
+    /// The networks that may look up, publish and call the admin endpoints
+    pub(crate) acl: Acl,
+    /// The resolution counts, if they are enabled
+    pub(crate) analytics: Option<Analytics>,
+    /// The usage counts of the tenants and API keys, if they are enabled
+    pub(crate) usage: Option<Usage>,
+    /// The hourly statistics, if they are enabled
+    pub(crate) hourly_stats: Option<HourlyStats>,
+    /// The injected faults, for tests of the clients
+    #[cfg(feature = "chaos")]
+    pub(crate) chaos: Chaos,
+}
+
+/// The addresses the servers are bound to, by server, added as the servers start.
+#[derive(Debug, Clone, Default)]
+pub struct BoundAddrs(Arc<RwLock<BTreeMap<&'static str, Vec<SocketAddr>>>>);
+
+impl BoundAddrs {
+    /// Add an address that `server` (e.g. `http`) is bound to.
+    pub(crate) fn add(&self, server: &'static str, addr: SocketAddr) {
+        self.0.write().entry(server).or_default().push(addr);
+    }
+
+    /// Get the addresses by server.
+    pub fn get(&self) -> BTreeMap<&'static str, Vec<SocketAddr>> {
+        self.0.read().clone()
+    }
+}
+
+/// Number of task errors that are buffered for each subscriber.
+const TASK_ERRORS_CAPACITY: usize = 16;
+
+/// An error that stopped a task of the server
+#[derive(Debug, Clone)]
+pub struct TaskError {
+    /// The server the task belongs to, e.g. `http`
+    pub task: &'static str,
+    /// The error, with its causes
+    pub error: String,
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} task failed: {}", self.task, self.error)
+    }
+}
+
+/// The errors of the server tasks, sent to all subscribers as they happen.
+#[derive(Debug, Clone)]
+pub struct TaskErrors(broadcast::Sender<TaskError>);
+
+impl Default for TaskErrors {
+    fn default() -> Self {
+        Self(broadcast::channel(TASK_ERRORS_CAPACITY).0)
+    }
+}
+
+impl TaskErrors {
+    /// Report that a task of `task` (e.g. `http`) failed with `error`.
+    pub(crate) fn report(&self, task: &'static str, error: impl fmt::Display) {
+        let error = TaskError {
+            task,
+            error: format!("{error:#}"),
+        };
+        // nobody may be subscribed
+        self.0.send(error).ok();
+    }
+
+    /// Subscribe to the errors that are reported from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<TaskError> {
+        self.0.subscribe()
+    }
+}
+
+/// The rate limiters of the HTTP server, added as the server starts.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimiters(Arc<RwLock<Vec<Arc<HttpRateLimiter>>>>);
+
+impl RateLimiters {
+    pub(crate) fn add(&self, limiter: Arc<HttpRateLimiter>) {
+        self.0.write().push(limiter);
+    }
+
+    /// Get all rate limiters.
+    pub(crate) fn all(&self) -> Vec<Arc<HttpRateLimiter>> {
+        self.0.read().clone()
+    }
+
+    /// Remove the expired keys of the rate limiters in the GC interval, until the task is
+    /// aborted.
+    pub(crate) async fn run_gc(self) {
+        loop {
+            tokio::time::sleep(rate_limiting::GC_INTERVAL).await;
+            for limiter in self.all() {
+                limiter.gc();
+            }
+        }
+    }
+
+    /// Get the rate limiter of `class`, if rate limiting is enabled for it.
+    pub(crate) fn get(&self, class: RateLimitClass) -> Option<Arc<HttpRateLimiter>> {
+        self.0
+            .read()
+            .iter()
+            .find(|limiter| limiter.class() == class)
+            .cloned()
+    }
 }
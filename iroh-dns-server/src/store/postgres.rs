@@ -0,0 +1,413 @@
+//! The store of the signed packets in Postgres, shared by the servers of a deployment
+//!
+//! Writes are serialized with a transaction-level advisory lock, so that the sequence numbers of
+//! the changes are committed in order and readers of the change feed don't skip any. Each write
+//! is announced with a `NOTIFY`, which the other servers use to invalidate their cached zones.
+//!
+//! The connections use TLS as the `sslmode` of the URL asks for it, `prefer` by default, with
+//! the web PKI roots and an optional CA certificate of the database.
+//!
+//! The API keys are kept alongside the packets, so that all servers accept the same keys.
+//!
+//! The leader of the servers holds a session-level advisory lock on a connection of its own, and
+//! keeps it until the connection is lost or the store is dropped.
+
+use std::{future::poll_fn, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use deadpool_postgres::{ClientWrapper, Manager, ManagerConfig, Object, Pool, RecyclingMethod};
+use iroh_metrics::{inc, inc_by};
+use pkarr::SignedPacket;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::sync::{broadcast, Mutex};
+use tokio_postgres::{AsyncMessage, Row};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{debug, info, warn};
+
+use super::signed_packets::{ExternalChange, SignedPacketStore};
+use crate::{metrics::Metrics, util::PublicKeyBytes};
+
+/// The schema of the store, created if it doesn't exist.
+const SCHEMA: &str = "
+CREATE SEQUENCE IF NOT EXISTS signed_packet_changes;
+CREATE TABLE IF NOT EXISTS signed_packets (
+    pubkey BYTEA PRIMARY KEY,
+    packet BYTEA NOT NULL,
+    change_seq BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS signed_packets_change_seq ON signed_packets (change_seq);
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    key BYTEA NOT NULL
+);
+";
+/// The key of the advisory lock that serializes the writes and the creation of the schema.
+const WRITE_LOCK: i64 = 0x69726f68_646e7300;
+/// The key of the advisory lock that is held by the leader of the servers.
+const LEADER_LOCK: i64 = 0x69726f68_646e7301;
+/// The channel the writes are announced on.
+const CHANNEL: &str = "signed_packets";
+/// Delay until the listener reconnects after it lost the connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Number of changes by other servers that are buffered for the invalidation of the cache.
+const WATCH_CAPACITY: usize = 1024;
+
+/// The store of the signed packets in a Postgres database
+#[derive(derive_more::Debug)]
+pub struct PostgresStore {
+    #[debug("Pool")]
+    pool: Pool,
+    /// A random ID of this store, to tell its own notifications from those of other servers
+    origin: String,
+    changes: broadcast::Sender<ExternalChange>,
+    _listener: AbortOnDropHandle<()>,
+    /// The connection that holds the leader lock, if this server is the leader
+    #[debug(skip)]
+    leader: Mutex<Option<ClientWrapper>>,
+}
+
+impl PostgresStore {
+    /// Connect to the database at `url` with up to `pool_size` connections, and create the
+    /// schema if it doesn't exist.
+    ///
+    /// The certificate of the database is verified against the web PKI roots, and the CA
+    /// certificates in the PEM file at `ca_cert`.
+    pub async fn connect(url: &str, pool_size: usize, ca_cert: Option<&Path>) -> Result<Self> {
+        let config: tokio_postgres::Config = url.parse().context("invalid Postgres URL")?;
+        let tls = tls_connector(ca_cert).await?;
+        let manager = Manager::from_config(
+            config.clone(),
+            tls.clone(),
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager).max_size(pool_size).build()?;
+        let mut client = pool
+            .get()
+            .await
+            .context("failed to connect to the Postgres store")?;
+        let tx = client.transaction().await?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&WRITE_LOCK])
+            .await?;
+        tx.batch_execute(SCHEMA).await?;
+        tx.commit().await?;
+        info!(
+            host = ?config.get_hosts(),
+            dbname = config.get_dbname(),
+            "using Postgres packet store"
+        );
+        let origin = hex::encode(rand::random::<[u8; 8]>());
+        let changes = broadcast::channel(WATCH_CAPACITY).0;
+        let listener = tokio::spawn(listen(config, tls, origin.clone(), changes.clone()));
+        Ok(Self {
+            pool,
+            origin,
+            changes,
+            _listener: AbortOnDropHandle::new(listener),
+            leader: Mutex::new(None),
+        })
+    }
+
+    /// Get the pool of the connections, to store other data alongside the packets.
+    pub(crate) fn pool(&self) -> Pool {
+        self.pool.clone()
+    }
+
+    /// Announce a write of the packet of `key` to the other servers.
+    async fn notify(
+        &self,
+        tx: &tokio_postgres::Transaction<'_>,
+        key: &PublicKeyBytes,
+    ) -> Result<()> {
+        let payload = format!("{} {}", self.origin, key.to_z32());
+        tx.execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SignedPacketStore for PostgresStore {
+    async fn upsert(&self, packet: SignedPacket) -> Result<bool> {
+        let key = PublicKeyBytes::from_signed_packet(&packet);
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&WRITE_LOCK])
+            .await?;
+        let existing = tx
+            .query_opt(
+                "SELECT packet FROM signed_packets WHERE pubkey = $1",
+                &[&key.as_bytes().as_slice()],
+            )
+            .await?;
+        let replaced = match existing {
+            Some(row) => {
+                let bytes: Vec<u8> = row.get(0);
+                let existing = SignedPacket::from_bytes(&bytes.into())?;
+                if existing.more_recent_than(&packet) {
+                    return Ok(false);
+                }
+                true
+            }
+            None => false,
+        };
+        tx.execute(
+            "INSERT INTO signed_packets (pubkey, packet, change_seq)
+             VALUES ($1, $2, nextval('signed_packet_changes'))
+             ON CONFLICT (pubkey) DO UPDATE
+             SET packet = EXCLUDED.packet, change_seq = EXCLUDED.change_seq",
+            &[&key.as_bytes().as_slice(), &&packet.as_bytes()[..]],
+        )
+        .await?;
+        self.notify(&tx, &key).await?;
+        tx.commit().await?;
+        if replaced {
+            inc!(Metrics, store_packets_updated);
+        } else {
+            inc!(Metrics, store_packets_inserted);
+        }
+        Ok(true)
+    }
+
+    async fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt(
+                "SELECT packet FROM signed_packets WHERE pubkey = $1",
+                &[&key.as_bytes().as_slice()],
+            )
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let bytes: Vec<u8> = row.get(0);
+        Ok(Some(SignedPacket::from_bytes(&bytes.into())?))
+    }
+
+    async fn raw_packets_after(
+        &self,
+        after: Option<PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<(PublicKeyBytes, Vec<u8>)>> {
+        let client = self.pool.get().await?;
+        // an empty key sorts before all keys
+        let after = after.map(|key| key.as_bytes().to_vec()).unwrap_or_default();
+        let rows = client
+            .query(
+                "SELECT pubkey, packet FROM signed_packets WHERE pubkey > $1
+                 ORDER BY pubkey LIMIT $2",
+                &[&after, &(limit as i64)],
+            )
+            .await?;
+        rows.into_iter()
+            .map(|row| Ok((key_of(&row)?, row.get(1))))
+            .collect()
+    }
+
+    async fn changes_after(&self, since: u64, limit: usize) -> Result<Vec<(u64, SignedPacket)>> {
+        let client = self.pool.get().await?;
+        let rows = client
+            .query(
+                "SELECT change_seq, packet FROM signed_packets WHERE change_seq > $1
+                 ORDER BY change_seq LIMIT $2",
+                &[&(since.min(i64::MAX as u64) as i64), &(limit as i64)],
+            )
+            .await?;
+        rows.into_iter()
+            .map(|row| {
+                let seq: i64 = row.get(0);
+                let bytes: Vec<u8> = row.get(1);
+                Ok((seq as u64, SignedPacket::from_bytes(&bytes.into())?))
+            })
+            .collect()
+    }
+
+    async fn last_change(&self) -> Result<u64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "SELECT COALESCE(MAX(change_seq), 0) FROM signed_packets",
+                &[],
+            )
+            .await?;
+        let last: i64 = row.get(0);
+        Ok(last as u64)
+    }
+
+    async fn len(&self) -> Result<u64> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one("SELECT COUNT(*) FROM signed_packets", &[])
+            .await?;
+        let len: i64 = row.get(0);
+        Ok(len as u64)
+    }
+
+    async fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&WRITE_LOCK])
+            .await?;
+        let removed = tx
+            .execute(
+                "DELETE FROM signed_packets WHERE pubkey = $1",
+                &[&key.as_bytes().as_slice()],
+            )
+            .await?
+            > 0;
+        if removed {
+            self.notify(&tx, key).await?;
+        }
+        tx.commit().await?;
+        if removed {
+            inc!(Metrics, store_packets_removed)
+        }
+        Ok(removed)
+    }
+
+    async fn remove_unchanged(
+        &self,
+        packets: &[(PublicKeyBytes, Vec<u8>)],
+    ) -> Result<Vec<PublicKeyBytes>> {
+        let keys: Vec<&[u8]> = packets.iter().map(|(key, _)| &key.as_bytes()[..]).collect();
+        let bytes: Vec<&[u8]> = packets.iter().map(|(_, bytes)| &bytes[..]).collect();
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&WRITE_LOCK])
+            .await?;
+        let rows = tx
+            .query(
+                "DELETE FROM signed_packets AS p
+                 USING unnest($1::bytea[], $2::bytea[]) AS e (pubkey, packet)
+                 WHERE p.pubkey = e.pubkey AND p.packet = e.packet
+                 RETURNING p.pubkey",
+                &[&keys, &bytes],
+            )
+            .await?;
+        let removed = rows.iter().map(key_of).collect::<Result<Vec<_>>>()?;
+        for key in &removed {
+            self.notify(&tx, key).await?;
+        }
+        tx.commit().await?;
+        inc_by!(Metrics, store_packets_removed, removed.len() as u64);
+        Ok(removed)
+    }
+
+    fn watch(&self) -> Option<broadcast::Receiver<ExternalChange>> {
+        Some(self.changes.subscribe())
+    }
+
+    async fn is_leader(&self) -> Result<bool> {
+        let mut leader = self.leader.lock().await;
+        if let Some(client) = leader.as_ref() {
+            // the lock is held as long as its connection is alive
+            if client.simple_query("SELECT 1").await.is_ok() {
+                return Ok(true);
+            }
+            warn!("lost the connection of the leader lock, no longer leading the servers");
+            *leader = None;
+        }
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one("SELECT pg_try_advisory_lock($1)", &[&LEADER_LOCK])
+            .await?;
+        if !row.get::<_, bool>(0) {
+            return Ok(false);
+        }
+        info!("leading the servers of the Postgres store");
+        // the connection must not return to the pool while it holds the lock, it is closed when
+        // it is dropped
+        *leader = Some(Object::take(client));
+        Ok(true)
+    }
+}
+
+/// Create the TLS connector, with the web PKI roots and the CA certificates in the PEM file at
+/// `ca_cert`.
+async fn tls_connector(ca_cert: Option<&Path>) -> Result<MakeRustlsConnect> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(path) = ca_cert {
+        let pem = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        for cert in rustls_pemfile::certs(&mut &pem[..]) {
+            roots
+                .add(cert?)
+                .context("invalid CA certificate for the Postgres store")?;
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+/// Get the key in the first column of `row`.
+fn key_of(row: &Row) -> Result<PublicKeyBytes> {
+    let key: Vec<u8> = row.get(0);
+    let key: [u8; 32] = key.try_into().ok().context("invalid key in the store")?;
+    Ok(key.into())
+}
+
+/// Listen for the writes of other servers, and send them to `changes`, until the task is
+/// aborted.
+///
+/// Writes may be missed while the connection is lost, so after a reconnect all packets are
+/// considered changed.
+async fn listen(
+    config: tokio_postgres::Config,
+    tls: MakeRustlsConnect,
+    origin: String,
+    changes: broadcast::Sender<ExternalChange>,
+) {
+    let mut connected = false;
+    loop {
+        let res = async {
+            let (client, mut connection) = config.connect(tls.clone()).await?;
+            let (notifications, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            let connection = async move {
+                while let Some(message) = poll_fn(|cx| connection.poll_message(cx)).await {
+                    if let AsyncMessage::Notification(notification) = message? {
+                        notifications.send(notification).ok();
+                    }
+                }
+                anyhow::Ok(())
+            };
+            let notifications = async {
+                client.batch_execute(&format!("LISTEN {CHANNEL}")).await?;
+                if connected {
+                    changes.send(ExternalChange::All).ok();
+                }
+                connected = true;
+                debug!("listening for the writes of other servers");
+                while let Some(notification) = rx.recv().await {
+                    let Some((from, key)) = notification.payload().split_once(' ') else {
+                        continue;
+                    };
+                    if from == origin {
+                        continue;
+                    }
+                    match PublicKeyBytes::from_z32(key) {
+                        // there are no receivers if the cache is not invalidated
+                        Ok(key) => changes.send(ExternalChange::Key(key)).ok(),
+                        Err(err) => {
+                            debug!("invalid notification {:?}: {err}", notification.payload());
+                            None
+                        }
+                    };
+                }
+                anyhow::Ok(())
+            };
+            tokio::try_join!(connection, notifications)?;
+            anyhow::Ok(())
+        };
+        if let Err(err) = res.await {
+            warn!("lost the connection for the writes of other servers: {err:#}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
@@ -1,9 +1,17 @@
-use std::path::Path;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
-use iroh_metrics::inc;
+use async_trait::async_trait;
+use iroh_metrics::{inc, inc_by};
 use pkarr::SignedPacket;
-use redb::{backends::InMemoryBackend, Database, ReadableTable, TableDefinition};
+use redb::{
+    backends::InMemoryBackend, Database, ReadableTable, ReadableTableMetadata, TableDefinition,
+};
+use tokio::sync::broadcast;
 use tracing::info;
 
 use crate::{metrics::Metrics, util::PublicKeyBytes};
@@ -11,13 +19,118 @@ use crate::{metrics::Metrics, util::PublicKeyBytes};
 pub type SignedPacketsKey = [u8; 32];
 const SIGNED_PACKETS_TABLE: TableDefinition<&SignedPacketsKey, &[u8]> =
     TableDefinition::new("signed-packets-1");
+/// The key of the changed packet by the sequence number of the change, for the change feed
+const CHANGES_TABLE: TableDefinition<u64, &SignedPacketsKey> = TableDefinition::new("changes-1");
+/// The sequence number of the last change by key
+const CHANGE_SEQS_TABLE: TableDefinition<&SignedPacketsKey, u64> =
+    TableDefinition::new("change-seqs-1");
 
-#[derive(Debug)]
-pub struct SignedPacketStore {
-    db: Database,
+/// A store of the signed packets by their keys, with a feed of the changes
+///
+/// A stored packet is only replaced by a more recent packet of its key. The changes are numbered
+/// with increasing sequence numbers, and only the last change of each key is kept in the feed.
+#[async_trait]
+pub trait SignedPacketStore: fmt::Debug + Send + Sync + 'static {
+    /// Store the packet, unless a more recent packet of its key is stored.
+    ///
+    /// Returns whether the packet was stored.
+    async fn upsert(&self, packet: SignedPacket) -> Result<bool>;
+
+    /// Get the packet of `key`.
+    async fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>>;
+
+    /// Get up to `limit` keys with the bytes of their packets, in the order of the keys, starting
+    /// after `after`, without decoding the packets.
+    async fn raw_packets_after(
+        &self,
+        after: Option<PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<(PublicKeyBytes, Vec<u8>)>>;
+
+    /// Get up to `limit` packets, in the order of their keys, starting after `after`.
+    async fn packets_after(
+        &self,
+        after: Option<PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<SignedPacket>> {
+        self.raw_packets_after(after, limit)
+            .await?
+            .into_iter()
+            .map(|(_key, bytes)| Ok(SignedPacket::from_bytes(&bytes.into())?))
+            .collect()
+    }
+
+    /// Get up to `limit` of the packets that changed after the change `since`, with the sequence
+    /// numbers of their changes, in the order of the changes.
+    ///
+    /// Removals are not part of the feed.
+    async fn changes_after(&self, since: u64, limit: usize) -> Result<Vec<(u64, SignedPacket)>>;
+
+    /// Get the sequence number of the last change, or 0 if nothing changed yet.
+    async fn last_change(&self) -> Result<u64>;
+
+    /// Get the number of stored packets.
+    async fn len(&self) -> Result<u64>;
+
+    /// Remove the packet of `key`.
+    ///
+    /// Returns whether there was a packet.
+    async fn remove(&self, key: &PublicKeyBytes) -> Result<bool>;
+
+    /// Remove each of `packets`, keys with the bytes of their packets, unless another packet of
+    /// the key was stored since.
+    ///
+    /// Returns the keys of the removed packets.
+    async fn remove_unchanged(
+        &self,
+        packets: &[(PublicKeyBytes, Vec<u8>)],
+    ) -> Result<Vec<PublicKeyBytes>> {
+        let mut removed = Vec::new();
+        for (key, bytes) in packets {
+            let stored = self.get(key).await?;
+            let unchanged = stored.is_some_and(|packet| packet.as_bytes()[..] == bytes[..]);
+            if unchanged && self.remove(key).await? {
+                removed.push(*key);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Receive the changes of the packets by other servers, if the store is shared with them.
+    fn watch(&self) -> Option<broadcast::Receiver<ExternalChange>> {
+        None
+    }
+
+    /// Whether this server leads the servers that share the store.
+    ///
+    /// Only the leader runs the duties that every server would otherwise repeat, like the removal
+    /// of old packets, the republishing to the mainline DHT and the NOTIFY to the secondaries. A
+    /// store that is not shared is always led by its server.
+    async fn is_leader(&self) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// A change of the packets in a shared store by another server
+#[derive(Debug, Clone, Copy)]
+pub enum ExternalChange {
+    /// The packet of the key was changed or removed
+    Key(PublicKeyBytes),
+    /// Any of the packets may have been changed or removed
+    All,
+}
+
+/// The store of the signed packets in a database file, or in memory
+///
+/// The database also keeps the other data of the server, e.g. the API keys.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    db: Arc<Database>,
+    /// The path of the database file, if it is persistent
+    path: Option<PathBuf>,
 }
 
-impl SignedPacketStore {
+impl FileStore {
     pub fn persistent(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref();
         info!("loading packet database from {}", path.to_string_lossy());
@@ -32,7 +145,10 @@ impl SignedPacketStore {
         let db = Database::builder()
             .create(path)
             .context("failed to open packet database")?;
-        Self::open(db)
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            ..Self::open(db)?
+        })
     }
 
     pub fn in_memory() -> Result<Self> {
@@ -44,13 +160,77 @@ impl SignedPacketStore {
     pub fn open(db: Database) -> Result<Self> {
         let write_tx = db.begin_write()?;
         {
-            let _table = write_tx.open_table(SIGNED_PACKETS_TABLE)?;
+            let table = write_tx.open_table(SIGNED_PACKETS_TABLE)?;
+            let mut changes = write_tx.open_table(CHANGES_TABLE)?;
+            let mut seqs = write_tx.open_table(CHANGE_SEQS_TABLE)?;
+            // packets stored before the change feed existed are added to it once
+            if changes.is_empty()? && !table.is_empty()? {
+                info!("adding the stored packets to the change feed");
+                for (seq, row) in (1..).zip(table.iter()?) {
+                    let (key, _value) = row?;
+                    changes.insert(seq, key.value())?;
+                    seqs.insert(key.value(), seq)?;
+                }
+            }
         }
         write_tx.commit()?;
-        Ok(Self { db })
+        Ok(Self {
+            db: Arc::new(db),
+            path: None,
+        })
+    }
+
+    /// Get the path of the database file, if it is persistent.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Get the underlying database, to store other data alongside the packets.
+    pub fn database(&self) -> Arc<Database> {
+        self.db.clone()
+    }
+
+    fn scan(
+        &self,
+        after: Option<&PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<(PublicKeyBytes, Vec<u8>)>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+        let range = match after {
+            Some(key) => table.range::<&SignedPacketsKey>((
+                std::ops::Bound::Excluded(key.as_bytes()),
+                std::ops::Bound::Unbounded,
+            ))?,
+            None => table.range::<&SignedPacketsKey>(..)?,
+        };
+        let mut packets = Vec::new();
+        for row in range.take(limit) {
+            let (key, value) = row?;
+            packets.push(((*key.value()).into(), value.value().to_vec()));
+        }
+        Ok(packets)
+    }
+
+    fn changes(&self, since: u64, limit: usize) -> Result<Vec<(u64, SignedPacket)>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+        let changes = tx.open_table(CHANGES_TABLE)?;
+        let mut packets = Vec::new();
+        for row in changes.range(since.saturating_add(1)..)?.take(limit) {
+            let (seq, key) = row?;
+            let key = PublicKeyBytes::from(*key.value());
+            if let Some(packet) = get_packet(&table, &key)? {
+                packets.push((seq.value(), packet));
+            }
+        }
+        Ok(packets)
     }
+}
 
-    pub fn upsert(&self, packet: SignedPacket) -> Result<bool> {
+#[async_trait]
+impl SignedPacketStore for FileStore {
+    async fn upsert(&self, packet: SignedPacket) -> Result<bool> {
         let key = PublicKeyBytes::from_signed_packet(&packet);
         let tx = self.db.begin_write()?;
         let mut replaced = false;
@@ -65,6 +245,15 @@ impl SignedPacketStore {
             }
             let value = packet.as_bytes();
             table.insert(key.as_bytes(), &value[..])?;
+            let mut changes = tx.open_table(CHANGES_TABLE)?;
+            let mut seqs = tx.open_table(CHANGE_SEQS_TABLE)?;
+            let seq = changes.last()?.map(|(seq, _)| seq.value()).unwrap_or(0) + 1;
+            // keep only the last change of each key
+            let previous = seqs.insert(key.as_bytes(), seq)?.map(|seq| seq.value());
+            if let Some(previous) = previous {
+                changes.remove(previous)?;
+            }
+            changes.insert(seq, key.as_bytes())?;
         }
         tx.commit()?;
         if replaced {
@@ -75,18 +264,50 @@ impl SignedPacketStore {
         Ok(true)
     }
 
-    pub fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
+    async fn get(&self, key: &PublicKeyBytes) -> Result<Option<SignedPacket>> {
         let tx = self.db.begin_read()?;
         let table = tx.open_table(SIGNED_PACKETS_TABLE)?;
         get_packet(&table, key)
     }
 
-    pub fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
+    async fn raw_packets_after(
+        &self,
+        after: Option<PublicKeyBytes>,
+        limit: usize,
+    ) -> Result<Vec<(PublicKeyBytes, Vec<u8>)>> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.scan(after.as_ref(), limit)).await?
+    }
+
+    async fn changes_after(&self, since: u64, limit: usize) -> Result<Vec<(u64, SignedPacket)>> {
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.changes(since, limit)).await?
+    }
+
+    async fn last_change(&self) -> Result<u64> {
+        let tx = self.db.begin_read()?;
+        let changes = tx.open_table(CHANGES_TABLE)?;
+        let last = changes.last()?.map(|(seq, _)| seq.value());
+        Ok(last.unwrap_or(0))
+    }
+
+    async fn len(&self) -> Result<u64> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+        Ok(table.len()?)
+    }
+
+    async fn remove(&self, key: &PublicKeyBytes) -> Result<bool> {
         let tx = self.db.begin_write()?;
         let updated = {
             let mut table = tx.open_table(SIGNED_PACKETS_TABLE)?;
             let did_remove = table.remove(key.as_bytes())?.is_some();
-            #[allow(clippy::let_and_return)]
+            let mut changes = tx.open_table(CHANGES_TABLE)?;
+            let mut seqs = tx.open_table(CHANGE_SEQS_TABLE)?;
+            let seq = seqs.remove(key.as_bytes())?.map(|seq| seq.value());
+            if let Some(seq) = seq {
+                changes.remove(seq)?;
+            }
             did_remove
         };
         tx.commit()?;
@@ -95,6 +316,36 @@ impl SignedPacketStore {
         }
         Ok(updated)
     }
+
+    async fn remove_unchanged(
+        &self,
+        packets: &[(PublicKeyBytes, Vec<u8>)],
+    ) -> Result<Vec<PublicKeyBytes>> {
+        let tx = self.db.begin_write()?;
+        let mut removed = Vec::new();
+        {
+            let mut table = tx.open_table(SIGNED_PACKETS_TABLE)?;
+            let mut changes = tx.open_table(CHANGES_TABLE)?;
+            let mut seqs = tx.open_table(CHANGE_SEQS_TABLE)?;
+            for (key, bytes) in packets {
+                let unchanged = table
+                    .get(key.as_bytes())?
+                    .is_some_and(|row| row.value() == &bytes[..]);
+                if !unchanged {
+                    continue;
+                }
+                table.remove(key.as_bytes())?;
+                let seq = seqs.remove(key.as_bytes())?.map(|seq| seq.value());
+                if let Some(seq) = seq {
+                    changes.remove(seq)?;
+                }
+                removed.push(*key);
+            }
+        }
+        tx.commit()?;
+        inc_by!(Metrics, store_packets_removed, removed.len() as u64);
+        Ok(removed)
+    }
 }
 
 fn get_packet(
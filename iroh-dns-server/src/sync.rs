@@ -0,0 +1,221 @@
+//! Tailing the packet change feed of another server
+//!
+//! Every packet that changes the store of a server gets a new sequence number in its change feed,
+//! served at `/sync` to clients with a [`crate::api_keys::ApiKeyScope::Sync`] API key. A server
+//! with a [`SyncConfig`] polls the feed of its upstream server from the cursor of the last batch,
+//! and stores the packets. The cursor is persisted, so a restarted server continues where it
+//! stopped. This keeps a passive server in sync with an active one without a gossip mesh.
+//!
+//! The state of the upstream server is exported in the `peer_up` metric. While its requests
+//! fail, the feed is polled less often, up to every [`MAX_BACKOFF`].
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use iroh_metrics::inc;
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use url::Url;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::{
+    events::ServerEvent,
+    metrics::{Metrics, PeerKind, PeerMetrics},
+    proxy::HttpClient,
+    secrets::{RefreshingSecret, SecretValue},
+    store::{PacketSource, ZoneStore},
+};
+
+/// The maximum number of packets in a batch of the change feed.
+pub(crate) const MAX_BATCH: usize = 1000;
+/// The file in the data directory with the cursor of the last stored batch
+const CURSOR_FILE: &str = "sync-cursor";
+/// Default interval in seconds in which the feed is polled.
+const DEFAULT_INTERVAL_SECS: u64 = 5;
+/// Timeout of the requests to the upstream server.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The longest interval in which a failing upstream server is polled.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Config for tailing the change feed of another server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncConfig {
+    /// The HTTP base URL of the upstream server, e.g. `https://dns1.example.org/`
+    pub upstream: Url,
+    /// An API key of the upstream server with the `sync` scope.
+    pub token: SecretValue,
+    /// Interval in seconds in which the feed is polled when there are no new changes
+    /// (defaults to 5).
+    pub interval_secs: Option<u64>,
+    /// The proxy for the requests to the upstream server, e.g. `socks5h://127.0.0.1:9050` for
+    /// Tor (direct if unset).
+    ///
+    /// `http://`, `https://`, `socks5://` and `socks5h://` proxies are supported. The mainline
+    /// DHT is never proxied.
+    #[serde(default)]
+    pub proxy: Option<Url>,
+}
+
+/// A batch of the change feed
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct SyncBatch {
+    /// The cursor to get the next batch with, as `since`
+    pub cursor: u64,
+    /// Whether there are more changes after the cursor
+    pub more: bool,
+    /// The signed packets, base64 encoded as public key, signature, timestamp and DNS packet
+    pub packets: Vec<String>,
+}
+
+/// Tail the change feed of the upstream server until the task is aborted.
+pub(crate) async fn spawn(
+    config: &SyncConfig,
+    store: ZoneStore,
+    data_dir: &Path,
+) -> Result<JoinHandle<()>> {
+    let cursor_path = data_dir.join(CURSOR_FILE);
+    let cursor = match std::fs::read_to_string(&cursor_path) {
+        Ok(cursor) => cursor
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid sync cursor in {}", cursor_path.display()))?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err).context("failed to read the sync cursor"),
+    };
+    let tail = Tail {
+        url: config.upstream.join("sync")?,
+        token: RefreshingSecret::new(&config.token).await?,
+        client: HttpClient::new(config.proxy.as_ref(), REQUEST_TIMEOUT)?,
+        store,
+        cursor_path,
+    };
+    let interval = Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS));
+    info!(upstream = %config.upstream, cursor, "tailing the change feed");
+    Ok(tokio::spawn(tail.run(cursor, interval)))
+}
+
+/// The interval after `failures` failed requests in a row, doubled for each up to [`MAX_BACKOFF`].
+fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 1u32 << failures.min(16);
+    interval
+        .saturating_mul(factor)
+        .min(MAX_BACKOFF.max(interval))
+}
+
+#[derive(Debug)]
+struct Tail {
+    url: Url,
+    token: RefreshingSecret,
+    client: HttpClient,
+    store: ZoneStore,
+    cursor_path: PathBuf,
+}
+
+impl Tail {
+    async fn run(self, mut cursor: u64, interval: Duration) {
+        // only the first failure is sent as event, until a request succeeds again
+        let mut failures = 0u32;
+        let upstream = self.url.to_string();
+        loop {
+            match self.poll(cursor).await {
+                Ok(batch) => {
+                    failures = 0;
+                    PeerMetrics::set_up(PeerKind::Sync, &upstream, true);
+                    if batch.cursor < cursor {
+                        warn!(
+                            cursor,
+                            upstream = batch.cursor,
+                            "the upstream feed is behind the cursor, syncing from the start"
+                        );
+                        cursor = 0;
+                    } else {
+                        cursor = batch.cursor;
+                    }
+                    if let Err(err) = tokio::fs::write(&self.cursor_path, cursor.to_string()).await
+                    {
+                        warn!("failed to write the sync cursor: {err:#}");
+                    }
+                    if batch.more {
+                        continue;
+                    }
+                }
+                Err(err) => {
+                    inc!(Metrics, sync_requests_failed);
+                    PeerMetrics::set_up(PeerKind::Sync, &upstream, false);
+                    warn!("failed to sync from upstream: {err:#}");
+                    if failures == 0 {
+                        self.store.events().send(ServerEvent::UpstreamUnhealthy {
+                            upstream: upstream.clone(),
+                            error: format!("{err:#}"),
+                        });
+                    }
+                    failures = failures.saturating_add(1);
+                }
+            }
+            tokio::time::sleep(backoff(interval, failures)).await;
+        }
+    }
+
+    /// Get the batch after `cursor`, and store its packets.
+    async fn poll(&self, cursor: u64) -> Result<SyncBatch> {
+        let batch: SyncBatch = self
+            .client
+            .get(self.url.clone())
+            .query(&[("since", cursor)])
+            .bearer_auth(self.token.get())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        debug!(
+            cursor = batch.cursor,
+            "synced {} packets",
+            batch.packets.len()
+        );
+        for packet in &batch.packets {
+            let signed_packet = match STANDARD
+                .decode(packet)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(SignedPacket::from_bytes(&bytes.into())?))
+            {
+                Ok(signed_packet) => signed_packet,
+                Err(err) => {
+                    // skip it, so that one bad packet doesn't stop the feed
+                    inc!(Metrics, sync_packets_invalid);
+                    warn!("invalid packet in the change feed: {err:#}");
+                    continue;
+                }
+            };
+            if self.store.insert(signed_packet, PacketSource::Sync).await? {
+                inc!(Metrics, sync_packets_stored);
+            }
+        }
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_after_failures() {
+        let interval = Duration::from_secs(5);
+        assert_eq!(backoff(interval, 0), interval);
+        assert_eq!(backoff(interval, 1), Duration::from_secs(10));
+        assert_eq!(backoff(interval, 3), Duration::from_secs(40));
+        assert_eq!(backoff(interval, 10), MAX_BACKOFF);
+        assert_eq!(backoff(interval, u32::MAX), MAX_BACKOFF);
+        let long = Duration::from_secs(600);
+        assert_eq!(backoff(long, 2), long);
+    }
+}
@@ -0,0 +1,192 @@
+//! Sockets passed by systemd socket activation
+//!
+//! With socket activation, systemd binds the sockets of a `.socket` unit and passes them to the
+//! server as file descriptors, announced in the `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`
+//! environment variables. This way the server can listen on privileged ports, e.g. 53 and 443,
+//! without running as root or having `CAP_NET_BIND_SERVICE`.
+//!
+//! Sockets named `http`, `https` or `dns` (with `FileDescriptorName=`) replace the bind addresses
+//! of that server. Every passed socket is used instead of binding a new socket with the same
+//! local address and type, so unnamed sockets can also be matched with the configured addresses.
+//!
+//! The server also notifies systemd when it is ready and when it stops (with `Type=notify`), and
+//! sends keep-alive pings if the service has a `WatchdogSec=`.
+
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use tracing::{info, warn};
+
+/// A socket passed by systemd
+#[derive(Debug)]
+struct ActivatedSocket {
+    /// The `FileDescriptorName=` of the socket, `unknown` if unset
+    name: String,
+    local_addr: SocketAddr,
+    ty: socket2::Type,
+    /// The socket, until it is taken by a listener
+    socket: Option<socket2::Socket>,
+}
+
+fn sockets() -> &'static Mutex<Vec<ActivatedSocket>> {
+    static SOCKETS: OnceLock<Mutex<Vec<ActivatedSocket>>> = OnceLock::new();
+    SOCKETS.get_or_init(|| Mutex::new(from_env()))
+}
+
+/// The local addresses of the sockets named `name`, if systemd passed any.
+pub(crate) fn listen_addrs(name: &str) -> Option<Vec<SocketAddr>> {
+    let sockets = sockets().lock().expect("poisoned");
+    let mut addrs = Vec::new();
+    for socket in sockets.iter().filter(|socket| socket.name == name) {
+        if !addrs.contains(&socket.local_addr) {
+            addrs.push(socket.local_addr);
+        }
+    }
+    (!addrs.is_empty()).then_some(addrs)
+}
+
+/// Take the socket of type `ty` bound to `addr`, if systemd passed one.
+pub(crate) fn take(addr: SocketAddr, ty: socket2::Type) -> Option<socket2::Socket> {
+    let mut sockets = sockets().lock().expect("poisoned");
+    let activated = sockets
+        .iter_mut()
+        .find(|socket| socket.local_addr == addr && socket.ty == ty && socket.socket.is_some())?;
+    info!(name = %activated.name, "using inherited socket {addr}");
+    activated.socket.take()
+}
+
+/// The name of the socket of type `ty` on `addr`, if it was passed by systemd.
+pub(crate) fn activated_name(addr: SocketAddr, ty: socket2::Type) -> Option<String> {
+    let sockets = sockets().lock().expect("poisoned");
+    let activated = sockets
+        .iter()
+        .find(|socket| socket.local_addr == addr && socket.ty == ty)?;
+    Some(activated.name.clone())
+}
+
+/// Close the passed sockets that were not taken by a listener.
+pub(crate) fn close_unused() {
+    let mut sockets = sockets().lock().expect("poisoned");
+    for activated in sockets.iter_mut() {
+        if activated.socket.take().is_some() {
+            warn!(
+                name = %activated.name,
+                "closing inherited socket {}, no listener is configured for it",
+                activated.local_addr
+            );
+        }
+    }
+}
+
+/// Send a state change, e.g. `READY=1`, to the notification socket of systemd, if there is one.
+pub(crate) fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(err) = send_notification(&path, state) {
+        warn!("failed to notify systemd of {state:?}: {err}");
+    }
+}
+
+#[cfg(unix)]
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    // an `@` prefix stands for a socket in the abstract namespace
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_notification(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// The interval in which `WATCHDOG=1` must be sent, if systemd watches this process.
+///
+/// This is half of the `WatchdogSec=` of the service, so that a late ping is not fatal.
+pub(crate) fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec) / 2)
+}
+
+/// The sockets announced in the environment, if they are meant for this process.
+#[cfg(unix)]
+fn from_env() -> Vec<ActivatedSocket> {
+    use std::os::fd::FromRawFd;
+
+    /// The file descriptor of the first passed socket
+    const LISTEN_FDS_START: i32 = 3;
+
+    // the variables are inherited by child processes, which must not use the sockets
+    let for_this_process = match env::var("LISTEN_PID") {
+        Ok(pid) => pid.parse() == Ok(std::process::id()),
+        Err(_) => crate::handoff::passed_by_parent(),
+    };
+    if !for_this_process {
+        return Vec::new();
+    }
+    let Some(count) = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok())
+    else {
+        return Vec::new();
+    };
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or("unknown").to_string();
+        // SAFETY: systemd passes open file descriptors from `LISTEN_FDS_START` on, which are not
+        // used anywhere else in this process
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        let describe = || {
+            let local_addr = socket.local_addr()?.as_socket().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "not an IP socket")
+            })?;
+            let ty = socket.r#type()?;
+            socket.set_cloexec(true)?;
+            socket.set_nonblocking(true)?;
+            std::io::Result::Ok((local_addr, ty))
+        };
+        match describe() {
+            Ok((local_addr, ty)) => sockets.push(ActivatedSocket {
+                name,
+                local_addr,
+                ty,
+                socket: Some(socket),
+            }),
+            Err(err) => warn!("ignoring file descriptor {fd} ({name}) passed by systemd: {err}"),
+        }
+    }
+    sockets
+}
+
+/// There is no systemd on this platform.
+#[cfg(not(unix))]
+fn from_env() -> Vec<ActivatedSocket> {
+    Vec::new()
+}
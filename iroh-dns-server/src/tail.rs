@@ -0,0 +1,314 @@
+//! Live events of the server, for the `tail` command
+//!
+//! The DNS handler and the store send the answered queries and the stored packets to subscribers,
+//! and `GET /admin/tail` streams a sample of them, filtered by pubkey and query type, as JSON
+//! lines. Query events are only built while someone is subscribed, and a subscriber that can't
+//! keep up misses events instead of slowing down the server.
+
+use std::{collections::BTreeSet, fmt, net::IpAddr};
+
+use anyhow::{bail, Result};
+use pkarr::SignedPacket;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::{query_log::QueryLog, state::AppState, store::PacketSource, util::PublicKeyBytes};
+
+/// Number of query events that are buffered for each subscriber.
+pub(crate) const QUERIES_CAPACITY: usize = 1024;
+/// Number of matching events that are buffered for each stream.
+const STREAM_CAPACITY: usize = 256;
+
+/// An answered DNS query
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryEvent {
+    /// When the query was answered, in milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    /// The IP address of the client
+    pub client: IpAddr,
+    /// The protocol of the query, e.g. `udp`
+    pub protocol: String,
+    /// The queried name
+    pub name: String,
+    /// The z-base-32 encoded pubkey in the name, if it is a pkarr name
+    pub pubkey: Option<String>,
+    /// The queried record type
+    pub qtype: String,
+    /// The response code
+    pub rcode: String,
+    /// The number of answers
+    pub answers: u16,
+    /// How long the answer took, in microseconds
+    pub duration_us: u64,
+}
+
+/// A packet that was stored
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublishEvent {
+    /// When the packet was stored, in milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    /// The z-base-32 encoded pubkey of the packet
+    pub pubkey: String,
+    /// Where the packet came from: `publish`, `gossip`, `sync` or `import`
+    pub source: String,
+    /// The number of records in the packet
+    pub records: usize,
+}
+
+/// An event streamed by `GET /admin/tail`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TailEvent {
+    /// A query was answered
+    Query(QueryEvent),
+    /// A packet was stored
+    Publish(PublishEvent),
+    /// Events were missed because the stream fell behind
+    Lagged {
+        /// The number of missed events
+        missed: u64,
+    },
+}
+
+/// Which events to stream
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::IntoParams))]
+pub struct TailFilter {
+    /// Only events of these z-base-32 encoded pubkeys, comma separated
+    pub pubkey: Option<String>,
+    /// Only queries of these record types, comma separated, e.g. `TXT,A`; no publishes are
+    /// streamed if set
+    pub qtype: Option<String>,
+    /// The fraction of the matching events that are streamed, from `0.0` to `1.0` (defaults to
+    /// `1.0`)
+    pub sample_rate: Option<f64>,
+}
+
+impl TailFilter {
+    fn matcher(&self) -> impl Fn(&TailEvent) -> bool {
+        let list = |value: &Option<String>| -> BTreeSet<String> {
+            value
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        };
+        let pubkeys = list(&self.pubkey);
+        let qtypes: BTreeSet<String> = list(&self.qtype)
+            .into_iter()
+            .map(|qtype| qtype.to_uppercase())
+            .collect();
+        let sample_rate = self.sample_rate.unwrap_or(1.0);
+        move |event| {
+            let matches = match event {
+                TailEvent::Query(query) => {
+                    (pubkeys.is_empty()
+                        || query.pubkey.as_ref().is_some_and(|p| pubkeys.contains(p)))
+                        && (qtypes.is_empty() || qtypes.contains(&query.qtype))
+                }
+                TailEvent::Publish(publish) => {
+                    (pubkeys.is_empty() || pubkeys.contains(&publish.pubkey)) && qtypes.is_empty()
+                }
+                // missed events are always reported
+                TailEvent::Lagged { .. } => return true,
+            };
+            matches && (sample_rate >= 1.0 || rand::random::<f64>() < sample_rate)
+        }
+    }
+}
+
+impl PublishEvent {
+    fn new(packet: &SignedPacket, source: PacketSource) -> Self {
+        let source = match source {
+            PacketSource::PkarrPublish => "publish",
+            PacketSource::Gossip => "gossip",
+            PacketSource::Sync => "sync",
+            PacketSource::Import => "import",
+        };
+        Self {
+            timestamp_ms: QueryLog::now_ms(),
+            pubkey: PublicKeyBytes::from_signed_packet(packet).to_z32(),
+            source: source.to_string(),
+            records: packet.packet().answers.len(),
+        }
+    }
+}
+
+/// Stream the events of the server that match `filter`, until the receiver is dropped or
+/// `shutdown` is cancelled.
+pub(crate) fn subscribe(
+    state: &AppState,
+    filter: &TailFilter,
+    shutdown: CancellationToken,
+) -> mpsc::Receiver<TailEvent> {
+    let mut queries = state.dns_handler.subscribe_queries();
+    let mut publishes = state.store.subscribe();
+    let matches = filter.matcher();
+    let (tx, rx) = mpsc::channel(STREAM_CAPACITY);
+    tokio::spawn(async move {
+        let lagged = |err| match err {
+            broadcast::error::RecvError::Lagged(missed) => Some(TailEvent::Lagged { missed }),
+            broadcast::error::RecvError::Closed => None,
+        };
+        let mut missed = 0;
+        loop {
+            let event = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                _ = tx.closed() => break,
+                res = queries.recv() => res.map(TailEvent::Query).map_err(lagged),
+                res = publishes.recv() => res
+                    .map(|(packet, source)| TailEvent::Publish(PublishEvent::new(&packet, source)))
+                    .map_err(lagged),
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(Some(event)) => event,
+                Err(None) => break,
+            };
+            if !matches(&event) {
+                continue;
+            }
+            // a client that doesn't read misses the events, like a lagging subscriber
+            if missed > 0 {
+                match tx.try_send(TailEvent::Lagged { missed }) {
+                    Ok(()) => missed = 0,
+                    Err(mpsc::error::TrySendError::Full(_)) => {}
+                    Err(mpsc::error::TrySendError::Closed(_)) => break,
+                }
+            }
+            match tx.try_send(event) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => missed += 1,
+                Err(mpsc::error::TrySendError::Closed(_)) => break,
+            }
+        }
+    });
+    rx
+}
+
+/// Stream the events that match `filter` from the admin API of the server at `url`, and call
+/// `on_event` for each, until the server ends the stream.
+pub async fn follow(
+    url: &Url,
+    token: &str,
+    filter: &TailFilter,
+    mut on_event: impl FnMut(TailEvent),
+) -> Result<()> {
+    let mut res = reqwest::Client::new()
+        .get(url.join("admin/tail")?)
+        .bearer_auth(token)
+        .query(filter)
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut buf = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        buf.extend_from_slice(&chunk);
+        while let Some(end) = buf.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            on_event(serde_json::from_slice(&line)?);
+        }
+    }
+    if !buf.is_empty() {
+        bail!("the stream ended within an event");
+    }
+    Ok(())
+}
+
+impl fmt::Display for TailEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Query(query) => write!(
+                f,
+                "{} query   {:<5} {:<15} {:<5} {} {} answers={} {:.2}ms",
+                format_time(query.timestamp_ms),
+                query.protocol,
+                query.client,
+                query.qtype,
+                query.name,
+                query.rcode,
+                query.answers,
+                query.duration_us as f64 / 1000.0
+            ),
+            Self::Publish(publish) => write!(
+                f,
+                "{} publish {:<21} {} records={}",
+                format_time(publish.timestamp_ms),
+                publish.source,
+                publish.pubkey,
+                publish.records
+            ),
+            Self::Lagged { missed } => write!(f, "... missed {missed} events"),
+        }
+    }
+}
+
+fn format_time(timestamp_ms: u64) -> String {
+    OffsetDateTime::from_unix_timestamp_nanos(timestamp_ms as i128 * 1_000_000)
+        .ok()
+        .and_then(|time| time.format(&Rfc3339).ok())
+        .unwrap_or_else(|| timestamp_ms.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pubkey: Option<&str>, qtype: &str) -> TailEvent {
+        TailEvent::Query(QueryEvent {
+            timestamp_ms: 0,
+            client: IpAddr::from([127, 0, 0, 1]),
+            protocol: "udp".to_string(),
+            name: "_iroh.example.".to_string(),
+            pubkey: pubkey.map(ToString::to_string),
+            qtype: qtype.to_string(),
+            rcode: "NoError".to_string(),
+            answers: 1,
+            duration_us: 100,
+        })
+    }
+
+    #[test]
+    fn filter_events() {
+        let publish = TailEvent::Publish(PublishEvent {
+            timestamp_ms: 0,
+            pubkey: "a".to_string(),
+            source: "publish".to_string(),
+            records: 1,
+        });
+        let matches = TailFilter::default().matcher();
+        assert!(matches(&query(None, "A")));
+        assert!(matches(&publish));
+
+        let matches = TailFilter {
+            pubkey: Some("a,b".to_string()),
+            ..Default::default()
+        }
+        .matcher();
+        assert!(matches(&query(Some("b"), "TXT")));
+        assert!(!matches(&query(Some("c"), "TXT")));
+        assert!(!matches(&query(None, "TXT")));
+        assert!(matches(&publish));
+
+        let matches = TailFilter {
+            qtype: Some("txt".to_string()),
+            ..Default::default()
+        }
+        .matcher();
+        assert!(matches(&query(None, "TXT")));
+        assert!(!matches(&query(None, "A")));
+        assert!(!matches(&publish));
+
+        let matches = TailFilter {
+            sample_rate: Some(0.0),
+            ..Default::default()
+        }
+        .matcher();
+        assert!(!matches(&query(None, "A")));
+        assert!(matches(&TailEvent::Lagged { missed: 1 }));
+    }
+}
@@ -0,0 +1,431 @@
+//! Tracing setup, with optional export of spans via OpenTelemetry
+//!
+//! The log output is configured with a [`LoggingConfig`]: the format, the levels per module,
+//! and static fields that are added to every line to tell instances apart.
+//!
+//! If an [`OtlpConfig`] is set, spans of the HTTP and DNS request handlers are exported via
+//! OTLP/HTTP. Incoming W3C `traceparent` headers are used as parents for the HTTP request spans,
+//! and HTTP responses carry a `traceparent` header of the request span. The traces can be sampled
+//! with a ratio per endpoint.
+//!
+//! If a [`SentryConfig`] is set, panics and error events are reported to Sentry.
+//!
+//! The filter of the log output can be changed at runtime with [`set_log_filter`], e.g. to
+//! enable debug logging during an incident without losing the state of the server.
+
+use std::{collections::BTreeMap, fmt, sync::OnceLock};
+
+use anyhow::{Context, Result};
+use http::HeaderMap;
+#[cfg(feature = "otlp")]
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+#[cfg(feature = "otlp")]
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::WithExportConfig;
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, Event, Span, Subscriber};
+#[cfg(feature = "otlp")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::filter_fn,
+    fmt::{
+        format::{self, FormatEvent, FormatFields, Writer},
+        FmtContext, MakeWriter,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+#[cfg(feature = "otlp")]
+use self::sampling::EndpointSampler;
+use self::{log_file::RotatingFile, sentry::SentryLayer};
+pub use self::{
+    log_file::{LogFileConfig, LogRotation},
+    sentry::SentryConfig,
+};
+
+mod log_file;
+#[cfg(feature = "otlp")]
+mod sampling;
+mod sentry;
+
+#[cfg(feature = "otlp")]
+const DEFAULT_SERVICE_NAME: &str = "iroh-dns-server";
+
+/// The filter of the log output, set in [`init`].
+static LOG_FILTER: OnceLock<LogFilter> = OnceLock::new();
+
+/// The reloadable filter of the log output.
+#[derive(Debug)]
+struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// The filter from the config or `RUST_LOG`, replaced when the config is reloaded
+    configured: parking_lot::RwLock<String>,
+}
+
+/// Boxed layer, as the layers of the subscriber.
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Config for exporting traces via OTLP
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OtlpConfig {
+    /// The OTLP/HTTP endpoint to export spans to, e.g. `http://localhost:4318/v1/traces`
+    pub endpoint: String,
+    /// The service name reported with the spans (defaults to `iroh-dns-server`)
+    pub service_name: Option<String>,
+    /// The fraction of traces that are exported, from `0.0` to `1.0` (defaults to `1.0`)
+    #[serde(default)]
+    pub sample_ratio: Option<f64>,
+    /// Sampling ratios that override `sample_ratio` per endpoint: HTTP path prefixes, e.g.
+    /// `"/pkarr" = 0.1`, and `dns` for DNS requests via UDP and TCP
+    #[serde(default)]
+    pub sample_overrides: BTreeMap<String, f64>,
+}
+
+/// Config for the log output
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LoggingConfig {
+    /// The format of the log lines
+    #[serde(default)]
+    pub format: LogFormat,
+    /// The level for all modules without an override, e.g. `info` (defaults to `error`)
+    #[serde(default)]
+    pub level: Option<String>,
+    /// Levels per module, e.g. `"iroh_dns_server::http" = "debug"`
+    #[serde(default)]
+    pub levels: BTreeMap<String, String>,
+    /// Fields added to every log line, e.g. `instance_id` and `region`
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Write the logs to a file instead of stdout
+    #[serde(default)]
+    pub file: Option<LogFileConfig>,
+}
+
+/// The format of the log lines
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line of human readable text per event
+    #[default]
+    Text,
+    /// Multiple lines of human readable text per event
+    Pretty,
+    /// One JSON object per event
+    Json,
+}
+
+impl LoggingConfig {
+    /// The filter for the log output.
+    ///
+    /// The `RUST_LOG` environment variable replaces the configured levels if it is set.
+    fn filter(&self) -> Result<EnvFilter> {
+        if std::env::var_os(EnvFilter::DEFAULT_ENV).is_some() {
+            return Ok(EnvFilter::from_default_env());
+        }
+        let mut directives = vec![self.level.clone().unwrap_or_else(|| "error".to_string())];
+        directives.extend(
+            self.levels
+                .iter()
+                .map(|(module, level)| format!("{module}={level}")),
+        );
+        EnvFilter::builder()
+            .parse(directives.join(","))
+            .context("invalid log level")
+    }
+
+    /// The layer that writes the log lines to stdout, or to the log file, and the handle to its
+    /// filter.
+    fn layer(&self) -> Result<(BoxedLayer, LogFilter)> {
+        match &self.file {
+            Some(file) => {
+                let file = RotatingFile::open(file.clone())?;
+                self.layer_with_writer(file, false)
+            }
+            None => self.layer_with_writer(std::io::stdout, true),
+        }
+    }
+
+    fn layer_with_writer<W>(&self, writer: W, ansi: bool) -> Result<(BoxedLayer, LogFilter)>
+    where
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let filter = self.filter()?;
+        let configured = filter.to_string();
+        let (filter, handle) = reload::Layer::new(filter);
+        let fmt = tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(ansi);
+        let layer = match self.format {
+            LogFormat::Text => fmt
+                .event_format(StaticFields::new(format::format(), &self.fields, false))
+                .with_filter(filter)
+                .boxed(),
+            LogFormat::Pretty => fmt
+                .pretty()
+                .map_event_format(|f| StaticFields::new(f, &self.fields, false))
+                .with_filter(filter)
+                .boxed(),
+            LogFormat::Json => fmt
+                .json()
+                .map_event_format(|f| StaticFields::new(f, &self.fields, true))
+                .with_filter(filter)
+                .boxed(),
+        };
+        let configured = parking_lot::RwLock::new(configured);
+        Ok((layer, LogFilter { handle, configured }))
+    }
+}
+
+/// An event format that adds static fields to the events of the `inner` format.
+struct StaticFields<F> {
+    inner: F,
+    /// The encoded fields, `key=value ` for text or `"key":"value",` for JSON
+    prefix: String,
+    json: bool,
+}
+
+impl<F> StaticFields<F> {
+    fn new(inner: F, fields: &BTreeMap<String, String>, json: bool) -> Self {
+        let prefix = fields
+            .iter()
+            .map(|(key, value)| match json {
+                true => format!(
+                    "{}:{},",
+                    Value::from(key.as_str()),
+                    Value::from(value.as_str())
+                ),
+                false => format!("{key}={value} "),
+            })
+            .collect();
+        Self {
+            inner,
+            prefix,
+            json,
+        }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for StaticFields<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if self.prefix.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+        if !self.json {
+            writer.write_str(&self.prefix)?;
+            return self.inner.format_event(ctx, writer, event);
+        }
+        // insert the fields at the start of the JSON object
+        let mut line = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut line), event)?;
+        match line.strip_prefix('{') {
+            Some(rest) => write!(writer, "{{{}{rest}", self.prefix),
+            None => writer.write_str(&line),
+        }
+    }
+}
+
+/// The provider of the exported spans
+#[cfg(feature = "otlp")]
+type TracerProvider = trace::TracerProvider;
+
+/// Spans are never exported without the `otlp` feature.
+#[cfg(not(feature = "otlp"))]
+type TracerProvider = std::convert::Infallible;
+
+/// Guard that flushes exported spans when dropped.
+#[derive(Debug)]
+pub struct TelemetryGuard {
+    #[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+    provider: Option<TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("failed to shutdown OpenTelemetry exporter: {err}");
+            }
+        }
+    }
+}
+
+/// Initialize the global tracing subscriber.
+///
+/// Log output is written to stdout as configured in `logging`, and filtered with the `RUST_LOG`
+/// environment variable if it is set. If `otlp` is set, spans are also exported via OTLP, and if
+/// `sentry` is set, panics and errors are reported to Sentry. Keep the returned guard alive until
+/// the server exits, to flush exported spans on shutdown.
+pub fn init(
+    otlp: Option<&OtlpConfig>,
+    logging: Option<&LoggingConfig>,
+    sentry: Option<&SentryConfig>,
+) -> Result<TelemetryGuard> {
+    let (fmt, filter) = logging.cloned().unwrap_or_default().layer()?;
+    let mut layers = vec![fmt];
+    if let Some(config) = sentry {
+        let sentry = SentryLayer::spawn(config.clone())?;
+        layers.push(sentry.with_filter(filter_fn(SentryLayer::enabled)).boxed());
+    }
+    let provider = match otlp {
+        Some(config) => {
+            let (layer, provider) = otlp_layer(config)?;
+            layers.push(layer);
+            Some(provider)
+        }
+        None => None,
+    };
+    tracing_subscriber::registry().with(layers).init();
+    let _ = LOG_FILTER.set(filter);
+    Ok(TelemetryGuard { provider })
+}
+
+/// Create the layer that exports the spans via OTLP, as configured in `config`.
+#[cfg(feature = "otlp")]
+fn otlp_layer(config: &OtlpConfig) -> Result<(BoxedLayer, TracerProvider)> {
+    use tracing::Level;
+    use tracing_subscriber::filter::Targets;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let service_name = config
+        .service_name
+        .clone()
+        .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string());
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&config.endpoint),
+        )
+        .with_trace_config(
+            trace::Config::default()
+                .with_sampler(EndpointSampler::new(config))
+                .with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+        )
+        .install_batch(runtime::Tokio)
+        .context("failed to create OTLP exporter")?;
+    global::set_tracer_provider(provider.clone());
+
+    // The request spans are on the debug level, export them independent of `RUST_LOG`.
+    let otel = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(DEFAULT_SERVICE_NAME))
+        .with_filter(Targets::new().with_target("iroh_dns_server", Level::DEBUG));
+    Ok((otel.boxed(), provider))
+}
+
+/// Exporting spans is not supported without the `otlp` feature.
+#[cfg(not(feature = "otlp"))]
+fn otlp_layer(_config: &OtlpConfig) -> Result<(BoxedLayer, TracerProvider)> {
+    anyhow::bail!("the OTLP export of spans requires the `otlp` feature")
+}
+
+/// The current and the configured filter of the log output.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct LogFilterStatus {
+    /// The current filter directives
+    pub filter: String,
+    /// The filter directives from the config or `RUST_LOG`
+    pub configured: String,
+}
+
+/// Get the filter of the log output, if the tracing subscriber was set up with [`init`].
+pub fn log_filter() -> Option<LogFilterStatus> {
+    let filter = LOG_FILTER.get()?;
+    let current = filter.handle.with_current(|f| f.to_string()).ok()?;
+    Some(LogFilterStatus {
+        filter: current,
+        configured: filter.configured.read().clone(),
+    })
+}
+
+/// Replace the filter of the log output with `directives`, e.g. `info,iroh_dns_server=debug`.
+///
+/// Fails if the directives are invalid, or the tracing subscriber was not set up with [`init`].
+pub fn set_log_filter(directives: &str) -> Result<LogFilterStatus> {
+    let filter = LOG_FILTER.get().context("log output is not set up")?;
+    let new = EnvFilter::builder()
+        .parse(directives)
+        .context("invalid log filter")?;
+    filter.handle.reload(new)?;
+    info!(filter = directives, "changed log filter");
+    log_filter().context("log output is not set up")
+}
+
+/// Restore the configured filter of the log output.
+pub fn reset_log_filter() -> Result<LogFilterStatus> {
+    let filter = LOG_FILTER.get().context("log output is not set up")?;
+    let configured = filter.configured.read().clone();
+    set_log_filter(&configured)
+}
+
+/// Apply the levels of a reloaded `logging` config.
+///
+/// The configured filter is replaced, and applied unless the filter was changed at runtime. The
+/// format and the output of the logs can't be changed without a restart.
+pub(crate) fn reload_log_levels(logging: &LoggingConfig) -> Result<()> {
+    let filter = LOG_FILTER.get().context("log output is not set up")?;
+    let new = logging.filter()?.to_string();
+    let status = log_filter().context("log output is not set up")?;
+    let old = std::mem::replace(&mut *filter.configured.write(), new.clone());
+    if status.filter == old && new != old {
+        set_log_filter(&new)?;
+    }
+    Ok(())
+}
+
+/// Switch between the configured filter and debug logging for this crate.
+pub(crate) fn toggle_debug_logging() -> Result<LogFilterStatus> {
+    let status = log_filter().context("log output is not set up")?;
+    match status.filter == status.configured {
+        true => set_log_filter(&format!("{},iroh_dns_server=debug", status.configured)),
+        false => reset_log_filter(),
+    }
+}
+
+/// Set the parent of `span` from the `traceparent` header in `headers`, if present.
+#[cfg(feature = "otlp")]
+pub(crate) fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// Traces are not propagated without the `otlp` feature.
+#[cfg(not(feature = "otlp"))]
+pub(crate) fn set_parent_from_headers(_span: &Span, _headers: &HeaderMap) {}
+
+/// Insert the `traceparent` header for `span` into `headers`.
+#[cfg(feature = "otlp")]
+pub(crate) fn inject_headers(span: &Span, headers: &mut HeaderMap) {
+    let context = span.context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Traces are not propagated without the `otlp` feature.
+#[cfg(not(feature = "otlp"))]
+pub(crate) fn inject_headers(_span: &Span, _headers: &mut HeaderMap) {}
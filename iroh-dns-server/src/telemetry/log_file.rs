@@ -0,0 +1,266 @@
+//! Log file with size and time based rotation
+//!
+//! When the file is rotated, it is renamed to `<file name>.<timestamp>` and a new file is
+//! started. Only the newest [`LogFileConfig::max_files`] rotated files are kept.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use time::{macros::format_description, OffsetDateTime};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Default number of rotated files that are kept.
+const DEFAULT_MAX_FILES: usize = 7;
+
+/// Config for writing the logs to a file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LogFileConfig {
+    /// The path of the log file
+    pub path: PathBuf,
+    /// Rotate the file when it is larger than this many bytes
+    #[serde(default)]
+    pub max_size_bytes: Option<u64>,
+    /// Rotate the file at the start of every hour or day
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// The number of rotated files to keep (defaults to 7)
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+/// Time based rotation of the log file
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// Only rotate by size
+    #[default]
+    Never,
+    /// Rotate at the start of every hour (UTC)
+    Hourly,
+    /// Rotate at the start of every day (UTC)
+    Daily,
+}
+
+impl LogFileConfig {
+    /// Whether the file is ever rotated, which creates files in its directory.
+    pub(crate) fn rotates(&self) -> bool {
+        self.max_size_bytes.is_some() || self.rotation != LogRotation::Never
+    }
+}
+
+impl LogRotation {
+    fn period(self) -> Option<Duration> {
+        match self {
+            Self::Never => None,
+            Self::Hourly => Some(Duration::from_secs(60 * 60)),
+            Self::Daily => Some(Duration::from_secs(60 * 60 * 24)),
+        }
+    }
+}
+
+/// A log file that rotates itself, for use as the writer of a tracing layer.
+#[derive(Debug)]
+pub(super) struct RotatingFile {
+    config: LogFileConfig,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    size: u64,
+    /// The rotation period the file was started in
+    period: u64,
+}
+
+impl RotatingFile {
+    /// Open the log file, appending to it if it exists.
+    pub(super) fn open(config: LogFileConfig) -> Result<Self> {
+        if let Some(dir) = config
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create log directory {}", dir.display()))?;
+        }
+        let state = Self::open_state(&config)
+            .with_context(|| format!("failed to open log file {}", config.path.display()))?;
+        Ok(Self {
+            config,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn open_state(config: &LogFileConfig) -> io::Result<State> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(State {
+            file,
+            size,
+            period: current_period(config.rotation),
+        })
+    }
+
+    fn write_line(&self, buf: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let too_large = self
+            .config
+            .max_size_bytes
+            .is_some_and(|max| state.size > 0 && state.size + buf.len() as u64 > max);
+        if too_large || state.period != current_period(self.config.rotation) {
+            if let Err(err) = self.rotate(&mut state) {
+                // the logs can't be used to report errors of the logs
+                eprintln!(
+                    "failed to rotate log file {}: {err}",
+                    self.config.path.display()
+                );
+            }
+        }
+        state.file.write_all(buf)?;
+        state.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Rename the current file, start a new one, and remove the oldest rotated files.
+    fn rotate(&self, state: &mut State) -> io::Result<()> {
+        state.file.flush()?;
+        let now = OffsetDateTime::now_utc();
+        let timestamp = now
+            .format(format_description!(
+                "[year][month][day]T[hour][minute][second]"
+            ))
+            .map_err(io::Error::other)?;
+        let mut rotated = self.config.path.as_os_str().to_owned();
+        rotated.push(format!(".{timestamp}"));
+        let mut rotated = PathBuf::from(rotated);
+        // several rotations within a second
+        let mut n = 1;
+        while rotated.exists() {
+            let mut path = self.config.path.as_os_str().to_owned();
+            path.push(format!(".{timestamp}-{n}"));
+            rotated = PathBuf::from(path);
+            n += 1;
+        }
+        fs::rename(&self.config.path, &rotated)?;
+        *state = Self::open_state(&self.config)?;
+        self.remove_old_files()
+    }
+
+    fn remove_old_files(&self) -> io::Result<()> {
+        let path = &self.config.path;
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir.to_path_buf()
+        };
+        let prefix = format!("{}.", name.to_string_lossy());
+        let mut rotated: Vec<_> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        // the timestamps sort chronologically
+        rotated.sort();
+        let max_files = self.config.max_files.unwrap_or(DEFAULT_MAX_FILES);
+        let excess = rotated.len().saturating_sub(max_files);
+        for path in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+fn current_period(rotation: LogRotation) -> u64 {
+    let Some(period) = rotation.period() else {
+        return 0;
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() / period.as_secs()
+}
+
+/// Writes one formatted event to the [`RotatingFile`].
+pub(super) struct LineWriter<'a> {
+    file: &'a RotatingFile,
+    buf: Vec<u8>,
+}
+
+impl Write for LineWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LineWriter<'_> {
+    fn drop(&mut self) {
+        // events are written whole, so that they are never split across files
+        if !self.buf.is_empty() {
+            let _ = self.file.write_line(&self.buf);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = LineWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LineWriter {
+            file: self,
+            buf: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_by_size() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("log-file-test-{}", std::process::id()));
+        let config = LogFileConfig {
+            path: dir.join("server.log"),
+            max_size_bytes: Some(10),
+            rotation: LogRotation::Never,
+            max_files: Some(2),
+        };
+        let file = RotatingFile::open(config)?;
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.make_writer().write_all(line.as_bytes())?;
+        }
+        assert_eq!(fs::read_to_string(dir.join("server.log"))?, "fourth\n");
+        let mut rotated: Vec<_> = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().to_string()))
+            .collect::<io::Result<_>>()?;
+        rotated.retain(|name| name != "server.log");
+        rotated.sort();
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join(&rotated[1]))?,
+            "third\n",
+            "{rotated:?}"
+        );
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! Head-based sampling of the exported traces
+//!
+//! The decision is made when the root span of a trace starts: HTTP requests are sampled with the
+//! ratio of the longest matching path prefix in [`OtlpConfig::sample_overrides`], DNS requests
+//! over UDP and TCP with the ratio of the `dns` override, and everything else with
+//! [`OtlpConfig::sample_ratio`]. Child spans, and requests with a `traceparent` header, follow the
+//! decision of their parent.
+
+use opentelemetry::{
+    trace::{Link, SamplingResult, SpanKind, TraceId},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::trace::{Sampler, ShouldSample};
+
+use super::OtlpConfig;
+
+/// The override key of DNS requests that are not sent via DoH.
+const DNS_OVERRIDE: &str = "dns";
+
+/// Sampler with a ratio per endpoint.
+#[derive(Debug, Clone)]
+pub(super) struct EndpointSampler {
+    default: Sampler,
+    dns: Option<Sampler>,
+    /// HTTP path prefixes, longest first
+    paths: Vec<(String, Sampler)>,
+}
+
+impl EndpointSampler {
+    pub(super) fn new(config: &OtlpConfig) -> Self {
+        let sampler =
+            |ratio: f64| Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)));
+        let mut paths: Vec<_> = config
+            .sample_overrides
+            .iter()
+            .filter(|(endpoint, _)| endpoint.starts_with('/'))
+            .map(|(path, ratio)| (path.clone(), sampler(*ratio)))
+            .collect();
+        paths.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+        Self {
+            default: sampler(config.sample_ratio.unwrap_or(1.0)),
+            dns: config
+                .sample_overrides
+                .get(DNS_OVERRIDE)
+                .copied()
+                .map(sampler),
+            paths,
+        }
+    }
+
+    /// The sampler for a span, by the name and the fields of the request spans.
+    fn sampler(&self, name: &str, attributes: &[KeyValue]) -> &Sampler {
+        let attribute = |key: &str| {
+            attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.as_str())
+        };
+        match name {
+            "http_request" => {
+                let Some(uri) = attribute("uri") else {
+                    return &self.default;
+                };
+                let path = uri
+                    .parse::<http::Uri>()
+                    .map(|uri| uri.path().to_string())
+                    .unwrap_or_default();
+                self.paths
+                    .iter()
+                    .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+                    .map(|(_, sampler)| sampler)
+                    .unwrap_or(&self.default)
+            }
+            "dns_request" => self.dns.as_ref().unwrap_or(&self.default),
+            _ => &self.default,
+        }
+    }
+}
+
+impl ShouldSample for EndpointSampler {
+    fn should_sample(
+        &self,
+        parent_context: Option<&Context>,
+        trace_id: TraceId,
+        name: &str,
+        span_kind: &SpanKind,
+        attributes: &[KeyValue],
+        links: &[Link],
+    ) -> SamplingResult {
+        self.sampler(name, attributes).should_sample(
+            parent_context,
+            trace_id,
+            name,
+            span_kind,
+            attributes,
+            links,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::SamplingDecision;
+
+    use super::*;
+
+    #[test]
+    fn endpoint_overrides() {
+        let config = OtlpConfig {
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            service_name: None,
+            sample_ratio: Some(0.0),
+            sample_overrides: [("/pkarr".to_string(), 1.0), ("dns".to_string(), 0.0)]
+                .into_iter()
+                .collect(),
+        };
+        let sampler = EndpointSampler::new(&config);
+        let decision = |name: &str, uri: &str| {
+            let attributes = [KeyValue::new("uri", uri.to_string())];
+            sampler
+                .should_sample(
+                    None,
+                    TraceId::from_bytes([1; 16]),
+                    name,
+                    &SpanKind::Internal,
+                    &attributes,
+                    &[],
+                )
+                .decision
+        };
+        assert_eq!(
+            decision("http_request", "/pkarr/abc"),
+            SamplingDecision::RecordAndSample
+        );
+        assert_eq!(
+            decision("http_request", "/dns-query?dns=abc"),
+            SamplingDecision::Drop
+        );
+        assert_eq!(decision("dns_request", ""), SamplingDecision::Drop);
+    }
+}
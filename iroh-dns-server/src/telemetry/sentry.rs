@@ -0,0 +1,330 @@
+//! Reporting of panics and error events to Sentry
+//!
+//! Error events and panics are converted to Sentry events, with the fields of the enclosing
+//! spans of this crate (e.g. the method and URI of an HTTP request) as extra data, and sent as
+//! envelopes to the project of the configured DSN. Any Sentry compatible service (e.g.
+//! GlitchTip) can receive them.
+//!
+//! Events are sent in the background and dropped if the queue is full, so that a burst of
+//! errors doesn't slow down the server. Panics of the main thread abort the server before their
+//! event is sent, only panics of tasks are reported.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{
+    field::{Field, Visit},
+    span, warn, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use url::Url;
+
+/// The number of events that are queued for sending, before events are dropped.
+const QUEUE_SIZE: usize = 64;
+/// Timeout for requests to Sentry.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Replacement for scrubbed IP addresses.
+const SCRUBBED_IP: &str = "[ip]";
+
+/// Config for reporting errors to Sentry
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SentryConfig {
+    /// The DSN of the Sentry project, e.g. `https://<key>@o0.ingest.sentry.io/<project id>`
+    pub dsn: String,
+    /// The environment reported with the events, e.g. `production`
+    #[serde(default)]
+    pub environment: Option<String>,
+    /// Replace IP addresses in the messages and fields of the events (defaults to true)
+    #[serde(default = "default_scrub_ips")]
+    pub scrub_ips: bool,
+}
+
+fn default_scrub_ips() -> bool {
+    true
+}
+
+/// The tracing layer that reports error events, and the sender of the panic hook.
+pub(super) struct SentryLayer {
+    tx: mpsc::Sender<Value>,
+    config: SentryConfig,
+}
+
+impl SentryLayer {
+    /// Start sending events to the DSN of `config`, and install a panic hook that reports
+    /// panics.
+    ///
+    /// Must be called within a tokio runtime.
+    pub(super) fn spawn(config: SentryConfig) -> Result<Self> {
+        let dsn = Dsn::parse(&config.dsn)?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(send(rx, dsn, client));
+
+        let panic_tx = tx.clone();
+        let panic_config = config.clone();
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous(info);
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Box<dyn Any>".to_string());
+            let mut extra = BTreeMap::new();
+            if let Some(location) = info.location() {
+                extra.insert("location".to_string(), location.to_string());
+            }
+            if let Some(thread) = std::thread::current().name() {
+                extra.insert("thread".to_string(), thread.to_string());
+            }
+            let mut event = panic_config.event(Level::ERROR, "panic", &message, extra);
+            event["level"] = "fatal".into();
+            event["exception"] = json!({ "values": [{
+                "type": "panic",
+                "value": panic_config.scrub(&message),
+                "mechanism": { "type": "panic", "handled": false },
+            }]});
+            let _ = panic_tx.try_send(event);
+        }));
+        Ok(Self { tx, config })
+    }
+
+    /// Whether the layer is interested in a span or event.
+    ///
+    /// The spans of this crate are recorded for their fields, and error events are reported.
+    pub(super) fn enabled(metadata: &Metadata<'_>) -> bool {
+        match metadata.is_span() {
+            true => metadata.target().starts_with("iroh_dns_server"),
+            false => *metadata.level() == Level::ERROR,
+        }
+    }
+}
+
+impl SentryConfig {
+    /// Build a Sentry event.
+    fn event(
+        &self,
+        level: Level,
+        logger: &str,
+        message: &str,
+        extra: BTreeMap<String, String>,
+    ) -> Value {
+        let extra: BTreeMap<String, String> = extra
+            .into_iter()
+            .map(|(key, value)| (key, self.scrub(&value)))
+            .collect();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        json!({
+            "event_id": format!("{:032x}", rand::random::<u128>()),
+            "timestamp": timestamp,
+            "platform": "other",
+            "level": level.as_str().to_ascii_lowercase(),
+            "logger": logger,
+            "release": concat!("iroh-dns-server@", env!("CARGO_PKG_VERSION")),
+            "environment": self.environment,
+            "message": { "formatted": self.scrub(message) },
+            "extra": extra,
+        })
+    }
+
+    fn scrub(&self, value: &str) -> String {
+        match self.scrub_ips {
+            true => scrub_ips(value),
+            false => value.to_string(),
+        }
+    }
+}
+
+/// The fields of a span, stored in the span extensions.
+struct SpanFields(BTreeMap<String, String>);
+
+/// Collects the fields of spans and events as strings.
+#[derive(Default)]
+struct FieldVisitor(BTreeMap<String, String>);
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl<S> Layer<S> for SentryLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields(visitor.0));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            fields.0.extend(visitor.0);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let mut extra = visitor.0;
+        let message = extra.remove("message").unwrap_or_default();
+        // the fields of the enclosing spans, prefixed with the span name
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|s| s.from_root())
+        {
+            if let Some(fields) = span.extensions().get::<SpanFields>() {
+                for (key, value) in &fields.0 {
+                    extra.insert(format!("{}.{key}", span.name()), value.clone());
+                }
+            }
+        }
+        let metadata = event.metadata();
+        let event = self
+            .config
+            .event(*metadata.level(), metadata.target(), &message, extra);
+        // events are dropped if the queue is full
+        let _ = self.tx.try_send(event);
+    }
+}
+
+/// The envelope endpoint and public key of a DSN.
+#[derive(Debug, PartialEq, Eq)]
+struct Dsn {
+    envelope_url: String,
+    key: String,
+}
+
+impl Dsn {
+    /// Parse a DSN of the form `<scheme>://<key>@<host>[:<port>][/<path>]/<project id>`.
+    fn parse(dsn: &str) -> Result<Self> {
+        let url = Url::parse(dsn).context("invalid Sentry DSN")?;
+        let key = url.username();
+        let Some(host) = url.host_str().filter(|_| !key.is_empty()) else {
+            bail!("invalid Sentry DSN: the key and host are required");
+        };
+        let path = url.path().trim_end_matches('/');
+        let (prefix, project) = path.rsplit_once('/').unwrap_or_default();
+        if project.is_empty() {
+            bail!("invalid Sentry DSN: the project id is missing");
+        }
+        let port = url.port().map(|p| format!(":{p}")).unwrap_or_default();
+        Ok(Self {
+            envelope_url: format!(
+                "{}://{host}{port}{prefix}/api/{project}/envelope/",
+                url.scheme()
+            ),
+            key: key.to_string(),
+        })
+    }
+}
+
+/// Send the events from `rx` as envelopes.
+async fn send(mut rx: mpsc::Receiver<Value>, dsn: Dsn, client: reqwest::Client) {
+    let auth = format!(
+        "Sentry sentry_version=7, sentry_client=iroh-dns-server/{}, sentry_key={}",
+        env!("CARGO_PKG_VERSION"),
+        dsn.key
+    );
+    while let Some(event) = rx.recv().await {
+        let header = json!({ "event_id": event["event_id"] });
+        let body = format!("{header}\n{}\n{event}\n", json!({ "type": "event" }));
+        let res = client
+            .post(&dsn.envelope_url)
+            .header("X-Sentry-Auth", &auth)
+            .header(http::header::CONTENT_TYPE, "application/x-sentry-envelope")
+            .body(body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status());
+        // not logged as error, which would be reported again
+        if let Err(err) = res {
+            warn!("failed to send event to Sentry: {err:#}");
+        }
+    }
+}
+
+/// Replace the IP addresses, with or without port, in `value`.
+fn scrub_ips(value: &str) -> String {
+    let is_addr_char = |c: char| c.is_ascii_hexdigit() || matches!(c, '.' | ':' | '[' | ']');
+    let mut scrubbed = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find(is_addr_char) {
+        scrubbed.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(|c| !is_addr_char(c)).unwrap_or(rest.len());
+        let token = &rest[..end];
+        // the address may be followed by a full stop or a colon
+        let candidate = token.trim_end_matches(['.', ':']);
+        let stripped = candidate.trim_start_matches('[').trim_end_matches(']');
+        if candidate.parse::<SocketAddr>().is_ok() || stripped.parse::<IpAddr>().is_ok() {
+            scrubbed.push_str(SCRUBBED_IP);
+            scrubbed.push_str(&token[candidate.len()..]);
+        } else {
+            scrubbed.push_str(token);
+        }
+        rest = &rest[end..];
+    }
+    scrubbed.push_str(rest);
+    scrubbed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrub() {
+        assert_eq!(
+            scrub_ips("request from 192.0.2.1:4711 failed at 12:30:45"),
+            "request from [ip] failed at 12:30:45"
+        );
+        assert_eq!(
+            scrub_ips("src=[2001:db8::1]:443, peer 2001:db8::2. cafe"),
+            "src=[ip], peer [ip]. cafe"
+        );
+    }
+
+    #[test]
+    fn parse_dsn() -> Result<()> {
+        assert_eq!(
+            Dsn::parse("https://abc@sentry.example.org:8443/errors/42")?,
+            Dsn {
+                envelope_url: "https://sentry.example.org:8443/errors/api/42/envelope/".into(),
+                key: "abc".into(),
+            }
+        );
+        assert!(Dsn::parse("https://sentry.example.org/42").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+//! Tenants of a server that is shared by many customers
+//!
+//! A hosting provider can run one server for many customers by adding a [`TenantConfig`] for
+//! each of them. The DNS queries for the pkarr names in the origins of a tenant belong to the
+//! tenant, and so do the publishes with one of its API keys, or else the publishes and
+//! `GET /pkarr` lookups to a host in its origins. Each tenant has
+//!
+//! - a quota of `max_packets` pubkeys it may publish, counted in a table of the packet database,
+//! - its own budget of `publishes_per_minute`, shared by all of its clients, in addition to the
+//!   rate limits of the clients,
+//! - a `tenant` label in the `tenant_publishes`, `tenant_queries` and `tenant_packets` metrics,
+//! - and, with `isolated_store`, its own packet database in `tenants/{name}.db` in the data
+//!   directory. The packets in an isolated store are only resolved for the origins of the
+//!   tenant, and not from the mainline DHT. They are not synced, gossiped, expired or kept in
+//!   the history.
+
+use std::{
+    fmt,
+    num::NonZeroU32,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use anyhow::{ensure, Context, Result};
+use governor::{
+    clock::DefaultClock,
+    middleware::StateInformationMiddleware,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use hickory_proto::rr::{LowerName, Name};
+use parking_lot::Mutex;
+use redb::{Database, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{metrics::TenantMetrics, store::ZoneStore, util::PublicKeyBytes};
+
+/// The time of the first publish, in seconds since the unix epoch, by tenant and pubkey
+const TENANT_KEYS_TABLE: TableDefinition<(&str, &[u8; 32]), u64> =
+    TableDefinition::new("tenant-keys-1");
+
+/// Config of a tenant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TenantConfig {
+    /// The name of the tenant, in the metrics and the name of its isolated store.
+    ///
+    /// Only ASCII letters, digits, `-` and `_` are allowed.
+    pub name: String,
+    /// The origins of the tenant, which must be origins of the DNS server.
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// The ids of the API keys of the tenant.
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// The number of pubkeys the tenant may publish (unlimited if unset).
+    pub max_packets: Option<u64>,
+    /// The publishes per minute of all clients of the tenant together (unlimited if unset).
+    pub publishes_per_minute: Option<NonZeroU32>,
+    /// Store the packets of the tenant in a separate database.
+    #[serde(default)]
+    pub isolated_store: bool,
+}
+
+/// The reason a publish of a tenant is rejected
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum TenantRejected {
+    /// The publishes per minute of the tenant are exceeded
+    RateLimited,
+    /// The tenant published the maximum number of pubkeys
+    QuotaExceeded(u64),
+}
+
+impl fmt::Display for TenantRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited => write!(f, "the publish rate of the tenant is exceeded"),
+            Self::QuotaExceeded(max) => {
+                write!(f, "the tenant published the maximum of {max} pubkeys")
+            }
+        }
+    }
+}
+
+type TenantRateLimiter =
+    RateLimiter<NotKeyed, InMemoryState, DefaultClock, StateInformationMiddleware>;
+
+/// A tenant, see the [module docs](self).
+#[derive(derive_more::Debug)]
+pub(crate) struct Tenant {
+    name: String,
+    origins: Vec<LowerName>,
+    api_keys: Vec<String>,
+    max_packets: Option<u64>,
+    #[debug("{}", limiter.is_some())]
+    limiter: Option<(Quota, TenantRateLimiter)>,
+    /// When the used budget of publishes is full again
+    #[debug(skip)]
+    used_budget: Mutex<Option<SystemTime>>,
+    store: Option<ZoneStore>,
+    #[debug(skip)]
+    db: Arc<Database>,
+    /// The number of pubkeys of the tenant in the tenant keys table
+    packets: AtomicU64,
+}
+
+/// The tenants of the server, empty unless they are configured.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tenants(Arc<Vec<Arc<Tenant>>>);
+
+impl Tenants {
+    /// Create the tenants of `configs`, with their quotas in `db`.
+    ///
+    /// The isolated stores are created in `dir`, or in memory if it is `None`.
+    pub(crate) fn open(
+        db: Arc<Database>,
+        configs: &[TenantConfig],
+        dir: Option<&Path>,
+    ) -> Result<Self> {
+        let write_tx = db.begin_write()?;
+        {
+            let _table = write_tx.open_table(TENANT_KEYS_TABLE)?;
+        }
+        write_tx.commit()?;
+        let mut tenants: Vec<Arc<Tenant>> = Vec::new();
+        for config in configs {
+            let name = &config.name;
+            ensure!(
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                "invalid tenant name {name:?}, only ASCII letters, digits, `-` and `_` are allowed"
+            );
+            ensure!(
+                tenants.iter().all(|tenant| &tenant.name != name),
+                "duplicate tenant {name}"
+            );
+            let origins = config
+                .origins
+                .iter()
+                .map(|origin| Name::from_utf8(origin).map(LowerName::from))
+                .collect::<Result<_, _>>()
+                .with_context(|| format!("invalid origin of tenant {name}"))?;
+            let store = match (config.isolated_store, dir) {
+                (false, _) => None,
+                (true, Some(dir)) => Some(ZoneStore::persistent(dir.join(format!("{name}.db")))?),
+                (true, None) => Some(ZoneStore::in_memory()?),
+            };
+            let packets = {
+                let tx = db.begin_read()?;
+                let table = tx.open_table(TENANT_KEYS_TABLE)?;
+                let count = table
+                    .range((name.as_str(), &[0; 32])..=(name.as_str(), &[u8::MAX; 32]))?
+                    .count();
+                count as u64
+            };
+            TenantMetrics::set_packets(name, packets);
+            info!(
+                tenant = %name,
+                packets,
+                isolated = config.isolated_store,
+                "added tenant"
+            );
+            tenants.push(Arc::new(Tenant {
+                name: name.clone(),
+                origins,
+                api_keys: config.api_keys.clone(),
+                max_packets: config.max_packets,
+                limiter: config.publishes_per_minute.map(|rate| {
+                    let quota = Quota::per_minute(rate);
+                    let limiter =
+                        RateLimiter::direct(quota).with_middleware::<StateInformationMiddleware>();
+                    (quota, limiter)
+                }),
+                used_budget: Mutex::new(None),
+                store,
+                db: db.clone(),
+                packets: AtomicU64::new(packets),
+            }));
+        }
+        Ok(Self(Arc::new(tenants)))
+    }
+
+    /// Whether there are no tenants.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterate over the tenants.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        self.0.iter()
+    }
+
+    /// The tenant with `name` in one of its origins.
+    pub(crate) fn of_name(&self, name: &LowerName) -> Option<&Arc<Tenant>> {
+        self.0
+            .iter()
+            .find(|tenant| tenant.origins.iter().any(|origin| origin.zone_of(name)))
+    }
+
+    /// The tenant of a publish with the API key `api_key`, or else to `host`.
+    pub(crate) fn of_publish(
+        &self,
+        api_key: Option<&str>,
+        host: Option<&LowerName>,
+    ) -> Option<&Arc<Tenant>> {
+        let by_key = api_key.and_then(|id| {
+            self.0
+                .iter()
+                .find(|tenant| tenant.api_keys.iter().any(|key| key == id))
+        });
+        by_key.or_else(|| host.and_then(|host| self.of_name(host)))
+    }
+
+    /// Forget `pubkey` in the quotas of the tenants, after its packet was removed.
+    pub(crate) fn remove_key(&self, pubkey: &PublicKeyBytes) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let db = &self.0[0].db;
+        let tx = db.begin_write()?;
+        let mut removed = Vec::new();
+        {
+            let mut table = tx.open_table(TENANT_KEYS_TABLE)?;
+            for tenant in self.0.iter() {
+                // the packets of isolated stores are not removed from the shared store
+                if tenant.store.is_none()
+                    && table
+                        .remove((tenant.name.as_str(), pubkey.as_bytes()))?
+                        .is_some()
+                {
+                    removed.push(tenant);
+                }
+            }
+        }
+        tx.commit()?;
+        for tenant in removed {
+            let packets = tenant.packets.fetch_sub(1, Ordering::Relaxed) - 1;
+            TenantMetrics::set_packets(&tenant.name, packets);
+        }
+        Ok(())
+    }
+}
+
+impl Tenant {
+    /// The name of the tenant.
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The store of the packets of the tenant: its isolated store, or else `shared`.
+    pub(crate) fn store<'a>(&'a self, shared: &'a ZoneStore) -> &'a ZoneStore {
+        self.store.as_ref().unwrap_or(shared)
+    }
+
+    /// Why the tenant may not publish the packet of `pubkey` now, or `None` if it may.
+    ///
+    /// This uses up a publish of the budget of the tenant.
+    pub(crate) fn rejects(&self, pubkey: &PublicKeyBytes) -> Result<Option<TenantRejected>> {
+        if let Some((_, limiter)) = &self.limiter {
+            let Ok(snapshot) = limiter.check() else {
+                return Ok(Some(TenantRejected::RateLimited));
+            };
+            let quota = snapshot.quota();
+            let used = quota
+                .burst_size()
+                .get()
+                .saturating_sub(snapshot.remaining_burst_capacity());
+            *self.used_budget.lock() = Some(SystemTime::now() + quota.replenish_interval() * used);
+        }
+        self.quota_rejects(pubkey)
+    }
+
+    /// When the used budget of publishes of the tenant is full again, if it is not full.
+    pub(crate) fn used_budget(&self) -> Option<SystemTime> {
+        let full_at = (*self.used_budget.lock())?;
+        (full_at > SystemTime::now()).then_some(full_at)
+    }
+
+    /// Use up the budget of publishes of the tenant until it is full again at `full_at`, e.g.
+    /// from before a restart.
+    pub(crate) fn restore_budget(&self, full_at: SystemTime) {
+        let (Some((quota, limiter)), Ok(left)) =
+            (&self.limiter, full_at.duration_since(SystemTime::now()))
+        else {
+            return;
+        };
+        let cells = left
+            .as_nanos()
+            .div_ceil(quota.replenish_interval().as_nanos());
+        let cells = (cells.min(quota.burst_size().get() as u128) as u32).max(1);
+        limiter
+            .check_n(NonZeroU32::new(cells).expect("at least one cell"))
+            .ok();
+        *self.used_budget.lock() = Some(full_at);
+    }
+
+    /// Whether the quota of the tenant rejects a publish of `pubkey`, without taking from its
+    /// rate limit.
+    pub(crate) fn quota_rejects(&self, pubkey: &PublicKeyBytes) -> Result<Option<TenantRejected>> {
+        if let Some(max) = self.max_packets {
+            if self.packets.load(Ordering::Relaxed) >= max && !self.has_key(pubkey)? {
+                return Ok(Some(TenantRejected::QuotaExceeded(max)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Count `pubkey` in the quota of the tenant, if it is not counted yet.
+    pub(crate) fn add_key(&self, pubkey: &PublicKeyBytes) -> Result<()> {
+        if self.has_key(pubkey)? {
+            return Ok(());
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let tx = self.db.begin_write()?;
+        let added = {
+            let mut table = tx.open_table(TENANT_KEYS_TABLE)?;
+            let previous = table.insert((self.name.as_str(), pubkey.as_bytes()), now)?;
+            previous.is_none()
+        };
+        tx.commit()?;
+        if added {
+            let packets = self.packets.fetch_add(1, Ordering::Relaxed) + 1;
+            TenantMetrics::set_packets(&self.name, packets);
+        }
+        Ok(())
+    }
+
+    fn has_key(&self, pubkey: &PublicKeyBytes) -> Result<bool> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(TENANT_KEYS_TABLE)?;
+        Ok(table
+            .get((self.name.as_str(), pubkey.as_bytes()))?
+            .is_some())
+    }
+}
@@ -0,0 +1,224 @@
+//! An in-process DNS server and pkarr relay for integration tests
+//!
+//! [`TestServer`] runs the real server on random ports on localhost, with the packets in memory,
+//! so that crates which use iroh discovery can test it against the same code as in production.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use std::time::Duration;
+//!
+//! use iroh_dns_server::test_utils::TestServer;
+//! use iroh_net::{dns::ResolverExt, key::SecretKey};
+//!
+//! let server = TestServer::run().await?;
+//! let secret_key = SecretKey::generate();
+//! server
+//!     .publish_node(&secret_key, Some("https://relay.example.".parse()?), [])
+//!     .await?;
+//! let node_id = secret_key.public();
+//! let info = server
+//!     .dns_resolver()
+//!     .lookup_by_id(&node_id, &server.node_origin)
+//!     .await?;
+//! assert_eq!(info.node_id, node_id);
+//! server.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use hickory_server::resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig},
+    AsyncResolver,
+};
+use iroh_net::{
+    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery},
+    dns::DnsResolver,
+    key::{NodeId, SecretKey},
+    relay::RelayUrl,
+};
+use pkarr::SignedPacket;
+use url::Url;
+
+pub use crate::dev::{node_record, DEFAULT_ORIGIN};
+use crate::{
+    config::Config,
+    server::{Server, ServerBuilder},
+    store::PacketSource,
+    util::PublicKeyBytes,
+};
+
+/// A DNS server and pkarr relay on localhost, which keeps the packets in memory
+///
+/// The servers run until [`Self::shutdown`] is called.
+#[derive(derive_more::Debug)]
+pub struct TestServer {
+    /// The origin of the node records
+    pub node_origin: String,
+    /// The socket address of the DNS server
+    pub nameserver: SocketAddr,
+    /// The URL of the pkarr relay, to publish to with [`PkarrPublisher`]
+    pub pkarr_url: Url,
+    #[debug("Server")]
+    server: Server,
+}
+
+impl TestServer {
+    /// Run the servers with the node records under [`DEFAULT_ORIGIN`].
+    pub async fn run() -> Result<Self> {
+        Self::run_with_origin(DEFAULT_ORIGIN).await
+    }
+
+    /// Run the servers with the node records under `node_origin`.
+    pub async fn run_with_origin(node_origin: &str) -> Result<Self> {
+        Self::run_with(node_origin, |builder| builder).await
+    }
+
+    /// Run the servers with the node records under `node_origin`, after `configure` changed the
+    /// builder, e.g. to add hooks.
+    ///
+    /// Changes to the config with [`ServerBuilder::config`] replace the test config, which
+    /// [`Self::config`] returns.
+    pub async fn run_with(
+        node_origin: &str,
+        configure: impl FnOnce(ServerBuilder) -> ServerBuilder,
+    ) -> Result<Self> {
+        let builder = Server::builder().config(Self::config(node_origin));
+        let server = configure(builder).spawn().await?;
+        let http_addr = server
+            .http_addr()
+            .context("the HTTP server is not running")?;
+        Ok(Self {
+            node_origin: node_origin.to_string(),
+            nameserver: server.dns_addr(),
+            pkarr_url: format!("http://{http_addr}/pkarr").parse()?,
+            server,
+        })
+    }
+
+    /// The config of the servers: DNS and HTTP on random ports on localhost, without rate limits
+    /// and metrics, and with the packets in memory.
+    pub fn config(node_origin: &str) -> Config {
+        crate::dev::config(node_origin, 0, 0)
+    }
+
+    /// The server, e.g. for its store.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Create a [`ConcurrentDiscovery`] with [`DnsDiscovery`] and [`PkarrPublisher`] configured
+    /// to use the servers.
+    pub fn discovery(&self, secret_key: SecretKey) -> Box<ConcurrentDiscovery> {
+        Box::new(ConcurrentDiscovery::from_services(vec![
+            Box::new(DnsDiscovery::new(self.node_origin.clone())),
+            Box::new(PkarrPublisher::new(secret_key, self.pkarr_url.clone())),
+        ]))
+    }
+
+    /// Create a [`DnsResolver`] that only asks the DNS server.
+    pub fn dns_resolver(&self) -> DnsResolver {
+        let mut config = ResolverConfig::new();
+        config.add_name_server(NameServerConfig::new(self.nameserver, Protocol::Udp));
+        AsyncResolver::tokio(config, Default::default())
+    }
+
+    /// Store the node record of `secret_key`, as if the node had published it.
+    pub async fn publish_node(
+        &self,
+        secret_key: &SecretKey,
+        relay_url: Option<RelayUrl>,
+        direct_addresses: impl IntoIterator<Item = SocketAddr>,
+    ) -> Result<SignedPacket> {
+        let packet = node_record(secret_key, relay_url, direct_addresses)?;
+        self.publish(packet.clone()).await?;
+        Ok(packet)
+    }
+
+    /// Store `packet`, as if it had been published.
+    pub async fn publish(&self, packet: SignedPacket) -> Result<()> {
+        self.server
+            .store()
+            .insert(packet, PacketSource::PkarrPublish)
+            .await?;
+        Ok(())
+    }
+
+    /// Wait until a packet of `node_id` is stored, e.g. published by the node.
+    ///
+    /// Fails if `timeout` elapses first.
+    pub async fn on_node(&self, node_id: &NodeId, timeout: Duration) -> Result<SignedPacket> {
+        let store = self.server.store();
+        let key = PublicKeyBytes::from(*node_id.as_bytes());
+        let mut published = store.subscribe();
+        if let Some(packet) = store.get_signed_packet(&key).await? {
+            return Ok(packet);
+        }
+        let wait = async {
+            loop {
+                let packet = match published.recv().await {
+                    Ok((packet, _source)) => packet,
+                    // a missed packet may be the one of the node
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                        match store.get_signed_packet(&key).await? {
+                            Some(packet) => packet,
+                            None => continue,
+                        }
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                if PublicKeyBytes::from_signed_packet(&packet) == key {
+                    return Ok(packet);
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .with_context(|| format!("no packet of node {node_id} within {timeout:?}"))?
+    }
+
+    /// Shut down the servers.
+    pub async fn shutdown(self) -> Result<()> {
+        self.server.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use iroh_net::dns::ResolverExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_published_node() -> Result<()> {
+        iroh_test::logging::setup_multithreaded();
+        let server = TestServer::run().await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr: SocketAddr = (Ipv4Addr::LOCALHOST, 4433).into();
+        let published = tokio::spawn({
+            let pkarr_url = server.pkarr_url.clone();
+            let packet = node_record(&secret_key, None, [addr])?;
+            async move {
+                let client = iroh_net::discovery::pkarr::PkarrRelayClient::new(pkarr_url);
+                client.publish(&packet).await
+            }
+        });
+        server.on_node(&node_id, Duration::from_secs(10)).await?;
+        published.await??;
+
+        let info = server
+            .dns_resolver()
+            .lookup_by_id(&node_id, &server.node_origin)
+            .await?;
+        assert_eq!(info.node_id, node_id);
+        assert_eq!(info.info.direct_addresses, [addr].into());
+        server.shutdown().await?;
+        Ok(())
+    }
+}
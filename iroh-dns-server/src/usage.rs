@@ -0,0 +1,297 @@
+//! Usage reports for billing the tenants and API keys
+//!
+//! With a [`UsageConfig`], the server counts the accepted publishes and the answered resolutions
+//! of pkarr names, by [tenant](crate::tenants) and by the API key of the publish. At the end of
+//! each interval, the counts of the interval are exported as a [`UsageReport`]: appended to a file
+//! as a JSON object per line or as CSV rows, and posted as JSON to a push endpoint. The counts of
+//! the current interval can be looked at with `GET /admin/usage`.
+//!
+//! The counts are only kept in memory. A report that can't be pushed is still written to the
+//! file, and the report of the unfinished interval is exported when the server shuts down.
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+use url::Url;
+#[cfg(feature = "openapi")]
+use utoipa::ToSchema;
+
+use crate::secrets::{RefreshingSecret, SecretValue};
+
+/// Default interval in seconds of the reports.
+const DEFAULT_INTERVAL_SECS: u64 = 3600;
+/// Number of attempts to push a report.
+const PUSH_ATTEMPTS: u32 = 3;
+/// Timeout for the push requests.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The header of the CSV format.
+pub(crate) const CSV_HEADER: &str = "start,end,tenant,api_key,publishes,resolutions\n";
+
+/// Config for the usage reports
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UsageConfig {
+    /// Interval in seconds of the reports (defaults to 3600).
+    pub interval_secs: Option<u64>,
+    /// Append the reports to this file.
+    pub path: Option<PathBuf>,
+    /// The format of the file.
+    #[serde(default)]
+    pub format: UsageFormat,
+    /// Post the reports as JSON to this URL.
+    pub push_url: Option<Url>,
+    /// Bearer token of the push requests.
+    pub push_token: Option<SecretValue>,
+}
+
+/// The format of the usage reports in a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum UsageFormat {
+    /// A JSON object per report and line
+    #[default]
+    Json,
+    /// A row per tenant and API key, with the columns
+    /// `start,end,tenant,api_key,publishes,resolutions`
+    Csv,
+}
+
+/// The usage of a tenant with an API key in an interval
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct UsageRow {
+    /// The tenant, if the requests belong to one
+    pub tenant: Option<String>,
+    /// The id of the API key of the publishes, if they had one
+    pub api_key: Option<String>,
+    /// The accepted publishes
+    pub publishes: u64,
+    /// The answered resolutions, by DNS or `GET /pkarr`
+    pub resolutions: u64,
+}
+
+/// The usage in an interval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(ToSchema))]
+pub struct UsageReport {
+    /// The start of the interval, in RFC 3339 format
+    pub start: String,
+    /// The end of the interval, in RFC 3339 format
+    pub end: String,
+    /// The usage by tenant and API key, ordered by them
+    pub rows: Vec<UsageRow>,
+}
+
+impl UsageReport {
+    /// Format the report as CSV rows, without the header.
+    pub fn to_csv(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| {
+                format!(
+                    "{},{},{},{},{},{}\n",
+                    self.start,
+                    self.end,
+                    row.tenant.as_deref().unwrap_or_default(),
+                    row.api_key.as_deref().unwrap_or_default(),
+                    row.publishes,
+                    row.resolutions
+                )
+            })
+            .collect()
+    }
+}
+
+/// Publish and resolution counts by tenant and API key
+type Counts = BTreeMap<(Option<String>, Option<String>), UsageRow>;
+
+/// The usage counts, see the [module docs](self).
+#[derive(derive_more::Debug, Clone)]
+pub(crate) struct Usage {
+    /// The start of the current interval and its counts
+    current: Arc<Mutex<(OffsetDateTime, Counts)>>,
+    interval: Duration,
+    path: Option<PathBuf>,
+    format: UsageFormat,
+    #[debug(skip)]
+    push: Option<(reqwest::Client, Url, Option<RefreshingSecret>)>,
+}
+
+impl Usage {
+    /// Create the usage counts of `config`.
+    pub(crate) async fn open(config: &UsageConfig) -> Result<Self> {
+        let push = match &config.push_url {
+            Some(url) => {
+                let token = match &config.push_token {
+                    Some(token) => Some(RefreshingSecret::new(token).await?),
+                    None => None,
+                };
+                let client = reqwest::Client::builder()
+                    .timeout(REQUEST_TIMEOUT)
+                    .build()?;
+                Some((client, url.clone(), token))
+            }
+            None => None,
+        };
+        Ok(Self {
+            current: Arc::new(Mutex::new((OffsetDateTime::now_utc(), Counts::new()))),
+            interval: Duration::from_secs(config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS)),
+            path: config.path.clone(),
+            format: config.format,
+            push,
+        })
+    }
+
+    /// Count an accepted publish of `tenant` with the API key `api_key`.
+    pub(crate) fn record_publish(&self, tenant: Option<&str>, api_key: Option<&str>) {
+        self.row(tenant, api_key, |row| row.publishes += 1);
+    }
+
+    /// Count an answered resolution of a pkarr name of `tenant`.
+    pub(crate) fn record_resolution(&self, tenant: Option<&str>) {
+        self.row(tenant, None, |row| row.resolutions += 1);
+    }
+
+    fn row(&self, tenant: Option<&str>, api_key: Option<&str>, f: impl FnOnce(&mut UsageRow)) {
+        let mut current = self.current.lock();
+        let key = (tenant.map(str::to_string), api_key.map(str::to_string));
+        let row = current.1.entry(key).or_insert_with(|| UsageRow {
+            tenant: tenant.map(str::to_string),
+            api_key: api_key.map(str::to_string),
+            ..Default::default()
+        });
+        f(row);
+    }
+
+    /// The usage of the current interval, up to now.
+    pub(crate) fn report(&self) -> UsageReport {
+        let current = self.current.lock();
+        report(current.0, OffsetDateTime::now_utc(), &current.1)
+    }
+
+    /// End the current interval, and return its usage.
+    fn take(&self) -> UsageReport {
+        let now = OffsetDateTime::now_utc();
+        let mut current = self.current.lock();
+        let (start, counts) = std::mem::replace(&mut *current, (now, Counts::new()));
+        report(start, now, &counts)
+    }
+
+    /// Export the reports in the interval, until the task is aborted.
+    pub(crate) async fn run(self) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            self.export(self.take()).await;
+        }
+    }
+
+    /// Export the report of the unfinished interval.
+    pub(crate) async fn flush(&self) {
+        self.export(self.take()).await;
+    }
+
+    /// Write `report` to the file and push it, logging the failures.
+    async fn export(&self, report: UsageReport) {
+        if let Some(path) = &self.path {
+            if let Err(err) = self.write(path, &report).await {
+                warn!(
+                    "failed to write the usage report to {}: {err:#}",
+                    path.display()
+                );
+            }
+        }
+        if let Some((client, url, token)) = &self.push {
+            let mut backoff = Duration::from_millis(500);
+            for attempt in 1..=PUSH_ATTEMPTS {
+                let mut req = client.post(url.clone()).json(&report);
+                if let Some(token) = token {
+                    req = req.bearer_auth(token.get());
+                }
+                match req.send().await.and_then(|res| res.error_for_status()) {
+                    Ok(_) => break,
+                    Err(err) if attempt < PUSH_ATTEMPTS => {
+                        debug!("failed to push the usage report, retrying: {err:#}");
+                        tokio::time::sleep(backoff).await;
+                        backoff *= 2;
+                    }
+                    Err(err) => {
+                        warn!(start = %report.start, "failed to push the usage report: {err:#}")
+                    }
+                }
+            }
+        }
+        debug!(start = %report.start, rows = report.rows.len(), "exported the usage report");
+    }
+
+    async fn write(&self, path: &PathBuf, report: &UsageReport) -> Result<()> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .context("failed to open the file")?;
+        let content = match self.format {
+            UsageFormat::Json => format!("{}\n", serde_json::to_string(report)?),
+            UsageFormat::Csv if file.metadata().await?.len() == 0 => {
+                format!("{CSV_HEADER}{}", report.to_csv())
+            }
+            UsageFormat::Csv => report.to_csv(),
+        };
+        file.write_all(content.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+fn report(start: OffsetDateTime, end: OffsetDateTime, counts: &Counts) -> UsageReport {
+    let format = |time: OffsetDateTime| time.format(&Rfc3339).unwrap_or_default();
+    UsageReport {
+        start: format(start),
+        end: format(end),
+        rows: counts.values().cloned().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn csv_report() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("usage-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("usage.csv");
+        let usage = Usage::open(&UsageConfig {
+            path: Some(path.clone()),
+            format: UsageFormat::Csv,
+            ..Default::default()
+        })
+        .await?;
+        usage.record_publish(Some("acme"), Some("k1"));
+        usage.record_publish(Some("acme"), Some("k1"));
+        usage.record_resolution(Some("acme"));
+        usage.record_resolution(None);
+        let report = usage.report();
+        assert_eq!(report.rows.len(), 3);
+        usage.flush().await;
+        usage.record_publish(None, None);
+        usage.flush().await;
+
+        let csv = std::fs::read_to_string(&path)?;
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], CSV_HEADER.trim_end());
+        let columns = |line: &str| line.split(',').skip(2).collect::<Vec<_>>().join(",");
+        assert_eq!(columns(lines[1]), ",,0,1");
+        assert_eq!(columns(lines[2]), "acme,,0,1");
+        assert_eq!(columns(lines[3]), "acme,k1,2,0");
+        assert_eq!(columns(lines[4]), ",,1,0");
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
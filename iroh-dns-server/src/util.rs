@@ -1,11 +1,13 @@
 use core::fmt;
 use std::{
     collections::{btree_map, BTreeMap},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, OnceLock},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use hickory_proto::{
     op::Message,
     rr::{
@@ -15,6 +17,15 @@ use hickory_proto::{
     serialize::binary::BinDecodable,
 };
 use pkarr::SignedPacket;
+use serde::{Deserialize, Deserializer};
+use tracing::warn;
+
+#[cfg(unix)]
+use crate::handoff;
+use crate::{
+    config::{BindAddr, IpStack},
+    systemd,
+};
 
 #[derive(
     derive_more::From, derive_more::Into, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy,
@@ -107,7 +118,7 @@ pub fn signed_packet_to_hickory_records_without_origin(
         if name.num_labels() < 1 {
             continue;
         }
-        let zone = name.iter().last().unwrap().into_label()?;
+        let zone = name.iter().next_back().unwrap().into_label()?;
         if zone != common_zone {
             continue;
         }
@@ -151,3 +162,156 @@ pub fn record_set_append_origin(
     }
     Ok(output)
 }
+
+/// Get the socket addresses to bind for the listeners of `server` on `port`.
+///
+/// These are the addresses of the sockets named `server` passed by systemd if there are any, or
+/// else the configured addresses, or the wildcard addresses of `ip_stack` if no addresses are
+/// configured. Fails if a configured interface doesn't exist or has no addresses.
+pub(crate) fn socket_addrs(
+    server: &str,
+    addrs: &[BindAddr],
+    port: Option<u16>,
+    ip_stack: IpStack,
+) -> Result<Vec<SocketAddr>> {
+    if let Some(addrs) = systemd::listen_addrs(server) {
+        return Ok(addrs);
+    }
+    let Some(port) = port else {
+        return Ok(Vec::new());
+    };
+    if !addrs.is_empty() {
+        let mut socket_addrs = Vec::new();
+        for addr in addrs {
+            match addr {
+                BindAddr::Ip(ip) => socket_addrs.push(SocketAddr::new(*ip, port)),
+                BindAddr::Interface(name) => {
+                    let ips = interface_addrs(name, ip_stack)
+                        .with_context(|| format!("invalid {server}.bind_addr"))?;
+                    socket_addrs.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port)));
+                }
+            }
+        }
+        return Ok(socket_addrs);
+    }
+    let mut addrs = Vec::new();
+    if ip_stack != IpStack::V6 {
+        addrs.push(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port));
+    }
+    if ip_stack == IpStack::V6 || (ip_stack == IpStack::Dual && ipv6_supported()) {
+        addrs.push(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port));
+    }
+    Ok(addrs)
+}
+
+/// Get the addresses of the network interface `name` of the IP versions of `ip_stack`.
+///
+/// IPv6 link-local addresses are skipped, since they can't be bound without a scope.
+fn interface_addrs(name: &str, ip_stack: IpStack) -> Result<Vec<IpAddr>> {
+    let interface = netdev::get_interfaces()
+        .into_iter()
+        .find(|interface| {
+            interface.name == name || interface.friendly_name.as_deref() == Some(name)
+        })
+        .with_context(|| format!("no network interface {name}"))?;
+    let v4 = interface.ipv4.iter().map(|net| IpAddr::V4(net.addr));
+    let v6 = interface
+        .ipv6
+        .iter()
+        // fe80::/10, `Ipv6Addr::is_unicast_link_local` needs Rust 1.84
+        .filter(|net| net.addr.segments()[0] & 0xffc0 != 0xfe80)
+        .map(|net| IpAddr::V6(net.addr));
+    let addrs: Vec<_> = match ip_stack {
+        IpStack::Dual => v4.chain(v6).collect(),
+        IpStack::V4 => v4.collect(),
+        IpStack::V6 => v6.collect(),
+    };
+    if addrs.is_empty() {
+        bail!("network interface {name} has no {ip_stack} addresses");
+    }
+    Ok(addrs)
+}
+
+/// Whether IPv6 sockets can be bound on this host.
+fn ipv6_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        let supported = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).is_ok();
+        if !supported {
+            warn!("IPv6 is not supported on this host, only binding IPv4 addresses");
+        }
+        supported
+    })
+}
+
+/// Create a socket for `addr`, which only accepts IPv6 traffic if `addr` is an IPv6 address.
+///
+/// This way the IPv4 and the IPv6 wildcard addresses can be bound with the same port, and
+/// clients are never seen with IPv4-mapped IPv6 addresses.
+fn socket(addr: SocketAddr, ty: socket2::Type) -> io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Bind a non-blocking TCP listener, see [`socket`], or use the listener on `addr` passed by
+/// systemd.
+///
+/// The listener is passed on to the new process on upgrades, see [`handoff`].
+pub(crate) fn bind_tcp(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let socket = match systemd::take(addr, socket2::Type::STREAM) {
+        Some(socket) => socket,
+        None => {
+            let socket = socket(addr, socket2::Type::STREAM)?;
+            // like the listeners of std and tokio, allow binding while old connections are in
+            // TIME_WAIT
+            #[cfg(unix)]
+            socket.set_reuse_address(true)?;
+            socket.bind(&addr.into())?;
+            socket.listen(1024)?;
+            socket
+        }
+    };
+    #[cfg(unix)]
+    handoff::register(&socket);
+    Ok(socket.into())
+}
+
+/// Bind a non-blocking UDP socket, see [`socket`], or use the socket on `addr` passed by
+/// systemd.
+///
+/// The socket is passed on to the new process on upgrades, see [`handoff`].
+pub(crate) fn bind_udp(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    let socket = match systemd::take(addr, socket2::Type::DGRAM) {
+        Some(socket) => socket,
+        None => {
+            let socket = socket(addr, socket2::Type::DGRAM)?;
+            socket.bind(&addr.into())?;
+            socket
+        }
+    };
+    #[cfg(unix)]
+    handoff::register(&socket);
+    Ok(socket.into())
+}
+
+/// Deserialize either a single value or a list of values.
+pub(crate) fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
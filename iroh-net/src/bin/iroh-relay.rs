@@ -8,6 +8,7 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context as _, Result};
 use clap::Parser;
+use hickory_proto::rr::Name;
 use iroh_net::defaults::{
     DEFAULT_HTTPS_PORT, DEFAULT_HTTP_PORT, DEFAULT_METRICS_PORT, DEFAULT_STUN_PORT,
 };
@@ -144,6 +145,11 @@ struct Config {
     ///
     /// Disabled if not present.
     limits: Option<Limits>,
+    /// The forwarding of the DNS queries of clients to a nameserver.
+    ///
+    /// Lets clients on networks that block DNS discover nodes through their relay connection.
+    /// Disabled if not present.
+    dns_forward: Option<DnsForwardConfig>,
     /// Whether to run the metrics server.
     ///
     /// Defaults to `true`, when the metrics feature is enabled.
@@ -183,6 +189,7 @@ impl Default for Config {
             enable_stun: true,
             stun_bind_addr: None,
             limits: None,
+            dns_forward: None,
             enable_metrics: true,
             metrics_bind_addr: None,
         }
@@ -206,6 +213,14 @@ mod cfg_defaults {
         true
     }
 
+    pub(crate) mod dns_forward_config {
+        use iroh_net::discovery::dns::N0_DNS_NODE_ORIGIN_PROD;
+
+        pub(crate) fn origins() -> Vec<String> {
+            vec![N0_DNS_NODE_ORIGIN_PROD.to_string()]
+        }
+    }
+
     pub(crate) mod tls_config {
         pub(crate) fn prod_tls() -> bool {
             true
@@ -213,6 +228,18 @@ mod cfg_defaults {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DnsForwardConfig {
+    /// The nameserver to forward the DNS queries to.
+    nameserver: SocketAddr,
+    /// The origins of the node discovery whose node records can be looked up.
+    ///
+    /// Only TXT queries for `_iroh.<node id>.<origin>` are forwarded, other queries are
+    /// answered with `REFUSED`. Defaults to the origin of the n0 DNS server, `dns.iroh.link`.
+    #[serde(default = "cfg_defaults::dns_forward_config::origins")]
+    origins: Vec<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TlsConfig {
     /// The socket address to bind the Relay HTTPS server on.
@@ -427,11 +454,28 @@ async fn build_relay_config(cfg: Config) -> Result<iroh_relay::ServerConfig<std:
             .map(|l| l.accept_conn_burst)
             .unwrap_or_default(),
     };
+    let dns_forward = match cfg.dns_forward {
+        Some(ref dns_forward) => {
+            let origins = dns_forward
+                .origins
+                .iter()
+                .map(|origin| {
+                    Name::from_utf8(origin).with_context(|| format!("invalid origin {origin}"))
+                })
+                .collect::<Result<_>>()?;
+            Some(iroh_relay::DnsForward {
+                nameserver: dns_forward.nameserver,
+                origins,
+            })
+        }
+        None => None,
+    };
     let relay_config = iroh_relay::RelayConfig {
         secret_key: cfg.secret_key.clone(),
         http_bind_addr: cfg.http_bind_addr(),
         tls,
         limits,
+        dns_forward,
     };
     let stun_config = iroh_relay::StunConfig {
         bind_addr: cfg.stun_bind_addr(),
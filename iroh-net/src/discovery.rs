@@ -30,7 +30,9 @@
 //!   using HTTP.
 //!
 //! To use multiple discovery systems simultaneously use [`ConcurrentDiscovery`] which will
-//! perform lookups to all discovery systems at the same time.
+//! perform lookups to all discovery systems at the same time.  To ask some discovery systems
+//! before others, with timeouts, or to stop at the first result, use
+//! [`PrioritizedDiscovery`].
 //!
 //! [`RelayUrl`]: crate::relay::RelayUrl
 //! [`Builder::discovery`]: crate::endpoint::Builder::discovery
@@ -39,13 +41,24 @@
 //! [`PkarrResolver`]: pkarr::PkarrResolver
 //! [pkarr relay servers]: https://pkarr.org/#servers
 
-use std::time::Duration;
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use crate::{AddrInfo, Endpoint, NodeId};
+use crate::{dns::node_info::UserData, AddrInfo, Endpoint, NodeId};
 use anyhow::{anyhow, ensure, Result};
 use futures_lite::stream::{Boxed as BoxStream, StreamExt};
+use futures_util::stream::SelectAll;
 use iroh_base::node_addr::NodeAddr;
-use tokio::{sync::oneshot, task::JoinHandle};
+use tokio::{
+    sync::{broadcast::error::RecvError, oneshot},
+    task::JoinHandle,
+    time::{Instant, Sleep},
+};
+use tokio_util::task::AbortOnDropHandle;
 use tracing::{debug, error_span, warn, Instrument};
 
 pub mod dns;
@@ -116,6 +129,47 @@ pub trait Discovery: std::fmt::Debug + Send + Sync {
     fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
         None
     }
+
+    /// Returns the status of the publishing of this service.
+    ///
+    /// Services that publish the [`AddrInfo`] of the node somewhere return one
+    /// [`PublishStatus`] per target, combinators return the statuses of all their services.
+    /// This lets applications tell their users why the node is not discoverable.
+    fn publish_status(&self) -> Vec<PublishStatus> {
+        Vec::new()
+    }
+}
+
+/// The status of the publishing of a [`Discovery`] service to one target
+///
+/// See [`Endpoint::publish_status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishStatus {
+    /// A static string to identify the discovery service, as in [`DiscoveryItem::provenance`]
+    pub provenance: &'static str,
+    /// Where the addressing information is published to, e.g. the URL of a pkarr relay
+    pub target: String,
+    /// When the addressing information was last published successfully
+    pub last_published: Option<SystemTime>,
+    /// The error of the last publish, if it failed
+    ///
+    /// Cleared by the next successful publish.
+    pub last_error: Option<String>,
+    /// The number of publishes in a row that failed
+    pub failed_attempts: u32,
+}
+
+impl PublishStatus {
+    /// Creates the status of a service that did not publish yet.
+    pub fn new(provenance: &'static str, target: impl Into<String>) -> Self {
+        Self {
+            provenance,
+            target: target.into(),
+            last_published: None,
+            last_error: None,
+            failed_attempts: 0,
+        }
+    }
 }
 
 /// The results returned from [`Discovery::resolve`].
@@ -134,6 +188,63 @@ pub struct DiscoveryItem {
     pub last_updated: Option<u64>,
     /// The address info for the node being resolved.
     pub addr_info: AddrInfo,
+    /// The user-defined data the node published, if any.
+    pub user_data: Option<UserData>,
+}
+
+/// A change of the addressing information of a node, found by discovery
+///
+/// See [`Endpoint::discovery_events`].
+#[derive(Debug, Clone)]
+pub enum DiscoveryEvent {
+    /// The node was found for the first time since the stream was created
+    Found(DiscoveryItem),
+    /// The addressing information of a node that was found before changed
+    Changed(DiscoveryItem),
+}
+
+impl DiscoveryEvent {
+    /// Returns the [`DiscoveryItem`] of the event.
+    pub fn item(&self) -> &DiscoveryItem {
+        match self {
+            Self::Found(item) | Self::Changed(item) => item,
+        }
+    }
+}
+
+/// Creates the stream of [`DiscoveryEvent`]s from the nodes found by discovery, optionally only
+/// for `node_id`.
+///
+/// The `guard` is dropped with the stream, e.g. to stop a task that resolves the node.
+pub(crate) fn discovery_events(
+    found: tokio::sync::broadcast::Receiver<DiscoveryItem>,
+    node_id: Option<NodeId>,
+    guard: Option<AbortOnDropHandle<()>>,
+) -> BoxStream<DiscoveryEvent> {
+    let state = (found, HashMap::<NodeId, AddrInfo>::new(), guard);
+    let stream =
+        futures_lite::stream::unfold(state, move |(mut found, mut known, guard)| async move {
+            loop {
+                let item = match found.recv().await {
+                    Ok(item) => item,
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!(missed, "discovery events lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                if node_id.is_some_and(|node_id| node_id != item.node_id) {
+                    continue;
+                }
+                let event = match known.insert(item.node_id, item.addr_info.clone()) {
+                    None => DiscoveryEvent::Found(item),
+                    Some(previous) if previous != item.addr_info => DiscoveryEvent::Changed(item),
+                    Some(_) => continue,
+                };
+                return Some((event, (found, known, guard)));
+            }
+        });
+    Box::pin(stream)
 }
 
 /// A discovery service that combines multiple discovery sources.
@@ -203,6 +314,225 @@ impl Discovery for ConcurrentDiscovery {
         let streams = futures_buffered::MergeBounded::from_iter(streams);
         Some(Box::pin(streams))
     }
+
+    fn publish_status(&self) -> Vec<PublishStatus> {
+        self.services
+            .iter()
+            .flat_map(|service| service.publish_status())
+            .collect()
+    }
+}
+
+/// Delay after which a [`PrioritizedDiscovery`] asks the services of the next priority by default.
+pub const DEFAULT_PRIORITY_DELAY: Duration = Duration::from_millis(500);
+
+/// How a [`PrioritizedDiscovery`] combines the results of its services
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResolveMode {
+    /// Return the results of all services, as they arrive
+    #[default]
+    Merge,
+    /// Stop at the first result with addresses, and stop the lookups of all services
+    FirstSuccess,
+}
+
+/// A discovery service of a [`PrioritizedDiscovery`], with its priority and timeout
+#[derive(Debug)]
+pub struct PrioritizedService {
+    service: Box<dyn Discovery>,
+    priority: u32,
+    timeout: Option<Duration>,
+}
+
+impl PrioritizedService {
+    /// Creates the service with priority `0` and without timeout.
+    pub fn new(service: impl Discovery + 'static) -> Self {
+        Self {
+            service: Box::new(service),
+            priority: 0,
+            timeout: None,
+        }
+    }
+
+    /// Sets the priority, services with lower priorities are asked first.
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the time after which the lookups of the service are stopped.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// A discovery service that combines multiple discovery sources by priority.
+///
+/// The services with the lowest priority are asked first, concurrently.  The services of the
+/// next priority are asked after a delay, see [`PrioritizedDiscovery::with_priority_delay`],
+/// or right away when the lookups of the previous priorities ended.  Errors of single services
+/// are only returned if no service found the node.
+///
+/// All services publish, and the passively discovered nodes of all services are returned.
+#[derive(Debug, Default)]
+pub struct PrioritizedDiscovery {
+    services: Vec<Arc<PrioritizedService>>,
+    mode: ResolveMode,
+    priority_delay: Option<Duration>,
+}
+
+impl PrioritizedDiscovery {
+    /// Creates an empty [`PrioritizedDiscovery`] which combines the results with `mode`.
+    pub fn new(mode: ResolveMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a [`Discovery`] service.
+    pub fn add(&mut self, service: PrioritizedService) {
+        self.services.push(Arc::new(service));
+    }
+
+    /// Sets the delay after which the services of the next priority are asked.
+    ///
+    /// Defaults to [`DEFAULT_PRIORITY_DELAY`].
+    pub fn with_priority_delay(mut self, delay: Duration) -> Self {
+        self.priority_delay = Some(delay);
+        self
+    }
+}
+
+impl Discovery for PrioritizedDiscovery {
+    fn publish(&self, info: &AddrInfo) {
+        for service in &self.services {
+            service.service.publish(info);
+        }
+    }
+
+    fn resolve(
+        &self,
+        endpoint: Endpoint,
+        node_id: NodeId,
+    ) -> Option<BoxStream<Result<DiscoveryItem>>> {
+        let mut services = self.services.clone();
+        services.sort_by_key(|service| service.priority);
+        let mut levels = VecDeque::<Vec<Arc<PrioritizedService>>>::new();
+        for service in services {
+            match levels.back_mut() {
+                Some(level) if level[0].priority == service.priority => level.push(service),
+                _ => levels.push_back(vec![service]),
+            }
+        }
+        let lookup = PrioritizedLookup {
+            endpoint,
+            node_id,
+            levels,
+            running: SelectAll::new(),
+            next_level: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            priority_delay: self.priority_delay.unwrap_or(DEFAULT_PRIORITY_DELAY),
+            mode: self.mode,
+            found: false,
+            error: None,
+        };
+        let stream = futures_lite::stream::unfold(lookup, |mut lookup| async move {
+            let item = lookup.next().await?;
+            Some((item, lookup))
+        });
+        Some(Box::pin(stream))
+    }
+
+    fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
+        let streams = self
+            .services
+            .iter()
+            .filter_map(|service| service.service.subscribe());
+        let streams = futures_buffered::MergeBounded::from_iter(streams);
+        Some(Box::pin(streams))
+    }
+
+    fn publish_status(&self) -> Vec<PublishStatus> {
+        self.services
+            .iter()
+            .flat_map(|service| service.service.publish_status())
+            .collect()
+    }
+}
+
+/// The state of a lookup of a [`PrioritizedDiscovery`]
+struct PrioritizedLookup {
+    endpoint: Endpoint,
+    node_id: NodeId,
+    /// The services that were not asked yet, by priority
+    levels: VecDeque<Vec<Arc<PrioritizedService>>>,
+    running: SelectAll<BoxStream<Result<DiscoveryItem>>>,
+    /// When the services of the next priority are asked
+    next_level: Pin<Box<Sleep>>,
+    priority_delay: Duration,
+    mode: ResolveMode,
+    found: bool,
+    /// The last error of a service
+    error: Option<anyhow::Error>,
+}
+
+impl PrioritizedLookup {
+    async fn next(&mut self) -> Option<Result<DiscoveryItem>> {
+        loop {
+            if self.found && self.mode == ResolveMode::FirstSuccess {
+                return None;
+            }
+            if self.running.is_empty() && !self.start_next_level() {
+                return match (self.found, self.error.take()) {
+                    (false, Some(err)) => Some(Err(err)),
+                    _ => None,
+                };
+            }
+            let has_next_level = !self.levels.is_empty();
+            tokio::select! {
+                item = self.running.next() => match item {
+                    Some(Ok(item)) => {
+                        self.found |= !item.addr_info.is_empty();
+                        return Some(Ok(item));
+                    }
+                    Some(Err(err)) => {
+                        debug!(?err, "discovery service failed");
+                        self.error = Some(err);
+                    }
+                    // the services of the next priority are asked in the next iteration
+                    None => {}
+                },
+                _ = &mut self.next_level, if has_next_level => {
+                    self.start_next_level();
+                }
+            }
+        }
+    }
+
+    /// Asks the services of the next priority, returns `false` if all services were asked.
+    fn start_next_level(&mut self) -> bool {
+        let Some(level) = self.levels.pop_front() else {
+            return false;
+        };
+        for service in level {
+            let Some(stream) = service.service.resolve(self.endpoint.clone(), self.node_id) else {
+                continue;
+            };
+            let stream = match service.timeout {
+                Some(timeout) => Box::pin(futures_util::StreamExt::take_until(
+                    stream,
+                    tokio::time::sleep(timeout),
+                )),
+                None => stream,
+            };
+            self.running.push(stream);
+        }
+        self.next_level
+            .as_mut()
+            .reset(Instant::now() + self.priority_delay);
+        true
+    }
 }
 
 /// Maximum duration since the last control or data message received from an endpoint to make us
@@ -282,6 +612,52 @@ impl DiscoveryTask {
         self.task.abort();
     }
 
+    /// Resolves `node_id` with all discovery services until they are done, and adds the found
+    /// addresses to the endpoint.
+    ///
+    /// Returns the addresses found by all services, or an error if none were found.
+    pub(super) async fn resolve_all(ep: Endpoint, node_id: NodeId) -> Result<AddrInfo> {
+        let mut stream = Self::create_stream(&ep, node_id)?;
+        let mut found = AddrInfo::default();
+        let mut error = None;
+        loop {
+            let next = tokio::select! {
+                _ = ep.cancelled() => break,
+                next = stream.next() => next
+            };
+            match next {
+                Some(Ok(r)) if r.addr_info.is_empty() => {}
+                Some(Ok(r)) => {
+                    found.relay_url = found.relay_url.or(r.addr_info.relay_url.clone());
+                    found
+                        .direct_addresses
+                        .extend(r.addr_info.direct_addresses.iter().copied());
+                    Self::add_found(&ep, r);
+                }
+                Some(Err(err)) => error = Some(err),
+                None => break,
+            }
+        }
+        match (found.is_empty(), error) {
+            (false, _) => Ok(found),
+            (true, Some(err)) => Err(err),
+            (true, None) => Err(anyhow!(
+                "Discovery produced no results for {}",
+                node_id.fmt_short()
+            )),
+        }
+    }
+
+    /// Adds the addresses of a node found by discovery to the endpoint.
+    fn add_found(ep: &Endpoint, item: DiscoveryItem) {
+        ep.discovered(item.clone());
+        let addr = NodeAddr {
+            info: item.addr_info,
+            node_id: item.node_id,
+        };
+        ep.add_node_addr_with_source(addr, item.provenance).ok();
+    }
+
     fn create_stream(ep: &Endpoint, node_id: NodeId) -> Result<BoxStream<Result<DiscoveryItem>>> {
         let discovery = ep
             .discovery()
@@ -338,11 +714,7 @@ impl DiscoveryTask {
                         continue;
                     }
                     debug!(provenance = %r.provenance, addr = ?r.addr_info, "discovery: new address found");
-                    let addr = NodeAddr {
-                        info: r.addr_info,
-                        node_id,
-                    };
-                    ep.add_node_addr_with_source(addr, r.provenance).ok();
+                    Self::add_found(&ep, r);
                     if let Some(tx) = on_first_tx.take() {
                         tx.send(Ok(())).ok();
                     }
@@ -456,6 +828,7 @@ mod tests {
                         provenance: "test-disco",
                         last_updated: Some(ts),
                         addr_info,
+                        user_data: None,
                     };
                     let delay = self.delay;
                     let fut = async move {
@@ -539,6 +912,82 @@ mod tests {
         Ok(())
     }
 
+    /// This test resolves with services of three priorities, of which the first times out and
+    /// the second finds no address.
+    #[tokio::test]
+    async fn prioritized_discovery_first_success() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let disco_shared = TestDiscoveryShared::default();
+        let node_id = SecretKey::generate().public();
+        let addr_info = AddrInfo {
+            relay_url: None,
+            direct_addresses: BTreeSet::from(["127.0.0.1:4433".parse()?]),
+        };
+        disco_shared.create_discovery(node_id).publish(&addr_info);
+
+        let mut disco = PrioritizedDiscovery::new(ResolveMode::FirstSuccess)
+            .with_priority_delay(Duration::from_secs(5));
+        // would answer after 100ms with a wrong address
+        let lying = disco_shared.create_lying_discovery(node_id);
+        disco.add(PrioritizedService::new(lying).with_timeout(Duration::from_millis(50)));
+        disco.add(PrioritizedService::new(EmptyDiscovery).with_priority(1));
+        let found = disco_shared.create_discovery(node_id);
+        disco.add(PrioritizedService::new(found).with_priority(2));
+        // not asked, as the node is found first
+        let lying = disco_shared.create_lying_discovery(node_id);
+        disco.add(PrioritizedService::new(lying).with_priority(3));
+
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let stream = disco.resolve(ep, node_id).expect("resolves");
+        let items: Vec<_> = tokio::time::timeout(Duration::from_secs(2), stream.collect()).await?;
+        assert_eq!(items.len(), 1);
+        let item = items.into_iter().next().unwrap()?;
+        assert_eq!(item.addr_info, addr_info);
+        Ok(())
+    }
+
+    /// This test watches a node whose published address changes.
+    #[tokio::test]
+    async fn endpoint_watch_discovery() -> anyhow::Result<()> {
+        let _guard = iroh_test::logging::setup();
+        let disco_shared = TestDiscoveryShared::default();
+        let (ep, _guard) = {
+            let secret = SecretKey::generate();
+            let disco = disco_shared.create_discovery(secret.public());
+            new_endpoint(secret, disco).await
+        };
+        let node_id = SecretKey::generate().public();
+        let node_disco = disco_shared.create_discovery(node_id);
+        let addr_info = AddrInfo {
+            relay_url: None,
+            direct_addresses: BTreeSet::from(["127.0.0.1:4433".parse()?]),
+        };
+        node_disco.publish(&addr_info);
+
+        let events = ep.watch_discovery(node_id, Duration::from_millis(300))?;
+        tokio::pin!(events);
+        let event = tokio::time::timeout(Duration::from_secs(2), events.next())
+            .await?
+            .expect("event");
+        assert!(matches!(event, DiscoveryEvent::Found(_)));
+        assert_eq!(event.item().addr_info, addr_info);
+
+        let addr_info = AddrInfo {
+            relay_url: None,
+            direct_addresses: BTreeSet::from(["127.0.0.1:4434".parse()?]),
+        };
+        node_disco.publish(&addr_info);
+        let event = tokio::time::timeout(Duration::from_secs(2), events.next())
+            .await?
+            .expect("event");
+        assert!(matches!(event, DiscoveryEvent::Changed(_)));
+        assert_eq!(event.item().addr_info, addr_info);
+        Ok(())
+    }
+
     /// This test adds a "lying" discovery which provides a wrong address.
     /// This is to make sure that as long as one of the discoveries returns a working address, we
     /// will connect successfully.
@@ -665,20 +1114,29 @@ mod tests {
 /// publish to. The DNS and pkarr servers share their state.
 #[cfg(test)]
 mod test_dns_pkarr {
-    use std::time::Duration;
+    use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
 
     use anyhow::Result;
+    use futures_lite::StreamExt;
+    use hickory_proto::rr::Name;
     use iroh_base::key::SecretKey;
     use tokio_util::task::AbortOnDropHandle;
 
     use crate::{
-        discovery::pkarr::PkarrPublisher,
-        dns::{node_info::NodeInfo, ResolverExt},
-        relay::{RelayMap, RelayMode},
+        discovery::{
+            dns::{DnsDiscovery, DnsFallback, RecordVerification},
+            pkarr::{PkarrPublisher, PublishMode, RepublishPolicy},
+            Discovery,
+        },
+        dns::{
+            node_info::{NodeInfo, UserData},
+            ResolverExt,
+        },
+        relay::{server::DnsForward, RelayMap, RelayMode},
         test_utils::{
             dns_server::{create_dns_resolver, run_dns_server},
             pkarr_dns_state::State,
-            run_relay_server, DnsPkarrServer,
+            run_relay_server, run_relay_server_with_dns_forward, DnsPkarrServer,
         },
         AddrInfo, Endpoint, NodeAddr,
     };
@@ -743,6 +1201,466 @@ mod test_dns_pkarr {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn pkarr_publish_status() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let (relay_map, _relay_url, _relay_guard) = run_relay_server().await?;
+        let (ep, _guard) = ep_with_discovery(&relay_map, &dns_pkarr_server).await?;
+        dns_pkarr_server
+            .on_node(&ep.node_id(), PUBLISH_TIMEOUT)
+            .await?;
+        // the status is updated after the relay answered
+        let status = tokio::time::timeout(PUBLISH_TIMEOUT, async {
+            loop {
+                if let [status] = ep.publish_status().as_slice() {
+                    if status.last_published.is_some() {
+                        return status.clone();
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(status.target, dns_pkarr_server.pkarr_url.as_str());
+        assert_eq!(status.last_error, None);
+
+        // a pkarr relay that can't be reached
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let publisher =
+            PkarrPublisher::new(SecretKey::generate(), format!("http://{closed}").parse()?);
+        publisher.update_addr_info(&AddrInfo::default());
+        let status = tokio::time::timeout(PUBLISH_TIMEOUT, async {
+            loop {
+                let status = publisher.statuses().remove(0);
+                if status.last_error.is_some() {
+                    return status;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(status.last_published, None);
+        assert!(status.failed_attempts >= 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pkarr_publish_multiple_relays() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        // the first relay can't be reached, which doesn't hold up the second one
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let relays = [
+            format!("http://{closed}").parse()?,
+            dns_pkarr_server.pkarr_url.clone(),
+        ];
+        let publisher =
+            PkarrPublisher::with_relays(secret_key, relays, 30, RepublishPolicy::default());
+        publisher.update_addr_info(&AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        });
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+        let statuses = tokio::time::timeout(PUBLISH_TIMEOUT, async {
+            loop {
+                let statuses = publisher.statuses();
+                if statuses[0].last_error.is_some() && statuses[1].last_published.is_some() {
+                    return statuses;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(statuses[0].last_published, None);
+        assert_eq!(statuses[1].last_error, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pkarr_publish_relay_only() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            direct_addresses: ["127.0.0.1:4433".parse().unwrap()].into(),
+        };
+
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone())
+            .with_publish_mode(PublishMode::RelayOnly);
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+        let resolved = dns_pkarr_server
+            .dns_resolver()
+            .lookup_by_id(&node_id, &dns_pkarr_server.node_origin)
+            .await?;
+
+        assert_eq!(resolved.info.relay_url, addr_info.relay_url);
+        assert!(resolved.info.direct_addresses.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pkarr_publish_user_data() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let user_data: UserData = "proto=v1".parse()?;
+
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone())
+            .with_user_data(Some(user_data.clone()));
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+
+        let discovery = DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+            .with_resolvers([dns_pkarr_server.dns_resolver()]);
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let item = discovery
+            .resolve(ep, node_id)
+            .expect("dns discovery resolves")
+            .next()
+            .await
+            .expect("one item")?;
+        assert_eq!(item.addr_info, addr_info);
+        assert_eq!(item.user_data, Some(user_data));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_discovery_fallback_resolver() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        // a nameserver that never answers
+        let unresponsive = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+
+        let discovery = DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+            .with_resolvers([
+                create_dns_resolver(unresponsive.local_addr()?)?,
+                dns_pkarr_server.dns_resolver(),
+            ])
+            .with_fallback(DnsFallback::After(Duration::from_millis(100)));
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let mut stream = discovery
+            .resolve(ep, node_id)
+            .expect("dns discovery resolves");
+        // the primary resolver only times out after 5 seconds
+        let item = tokio::time::timeout(Duration::from_secs(2), stream.next())
+            .await?
+            .expect("one item")?;
+        assert_eq!(item.addr_info, addr_info);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_discovery_staggered_resolvers() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        // a nameserver that never answers
+        let unresponsive = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+
+        let discovery = DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+            .with_resolvers([
+                create_dns_resolver(unresponsive.local_addr()?)?,
+                dns_pkarr_server.dns_resolver(),
+                create_dns_resolver(unresponsive.local_addr()?)?,
+            ])
+            // the third resolver is only asked if the others fail
+            .with_fallback(DnsFallback::Staggered(vec![Duration::from_millis(200)]));
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let mut stream = discovery
+            .resolve(ep, node_id)
+            .expect("dns discovery resolves");
+        let item = tokio::time::timeout(Duration::from_secs(2), stream.next())
+            .await?
+            .expect("one item")?;
+        assert_eq!(item.addr_info, addr_info);
+
+        let stats = discovery.resolver_stats();
+        let asked: Vec<_> = stats.iter().map(|s| s.asked()).collect();
+        let answered: Vec<_> = stats.iter().map(|s| s.answered()).collect();
+        assert_eq!(asked, [1, 1, 0]);
+        assert_eq!(answered, [0, 1, 0]);
+        assert_eq!(stats[0].failed(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_discovery_cache() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+
+        let discovery = DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+            .with_resolvers([dns_pkarr_server.dns_resolver()]);
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        for _ in 0..2 {
+            let mut stream = discovery
+                .resolve(ep.clone(), node_id)
+                .expect("dns discovery resolves");
+            let item = stream.next().await.expect("one item")?;
+            assert_eq!(item.addr_info, addr_info);
+        }
+        // the record is cached for its TTL
+        assert_eq!(discovery.resolver_stats()[0].asked(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_discovery_negative_cache() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        // a node that never published its record
+        let node_id = SecretKey::generate().public();
+
+        let discovery = DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+            .with_resolvers([dns_pkarr_server.dns_resolver()]);
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        for _ in 0..2 {
+            let mut stream = discovery
+                .resolve(ep.clone(), node_id)
+                .expect("dns discovery resolves");
+            assert!(stream.next().await.expect("one item").is_err());
+        }
+        // the failed lookup is cached for a short time
+        assert_eq!(discovery.resolver_stats()[0].asked(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_discovery_verify_signed_packet() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let discovery = |max_age| {
+            DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+                .with_resolvers([dns_pkarr_server.dns_resolver()])
+                .with_verification(RecordVerification::SignedPacket {
+                    pkarr_relay: dns_pkarr_server.pkarr_url.clone(),
+                    max_age,
+                })
+        };
+        let item = discovery(Some(Duration::from_secs(60)))
+            .resolve(ep.clone(), node_id)
+            .expect("dns discovery resolves")
+            .next()
+            .await
+            .expect("one item")?;
+        assert_eq!(item.addr_info, addr_info);
+
+        // the packet is older than zero seconds
+        let res = discovery(Some(Duration::ZERO))
+            .resolve(ep, node_id)
+            .expect("dns discovery resolves")
+            .next()
+            .await
+            .expect("one item");
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_discovery_relay_fallback() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let dns_forward = DnsForward {
+            nameserver: dns_pkarr_server.nameserver,
+            origins: vec![Name::from_utf8(&dns_pkarr_server.node_origin)?],
+        };
+        let (relay_map, _relay_url, _relay_guard) =
+            run_relay_server_with_dns_forward(Some(dns_forward)).await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+        publisher.update_addr_info(&addr_info);
+        dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+
+        // a nameserver that can't be reached, but the relay server can
+        let blocked = tokio::net::UdpSocket::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let discovery = DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+            .with_resolvers([create_dns_resolver(blocked)?])
+            .with_relay_fallback(true);
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        tokio::time::timeout(PUBLISH_TIMEOUT, ep.watch_home_relay().next()).await?;
+        let item = discovery
+            .resolve(ep, node_id)
+            .expect("dns discovery resolves")
+            .next()
+            .await
+            .expect("one item")?;
+        assert_eq!(item.addr_info, addr_info);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn endpoint_resolve_many() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let dns_pkarr_server = DnsPkarrServer::run().await?;
+        let mut publishers = Vec::new();
+        let mut expected = BTreeMap::new();
+        for i in 0..3u16 {
+            let secret_key = SecretKey::generate();
+            let node_id = secret_key.public();
+            let addr_info = AddrInfo {
+                relay_url: None,
+                direct_addresses: [SocketAddr::from(([127, 0, 0, 1], 4000 + i))].into(),
+            };
+            let publisher = PkarrPublisher::new(secret_key, dns_pkarr_server.pkarr_url.clone());
+            publisher.update_addr_info(&addr_info);
+            dns_pkarr_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+            publishers.push(publisher);
+            expected.insert(node_id, addr_info);
+        }
+        // a node that never published its record
+        let unknown = SecretKey::generate().public();
+
+        let discovery = DnsDiscovery::new(dns_pkarr_server.node_origin.clone())
+            .with_resolvers([dns_pkarr_server.dns_resolver()]);
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .discovery(Box::new(discovery))
+            .bind()
+            .await?;
+        let node_ids = expected.keys().copied().chain([unknown]);
+        let resolved: BTreeMap<_, _> = ep.resolve_many(node_ids)?.collect().await;
+        assert_eq!(resolved.len(), 4);
+        assert!(resolved[&unknown].is_err());
+        for (node_id, addr_info) in &expected {
+            assert_eq!(resolved[node_id].as_ref().unwrap(), addr_info);
+            // the addresses are in the address book
+            assert!(ep.remote_info(*node_id).is_some());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dns_discovery_custom_origin() -> Result<()> {
+        let _logging_guard = iroh_test::logging::setup();
+
+        let default_server = DnsPkarrServer::run().await?;
+        let custom_server = DnsPkarrServer::run_with_origin("dns.self-hosted.test".into()).await?;
+        let secret_key = SecretKey::generate();
+        let node_id = secret_key.public();
+        let addr_info = AddrInfo {
+            relay_url: Some("https://relay.example".parse().unwrap()),
+            ..Default::default()
+        };
+        let publisher = PkarrPublisher::new(secret_key, custom_server.pkarr_url.clone());
+        publisher.update_addr_info(&addr_info);
+        custom_server.on_node(&node_id, PUBLISH_TIMEOUT).await?;
+
+        // the node is only published under the custom origin, with its own nameserver
+        let discovery = DnsDiscovery::new(default_server.node_origin.clone())
+            .with_resolvers([default_server.dns_resolver()])
+            .with_origin(
+                custom_server.node_origin.clone(),
+                [custom_server.nameserver],
+            );
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let items: Vec<_> = discovery
+            .resolve(ep, node_id)
+            .expect("dns discovery resolves")
+            .collect()
+            .await;
+        let [item] = &items[..] else {
+            panic!("expected one item, got {items:?}");
+        };
+        assert_eq!(item.as_ref().expect("resolved").addr_info, addr_info);
+        Ok(())
+    }
+
     const TEST_ALPN: &[u8] = b"TEST";
 
     #[tokio::test]
@@ -1,11 +1,33 @@
 //! DNS node discovery for iroh-net
 
-use anyhow::Result;
-use futures_lite::stream::Boxed as BoxStream;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{ensure, Context, Result};
+use futures_lite::{stream::Boxed as BoxStream, StreamExt};
+use futures_util::stream::FuturesUnordered;
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::RecordType,
+};
+use parking_lot::Mutex;
+use url::Url;
 
 use crate::{
-    discovery::{Discovery, DiscoveryItem},
-    dns::ResolverExt,
+    discovery::{pkarr::PkarrRelayClient, Discovery, DiscoveryItem},
+    dns::{
+        create_resolver,
+        node_info::{node_txt_name, IrohAttr, NodeInfo, TxtAttrs},
+        stagger_call, DnsResolver, DnsTransport,
+    },
     Endpoint, NodeId,
 };
 
@@ -19,6 +41,185 @@ pub const N0_DNS_NODE_ORIGIN_STAGING: &str = "staging-dns.iroh.link";
 pub const TEST_DNS_NODE_ORIGIN: &str = "dns.iroh.test";
 
 const DNS_STAGGERING_MS: &[u64] = &[200, 300];
+/// The time after which the next resolver is asked by default.
+const DEFAULT_FALLBACK_DELAY: Duration = Duration::from_secs(2);
+/// How long to wait for the relay server to answer a lookup.
+const RELAY_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long failed lookups are cached by default.
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+/// When [`DnsDiscovery`] asks the next of its resolvers
+///
+/// In all cases the next resolver is also asked when the lookups of the previous ones failed. The
+/// lookups of the previous resolvers keep running, and the first answer is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsFallback {
+    /// Ask the next resolver when the previous one didn't answer within this time
+    After(Duration),
+    /// Ask all resolvers at once
+    Race,
+    /// Ask the resolvers after these delays from the start of the lookup
+    ///
+    /// The first resolver is asked right away, the second after the first delay, the third
+    /// after the second delay, and so on: `[200ms, 300ms]` asks the resolvers at T+0ms, T+200ms
+    /// and T+300ms. Resolvers without a delay are only asked when the previous ones failed.
+    Staggered(Vec<Duration>),
+}
+
+impl Default for DnsFallback {
+    fn default() -> Self {
+        Self::After(DEFAULT_FALLBACK_DELAY)
+    }
+}
+
+impl DnsFallback {
+    /// Get when the resolver at `index` is asked, given when the lookup and the previous
+    /// resolver started, or `None` if it is only asked when the previous ones failed.
+    fn start(
+        &self,
+        index: usize,
+        lookup_start: Instant,
+        previous_start: Instant,
+    ) -> Option<Instant> {
+        match self {
+            Self::After(delay) => Some(previous_start + *delay),
+            Self::Race => Some(lookup_start),
+            Self::Staggered(delays) => delays.get(index - 1).map(|delay| lookup_start + *delay),
+        }
+    }
+}
+
+/// How [`DnsDiscovery`] verifies the node records it resolved
+///
+/// The TXT records of DNS answers don't carry the signature of the pkarr packet they were
+/// created from, so the signature and the age of the records can only be checked with the full
+/// signed packet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RecordVerification {
+    /// Trust the TXT records of the DNS answers
+    #[default]
+    None,
+    /// Fetch the signed packet of each resolved node from a pkarr relay, verify its signature,
+    /// and reject the records if they don't match the packet or the packet is too old
+    SignedPacket {
+        /// The pkarr relay to fetch the packets from, e.g. [`super::pkarr::N0_DNS_PKARR_RELAY_PROD`]
+        ///
+        /// Use an `https` URL, so that the packets can't be withheld on the way.
+        pkarr_relay: Url,
+        /// Packets whose timestamp is older than this are rejected
+        max_age: Option<Duration>,
+    },
+}
+
+/// Verifies the node records of a [`DnsDiscovery`] with their signed packets.
+#[derive(Debug)]
+struct PacketVerifier {
+    pkarr_client: PkarrRelayClient,
+    max_age: Option<Duration>,
+}
+
+impl PacketVerifier {
+    /// Checks that `node_info` matches the signed packet of the node, which is recent enough.
+    async fn verify(&self, node_info: &NodeInfo) -> Result<()> {
+        // the signature is verified when the packet is parsed
+        let packet = self
+            .pkarr_client
+            .resolve(node_info.node_id)
+            .await
+            .context("failed to fetch the signed packet")?;
+        if let Some(max_age) = self.max_age {
+            let timestamp = SystemTime::UNIX_EPOCH + Duration::from_micros(packet.timestamp());
+            let age = SystemTime::now()
+                .duration_since(timestamp)
+                .unwrap_or_default();
+            ensure!(
+                age <= max_age,
+                "the signed packet is {}s old, older than {}s",
+                age.as_secs(),
+                max_age.as_secs()
+            );
+        }
+        let signed = NodeInfo::from_pkarr_signed_packet(&packet)?;
+        ensure!(
+            signed == *node_info,
+            "the DNS answer does not match the signed packet"
+        );
+        Ok(())
+    }
+}
+
+/// The outcome of a lookup of a [`DnsDiscovery`]: the node record, or the error of the lookup
+type CachedLookup = std::result::Result<NodeInfo, String>;
+
+/// The node records resolved by a [`DnsDiscovery`] under each origin, until their TTL expires,
+/// and the lookups that failed, for a short time
+#[derive(Debug, Default)]
+struct NodeCache(Mutex<HashMap<(String, NodeId), (CachedLookup, Instant)>>);
+
+impl NodeCache {
+    /// Get the cached lookup of `node_id` under `origin`, unless it expired.
+    fn get(&self, origin: &str, node_id: &NodeId) -> Option<CachedLookup> {
+        let mut nodes = self.0.lock();
+        let key = (origin.to_string(), *node_id);
+        match nodes.get(&key) {
+            Some((lookup, valid_until)) if *valid_until > Instant::now() => Some(lookup.clone()),
+            Some(_) => {
+                nodes.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache the `lookup` of `node_id` under `origin` until `valid_until`, and remove the
+    /// expired lookups.
+    fn insert(&self, origin: &str, node_id: NodeId, lookup: CachedLookup, valid_until: Instant) {
+        let now = Instant::now();
+        let mut nodes = self.0.lock();
+        nodes.retain(|_, (_, valid_until)| *valid_until > now);
+        if valid_until > now {
+            nodes.insert((origin.to_string(), node_id), (lookup, valid_until));
+        }
+    }
+}
+
+/// An origin domain of a [`DnsDiscovery`] besides the one of [`DnsDiscovery::new`]
+#[derive(derive_more::Debug, Clone)]
+struct CustomOrigin {
+    domain: String,
+    /// The resolvers of the origin, or none to use the resolvers of the discovery
+    #[debug("{} resolvers", resolvers.len())]
+    resolvers: Vec<DnsResolver>,
+    stats: Arc<[DnsResolverStats]>,
+}
+
+/// The lookups of a resolver of a [`DnsDiscovery`]
+#[derive(Debug, Default)]
+pub struct DnsResolverStats {
+    asked: AtomicU64,
+    answered: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl DnsResolverStats {
+    /// The number of lookups the resolver was asked for.
+    pub fn asked(&self) -> u64 {
+        self.asked.load(Ordering::Relaxed)
+    }
+
+    /// The number of lookups the resolver answered first, whose answer was used.
+    pub fn answered(&self) -> u64 {
+        self.answered.load(Ordering::Relaxed)
+    }
+
+    /// The number of lookups of the resolver that failed, e.g. timed out.
+    ///
+    /// Lookups that were still running when another resolver answered are neither answered nor
+    /// failed.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
 
 /// DNS node discovery
 ///
@@ -31,24 +232,173 @@ const DNS_STAGGERING_MS: &[u64] = &[200, 300];
 /// * `<z32-node-id>` is the [`NodeId`] encoded in [`z-base-32`] format
 /// * `<origin-domain>` is the node origin domain as set in [`DnsDiscovery::new`].
 ///
+/// More origin domains, e.g. of self-hosted `iroh-dns-server`s, are added with
+/// [`DnsDiscovery::with_origin`], optionally with the nameservers that serve them. All origins
+/// are looked up at once, and the records found under each of them are returned.
+///
 /// Each TXT record returned from the query is expected to contain a string in the format `<name>=<value>`.
 /// If a TXT record contains multiple character strings, they are concatenated first.
 /// The supported attributes are:
 /// * `relay=<url>`: The URL of the home relay server of the node
 ///
 /// The DNS resolver defaults to using the nameservers configured on the host system, but can be changed
-/// with [`crate::endpoint::Builder::dns_resolver`].
+/// with [`crate::endpoint::Builder::dns_resolver`]. To not depend on a single resolver, an ordered
+/// list of resolvers can be set with [`DnsDiscovery::with_resolvers`]: the next resolver is asked
+/// when the previous ones fail or time out, after fixed delays, or all at once, see
+/// [`DnsFallback`]. Where plain DNS is blocked, resolvers that use DNS over TLS or HTTPS can be
+/// created with [`crate::dns::create_resolver`]. How often each resolver was asked and answered is counted in
+/// [`DnsDiscovery::resolver_stats`].
+///
+/// The resolved records are cached until their TTL expires, so that dialing a node again doesn't
+/// look it up again, see [`DnsDiscovery::with_cache`]. Failed lookups, e.g. of nodes that are
+/// offline, are cached for a short time as well, so that dialing them again and again doesn't ask
+/// the resolvers each time, see [`DnsDiscovery::with_negative_ttl`].
+///
+/// On networks where neither plain DNS nor DNS over HTTPS reaches a nameserver, the lookups
+/// can fall back to the nameserver of the home relay server, through the relay connection of the
+/// [`Endpoint`], see [`DnsDiscovery::with_relay_fallback`].
+///
+/// As the DNS answers are not signed, apps that don't trust the resolvers can verify the records
+/// with the signed packets from a pkarr relay, see [`DnsDiscovery::with_verification`].
 ///
 /// [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 pub struct DnsDiscovery {
     origin_domain: String,
+    origins: Vec<CustomOrigin>,
+    #[debug("{} resolvers", resolvers.len())]
+    resolvers: Vec<DnsResolver>,
+    fallback: DnsFallback,
+    /// The stats of each resolver, or of the [`Endpoint`]'s resolver if none is set
+    stats: Arc<[DnsResolverStats]>,
+    #[debug(skip)]
+    cache: Option<Arc<NodeCache>>,
+    negative_ttl: Duration,
+    relay_fallback: bool,
+    verifier: Option<Arc<PacketVerifier>>,
 }
 
 impl DnsDiscovery {
     /// Creates a new DNS discovery.
     pub fn new(origin_domain: String) -> Self {
-        Self { origin_domain }
+        Self {
+            origin_domain,
+            origins: Vec::new(),
+            resolvers: Vec::new(),
+            fallback: DnsFallback::default(),
+            stats: Arc::new([DnsResolverStats::default()]),
+            cache: Some(Default::default()),
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            relay_fallback: false,
+            verifier: None,
+        }
+    }
+
+    /// Sets the resolvers to use instead of the [`Endpoint`]'s DNS resolver, in order.
+    ///
+    /// The first resolver is asked first, the others are asked as set with [`Self::with_fallback`].
+    /// An empty list uses the [`Endpoint`]'s DNS resolver again.
+    pub fn with_resolvers(mut self, resolvers: impl IntoIterator<Item = DnsResolver>) -> Self {
+        self.resolvers = resolvers.into_iter().collect();
+        let stats = self.resolvers.len().max(1);
+        self.stats = (0..stats).map(|_| DnsResolverStats::default()).collect();
+        self
+    }
+
+    /// Also looks up the node records under `domain`, e.g. of a self-hosted `iroh-dns-server`.
+    ///
+    /// The records are looked up with the `nameservers` of the origin, or with the resolvers of
+    /// the discovery if there are none. This can't be undone, and adding the same domain again
+    /// only adds it once.
+    pub fn with_origin(
+        mut self,
+        domain: String,
+        nameservers: impl IntoIterator<Item = SocketAddr>,
+    ) -> Self {
+        let nameservers: Vec<_> = nameservers.into_iter().collect();
+        let resolvers = match nameservers.is_empty() {
+            true => Vec::new(),
+            false => vec![create_resolver(nameservers, DnsTransport::Udp)],
+        };
+        let origin = CustomOrigin {
+            stats: resolvers.iter().map(|_| Default::default()).collect(),
+            domain,
+            resolvers,
+        };
+        self.origins.retain(|o| o.domain != origin.domain);
+        if origin.domain != self.origin_domain {
+            self.origins.push(origin);
+        }
+        self
+    }
+
+    /// Get the origin domains that are looked up, starting with the one of [`Self::new`].
+    pub fn origins(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.origin_domain.as_str())
+            .chain(self.origins.iter().map(|o| o.domain.as_str()))
+    }
+
+    /// Sets when the next resolver is asked, see [`DnsFallback`].
+    ///
+    /// Defaults to [`DnsFallback::After`] 2 seconds.
+    pub fn with_fallback(mut self, fallback: DnsFallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Sets whether the resolved records are cached until their TTL expires.
+    ///
+    /// Defaults to `true`.
+    pub fn with_cache(mut self, enabled: bool) -> Self {
+        self.cache = enabled.then(Default::default);
+        self
+    }
+
+    /// Sets how long failed lookups are cached, if the cache is enabled.
+    ///
+    /// Resolving the node under the origin of the lookup fails with the cached error until then.
+    /// [`Duration::ZERO`] doesn't cache failed lookups. Defaults to 10 seconds.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Sets whether the lookups that failed with all resolvers are sent to the home relay server.
+    ///
+    /// The relay server looks them up with its nameserver, if it forwards DNS queries for the
+    /// origin, see [`crate::relay::server::RelayConfig::dns_forward`]. Relay servers that don't
+    /// advertise it when connecting, and connections over websockets, are not asked, and fail
+    /// the fallback. This still works where the nameservers are blocked, as long as the relay
+    /// server can be reached. Defaults to `false`.
+    pub fn with_relay_fallback(mut self, enabled: bool) -> Self {
+        self.relay_fallback = enabled;
+        self
+    }
+
+    /// Sets how the resolved records are verified, see [`RecordVerification`].
+    ///
+    /// Records that fail the verification are not returned, and the lookup fails as if the node
+    /// wasn't found. Defaults to [`RecordVerification::None`].
+    pub fn with_verification(mut self, verification: RecordVerification) -> Self {
+        self.verifier = match verification {
+            RecordVerification::None => None,
+            RecordVerification::SignedPacket {
+                pkarr_relay,
+                max_age,
+            } => Some(Arc::new(PacketVerifier {
+                pkarr_client: PkarrRelayClient::new(pkarr_relay),
+                max_age,
+            })),
+        };
+        self
+    }
+
+    /// Get the stats of the lookups of each resolver, in the order of [`Self::with_resolvers`].
+    ///
+    /// Without resolvers, these are the stats of the [`Endpoint`]'s DNS resolver. Lookups with
+    /// the nameservers of an origin of [`Self::with_origin`] are not counted.
+    pub fn resolver_stats(&self) -> &[DnsResolverStats] {
+        &self.stats
     }
 
     /// Creates a new DNS discovery using the `iroh.link` domain.
@@ -82,20 +432,235 @@ impl DnsDiscovery {
 
 impl Discovery for DnsDiscovery {
     fn resolve(&self, ep: Endpoint, node_id: NodeId) -> Option<BoxStream<Result<DiscoveryItem>>> {
-        let resolver = ep.dns_resolver().clone();
-        let origin_domain = self.origin_domain.clone();
-        let fut = async move {
-            let node_addr = resolver
-                .lookup_by_id_staggered(&node_id, &origin_domain, DNS_STAGGERING_MS)
-                .await?;
-            Ok(DiscoveryItem {
-                node_id,
-                provenance: "dns",
-                last_updated: None,
-                addr_info: node_addr.info,
-            })
+        let resolvers = match self.resolvers.is_empty() {
+            true => vec![ep.dns_resolver().clone()],
+            false => self.resolvers.clone(),
         };
-        let stream = futures_lite::stream::once_future(fut);
+        let primary = CustomOrigin {
+            domain: self.origin_domain.clone(),
+            resolvers,
+            stats: self.stats.clone(),
+        };
+        let origins = std::iter::once(primary.clone()).chain(self.origins.iter().map(|origin| {
+            match origin.resolvers.is_empty() {
+                true => CustomOrigin {
+                    domain: origin.domain.clone(),
+                    ..primary.clone()
+                },
+                false => origin.clone(),
+            }
+        }));
+        let lookups: FuturesUnordered<_> = origins
+            .map(|origin| {
+                let fallback = self.fallback.clone();
+                let cache = self.cache.clone();
+                let negative_ttl = self.negative_ttl;
+                let relay = self.relay_fallback.then(|| ep.clone());
+                let verifier = self.verifier.clone();
+                async move {
+                    match cache.as_ref().and_then(|c| c.get(&origin.domain, &node_id)) {
+                        Some(Ok(node_info)) => return Ok(discovery_item(node_info)),
+                        Some(Err(err)) => anyhow::bail!("{err} (cached)"),
+                        None => {}
+                    }
+                    let res = lookup_with_fallback(
+                        &origin.resolvers,
+                        &fallback,
+                        &origin.stats,
+                        &node_id,
+                        &origin.domain,
+                    )
+                    .await;
+                    let res = match (res, relay) {
+                        (Err(err), Some(ep)) => {
+                            lookup_node_over_relay(&ep, &node_id, &origin.domain)
+                                .await
+                                .map_err(|relay_err| {
+                                    anyhow::anyhow!("{err}, over relay: {relay_err:#}")
+                                })
+                        }
+                        (res, _) => res,
+                    };
+                    let res = match (res, verifier) {
+                        (Ok((node_info, valid_until)), Some(verifier)) => verifier
+                            .verify(&node_info)
+                            .await
+                            .with_context(|| format!("record of {node_id} rejected"))
+                            .map(|()| (node_info, valid_until)),
+                        (res, _) => res,
+                    };
+                    match (res, cache) {
+                        (Ok((node_info, valid_until)), Some(cache)) => {
+                            let lookup = Ok(node_info.clone());
+                            cache.insert(&origin.domain, node_id, lookup, valid_until);
+                            Ok(discovery_item(node_info))
+                        }
+                        (Err(err), Some(cache)) => {
+                            let valid_until = Instant::now() + negative_ttl;
+                            cache.insert(
+                                &origin.domain,
+                                node_id,
+                                Err(err.to_string()),
+                                valid_until,
+                            );
+                            Err(err)
+                        }
+                        (res, None) => res.map(|(node_info, _)| discovery_item(node_info)),
+                    }
+                }
+            })
+            .collect();
+        // the lookups of some origins may fail, which is only an error if all of them failed
+        let stream = futures_lite::stream::unfold(
+            (lookups, false, None),
+            |(mut lookups, mut found, mut error)| async move {
+                loop {
+                    match lookups.next().await {
+                        Some(Ok(item)) => {
+                            found = true;
+                            return Some((Ok(item), (lookups, found, error)));
+                        }
+                        Some(Err(err)) => error = Some(err),
+                        None => {
+                            let err = error.take().filter(|_| !found)?;
+                            return Some((Err(err), (lookups, true, None)));
+                        }
+                    }
+                }
+            },
+        );
         Some(Box::pin(stream))
     }
 }
+
+/// Looks up the node info of `node_id` with the first of `resolvers` that answers.
+///
+/// The next resolver is asked when all running lookups failed, or as set by `fallback`. The
+/// lookups are counted in the `stats` of the resolvers.
+async fn lookup_with_fallback(
+    resolvers: &[DnsResolver],
+    fallback: &DnsFallback,
+    stats: &[DnsResolverStats],
+    node_id: &NodeId,
+    origin: &str,
+) -> Result<(NodeInfo, Instant)> {
+    let lookup_start = Instant::now();
+    let mut previous_start = lookup_start;
+    // the lookups of the resolvers that were not asked yet
+    let mut remaining = resolvers.iter().zip(stats).map(|(resolver, stats)| {
+        stats.asked.fetch_add(1, Ordering::Relaxed);
+        async move {
+            let res =
+                stagger_call(|| lookup_node(resolver, node_id, origin), DNS_STAGGERING_MS).await;
+            let counter = match res {
+                Ok(_) => &stats.answered,
+                Err(_) => &stats.failed,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            res
+        }
+    });
+    let mut lookups = FuturesUnordered::new();
+    let mut errors = Vec::new();
+    loop {
+        if lookups.is_empty() {
+            match remaining.next() {
+                Some(lookup) => {
+                    lookups.push(lookup);
+                    previous_start = Instant::now();
+                }
+                None => break,
+            }
+        }
+        let next_index = resolvers.len() - remaining.len();
+        let next_start = match remaining.len() {
+            0 => None,
+            _ => fallback.start(next_index, lookup_start, previous_start),
+        };
+        let res = match next_start {
+            None => lookups.next().await,
+            Some(next_start) => tokio::select! {
+                res = lookups.next() => res,
+                _ = tokio::time::sleep_until(next_start.into()) => {
+                    lookups.extend(remaining.next());
+                    previous_start = Instant::now();
+                    continue;
+                }
+            },
+        };
+        match res {
+            Some(Ok(res)) => return Ok(res),
+            Some(Err(err)) => errors.push(err),
+            None => {}
+        }
+    }
+    anyhow::bail!(
+        "no resolver succeeded: [ {}]",
+        errors.into_iter().fold(String::new(), |mut summary, e| {
+            write!(summary, "{e} ").expect("infallible");
+            summary
+        })
+    )
+}
+
+/// Looks up the node info of `node_id`, and until when it is valid.
+async fn lookup_node(
+    resolver: &DnsResolver,
+    node_id: &NodeId,
+    origin: &str,
+) -> Result<(NodeInfo, Instant)> {
+    let (attrs, valid_until) =
+        TxtAttrs::<IrohAttr>::lookup_by_id_with_expiry(resolver, node_id, origin).await?;
+    Ok((attrs.into(), valid_until))
+}
+
+/// Looks up the node info of `node_id` with the nameserver of the home relay server of `ep`, and
+/// until when it is valid.
+async fn lookup_node_over_relay(
+    ep: &Endpoint,
+    node_id: &NodeId,
+    origin: &str,
+) -> Result<(NodeInfo, Instant)> {
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(
+            node_txt_name(node_id, origin)?,
+            RecordType::TXT,
+        ));
+    let response = tokio::time::timeout(
+        RELAY_LOOKUP_TIMEOUT,
+        ep.relay_dns_query(query.to_vec()?.into()),
+    )
+    .await
+    .context("relay timeout")??;
+    let response = Message::from_vec(&response)?;
+    ensure!(
+        response.response_code() == ResponseCode::NoError,
+        "relay lookup failed: {}",
+        response.response_code()
+    );
+    let attrs = TxtAttrs::<IrohAttr>::from_hickory_records(response.answers())?;
+    let ttl = response
+        .answers()
+        .iter()
+        .map(|record| record.ttl())
+        .min()
+        .unwrap_or_default();
+    let valid_until = Instant::now() + Duration::from_secs(ttl.into());
+    Ok((attrs.into(), valid_until))
+}
+
+/// Creates the [`DiscoveryItem`] of a resolved node.
+fn discovery_item(node_info: NodeInfo) -> DiscoveryItem {
+    DiscoveryItem {
+        node_id: node_info.node_id,
+        provenance: "dns",
+        last_updated: None,
+        user_data: node_info.user_data.clone(),
+        addr_info: node_info.into(),
+    }
+}
@@ -347,6 +347,7 @@ fn peer_to_discovery_item(peer: &Peer, node_id: &NodeId) -> DiscoveryItem {
             relay_url: None,
             direct_addresses,
         },
+        user_data: None,
     }
 }
 
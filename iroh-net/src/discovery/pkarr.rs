@@ -44,11 +44,13 @@
 //! [`DnsDiscovery`]: crate::discovery::dns::DnsDiscovery
 //! [`DhtDiscovery`]: dht::DhtDiscovery
 
-use std::sync::Arc;
+use std::{sync::Arc, time::SystemTime};
 
 use anyhow::{anyhow, bail, Result};
 use futures_util::stream::BoxStream;
+use parking_lot::Mutex;
 use pkarr::SignedPacket;
+use rand::Rng;
 use tokio::{
     task::JoinHandle,
     time::{Duration, Instant},
@@ -58,8 +60,8 @@ use url::Url;
 use watchable::{Watchable, Watcher};
 
 use crate::{
-    discovery::{Discovery, DiscoveryItem},
-    dns::node_info::NodeInfo,
+    discovery::{Discovery, DiscoveryItem, PublishStatus},
+    dns::node_info::{NodeInfo, UserData},
     key::SecretKey,
     AddrInfo, Endpoint, NodeId,
 };
@@ -97,6 +99,80 @@ pub const DEFAULT_PKARR_TTL: u32 = 30;
 /// Interval in which to republish the node info even if unchanged: 5 minutes.
 pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 5);
 
+/// What a [`PkarrPublisher`] publishes about the node
+///
+/// Published records are public, so privacy-sensitive apps may not want to publish the direct
+/// addresses, which usually include the home IP address of the node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PublishMode {
+    /// Publish the relay URL if the node has one, otherwise the direct addresses
+    #[default]
+    RelayOrDirect,
+    /// Only publish the relay URL, never the direct addresses
+    RelayOnly,
+    /// Only publish the direct addresses, never the relay URL
+    DirectOnly,
+    /// Publish both the relay URL and the direct addresses
+    Both,
+    /// Publish nothing
+    ///
+    /// Other nodes can still be resolved with the other discovery services.
+    Disabled,
+}
+
+/// When a [`PkarrPublisher`] republishes the node info, and retries failed publishes
+///
+/// After the `n`th failed publish in a row, the publisher retries after `n * retry_backoff`, at
+/// most after `max_retry_backoff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepublishPolicy {
+    /// Interval in which to republish the node info even if unchanged
+    ///
+    /// Defaults to [`DEFAULT_REPUBLISH_INTERVAL`].
+    pub interval: Duration,
+    /// Maximum random time added to each interval
+    ///
+    /// This spreads the republishes of nodes that started at the same time. Defaults to zero.
+    pub jitter: Duration,
+    /// Time by which the wait before a retry grows with each failed publish
+    ///
+    /// Defaults to 1 second.
+    pub retry_backoff: Duration,
+    /// Maximum time to wait before a retry
+    ///
+    /// Defaults to [`DEFAULT_REPUBLISH_INTERVAL`].
+    pub max_retry_backoff: Duration,
+}
+
+impl Default for RepublishPolicy {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_REPUBLISH_INTERVAL,
+            jitter: Duration::ZERO,
+            retry_backoff: Duration::from_secs(1),
+            max_retry_backoff: DEFAULT_REPUBLISH_INTERVAL,
+        }
+    }
+}
+
+impl RepublishPolicy {
+    /// Get the time until the next republish after a successful publish.
+    fn republish_after(&self) -> Duration {
+        let jitter = match self.jitter.is_zero() {
+            true => Duration::ZERO,
+            false => rand::thread_rng().gen_range(Duration::ZERO..=self.jitter),
+        };
+        self.interval + jitter
+    }
+
+    /// Get the time until the retry after `failed_attempts` failed publishes in a row.
+    fn retry_after(&self, failed_attempts: u32) -> Duration {
+        self.retry_backoff
+            .saturating_mul(failed_attempts)
+            .min(self.max_retry_backoff)
+    }
+}
+
 /// Publisher of node discovery information to a [pkarr] relay.
 ///
 /// This publisher uses HTTP to publish node discovery information to a pkarr relay
@@ -106,9 +182,14 @@ pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 5);
 /// that it only publishes node discovery information, for the corresponding resolver use
 /// the [`PkarrResolver`] together with [`ConcurrentDiscovery`].
 ///
-/// This publisher will **only** publish the [`RelayUrl`] if the [`AddrInfo`] contains a
-/// [`RelayUrl`].  If the [`AddrInfo`] does not contain a [`RelayUrl`] the *direct
-/// addresses* are published instead.
+/// By default this publisher will **only** publish the [`RelayUrl`] if the [`AddrInfo`]
+/// contains a [`RelayUrl`].  If the [`AddrInfo`] does not contain a [`RelayUrl`] the *direct
+/// addresses* are published instead.  This can be changed with
+/// [`PkarrPublisher::with_publish_mode`].
+///
+/// To not depend on the availability of a single pkarr relay, the publisher can publish to
+/// several relays with [`PkarrPublisher::with_relays`].  Each relay is published to
+/// independently, with its own retries and [`PublishStatus`].
 ///
 /// [pkarr]: https://pkarr.org
 /// [module docs]: crate::discovery::pkarr
@@ -117,8 +198,11 @@ pub const DEFAULT_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 5);
 #[derive(derive_more::Debug, Clone)]
 pub struct PkarrPublisher {
     node_id: NodeId,
+    mode: PublishMode,
+    user_data: Option<UserData>,
     watchable: Watchable<Option<NodeInfo>>,
-    join_handle: Arc<JoinHandle<()>>,
+    statuses: Vec<Arc<Mutex<PublishStatus>>>,
+    join_handles: Arc<Vec<JoinHandle<()>>>,
 }
 
 impl PkarrPublisher {
@@ -131,11 +215,11 @@ impl PkarrPublisher {
     ///
     /// [pkarr]: https://pkarr.org
     pub fn new(secret_key: SecretKey, pkarr_relay: Url) -> Self {
-        Self::with_options(
+        Self::with_policy(
             secret_key,
             pkarr_relay,
             DEFAULT_PKARR_TTL,
-            DEFAULT_REPUBLISH_INTERVAL,
+            RepublishPolicy::default(),
         )
     }
 
@@ -149,29 +233,80 @@ impl PkarrPublisher {
         ttl: u32,
         republish_interval: std::time::Duration,
     ) -> Self {
-        debug!("creating pkarr publisher that publishes to {pkarr_relay}");
+        let policy = RepublishPolicy {
+            interval: republish_interval,
+            ..Default::default()
+        };
+        Self::with_policy(secret_key, pkarr_relay, ttl, policy)
+    }
+
+    /// Creates a new [`PkarrPublisher`] with a custom TTL and [`RepublishPolicy`].
+    ///
+    /// This allows tuning how often the node info is refreshed, e.g. less often on battery
+    /// powered devices.
+    pub fn with_policy(
+        secret_key: SecretKey,
+        pkarr_relay: Url,
+        ttl: u32,
+        policy: RepublishPolicy,
+    ) -> Self {
+        Self::with_relays(secret_key, [pkarr_relay], ttl, policy)
+    }
+
+    /// Creates a new [`PkarrPublisher`] which publishes to all of the `pkarr_relays`.
+    ///
+    /// Each relay is published to by its own task, so a relay that is down only delays the
+    /// retries of the publishes to that relay, following the [`RepublishPolicy`].
+    pub fn with_relays(
+        secret_key: SecretKey,
+        pkarr_relays: impl IntoIterator<Item = Url>,
+        ttl: u32,
+        policy: RepublishPolicy,
+    ) -> Self {
         let node_id = secret_key.public();
-        let pkarr_client = PkarrRelayClient::new(pkarr_relay);
         let watchable = Watchable::default();
-        let service = PublisherService {
-            ttl,
-            watcher: watchable.watch(),
-            secret_key,
-            pkarr_client,
-            republish_interval,
-        };
-        let join_handle = tokio::task::spawn(
-            service
-                .run()
-                .instrument(error_span!("pkarr_publish", me=%node_id.fmt_short())),
-        );
+        let mut statuses = Vec::new();
+        let mut join_handles = Vec::new();
+        for pkarr_relay in pkarr_relays {
+            debug!("creating pkarr publisher that publishes to {pkarr_relay}");
+            let status = Arc::new(Mutex::new(PublishStatus::new(
+                "pkarr",
+                pkarr_relay.as_str(),
+            )));
+            let span = error_span!("pkarr_publish", me=%node_id.fmt_short(), relay=%pkarr_relay);
+            let service = PublisherService {
+                ttl,
+                watcher: watchable.watch(),
+                secret_key: secret_key.clone(),
+                pkarr_client: PkarrRelayClient::new(pkarr_relay),
+                policy: policy.clone(),
+                status: status.clone(),
+            };
+            join_handles.push(tokio::task::spawn(service.run().instrument(span)));
+            statuses.push(status);
+        }
         Self {
             watchable,
             node_id,
-            join_handle: Arc::new(join_handle),
+            mode: PublishMode::default(),
+            user_data: None,
+            statuses,
+            join_handles: Arc::new(join_handles),
         }
     }
 
+    /// Sets what is published about the node, see [`PublishMode`].
+    pub fn with_publish_mode(mut self, mode: PublishMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the [`UserData`] that is published with the node info.
+    pub fn with_user_data(mut self, user_data: Option<UserData>) -> Self {
+        self.user_data = user_data;
+        self
+    }
+
     /// Creates a pkarr publisher which uses the [number 0] pkarr relay server.
     ///
     /// This uses the pkarr relay server operated by [number 0], at
@@ -182,40 +317,70 @@ impl PkarrPublisher {
     ///
     /// [number 0]: https://n0.computer
     pub fn n0_dns(secret_key: SecretKey) -> Self {
+        Self::n0_dns_with_policy(secret_key, RepublishPolicy::default())
+    }
+
+    /// Creates a pkarr publisher which uses the [number 0] pkarr relay server, with a custom
+    /// [`RepublishPolicy`].
+    ///
+    /// See [`Self::n0_dns`] for the pkarr relay server that is used.
+    ///
+    /// [number 0]: https://n0.computer
+    pub fn n0_dns_with_policy(secret_key: SecretKey, policy: RepublishPolicy) -> Self {
         #[cfg(not(any(test, feature = "test-utils")))]
         let pkarr_relay = N0_DNS_PKARR_RELAY_PROD;
         #[cfg(any(test, feature = "test-utils"))]
         let pkarr_relay = N0_DNS_PKARR_RELAY_STAGING;
 
         let pkarr_relay: Url = pkarr_relay.parse().expect("url is valid");
-        Self::new(secret_key, pkarr_relay)
+        Self::with_policy(secret_key, pkarr_relay, DEFAULT_PKARR_TTL, policy)
     }
 
     /// Publishes [`AddrInfo`] about this node to a pkarr relay.
     ///
     /// This is a nonblocking function, the actual update is performed in the background.
     pub fn update_addr_info(&self, info: &AddrInfo) {
-        let (relay_url, direct_addresses) = if let Some(relay_url) = info.relay_url.as_ref() {
-            (Some(relay_url.clone().into()), Default::default())
-        } else {
-            (None, info.direct_addresses.clone())
+        let relay_url = info.relay_url.clone().map(Url::from);
+        let direct_addresses = info.direct_addresses.clone();
+        let (relay_url, direct_addresses) = match self.mode {
+            PublishMode::RelayOrDirect if relay_url.is_some() => (relay_url, Default::default()),
+            PublishMode::RelayOrDirect => (None, direct_addresses),
+            PublishMode::RelayOnly => (relay_url, Default::default()),
+            PublishMode::DirectOnly => (None, direct_addresses),
+            PublishMode::Both => (relay_url, direct_addresses),
+            PublishMode::Disabled => return,
         };
-        let info = NodeInfo::new(self.node_id, relay_url, direct_addresses);
+        let info = NodeInfo::new(self.node_id, relay_url, direct_addresses)
+            .with_user_data(self.user_data.clone());
         self.watchable.update(Some(info)).ok();
     }
+
+    /// Returns the status of the publishing to each pkarr relay, in the order they were given.
+    pub fn statuses(&self) -> Vec<PublishStatus> {
+        self.statuses
+            .iter()
+            .map(|status| status.lock().clone())
+            .collect()
+    }
 }
 
 impl Discovery for PkarrPublisher {
     fn publish(&self, info: &AddrInfo) {
         self.update_addr_info(info);
     }
+
+    fn publish_status(&self) -> Vec<PublishStatus> {
+        self.statuses()
+    }
 }
 
 impl Drop for PkarrPublisher {
     fn drop(&mut self) {
         // this means we're dropping the last reference
-        if let Some(handle) = Arc::get_mut(&mut self.join_handle) {
-            handle.abort();
+        if let Some(handles) = Arc::get_mut(&mut self.join_handles) {
+            for handle in handles {
+                handle.abort();
+            }
         }
     }
 }
@@ -229,20 +394,21 @@ struct PublisherService {
     pkarr_client: PkarrRelayClient,
     watcher: Watcher<Option<NodeInfo>>,
     ttl: u32,
-    republish_interval: Duration,
+    policy: RepublishPolicy,
+    status: Arc<Mutex<PublishStatus>>,
 }
 
 impl PublisherService {
     async fn run(self) {
-        let mut failed_attempts = 0;
         let republish = tokio::time::sleep(Duration::MAX);
         tokio::pin!(republish);
         loop {
             if let Some(info) = self.watcher.get() {
-                if let Err(err) = self.publish_current(info).await {
-                    failed_attempts += 1;
+                let res = self.publish_current(info).await;
+                let failed_attempts = self.update_status(&res);
+                if let Err(err) = res {
                     // Retry after increasing timeout
-                    let retry_after = Duration::from_secs(failed_attempts);
+                    let retry_after = self.policy.retry_after(failed_attempts);
                     republish.as_mut().reset(Instant::now() + retry_after);
                     warn!(
                         err = %format!("{err:#}"),
@@ -252,11 +418,10 @@ impl PublisherService {
                         "Failed to publish to pkarr",
                     );
                 } else {
-                    failed_attempts = 0;
-                    // Republish after fixed interval
+                    // Republish after the interval, with jitter
                     republish
                         .as_mut()
-                        .reset(Instant::now() + self.republish_interval);
+                        .reset(Instant::now() + self.policy.republish_after());
                 }
             }
             // Wait until either the retry/republish timeout is reached, or the node info changed.
@@ -270,6 +435,23 @@ impl PublisherService {
         }
     }
 
+    /// Records the result of a publish in the status, and returns the failed publishes in a row.
+    fn update_status(&self, res: &Result<()>) -> u32 {
+        let mut status = self.status.lock();
+        match res {
+            Ok(()) => {
+                status.last_published = Some(SystemTime::now());
+                status.last_error = None;
+                status.failed_attempts = 0;
+            }
+            Err(err) => {
+                status.last_error = Some(format!("{err:#}"));
+                status.failed_attempts += 1;
+            }
+        }
+        status.failed_attempts
+    }
+
     async fn publish_current(&self, info: NodeInfo) -> Result<()> {
         info!(
             relay_url = ?info
@@ -344,6 +526,7 @@ impl Discovery for PkarrResolver {
                 node_id,
                 provenance: "pkarr",
                 last_updated: None,
+                user_data: info.user_data.clone(),
                 addr_info: info.into(),
             };
             Ok(item)
@@ -291,6 +291,7 @@ impl DhtDiscovery {
             Ok(Some(signed_packet)) => {
                 if let Ok(node_info) = NodeInfo::from_pkarr_signed_packet(&signed_packet) {
                     let node_id = node_info.node_id;
+                    let user_data = node_info.user_data.clone();
                     let addr_info = node_info.into();
                     tracing::info!("discovered node info from relay {:?}", addr_info);
                     co.yield_(Ok(DiscoveryItem {
@@ -298,6 +299,7 @@ impl DhtDiscovery {
                         provenance: "relay",
                         last_updated: None,
                         addr_info,
+                        user_data,
                     }))
                     .await;
                 } else {
@@ -337,6 +339,7 @@ impl DhtDiscovery {
         };
         if let Ok(node_info) = NodeInfo::from_pkarr_signed_packet(&signed_packet) {
             let node_id = node_info.node_id;
+            let user_data = node_info.user_data.clone();
             let addr_info = node_info.into();
             tracing::info!("discovered node info from DHT {:?}", addr_info);
             co.yield_(Ok(DiscoveryItem {
@@ -344,6 +347,7 @@ impl DhtDiscovery {
                 provenance: "mainline",
                 last_updated: None,
                 addr_info,
+                user_data,
             }))
             .await;
         } else {
@@ -376,6 +380,7 @@ impl Discovery for DhtDiscovery {
             } else {
                 Default::default()
             },
+            user_data: None,
         };
         let Ok(signed_packet) = info.to_pkarr_signed_packet(keypair, self.0.ttl) else {
             tracing::warn!("failed to create signed packet");
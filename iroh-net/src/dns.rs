@@ -6,11 +6,12 @@
 //! iroh node records are structured.
 
 use std::fmt::Write;
-use std::net::{IpAddr, Ipv6Addr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::time::Duration;
 
 use anyhow::Result;
 use futures_lite::{Future, StreamExt};
+use hickory_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
 use hickory_resolver::{AsyncResolver, IntoName, TokioAsyncResolver};
 use iroh_base::key::NodeId;
 use iroh_base::node_addr::NodeAddr;
@@ -37,6 +38,59 @@ pub fn resolver() -> &'static TokioAsyncResolver {
     Lazy::force(&DNS_RESOLVER)
 }
 
+/// The transport of the queries of a resolver created with [`create_resolver`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsTransport {
+    /// Plain DNS over UDP, falling back to TCP for large answers
+    Udp,
+    /// DNS over TLS (DoT), usually on port 853
+    #[cfg(feature = "dns-over-tls")]
+    #[cfg_attr(iroh_docsrs, doc(cfg(feature = "dns-over-tls")))]
+    Tls {
+        /// The name in the TLS certificate of the nameservers
+        server_name: String,
+    },
+    /// DNS over HTTPS (DoH) at `/dns-query`, usually on port 443
+    #[cfg(feature = "dns-over-https")]
+    #[cfg_attr(iroh_docsrs, doc(cfg(feature = "dns-over-https")))]
+    Https {
+        /// The name in the TLS certificate of the nameservers
+        server_name: String,
+    },
+}
+
+/// Create a resolver that only asks the nameservers at `addrs`, over `transport`.
+///
+/// This allows to look up node records where plain DNS is blocked or rewritten, e.g. with
+/// [`crate::discovery::dns::DnsDiscovery::with_resolvers`] and [`DnsTransport::Https`]. The
+/// certificates of TLS and HTTPS nameservers are verified against the webpki roots.
+pub fn create_resolver(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    transport: DnsTransport,
+) -> DnsResolver {
+    let mut config = ResolverConfig::new();
+    for addr in addrs {
+        let nameserver = match &transport {
+            DnsTransport::Udp => {
+                config.add_name_server(NameServerConfig::new(addr, Protocol::Udp));
+                NameServerConfig::new(addr, Protocol::Tcp)
+            }
+            #[cfg(feature = "dns-over-tls")]
+            DnsTransport::Tls { server_name } => NameServerConfig {
+                tls_dns_name: Some(server_name.clone()),
+                ..NameServerConfig::new(addr, Protocol::Tls)
+            },
+            #[cfg(feature = "dns-over-https")]
+            DnsTransport::Https { server_name } => NameServerConfig {
+                tls_dns_name: Some(server_name.clone()),
+                ..NameServerConfig::new(addr, Protocol::Https)
+            },
+        };
+        config.add_name_server(nameserver);
+    }
+    AsyncResolver::tokio(config, Default::default())
+}
+
 /// Deprecated IPv6 site-local anycast addresses still configured by windows.
 ///
 /// Windows still configures these site-local addresses as soon even as an IPv6 loopback
@@ -343,7 +397,7 @@ impl<A: Iterator<Item = IpAddr>, B: Iterator<Item = IpAddr>> Iterator for Lookup
 ///
 /// The first call is performed immediately. The first call to succeed generates an Ok result
 /// ignoring any previous error. If all calls fail, an error summarizing all errors is returned.
-async fn stagger_call<T, F: Fn() -> Fut, Fut: Future<Output = Result<T>>>(
+pub(crate) async fn stagger_call<T, F: Fn() -> Fut, Fut: Future<Output = Result<T>>>(
     f: F,
     delays_ms: &[u64],
 ) -> Result<T> {
@@ -25,6 +25,8 @@
 //! - `addr=<addr> <addr>`: A space-separated list of sockets addresses for this iroh node.
 //!   Each address is an IPv4 or IPv6 address with a port.
 //!
+//! - `user-data=<data>`: Opaque [`UserData`] of the application, e.g. a protocol version.
+//!
 //! [Pkarr]: https://app.pkarr.org
 //! [z-base-32]: https://philzimmermann.com/docs/human-oriented-base-32-encoding.txt
 //! [RFC1464]: https://www.rfc-editor.org/rfc/rfc1464
@@ -38,6 +40,7 @@ use std::{
     hash::Hash,
     net::SocketAddr,
     str::FromStr,
+    time::Instant,
 };
 
 use anyhow::{anyhow, ensure, Result};
@@ -62,6 +65,46 @@ pub enum IrohAttr {
     Relay,
     /// Direct address.
     Addr,
+    /// User-defined data.
+    UserData,
+}
+
+/// Maximum length of [`UserData`] in bytes.
+///
+/// This fits the attribute into a single TXT character string of 255 bytes.
+pub const MAX_USER_DATA_LENGTH: usize = 245;
+
+/// A small opaque string that applications publish with the node info
+///
+/// This is useful for hints like the protocol versions of the node, and is public like the
+/// rest of the node info.  It is at most [`MAX_USER_DATA_LENGTH`] bytes long.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, derive_more::Display)]
+pub struct UserData(String);
+
+impl TryFrom<String> for UserData {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        ensure!(
+            value.len() <= MAX_USER_DATA_LENGTH,
+            "user data is longer than {MAX_USER_DATA_LENGTH} bytes"
+        );
+        Ok(Self(value))
+    }
+}
+
+impl FromStr for UserData {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::try_from(s.to_string())
+    }
+}
+
+impl AsRef<str> for UserData {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
 }
 
 /// Encodes a [`NodeId`] in [`z-base-32`] encoding.
@@ -91,6 +134,8 @@ pub struct NodeInfo {
     pub relay_url: Option<Url>,
     /// Any direct addresses.
     pub direct_addresses: BTreeSet<SocketAddr>,
+    /// The user-defined data, if any.
+    pub user_data: Option<UserData>,
 }
 
 impl From<TxtAttrs<IrohAttr>> for NodeInfo {
@@ -115,10 +160,17 @@ impl From<&TxtAttrs<IrohAttr>> for NodeInfo {
             .flatten()
             .filter_map(|s| SocketAddr::from_str(s).ok())
             .collect();
+        let user_data = attrs
+            .get(&IrohAttr::UserData)
+            .into_iter()
+            .flatten()
+            .next()
+            .and_then(|s| UserData::from_str(s).ok());
         Self {
             node_id,
             relay_url,
             direct_addresses,
+            user_data,
         }
     }
 }
@@ -132,6 +184,9 @@ impl From<&NodeInfo> for TxtAttrs<IrohAttr> {
         for addr in &info.direct_addresses {
             attrs.push((IrohAttr::Addr, addr.to_string()));
         }
+        if let Some(user_data) = &info.user_data {
+            attrs.push((IrohAttr::UserData, user_data.to_string()));
+        }
         Self::from_parts(info.node_id, attrs.into_iter())
     }
 }
@@ -165,9 +220,16 @@ impl NodeInfo {
             node_id,
             relay_url,
             direct_addresses,
+            user_data: None,
         }
     }
 
+    /// Sets the user-defined data.
+    pub fn with_user_data(mut self, user_data: Option<UserData>) -> Self {
+        self.user_data = user_data;
+        self
+    }
+
     fn to_attrs(&self) -> TxtAttrs<IrohAttr> {
         self.into()
     }
@@ -251,8 +313,8 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     pub fn from_strings(node_id: NodeId, strings: impl Iterator<Item = String>) -> Result<Self> {
         let mut attrs: BTreeMap<T, Vec<String>> = BTreeMap::new();
         for s in strings {
-            let mut parts = s.split('=');
-            let (Some(key), Some(value)) = (parts.next(), parts.next()) else {
+            // the value may contain `=`, e.g. in user data
+            let Some((key, value)) = s.split_once('=') else {
                 continue;
             };
             let Ok(attr) = T::from_str(key) else {
@@ -263,11 +325,11 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
         Ok(Self { attrs, node_id })
     }
 
-    async fn lookup(resolver: &TokioAsyncResolver, name: Name) -> Result<Self> {
+    async fn lookup(resolver: &TokioAsyncResolver, name: Name) -> Result<(Self, Instant)> {
         let name = ensure_iroh_txt_label(name)?;
         let lookup = resolver.txt_lookup(name).await?;
         let attrs = Self::from_hickory_records(lookup.as_lookup().records())?;
-        Ok(attrs)
+        Ok((attrs, lookup.valid_until()))
     }
 
     /// Looks up attributes by [`NodeId`] and origin domain.
@@ -276,6 +338,19 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
         node_id: &NodeId,
         origin: &str,
     ) -> Result<Self> {
+        let (attrs, _valid_until) =
+            Self::lookup_by_id_with_expiry(resolver, node_id, origin).await?;
+        Ok(attrs)
+    }
+
+    /// Looks up attributes by [`NodeId`] and origin domain, and until when they are valid.
+    ///
+    /// They are valid until the TTL of the records expires.
+    pub(crate) async fn lookup_by_id_with_expiry(
+        resolver: &TokioAsyncResolver,
+        node_id: &NodeId,
+        origin: &str,
+    ) -> Result<(Self, Instant)> {
         let name = node_domain(node_id, origin)?;
         TxtAttrs::lookup(resolver, name).await
     }
@@ -283,7 +358,8 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     /// Looks up attributes by DNS name.
     pub async fn lookup_by_name(resolver: &TokioAsyncResolver, name: &str) -> Result<Self> {
         let name = Name::from_str(name)?;
-        TxtAttrs::lookup(resolver, name).await
+        let (attrs, _valid_until) = TxtAttrs::lookup(resolver, name).await?;
+        Ok(attrs)
     }
 
     /// Returns the parsed attributes.
@@ -319,21 +395,24 @@ impl<T: FromStr + Display + Hash + Ord> TxtAttrs<T> {
     /// Parses a set of DNS resource records.
     pub fn from_hickory_records(records: &[hickory_proto::rr::Record]) -> Result<Self> {
         use hickory_proto::rr;
-        let mut records = records.iter().filter_map(|rr| match rr.data() {
-            rr::RData::TXT(txt) => {
-                node_id_from_hickory_name(rr.name()).map(|node_id| (node_id, txt))
-            }
-            _ => None,
-        });
-        let (node_id, first) = records.next().ok_or_else(|| {
+        let records: Vec<_> = records
+            .iter()
+            .filter_map(|rr| match rr.data() {
+                rr::RData::TXT(txt) => {
+                    node_id_from_hickory_name(rr.name()).map(|node_id| (node_id, txt))
+                }
+                _ => None,
+            })
+            .collect();
+        let (node_id, _) = records.first().ok_or_else(|| {
             anyhow!("invalid DNS answer: no TXT record with name _iroh.z32encodedpubkey found")
         })?;
+        let node_id = *node_id;
         ensure!(
-            &records.all(|(n, _)| n == node_id),
+            records.iter().all(|(n, _)| *n == node_id),
             "invalid DNS answer: all _iroh txt records must belong to the same node domain"
         );
-        let records = records.map(|(_, txt)| txt).chain(Some(first));
-        let strings = records.map(ToString::to_string);
+        let strings = records.into_iter().map(|(_, txt)| txt.to_string());
         Self::from_strings(node_id, strings)
     }
 
@@ -402,6 +481,12 @@ fn ensure_iroh_txt_label(name: Name) -> Result<Name, ProtoError> {
     }
 }
 
+/// Get the name of the [`IROH_TXT_NAME`] records of `node_id` under `origin`.
+pub(crate) fn node_txt_name(node_id: &NodeId, origin: &str) -> Result<Name> {
+    let name = ensure_iroh_txt_label(node_domain(node_id, origin)?)?;
+    Ok(name)
+}
+
 fn node_domain(node_id: &NodeId, origin: &str) -> Result<Name> {
     let domain = format!("{}.{}", to_z32(node_id), origin);
     let domain = Name::from_str(&domain)?;
@@ -423,6 +508,7 @@ mod tests {
                 .unwrap(),
             relay_url: Some("https://example.com".parse().unwrap()),
             direct_addresses: ["127.0.0.1:1234".parse().unwrap()].into_iter().collect(),
+            user_data: Some("foobar".parse().unwrap()),
         };
         let attrs = expected.to_attrs();
         let actual = NodeInfo::from(&attrs);
@@ -437,6 +523,7 @@ mod tests {
             node_id: secret_key.public(),
             relay_url: Some("https://example.com".parse().unwrap()),
             direct_addresses: ["127.0.0.1:1234".parse().unwrap()].into_iter().collect(),
+            user_data: Some("foobar".parse().unwrap()),
         };
         let packet = expected.to_pkarr_signed_packet(&secret_key, 30).unwrap();
         let actual = NodeInfo::from_pkarr_signed_packet(&packet).unwrap();
@@ -19,15 +19,21 @@ use std::sync::Arc;
 use std::task::Poll;
 use std::time::Duration;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::Bytes;
 use derive_more::Debug;
 use futures_lite::{Stream, StreamExt};
 use pin_project::pin_project;
-use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use tokio_util::{
+    sync::{CancellationToken, WaitForCancellationFuture},
+    task::AbortOnDropHandle,
+};
 use tracing::{debug, instrument, trace, warn};
 use url::Url;
 
-use crate::discovery::{Discovery, DiscoveryTask};
+use crate::discovery::{
+    self, Discovery, DiscoveryEvent, DiscoveryItem, DiscoveryTask, PublishStatus,
+};
 use crate::dns::{default_resolver, DnsResolver};
 use crate::key::{PublicKey, SecretKey};
 use crate::magicsock::{self, Handle, QuicMappedAddr};
@@ -57,6 +63,9 @@ pub use iroh_base::node_addr::{AddrInfo, NodeAddr};
 /// is still no connection the configured [`Discovery`] will be used however.
 const DISCOVERY_WAIT_PERIOD: Duration = Duration::from_millis(500);
 
+/// The maximum number of nodes that [`Endpoint::resolve_many`] resolves at the same time.
+pub const BATCH_RESOLVE_CONCURRENCY: usize = 64;
+
 /// Environment variable to force the use of staging relays.
 #[cfg(not(any(test, feature = "test-utils")))]
 #[cfg_attr(iroh_docsrs, doc(cfg(not(any(test, feature = "test-utils")))))]
@@ -807,6 +816,110 @@ impl Endpoint {
         self.msock.discovery()
     }
 
+    /// Returns the status of the publishing of the addressing information of this node.
+    ///
+    /// Returns one [`PublishStatus`] per target of the discovery services, e.g. per pkarr
+    /// relay of a [`PkarrPublisher`], with when the node was last published and the error of the
+    /// last failed publish.  Empty if no discovery service publishes.
+    ///
+    /// [`PkarrPublisher`]: crate::discovery::pkarr::PkarrPublisher
+    pub fn publish_status(&self) -> Vec<PublishStatus> {
+        self.discovery()
+            .map(|discovery| discovery.publish_status())
+            .unwrap_or_default()
+    }
+
+    /// Returns a stream of the nodes found by discovery.
+    ///
+    /// Yields a [`DiscoveryEvent`] when a node is found for the first time since the stream was
+    /// created, or when the addressing information of a node changed.  This includes the nodes
+    /// resolved when connecting and the nodes discovered passively, e.g. on the local network.
+    /// Nodes found while the stream is not polled may be missed.
+    pub fn discovery_events(&self) -> impl Stream<Item = DiscoveryEvent> {
+        discovery::discovery_events(self.msock.subscribe_discovery(), None, None)
+    }
+
+    /// Watches the addressing information of `node_id` with discovery.
+    ///
+    /// Resolves the node every `interval` and returns its events from
+    /// [`Self::discovery_events`], so that long-lived applications can react when the relay or
+    /// the direct addresses of a peer change.  The found addresses are also added to the
+    /// endpoint.  Resolving stops when the stream is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Will error if no discovery service is configured.
+    pub fn watch_discovery(
+        &self,
+        node_id: NodeId,
+        interval: Duration,
+    ) -> Result<impl Stream<Item = DiscoveryEvent>> {
+        ensure!(
+            self.discovery().is_some(),
+            "No discovery services configured"
+        );
+        let found = self.msock.subscribe_discovery();
+        let ep = self.clone();
+        let resolve = tokio::task::spawn(async move {
+            loop {
+                // dropping the task stops the lookup
+                let _task = DiscoveryTask::start(ep.clone(), node_id);
+                tokio::time::sleep(interval).await;
+            }
+        });
+        let guard = AbortOnDropHandle::new(resolve);
+        Ok(discovery::discovery_events(
+            found,
+            Some(node_id),
+            Some(guard),
+        ))
+    }
+
+    /// Resolves many nodes with discovery at once, e.g. to fill the address book at startup.
+    ///
+    /// Up to [`BATCH_RESOLVE_CONCURRENCY`] nodes are resolved at the same time, so that e.g. the
+    /// DNS queries of [`DnsDiscovery`] are sent without waiting for the answers to the previous
+    /// ones.  Each node is resolved with all discovery services until they are done, and the
+    /// found addresses are added to the endpoint, as when connecting.  Yields each node with the
+    /// addresses found by all services, or with the error if none were found, in the order the
+    /// lookups finish.
+    ///
+    /// # Errors
+    ///
+    /// Will error if no discovery service is configured.
+    ///
+    /// [`DnsDiscovery`]: crate::discovery::dns::DnsDiscovery
+    pub fn resolve_many(
+        &self,
+        node_ids: impl IntoIterator<Item = NodeId>,
+    ) -> Result<impl Stream<Item = (NodeId, Result<AddrInfo>)>> {
+        ensure!(
+            self.discovery().is_some(),
+            "No discovery services configured"
+        );
+        let ep = self.clone();
+        let lookups = node_ids.into_iter().map(move |node_id| {
+            let ep = ep.clone();
+            async move { (node_id, DiscoveryTask::resolve_all(ep, node_id).await) }
+        });
+        Ok(futures_util::StreamExt::buffer_unordered(
+            futures_lite::stream::iter(lookups),
+            BATCH_RESOLVE_CONCURRENCY,
+        ))
+    }
+
+    /// Looks up the DNS `query` message with the nameserver of the home relay server.
+    ///
+    /// See [`MagicSock::relay_dns_query`].
+    pub(crate) async fn relay_dns_query(&self, query: Bytes) -> Result<Bytes> {
+        self.msock.relay_dns_query(query).await
+    }
+
+    /// Sends a node found by discovery to the subscribers of [`Self::discovery_events`].
+    pub(crate) fn discovered(&self, item: DiscoveryItem) {
+        self.msock.discovered(item);
+    }
+
     // # Methods for less common state updates.
 
     /// Notifies the system of potential network changes.
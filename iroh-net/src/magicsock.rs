@@ -92,6 +92,9 @@ const ENDPOINTS_FRESH_ENOUGH_DURATION: Duration = Duration::from_secs(27);
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
+/// Number of nodes found by discovery that are buffered for each subscriber.
+const DISCOVERY_EVENTS_CAPACITY: usize = 64;
+
 /// Contains options for `MagicSock::listen`.
 #[derive(derive_more::Debug)]
 pub(crate) struct Options {
@@ -229,6 +232,8 @@ pub(crate) struct MagicSock {
 
     /// Optional discovery service
     discovery: Option<Box<dyn Discovery>>,
+    /// The nodes found by discovery, for [`crate::Endpoint::discovery_events`]
+    discovery_events: sync::broadcast::Sender<DiscoveryItem>,
 
     /// Our discovered direct addresses.
     direct_addrs: Watchable<DiscoveredDirectAddrs>,
@@ -414,6 +419,36 @@ impl MagicSock {
         self.discovery.as_ref().map(Box::as_ref)
     }
 
+    /// Looks up the DNS `query` message with the nameserver of the home relay server, and
+    /// returns the response message.
+    ///
+    /// Fails if there is no home relay, or if it doesn't forward DNS queries. Relay servers drop
+    /// the queries they can't forward, so callers should use a timeout.
+    pub(crate) async fn relay_dns_query(&self, query: Bytes) -> Result<Bytes> {
+        let url = self.my_relay().context("no home relay")?;
+        let (response, response_rx) = sync::oneshot::channel();
+        self.relay_actor_sender
+            .send(RelayActorMessage::DnsQuery {
+                url,
+                query,
+                response,
+            })
+            .await
+            .map_err(|_| anyhow!("relay actor gone"))?;
+        response_rx.await.context("relay actor gone")?
+    }
+
+    /// Sends a node found by discovery to the subscribers of [`Self::subscribe_discovery`].
+    pub(crate) fn discovered(&self, item: DiscoveryItem) {
+        // there are no receivers if nothing subscribed
+        self.discovery_events.send(item).ok();
+    }
+
+    /// Subscribes to the nodes found by discovery from now on.
+    pub(crate) fn subscribe_discovery(&self) -> sync::broadcast::Receiver<DiscoveryItem> {
+        self.discovery_events.subscribe()
+    }
+
     /// Call to notify the system of potential network changes.
     pub(crate) async fn network_change(&self) {
         self.actor_sender
@@ -1449,6 +1484,7 @@ impl Handle {
             relay_actor_sender: relay_actor_sender.clone(),
             udp_disco_sender,
             discovery,
+            discovery_events: sync::broadcast::channel(DISCOVERY_EVENTS_CAPACITY).0,
             direct_addrs: Watchable::new(Default::default()),
             pending_call_me_maybes: Default::default(),
             direct_addr_update_state: DirectAddrUpdateState::new(),
@@ -1895,6 +1931,7 @@ impl Actor {
                 }
                 Some(discovery_item) = discovery_events.next() => {
                     trace!("tick: discovery event, address discovered: {discovery_item:?}");
+                    self.msock.discovered(discovery_item.clone());
                     let node_addr = NodeAddr {node_id: discovery_item.node_id, info: discovery_item.addr_info};
                     if let Err(e) = self.msock.add_node_addr(node_addr.clone(), Source::Discovery { name: discovery_item.provenance.into() }) {
                         warn!(?node_addr, "unable to add discovered node address to the node map: {e:?}");
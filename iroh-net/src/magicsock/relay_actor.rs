@@ -42,6 +42,11 @@ pub(super) enum RelayActorMessage {
     SetHome {
         url: RelayUrl,
     },
+    DnsQuery {
+        url: RelayUrl,
+        query: Bytes,
+        response: oneshot::Sender<anyhow::Result<Bytes>>,
+    },
 }
 
 /// Contains fields for an active relay connection.
@@ -351,6 +356,13 @@ impl RelayActor {
             RelayActorMessage::MaybeCloseRelaysOnRebind(ifs) => {
                 self.maybe_close_relays_on_rebind(&ifs).await;
             }
+            RelayActorMessage::DnsQuery {
+                url,
+                query,
+                response,
+            } => {
+                self.dns_query(&url, query, response).await;
+            }
         }
     }
 
@@ -364,6 +376,26 @@ impl RelayActor {
         .await;
     }
 
+    /// Looks up the DNS `query` with the nameserver of the relay server at `url`.
+    ///
+    /// The response is awaited in a task, which stops when the caller stops waiting for it.
+    async fn dns_query(
+        &mut self,
+        url: &RelayUrl,
+        query: Bytes,
+        mut response: oneshot::Sender<anyhow::Result<Bytes>>,
+    ) {
+        trace!(%url, len = query.len(), "dns query over relay");
+        let relay_client = self.connect_relay(url, None).await;
+        tokio::task::spawn(async move {
+            let res = tokio::select! {
+                res = relay_client.dns_query(query) => res,
+                _ = response.closed() => return,
+            };
+            response.send(res.map_err(Into::into)).ok();
+        });
+    }
+
     async fn send_relay(&mut self, url: &RelayUrl, contents: RelayContents, peer: PublicKey) {
         trace!(%url, peer = %peer.fmt_short(),len = contents.iter().map(|c| c.len()).sum::<usize>(),  "sending over relay");
         // Relay Send
@@ -6,7 +6,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures_lite::future::Boxed as BoxFuture;
 use futures_util::StreamExt;
 use http_body_util::Empty;
@@ -37,13 +37,16 @@ use crate::defaults::timeouts::relay::*;
 use crate::dns::{DnsResolver, ResolverExt};
 use crate::key::{PublicKey, SecretKey};
 use crate::relay::codec::DerpCodec;
-use crate::relay::http::{Protocol, RELAY_PATH};
+use crate::relay::http::{Protocol, DNS_FORWARD_HEADER, RELAY_PATH};
 use crate::relay::RelayUrl;
 use crate::util::chain;
 
 pub(crate) mod conn;
 pub(crate) mod streams;
 
+/// Maximum number of DNS queries waiting for their response.
+const MAX_DNS_QUERIES: usize = 256;
+
 /// Possible connection errors on the [`Client`]
 #[derive(Debug, thiserror::Error)]
 pub enum ClientError {
@@ -110,6 +113,15 @@ pub enum ClientError {
     /// This [`Client`] cannot acknowledge pings
     #[error("cannot acknowledge pings")]
     CannotAckPings,
+    /// The DNS query is not a valid DNS message
+    #[error("invalid dns query")]
+    InvalidDnsQuery,
+    /// The relay server does not forward DNS queries
+    #[error("dns queries not forwarded by the relay server")]
+    DnsForwardUnsupported,
+    /// Too many DNS queries are waiting for their response
+    #[error("too many dns queries")]
+    TooManyDnsQueries,
     /// The given [`Url`] is invalid
     #[error("invalid url: {0}")]
     InvalidUrl(String),
@@ -146,6 +158,7 @@ enum ActorMessage {
     Ping(oneshot::Sender<Result<Duration, ClientError>>),
     Pong([u8; 8], oneshot::Sender<Result<(), ClientError>>),
     Send(PublicKey, Bytes, oneshot::Sender<Result<(), ClientError>>),
+    DnsQuery(Bytes, oneshot::Sender<Result<Bytes, ClientError>>),
     Close(oneshot::Sender<Result<(), ClientError>>),
     CloseForReconnect(oneshot::Sender<Result<(), ClientError>>),
     IsConnected(oneshot::Sender<Result<bool, ClientError>>),
@@ -173,6 +186,11 @@ struct Actor {
     tls_connector: tokio_rustls::TlsConnector,
     pings: PingTracker,
     ping_tasks: JoinSet<()>,
+    /// Whether the server of the current connection forwards DNS queries
+    server_forwards_dns: bool,
+    /// The DNS queries waiting for their response, by the id they were sent with, with the id
+    /// of the query
+    dns_queries: HashMap<u16, (u16, oneshot::Sender<Result<Bytes, ClientError>>)>,
     dns_resolver: DnsResolver,
     proxy_url: Option<Url>,
 }
@@ -340,6 +358,8 @@ impl ClientBuilder {
             conn_gen: 0,
             pings: PingTracker::default(),
             ping_tasks: Default::default(),
+            server_forwards_dns: false,
+            dns_queries: Default::default(),
             url: self.url,
             protocol: self.protocol,
             tls_connector,
@@ -455,6 +475,19 @@ impl Client {
         self.send_actor(|s| ActorMessage::Send(dst_key, b, s)).await
     }
 
+    /// Look up a DNS query message with the nameserver of the server, and return the response
+    /// message.
+    ///
+    /// This allows lookups on networks where the nameservers can't be reached, but the relay
+    /// server can. If there is no underlying active relay connection, it creates one before
+    /// sending the query. Fails with [`ClientError::DnsForwardUnsupported`] if the server did not
+    /// advertise that it forwards DNS queries when connecting, which is always the case over
+    /// websockets. Only returns once the response arrives: a server drops the queries it can't
+    /// forward, e.g. when its nameserver doesn't answer, so callers should use a timeout.
+    pub async fn dns_query(&self, query: Bytes) -> Result<Bytes, ClientError> {
+        self.send_actor(|s| ActorMessage::DnsQuery(query, s)).await
+    }
+
     /// Close the http relay connection.
     pub async fn close(self) -> Result<(), ClientError> {
         self.send_actor(ActorMessage::Close).await
@@ -498,6 +531,18 @@ impl Actor {
                         }
                         continue;
                     }
+                    if let Ok((ReceivedMessage::DnsResponse(response), _)) = &res {
+                        let query = dns_message_id(response).and_then(|id| self.dns_queries.remove(&id));
+                        match query {
+                            Some((id, chan)) => {
+                                chan.send(Ok(with_dns_message_id(response, id))).ok();
+                            }
+                            None => {
+                                warn!("dns response received, but no query registered");
+                            }
+                        }
+                        continue;
+                    }
                     msg_sender.send(res).await.ok();
                 }
                 Some(msg) = inbox.recv() => {
@@ -524,6 +569,9 @@ impl Actor {
                             let res = self.send(key, data).await;
                             s.send(res).ok();
                         },
+                        ActorMessage::DnsQuery(query, s) => {
+                            self.dns_query(query, s).await;
+                        },
                         ActorMessage::Close(s) => {
                             let res = self.close().await;
                             s.send(Ok(res)).ok();
@@ -565,12 +613,13 @@ impl Actor {
         async move {
             if self.relay_client.is_none() {
                 trace!("no connection, trying to connect");
-                let (relay_client, receiver) =
+                let (relay_client, receiver, forwards_dns) =
                     tokio::time::timeout(CONNECT_TIMEOUT, self.connect_0())
                         .await
                         .map_err(|_| ClientError::ConnectTimeout)??;
 
                 self.relay_client = Some((relay_client.clone(), receiver));
+                self.server_forwards_dns = forwards_dns;
                 self.next_conn();
             } else {
                 trace!("already had connection");
@@ -588,16 +637,18 @@ impl Actor {
         .await
     }
 
-    async fn connect_0(&self) -> Result<(RelayClient, RelayClientReceiver), ClientError> {
-        let (reader, writer, local_addr) = match self.protocol {
+    /// Connects to the server, and returns whether it forwards DNS queries.
+    async fn connect_0(&self) -> Result<(RelayClient, RelayClientReceiver, bool), ClientError> {
+        let (reader, writer, local_addr, forwards_dns) = match self.protocol {
             Protocol::Websocket => {
                 let (reader, writer) = self.connect_ws().await?;
                 let local_addr = None;
-                (reader, writer, local_addr)
+                // the websocket handshake doesn't expose the response headers
+                (reader, writer, local_addr, false)
             }
             Protocol::Relay => {
-                let (reader, writer, local_addr) = self.connect_derp().await?;
-                (reader, writer, Some(local_addr))
+                let (reader, writer, local_addr, forwards_dns) = self.connect_derp().await?;
+                (reader, writer, Some(local_addr), forwards_dns)
             }
         };
 
@@ -620,7 +671,7 @@ impl Actor {
         );
 
         trace!("connect_0 done");
-        Ok((relay_client, receiver))
+        Ok((relay_client, receiver, forwards_dns))
     }
 
     async fn connect_ws(&self) -> Result<(ConnReader, ConnWriter), ClientError> {
@@ -642,7 +693,9 @@ impl Actor {
         Ok((reader, writer))
     }
 
-    async fn connect_derp(&self) -> Result<(ConnReader, ConnWriter, SocketAddr), ClientError> {
+    async fn connect_derp(
+        &self,
+    ) -> Result<(ConnReader, ConnWriter, SocketAddr, bool), ClientError> {
         let tcp_stream = self.dial_url().await?;
 
         let local_addr = tcp_stream
@@ -676,6 +729,8 @@ impl Actor {
             ));
         }
 
+        let forwards_dns = response.headers().contains_key(DNS_FORWARD_HEADER);
+
         debug!("starting upgrade");
         let upgraded = match hyper::upgrade::on(response).await {
             Ok(upgraded) => upgraded,
@@ -692,7 +747,7 @@ impl Actor {
         let reader = ConnReader::Derp(FramedRead::new(reader, DerpCodec));
         let writer = ConnWriter::Derp(FramedWrite::new(writer, DerpCodec));
 
-        Ok((reader, writer, local_addr))
+        Ok((reader, writer, local_addr, forwards_dns))
     }
 
     /// Sends the HTTP upgrade request to the relay server.
@@ -792,6 +847,50 @@ impl Actor {
         Ok(())
     }
 
+    async fn dns_query(&mut self, query: Bytes, s: oneshot::Sender<Result<Bytes, ClientError>>) {
+        let Some(id) = dns_message_id(&query) else {
+            s.send(Err(ClientError::InvalidDnsQuery)).ok();
+            return;
+        };
+        trace!(id, len = query.len(), "dns query");
+        let client = match self.connect("dns query").await {
+            Ok((client, _, _)) => client,
+            Err(err) => {
+                s.send(Err(err)).ok();
+                return;
+            }
+        };
+        // older servers close the connection on the unknown frame
+        if !self.server_forwards_dns {
+            s.send(Err(ClientError::DnsForwardUnsupported)).ok();
+            return;
+        }
+        // forget the queries whose callers stopped waiting, e.g. because the server dropped them
+        self.dns_queries.retain(|_, (_, s)| !s.is_closed());
+        if self.dns_queries.len() >= MAX_DNS_QUERIES {
+            s.send(Err(ClientError::TooManyDnsQueries)).ok();
+            return;
+        }
+        // concurrent queries can have the same id, so each query is sent with an id that no
+        // other pending query has, and the response gets the id of the query back
+        let sent_id = loop {
+            let sent_id = rand::random();
+            if !self.dns_queries.contains_key(&sent_id) {
+                break sent_id;
+            }
+        };
+        if client
+            .send_dns_query(with_dns_message_id(&query, sent_id))
+            .await
+            .is_err()
+        {
+            self.close_for_reconnect().await;
+            s.send(Err(ClientError::Send)).ok();
+            return;
+        }
+        self.dns_queries.insert(sent_id, (id, s));
+    }
+
     async fn send_pong(&mut self, data: [u8; 8]) -> Result<(), ClientError> {
         debug!("send_pong");
         if self.can_ack_pings {
@@ -1104,6 +1203,21 @@ fn url_port(url: &Url) -> Option<u16> {
     }
 }
 
+/// Get the id of a DNS message in wire format, its first two bytes.
+fn dns_message_id(message: &[u8]) -> Option<u16> {
+    let id = message.get(..2)?;
+    Some(u16::from_be_bytes([id[0], id[1]]))
+}
+
+/// Copy the DNS `message` in wire format, with the id set to `id`.
+///
+/// The message must be at least two bytes long, see [`dns_message_id`].
+fn with_dns_message_id(message: &[u8], id: u16) -> Bytes {
+    let mut message = BytesMut::from(message);
+    message[..2].copy_from_slice(&id.to_be_bytes());
+    message.freeze()
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::{bail, Result};
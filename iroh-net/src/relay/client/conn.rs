@@ -113,6 +113,24 @@ impl Conn {
         Ok(())
     }
 
+    /// Sends a DNS query message to the server, to look it up with the nameserver of the
+    /// server.
+    ///
+    /// The server answers with a [`ReceivedMessage::DnsResponse`], if it forwards DNS queries.
+    /// Errors if the query is larger than [`MAX_PACKET_SIZE`]
+    pub async fn send_dns_query(&self, query: Bytes) -> Result<()> {
+        ensure!(
+            query.len() <= MAX_PACKET_SIZE,
+            "dns query too big: {}",
+            query.len()
+        );
+        self.inner
+            .writer_channel
+            .send(ConnWriterMessage::DnsQuery(query))
+            .await?;
+        Ok(())
+    }
+
     /// Sends a packet that tells the server whether this
     /// connection is to the user's preferred server. This is only
     /// used in the server for stats.
@@ -189,6 +207,7 @@ fn process_incoming_frame(frame: Frame) -> Result<ReceivedMessage> {
                 try_for,
             })
         }
+        Frame::DnsResponse { response } => Ok(ReceivedMessage::DnsResponse(response)),
         _ => bail!("unexpected packet: {:?}", frame.typ()),
     }
 }
@@ -204,6 +223,8 @@ enum ConnWriterMessage {
     Ping([u8; 8]),
     /// Tell the server whether or not this client is the user's preferred client
     NotePreferred(bool),
+    /// Send a DNS query to the server
+    DnsQuery(Bytes),
     /// Shutdown the writer
     Shutdown,
 }
@@ -238,6 +259,10 @@ impl ConnWriterTasks {
                     write_frame(&mut self.writer, Frame::NotePreferred { preferred }, None).await?;
                     self.writer.flush().await?;
                 }
+                ConnWriterMessage::DnsQuery(query) => {
+                    write_frame(&mut self.writer, Frame::DnsQuery { query }, None).await?;
+                    self.writer.flush().await?;
+                }
                 ConnWriterMessage::Shutdown => {
                     return Ok(());
                 }
@@ -476,6 +501,9 @@ pub enum ReceivedMessage {
         /// than a few seconds.
         try_for: Duration,
     },
+    /// The response of the nameserver of the server to a DNS query sent with
+    /// [`Conn::send_dns_query`], as DNS message in wire format.
+    DnsResponse(Bytes),
 }
 
 pub(crate) async fn send_packet<S: Sink<Frame, Error = std::io::Error> + Unpin>(
@@ -60,6 +60,8 @@ pub(super) const PROTOCOL_VERSION: usize = 3;
 ///  * client responds to any FrameType::Ping with a FrameType::Pong
 ///  * clients sends FrameType::SendPacket
 ///  * server then sends FrameType::RecvPacket to recipient
+///  * client may send FrameType::DnsQuery, if the server advertised it in the upgrade response,
+///    which the server answers with a FrameType::DnsResponse
 ///
 
 const PREFERRED: u8 = 1u8;
@@ -108,6 +110,18 @@ pub(crate) enum FrameType {
     ///
     /// Handled on the `[relay::Client]`, but currently never sent on the `[relay::Server]`
     Restarting = 15,
+    /// Sent from client to server to look up a name with the nameserver of the server.
+    ///
+    /// The entire frame body is a DNS query message in wire format. Servers that forward DNS
+    /// queries advertise it with the `Iroh-Relay-Dns-Forward` header of the upgrade response,
+    /// and clients only send this frame to them: older servers close the connection on a frame
+    /// type they don't know, so this doesn't need a new protocol version.
+    DnsQuery = 16,
+    /// Sent from server to client with the response of the nameserver to a
+    /// `FrameType::DnsQuery`.
+    ///
+    /// The entire frame body is a DNS response message in wire format, with the id of the query.
+    DnsResponse = 17,
     #[num_enum(default)]
     Unknown = 255,
 }
@@ -239,6 +253,12 @@ pub(crate) enum Frame {
         reconnect_in: u32,
         try_for: u32,
     },
+    DnsQuery {
+        query: Bytes,
+    },
+    DnsResponse {
+        response: Bytes,
+    },
 }
 
 impl Frame {
@@ -254,6 +274,8 @@ impl Frame {
             Frame::Pong { .. } => FrameType::Pong,
             Frame::Health { .. } => FrameType::Health,
             Frame::Restarting { .. } => FrameType::Restarting,
+            Frame::DnsQuery { .. } => FrameType::DnsQuery,
+            Frame::DnsResponse { .. } => FrameType::DnsResponse,
         }
     }
 
@@ -277,6 +299,8 @@ impl Frame {
             Frame::Pong { .. } => 8,
             Frame::Health { problem } => problem.len(),
             Frame::Restarting { .. } => 4 + 4,
+            Frame::DnsQuery { query } => query.len(),
+            Frame::DnsResponse { response } => response.len(),
         }
     }
 
@@ -351,6 +375,12 @@ impl Frame {
                 dst.put_u32(*reconnect_in);
                 dst.put_u32(*try_for);
             }
+            Frame::DnsQuery { query } => {
+                dst.put(query.as_ref());
+            }
+            Frame::DnsResponse { response } => {
+                dst.put(response.as_ref());
+            }
         }
     }
 
@@ -459,6 +489,22 @@ impl Frame {
                     try_for,
                 }
             }
+            FrameType::DnsQuery => {
+                ensure!(
+                    content.len() <= MAX_PACKET_SIZE,
+                    "dns query longer ({}) than max of {MAX_PACKET_SIZE}",
+                    content.len()
+                );
+                Self::DnsQuery { query: content }
+            }
+            FrameType::DnsResponse => {
+                ensure!(
+                    content.len() <= MAX_PACKET_SIZE,
+                    "dns response longer ({}) than max of {MAX_PACKET_SIZE}",
+                    content.len()
+                );
+                Self::DnsResponse { response: content }
+            }
             _ => {
                 anyhow::bail!("invalid frame type: {:?}", frame_type);
             }
@@ -677,6 +723,18 @@ mod tests {
                 },
                 "0f 00 00 00 0a 00 00 00 14",
             ),
+            (
+                Frame::DnsQuery {
+                    query: "query".into(),
+                },
+                "10 71 75 65 72 79",
+            ),
+            (
+                Frame::DnsResponse {
+                    response: "response".into(),
+                },
+                "11 72 65 73 70 6f 6e 73 65",
+            ),
         ];
 
         for (frame, expected_hex) in frames {
@@ -740,6 +798,8 @@ mod proptests {
                 reconnect_in,
                 try_for,
             });
+        let dns_query = data(0).prop_map(|query| Frame::DnsQuery { query });
+        let dns_response = data(0).prop_map(|response| Frame::DnsResponse { response });
         prop_oneof![
             client_info,
             send_packet,
@@ -751,6 +811,8 @@ mod proptests {
             pong,
             health,
             restarting,
+            dns_query,
+            dns_response,
         ]
     }
 
@@ -767,6 +829,8 @@ mod proptests {
                 | FrameType::Health
                 | FrameType::SendPacket
                 | FrameType::RecvPacket
+                | FrameType::DnsQuery
+                | FrameType::DnsResponse
                 | FrameType::Unknown => false,
             }
         }
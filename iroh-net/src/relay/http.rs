@@ -2,6 +2,9 @@
 
 pub(crate) const HTTP_UPGRADE_PROTOCOL: &str = "iroh derp http";
 pub(crate) const WEBSOCKET_UPGRADE_PROTOCOL: &str = "websocket";
+/// The header of the upgrade response with which the relay server advertises that it answers
+/// `FrameType::DnsQuery`.
+pub(crate) const DNS_FORWARD_HEADER: &str = "Iroh-Relay-Dns-Forward";
 #[cfg(feature = "iroh-relay")] // only used in the server for now
 #[cfg_attr(iroh_docsrs, doc(cfg(feature = "iroh-relay")))]
 pub(crate) const SUPPORTED_WEBSOCKET_VERSION: &str = "13";
@@ -42,6 +42,7 @@ pub(crate) mod streams;
 pub(crate) mod types;
 
 pub use self::actor::{ClientConnHandler, ServerActorTask};
+pub use self::client_conn::DnsForward;
 pub use self::metrics::Metrics;
 pub use self::streams::MaybeTlsStream as MaybeTlsStreamServer;
 
@@ -109,6 +110,11 @@ pub struct RelayConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
     pub tls: Option<TlsConfig<EC, EA>>,
     /// Rate limits.
     pub limits: Limits,
+    /// The forwarding of the DNS queries of clients to a nameserver.
+    ///
+    /// Lets clients on networks that block DNS look up node records through their relay
+    /// connection, see [`DnsForward`]. Disabled if `None`.
+    pub dns_forward: Option<DnsForward>,
 }
 
 /// Configuration for the STUN server.
@@ -243,6 +249,7 @@ impl Server {
                 };
                 let mut builder = http_server::ServerBuilder::new(relay_bind_addr)
                     .secret_key(Some(relay_config.secret_key))
+                    .dns_forward(relay_config.dns_forward)
                     .headers(headers)
                     .relay_override(Box::new(relay_disabled_handler))
                     .request_handler(Method::GET, "/", Box::new(root_handler))
@@ -713,19 +720,29 @@ mod tests {
     use http::header::UPGRADE;
     use iroh_base::node_addr::RelayUrl;
 
+    use hickory_proto::{
+        op::{Message, Query},
+        rr::{Name, RecordType},
+    };
+
     use crate::relay::client::conn::ReceivedMessage;
-    use crate::relay::client::ClientBuilder;
+    use crate::relay::client::{ClientBuilder, ClientError};
     use crate::relay::http::{Protocol, HTTP_UPGRADE_PROTOCOL};
 
     use super::*;
 
     async fn spawn_local_relay() -> Result<Server> {
+        spawn_local_relay_with_dns_forward(None).await
+    }
+
+    async fn spawn_local_relay_with_dns_forward(dns_forward: Option<DnsForward>) -> Result<Server> {
         Server::spawn(ServerConfig::<(), ()> {
             relay: Some(RelayConfig {
                 secret_key: SecretKey::generate(),
                 http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
                 tls: None,
                 limits: Default::default(),
+                dns_forward,
             }),
             stun: None,
             metrics_addr: None,
@@ -755,6 +772,7 @@ mod tests {
                 http_bind_addr: (Ipv4Addr::LOCALHOST, 1234).into(),
                 tls: None,
                 limits: Default::default(),
+                dns_forward: None,
             }),
             stun: None,
             metrics_addr: Some((Ipv4Addr::LOCALHOST, 1234).into()),
@@ -884,6 +902,70 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_relay_client_dns_query() -> Result<()> {
+        let _guard = iroh_test::logging::setup();
+        // a nameserver that answers each query with the query itself
+        let nameserver = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let nameserver_addr = nameserver.local_addr()?;
+        let _nameserver_task = AbortOnDropHandle::new(tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = nameserver.recv_from(&mut buf).await {
+                nameserver.send_to(&buf[..len], from).await.ok();
+            }
+        }));
+        let mut query = Message::new();
+        query.set_id(42).add_query(Query::query(
+            Name::from_ascii("_iroh.node.dns.iroh.link.")?,
+            RecordType::TXT,
+        ));
+        let query = Bytes::from(query.to_vec()?);
+
+        // the query is not sent to a server that doesn't advertise forwarding
+        let server = spawn_local_relay().await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        let resolver = crate::dns::default_resolver().clone();
+        let (client, _receiver) =
+            ClientBuilder::new(relay_url).build(SecretKey::generate(), resolver);
+        let res = client.dns_query(query.clone()).await;
+        assert!(
+            matches!(res, Err(ClientError::DnsForwardUnsupported)),
+            "{res:?}"
+        );
+        assert!(client.is_connected().await?);
+
+        let server = spawn_local_relay_with_dns_forward(Some(DnsForward {
+            nameserver: nameserver_addr,
+            origins: vec![Name::from_ascii("dns.iroh.link.")?],
+        }))
+        .await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap()).parse()?;
+        let resolver = crate::dns::default_resolver().clone();
+        let (client, _receiver) =
+            ClientBuilder::new(relay_url).build(SecretKey::generate(), resolver);
+        let response =
+            tokio::time::timeout(Duration::from_secs(5), client.dns_query(query.clone())).await??;
+        assert_eq!(response, query);
+
+        // concurrent queries with the same id each get their own response
+        let mut other_query = Message::new();
+        other_query.set_id(42).add_query(Query::query(
+            Name::from_ascii("_iroh.other.dns.iroh.link.")?,
+            RecordType::TXT,
+        ));
+        let other_query = Bytes::from(other_query.to_vec()?);
+        let (response, other_response) = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(
+                client.dns_query(query.clone()),
+                client.dns_query(other_query.clone())
+            )
+        })
+        .await?;
+        assert_eq!(response?, query);
+        assert_eq!(other_response?, other_query);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_relay_clients_both_websockets() {
         let _guard = iroh_test::logging::setup();
@@ -30,7 +30,7 @@ use crate::relay::{
         recv_client_key, DerpCodec, PER_CLIENT_SEND_QUEUE_DEPTH, PROTOCOL_VERSION,
         SERVER_CHANNEL_SIZE,
     },
-    server::client_conn::ClientConnBuilder,
+    server::client_conn::{ClientConnBuilder, DnsForward},
     server::clients::Clients,
     server::metrics::Metrics,
 };
@@ -65,6 +65,8 @@ pub struct ServerActorTask {
     loop_handler: AbortOnDropHandle<Result<()>>,
     /// Done token, forces a hard shutdown. To gracefully shutdown, use [`ServerActorTask::close`]
     cancel: CancellationToken,
+    /// The forwarding of the DNS queries of clients, if any
+    dns_forward: Option<DnsForward>,
     // TODO: stats collection
 }
 
@@ -88,9 +90,20 @@ impl ServerActorTask {
             closed: false,
             loop_handler: server_task,
             cancel: cancel_token,
+            dns_forward: None,
         }
     }
 
+    /// Forwards the DNS queries of clients to a nameserver.
+    ///
+    /// This lets clients look up node records for discovery on networks where they can't reach
+    /// nameservers themselves. Only affects the connections handled by the
+    /// [`ClientConnHandler`]s created afterwards. Disabled by default.
+    pub fn with_dns_forward(mut self, dns_forward: Option<DnsForward>) -> Self {
+        self.dns_forward = dns_forward;
+        self
+    }
+
     /// Returns the server's secret key.
     pub fn secret_key(&self) -> &SecretKey {
         &self.secret_key
@@ -140,6 +153,7 @@ impl ServerActorTask {
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
             default_headers: Arc::new(default_headers),
+            dns_forward: self.dns_forward.clone(),
         }
     }
 
@@ -161,6 +175,7 @@ pub struct ClientConnHandler {
     secret_key: SecretKey,
     write_timeout: Option<Duration>,
     pub(crate) default_headers: Arc<HeaderMap>,
+    dns_forward: Option<DnsForward>,
 }
 
 impl Clone for ClientConnHandler {
@@ -170,11 +185,17 @@ impl Clone for ClientConnHandler {
             secret_key: self.secret_key.clone(),
             write_timeout: self.write_timeout,
             default_headers: Arc::clone(&self.default_headers),
+            dns_forward: self.dns_forward.clone(),
         }
     }
 }
 
 impl ClientConnHandler {
+    /// Whether the DNS queries of the clients are forwarded to a nameserver.
+    pub(crate) fn forwards_dns(&self) -> bool {
+        self.dns_forward.is_some()
+    }
+
     /// Adds a new connection to the server and serves it.
     ///
     /// Will error if it takes too long (10 sec) to write or read to the connection, if there is
@@ -218,6 +239,7 @@ impl ClientConnHandler {
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
             server_channel: self.server_channel.clone(),
+            dns_forward: self.dns_forward.clone(),
         };
         trace!("accept: create client");
         self.server_channel
@@ -433,6 +455,7 @@ mod tests {
                 write_timeout: None,
                 channel_capacity: 10,
                 server_channel,
+                dns_forward: None,
             },
             Framed::new(test_io, DerpCodec),
         )
@@ -516,6 +539,7 @@ mod tests {
             write_timeout: None,
             server_channel: server_channel_s,
             default_headers: Default::default(),
+            dns_forward: None,
         };
 
         // create the parts needed for a client
@@ -1,5 +1,6 @@
 //! The server-side representation of an ongoing client relaying connection.
 
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -8,12 +9,17 @@ use anyhow::{Context, Result};
 use bytes::Bytes;
 use futures_lite::StreamExt;
 use futures_util::SinkExt;
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, ResponseCode},
+    rr::{DNSClass, Name, RecordType},
+};
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 use tokio_util::task::AbortOnDropHandle;
-use tracing::{trace, Instrument};
+use tracing::{debug, trace, Instrument};
 
-use crate::{disco::looks_like_disco_wrapper, key::PublicKey};
+use crate::{disco::looks_like_disco_wrapper, dns::node_info::IROH_TXT_NAME, key::PublicKey};
 
 use iroh_metrics::{inc, inc_by};
 
@@ -21,10 +27,15 @@ use crate::relay::codec::Frame;
 use crate::relay::server::streams::RelayIo;
 use crate::relay::server::types::{Packet, ServerMessage};
 use crate::relay::{
-    codec::{write_frame, KEEP_ALIVE},
+    codec::{write_frame, KEEP_ALIVE, MAX_PACKET_SIZE},
     server::metrics::Metrics,
 };
 
+/// The maximum number of DNS queries of a client that are forwarded at the same time.
+const MAX_DNS_QUERIES_PER_CLIENT: usize = 16;
+/// How long to wait for the nameserver to answer a forwarded DNS query.
+const DNS_FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// The [`Server`] side representation of a [`Client`]'s connection.
 ///
 /// [`Server`]: crate::relay::server::Server
@@ -72,6 +83,20 @@ pub(crate) struct ClientChannels {
     pub(crate) peer_gone: mpsc::Sender<PublicKey>,
 }
 
+/// The forwarding of the DNS queries of relay clients to a nameserver.
+///
+/// Only the lookups of the TXT records of nodes under one of the `origins`, i.e. of the names
+/// `_iroh.<z32 node id>.<origin>`, are forwarded. Other queries are answered with `REFUSED`, so
+/// that the relay server does not become an open forwarder for its clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsForward {
+    /// The nameserver to forward the DNS queries to.
+    pub nameserver: SocketAddr,
+    /// The origins of the node discovery whose node records can be looked up, e.g.
+    /// [`crate::discovery::dns::N0_DNS_NODE_ORIGIN_PROD`].
+    pub origins: Vec<Name>,
+}
+
 /// A builds a [`ClientConnManager`] from a [`PublicKey`] and an io connection.
 #[derive(Debug)]
 pub struct ClientConnBuilder {
@@ -81,6 +106,7 @@ pub struct ClientConnBuilder {
     pub(crate) write_timeout: Option<Duration>,
     pub(crate) channel_capacity: usize,
     pub(crate) server_channel: mpsc::Sender<ServerMessage>,
+    pub(crate) dns_forward: Option<DnsForward>,
 }
 
 impl ClientConnBuilder {
@@ -94,6 +120,7 @@ impl ClientConnBuilder {
             self.write_timeout,
             self.channel_capacity,
             self.server_channel,
+            self.dns_forward,
         )
     }
 }
@@ -110,6 +137,7 @@ impl ClientConnManager {
         write_timeout: Option<Duration>,
         channel_capacity: usize,
         server_channel: mpsc::Sender<ServerMessage>,
+        dns_forward: Option<DnsForward>,
     ) -> ClientConnManager {
         let done = CancellationToken::new();
         let client_id = (key, conn_num);
@@ -129,6 +157,8 @@ impl ClientConnManager {
             key,
             preferred: Arc::clone(&preferred),
             server_channel: server_channel.clone(),
+            dns_forward,
+            dns_queries: JoinSet::new(),
         };
 
         // start io loop
@@ -204,6 +234,7 @@ impl ClientConnManager {
 ///     - receive a ping and write a pong back
 ///     - note whether the client is `preferred`, aka this client is the preferred way
 ///     to speak to the node ID associated with that client.
+///     - receive a DNS query, forward it to the nameserver and write its response back
 #[derive(Debug)]
 pub(crate) struct ClientConnIo {
     /// Io to talk to the client
@@ -231,6 +262,11 @@ pub(crate) struct ClientConnIo {
     // might find that the alternative is better, once I have a better idea of how this is supposed
     // to be read.
     preferred: Arc<AtomicBool>,
+
+    /// The forwarding of the DNS queries of the client, if any
+    dns_forward: Option<DnsForward>,
+    /// The DNS queries of the client that are forwarded to the nameserver
+    dns_queries: JoinSet<Result<Bytes>>,
 }
 
 impl ClientConnIo {
@@ -285,6 +321,18 @@ impl ClientConnIo {
                     // TODO: stats
                     // record `packet.enqueuedAt`
                 }
+                Some(res) = self.dns_queries.join_next() => {
+                    match res.context("dns query task")? {
+                        Ok(response) => {
+                            trace!("send dns response");
+                            self.send_dns_response(response).await.context("send dns response")?;
+                        }
+                        Err(err) => {
+                            debug!("forwarding dns query failed: {err:#}");
+                            inc!(Metrics, dns_queries_dropped);
+                        }
+                    }
+                }
                 _ = keep_alive.tick() => {
                     trace!("keep alive");
                     self.send_keep_alive().await.context("send keep alive")?;
@@ -313,6 +361,13 @@ impl ClientConnIo {
         write_frame(&mut self.io, Frame::Pong { data }, self.timeout).await
     }
 
+    /// Sends a DNS response frame, does not flush
+    ///
+    /// Errors if the send does not happen within the `timeout` duration
+    async fn send_dns_response(&mut self, response: Bytes) -> Result<()> {
+        write_frame(&mut self.io, Frame::DnsResponse { response }, self.timeout).await
+    }
+
     /// Sends a peer gone frame, does not flush
     ///
     /// Errors if the send does not happen within the `timeout` duration
@@ -364,6 +419,9 @@ impl ClientConnIo {
             Frame::Health { .. } => {
                 inc!(Metrics, other_packets_recv);
             }
+            Frame::DnsQuery { query } => {
+                self.handle_frame_dns_query(query).await?;
+            }
             _ => {
                 inc!(Metrics, unknown_frames);
             }
@@ -423,6 +481,41 @@ impl ClientConnIo {
         Ok(())
     }
 
+    /// Forwards the DNS query to the nameserver, unless forwarding is disabled or too many
+    /// queries of the client are forwarded already.
+    ///
+    /// Only lookups of the TXT records of nodes under the origins of [`DnsForward`] are
+    /// forwarded, other queries are answered with `REFUSED`. The response is sent to the client
+    /// once the nameserver answers.
+    async fn handle_frame_dns_query(&mut self, query: Bytes) -> Result<()> {
+        let Some(dns_forward) = &self.dns_forward else {
+            inc!(Metrics, dns_queries_dropped);
+            return Ok(());
+        };
+        let nameserver = dns_forward.nameserver;
+        match refused_dns_query(&query, &dns_forward.origins) {
+            Ok(None) => {}
+            Ok(Some(response)) => {
+                debug!("refusing dns query: not a node lookup");
+                inc!(Metrics, dns_queries_refused);
+                return self.send_dns_response(response).await;
+            }
+            Err(err) => {
+                debug!("dropping dns query: {err:#}");
+                inc!(Metrics, dns_queries_dropped);
+                return Ok(());
+            }
+        }
+        if self.dns_queries.len() >= MAX_DNS_QUERIES_PER_CLIENT {
+            debug!("dropping dns query: too many queries");
+            inc!(Metrics, dns_queries_dropped);
+            return Ok(());
+        }
+        inc!(Metrics, dns_queries_forwarded);
+        self.dns_queries.spawn(forward_dns_query(nameserver, query));
+        Ok(())
+    }
+
     /// Parse the SEND_PACKET frame, getting the destination and packet content
     /// Then sends the packet to the server, who directs it to the destination.
     ///
@@ -454,6 +547,58 @@ impl ClientConnIo {
     }
 }
 
+/// Returns the `REFUSED` response to the DNS `query`, unless it only looks up the TXT records of
+/// nodes under one of the `origins`.
+///
+/// Errors if `query` is not a DNS message.
+fn refused_dns_query(query: &[u8], origins: &[Name]) -> Result<Option<Bytes>> {
+    let query = Message::from_vec(query).context("invalid dns query")?;
+    let node_lookup = query.message_type() == MessageType::Query
+        && query.op_code() == OpCode::Query
+        && !query.queries().is_empty()
+        && query.queries().iter().all(|q| {
+            q.query_type() == RecordType::TXT
+                && q.query_class() == DNSClass::IN
+                && is_node_txt_name(q.name(), origins)
+        });
+    if node_lookup {
+        return Ok(None);
+    }
+    let mut response = Message::error_msg(query.id(), query.op_code(), ResponseCode::Refused);
+    response.add_queries(query.queries().iter().cloned());
+    Ok(Some(response.to_vec()?.into()))
+}
+
+/// Whether `name` is `_iroh.<node id>.<origin>` for one of the `origins`, i.e. its first label is
+/// [`IROH_TXT_NAME`] and it has one label between that and the origin.
+fn is_node_txt_name(name: &Name, origins: &[Name]) -> bool {
+    let is_txt_name = name
+        .iter()
+        .next()
+        .is_some_and(|label| label.eq_ignore_ascii_case(IROH_TXT_NAME.as_bytes()));
+    is_txt_name
+        && origins
+            .iter()
+            .any(|origin| name.num_labels() == origin.num_labels() + 2 && origin.zone_of(name))
+}
+
+/// Sends the DNS `query` to the `nameserver` over UDP, and returns its response.
+async fn forward_dns_query(nameserver: SocketAddr, query: Bytes) -> Result<Bytes> {
+    let bind_addr: SocketAddr = match nameserver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = tokio::net::UdpSocket::bind(bind_addr).await?;
+    socket.connect(nameserver).await?;
+    socket.send(&query).await?;
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    let len = tokio::time::timeout(DNS_FORWARD_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .context("nameserver timeout")??;
+    buf.truncate(len);
+    Ok(buf.into())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::key::SecretKey;
@@ -488,6 +633,8 @@ mod tests {
             key,
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            dns_forward: None,
+            dns_queries: JoinSet::new(),
         };
 
         let done = CancellationToken::new();
@@ -598,6 +745,108 @@ mod tests {
         Ok(())
     }
 
+    /// A DNS query message of `record_type` for `name`.
+    fn dns_query(name: &str, record_type: RecordType) -> Result<Bytes> {
+        let mut query = Message::new();
+        query
+            .set_id(42)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(hickory_proto::op::Query::query(
+                Name::from_ascii(name)?,
+                record_type,
+            ));
+        Ok(query.to_vec()?.into())
+    }
+
+    #[tokio::test]
+    async fn test_client_conn_dns_forward() -> Result<()> {
+        let (_send_queue_s, send_queue_r) = mpsc::channel(10);
+        let (_disco_send_queue_s, disco_send_queue_r) = mpsc::channel(10);
+        let (_peer_gone_s, peer_gone_r) = mpsc::channel(10);
+        let (server_channel_s, _server_channel_r) = mpsc::channel(10);
+        let (io, io_rw) = tokio::io::duplex(1024);
+        let mut io_rw = Framed::new(io_rw, DerpCodec);
+
+        // a nameserver that answers each query with the query itself
+        let nameserver = tokio::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let nameserver_addr = nameserver.local_addr()?;
+        let (queries_s, mut queries_r) = mpsc::unbounded_channel();
+        let _nameserver_task = AbortOnDropHandle::new(tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, from)) = nameserver.recv_from(&mut buf).await {
+                queries_s.send(Bytes::copy_from_slice(&buf[..len])).ok();
+                nameserver.send_to(&buf[..len], from).await.ok();
+            }
+        }));
+
+        let conn_io = ClientConnIo {
+            io: RelayIo::Derp(Framed::new(MaybeTlsStream::Test(io), DerpCodec)),
+            timeout: None,
+            send_queue: send_queue_r,
+            disco_send_queue: disco_send_queue_r,
+            peer_gone: peer_gone_r,
+            key: SecretKey::generate().public(),
+            server_channel: server_channel_s,
+            preferred: Default::default(),
+            dns_forward: Some(DnsForward {
+                nameserver: nameserver_addr,
+                origins: vec![Name::from_ascii("dns.iroh.link.")?],
+            }),
+            dns_queries: JoinSet::new(),
+        };
+        let done = CancellationToken::new();
+        let io_done = done.clone();
+        let io_handle = tokio::task::spawn(async move { conn_io.run(io_done).await });
+
+        // node lookups under the origin are forwarded, also with a different case
+        let node_id = SecretKey::generate().public();
+        let query = dns_query(&format!("_iroh.{node_id}.DNS.iroh.link."), RecordType::TXT)?;
+        write_frame(
+            &mut io_rw,
+            Frame::DnsQuery {
+                query: query.clone(),
+            },
+            None,
+        )
+        .await?;
+        io_rw.flush().await?;
+        let frame = recv_frame(FrameType::DnsResponse, &mut io_rw).await?;
+        assert_eq!(frame, Frame::DnsResponse { response: query });
+        assert!(queries_r.recv().await.is_some());
+
+        // other queries are refused, without asking the nameserver
+        for query in [
+            dns_query(&format!("_iroh.{node_id}.dns.iroh.link."), RecordType::A)?,
+            dns_query("example.com.", RecordType::TXT)?,
+            dns_query("_iroh.", RecordType::TXT)?,
+            // node lookups under a foreign origin
+            dns_query(&format!("_iroh.{node_id}.example.com."), RecordType::TXT)?,
+            dns_query(&format!("_iroh.{node_id}.iroh.link."), RecordType::TXT)?,
+            dns_query(
+                &format!("_iroh.x.{node_id}.dns.iroh.link."),
+                RecordType::TXT,
+            )?,
+            dns_query("_iroh.dns.iroh.link.", RecordType::TXT)?,
+        ] {
+            write_frame(&mut io_rw, Frame::DnsQuery { query }, None).await?;
+            io_rw.flush().await?;
+            let Frame::DnsResponse { response } =
+                recv_frame(FrameType::DnsResponse, &mut io_rw).await?
+            else {
+                bail!("expected a dns response");
+            };
+            let response = Message::from_vec(&response)?;
+            assert_eq!(response.id(), 42);
+            assert_eq!(response.response_code(), ResponseCode::Refused);
+        }
+        assert!(queries_r.try_recv().is_err());
+
+        done.cancel();
+        io_handle.await??;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_client_conn_read_err() -> Result<()> {
         let (_send_queue_s, send_queue_r) = mpsc::channel(10);
@@ -621,6 +870,8 @@ mod tests {
             key,
             server_channel: server_channel_s,
             preferred: Arc::clone(&preferred),
+            dns_forward: None,
+            dns_queries: JoinSet::new(),
         };
 
         let done = CancellationToken::new();
@@ -283,6 +283,7 @@ mod tests {
                 write_timeout: None,
                 channel_capacity: 10,
                 server_channel,
+                dns_forward: None,
             },
             FramedRead::new(test_io, DerpCodec),
         )
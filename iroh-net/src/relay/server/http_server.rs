@@ -23,8 +23,11 @@ use tracing::{debug, debug_span, error, info, info_span, warn, Instrument};
 use tungstenite::handshake::derive_accept_key;
 
 use crate::key::SecretKey;
-use crate::relay::http::{Protocol, LEGACY_RELAY_PATH, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION};
+use crate::relay::http::{
+    Protocol, DNS_FORWARD_HEADER, LEGACY_RELAY_PATH, RELAY_PATH, SUPPORTED_WEBSOCKET_VERSION,
+};
 use crate::relay::server::actor::{ClientConnHandler, ServerActorTask};
+use crate::relay::server::client_conn::DnsForward;
 use crate::relay::server::streams::MaybeTlsStream;
 
 type BytesBody = http_body_util::Full<hyper::body::Bytes>;
@@ -176,6 +179,8 @@ pub struct ServerBuilder {
     relay_override: Option<HyperHandler>,
     /// Headers to use for HTTP responses.
     headers: HeaderMap,
+    /// The forwarding of the DNS queries of relay clients.
+    dns_forward: Option<DnsForward>,
     /// 404 not found response.
     ///
     /// When `None`, a default is provided.
@@ -193,6 +198,7 @@ impl ServerBuilder {
             handlers: Default::default(),
             relay_override: None,
             headers: HeaderMap::new(),
+            dns_forward: None,
             not_found_fn: None,
         }
     }
@@ -245,6 +251,14 @@ impl ServerBuilder {
         self
     }
 
+    /// Forwards the DNS queries of relay clients to a nameserver.
+    ///
+    /// Disabled when `None`, which is the default.
+    pub fn dns_forward(mut self, dns_forward: Option<DnsForward>) -> Self {
+        self.dns_forward = dns_forward;
+        self
+    }
+
     /// Builds and spawns an HTTP(S) Relay Server.
     pub async fn spawn(self) -> Result<Server> {
         ensure!(
@@ -253,7 +267,8 @@ impl ServerBuilder {
         );
         let (relay_handler, relay_server) = if let Some(secret_key) = self.secret_key {
             // spawns a server actor/task
-            let server = ServerActorTask::new(secret_key.clone());
+            let server =
+                ServerActorTask::new(secret_key.clone()).with_dns_forward(self.dns_forward);
             (
                 RelayHandler::ConnHandler(server.client_conn_handler(self.headers.clone())),
                 Some(server),
@@ -383,6 +398,7 @@ impl Service<Request<Incoming>> for ClientConnHandler {
     fn call(&self, mut req: Request<Incoming>) -> Self::Future {
         // TODO: soooo much cloning. See if there is an alternative
         let closure_conn_handler = self.clone();
+        let forwards_dns = self.forwards_dns();
         let mut builder = Response::builder();
         for (key, value) in self.default_headers.iter() {
             builder = builder.header(key, value);
@@ -471,6 +487,11 @@ impl Service<Request<Incoming>> for ClientConnHandler {
                 builder = builder
                     .status(StatusCode::SWITCHING_PROTOCOLS)
                     .header(UPGRADE, HeaderValue::from_static(protocol.upgrade_header()));
+                // clients only send DNS queries to servers that advertise them, older servers
+                // close the connection on the unknown frame
+                if forwards_dns {
+                    builder = builder.header(DNS_FORWARD_HEADER, HeaderValue::from_static("1"));
+                }
 
                 if let Some((key, _version)) = websocket_headers {
                     Ok(builder
@@ -42,6 +42,12 @@ pub struct Metrics {
     pub sent_pong: Counter,
     /// Number of `FrameType::Unknown` received
     pub unknown_frames: Counter,
+    /// Number of `FrameType::DnsQuery`s forwarded to the nameserver
+    pub dns_queries_forwarded: Counter,
+    /// Number of `FrameType::DnsQuery`s dropped, e.g. because DNS forwarding is disabled
+    pub dns_queries_dropped: Counter,
+    /// Number of `FrameType::DnsQuery`s answered with `REFUSED`, because they are no node lookups
+    pub dns_queries_refused: Counter,
 
     /*
      * Metrics about peers
@@ -92,6 +98,13 @@ impl Default for Metrics {
             got_ping: Counter::new("Number of times the server has received a Ping from a client."),
             sent_pong: Counter::new("Number of times the server has sent a Pong to a client."),
             unknown_frames: Counter::new("Number of unknown frames sent to this server."),
+            dns_queries_forwarded: Counter::new(
+                "Number of DNS queries of clients forwarded to the nameserver.",
+            ),
+            dns_queries_dropped: Counter::new("Number of DNS queries of clients dropped."),
+            dns_queries_refused: Counter::new(
+                "Number of DNS queries of clients refused for not looking up a node.",
+            ),
 
             /*
              * Metrics about peers
@@ -4,7 +4,9 @@ use std::net::Ipv4Addr;
 use anyhow::Result;
 use tokio::sync::oneshot;
 
-use crate::relay::server::{CertConfig, RelayConfig, Server, ServerConfig, StunConfig, TlsConfig};
+use crate::relay::server::{
+    CertConfig, DnsForward, RelayConfig, Server, ServerConfig, StunConfig, TlsConfig,
+};
 use crate::{
     key::SecretKey,
     relay::{RelayMap, RelayNode, RelayUrl},
@@ -28,6 +30,14 @@ pub struct CleanupDropGuard(pub(crate) oneshot::Sender<()>);
 /// The returned `Url` is the url of the relay server in the returned [`RelayMap`].
 /// When dropped, the returned [`Server`] does will stop running.
 pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, Server)> {
+    run_relay_server_with_dns_forward(None).await
+}
+
+/// Runs a relay server like [`run_relay_server`], which forwards the DNS queries of clients as
+/// configured by `dns_forward`.
+pub async fn run_relay_server_with_dns_forward(
+    dns_forward: Option<DnsForward>,
+) -> Result<(RelayMap, RelayUrl, Server)> {
     let secret_key = SecretKey::generate();
     let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
     let rustls_cert = rustls::pki_types::CertificateDer::from(cert.serialize_der().unwrap());
@@ -47,6 +57,7 @@ pub async fn run_relay_server() -> Result<(RelayMap, RelayUrl, Server)> {
                 https_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
             }),
             limits: Default::default(),
+            dns_forward,
         }),
         stun: Some(StunConfig {
             bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
@@ -260,7 +271,7 @@ pub(crate) mod pkarr_relay {
     use axum::{
         extract::{Path, State},
         response::IntoResponse,
-        routing::put,
+        routing::get,
         Router,
     };
     use bytes::Bytes;
@@ -268,14 +279,14 @@ pub(crate) mod pkarr_relay {
     use tracing::{debug, error, warn};
     use url::Url;
 
-    use crate::test_utils::pkarr_dns_state::State as AppState;
+    use crate::{test_utils::pkarr_dns_state::State as AppState, NodeId};
 
     use super::CleanupDropGuard;
 
     pub async fn run_pkarr_relay(state: AppState) -> Result<(Url, CleanupDropGuard)> {
         let bind_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
         let app = Router::new()
-            .route("/pkarr/:key", put(pkarr_put))
+            .route("/pkarr/:key", get(pkarr_get).put(pkarr_put))
             .with_state(state);
         let listener = tokio::net::TcpListener::bind(bind_addr).await?;
         let bound_addr = listener.local_addr()?;
@@ -311,6 +322,19 @@ pub(crate) mod pkarr_relay {
         Ok(http::StatusCode::NO_CONTENT)
     }
 
+    async fn pkarr_get(
+        State(state): State<AppState>,
+        Path(key): Path<String>,
+    ) -> Result<impl IntoResponse, AppError> {
+        let key = pkarr::PublicKey::try_from(key.as_str())?;
+        let node_id = NodeId::from_bytes(&key.to_bytes())?;
+        let payload = state.get(&node_id).map(|packet| packet.to_relay_payload());
+        Ok(match payload {
+            Some(payload) => payload.into_response(),
+            None => http::StatusCode::NOT_FOUND.into_response(),
+        })
+    }
+
     #[derive(Debug)]
     struct AppError(anyhow::Error);
     impl<T: Into<anyhow::Error>> From<T> for AppError {
@@ -72,6 +72,8 @@ mod protocol;
 mod rpc;
 mod rpc_status;
 
+#[cfg(feature = "discovery-pkarr-dht")]
+pub use self::builder::PkarrPublishTarget;
 pub use self::builder::{
     Builder, DiscoveryConfig, DocsStorage, GcPolicy, ProtocolBuilder, StorageConfig,
     DEFAULT_RPC_ADDR,
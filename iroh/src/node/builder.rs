@@ -20,8 +20,14 @@ use iroh_docs::net::DOCS_ALPN;
 use iroh_gossip::net::{Gossip, GOSSIP_ALPN};
 #[cfg(not(test))]
 use iroh_net::discovery::local_swarm_discovery::LocalSwarmDiscovery;
+#[cfg(feature = "discovery-pkarr-dht")]
+use iroh_net::discovery::pkarr::dht::DhtDiscovery;
 use iroh_net::{
-    discovery::{dns::DnsDiscovery, pkarr::PkarrPublisher, ConcurrentDiscovery, Discovery},
+    discovery::{
+        dns::DnsDiscovery,
+        pkarr::{PkarrPublisher, RepublishPolicy},
+        ConcurrentDiscovery, Discovery,
+    },
     dns::DnsResolver,
     endpoint::TransportConfig,
     relay::RelayMode,
@@ -119,6 +125,9 @@ where
     gc_policy: GcPolicy,
     dns_resolver: Option<DnsResolver>,
     node_discovery: DiscoveryConfig,
+    pkarr_republish: RepublishPolicy,
+    #[cfg(feature = "discovery-pkarr-dht")]
+    pkarr_publish_target: PkarrPublishTarget,
     docs_storage: DocsStorage,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_relay_cert_verify: bool,
@@ -188,6 +197,30 @@ pub enum DiscoveryConfig {
     Custom(Box<dyn Discovery>),
 }
 
+/// Where [`DiscoveryConfig::Default`] publishes the node info
+#[cfg(feature = "discovery-pkarr-dht")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PkarrPublishTarget {
+    /// Publish to the pkarr relay operated by [number 0]
+    ///
+    /// [number 0]: https://n0.computer
+    #[default]
+    Relay,
+    /// Publish to the pkarr relay and straight to the Mainline DHT
+    ///
+    /// This keeps the node discoverable if the pkarr relay is down. Nodes are then also resolved
+    /// from the Mainline DHT.
+    RelayAndMainline,
+    /// Only publish straight to the Mainline DHT
+    ///
+    /// The node is still discoverable via DNS, as the DNS server of [number 0] resolves packets
+    /// which are not published to it from the Mainline DHT. Nodes are then also resolved from the
+    /// Mainline DHT.
+    ///
+    /// [number 0]: https://n0.computer
+    Mainline,
+}
+
 impl From<Box<ConcurrentDiscovery>> for DiscoveryConfig {
     fn from(value: Box<ConcurrentDiscovery>) -> Self {
         Self::Custom(value)
@@ -224,6 +257,37 @@ impl BoxableServerEndpoint<crate::rpc_protocol::Request, crate::rpc_protocol::Re
     }
 }
 
+/// Creates the pkarr publishers of [`DiscoveryConfig::Default`].
+fn default_publishers(
+    secret_key: &SecretKey,
+    policy: RepublishPolicy,
+    #[cfg(feature = "discovery-pkarr-dht")] target: PkarrPublishTarget,
+) -> Result<Vec<Box<dyn Discovery>>> {
+    let relay = || -> Box<dyn Discovery> {
+        Box::new(PkarrPublisher::n0_dns_with_policy(
+            secret_key.clone(),
+            policy.clone(),
+        ))
+    };
+    #[cfg(not(feature = "discovery-pkarr-dht"))]
+    let publishers = vec![relay()];
+    #[cfg(feature = "discovery-pkarr-dht")]
+    let publishers = {
+        let mainline = || -> Result<Box<dyn Discovery>> {
+            let discovery = DhtDiscovery::builder()
+                .secret_key(secret_key.clone())
+                .build()?;
+            Ok(Box::new(discovery))
+        };
+        match target {
+            PkarrPublishTarget::Relay => vec![relay()],
+            PkarrPublishTarget::RelayAndMainline => vec![relay(), mainline()?],
+            PkarrPublishTarget::Mainline => vec![mainline()?],
+        }
+    };
+    Ok(publishers)
+}
+
 fn mk_external_rpc() -> IrohServerEndpoint {
     quic_rpc::transport::boxed::ServerEndpoint::new(DummyServerEndpoint)
 }
@@ -250,6 +314,9 @@ impl Default for Builder<iroh_blobs::store::mem::Store> {
             gc_policy: GcPolicy::Disabled,
             docs_storage: DocsStorage::Disabled,
             node_discovery: Default::default(),
+            pkarr_republish: Default::default(),
+            #[cfg(feature = "discovery-pkarr-dht")]
+            pkarr_publish_target: Default::default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
             gc_done_callback: None,
@@ -286,6 +353,9 @@ impl<D: Map> Builder<D> {
             gc_policy: GcPolicy::Disabled,
             docs_storage,
             node_discovery: Default::default(),
+            pkarr_republish: Default::default(),
+            #[cfg(feature = "discovery-pkarr-dht")]
+            pkarr_publish_target: Default::default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
             gc_done_callback: None,
@@ -346,6 +416,9 @@ where
             gc_policy: self.gc_policy,
             docs_storage,
             node_discovery: self.node_discovery,
+            pkarr_republish: self.pkarr_republish,
+            #[cfg(feature = "discovery-pkarr-dht")]
+            pkarr_publish_target: self.pkarr_publish_target,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: false,
             gc_done_callback: self.gc_done_callback,
@@ -440,6 +513,24 @@ where
         self
     }
 
+    /// Sets how often the default node discovery republishes the node info to pkarr.
+    ///
+    /// This only applies to [`DiscoveryConfig::Default`], custom discovery services are
+    /// configured when they are created, e.g. with [`PkarrPublisher::with_policy`].
+    pub fn pkarr_republish_policy(mut self, policy: RepublishPolicy) -> Self {
+        self.pkarr_republish = policy;
+        self
+    }
+
+    /// Sets where the default node discovery publishes the node info.
+    ///
+    /// This only applies to [`DiscoveryConfig::Default`], see [`PkarrPublishTarget`].
+    #[cfg(feature = "discovery-pkarr-dht")]
+    pub fn pkarr_publish_target(mut self, target: PkarrPublishTarget) -> Self {
+        self.pkarr_publish_target = target;
+        self
+    }
+
     /// Optionally set a custom DNS resolver to use for the magic endpoint.
     ///
     /// The DNS resolver is used to resolve relay hostnames, and node addresses if
@@ -568,32 +659,28 @@ where
                 DiscoveryConfig::None => None,
                 DiscoveryConfig::Custom(discovery) => Some(discovery),
                 DiscoveryConfig::Default => {
-                    #[cfg(not(test))]
-                    let discovery = {
-                        let mut discovery_services: Vec<Box<dyn Discovery>> = vec![
-                            // Enable DNS discovery by default
-                            Box::new(DnsDiscovery::n0_dns()),
-                            // Enable pkarr publishing by default
-                            Box::new(PkarrPublisher::n0_dns(self.secret_key.clone())),
-                        ];
-                        // Enable local swarm discovery by default, but fail silently if it errors
-                        match LocalSwarmDiscovery::new(self.secret_key.public()) {
-                            Err(e) => {
-                                tracing::error!("unable to start LocalSwarmDiscoveryService: {e:?}")
-                            }
-                            Ok(service) => {
-                                discovery_services.push(Box::new(service));
-                            }
-                        }
-                        ConcurrentDiscovery::from_services(discovery_services)
-                    };
-                    #[cfg(test)]
-                    let discovery = ConcurrentDiscovery::from_services(vec![
+                    let mut discovery_services: Vec<Box<dyn Discovery>> = vec![
                         // Enable DNS discovery by default
                         Box::new(DnsDiscovery::n0_dns()),
-                        // Enable pkarr publishing by default
-                        Box::new(PkarrPublisher::n0_dns(self.secret_key.clone())),
-                    ]);
+                    ];
+                    // Enable pkarr publishing by default
+                    discovery_services.extend(default_publishers(
+                        &self.secret_key,
+                        self.pkarr_republish,
+                        #[cfg(feature = "discovery-pkarr-dht")]
+                        self.pkarr_publish_target,
+                    )?);
+                    // Enable local swarm discovery by default, but fail silently if it errors
+                    #[cfg(not(test))]
+                    match LocalSwarmDiscovery::new(self.secret_key.public()) {
+                        Err(e) => {
+                            tracing::error!("unable to start LocalSwarmDiscoveryService: {e:?}")
+                        }
+                        Ok(service) => {
+                            discovery_services.push(Box::new(service));
+                        }
+                    }
+                    let discovery = ConcurrentDiscovery::from_services(discovery_services);
                     Some(Box::new(discovery))
                 }
             };